@@ -0,0 +1,230 @@
+//! Benchmarks for the note data-access path.
+//!
+//! The mapping benchmarks always run. The database benchmarks go through
+//! `MySqlNoteRepository`, so they cover its filters, cursor paging and the
+//! compression of large content, against `DATABASE_URL`. They are skipped
+//! when it is unset or unreachable, so `cargo bench` stays usable on
+//! machines without MySQL.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rust_axum_mysql::{
+    attachment::LocalDiskStorage,
+    clock::SystemClock,
+    handler::filter_db_record,
+    model::{NoteId, NoteModel, NoteStatus, Title, UserId},
+    repository::{
+        MySqlNoteRepository, NoteFilter, NoteRepository, NoteSort, StoredNoteContent, DELETE_NOTE,
+    },
+};
+use sqlx::mysql::{MySqlPool, MySqlPoolOptions};
+use tokio::runtime::Runtime;
+
+fn sample_note(n: usize) -> NoteModel {
+    NoteModel {
+        id: NoteId::from(uuid::Uuid::new_v4()),
+        title: Title::parse(format!("Note {}", n)).unwrap(),
+        content: "Lorem ipsum dolor sit amet. ".repeat(40),
+        preview: "Lorem ipsum dolor sit amet.".to_string(),
+        category: "bench".to_string(),
+        category_id: None,
+        published: (n % 2 == 1).into(),
+        published_at: None,
+        status: if n % 2 == 1 {
+            NoteStatus::Published
+        } else {
+            NoteStatus::Draft
+        },
+        expires_at: None,
+        view_count: n as u64,
+        last_accessed_at: None,
+        created_at: Some(Utc::now()),
+        updated_at: Some(Utc::now()),
+        user_id: None,
+        deleted_at: None,
+        version: 1,
+        content_key: None,
+    }
+}
+
+fn mapping_benches(c: &mut Criterion) {
+    let page = (0..100).map(sample_note).collect::<Vec<_>>();
+
+    c.bench_function("map_page_of_100_notes", |b| {
+        b.iter(|| {
+            black_box(&page)
+                .iter()
+                .map(filter_db_record)
+                .collect::<Vec<_>>()
+        })
+    });
+}
+
+fn connect(rt: &Runtime) -> Option<MySqlPool> {
+    dotenv::dotenv().ok();
+    let Ok(database_url) = std::env::var("DATABASE_URL") else {
+        eprintln!("DATABASE_URL is not set, skipping database benchmarks");
+        return None;
+    };
+
+    match rt.block_on(
+        MySqlPoolOptions::new()
+            .max_connections(4)
+            .connect(&database_url),
+    ) {
+        Ok(pool) => Some(pool),
+        Err(err) => {
+            eprintln!(
+                "Database unreachable ({:?}), skipping database benchmarks",
+                err
+            );
+            None
+        }
+    }
+}
+
+/// Inserts `note` with a fresh id `iters` times, timing only the inserts,
+/// and deletes what was inserted.
+async fn time_inserts(
+    repository: &MySqlNoteRepository,
+    pool: &MySqlPool,
+    note: &NoteModel,
+    iters: u64,
+) -> Duration {
+    let mut elapsed = Duration::ZERO;
+    for _ in 0..iters {
+        let mut note = note.clone();
+        note.id = NoteId::from(uuid::Uuid::new_v4());
+        note.title = Title::parse(format!("bench {}", note.id)).unwrap();
+        let started = Instant::now();
+        repository.insert(&note).await.unwrap();
+        elapsed += started.elapsed();
+        sqlx::query(DELETE_NOTE)
+            .bind(note.id)
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+    elapsed
+}
+
+fn database_benches(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let Some(pool) = connect(&rt) else {
+        return;
+    };
+    let storage = LocalDiskStorage::new(std::env::temp_dir().join("rust-axum-mysql-bench"));
+    let repository =
+        MySqlNoteRepository::new(pool.clone(), Arc::new(SystemClock), Arc::new(storage));
+
+    c.bench_function("list_notes_page", |b| {
+        b.to_async(&rt)
+            .iter(|| async { repository.list_page(None, 10, 0).await.unwrap() })
+    });
+
+    c.bench_function("list_user_notes_page", |b| {
+        b.to_async(&rt).iter(|| async {
+            let owner = UserId::from(uuid::Uuid::nil());
+            repository.list_page(Some(owner), 10, 0).await.unwrap()
+        })
+    });
+
+    let filter = NoteFilter {
+        category: Some("bench".to_string()),
+        status: Some(NoteStatus::Published),
+        created_after: Some(Utc::now() - chrono::Duration::days(30)),
+        ..Default::default()
+    };
+    c.bench_function("list_filtered_notes_page", |b| {
+        b.to_async(&rt).iter(|| async {
+            repository
+                .list_filtered(&filter, NoteSort::default(), None, None, 10, 0)
+                .await
+                .unwrap()
+        })
+    });
+
+    let by_title = NoteSort::parse(Some("title"), Some("desc")).unwrap();
+    c.bench_function("list_notes_sorted_by_title", |b| {
+        b.to_async(&rt).iter(|| async {
+            repository
+                .list_filtered(&NoteFilter::default(), by_title, None, None, 10, 0)
+                .await
+                .unwrap()
+        })
+    });
+
+    c.bench_function("count_filtered_notes", |b| {
+        b.to_async(&rt)
+            .iter(|| async { repository.count_filtered(&filter, None).await.unwrap() })
+    });
+
+    let existing_id = rt
+        .block_on(repository.list_page(None, 1, 0))
+        .unwrap()
+        .first()
+        .map(|note| note.id);
+    if let Some(id) = existing_id {
+        c.bench_function("get_note_by_id", |b| {
+            b.to_async(&rt)
+                .iter(|| async { repository.find(id).await.unwrap() })
+        });
+
+        c.bench_function("list_notes_after_cursor", |b| {
+            b.to_async(&rt)
+                .iter(|| async { repository.list_after(None, id, 10).await.unwrap() })
+        });
+
+        c.bench_function("list_filtered_notes_after_cursor", |b| {
+            b.to_async(&rt).iter(|| async {
+                repository
+                    .list_filtered(&filter, NoteSort::default(), None, Some(id), 10, 0)
+                    .await
+                    .unwrap()
+            })
+        });
+    }
+
+    let small = sample_note(0);
+    c.bench_function("insert_note", |b| {
+        b.to_async(&rt)
+            .iter_custom(|iters| time_inserts(&repository, &pool, &small, iters))
+    });
+
+    // Large enough to be stored compressed.
+    let mut large = sample_note(1);
+    large.content = "Lorem ipsum dolor sit amet. ".repeat(1_000);
+    c.bench_function("insert_large_note", |b| {
+        b.to_async(&rt)
+            .iter_custom(|iters| time_inserts(&repository, &pool, &large, iters))
+    });
+
+    large.id = NoteId::from(uuid::Uuid::new_v4());
+    large.title = Title::parse(format!("bench {}", large.id)).unwrap();
+    rt.block_on(repository.insert(&large)).unwrap();
+    c.bench_function("get_large_note_by_id", |b| {
+        b.to_async(&rt)
+            .iter(|| async { repository.find(large.id).await.unwrap() })
+    });
+    c.bench_function("read_large_note_content", |b| {
+        b.to_async(&rt).iter(|| async {
+            match repository.content(large.id).await.unwrap().unwrap() {
+                StoredNoteContent::Compressed(compressed) => black_box(compressed.len()),
+                StoredNoteContent::Plain { size, slices } => {
+                    slices.finish().await.unwrap();
+                    black_box(size as usize)
+                }
+            }
+        })
+    });
+    rt.block_on(sqlx::query(DELETE_NOTE).bind(large.id).execute(&pool))
+        .unwrap();
+}
+
+criterion_group!(benches, mapping_benches, database_benches);
+criterion_main!(benches);
@@ -0,0 +1,3 @@
+ALTER TABLE notes
+    DROP COLUMN view_count,
+    DROP COLUMN last_accessed_at;
@@ -0,0 +1,3 @@
+ALTER TABLE notes
+    ADD COLUMN view_count BIGINT UNSIGNED NOT NULL DEFAULT 0,
+    ADD COLUMN last_accessed_at TIMESTAMP NULL;
@@ -0,0 +1,25 @@
+ALTER TABLE notes ADD COLUMN id_char CHAR(36) NULL AFTER id;
+
+DROP PROCEDURE IF EXISTS backfill_note_char_ids;
+CREATE PROCEDURE backfill_note_char_ids()
+BEGIN
+    DECLARE last_id VARBINARY(16) DEFAULT '';
+    DECLARE chunk_end VARBINARY(16);
+    WHILE last_id IS NOT NULL DO
+        SET chunk_end = (
+            SELECT MAX(id) FROM (
+                SELECT id FROM notes WHERE id > last_id ORDER BY id LIMIT 1000
+            ) AS chunk
+        );
+        UPDATE notes
+        SET id_char = LOWER(INSERT(INSERT(INSERT(INSERT(HEX(id), 9, 0, '-'), 14, 0, '-'), 19, 0, '-'), 24, 0, '-')),
+            updated_at = updated_at
+        WHERE id > last_id AND id <= chunk_end;
+        SET last_id = chunk_end;
+    END WHILE;
+END;
+CALL backfill_note_char_ids();
+DROP PROCEDURE backfill_note_char_ids;
+
+ALTER TABLE notes DROP PRIMARY KEY, DROP COLUMN id;
+ALTER TABLE notes CHANGE COLUMN id_char id CHAR(36) NOT NULL FIRST, ADD PRIMARY KEY (id);
@@ -0,0 +1,29 @@
+-- Store note ids as BINARY(16) instead of CHAR(36). Existing rows are
+-- converted in chunks of the primary key so a large table is not rewritten
+-- in one statement.
+ALTER TABLE notes ADD COLUMN id_bin BINARY(16) NULL AFTER id;
+
+DROP PROCEDURE IF EXISTS backfill_note_binary_ids;
+CREATE PROCEDURE backfill_note_binary_ids()
+BEGIN
+    DECLARE last_id CHAR(36) DEFAULT '';
+    DECLARE chunk_end CHAR(36);
+    WHILE last_id IS NOT NULL DO
+        -- Walks the primary key, so each chunk is a range seek rather than a
+        -- scan for rows not converted yet.
+        SET chunk_end = (
+            SELECT MAX(id) FROM (
+                SELECT id FROM notes WHERE id > last_id ORDER BY id LIMIT 1000
+            ) AS chunk
+        );
+        UPDATE notes
+        SET id_bin = UNHEX(REPLACE(id, '-', '')), updated_at = updated_at
+        WHERE id > last_id AND id <= chunk_end;
+        SET last_id = chunk_end;
+    END WHILE;
+END;
+CALL backfill_note_binary_ids();
+DROP PROCEDURE backfill_note_binary_ids;
+
+ALTER TABLE notes DROP PRIMARY KEY, DROP COLUMN id;
+ALTER TABLE notes CHANGE COLUMN id_bin id BINARY(16) NOT NULL FIRST, ADD PRIMARY KEY (id);
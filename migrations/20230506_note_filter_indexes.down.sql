@@ -0,0 +1,3 @@
+DROP INDEX idx_notes_category_created_at ON notes;
+DROP INDEX idx_notes_published_created_at ON notes;
+DROP INDEX idx_notes_created_at ON notes;
@@ -0,0 +1,3 @@
+CREATE INDEX idx_notes_category_created_at ON notes (category, created_at);
+CREATE INDEX idx_notes_published_created_at ON notes (published, created_at);
+CREATE INDEX idx_notes_created_at ON notes (created_at);
@@ -0,0 +1,3 @@
+DROP TABLE IF EXISTS note_trending;
+DROP TABLE IF EXISTS note_category_facets;
+DROP TABLE IF EXISTS note_stats_summary;
@@ -0,0 +1,20 @@
+CREATE TABLE IF NOT EXISTS note_stats_summary (
+    id TINYINT PRIMARY KEY NOT NULL,
+    total_notes BIGINT NOT NULL,
+    published_notes BIGINT NOT NULL,
+    total_views BIGINT NOT NULL,
+    refreshed_at TIMESTAMP NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS note_category_facets (
+    category VARCHAR(100) PRIMARY KEY NOT NULL,
+    note_count BIGINT NOT NULL,
+    published_count BIGINT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS note_trending (
+    position INT PRIMARY KEY NOT NULL,
+    note_id BINARY(16) NOT NULL,
+    title VARCHAR(255) NOT NULL,
+    view_count BIGINT NOT NULL
+);
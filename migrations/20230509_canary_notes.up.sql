@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS canary_notes (
+    note_id BINARY(16) PRIMARY KEY NOT NULL,
+    label VARCHAR(255) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS moderation_rules (
+    id BIGINT UNSIGNED PRIMARY KEY NOT NULL AUTO_INCREMENT,
+    kind VARCHAR(16) NOT NULL,
+    pattern VARCHAR(1024) NOT NULL,
+    action VARCHAR(16) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS moderation_queue (
+    id BIGINT UNSIGNED PRIMARY KEY NOT NULL AUTO_INCREMENT,
+    note_id BINARY(16) NOT NULL,
+    action VARCHAR(16) NOT NULL,
+    reasons TEXT NOT NULL,
+    status VARCHAR(16) NOT NULL DEFAULT 'pending',
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    resolved_at TIMESTAMP NULL,
+    INDEX moderation_queue_status_idx (status, created_at)
+);
@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS note_reports (
+    id BIGINT UNSIGNED PRIMARY KEY NOT NULL AUTO_INCREMENT,
+    note_id BINARY(16) NOT NULL,
+    reason VARCHAR(32) NOT NULL,
+    details TEXT NULL,
+    reporter VARCHAR(255) NULL,
+    state VARCHAR(16) NOT NULL DEFAULT 'open',
+    resolution TEXT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    resolved_at TIMESTAMP NULL,
+    INDEX note_reports_state_idx (state, created_at),
+    INDEX note_reports_note_idx (note_id, state)
+);
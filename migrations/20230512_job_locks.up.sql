@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS job_locks (
+    name VARCHAR(64) PRIMARY KEY NOT NULL,
+    owner VARCHAR(128) NOT NULL,
+    acquired_at TIMESTAMP(3) NOT NULL,
+    expires_at TIMESTAMP(3) NOT NULL
+);
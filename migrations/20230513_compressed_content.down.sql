@@ -0,0 +1,5 @@
+-- Rows with content_encoding = 'zstd' have an empty `content`; rewrite them
+-- uncompressed through the API before rolling this back.
+ALTER TABLE notes
+    DROP COLUMN content_zstd,
+    DROP COLUMN content_encoding;
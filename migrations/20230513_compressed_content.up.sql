@@ -0,0 +1,3 @@
+ALTER TABLE notes
+    ADD COLUMN content_encoding VARCHAR(16) NOT NULL DEFAULT 'plain' AFTER content,
+    ADD COLUMN content_zstd MEDIUMBLOB NULL AFTER content_encoding;
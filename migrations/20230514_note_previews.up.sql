@@ -0,0 +1,2 @@
+-- NULL until the note is next written; reads compute it meanwhile.
+ALTER TABLE notes ADD COLUMN preview VARCHAR(255) NULL AFTER content_zstd;
@@ -0,0 +1 @@
+DROP INDEX idx_notes_sidebar ON notes;
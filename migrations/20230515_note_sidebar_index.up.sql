@@ -0,0 +1,2 @@
+-- Covers GET /api/notes/index (id comes with every secondary index).
+CREATE INDEX idx_notes_sidebar ON notes (title, category, updated_at);
@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS hook_scripts (
+    id BIGINT UNSIGNED PRIMARY KEY NOT NULL AUTO_INCREMENT,
+    name VARCHAR(100) NOT NULL,
+    version INT UNSIGNED NOT NULL,
+    hook VARCHAR(32) NOT NULL,
+    source TEXT NOT NULL,
+    active BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE INDEX hook_scripts_name_version_idx (name, version)
+);
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS plugin_migrations (
+    plugin VARCHAR(100) NOT NULL,
+    version VARCHAR(100) NOT NULL,
+    applied_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (plugin, version)
+);
@@ -0,0 +1 @@
+DROP INDEX idx_notes_fulltext ON notes;
@@ -0,0 +1,3 @@
+-- Backs GET /api/notes/search. `preview` is included because notes compressed
+-- at rest keep an empty `content`.
+CREATE FULLTEXT INDEX idx_notes_fulltext ON notes (title, content, preview);
@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS note_link_previews (
+    note_id BINARY(16) NOT NULL,
+    url_hash CHAR(64) NOT NULL,
+    url TEXT NOT NULL,
+    title TEXT NULL,
+    description TEXT NULL,
+    image_url TEXT NULL,
+    site_name VARCHAR(255) NULL,
+    status VARCHAR(16) NOT NULL,
+    fetched_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (note_id, url_hash)
+);
@@ -0,0 +1,7 @@
+ALTER TABLE note_link_previews
+    DROP INDEX note_link_previews_due_idx,
+    DROP INDEX note_link_previews_check_idx,
+    DROP COLUMN checked_at,
+    DROP COLUMN redirect_url,
+    DROP COLUMN http_status,
+    DROP COLUMN check_status;
@@ -0,0 +1,7 @@
+ALTER TABLE note_link_previews
+    ADD COLUMN check_status VARCHAR(16) NULL,
+    ADD COLUMN http_status SMALLINT UNSIGNED NULL,
+    ADD COLUMN redirect_url TEXT NULL,
+    ADD COLUMN checked_at TIMESTAMP NULL,
+    ADD INDEX note_link_previews_check_idx (check_status, checked_at),
+    ADD INDEX note_link_previews_due_idx (checked_at);
@@ -0,0 +1,6 @@
+ALTER TABLE notes
+    DROP INDEX idx_notes_owner_title,
+    ADD UNIQUE INDEX title (title),
+    DROP COLUMN owner_key;
+ALTER TABLE notes DROP INDEX idx_notes_user, DROP COLUMN user_id;
+DROP TABLE IF EXISTS users;
@@ -0,0 +1,17 @@
+CREATE TABLE IF NOT EXISTS users (
+    id BINARY(16) PRIMARY KEY NOT NULL,
+    email VARCHAR(255) NOT NULL UNIQUE,
+    password_hash VARCHAR(255) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Notes written before user accounts existed (or through service accounts)
+-- have no owner.
+ALTER TABLE notes ADD COLUMN user_id BINARY(16) NULL, ADD INDEX idx_notes_user (user_id);
+
+-- Titles are unique per owner rather than globally, so users cannot probe
+-- each other's titles. Unowned notes share the nil owner key.
+ALTER TABLE notes
+    ADD COLUMN owner_key BINARY(16) AS (IFNULL(user_id, UNHEX(REPEAT('0', 32)))) STORED,
+    DROP INDEX title,
+    ADD UNIQUE INDEX idx_notes_owner_title (owner_key, title);
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS note_sources (
+    note_id BINARY(16) PRIMARY KEY NOT NULL,
+    url TEXT NOT NULL,
+    site_name VARCHAR(255) NULL,
+    author VARCHAR(255) NULL,
+    excerpt TEXT NULL,
+    clipped_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
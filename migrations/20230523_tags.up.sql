@@ -0,0 +1,16 @@
+-- Tags belong to the owner of the notes they label, like note titles.
+CREATE TABLE IF NOT EXISTS tags (
+    id BINARY(16) PRIMARY KEY NOT NULL,
+    user_id BINARY(16) NULL,
+    owner_key BINARY(16) AS (IFNULL(user_id, UNHEX(REPEAT('0', 32)))) STORED,
+    name VARCHAR(100) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE INDEX idx_tags_owner_name (owner_key, name)
+);
+
+CREATE TABLE IF NOT EXISTS note_tags (
+    note_id BINARY(16) NOT NULL,
+    tag_id BINARY(16) NOT NULL,
+    PRIMARY KEY (note_id, tag_id),
+    INDEX idx_note_tags_tag (tag_id, note_id)
+);
@@ -0,0 +1,6 @@
+DELETE FROM notes WHERE deleted_at IS NOT NULL;
+ALTER TABLE notes
+    DROP INDEX idx_notes_owner_live_title,
+    DROP COLUMN live_title,
+    ADD UNIQUE INDEX idx_notes_owner_title (owner_key, title);
+ALTER TABLE notes DROP INDEX idx_notes_deleted, DROP COLUMN deleted_at;
@@ -0,0 +1,11 @@
+-- Deleted notes stay in the trash until they are restored or purged.
+ALTER TABLE notes
+    ADD COLUMN deleted_at TIMESTAMP NULL,
+    ADD INDEX idx_notes_deleted (deleted_at);
+
+-- Only live notes hold on to their title: NULLs never collide in a unique
+-- index, so a trashed note does not block a new note with the same title.
+ALTER TABLE notes
+    ADD COLUMN live_title VARCHAR(255) AS (IF(deleted_at IS NULL, title, NULL)) STORED,
+    DROP INDEX idx_notes_owner_title,
+    ADD UNIQUE INDEX idx_notes_owner_live_title (owner_key, live_title);
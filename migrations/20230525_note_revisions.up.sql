@@ -0,0 +1,14 @@
+-- Every saved state of an edited note. Content is stored like in `notes`,
+-- compressed above the same threshold.
+CREATE TABLE IF NOT EXISTS note_revisions (
+    note_id BINARY(16) NOT NULL,
+    rev INT UNSIGNED NOT NULL,
+    title VARCHAR(255) NOT NULL,
+    content TEXT NOT NULL,
+    content_encoding VARCHAR(16) NOT NULL DEFAULT 'plain',
+    content_zstd MEDIUMBLOB NULL,
+    category VARCHAR(100),
+    published BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (note_id, rev)
+);
@@ -0,0 +1,2 @@
+-- Bumped by every edit; served as the ETag of the note.
+ALTER TABLE notes ADD COLUMN version INT UNSIGNED NOT NULL DEFAULT 1;
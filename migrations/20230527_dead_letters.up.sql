@@ -0,0 +1,13 @@
+-- Deliveries that failed, kept for inspection and replay.
+CREATE TABLE IF NOT EXISTS dead_letters (
+    id BIGINT UNSIGNED PRIMARY KEY NOT NULL AUTO_INCREMENT,
+    target VARCHAR(64) NOT NULL,
+    kind VARCHAR(64) NOT NULL,
+    payload MEDIUMTEXT NOT NULL,
+    error TEXT NOT NULL,
+    attempts INT UNSIGNED NOT NULL DEFAULT 1,
+    failed_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    replayed_at TIMESTAMP NULL,
+    last_replay_error TEXT NULL,
+    INDEX dead_letters_open_idx (replayed_at, id)
+);
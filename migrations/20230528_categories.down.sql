@@ -0,0 +1,2 @@
+ALTER TABLE notes DROP FOREIGN KEY fk_notes_category, DROP COLUMN category_id;
+DROP TABLE IF EXISTS categories;
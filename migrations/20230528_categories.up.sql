@@ -0,0 +1,27 @@
+-- Categories belong to the owner of the notes they group, like tags. The
+-- name stays on notes as well, kept in step with the category, so note
+-- queries need no join.
+CREATE TABLE IF NOT EXISTS categories (
+    id BINARY(16) PRIMARY KEY NOT NULL,
+    user_id BINARY(16) NULL,
+    owner_key BINARY(16) AS (IFNULL(user_id, UNHEX(REPEAT('0', 32)))) STORED,
+    name VARCHAR(100) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE INDEX idx_categories_owner_name (owner_key, name)
+);
+
+ALTER TABLE notes
+    ADD COLUMN category_id BINARY(16) NULL,
+    ADD CONSTRAINT fk_notes_category FOREIGN KEY (category_id) REFERENCES categories (id);
+
+-- One category per owner and name as the collation compares them, so
+-- "Work" and "work" end up in the same category.
+INSERT IGNORE INTO categories (id, user_id, name)
+    SELECT UUID_TO_BIN(UUID()), user_id, category FROM notes
+    WHERE category IS NOT NULL AND category <> '';
+
+UPDATE notes JOIN categories
+    ON categories.owner_key = notes.owner_key AND categories.name = notes.category
+SET notes.category_id = categories.id,
+    notes.category = categories.name,
+    notes.updated_at = notes.updated_at;
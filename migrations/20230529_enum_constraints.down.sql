@@ -0,0 +1,15 @@
+ALTER TABLE hook_scripts DROP CHECK chk_hook_scripts_hook;
+ALTER TABLE note_link_previews
+    DROP CHECK chk_note_link_previews_status,
+    DROP CHECK chk_note_link_previews_check_status;
+ALTER TABLE note_reports
+    DROP CHECK chk_note_reports_reason,
+    DROP CHECK chk_note_reports_state;
+ALTER TABLE moderation_queue
+    DROP CHECK chk_moderation_queue_action,
+    DROP CHECK chk_moderation_queue_status;
+ALTER TABLE moderation_rules
+    DROP CHECK chk_moderation_rules_kind,
+    DROP CHECK chk_moderation_rules_action;
+ALTER TABLE note_revisions DROP CHECK chk_note_revisions_published;
+ALTER TABLE notes DROP CHECK chk_notes_published;
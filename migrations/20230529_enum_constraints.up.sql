@@ -0,0 +1,26 @@
+-- Columns holding one of a fixed set of values accept only those values,
+-- the ones the enums they are read into can parse.
+ALTER TABLE notes
+    ADD CONSTRAINT chk_notes_published CHECK (published IN (0, 1));
+
+ALTER TABLE note_revisions
+    ADD CONSTRAINT chk_note_revisions_published CHECK (published IN (0, 1));
+
+ALTER TABLE moderation_rules
+    ADD CONSTRAINT chk_moderation_rules_kind CHECK (kind IN ('word', 'regex')),
+    ADD CONSTRAINT chk_moderation_rules_action CHECK (action IN ('allow', 'flag', 'unpublish', 'reject'));
+
+ALTER TABLE moderation_queue
+    ADD CONSTRAINT chk_moderation_queue_action CHECK (action IN ('allow', 'flag', 'unpublish', 'reject')),
+    ADD CONSTRAINT chk_moderation_queue_status CHECK (status IN ('pending', 'approved', 'removed'));
+
+ALTER TABLE note_reports
+    ADD CONSTRAINT chk_note_reports_reason CHECK (reason IN ('spam', 'abuse', 'illegal', 'other')),
+    ADD CONSTRAINT chk_note_reports_state CHECK (state IN ('open', 'reviewed', 'actioned'));
+
+ALTER TABLE note_link_previews
+    ADD CONSTRAINT chk_note_link_previews_status CHECK (status IN ('ok', 'failed')),
+    ADD CONSTRAINT chk_note_link_previews_check_status CHECK (check_status IN ('ok', 'redirected', 'broken'));
+
+ALTER TABLE hook_scripts
+    ADD CONSTRAINT chk_hook_scripts_hook CHECK (hook IN ('before_create', 'before_update'));
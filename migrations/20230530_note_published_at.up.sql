@@ -0,0 +1,8 @@
+-- When each note was last published. Notes published before it was
+-- recorded count from their last change.
+ALTER TABLE notes ADD COLUMN published_at TIMESTAMP NULL AFTER published;
+
+UPDATE notes
+SET published_at = COALESCE(updated_at, created_at),
+    updated_at = updated_at
+WHERE published = 1;
@@ -0,0 +1,5 @@
+ALTER TABLE notes
+    DROP CHECK chk_notes_status,
+    DROP INDEX idx_notes_status,
+    DROP COLUMN reviewer_id,
+    DROP COLUMN status;
@@ -0,0 +1,12 @@
+-- Where each note is in its editorial workflow, and who is to review it.
+-- Published notes start out published, the rest as drafts.
+ALTER TABLE notes
+    ADD COLUMN status VARCHAR(16) NOT NULL DEFAULT 'draft' AFTER published_at,
+    ADD COLUMN reviewer_id BINARY(16) NULL AFTER status,
+    ADD INDEX idx_notes_status (status),
+    ADD CONSTRAINT chk_notes_status CHECK (status IN ('draft', 'in_review', 'published', 'archived'));
+
+UPDATE notes
+SET status = 'published',
+    updated_at = updated_at
+WHERE published = 1;
@@ -0,0 +1,8 @@
+ALTER TABLE notes ADD COLUMN reviewer_id BINARY(16) NULL AFTER status;
+
+UPDATE notes
+JOIN note_reviews ON note_reviews.note_id = notes.id AND note_reviews.state = 'pending'
+SET notes.reviewer_id = note_reviews.reviewer_id,
+    notes.updated_at = notes.updated_at;
+
+DROP TABLE IF EXISTS note_reviews;
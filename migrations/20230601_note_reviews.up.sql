@@ -0,0 +1,23 @@
+-- Review requests, one per reviewer of a note, replacing the single
+-- `notes.reviewer_id`. Notes in review keep their reviewer as a pending
+-- review.
+CREATE TABLE IF NOT EXISTS note_reviews (
+    id BIGINT UNSIGNED NOT NULL AUTO_INCREMENT PRIMARY KEY,
+    note_id BINARY(16) NOT NULL,
+    reviewer_id BINARY(16) NOT NULL,
+    requested_by BINARY(16) NULL,
+    state VARCHAR(16) NOT NULL DEFAULT 'pending',
+    comment TEXT NULL,
+    requested_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    decided_at TIMESTAMP NULL,
+    INDEX idx_note_reviews_reviewer (reviewer_id, state),
+    INDEX idx_note_reviews_note (note_id, state),
+    CONSTRAINT chk_note_reviews_state CHECK (state IN ('pending', 'approved', 'rejected', 'cancelled'))
+);
+
+INSERT INTO note_reviews (note_id, reviewer_id, requested_by)
+SELECT id, reviewer_id, user_id
+FROM notes
+WHERE status = 'in_review' AND reviewer_id IS NOT NULL;
+
+ALTER TABLE notes DROP COLUMN reviewer_id;
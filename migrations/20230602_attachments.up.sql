@@ -0,0 +1,13 @@
+-- Metadata of the files attached to notes; the contents are in attachment
+-- storage under `storage_key`.
+CREATE TABLE IF NOT EXISTS attachments (
+    id BINARY(16) PRIMARY KEY NOT NULL,
+    note_id BINARY(16) NOT NULL,
+    filename VARCHAR(255) NOT NULL,
+    content_type VARCHAR(255) NOT NULL,
+    size_bytes BIGINT UNSIGNED NOT NULL,
+    sha256 CHAR(64) NOT NULL,
+    storage_key VARCHAR(255) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    INDEX idx_attachments_note (note_id, created_at)
+);
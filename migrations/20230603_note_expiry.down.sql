@@ -0,0 +1,3 @@
+ALTER TABLE notes
+    DROP INDEX idx_notes_expires_at,
+    DROP COLUMN expires_at;
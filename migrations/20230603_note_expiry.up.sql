@@ -0,0 +1,5 @@
+-- When a note self-destructs. Expired notes are hidden from reads right away
+-- and purged by the expiry sweep.
+ALTER TABLE notes
+    ADD COLUMN expires_at TIMESTAMP NULL AFTER status,
+    ADD INDEX idx_notes_expires_at (expires_at);
@@ -0,0 +1,10 @@
+-- Read receipts: when each user other than the owner first and last opened
+-- a note.
+CREATE TABLE IF NOT EXISTS note_reads (
+    note_id BINARY(16) NOT NULL,
+    user_id BINARY(16) NOT NULL,
+    first_read_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_read_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    read_count INT UNSIGNED NOT NULL DEFAULT 1,
+    PRIMARY KEY (note_id, user_id)
+);
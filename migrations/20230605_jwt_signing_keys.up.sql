@@ -0,0 +1,9 @@
+-- Keys user tokens are signed with. The private key is sealed with a key
+-- derived from JWT_SECRET; retired keys are dropped once no token they signed
+-- can still be valid.
+CREATE TABLE IF NOT EXISTS jwt_signing_keys (
+    kid VARCHAR(32) PRIMARY KEY NOT NULL,
+    private_key VARBINARY(255) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    retired_at TIMESTAMP NULL
+);
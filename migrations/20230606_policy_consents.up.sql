@@ -0,0 +1,15 @@
+-- Versions of the terms of service; the highest version is the current one.
+CREATE TABLE IF NOT EXISTS policy_versions (
+    version INT UNSIGNED PRIMARY KEY NOT NULL AUTO_INCREMENT,
+    title VARCHAR(255) NOT NULL,
+    body TEXT NOT NULL,
+    published_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS policy_consents (
+    user_id BINARY(16) NOT NULL,
+    version INT UNSIGNED NOT NULL,
+    accepted_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, version),
+    INDEX idx_policy_consents_version (version)
+);
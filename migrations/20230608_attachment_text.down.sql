@@ -0,0 +1,4 @@
+DROP INDEX idx_attachments_text ON attachments;
+ALTER TABLE attachments
+    DROP COLUMN attachment_text,
+    DROP COLUMN text_status;
@@ -0,0 +1,7 @@
+-- Text read from image attachments by OCR (src/ocr.rs), matched by
+-- GET /api/notes/search together with the note. `text_status` is NULL for
+-- attachments that are not read.
+ALTER TABLE attachments
+    ADD COLUMN attachment_text MEDIUMTEXT NULL,
+    ADD COLUMN text_status VARCHAR(16) NULL;
+CREATE FULLTEXT INDEX idx_attachments_text ON attachments (attachment_text);
@@ -0,0 +1,4 @@
+-- Fails on rows whose content is longer than 65535 bytes; compress or
+-- shorten them through the API before rolling this back.
+ALTER TABLE note_revisions MODIFY content TEXT NOT NULL;
+ALTER TABLE notes MODIFY content TEXT NOT NULL;
@@ -0,0 +1,5 @@
+-- TEXT holds at most 65535 bytes, less than the default content limit, so
+-- plain content is stored as MEDIUMTEXT like compressed content is stored
+-- as MEDIUMBLOB. See `validation::MAX_STORED_CONTENT_BYTES`.
+ALTER TABLE notes MODIFY content MEDIUMTEXT NOT NULL;
+ALTER TABLE note_revisions MODIFY content MEDIUMTEXT NOT NULL;
@@ -0,0 +1,4 @@
+ALTER TABLE attachments
+    DROP COLUMN voice_note,
+    DROP COLUMN transcript,
+    DROP COLUMN transcript_status;
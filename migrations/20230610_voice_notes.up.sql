@@ -0,0 +1,7 @@
+-- Audio attachments uploaded as voice notes and their transcripts
+-- (src/transcription.rs). `transcript_status` is NULL for attachments that
+-- are not transcribed.
+ALTER TABLE attachments
+    ADD COLUMN voice_note BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN transcript MEDIUMTEXT NULL,
+    ADD COLUMN transcript_status VARCHAR(16) NULL;
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS operation_errors;
+DROP TABLE IF EXISTS operations;
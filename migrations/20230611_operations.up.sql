@@ -0,0 +1,25 @@
+-- Long-running operations started by requests and polled at
+-- /api/operations/:id; each is the subject of a job of the same kind (see
+-- src/operation.rs). Results are in attachment storage under `result_key`.
+CREATE TABLE IF NOT EXISTS operations (
+    id BINARY(16) PRIMARY KEY NOT NULL,
+    kind VARCHAR(32) NOT NULL,
+    user_id BINARY(16) NULL,
+    status VARCHAR(16) NOT NULL DEFAULT 'pending',
+    total_items INT UNSIGNED NULL,
+    completed_items INT UNSIGNED NOT NULL DEFAULT 0,
+    result_key VARCHAR(255) NULL,
+    created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3),
+    updated_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3),
+    finished_at TIMESTAMP(3) NULL,
+    INDEX idx_operations_user (user_id, created_at)
+);
+
+-- What went wrong with single items of an operation that carried on anyway.
+CREATE TABLE IF NOT EXISTS operation_errors (
+    id BIGINT UNSIGNED AUTO_INCREMENT PRIMARY KEY,
+    operation_id BINARY(16) NOT NULL,
+    message TEXT NOT NULL,
+    created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3),
+    INDEX idx_operation_errors_operation (operation_id, id)
+);
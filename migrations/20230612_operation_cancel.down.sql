@@ -0,0 +1,3 @@
+ALTER TABLE operations
+    DROP COLUMN cancel_requested_at,
+    DROP COLUMN rolled_back_items;
@@ -0,0 +1,5 @@
+-- Cancellation of operations (src/operation.rs): when it was asked for, and
+-- how many of the items done were undone when the job stopped.
+ALTER TABLE operations
+    ADD COLUMN cancel_requested_at TIMESTAMP(3) NULL,
+    ADD COLUMN rolled_back_items INT UNSIGNED NULL;
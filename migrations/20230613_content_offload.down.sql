@@ -0,0 +1,5 @@
+-- Rows with content_encoding = 'external' keep only an excerpt in `content`;
+-- rewrite them through the API with CONTENT_OFFLOAD_BYTES unset before
+-- rolling this back.
+ALTER TABLE note_revisions DROP COLUMN content_key;
+ALTER TABLE notes DROP COLUMN content_key;
@@ -0,0 +1,5 @@
+-- Notes whose content is offloaded to attachment storage (src/offload.rs) have
+-- content_encoding = 'external' and the storage key of the body here.
+-- Revisions copy it like the rest of the content columns.
+ALTER TABLE notes ADD COLUMN content_key VARCHAR(255) NULL AFTER content_zstd;
+ALTER TABLE note_revisions ADD COLUMN content_key VARCHAR(255) NULL AFTER content_zstd;
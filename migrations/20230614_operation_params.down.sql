@@ -0,0 +1,2 @@
+ALTER TABLE operations
+    DROP COLUMN params;
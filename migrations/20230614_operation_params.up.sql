@@ -0,0 +1,4 @@
+-- What an operation was started with, as JSON, for jobs that take more than
+-- their subject, such as the filter of a zip export (src/archive.rs).
+ALTER TABLE operations
+    ADD COLUMN params TEXT NULL AFTER kind;
@@ -0,0 +1,7 @@
+ALTER TABLE attachments
+    DROP CHECK chk_attachments_text_status,
+    DROP CHECK chk_attachments_transcript_status;
+ALTER TABLE operations DROP CHECK chk_operations_status;
+ALTER TABLE jobs DROP CHECK chk_jobs_status;
+ALTER TABLE note_revisions DROP CHECK chk_note_revisions_content_encoding;
+ALTER TABLE notes DROP CHECK chk_notes_content_encoding;
@@ -0,0 +1,18 @@
+-- Closed-set columns added since 20230529_enum_constraints accept only the
+-- values the code reads them into. NULL still passes the nullable ones:
+-- attachments without text extraction or transcription have no status.
+ALTER TABLE notes
+    ADD CONSTRAINT chk_notes_content_encoding CHECK (content_encoding IN ('plain', 'zstd', 'external'));
+
+ALTER TABLE note_revisions
+    ADD CONSTRAINT chk_note_revisions_content_encoding CHECK (content_encoding IN ('plain', 'zstd', 'external'));
+
+ALTER TABLE jobs
+    ADD CONSTRAINT chk_jobs_status CHECK (status IN ('pending', 'running', 'done', 'failed'));
+
+ALTER TABLE operations
+    ADD CONSTRAINT chk_operations_status CHECK (status IN ('pending', 'running', 'succeeded', 'failed', 'cancelled'));
+
+ALTER TABLE attachments
+    ADD CONSTRAINT chk_attachments_text_status CHECK (text_status IN ('pending', 'running', 'done', 'failed')),
+    ADD CONSTRAINT chk_attachments_transcript_status CHECK (transcript_status IN ('pending', 'running', 'done', 'failed'));
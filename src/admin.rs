@@ -0,0 +1,78 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+
+use crate::{
+    alerts::request_context,
+    anomaly::Signal,
+    lockout,
+    service_account::{ServiceAccountPrincipal, SCOPE_ADMIN},
+    AppState,
+};
+
+pub const ADMIN_TOKEN_HEADER: &str = "x-admin-token";
+
+/// Guards `/api/admin/*` routes. The admin API is disabled entirely unless
+/// `ADMIN_TOKEN` is configured; service accounts with the `admin` scope are
+/// let through as well.
+pub async fn require_admin<B>(
+    State(data): State<Arc<AppState>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(expected) = data.settings.admin_token.as_deref() else {
+        let error_response = json!({
+            "status": "fail",
+            "message": "Admin API is disabled",
+        });
+        return (StatusCode::FORBIDDEN, Json(error_response)).into_response();
+    };
+
+    // Expiry and allowlist were checked when the key was authenticated.
+    if let Some(principal) = req.extensions().get::<ServiceAccountPrincipal>() {
+        return match principal.require(SCOPE_ADMIN) {
+            Ok(()) => next.run(req).await,
+            Err(response) => response.into_response(),
+        };
+    }
+
+    // Counted per client only: the token is shared, so counting failures
+    // against it would let any client lock the operators out.
+    let keys = lockout::lockout_keys(&data, &req, None);
+    if let Err(response) = lockout::admit(&data, &req, &keys).await {
+        return response;
+    }
+
+    let provided = req
+        .headers()
+        .get(ADMIN_TOKEN_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+
+    if !constant_time_eq(provided.as_bytes(), expected.as_bytes()) {
+        data.anomalies.record(
+            Signal::AuthFailure,
+            request_context(&req, data.settings.trust_forwarded_for),
+        );
+        lockout::record_failure(&data, &req, &keys);
+        let error_response = json!({
+            "status": "fail",
+            "message": "Invalid admin token",
+        });
+        return (StatusCode::UNAUTHORIZED, Json(error_response)).into_response();
+    }
+
+    data.logins.record_success(&keys);
+    next.run(req).await
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
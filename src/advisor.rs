@@ -0,0 +1,242 @@
+use axum::{extract::State, response::IntoResponse, Json};
+use serde::Serialize;
+use serde_json::json;
+use sqlx::{mysql::MySqlRow, Row};
+
+use crate::{
+    error::AppError,
+    model::BinaryId,
+    repository::{
+        SELECT_NOTES_AFTER, SELECT_NOTES_PAGE, SELECT_NOTE_BY_ID, SELECT_USER_NOTES_PAGE,
+    },
+    state::DbPool,
+    trash::SELECT_TRASHED_NOTES,
+};
+
+/// A hot query and representative parameters to EXPLAIN it with.
+struct HotQuery {
+    name: &'static str,
+    sql: &'static str,
+    params: fn() -> Vec<SampleParam>,
+}
+
+enum SampleParam {
+    Id(BinaryId),
+    Text(&'static str),
+    Int(i64),
+    Bool(bool),
+}
+
+/// The time notes are expired by in the sampled queries.
+const SAMPLE_NOW: &str = "2023-01-01 00:00:00";
+
+const HOT_QUERIES: &[HotQuery] = &[
+    HotQuery {
+        name: "list_notes_page",
+        sql: SELECT_NOTES_PAGE,
+        params: || {
+            vec![
+                SampleParam::Text(SAMPLE_NOW),
+                SampleParam::Int(10),
+                SampleParam::Int(0),
+            ]
+        },
+    },
+    HotQuery {
+        name: "list_notes_after_cursor",
+        sql: SELECT_NOTES_AFTER,
+        params: || {
+            vec![
+                SampleParam::Text(SAMPLE_NOW),
+                SampleParam::Id(BinaryId::from(uuid::Uuid::nil())),
+                SampleParam::Int(10),
+            ]
+        },
+    },
+    HotQuery {
+        name: "list_user_notes_page",
+        sql: SELECT_USER_NOTES_PAGE,
+        params: || {
+            vec![
+                SampleParam::Id(BinaryId::from(uuid::Uuid::nil())),
+                SampleParam::Text(SAMPLE_NOW),
+                SampleParam::Int(10),
+                SampleParam::Int(0),
+            ]
+        },
+    },
+    HotQuery {
+        name: "list_trash",
+        sql: SELECT_TRASHED_NOTES,
+        params: || {
+            vec![
+                SampleParam::Id(BinaryId::from(uuid::Uuid::nil())),
+                SampleParam::Id(BinaryId::from(uuid::Uuid::nil())),
+                SampleParam::Int(10),
+                SampleParam::Int(0),
+            ]
+        },
+    },
+    HotQuery {
+        name: "get_note_by_id",
+        sql: SELECT_NOTE_BY_ID,
+        params: || {
+            vec![
+                SampleParam::Id(BinaryId::from(uuid::Uuid::nil())),
+                SampleParam::Text(SAMPLE_NOW),
+            ]
+        },
+    },
+    HotQuery {
+        name: "list_notes_by_category",
+        sql: "SELECT * FROM notes WHERE category = ? ORDER BY created_at DESC LIMIT 10",
+        params: || vec![SampleParam::Text("work")],
+    },
+    HotQuery {
+        name: "list_notes_by_tag",
+        sql: "SELECT notes.* FROM notes JOIN note_tags ON note_tags.note_id = notes.id JOIN tags ON tags.id = note_tags.tag_id WHERE tags.name = ? ORDER BY notes.id LIMIT 10",
+        params: || vec![SampleParam::Text("work")],
+    },
+    HotQuery {
+        name: "list_published_notes",
+        sql: "SELECT * FROM notes WHERE published = ? ORDER BY created_at DESC LIMIT 10",
+        params: || vec![SampleParam::Bool(true)],
+    },
+    HotQuery {
+        name: "list_notes_created_after",
+        sql: "SELECT * FROM notes WHERE created_at >= ? ORDER BY created_at LIMIT 10",
+        params: || vec![SampleParam::Text("2023-01-01 00:00:00")],
+    },
+];
+
+#[derive(Debug, Serialize)]
+struct PlanStep {
+    table: Option<String>,
+    access_type: Option<String>,
+    possible_keys: Option<String>,
+    key: Option<String>,
+    rows: Option<i64>,
+    extra: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct QueryReport {
+    name: &'static str,
+    sql: &'static str,
+    plan: Vec<PlanStep>,
+    warnings: Vec<String>,
+}
+
+fn text_column(row: &MySqlRow, column: &str) -> Option<String> {
+    row.try_get::<Option<String>, _>(column)
+        .ok()
+        .flatten()
+        .or_else(|| {
+            row.try_get::<Option<Vec<u8>>, _>(column)
+                .ok()
+                .flatten()
+                .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+        })
+}
+
+fn int_column(row: &MySqlRow, column: &str) -> Option<i64> {
+    row.try_get::<Option<i64>, _>(column)
+        .ok()
+        .flatten()
+        .or_else(|| {
+            row.try_get::<Option<u64>, _>(column)
+                .ok()
+                .flatten()
+                .map(|rows| rows as i64)
+        })
+}
+
+fn plan_step(row: &MySqlRow) -> PlanStep {
+    PlanStep {
+        table: text_column(row, "table"),
+        access_type: text_column(row, "type"),
+        possible_keys: text_column(row, "possible_keys"),
+        key: text_column(row, "key"),
+        rows: int_column(row, "rows"),
+        extra: text_column(row, "Extra"),
+    }
+}
+
+fn warnings_for(plan: &[PlanStep]) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for step in plan {
+        let table = step.table.as_deref().unwrap_or("?");
+        if step.access_type.as_deref() == Some("ALL") {
+            warnings.push(format!("full table scan on `{}`", table));
+        }
+        if step.key.is_none() && step.access_type.is_some() {
+            warnings.push(format!("no index used for `{}`", table));
+        }
+        if let Some(extra) = step.extra.as_deref() {
+            if extra.contains("Using filesort") {
+                warnings.push(format!(
+                    "filesort on `{}`; consider an index matching ORDER BY",
+                    table
+                ));
+            }
+            if extra.contains("Using temporary") {
+                warnings.push(format!("temporary table on `{}`", table));
+            }
+        }
+    }
+    warnings
+}
+
+async fn explain(db: &sqlx::MySqlPool, hot_query: &HotQuery) -> Result<QueryReport, sqlx::Error> {
+    let sql = format!("EXPLAIN {}", hot_query.sql);
+    let mut query = sqlx::query(&sql);
+    for param in (hot_query.params)() {
+        query = match param {
+            SampleParam::Id(id) => query.bind(id),
+            SampleParam::Text(text) => query.bind(text),
+            SampleParam::Int(n) => query.bind(n),
+            SampleParam::Bool(b) => query.bind(b),
+        };
+    }
+
+    let plan = query
+        .fetch_all(db)
+        .await?
+        .iter()
+        .map(plan_step)
+        .collect::<Vec<_>>();
+
+    Ok(QueryReport {
+        name: hot_query.name,
+        sql: hot_query.sql,
+        warnings: warnings_for(&plan),
+        plan,
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/db/index-advisor",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Query plans and index suggestions"),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn index_advisor_handler(
+    State(db): State<DbPool>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut reports = Vec::with_capacity(HOT_QUERIES.len());
+    for hot_query in HOT_QUERIES {
+        let report = explain(&db, hot_query).await?;
+        reports.push(report);
+    }
+
+    let warning_count: usize = reports.iter().map(|report| report.warnings.len()).sum();
+
+    Ok(Json(json!({
+        "status": "success",
+        "warnings": warning_count,
+        "queries": reports,
+    })))
+}
@@ -0,0 +1,165 @@
+//! Operator alerts.
+//!
+//! Alerts are always written to the log and, when `ALERT_WEBHOOK_URL` is
+//! set, posted to that webhook as JSON. Delivery happens in the background so
+//! raising an alert never slows down the request that triggered it. Failed
+//! deliveries are kept as dead letters for an admin to replay; see
+//! [`crate::dead_letter`].
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use axum::http::{header, Request};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{client_ip::client_ip, dead_letter::DeadLetterStore, http_client::HttpClient};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Alert {
+    pub kind: &'static str,
+    pub message: String,
+    pub context: Value,
+    pub at: DateTime<Utc>,
+}
+
+/// What an alert should say about the request that triggered it.
+pub fn request_context<B>(req: &Request<B>, trust_forwarded_for: bool) -> Value {
+    json!({
+        "method": req.method().as_str(),
+        "path": req.uri().path(),
+        "client_ip": client_ip(req, trust_forwarded_for),
+        "user_agent": req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok()),
+    })
+}
+
+#[async_trait]
+pub trait AlertSink: Send + Sync {
+    /// Names the sink in dead letters.
+    fn name(&self) -> &'static str;
+
+    async fn deliver(&self, alert: &Alert) -> Result<(), String>;
+
+    /// Sends an alert recorded by a failed delivery again.
+    async fn redeliver(&self, payload: &Value) -> Result<(), String>;
+}
+
+pub struct LogSink;
+
+#[async_trait]
+impl AlertSink for LogSink {
+    fn name(&self) -> &'static str {
+        "log"
+    }
+
+    async fn deliver(&self, alert: &Alert) -> Result<(), String> {
+        tracing::warn!(kind = %alert.kind, context = %alert.context, "{}", alert.message);
+        Ok(())
+    }
+
+    async fn redeliver(&self, payload: &Value) -> Result<(), String> {
+        tracing::warn!(%payload, "Replayed alert");
+        Ok(())
+    }
+}
+
+pub struct WebhookSink {
+    http: Arc<HttpClient>,
+    url: String,
+}
+
+impl WebhookSink {
+    pub fn new(http: Arc<HttpClient>, url: String) -> Self {
+        Self { http, url }
+    }
+}
+
+#[async_trait]
+impl AlertSink for WebhookSink {
+    fn name(&self) -> &'static str {
+        "alert-webhook"
+    }
+
+    async fn deliver(&self, alert: &Alert) -> Result<(), String> {
+        self.redeliver(&json!(alert)).await
+    }
+
+    async fn redeliver(&self, payload: &Value) -> Result<(), String> {
+        let response = self
+            .http
+            .send("alert-webhook", self.http.post(&self.url).json(payload))
+            .await
+            .map_err(|err| err.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("webhook answered {}", response.status()));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct Alerts {
+    sinks: Vec<Arc<dyn AlertSink>>,
+    dead_letters: Option<Arc<dyn DeadLetterStore>>,
+}
+
+impl Alerts {
+    pub fn new(sinks: Vec<Arc<dyn AlertSink>>) -> Self {
+        Self {
+            sinks,
+            dead_letters: None,
+        }
+    }
+
+    /// Records failed deliveries in `dead_letters`.
+    pub fn with_dead_letters(mut self, dead_letters: Arc<dyn DeadLetterStore>) -> Self {
+        self.dead_letters = Some(dead_letters);
+        self
+    }
+
+    /// Logs alerts, and posts them to `webhook_url` when set.
+    pub fn with_webhook(http: Arc<HttpClient>, webhook_url: Option<String>) -> Self {
+        let mut sinks: Vec<Arc<dyn AlertSink>> = vec![Arc::new(LogSink)];
+        if let Some(url) = webhook_url {
+            sinks.push(Arc::new(WebhookSink::new(http, url)));
+        }
+        Self::new(sinks)
+    }
+
+    pub fn send(&self, alert: Alert) {
+        let alert = Arc::new(alert);
+        for sink in &self.sinks {
+            let sink = sink.clone();
+            let alert = alert.clone();
+            let dead_letters = self.dead_letters.clone();
+            tokio::spawn(async move {
+                let Err(err) = sink.deliver(&alert).await else {
+                    return;
+                };
+                tracing::error!(kind = %alert.kind, error = %err, "Failed to deliver alert");
+                if let Some(dead_letters) = dead_letters {
+                    let recorded = dead_letters
+                        .record(sink.name(), alert.kind, &json!(*alert), &err)
+                        .await;
+                    if let Err(err) = recorded {
+                        tracing::error!(error = ?err, "Failed to record a dead letter");
+                    }
+                }
+            });
+        }
+    }
+
+    /// Sends `payload` through the sink named `target` again.
+    pub async fn redeliver(&self, target: &str, payload: &Value) -> Result<(), String> {
+        let sink = self
+            .sinks
+            .iter()
+            .find(|sink| sink.name() == target)
+            .ok_or_else(|| format!("no '{}' sink is configured", target))?;
+        sink.redeliver(payload).await
+    }
+}
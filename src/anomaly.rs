@@ -0,0 +1,391 @@
+//! Lightweight abuse detection.
+//!
+//! Counts security-relevant signals in sliding windows. When a signal
+//! crosses its threshold an alert is raised and the matching traffic is
+//! throttled for a while: note deletes are refused with 429 and requests
+//! carrying credentials are slowed down. Admins can inspect the detector and
+//! lift throttles early.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    extract::State,
+    http::{header, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    admin::ADMIN_TOKEN_HEADER,
+    alerts::{Alert, Alerts},
+    clock::Clock,
+    events::{NoteEvent, NoteEventKind},
+    AppState,
+};
+
+/// Extra latency added to credentialed requests while failed
+/// authentications are spiking.
+const AUTH_THROTTLE_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Signal {
+    NoteDeleted,
+    AuthFailure,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Threshold {
+    pub limit: usize,
+    pub window: Duration,
+}
+
+impl Threshold {
+    fn covers(&self, hit: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        (now - hit).to_std().unwrap_or_default() <= self.window
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AnomalyOptions {
+    pub deletes: Threshold,
+    pub auth_failures: Threshold,
+    pub throttle_for: Duration,
+}
+
+impl Default for AnomalyOptions {
+    fn default() -> Self {
+        Self {
+            deletes: Threshold {
+                limit: 50,
+                window: Duration::from_secs(60),
+            },
+            auth_failures: Threshold {
+                limit: 20,
+                window: Duration::from_secs(60),
+            },
+            throttle_for: Duration::from_secs(300),
+        }
+    }
+}
+
+#[derive(Default)]
+struct SignalState {
+    hits: VecDeque<DateTime<Utc>>,
+    throttled_until: Option<DateTime<Utc>>,
+}
+
+pub struct AnomalyDetector {
+    options: AnomalyOptions,
+    signals: Mutex<HashMap<Signal, SignalState>>,
+    alerts: Arc<Alerts>,
+    clock: Arc<dyn Clock>,
+}
+
+impl AnomalyDetector {
+    pub fn new(options: AnomalyOptions, alerts: Arc<Alerts>, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            options,
+            signals: Mutex::new(HashMap::new()),
+            alerts,
+            clock,
+        }
+    }
+
+    fn threshold(&self, signal: Signal) -> Threshold {
+        match signal {
+            Signal::NoteDeleted => self.options.deletes,
+            Signal::AuthFailure => self.options.auth_failures,
+        }
+    }
+
+    pub fn record(&self, signal: Signal, context: Value) {
+        let threshold = self.threshold(signal);
+        let now = self.clock.now();
+        let mut signals = self.signals.lock().unwrap();
+        let state = signals.entry(signal).or_default();
+
+        state.hits.push_back(now);
+        while state
+            .hits
+            .front()
+            .is_some_and(|hit| !threshold.covers(*hit, now))
+        {
+            state.hits.pop_front();
+        }
+
+        let already_throttled = state.throttled_until.is_some_and(|until| until > now);
+        if state.hits.len() < threshold.limit || already_throttled {
+            return;
+        }
+
+        state.throttled_until = Some(later(now, self.options.throttle_for));
+        let count = state.hits.len();
+        state.hits.clear();
+        drop(signals);
+
+        self.alerts.send(Alert {
+            kind: "anomaly",
+            message: format!(
+                "{:?} spiked to {} in {}s, throttling for {}s",
+                signal,
+                count,
+                threshold.window.as_secs(),
+                self.options.throttle_for.as_secs()
+            ),
+            context: json!({
+                "signal": signal,
+                "count": count,
+                "last": context,
+            }),
+            at: self.clock.now(),
+        });
+    }
+
+    /// Remaining throttle time for `signal`, if it is throttled.
+    pub fn throttled(&self, signal: Signal) -> Option<Duration> {
+        let now = self.clock.now();
+        self.signals
+            .lock()
+            .unwrap()
+            .get(&signal)
+            .and_then(|state| state.throttled_until)
+            .and_then(|until| (until - now).to_std().ok())
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    pub fn lift_throttles(&self) {
+        for state in self.signals.lock().unwrap().values_mut() {
+            state.throttled_until = None;
+        }
+    }
+
+    pub fn snapshot(&self) -> Value {
+        let now = self.clock.now();
+        let signals = self.signals.lock().unwrap();
+        let report = [Signal::NoteDeleted, Signal::AuthFailure]
+            .into_iter()
+            .map(|signal| {
+                let threshold = self.threshold(signal);
+                let state = signals.get(&signal);
+                let recent = state.map_or(0, |state| {
+                    state
+                        .hits
+                        .iter()
+                        .filter(|hit| threshold.covers(**hit, now))
+                        .count()
+                });
+                let throttled_for = state
+                    .and_then(|state| state.throttled_until)
+                    .filter(|until| *until > now)
+                    .map(|until| (until - now).num_seconds());
+                json!({
+                    "signal": signal,
+                    "recent": recent,
+                    "limit": threshold.limit,
+                    "window_seconds": threshold.window.as_secs(),
+                    "throttled_for_seconds": throttled_for,
+                })
+            })
+            .collect::<Vec<_>>();
+        json!(report)
+    }
+}
+
+fn later(now: DateTime<Utc>, by: Duration) -> DateTime<Utc> {
+    chrono::Duration::from_std(by)
+        .ok()
+        .and_then(|by| now.checked_add_signed(by))
+        .unwrap_or(DateTime::<Utc>::MAX_UTC)
+}
+
+/// Feeds note deletions, to the trash or for good, from the change hub into
+/// the detector.
+pub fn spawn_event_listener(
+    detector: Arc<AnomalyDetector>,
+    mut events: broadcast::Receiver<NoteEvent>,
+) {
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event)
+                    if matches!(event.kind, NoteEventKind::Trashed | NoteEventKind::Deleted) =>
+                {
+                    detector.record(Signal::NoteDeleted, json!({ "note_id": event.note_id }));
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!(skipped, "Anomaly detector skipped change events");
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+fn has_credentials<B>(req: &Request<B>) -> bool {
+    req.headers().contains_key(ADMIN_TOKEN_HEADER)
+        || req.headers().contains_key(header::AUTHORIZATION)
+}
+
+/// Applies the throttles the detector has put in place.
+pub async fn throttle_anomalies<B>(
+    State(data): State<Arc<AppState>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if req.method() == Method::DELETE && req.uri().path().starts_with("/api/notes") {
+        if let Some(remaining) = data.anomalies.throttled(Signal::NoteDeleted) {
+            let error_response = json!({
+                "status": "fail",
+                "message": "Deletes are temporarily throttled after an unusual spike",
+            });
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, remaining.as_secs().max(1).to_string())],
+                Json(error_response),
+            )
+                .into_response();
+        }
+    }
+
+    if has_credentials(&req) && data.anomalies.throttled(Signal::AuthFailure).is_some() {
+        tokio::time::sleep(AUTH_THROTTLE_DELAY).await;
+    }
+
+    next.run(req).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/anomalies",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Recent anomalies and active throttles"),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn anomalies_handler(State(data): State<Arc<AppState>>) -> impl IntoResponse {
+    Json(json!({
+        "status": "success",
+        "signals": data.anomalies.snapshot(),
+    }))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/admin/anomalies/throttles",
+    tag = "admin",
+    responses(
+        (status = 204, description = "All throttles were lifted"),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn lift_throttles_handler(State(data): State<Arc<AppState>>) -> impl IntoResponse {
+    data.anomalies.lift_throttles();
+    tracing::info!("Anomaly throttles lifted");
+    StatusCode::NO_CONTENT
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Method;
+    use chrono::Duration as ChronoDuration;
+
+    use crate::testing::TestApp;
+
+    use super::*;
+
+    fn delete(app: &TestApp, times: usize) {
+        for _ in 0..times {
+            app.state
+                .anomalies
+                .record(Signal::NoteDeleted, json!({ "note_id": null }));
+        }
+    }
+
+    fn recent(app: &TestApp, signal: &str) -> Value {
+        app.state
+            .anomalies
+            .snapshot()
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|report| report["signal"] == signal)
+            .cloned()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn crossing_the_threshold_alerts_once_and_throttles() {
+        let app = TestApp::new();
+        delete(&app, 49);
+        assert!(app.state.anomalies.throttled(Signal::NoteDeleted).is_none());
+        assert_eq!(recent(&app, "note_deleted")["recent"], 49);
+
+        delete(&app, 1);
+        assert_eq!(
+            app.state.anomalies.throttled(Signal::NoteDeleted),
+            Some(Duration::from_secs(300))
+        );
+        // Other signals are not affected.
+        assert!(app.state.anomalies.throttled(Signal::AuthFailure).is_none());
+
+        // More of the same while throttled raises no new alert.
+        delete(&app, 60);
+        let alerts = app.alerts().await;
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0]["kind"], "anomaly");
+        assert_eq!(alerts[0]["context"]["count"], 50);
+
+        app.clock.advance(ChronoDuration::seconds(299));
+        assert_eq!(
+            app.state.anomalies.throttled(Signal::NoteDeleted),
+            Some(Duration::from_secs(1))
+        );
+        app.clock.advance(ChronoDuration::seconds(1));
+        assert!(app.state.anomalies.throttled(Signal::NoteDeleted).is_none());
+    }
+
+    #[tokio::test]
+    async fn only_hits_within_the_window_count() {
+        let app = TestApp::new();
+        delete(&app, 30);
+        app.clock.advance(ChronoDuration::seconds(60));
+        // Still inside the window, just.
+        assert_eq!(recent(&app, "note_deleted")["recent"], 30);
+        app.clock.advance(ChronoDuration::seconds(1));
+        assert_eq!(recent(&app, "note_deleted")["recent"], 0);
+
+        delete(&app, 30);
+        assert!(app.state.anomalies.throttled(Signal::NoteDeleted).is_none());
+        app.clock.advance(ChronoDuration::seconds(30));
+        delete(&app, 20);
+        assert!(app.state.anomalies.throttled(Signal::NoteDeleted).is_some());
+    }
+
+    #[tokio::test]
+    async fn throttled_deletes_are_refused_until_lifted() {
+        let app = TestApp::new();
+        let uri = format!("/api/notes/{}", uuid::Uuid::new_v4());
+        delete(&app, 50);
+
+        let (status, headers, _) = app.send(Method::DELETE, &uri, None, None).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(headers[header::RETRY_AFTER], "300");
+        assert_eq!(recent(&app, "note_deleted")["throttled_for_seconds"], 300);
+
+        app.state.anomalies.lift_throttles();
+        let (status, _, _) = app.send(Method::DELETE, &uri, None, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
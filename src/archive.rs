@@ -0,0 +1,539 @@
+//! Zip archives of notes, for partial backups.
+//!
+//! `POST /api/export/zip` starts an operation (see [`crate::operation`])
+//! over the notes in scope matching a filter of category, tag and creation
+//! dates. Its `zip_export` job writes each note as a Markdown file,
+//! `notes/{title}.md` with the title, category, tags and times as front
+//! matter, and the note's attachments next to it under
+//! `attachments/{title}/`, linked from the end of the note. The archive is
+//! the operation's result, downloaded from `/api/operations/:id/result`.
+//! Cancelling the operation stops the job before its next page of notes and
+//! drops the archive.
+//!
+//! Notes have no notebooks here, so there is no notebook filter and the
+//! archive is not grouped by notebook: every note is in `notes/`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    io::{Cursor, Write},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use axum::{body::Bytes, extract::State, response::Response, Json};
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::mysql::MySqlPool;
+use utoipa::ToSchema;
+use zip::{result::ZipResult, write::FileOptions, CompressionMethod, ZipWriter};
+
+use crate::{
+    attachment::AttachmentModel,
+    auth::NoteScope,
+    error::AppError,
+    export::file_stem,
+    job::JobHandler,
+    loader::in_list,
+    model::{BinaryId, NoteId, NoteModel},
+    ocr::read_attachment,
+    operation,
+    repository::{NoteFilter, NoteSort},
+    AppState,
+};
+
+pub const JOB_KIND: &str = "zip_export";
+/// Notes read at once, and how often progress is recorded.
+const EXPORT_PAGE_SIZE: usize = 100;
+
+/// Which notes a zip export takes; fields left out match every note.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ZipExportSchema {
+    /// Name of the notes' category; empty for uncategorized notes.
+    pub category: Option<String>,
+    /// Name of a tag of the notes.
+    pub tag: Option<String>,
+    /// Created at or after.
+    pub created_after: Option<DateTime<Utc>>,
+    /// Created before.
+    pub created_before: Option<DateTime<Utc>>,
+}
+
+impl ZipExportSchema {
+    fn filter(self) -> NoteFilter {
+        NoteFilter {
+            tag: self.tag,
+            category: self.category,
+            created_after: self.created_after,
+            created_before: self.created_before,
+            ..Default::default()
+        }
+    }
+}
+
+/// A note to archive, with the names of its tags and its attachments that
+/// could be read.
+struct ArchivedNote {
+    note: NoteModel,
+    tags: Vec<String>,
+    attachments: Vec<(AttachmentModel, Bytes)>,
+}
+
+/// `filename` with anything but letters, digits, `-`, `_` and `.` replaced,
+/// and without leading dots, so it names a file in the attachments folder
+/// of its note and nowhere else.
+fn attachment_name(filename: &str) -> String {
+    let name = filename
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect::<String>();
+    let name = name.trim_start_matches('.');
+    if name.is_empty() {
+        "attachment".to_string()
+    } else {
+        name.chars().take(100).collect()
+    }
+}
+
+/// The file of `note`: front matter, the content as written, and links to
+/// the `attachments` stored under `attachments/{name}/`.
+fn markdown(note: &NoteModel, tags: &[String], name: &str, attachments: &[String]) -> String {
+    // JSON strings are YAML strings too, quoted and escaped.
+    let mut markdown = format!(
+        "---\nid: {}\ntitle: {}\ncategory: {}\ntags: {}\n",
+        json!(note.id),
+        json!(note.title.as_str()),
+        json!(note.category),
+        json!(tags),
+    );
+    for (field, at) in [
+        ("created_at", note.created_at),
+        ("updated_at", note.updated_at),
+    ] {
+        if let Some(at) = at {
+            markdown.push_str(&format!("{}: {}\n", field, json!(at)));
+        }
+    }
+    markdown.push_str("---\n\n");
+    markdown.push_str(&note.content);
+    if !attachments.is_empty() {
+        markdown.push_str("\n\n## Attachments\n\n");
+        for attachment in attachments {
+            markdown.push_str(&format!(
+                "- [{}](../attachments/{}/{})\n",
+                attachment, name, attachment
+            ));
+        }
+    }
+    markdown
+}
+
+/// The time zip entries of `note` are dated with.
+fn modified(note: &NoteModel) -> zip::DateTime {
+    note.updated_at
+        .or(note.created_at)
+        .and_then(|at| {
+            zip::DateTime::from_date_and_time(
+                at.year().try_into().ok()?,
+                at.month() as u8,
+                at.day() as u8,
+                at.hour() as u8,
+                at.minute() as u8,
+                at.second() as u8,
+            )
+            .ok()
+        })
+        .unwrap_or_default()
+}
+
+/// A zip archive of notes, written in memory.
+struct Archive {
+    writer: ZipWriter<Cursor<Vec<u8>>>,
+    /// The names notes were given, to keep them apart.
+    names: HashSet<String>,
+}
+
+impl Archive {
+    fn new() -> Self {
+        Self {
+            writer: ZipWriter::new(Cursor::new(Vec::new())),
+            names: HashSet::new(),
+        }
+    }
+
+    /// Adds `archived` under the file name of its title, or the title and
+    /// its ID when another note took that name.
+    fn add(&mut self, archived: &ArchivedNote) -> ZipResult<()> {
+        let note = &archived.note;
+        let mut name = file_stem(note);
+        if !self.names.insert(name.clone()) {
+            name = format!("{}-{}", name, note.id);
+            self.names.insert(name.clone());
+        }
+        let options = FileOptions::default().last_modified_time(modified(note));
+
+        let mut files = Vec::with_capacity(archived.attachments.len());
+        for (attachment, contents) in &archived.attachments {
+            let mut file = attachment_name(&attachment.filename);
+            if files.contains(&file) {
+                file = format!("{}-{}", attachment.id, file);
+            }
+            // Mostly images and recordings, compressed already.
+            self.writer.start_file(
+                format!("attachments/{}/{}", name, file),
+                options.compression_method(CompressionMethod::Stored),
+            )?;
+            self.writer.write_all(contents)?;
+            files.push(file);
+        }
+
+        self.writer
+            .start_file(format!("notes/{}.md", name), options)?;
+        self.writer
+            .write_all(markdown(note, &archived.tags, &name, &files).as_bytes())?;
+        Ok(())
+    }
+
+    fn finish(mut self) -> ZipResult<Vec<u8>> {
+        Ok(self.writer.finish()?.into_inner())
+    }
+}
+
+/// The attachments of the notes `ids`, by note.
+async fn attachments_of(
+    db: &MySqlPool,
+    ids: &[NoteId],
+) -> Result<HashMap<NoteId, Vec<AttachmentModel>>, sqlx::Error> {
+    let mut query = in_list("SELECT * FROM attachments WHERE note_id IN", ids);
+    query.push(" ORDER BY created_at, id");
+    let rows = query
+        .build_query_as::<AttachmentModel>()
+        .fetch_all(db)
+        .await?;
+    let mut attachments: HashMap<NoteId, Vec<AttachmentModel>> = HashMap::new();
+    for attachment in rows {
+        attachments
+            .entry(attachment.note_id)
+            .or_default()
+            .push(attachment);
+    }
+    Ok(attachments)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/export/zip",
+    tag = "operations",
+    request_body = ZipExportSchema,
+    responses(
+        (status = 202, description = "The export operation, polled at its Location"),
+        (status = 400, description = "Invalid date range", body = ErrorResponse),
+    ),
+)]
+pub async fn export_zip_handler(
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+    Json(body): Json<ZipExportSchema>,
+) -> Result<Response, AppError> {
+    if let (Some(after), Some(before)) = (body.created_after, body.created_before) {
+        if after >= before {
+            return Err(AppError::Validation(
+                "'created_after' must be before 'created_before'".to_string(),
+            ));
+        }
+    }
+    let operation = operation::start(&data, JOB_KIND, scope.owner(), Some(json!(body))).await?;
+    Ok(operation::accepted(&operation))
+}
+
+/// Runs the `zip_export` jobs of `POST /api/export/zip`.
+pub struct ZipExportJob;
+
+impl ZipExportJob {
+    async fn export(
+        &self,
+        data: &AppState,
+        id: BinaryId,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let Some(export) = operation::find(&data.db, id).await? else {
+            return Ok(());
+        };
+        let owner = export.user_id;
+        let filter = export
+            .params
+            .as_deref()
+            .map(serde_json::from_str::<ZipExportSchema>)
+            .transpose()?
+            .unwrap_or_default()
+            .filter();
+        let total = data.notes.count_filtered(&filter, owner).await?;
+        let Some(cancel) = operation::begin(&data.db, id, total as u32, data.clock.now()).await?
+        else {
+            return Ok(());
+        };
+
+        let mut archive = Archive::new();
+        let mut archived = 0;
+        let mut after = None;
+        loop {
+            if cancel.is_cancelled().await? {
+                // The archive is dropped unfinished, so every note in it is undone.
+                operation::cancelled(&data.db, id, archived, data.clock.now()).await?;
+                return Ok(());
+            }
+            let page = data
+                .notes
+                .list_filtered(
+                    &filter,
+                    NoteSort::default(),
+                    owner,
+                    after,
+                    EXPORT_PAGE_SIZE,
+                    0,
+                )
+                .await?;
+            let Some(last) = page.last() else {
+                break;
+            };
+            after = Some(last.id);
+
+            let ids = page.iter().map(|note| note.id).collect::<Vec<_>>();
+            let mut tags = match data.notes.tags(&ids).await {
+                Ok(tags) => tags,
+                Err(err) => {
+                    let message = format!("Left out the tags of {} notes: {}", ids.len(), err);
+                    operation::record_error(&data.db, id, &message, data.clock.now()).await?;
+                    Default::default()
+                }
+            };
+            let mut attachments = match attachments_of(&data.db, &ids).await {
+                Ok(attachments) => attachments,
+                Err(err) => {
+                    let message =
+                        format!("Left out the attachments of {} notes: {}", ids.len(), err);
+                    operation::record_error(&data.db, id, &message, data.clock.now()).await?;
+                    Default::default()
+                }
+            };
+
+            let mut notes = Vec::with_capacity(page.len());
+            for note in page {
+                let mut files = Vec::new();
+                for attachment in attachments.remove(&note.id).unwrap_or_default() {
+                    match read_attachment(data, &attachment).await {
+                        Ok(contents) => files.push((attachment, contents)),
+                        Err(err) => {
+                            let message = format!(
+                                "Left out attachment {} of note {}: {}",
+                                attachment.id, note.id, err
+                            );
+                            operation::record_error(&data.db, id, &message, data.clock.now())
+                                .await?;
+                        }
+                    }
+                }
+                let tags = tags
+                    .remove(&note.id)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|tag| tag.name)
+                    .collect();
+                notes.push(ArchivedNote {
+                    note,
+                    tags,
+                    attachments: files,
+                });
+            }
+
+            archived += notes.len() as u32;
+            archive = tokio::task::spawn_blocking(move || {
+                for note in &notes {
+                    archive.add(note)?;
+                }
+                ZipResult::Ok(archive)
+            })
+            .await
+            .expect("archiving does not panic")?;
+            operation::advance(&data.db, id, archived, data.clock.now()).await?;
+        }
+
+        let contents = tokio::task::spawn_blocking(move || archive.finish())
+            .await
+            .expect("archiving does not panic")?;
+        let key = format!("exports/{}.zip", id);
+        data.attachments.put(&key, Bytes::from(contents)).await?;
+        operation::succeed(&data.db, id, &key, data.clock.now()).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl JobHandler for ZipExportJob {
+    fn kind(&self) -> &'static str {
+        JOB_KIND
+    }
+
+    async fn run(&self, data: &AppState, subject: BinaryId) -> Result<(), String> {
+        self.export(data, subject)
+            .await
+            .map_err(|err| err.to_string())
+    }
+
+    async fn give_up(&self, data: &AppState, subject: BinaryId, _error: &str) {
+        operation::fail(data, subject).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use axum::http::StatusCode;
+    use chrono::TimeZone;
+    use zip::ZipArchive;
+
+    use crate::{handler::new_note, schema::CreateNoteSchema, testing::TestApp};
+
+    use super::*;
+
+    fn note(app: &TestApp, title: &str, content: &str) -> NoteModel {
+        let body = CreateNoteSchema {
+            title: title.to_string(),
+            content: content.to_string(),
+            category: Some("Travel".to_string()),
+            published: None,
+            expires_at: None,
+        };
+        let mut note =
+            new_note(&app.state, NoteId::from(uuid::Uuid::new_v4()), None, body).unwrap();
+        note.created_at = Some(Utc.with_ymd_and_hms(2023, 5, 3, 12, 0, 0).unwrap());
+        note.updated_at = note.created_at;
+        note
+    }
+
+    fn attachment(note: &NoteModel, filename: &str) -> AttachmentModel {
+        let id = BinaryId::from(uuid::Uuid::new_v4());
+        AttachmentModel {
+            id,
+            note_id: note.id,
+            filename: filename.to_string(),
+            content_type: "image/png".to_string(),
+            size_bytes: 4,
+            sha256: String::new(),
+            storage_key: format!("{}/{}", note.id, id),
+            attachment_text: None,
+            text_status: None,
+            voice_note: false,
+            transcript: None,
+            transcript_status: None,
+            created_at: Utc.with_ymd_and_hms(2023, 5, 3, 12, 0, 0).unwrap(),
+        }
+    }
+
+    fn read(archive: &mut ZipArchive<Cursor<Vec<u8>>>, name: &str) -> Vec<u8> {
+        let mut contents = Vec::new();
+        archive
+            .by_name(name)
+            .unwrap_or_else(|err| panic!("{}: {}", name, err))
+            .read_to_end(&mut contents)
+            .unwrap();
+        contents
+    }
+
+    #[tokio::test]
+    async fn notes_are_written_as_markdown_next_to_their_attachments() {
+        let app = TestApp::new();
+        let trip = note(&app, "Trip: Lisbon", "Photos attached");
+        let beach = attachment(&trip, "beach.png");
+        let sneaky = attachment(&trip, "../../etc/passwd");
+        let again = attachment(&trip, "beach.png");
+        // Another note whose title makes the same file name.
+        let other = note(&app, "Trip? Lisbon", "Second trip");
+
+        let mut archive = Archive::new();
+        archive
+            .add(&ArchivedNote {
+                note: trip.clone(),
+                tags: vec!["holiday".to_string()],
+                attachments: vec![
+                    (beach, Bytes::from_static(b"\x89PNG")),
+                    (sneaky, Bytes::from_static(b"root")),
+                    (again.clone(), Bytes::from_static(b"\x89PNG")),
+                ],
+            })
+            .unwrap();
+        archive
+            .add(&ArchivedNote {
+                note: other.clone(),
+                tags: Vec::new(),
+                attachments: Vec::new(),
+            })
+            .unwrap();
+        let mut archive = ZipArchive::new(Cursor::new(archive.finish().unwrap())).unwrap();
+
+        let markdown = String::from_utf8(read(&mut archive, "notes/Trip--Lisbon.md")).unwrap();
+        assert!(markdown.starts_with(&format!(
+            "---\nid: \"{}\"\ntitle: \"Trip: Lisbon\"\ncategory: \"Travel\"\ntags: [\"holiday\"]\n\
+             created_at: \"2023-05-03T12:00:00Z\"\nupdated_at: \"2023-05-03T12:00:00Z\"\n---\n\n\
+             Photos attached\n\n## Attachments\n\n",
+            trip.id
+        )));
+        assert!(markdown.contains("- [beach.png](../attachments/Trip--Lisbon/beach.png)\n"));
+        assert_eq!(
+            read(&mut archive, "attachments/Trip--Lisbon/beach.png"),
+            b"\x89PNG"
+        );
+        assert_eq!(
+            read(
+                &mut archive,
+                &format!("attachments/Trip--Lisbon/{}-beach.png", again.id)
+            ),
+            b"\x89PNG"
+        );
+        // Attachment names do not get to leave their folder.
+        assert_eq!(
+            read(&mut archive, "attachments/Trip--Lisbon/-..-etc-passwd"),
+            b"root"
+        );
+        assert!(archive
+            .file_names()
+            .all(|name| name.starts_with("notes/") || name.starts_with("attachments/")));
+
+        let markdown = read(&mut archive, &format!("notes/Trip--Lisbon-{}.md", other.id));
+        assert!(String::from_utf8(markdown)
+            .unwrap()
+            .ends_with("---\n\nSecond trip"));
+    }
+
+    #[tokio::test]
+    async fn empty_date_ranges_are_refused() {
+        let app = TestApp::new();
+        let (status, body) = app
+            .post(
+                "/api/export/zip",
+                json!({
+                    "created_after": "2023-06-01T00:00:00Z",
+                    "created_before": "2023-05-01T00:00:00Z",
+                }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    }
+
+    #[tokio::test]
+    async fn notebook_filters_are_refused_rather_than_ignored() {
+        let app = TestApp::new();
+        let (status, _) = app
+            .post("/api/export/zip", json!({ "notebook": "Work" }))
+            .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+}
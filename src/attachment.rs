@@ -0,0 +1,624 @@
+//! Files attached to notes.
+//!
+//! `POST /api/notes/:id/attachments` takes a `multipart/form-data` upload
+//! with the file in its `file` field, up to `ATTACHMENT_MAX_BYTES`. The file
+//! goes to an [`AttachmentStorage`] and its metadata to the `attachments`
+//! table; `GET /api/notes/:id/attachments/:aid` streams it back. Storage is
+//! a directory on local disk (`ATTACHMENT_DIR`); an object store implements
+//! the same trait. Attachments go when their note is purged. The text of
+//! images is extracted in the background by [`crate::ocr`], and recordings
+//! uploaded with `?voice_note=true` are transcribed by
+//! [`crate::transcription`].
+
+use std::{io, path::PathBuf, sync::Arc};
+
+use async_trait::async_trait;
+use axum::{
+    body::{Bytes, StreamBody},
+    extract::{multipart::Field, Multipart, Path, Query, State},
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::AsyncRead,
+    sync::broadcast::{self, error::RecvError},
+};
+use tokio_util::io::ReaderStream;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    auth::NoteScope,
+    error::AppError,
+    events::{NoteEvent, NoteEventKind},
+    job::JobStatus,
+    model::{BinaryId, NoteId},
+    ocr, transcription,
+    validation::FieldErrors,
+    AppState,
+};
+
+pub const DEFAULT_MAX_BYTES: usize = 10 * 1024 * 1024;
+const MAX_FILENAME_CHARS: usize = 255;
+
+pub type AttachmentReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Where attachment contents live, under keys the caller picks.
+#[async_trait]
+pub trait AttachmentStorage: Send + Sync {
+    async fn put(&self, key: &str, contents: Bytes) -> io::Result<()>;
+
+    /// `None` when nothing is stored under `key`.
+    async fn open(&self, key: &str) -> io::Result<Option<AttachmentReader>>;
+
+    /// Succeeds when nothing is stored under `key`.
+    async fn delete(&self, key: &str) -> io::Result<()>;
+
+    /// Deletes everything stored under keys that start with `prefix/`.
+    async fn delete_all(&self, prefix: &str) -> io::Result<()>;
+}
+
+/// Stores each attachment as a file under `root`.
+pub struct LocalDiskStorage {
+    root: PathBuf,
+}
+
+impl LocalDiskStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+#[async_trait]
+impl AttachmentStorage for LocalDiskStorage {
+    async fn put(&self, key: &str, contents: Bytes) -> io::Result<()> {
+        let path = self.path(key);
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        // Readers never see half a file.
+        let partial = path.with_extension("part");
+        tokio::fs::write(&partial, contents).await?;
+        tokio::fs::rename(&partial, &path).await
+    }
+
+    async fn open(&self, key: &str) -> io::Result<Option<AttachmentReader>> {
+        match tokio::fs::File::open(self.path(key)).await {
+            Ok(file) => Ok(Some(Box::new(file))),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        let path = self.path(key);
+        match tokio::fs::remove_file(&path).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        // Drops the directory of the note once its last attachment is gone.
+        if let Some(dir) = path.parent().filter(|dir| *dir != self.root) {
+            let _ = tokio::fs::remove_dir(dir).await;
+        }
+        Ok(())
+    }
+
+    async fn delete_all(&self, prefix: &str) -> io::Result<()> {
+        match tokio::fs::remove_dir_all(self.path(prefix)).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct AttachmentModel {
+    pub id: BinaryId,
+    pub note_id: NoteId,
+    pub filename: String,
+    pub content_type: String,
+    pub size_bytes: u64,
+    /// Hex SHA-256 of the contents.
+    pub sha256: String,
+    #[serde(skip_serializing)]
+    pub storage_key: String,
+    /// Read from images by OCR.
+    pub attachment_text: Option<String>,
+    /// Of the text extraction; `None` when there is none to run.
+    pub text_status: Option<JobStatus>,
+    /// Uploaded as a recording to transcribe.
+    pub voice_note: bool,
+    pub transcript: Option<String>,
+    /// Of the transcription; `None` when there is none to run.
+    pub transcript_status: Option<JobStatus>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The `multipart/form-data` body of an upload.
+#[derive(ToSchema)]
+pub struct AttachmentUpload {
+    #[schema(value_type = String, format = Binary)]
+    pub file: Vec<u8>,
+}
+
+#[derive(Debug, Deserialize, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UploadOptions {
+    /// Whether the file is a recording to transcribe.
+    pub voice_note: Option<bool>,
+}
+
+fn attachment_not_found(id: impl std::fmt::Display) -> AppError {
+    AppError::NotFound(format!("Attachment with ID: {} not found", id))
+}
+
+/// The file name of an upload, without the directories some browsers send.
+fn clean_filename(name: Option<&str>) -> String {
+    let name = name
+        .unwrap_or_default()
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .trim();
+    if name.is_empty() {
+        "attachment".to_string()
+    } else {
+        name.chars().take(MAX_FILENAME_CHARS).collect()
+    }
+}
+
+/// `attachment; filename="..."`, with what a quoted ASCII string cannot hold
+/// replaced by `_`.
+fn disposition(filename: &str) -> HeaderValue {
+    let safe = filename
+        .chars()
+        .map(|c| {
+            if c.is_ascii_graphic() && c != '"' && c != '\\' || c == ' ' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    HeaderValue::from_str(&format!("attachment; filename=\"{}\"", safe))
+        .expect("file names are printable ASCII without quotes")
+}
+
+/// Reads the contents of `field`, failing once they pass `max_bytes`.
+async fn read_limited(mut field: Field<'_>, max_bytes: usize) -> Result<Bytes, AppError> {
+    let mut contents = Vec::new();
+    while let Some(chunk) = field
+        .chunk()
+        .await
+        .map_err(|err| AppError::Validation(err.to_string()))?
+    {
+        if contents.len() + chunk.len() > max_bytes {
+            return Err(AppError::Response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Json(json!({
+                    "status": "fail",
+                    "message": format!("Attachments must be at most {} bytes", max_bytes),
+                })),
+            ));
+        }
+        contents.extend_from_slice(&chunk);
+    }
+    Ok(Bytes::from(contents))
+}
+
+/// Fails unless note `id` is live and `scope` may see it.
+async fn check_note(data: &AppState, scope: &NoteScope, id: NoteId) -> Result<(), AppError> {
+    scope.check(data, id).await?;
+    if data.notes.find(id).await?.is_none() {
+        return Err(AppError::note_not_found(id));
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/notes/{id}/attachments",
+    tag = "notes",
+    params(("id" = Uuid, Path, description = "Note ID"), UploadOptions),
+    request_body(content = AttachmentUpload, content_type = "multipart/form-data"),
+    responses(
+        (status = 201, description = "The attachment"),
+        (status = 400, description = "Malformed upload", body = ErrorResponse),
+        (status = 404, description = "Note not found", body = ErrorResponse),
+        (status = 413, description = "File over ATTACHMENT_MAX_BYTES", body = ErrorResponse),
+        (status = 422, description = "No file in the upload, or a voice note that is not audio", body = ErrorResponse),
+    ),
+)]
+pub async fn upload_attachment_handler(
+    Path(id): Path<uuid::Uuid>,
+    opts: Option<Query<UploadOptions>>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, AppError> {
+    let Query(opts) = opts.unwrap_or_default();
+    let voice_note = opts.voice_note.unwrap_or(false);
+    let note_id = NoteId::from(id);
+    check_note(&data, &scope, note_id).await?;
+
+    let mut upload = None;
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|err| AppError::Validation(err.to_string()))?
+    {
+        if field.name() != Some("file") {
+            continue;
+        }
+        let filename = clean_filename(field.file_name());
+        let content_type = field
+            .content_type()
+            .filter(|content_type| HeaderValue::from_str(content_type).is_ok())
+            .unwrap_or("application/octet-stream")
+            .to_string();
+        let contents = read_limited(field, data.settings.max_attachment_bytes).await?;
+        upload = Some((filename, content_type, contents));
+        break;
+    }
+    let Some((filename, content_type, contents)) = upload else {
+        return Err(AppError::InvalidFields(FieldErrors::single(
+            "file",
+            "must be uploaded".to_string(),
+        )));
+    };
+
+    if voice_note && !transcription::is_audio(&content_type) {
+        return Err(AppError::InvalidFields(FieldErrors::single(
+            "voice_note",
+            format!("needs an audio file, not {}", content_type),
+        )));
+    }
+
+    let id = BinaryId::from(data.ids.generate());
+    let read_text = ocr::is_ocr_image(&content_type) && data.jobs.handles(ocr::JOB_KIND);
+    let transcribe = voice_note && data.jobs.handles(transcription::JOB_KIND);
+    let attachment = AttachmentModel {
+        id,
+        note_id,
+        filename,
+        content_type,
+        size_bytes: contents.len() as u64,
+        sha256: hex::encode(Sha256::digest(&contents)),
+        storage_key: format!("{}/{}", note_id, id),
+        attachment_text: None,
+        text_status: read_text.then_some(JobStatus::Pending),
+        voice_note,
+        transcript: None,
+        transcript_status: transcribe.then_some(JobStatus::Pending),
+        created_at: data.clock.now(),
+    };
+    data.attachments
+        .put(&attachment.storage_key, contents)
+        .await
+        .map_err(|err| {
+            tracing::error!(attachment_id = %id, error = ?err, "Failed to store attachment");
+            AppError::Response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"status": "error", "message": "Failed to store the attachment"})),
+            )
+        })?;
+
+    let inserted = async {
+        let mut tx = data.db.begin().await?;
+        sqlx::query(
+            r#"INSERT INTO attachments (id, note_id, filename, content_type, size_bytes, sha256, storage_key, text_status, voice_note, transcript_status, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
+        )
+        .bind(attachment.id)
+        .bind(attachment.note_id)
+        .bind(&attachment.filename)
+        .bind(&attachment.content_type)
+        .bind(attachment.size_bytes)
+        .bind(&attachment.sha256)
+        .bind(&attachment.storage_key)
+        .bind(attachment.text_status)
+        .bind(attachment.voice_note)
+        .bind(attachment.transcript_status)
+        .bind(attachment.created_at)
+        .execute(&mut tx)
+        .await?;
+        if read_text {
+            data.jobs
+                .enqueue(&mut tx, ocr::JOB_KIND, attachment.id, attachment.created_at)
+                .await?;
+        }
+        if transcribe {
+            data.jobs
+                .enqueue(
+                    &mut tx,
+                    transcription::JOB_KIND,
+                    attachment.id,
+                    attachment.created_at,
+                )
+                .await?;
+        }
+        tx.commit().await
+    }
+    .await;
+    if let Err(err) = inserted {
+        let _ = data.attachments.delete(&attachment.storage_key).await;
+        return Err(err.into());
+    }
+
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({"status": "success", "data": {"attachment": attachment}})),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/notes/{id}/attachments",
+    tag = "notes",
+    params(("id" = Uuid, Path, description = "Note ID")),
+    responses(
+        (status = 200, description = "The attachments of the note, oldest first"),
+        (status = 404, description = "Note not found", body = ErrorResponse),
+    ),
+)]
+pub async fn list_attachments_handler(
+    Path(id): Path<uuid::Uuid>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let note_id = NoteId::from(id);
+    check_note(&data, &scope, note_id).await?;
+    let attachments = sqlx::query_as::<_, AttachmentModel>(
+        "SELECT * FROM attachments WHERE note_id = ? ORDER BY created_at, id",
+    )
+    .bind(note_id)
+    .fetch_all(&data.db)
+    .await?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "results": attachments.len(),
+        "attachments": attachments,
+    })))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/notes/{id}/attachments/{aid}",
+    tag = "notes",
+    params(
+        ("id" = Uuid, Path, description = "Note ID"),
+        ("aid" = Uuid, Path, description = "Attachment ID"),
+    ),
+    responses(
+        (status = 200, description = "The file, with the content type it was uploaded with", body = String, content_type = "application/octet-stream"),
+        (status = 404, description = "Note or attachment not found", body = ErrorResponse),
+    ),
+)]
+pub async fn download_attachment_handler(
+    Path((id, aid)): Path<(uuid::Uuid, uuid::Uuid)>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    let note_id = NoteId::from(id);
+    check_note(&data, &scope, note_id).await?;
+    let attachment = sqlx::query_as::<_, AttachmentModel>(
+        "SELECT * FROM attachments WHERE id = ? AND note_id = ?",
+    )
+    .bind(BinaryId::from(aid))
+    .bind(note_id)
+    .fetch_optional(&data.db)
+    .await?
+    .ok_or_else(|| attachment_not_found(aid))?;
+
+    let reader = match data.attachments.open(&attachment.storage_key).await {
+        Ok(Some(reader)) => reader,
+        Ok(None) => {
+            tracing::warn!(attachment_id = %aid, "Attachment is missing from storage");
+            return Err(attachment_not_found(aid));
+        }
+        Err(err) => {
+            tracing::error!(attachment_id = %aid, error = ?err, "Failed to open attachment");
+            return Err(AppError::Response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"status": "error", "message": "Failed to read the attachment"})),
+            ));
+        }
+    };
+
+    let content_type = HeaderValue::from_str(&attachment.content_type)
+        .unwrap_or(HeaderValue::from_static("application/octet-stream"));
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type),
+            (
+                header::CONTENT_LENGTH,
+                HeaderValue::from(attachment.size_bytes),
+            ),
+            (
+                header::CONTENT_DISPOSITION,
+                disposition(&attachment.filename),
+            ),
+            (
+                header::X_CONTENT_TYPE_OPTIONS,
+                HeaderValue::from_static("nosniff"),
+            ),
+        ],
+        StreamBody::new(ReaderStream::new(reader)),
+    )
+        .into_response())
+}
+
+/// Removes the attachments of deleted notes, files and rows.
+pub fn spawn_note_cleanup(data: Arc<AppState>, mut events: broadcast::Receiver<NoteEvent>) {
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) if event.kind == NoteEventKind::Deleted => {
+                    if let Err(err) = remove_note_attachments(&data, event.note_id).await {
+                        tracing::error!(
+                            error = ?err,
+                            note_id = %event.note_id,
+                            "Failed to remove note attachments",
+                        );
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!(skipped, "Attachment cleanup skipped change events");
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+async fn remove_note_attachments(data: &AppState, note_id: NoteId) -> Result<(), AppError> {
+    let keys: Vec<(String,)> =
+        sqlx::query_as("SELECT storage_key FROM attachments WHERE note_id = ?")
+            .bind(note_id)
+            .fetch_all(&data.db)
+            .await?;
+    for (key,) in keys {
+        if let Err(err) = data.attachments.delete(&key).await {
+            tracing::error!(%key, error = ?err, "Failed to delete stored attachment");
+        }
+    }
+    sqlx::query("DELETE FROM attachments WHERE note_id = ?")
+        .bind(note_id)
+        .execute(&data.db)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Request};
+    use tokio::io::AsyncReadExt;
+
+    use crate::testing::TestApp;
+
+    use super::*;
+
+    const BOUNDARY: &str = "attachment-test-boundary";
+
+    /// A `multipart/form-data` upload of `contents` in the field `field`.
+    fn upload(uri: &str, field: &str, contents: &[u8]) -> Request<Body> {
+        let mut body = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"C:\\\\Users\\\\me\\\\notes.txt\"\r\nContent-Type: text/plain\r\n\r\n",
+            BOUNDARY, field
+        )
+        .into_bytes();
+        body.extend_from_slice(contents);
+        body.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+        Request::post(uri)
+            .header(
+                header::CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", BOUNDARY),
+            )
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    async fn create(app: &TestApp) -> String {
+        let (status, body) = app
+            .post(
+                "/api/notes",
+                json!({ "title": "Trip", "content": "Tickets" }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        body["data"]["note"]["id"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn file_names_lose_their_directories() {
+        assert_eq!(clean_filename(Some("C:\\Users\\me\\a.txt")), "a.txt");
+        assert_eq!(clean_filename(Some("../../etc/passwd")), "passwd");
+        assert_eq!(clean_filename(Some("dir/")), "attachment");
+        assert_eq!(clean_filename(None), "attachment");
+        assert_eq!(
+            disposition("say \"hi\"\r\n.txt"),
+            "attachment; filename=\"say _hi___.txt\""
+        );
+        assert_eq!(disposition("café.txt"), "attachment; filename=\"caf_.txt\"");
+    }
+
+    #[tokio::test]
+    async fn local_disk_storage_round_trips_and_cleans_up() {
+        let root = std::env::temp_dir().join(format!("attachments-{}", uuid::Uuid::new_v4()));
+        let storage = LocalDiskStorage::new(&root);
+        storage
+            .put("note/file", Bytes::from_static(b"contents"))
+            .await
+            .unwrap();
+
+        let mut read = String::new();
+        let mut reader = storage.open("note/file").await.unwrap().unwrap();
+        reader.read_to_string(&mut read).await.unwrap();
+        assert_eq!(read, "contents");
+
+        storage.delete("note/file").await.unwrap();
+        assert!(storage.open("note/file").await.unwrap().is_none());
+        assert!(!root.join("note").exists());
+        // Deleting what is not there is not an error.
+        storage.delete("note/file").await.unwrap();
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn uploads_need_a_live_note_and_a_file() {
+        let app = TestApp::new();
+        let missing = format!("/api/notes/{}/attachments", uuid::Uuid::new_v4());
+        let (status, _, _) = app.call(upload(&missing, "file", b"hi")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let id = create(&app).await;
+        let uri = format!("/api/notes/{}/attachments", id);
+        let (status, _, body) = app.call(upload(&uri, "other", b"hi")).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{}", body);
+    }
+
+    #[tokio::test]
+    async fn voice_notes_must_be_audio() {
+        let app = TestApp::new();
+        let id = create(&app).await;
+        let uri = format!("/api/notes/{}/attachments?voice_note=true", id);
+        let (status, _, body) = app.call(upload(&uri, "file", b"hi")).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{}", body);
+        assert!(body.to_string().contains("voice_note"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn uploads_over_the_limit_are_refused() {
+        let app = TestApp::new();
+        let id = create(&app).await;
+        let uri = format!("/api/notes/{}/attachments", id);
+        let contents = vec![b'x'; app.state.settings.max_attachment_bytes + 1];
+        let (status, _, _) = app.call(upload(&uri, "file", &contents)).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn files_of_failed_uploads_are_removed() {
+        let app = TestApp::new();
+        let id = create(&app).await;
+        let uri = format!("/api/notes/{}/attachments", id);
+
+        // The test app has no database to record the upload in.
+        let (status, _, _) = app.call(upload(&uri, "file", b"hi")).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        let dir = std::env::temp_dir()
+            .join("rust-axum-mysql-test-attachments")
+            .join(&id);
+        assert!(!dir.exists());
+    }
+}
@@ -0,0 +1,593 @@
+//! User accounts authenticated with JWTs.
+//!
+//! Accounts are enabled by setting `JWT_SECRET`. Users register and log in at
+//! `/api/auth/*` and send the returned token as `Authorization: Bearer ...`
+//! (service-account keys, which start with `sa_`, are left to
+//! `service_account`). Once enabled, the note API requires a user or a
+//! service account, and users only ever see the notes they own; service
+//! accounts keep unscoped access for automation. Passwords are stored as
+//! Argon2 hashes. Tokens are signed with rotating keys; see `signing_key`.
+
+use std::{sync::Arc, time::Duration};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
+use async_trait::async_trait;
+use axum::{
+    body::{Body, HttpBody},
+    extract::{FromRequestParts, State},
+    http::{header, request::Parts, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{Algorithm, DecodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::mysql::MySqlPool;
+use utoipa::ToSchema;
+
+use crate::{
+    alerts::request_context,
+    anomaly::Signal,
+    consent::Consent,
+    error::AppError,
+    lockout::{self, LockoutKey},
+    model::{NoteId, UserId},
+    service_account::ServiceAccountPrincipal,
+    signing_key::KeyRing,
+    AppState,
+};
+
+const MIN_PASSWORD_CHARS: usize = 8;
+/// Far more than any email and password pair needs.
+const MAX_CREDENTIALS_BYTES: usize = 4 * 1024;
+/// Paths that need a user or a service account once accounts are enabled.
+const PROTECTED_PREFIXES: &[&str] = &[
+    "/api/notes",
+    "/api/clip",
+    "/api/tags",
+    "/api/me/",
+    "/api/reviews",
+    "/api/graphql",
+    "/api/categories",
+];
+
+pub struct JwtAuth {
+    pub keys: KeyRing,
+    /// Terms of service users have to accept; see `consent`.
+    pub consent: Consent,
+    /// Verifies the HS256 tokens issued before signing keys were rotated,
+    /// for as long as [`KeyRing::accepts_legacy`] allows.
+    legacy: DecodingKey,
+    ttl: Duration,
+}
+
+impl JwtAuth {
+    pub fn new(secret: &[u8], ttl: Duration) -> Self {
+        Self {
+            keys: KeyRing::new(secret, ttl),
+            consent: Consent::default(),
+            legacy: DecodingKey::from_secret(secret),
+            ttl,
+        }
+    }
+
+    pub(crate) fn issue(&self, user: &UserModel, now: DateTime<Utc>) -> String {
+        let claims = Claims {
+            sub: user.id.to_string(),
+            email: user.email.clone(),
+            iat: now.timestamp(),
+            exp: now.timestamp() + self.ttl.as_secs() as i64,
+        };
+        let key = self.keys.signing_key();
+        let header = Header {
+            kid: Some(key.kid.clone()),
+            ..Header::new(Algorithm::EdDSA)
+        };
+        jsonwebtoken::encode(&header, &claims, &key.encoding).expect("Ed25519 signing cannot fail")
+    }
+
+    /// Tokens name the key they were signed with; one unknown here may come
+    /// from a key another instance just created, so the keys are reloaded.
+    async fn verify(
+        &self,
+        token: &str,
+        db: &MySqlPool,
+        now: DateTime<Utc>,
+    ) -> Option<UserPrincipal> {
+        let header = jsonwebtoken::decode_header(token).ok()?;
+        let claims = match header.kid {
+            Some(kid) => {
+                let key = match self.keys.find(&kid) {
+                    Some(key) => key,
+                    None => {
+                        self.keys.reload_for_unknown_key(db, now).await;
+                        self.keys.find(&kid)?
+                    }
+                };
+                let validation = Validation::new(Algorithm::EdDSA);
+                jsonwebtoken::decode::<Claims>(token, &key.decoding, &validation).ok()?
+            }
+            None => {
+                let validation = Validation::new(Algorithm::HS256);
+                let claims =
+                    jsonwebtoken::decode::<Claims>(token, &self.legacy, &validation).ok()?;
+                if !self.keys.accepts_legacy(claims.claims.iat, now) {
+                    return None;
+                }
+                claims
+            }
+        };
+        Some(UserPrincipal {
+            id: claims.claims.sub.parse().ok()?,
+            email: claims.claims.email,
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    sub: String,
+    email: String,
+    iat: i64,
+    exp: i64,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct UserModel {
+    pub id: UserId,
+    pub email: String,
+    pub password_hash: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The user a request was authenticated as, in the request extensions.
+#[derive(Debug, Clone)]
+pub struct UserPrincipal {
+    pub id: UserId,
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CredentialsSchema {
+    pub email: String,
+    pub password: String,
+}
+
+/// Whose notes a request may see: `None` for every note.
+#[derive(Debug, Clone, Copy)]
+pub struct NoteScope(pub Option<UserId>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for NoteScope {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(NoteScope(
+            parts
+                .extensions
+                .get::<UserPrincipal>()
+                .map(|principal| principal.id),
+        ))
+    }
+}
+
+impl NoteScope {
+    pub fn owner(&self) -> Option<UserId> {
+        self.0
+    }
+
+    /// Whether a note owned by `owner` is visible in this scope.
+    pub fn permits(&self, owner: Option<UserId>) -> bool {
+        self.0.is_none_or(|user| owner == Some(user))
+    }
+
+    /// Answers 404 for a note that exists but belongs to someone else, as if
+    /// it did not exist. Missing notes are left to the caller.
+    pub async fn check(&self, data: &AppState, id: NoteId) -> Result<(), AppError> {
+        if self.0.is_none() {
+            return Ok(());
+        }
+        match data.notes.find_owner(id).await? {
+            Some(owner) if !self.permits(owner) => Err(AppError::note_not_found(id)),
+            _ => Ok(()),
+        }
+    }
+
+    /// The owner of note `id`, live or trashed, answering 404 like
+    /// [`check`](Self::check) for a note of someone else's.
+    pub async fn owner_of(&self, data: &AppState, id: NoteId) -> Result<Option<UserId>, AppError> {
+        match data.notes.find_owner(id).await? {
+            Some(owner) if !self.permits(owner) => Err(AppError::note_not_found(id)),
+            owner => Ok(owner.flatten()),
+        }
+    }
+}
+
+fn unauthorized(message: &str) -> Response {
+    let error_response = json!({
+        "status": "fail",
+        "message": message,
+    });
+    (StatusCode::UNAUTHORIZED, Json(error_response)).into_response()
+}
+
+/// Authenticates user tokens and, once accounts are enabled, turns away
+/// anonymous requests to the note, tag, category and GraphQL APIs and the
+/// clipper. Runs after service-account authentication.
+pub async fn authenticate_user<B>(
+    State(data): State<Arc<AppState>>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(auth) = data.auth.as_ref() else {
+        return next.run(req).await;
+    };
+
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|token| !token.starts_with("sa_"));
+    if let Some(token) = token {
+        let Some(principal) = auth.verify(token, &data.db, data.clock.now()).await else {
+            data.anomalies.record(
+                Signal::AuthFailure,
+                request_context(&req, data.settings.trust_forwarded_for),
+            );
+            return unauthorized("Invalid or expired token");
+        };
+        req.extensions_mut().insert(principal);
+    }
+
+    let authenticated = req.extensions().get::<UserPrincipal>().is_some()
+        || req.extensions().get::<ServiceAccountPrincipal>().is_some();
+    let protected = PROTECTED_PREFIXES
+        .iter()
+        .any(|prefix| req.uri().path().starts_with(prefix));
+    if !authenticated && protected {
+        return unauthorized("Authentication required");
+    }
+    next.run(req).await
+}
+
+fn accounts_disabled() -> AppError {
+    AppError::Response(
+        StatusCode::NOT_FOUND,
+        Json(json!({
+            "status": "fail",
+            "message": "User accounts are disabled",
+        })),
+    )
+}
+
+/// Reads the credentials in `body`, failing once it passes
+/// [`MAX_CREDENTIALS_BYTES`]: these routes are open to anyone.
+async fn read_credentials(mut body: Body) -> Result<CredentialsSchema, AppError> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| AppError::Validation(err.to_string()))?;
+        if bytes.len() + chunk.len() > MAX_CREDENTIALS_BYTES {
+            return Err(AppError::Response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Json(json!({
+                    "status": "fail",
+                    "message": format!(
+                        "Credentials must be at most {} bytes",
+                        MAX_CREDENTIALS_BYTES
+                    ),
+                })),
+            ));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    let mut credentials: CredentialsSchema = serde_json::from_slice(&bytes)
+        .map_err(|err| AppError::Validation(format!("Invalid credentials: {}", err)))?;
+    credentials.email = credentials.email.trim().to_lowercase();
+    Ok(credentials)
+}
+
+async fn hash_password(password: String) -> String {
+    tokio::task::spawn_blocking(move || {
+        Argon2::default()
+            .hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))
+            .expect("Argon2 hashing with default parameters cannot fail")
+            .to_string()
+    })
+    .await
+    .unwrap()
+}
+
+async fn verify_password(password: String, hash: String) -> bool {
+    tokio::task::spawn_blocking(move || {
+        PasswordHash::new(&hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    })
+    .await
+    .unwrap()
+}
+
+fn token_response(auth: &JwtAuth, user: &UserModel, now: DateTime<Utc>) -> Json<serde_json::Value> {
+    Json(json!({
+        "status": "success",
+        "data": {
+            "user": {
+                "id": user.id,
+                "email": user.email,
+                "created_at": user.created_at,
+            },
+            "token": auth.issue(user, now),
+            "expires_in": auth.ttl.as_secs(),
+        }
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/auth/register",
+    tag = "auth",
+    request_body = CredentialsSchema,
+    responses(
+        (status = 201, description = "The new user and a token", body = TokenResponse),
+        (status = 400, description = "Invalid email or password", body = ErrorResponse),
+        (status = 404, description = "User accounts are disabled", body = ErrorResponse),
+        (status = 409, description = "A user with that email already exists", body = ErrorResponse),
+        (status = 413, description = "The credentials are too large", body = ErrorResponse),
+    ),
+    security(()),
+)]
+pub async fn register_handler(
+    State(data): State<Arc<AppState>>,
+    req: Request<Body>,
+) -> Result<impl IntoResponse, AppError> {
+    let auth = data.auth.as_ref().ok_or_else(accounts_disabled)?;
+    let credentials = read_credentials(req.into_body()).await?;
+    if !credentials.email.contains('@') {
+        return Err(AppError::Validation(
+            "A valid email is required".to_string(),
+        ));
+    }
+    if credentials.password.chars().count() < MIN_PASSWORD_CHARS {
+        return Err(AppError::Validation(format!(
+            "Passwords need at least {} characters",
+            MIN_PASSWORD_CHARS
+        )));
+    }
+
+    let now = data.clock.now();
+    let user = UserModel {
+        id: UserId::from(data.ids.generate()),
+        email: credentials.email,
+        password_hash: hash_password(credentials.password).await,
+        created_at: now,
+    };
+    let result =
+        sqlx::query("INSERT INTO users (id, email, password_hash, created_at) VALUES (?, ?, ?, ?)")
+            .bind(user.id)
+            .bind(&user.email)
+            .bind(&user.password_hash)
+            .bind(user.created_at)
+            .execute(&data.db)
+            .await;
+    if let Err(err) = result {
+        if err.to_string().contains("Duplicate entry") {
+            return Err(AppError::Conflict(
+                "A user with that email already exists".to_string(),
+            ));
+        }
+        return Err(err.into());
+    }
+
+    Ok((StatusCode::CREATED, token_response(auth, &user, now)))
+}
+
+/// Logins count against the same lockout as admin tokens and service-account
+/// keys, per client address and per email.
+#[utoipa::path(
+    post,
+    path = "/api/auth/login",
+    tag = "auth",
+    request_body = CredentialsSchema,
+    responses(
+        (status = 200, description = "The user and a token", body = TokenResponse),
+        (status = 401, description = "Invalid email or password", body = ErrorResponse),
+        (status = 404, description = "User accounts are disabled", body = ErrorResponse),
+        (status = 413, description = "The credentials are too large", body = ErrorResponse),
+        (status = 429, description = "Too many failed attempts", body = ErrorResponse),
+    ),
+    security(()),
+)]
+pub async fn login_handler(
+    State(data): State<Arc<AppState>>,
+    req: Request<Body>,
+) -> Result<Response, AppError> {
+    let auth = data.auth.as_ref().ok_or_else(accounts_disabled)?;
+    let (parts, body) = req.into_parts();
+    let head = Request::from_parts(parts, ());
+    let credentials = read_credentials(body).await?;
+
+    let account = format!("user:{}", credentials.email);
+    let keys: Vec<LockoutKey> = lockout::lockout_keys(&data, &head, Some(&account));
+    if let Err(response) = lockout::admit(&data, &head, &keys).await {
+        return Ok(response);
+    }
+
+    let user = sqlx::query_as::<_, UserModel>("SELECT * FROM users WHERE email = ?")
+        .bind(&credentials.email)
+        .fetch_optional(&data.db)
+        .await?;
+    // Unknown emails are verified against a dummy hash so that response
+    // times do not reveal which emails are registered.
+    let hash = match &user {
+        Some(user) => user.password_hash.clone(),
+        None => dummy_hash().to_string(),
+    };
+    let valid = verify_password(credentials.password, hash).await;
+
+    match user {
+        Some(user) if valid => {
+            data.logins.record_success(&keys);
+            Ok(token_response(auth, &user, data.clock.now()).into_response())
+        }
+        _ => {
+            data.anomalies.record(
+                Signal::AuthFailure,
+                request_context(&head, data.settings.trust_forwarded_for),
+            );
+            lockout::record_failure(&data, &head, &keys);
+            Ok(unauthorized("Invalid email or password"))
+        }
+    }
+}
+
+fn dummy_hash() -> &'static str {
+    static DUMMY: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+    DUMMY.get_or_init(|| {
+        Argon2::default()
+            .hash_password(b"not a password", &SaltString::generate(&mut OsRng))
+            .unwrap()
+            .to_string()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::EncodingKey;
+    use sqlx::mysql::MySqlPoolOptions;
+
+    use crate::signing_key::SigningKey;
+
+    use super::*;
+
+    fn user() -> UserModel {
+        UserModel {
+            id: UserId(uuid::Uuid::new_v4().into()),
+            email: "ada@example.com".to_string(),
+            password_hash: String::new(),
+            created_at: Utc::now(),
+        }
+    }
+
+    /// Nothing listens here, so reloading the keys fails.
+    fn unreachable_db() -> MySqlPool {
+        MySqlPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(100))
+            .connect_lazy("mysql://test@127.0.0.1:1/test")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn tokens_name_their_key_and_verify_across_rotations() {
+        let db = unreachable_db();
+        let auth = JwtAuth::new(b"secret", Duration::from_secs(3600));
+        let user = user();
+        let now = Utc::now();
+
+        let token = auth.issue(&user, now);
+        let header = jsonwebtoken::decode_header(&token).unwrap();
+        assert_eq!(header.alg, Algorithm::EdDSA);
+        assert_eq!(header.kid, Some(auth.keys.signing_key().kid.clone()));
+        let principal = auth.verify(&token, &db, now).await.unwrap();
+        assert_eq!(principal.id, user.id);
+
+        let (key, _) = SigningKey::generate(now);
+        let rotated = auth.keys.install(key, now);
+        let new_token = auth.issue(&user, now);
+        assert_eq!(
+            jsonwebtoken::decode_header(&new_token).unwrap().kid,
+            Some(rotated.kid.clone())
+        );
+        assert!(auth.verify(&new_token, &db, now).await.is_some());
+        assert!(auth.verify(&token, &db, now).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn foreign_and_tampered_tokens_are_refused() {
+        let db = unreachable_db();
+        let auth = JwtAuth::new(b"secret", Duration::from_secs(3600));
+        let now = Utc::now();
+
+        let other = JwtAuth::new(b"secret", Duration::from_secs(3600));
+        assert!(auth
+            .verify(&other.issue(&user(), now), &db, now)
+            .await
+            .is_none());
+
+        let token = auth.issue(&user(), now);
+        let (rest, signature) = token.rsplit_once('.').unwrap();
+        let flipped = if signature.starts_with('A') { "B" } else { "A" };
+        let tampered = format!("{}.{}{}", rest, flipped, &signature[1..]);
+        assert!(auth.verify(&tampered, &db, now).await.is_none());
+    }
+
+    /// Signed with the secret, as tokens were before signing keys.
+    fn legacy_token(header: &Header, iat: DateTime<Utc>) -> String {
+        let claims = Claims {
+            sub: uuid::Uuid::new_v4().to_string(),
+            email: "ada@example.com".to_string(),
+            iat: iat.timestamp(),
+            exp: Utc::now().timestamp() + 60,
+        };
+        jsonwebtoken::encode(header, &claims, &EncodingKey::from_secret(b"secret")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn tokens_from_before_signing_keys_verify_for_one_lifetime() {
+        let db = unreachable_db();
+        let auth = JwtAuth::new(b"secret", Duration::from_secs(3600));
+        let now = Utc::now();
+        let deployed = now - chrono::Duration::minutes(10);
+        let issued = deployed - chrono::Duration::minutes(1);
+        let legacy = legacy_token(&Header::default(), issued);
+        // Until the deployment is known, none are.
+        assert!(auth.verify(&legacy, &db, now).await.is_none());
+
+        auth.keys.set_legacy_cutoff(deployed);
+        assert!(auth.verify(&legacy, &db, now).await.is_some());
+        let later = deployed + chrono::Duration::hours(2);
+        assert!(auth.verify(&legacy, &db, later).await.is_none());
+
+        // The secret alone cannot sign for a key id.
+        let header = Header {
+            kid: Some(auth.keys.signing_key().kid.clone()),
+            ..Header::default()
+        };
+        let forged = legacy_token(&header, issued);
+        assert!(auth.verify(&forged, &db, now).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn fresh_legacy_tokens_are_refused() {
+        let db = unreachable_db();
+        let auth = JwtAuth::new(b"secret", Duration::from_secs(3600));
+        let now = Utc::now();
+        auth.keys
+            .set_legacy_cutoff(now - chrono::Duration::minutes(10));
+        let fresh = legacy_token(&Header::default(), now);
+        assert!(auth.verify(&fresh, &db, now).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn oversized_credentials_are_refused_unread() {
+        let body = Body::from(r#"{"email": " Ada@Example.com ", "password": "hunter22"}"#);
+        let credentials = read_credentials(body).await.unwrap();
+        assert_eq!(credentials.email, "ada@example.com");
+
+        let padding = " ".repeat(MAX_CREDENTIALS_BYTES);
+        let body = Body::from(format!(
+            r#"{{"email": "ada@example.com",{}"password": "x"}}"#,
+            padding
+        ));
+        let response = read_credentials(body).await.unwrap_err().into_response();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
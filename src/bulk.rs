@@ -0,0 +1,445 @@
+//! Creating and trashing many notes in one request.
+//!
+//! `POST /api/notes/bulk` takes an array of notes to create and
+//! `DELETE /api/notes/bulk` an array of note IDs to move to the trash. By
+//! default each batch runs in a single transaction: either every item goes
+//! through or nothing is written. With `?atomicity=per-item` every item is
+//! written on its own, whatever happens to the others, and with
+//! `?atomicity=stop-on-error` items are written in order until one fails.
+//! Items are checked the same way as by the single-note endpoints, and the
+//! response reports on every item by its index, so a batch that failed can
+//! be fixed and sent again.
+
+use std::{collections::HashSet, str::FromStr, sync::Arc};
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use utoipa::IntoParams;
+
+use crate::{
+    auth::NoteScope, error::AppError, handler::filter_db_record, model::NoteId,
+    schema::CreateNoteSchema, service::NoteService, AppState,
+};
+
+/// The most items a batch may hold.
+pub const MAX_BULK_ITEMS: usize = 500;
+
+/// How a batch with failing items is written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Atomicity {
+    /// One failure writes nothing.
+    #[default]
+    All,
+    /// Every item is written or fails on its own.
+    PerItem,
+    /// Items are written in order until one fails; the rest are skipped.
+    StopOnError,
+}
+
+impl FromStr for Atomicity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(Atomicity::All),
+            "per-item" => Ok(Atomicity::PerItem),
+            "stop-on-error" => Ok(Atomicity::StopOnError),
+            other => Err(format!(
+                "Unknown atomicity '{}', expected all, per-item or stop-on-error",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct BulkOptions {
+    /// `all` (the default), `per-item` or `stop-on-error`.
+    pub atomicity: Option<String>,
+}
+
+impl BulkOptions {
+    fn atomicity(&self) -> Result<Atomicity, AppError> {
+        self.atomicity
+            .as_deref()
+            .map(str::parse)
+            .transpose()
+            .map_err(AppError::Validation)
+            .map(Option::unwrap_or_default)
+    }
+}
+
+/// An item that failed: its error response, with `status` set to `failed`.
+struct Failure {
+    index: usize,
+    status: StatusCode,
+    body: Value,
+}
+
+impl Failure {
+    fn new(index: usize, err: AppError) -> Self {
+        let (status, Json(mut body)): (StatusCode, Json<Value>) = err.into();
+        body["index"] = json!(index);
+        body["status"] = json!("failed");
+        Self {
+            index,
+            status,
+            body,
+        }
+    }
+}
+
+fn check_size(len: usize) -> Result<(), AppError> {
+    if len == 0 {
+        Err(AppError::Validation("The batch is empty".to_string()))
+    } else if len > MAX_BULK_ITEMS {
+        Err(AppError::Validation(format!(
+            "A batch holds at most {} items",
+            MAX_BULK_ITEMS
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// The response to a batch of `len` items that was not written because of
+/// `failures`. Items that did not fail are reported as skipped; the status
+/// is that of the first failure.
+fn rolled_back(len: usize, failures: Vec<Failure>) -> Response {
+    let status = failures[0].status;
+    let message = format!(
+        "{} of {} items failed, so none were written",
+        failures.len(),
+        len
+    );
+    let mut results: Vec<Value> = (0..len)
+        .map(|index| json!({ "index": index, "status": "skipped" }))
+        .collect();
+    for failure in failures {
+        results[failure.index] = failure.body;
+    }
+
+    (
+        status,
+        Json(json!({
+            "status": "fail",
+            "message": message,
+            "results": results,
+        })),
+    )
+        .into_response()
+}
+
+/// The response to a batch of `len` items written one at a time, with the
+/// results of the items that ran in `outcomes`, in order. The items past
+/// them were skipped. Answers 207 when any item failed.
+fn one_by_one(len: usize, outcomes: Vec<Result<Value, Failure>>) -> Response {
+    let ran = outcomes.len();
+    let mut failed = 0;
+    let mut results = Vec::with_capacity(len);
+    for outcome in outcomes {
+        results.push(outcome.unwrap_or_else(|failure| {
+            failed += 1;
+            failure.body
+        }));
+    }
+    results.extend((ran..len).map(|index| json!({ "index": index, "status": "skipped" })));
+
+    if failed == 0 && ran == len {
+        return Json(json!({
+            "status": "success",
+            "results": results,
+        }))
+        .into_response();
+    }
+    let message = format!(
+        "{} of {} items failed and {} were skipped",
+        failed,
+        len,
+        len - ran
+    );
+    (
+        StatusCode::MULTI_STATUS,
+        Json(json!({
+            "status": "partial",
+            "message": message,
+            "results": results,
+        })),
+    )
+        .into_response()
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/notes/bulk",
+    tag = "notes",
+    params(BulkOptions),
+    request_body = [CreateNoteSchema],
+    responses(
+        (status = 200, description = "Every note was created; `results` holds them in order"),
+        (status = 207, description = "With per-item or stop-on-error atomicity, some items failed; `results` says which were created"),
+        (status = 400, description = "The batch is empty or oversized; nothing was created", body = ErrorResponse),
+        (status = 409, description = "A title is taken; nothing was created", body = ErrorResponse),
+        (status = 422, description = "Invalid items; nothing was created", body = ErrorResponse),
+    ),
+)]
+pub async fn bulk_create_handler(
+    Query(opts): Query<BulkOptions>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+    Json(items): Json<Vec<CreateNoteSchema>>,
+) -> Result<Response, AppError> {
+    check_size(items.len())?;
+    let len = items.len();
+    let service = NoteService::new(&data, &scope);
+
+    let atomicity = opts.atomicity()?;
+    if atomicity != Atomicity::All {
+        let mut outcomes = Vec::with_capacity(len);
+        for (index, item) in items.into_iter().enumerate() {
+            let outcome = match service.create(item).await {
+                Ok(note) => Ok(json!({
+                    "index": index,
+                    "status": "created",
+                    "note": filter_db_record(&note),
+                })),
+                Err(err) => Err(Failure::new(index, err)),
+            };
+            let stop = outcome.is_err() && atomicity == Atomicity::StopOnError;
+            outcomes.push(outcome);
+            if stop {
+                break;
+            }
+        }
+        return Ok(one_by_one(len, outcomes));
+    }
+
+    let notes = match service.create_many(items).await? {
+        Ok(notes) => notes,
+        Err(failures) => {
+            let failures = failures
+                .into_iter()
+                .map(|(index, err)| Failure::new(index, err))
+                .collect();
+            return Ok(rolled_back(len, failures));
+        }
+    };
+
+    let results = notes
+        .iter()
+        .enumerate()
+        .map(|(index, note)| {
+            json!({
+                "index": index,
+                "status": "created",
+                "note": filter_db_record(note),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    Ok(Json(json!({
+        "status": "success",
+        "results": results,
+    }))
+    .into_response())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/notes/bulk",
+    tag = "notes",
+    params(BulkOptions),
+    request_body = [Uuid],
+    responses(
+        (status = 200, description = "Every note was moved to the trash"),
+        (status = 207, description = "With per-item or stop-on-error atomicity, some items failed; `results` says which were trashed"),
+        (status = 400, description = "An ID is listed twice, or the batch is empty or oversized; nothing was trashed", body = ErrorResponse),
+        (status = 404, description = "A note was not found; nothing was trashed", body = ErrorResponse),
+    ),
+)]
+pub async fn bulk_delete_handler(
+    Query(opts): Query<BulkOptions>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+    Json(ids): Json<Vec<uuid::Uuid>>,
+) -> Result<Response, AppError> {
+    check_size(ids.len())?;
+    let len = ids.len();
+    let ids: Vec<NoteId> = ids.into_iter().map(NoteId::from).collect();
+    let service = NoteService::new(&data, &scope);
+
+    let atomicity = opts.atomicity()?;
+    if atomicity != Atomicity::All {
+        let mut seen = HashSet::new();
+        let mut outcomes = Vec::with_capacity(len);
+        for (index, &id) in ids.iter().enumerate() {
+            let trashed = if seen.insert(id) {
+                service.trash(id).await
+            } else {
+                Err(AppError::Validation(format!(
+                    "Note with ID: {} is listed more than once",
+                    id
+                )))
+            };
+            let outcome = match trashed {
+                Ok(()) => Ok(json!({ "index": index, "status": "trashed", "id": id })),
+                Err(err) => Err(Failure::new(index, err)),
+            };
+            let stop = outcome.is_err() && atomicity == Atomicity::StopOnError;
+            outcomes.push(outcome);
+            if stop {
+                break;
+            }
+        }
+        return Ok(one_by_one(len, outcomes));
+    }
+
+    if let Err(failures) = service.trash_many(&ids).await? {
+        let failures = failures
+            .into_iter()
+            .map(|(index, err)| Failure::new(index, err))
+            .collect();
+        return Ok(rolled_back(len, failures));
+    }
+
+    let results = ids
+        .iter()
+        .enumerate()
+        .map(|(index, id)| json!({ "index": index, "status": "trashed", "id": id }))
+        .collect::<Vec<_>>();
+
+    Ok(Json(json!({
+        "status": "success",
+        "results": results,
+    }))
+    .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Method;
+
+    use crate::testing::TestApp;
+
+    use super::*;
+
+    fn item(title: &str) -> Value {
+        json!({ "title": title, "content": "Some content" })
+    }
+
+    #[tokio::test]
+    async fn creates_every_item() {
+        let app = TestApp::new();
+        let (status, body) = app
+            .post("/api/notes/bulk", json!([item("One"), item("Two")]))
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["results"][1]["note"]["title"], json!("Two"));
+        assert_eq!(app.get("/api/notes").await.1["total"], json!(2));
+    }
+
+    #[tokio::test]
+    async fn creates_nothing_when_an_item_fails() {
+        let app = TestApp::new();
+        let (status, body) = app
+            .post("/api/notes/bulk", json!([item("One"), item(" ")]))
+            .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{}", body);
+        assert_eq!(body["results"][0]["status"], json!("skipped"));
+        assert_eq!(body["results"][1]["status"], json!("failed"));
+
+        // Only inserting finds titles taken within the batch.
+        let (status, body) = app
+            .post("/api/notes/bulk", json!([item("Same"), item("same")]))
+            .await;
+        assert_eq!(status, StatusCode::CONFLICT, "{}", body);
+        assert_eq!(body["results"][1]["status"], json!("failed"));
+        assert_eq!(app.get("/api/notes").await.1["total"], json!(0));
+    }
+
+    #[tokio::test]
+    async fn trashes_all_or_none() {
+        let app = TestApp::new();
+        let (_, body) = app
+            .post("/api/notes/bulk", json!([item("One"), item("Two")]))
+            .await;
+        let ids = [0, 1].map(|index| body["results"][index]["note"]["id"].clone());
+        let trash = |ids: Value| app.send(Method::DELETE, "/api/notes/bulk", None, Some(ids));
+
+        let missing = json!(uuid::Uuid::new_v4());
+        let (status, _, body) = trash(json!([ids[0], missing])).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{}", body);
+        assert_eq!(body["results"][0]["status"], json!("skipped"));
+        let (status, _, _) = trash(json!([ids[0], ids[0]])).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(app.get("/api/notes").await.1["total"], json!(2));
+
+        let (status, _, body) = trash(json!(ids)).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["results"][1]["status"], json!("trashed"));
+        assert_eq!(app.get("/api/notes").await.1["total"], json!(0));
+    }
+
+    #[tokio::test]
+    async fn per_item_batches_write_what_they_can() {
+        let app = TestApp::new();
+        let uri = "/api/notes/bulk?atomicity=per-item";
+        let (status, body) = app
+            .post(uri, json!([item("One"), item(" "), item("Two")]))
+            .await;
+        assert_eq!(status, StatusCode::MULTI_STATUS, "{}", body);
+        let statuses = [0, 1, 2].map(|index| body["results"][index]["status"].clone());
+        assert_eq!(
+            statuses,
+            [json!("created"), json!("failed"), json!("created")]
+        );
+        assert_eq!(app.get("/api/notes").await.1["total"], json!(2));
+
+        let id = body["results"][0]["note"]["id"].clone();
+        let missing = json!(uuid::Uuid::new_v4());
+        let (status, _, body) = app
+            .send(Method::DELETE, uri, None, Some(json!([missing, id, id])))
+            .await;
+        assert_eq!(status, StatusCode::MULTI_STATUS, "{}", body);
+        assert_eq!(body["results"][0]["status"], json!("failed"));
+        assert_eq!(body["results"][1]["status"], json!("trashed"));
+        assert_eq!(body["results"][2]["status"], json!("failed"));
+        assert_eq!(app.get("/api/notes").await.1["total"], json!(1));
+    }
+
+    #[tokio::test]
+    async fn stop_on_error_batches_keep_what_came_before() {
+        let app = TestApp::new();
+        let (status, body) = app
+            .post(
+                "/api/notes/bulk?atomicity=stop-on-error",
+                json!([item("One"), item(" "), item("Two")]),
+            )
+            .await;
+        assert_eq!(status, StatusCode::MULTI_STATUS, "{}", body);
+        assert_eq!(body["results"][0]["status"], json!("created"));
+        assert_eq!(body["results"][1]["status"], json!("failed"));
+        assert_eq!(body["results"][2]["status"], json!("skipped"));
+        assert_eq!(app.get("/api/notes").await.1["total"], json!(1));
+
+        let (status, body) = app
+            .post(
+                "/api/notes/bulk?atomicity=stop-on-error",
+                json!([item("Two")]),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+
+        let (status, _) = app
+            .post("/api/notes/bulk?atomicity=some", json!([item("Three")]))
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
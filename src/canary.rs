@@ -0,0 +1,362 @@
+//! Canary notes.
+//!
+//! Admins plant notes nobody legitimately reads; any access to one, directly
+//! by id or through a list page, raises an immediate alert with the full
+//! request context. Useful for spotting leaked credentials and scrapers.
+//!
+//! Canary ids are kept in memory and reloaded periodically so notes planted
+//! through another replica are picked up too.
+
+use std::{
+    collections::HashSet,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use axum::{
+    extract::{Path, State},
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use sqlx::mysql::MySqlPool;
+use utoipa::ToSchema;
+
+use crate::{
+    admin::ADMIN_TOKEN_HEADER,
+    alerts::{request_context, Alert},
+    error::AppError,
+    handler::{filter_db_record, new_note},
+    model::NoteId,
+    repository::{insert_note, DELETE_NOTE},
+    schema::CreateNoteSchema,
+    service_account::ServiceAccountPrincipal,
+    state::DbPool,
+    AppState,
+};
+
+/// Headers whose values are credentials; alerts only say they were present.
+const REDACTED_HEADERS: &[&str] = &["authorization", "cookie", ADMIN_TOKEN_HEADER];
+
+#[derive(Default)]
+pub struct Canaries {
+    ids: RwLock<HashSet<NoteId>>,
+}
+
+impl Canaries {
+    pub fn contains(&self, id: NoteId) -> bool {
+        self.ids.read().unwrap().contains(&id)
+    }
+
+    /// The canaries among `ids`.
+    pub fn hits(&self, ids: impl IntoIterator<Item = NoteId>) -> Vec<NoteId> {
+        let canaries = self.ids.read().unwrap();
+        ids.into_iter().filter(|id| canaries.contains(id)).collect()
+    }
+
+    pub async fn reload(&self, db: &MySqlPool) -> Result<(), sqlx::Error> {
+        let ids = sqlx::query_scalar::<_, NoteId>("SELECT note_id FROM canary_notes")
+            .fetch_all(db)
+            .await?;
+        *self.ids.write().unwrap() = ids.into_iter().collect();
+        Ok(())
+    }
+}
+
+pub fn spawn_reloader(db: MySqlPool, canaries: Arc<Canaries>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(err) = canaries.reload(&db).await {
+                tracing::error!(error = ?err, "Failed to reload canary notes");
+            }
+        }
+    });
+}
+
+/// Response extension through which handlers report canaries they served.
+#[derive(Debug, Clone, Default)]
+pub struct CanaryHits(pub Vec<NoteId>);
+
+/// The note id addressed by `/api/notes/:id` and its sub-resources.
+fn addressed_note(path: &str) -> Option<NoteId> {
+    path.strip_prefix("/api/notes/")?
+        .split('/')
+        .next()?
+        .parse()
+        .ok()
+}
+
+fn full_request_context<B>(data: &AppState, req: &Request<B>) -> Value {
+    let mut context = request_context(req, data.settings.trust_forwarded_for);
+    let headers = req
+        .headers()
+        .iter()
+        .map(|(name, value)| {
+            let value = if REDACTED_HEADERS.contains(&name.as_str()) {
+                "[redacted]".to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            (name.to_string(), Value::String(value))
+        })
+        .collect::<Map<_, _>>();
+    context["query"] = json!(req.uri().query());
+    context["headers"] = Value::Object(headers);
+    context["service_account"] = json!(req
+        .extensions()
+        .get::<ServiceAccountPrincipal>()
+        .map(|principal| &principal.name));
+    context
+}
+
+/// Alerts on any request that touches a canary note.
+pub async fn detect_canary_access<B>(
+    State(data): State<Arc<AppState>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let direct = addressed_note(req.uri().path()).filter(|id| data.canaries.contains(*id));
+    let context = full_request_context(&data, &req);
+
+    let response = next.run(req).await;
+
+    let mut hits = response
+        .extensions()
+        .get::<CanaryHits>()
+        .map(|hits| hits.0.clone())
+        .unwrap_or_default();
+    hits.extend(direct);
+    hits.sort();
+    hits.dedup();
+    if !hits.is_empty() {
+        data.alerts.send(Alert {
+            kind: "canary",
+            message: format!("Canary note accessed ({} note(s))", hits.len()),
+            context: json!({
+                "note_ids": hits,
+                "response_status": response.status().as_u16(),
+                "request": context,
+            }),
+            at: data.clock.now(),
+        });
+    }
+    response
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct CanaryNote {
+    pub note_id: NoteId,
+    pub label: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateCanarySchema {
+    pub label: String,
+    #[serde(flatten)]
+    pub note: CreateNoteSchema,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/canaries",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Canary notes"),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn list_canaries_handler(
+    State(db): State<DbPool>,
+) -> Result<impl IntoResponse, AppError> {
+    let canaries = sqlx::query_as::<_, CanaryNote>(
+        "SELECT note_id, label, created_at FROM canary_notes ORDER BY created_at",
+    )
+    .fetch_all(&*db)
+    .await?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "results": canaries.len(),
+        "canaries": canaries,
+    })))
+}
+
+/// Plants a canary: an ordinary-looking note plus its marker row.
+#[utoipa::path(
+    post,
+    path = "/api/admin/canaries",
+    tag = "admin",
+    request_body = CreateCanarySchema,
+    responses(
+        (status = 201, description = "The created canary"),
+        (status = 409, description = "A note with that title already exists", body = ErrorResponse),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn create_canary_handler(
+    State(data): State<Arc<AppState>>,
+    Json(body): Json<CreateCanarySchema>,
+) -> Result<impl IntoResponse, AppError> {
+    let label = body.label.trim().to_string();
+    let note = new_note(&data, NoteId::from(data.ids.generate()), None, body.note)?;
+
+    let mut tx = data.db.begin().await?;
+    if let Err(err) = insert_note(&mut tx, &note).await {
+        if err.to_string().contains("Duplicate entry") {
+            return Err(AppError::Conflict(
+                "Note with that title already exists".to_string(),
+            ));
+        }
+        return Err(err.into());
+    }
+    sqlx::query("INSERT INTO canary_notes (note_id, label, created_at) VALUES (?, ?, ?)")
+        .bind(note.id)
+        .bind(&label)
+        .bind(note.created_at)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+
+    data.canaries.ids.write().unwrap().insert(note.id);
+    tracing::info!(note_id = %note.id, %label, "Canary note planted");
+
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({
+            "status": "success",
+            "data": json!({
+                "label": label,
+                "note": filter_db_record(&note),
+            })
+        })),
+    ))
+}
+
+/// Removes a canary together with its note.
+#[utoipa::path(
+    delete,
+    path = "/api/admin/canaries/{id}",
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "Canary note ID")),
+    responses(
+        (status = 204, description = "The canary was deleted"),
+        (status = 404, description = "Canary not found", body = ErrorResponse),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn delete_canary_handler(
+    Path(id): Path<uuid::Uuid>,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let id = NoteId::from(id);
+    let mut tx = data.db.begin().await?;
+    let result = sqlx::query("DELETE FROM canary_notes WHERE note_id = ?")
+        .bind(id)
+        .execute(&mut tx)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound(format!(
+            "Canary note with ID: {} not found",
+            id
+        )));
+    }
+    sqlx::query(DELETE_NOTE).bind(id).execute(&mut tx).await?;
+    tx.commit().await?;
+
+    data.canaries.ids.write().unwrap().remove(&id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header, Method};
+
+    use crate::testing::TestApp;
+
+    use super::*;
+
+    async fn plant(app: &TestApp, title: &str) -> String {
+        let (status, body) = app
+            .post("/api/notes", json!({ "title": title, "content": "Bait" }))
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        let id = body["data"]["note"]["id"].as_str().unwrap().to_string();
+        let note_id = id.parse().unwrap();
+        app.state.canaries.ids.write().unwrap().insert(note_id);
+        id
+    }
+
+    #[tokio::test]
+    async fn reading_a_canary_raises_an_alert_without_credentials() {
+        let app = TestApp::new();
+        let id = plant(&app, "Passwords").await;
+
+        let req = Request::builder()
+            .uri(format!("/api/notes/{}", id))
+            .header(header::COOKIE, "session=stolen")
+            .header(header::USER_AGENT, "scraper/1.0")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        app.call(req).await;
+
+        let alerts = app.alerts().await;
+        let alert = alerts
+            .iter()
+            .find(|alert| alert["kind"] == "canary")
+            .expect("a canary alert");
+        assert_eq!(alert["context"]["note_ids"], json!([id]));
+        let headers = &alert["context"]["request"]["headers"];
+        assert_eq!(headers["cookie"], json!("[redacted]"));
+        assert_eq!(headers["user-agent"], json!("scraper/1.0"));
+    }
+
+    #[tokio::test]
+    async fn listing_a_canary_raises_an_alert() {
+        let app = TestApp::new();
+        app.post(
+            "/api/notes",
+            json!({ "title": "Groceries", "content": "Milk" }),
+        )
+        .await;
+        let id = plant(&app, "Passwords").await;
+
+        let (status, _, _) = app.send(Method::GET, "/api/notes", None, None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let alerts = app.alerts().await;
+        let canaries: Vec<_> = alerts
+            .iter()
+            .filter(|alert| alert["kind"] == "canary")
+            .collect();
+        assert_eq!(canaries.len(), 1);
+        assert_eq!(canaries[0]["context"]["note_ids"], json!([id]));
+    }
+
+    #[tokio::test]
+    async fn other_notes_raise_nothing() {
+        let app = TestApp::new();
+        plant(&app, "Passwords").await;
+        let (status, body) = app
+            .post(
+                "/api/notes",
+                json!({ "title": "Groceries", "content": "Milk" }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let id = body["data"]["note"]["id"].as_str().unwrap();
+        app.get(&format!("/api/notes/{}", id)).await;
+        assert!(app
+            .alerts()
+            .await
+            .iter()
+            .all(|alert| alert["kind"] != "canary"));
+    }
+}
@@ -0,0 +1,384 @@
+//! Categories, which group notes through `notes.category_id`.
+//!
+//! Categories are managed at `/api/categories`. Notes still send and receive
+//! their category by name, but only names of existing categories of the
+//! note's owner are accepted, and they are stored in the category's own
+//! spelling: "work" files a note under "Work" instead of starting a second
+//! category. Renaming or deleting a category updates its notes.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use serde::Serialize;
+use serde_json::json;
+use sqlx::mysql::MySqlPool;
+
+use crate::{
+    auth::NoteScope,
+    canary::CanaryHits,
+    error::AppError,
+    events::NoteEventKind,
+    handler::{filter_db_record, page_offset, MAX_PAGE_SIZE},
+    model::{BinaryId, CategoryModel, CategoryName, NoteId, NoteModel, UserId},
+    offload,
+    repository::NoteRepository,
+    schema::{CategoryNotesOptions, CategorySchema},
+    state::DbPool,
+    validation::{FieldErrors, MAX_CATEGORY_CHARS},
+    AppState,
+};
+
+pub const SELECT_CATEGORY_BY_ID: &str =
+    "SELECT id, user_id, name, created_at FROM categories WHERE id = ?";
+/// Names compare as the column collation does, ignoring case.
+pub const SELECT_CATEGORY_BY_NAME: &str =
+    "SELECT id, user_id, name, created_at FROM categories WHERE user_id <=> ? AND name = ?";
+/// Binds the category, the current time, the limit and the offset.
+pub const SELECT_CATEGORY_NOTES: &str = "SELECT * FROM notes WHERE category_id = ? \
+    AND deleted_at IS NULL AND (expires_at IS NULL OR expires_at > ?) \
+    ORDER BY id LIMIT ? OFFSET ?";
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct CategorySummary {
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub category: CategoryModel,
+    pub note_count: i64,
+}
+
+fn category_name(name: &str) -> Result<CategoryName, AppError> {
+    CategoryName::parse(name).map_err(|_| {
+        AppError::Validation(format!(
+            "Category names need 1 to {} characters",
+            MAX_CATEGORY_CHARS
+        ))
+    })
+}
+
+fn category_not_found(id: impl std::fmt::Display) -> AppError {
+    AppError::NotFound(format!("Category with ID: {} not found", id))
+}
+
+fn duplicate_name(err: sqlx::Error) -> AppError {
+    if err.to_string().contains("Duplicate entry") {
+        AppError::Conflict("Category with that name already exists".to_string())
+    } else {
+        err.into()
+    }
+}
+
+async fn find_category(
+    db: &MySqlPool,
+    scope: &NoteScope,
+    id: BinaryId,
+) -> Result<CategoryModel, AppError> {
+    sqlx::query_as::<_, CategoryModel>(SELECT_CATEGORY_BY_ID)
+        .bind(id)
+        .fetch_optional(db)
+        .await?
+        .filter(|category| scope.permits(category.user_id))
+        .ok_or_else(|| category_not_found(id))
+}
+
+/// The category of `owner` named `name`; `None` for a blank name, and a 422
+/// when there is no such category.
+pub(crate) async fn resolve(
+    notes: &dyn NoteRepository,
+    owner: Option<UserId>,
+    name: &str,
+) -> Result<Option<CategoryModel>, AppError> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Ok(None);
+    }
+    match notes.find_category_by_name(owner, name).await? {
+        Some(category) => Ok(Some(category)),
+        None => Err(AppError::InvalidFields(FieldErrors::single(
+            "category",
+            format!("no category named '{}', create it first", name),
+        ))),
+    }
+}
+
+/// Files `note` under the category its `category` names, in the category's
+/// spelling; a blank name leaves it uncategorized.
+pub(crate) async fn assign(
+    notes: &dyn NoteRepository,
+    note: &mut NoteModel,
+) -> Result<(), AppError> {
+    let category = resolve(notes, note.user_id, &note.category).await?;
+    note.category_id = category.as_ref().map(|category| category.id);
+    note.category = category
+        .map(|category| String::from(category.name))
+        .unwrap_or_default();
+    Ok(())
+}
+
+/// Live notes of category `id`, to tell their watchers they changed.
+/// The live notes of category `id`, with their owners.
+async fn live_notes(
+    data: &AppState,
+    id: BinaryId,
+) -> Result<Vec<(NoteId, Option<UserId>)>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT id, user_id FROM notes WHERE category_id = ? AND deleted_at IS NULL \
+        AND (expires_at IS NULL OR expires_at > ?)",
+    )
+    .bind(id)
+    .bind(data.clock.now())
+    .fetch_all(&data.db)
+    .await
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/categories",
+    tag = "categories",
+    responses(
+        (status = 200, description = "Categories with their note counts", body = CategoryListResponse),
+    ),
+)]
+pub async fn list_categories_handler(
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let categories = sqlx::query_as::<_, CategorySummary>(
+        r#"SELECT categories.id, categories.user_id, categories.name, categories.created_at, COUNT(notes.id) AS note_count
+        FROM categories LEFT JOIN notes ON notes.category_id = categories.id AND notes.deleted_at IS NULL
+            AND (notes.expires_at IS NULL OR notes.expires_at > ?)
+        WHERE ? IS NULL OR categories.user_id = ?
+        GROUP BY categories.id ORDER BY categories.name"#,
+    )
+    .bind(data.clock.now())
+    .bind(scope.owner())
+    .bind(scope.owner())
+    .fetch_all(&data.db)
+    .await?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "results": categories.len(),
+        "categories": categories,
+    })))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/categories",
+    tag = "categories",
+    request_body = CategorySchema,
+    responses(
+        (status = 201, description = "The created category", body = CategoryResponse),
+        (status = 400, description = "Invalid name", body = ErrorResponse),
+        (status = 409, description = "A category with that name already exists", body = ErrorResponse),
+    ),
+)]
+pub async fn create_category_handler(
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+    Json(body): Json<CategorySchema>,
+) -> Result<impl IntoResponse, AppError> {
+    let category = CategoryModel {
+        id: BinaryId::from(data.ids.generate()),
+        user_id: scope.owner(),
+        name: category_name(&body.name)?,
+        created_at: data.clock.now(),
+    };
+    sqlx::query("INSERT INTO categories (id, user_id, name, created_at) VALUES (?, ?, ?, ?)")
+        .bind(category.id)
+        .bind(category.user_id)
+        .bind(&category.name)
+        .bind(category.created_at)
+        .execute(&data.db)
+        .await
+        .map_err(duplicate_name)?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({
+            "status": "success",
+            "data": json!({
+                "category": category
+            })
+        })),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/categories/{id}",
+    tag = "categories",
+    params(("id" = Uuid, Path, description = "Category ID")),
+    responses(
+        (status = 200, description = "The category", body = CategoryResponse),
+        (status = 404, description = "Category not found", body = ErrorResponse),
+    ),
+)]
+pub async fn get_category_handler(
+    Path(id): Path<uuid::Uuid>,
+    scope: NoteScope,
+    State(db): State<DbPool>,
+) -> Result<impl IntoResponse, AppError> {
+    let category = find_category(&db, &scope, BinaryId::from(id)).await?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "data": json!({
+            "category": category
+        })
+    })))
+}
+
+/// Renames the category and the category of every note in it.
+#[utoipa::path(
+    patch,
+    path = "/api/categories/{id}",
+    tag = "categories",
+    params(("id" = Uuid, Path, description = "Category ID")),
+    request_body = CategorySchema,
+    responses(
+        (status = 200, description = "The renamed category", body = CategoryResponse),
+        (status = 400, description = "Invalid name", body = ErrorResponse),
+        (status = 404, description = "Category not found", body = ErrorResponse),
+        (status = 409, description = "A category with that name already exists", body = ErrorResponse),
+    ),
+)]
+pub async fn rename_category_handler(
+    Path(id): Path<uuid::Uuid>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+    Json(body): Json<CategorySchema>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut category = find_category(&data.db, &scope, BinaryId::from(id)).await?;
+    category.name = category_name(&body.name)?;
+
+    let mut tx = data.db.begin().await?;
+    sqlx::query("UPDATE categories SET name = ? WHERE id = ?")
+        .bind(&category.name)
+        .bind(category.id)
+        .execute(&mut tx)
+        .await
+        .map_err(duplicate_name)?;
+    sqlx::query("UPDATE notes SET category = ?, updated_at = updated_at WHERE category_id = ?")
+        .bind(&category.name)
+        .bind(category.id)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+
+    for (note_id, owner) in live_notes(&data, category.id).await? {
+        data.events
+            .publish(NoteEventKind::Updated, note_id, owner, data.clock.now());
+    }
+
+    Ok(Json(json!({
+        "status": "success",
+        "data": json!({
+            "category": category
+        })
+    })))
+}
+
+/// Deletes the category, leaving its notes uncategorized.
+#[utoipa::path(
+    delete,
+    path = "/api/categories/{id}",
+    tag = "categories",
+    params(("id" = Uuid, Path, description = "Category ID")),
+    responses(
+        (status = 204, description = "The category was deleted"),
+        (status = 404, description = "Category not found", body = ErrorResponse),
+    ),
+)]
+pub async fn delete_category_handler(
+    Path(id): Path<uuid::Uuid>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let category = find_category(&data.db, &scope, BinaryId::from(id)).await?;
+    let notes = live_notes(&data, category.id).await?;
+
+    let mut tx = data.db.begin().await?;
+    sqlx::query(
+        "UPDATE notes SET category = '', category_id = NULL, updated_at = updated_at WHERE category_id = ?",
+    )
+    .bind(category.id)
+    .execute(&mut tx)
+    .await?;
+    sqlx::query("DELETE FROM categories WHERE id = ?")
+        .bind(category.id)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+
+    for (note_id, owner) in notes {
+        data.events
+            .publish(NoteEventKind::Updated, note_id, owner, data.clock.now());
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/categories/{id}/notes",
+    tag = "categories",
+    params(("id" = Uuid, Path, description = "Category ID"), CategoryNotesOptions),
+    responses(
+        (status = 200, description = "A page of the notes in the category", body = NoteListResponse),
+        (status = 404, description = "Category not found", body = ErrorResponse),
+    ),
+)]
+pub async fn category_notes_handler(
+    Path(id): Path<uuid::Uuid>,
+    opts: Option<Query<CategoryNotesOptions>>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let category = find_category(&data.db, &scope, BinaryId::from(id)).await?;
+    let Query(opts) = opts.unwrap_or_default();
+    let limit = opts.limit.unwrap_or(10).min(MAX_PAGE_SIZE);
+    let offset = page_offset(opts.page, limit)?;
+
+    let mut notes = sqlx::query_as::<_, NoteModel>(SELECT_CATEGORY_NOTES)
+        .bind(category.id)
+        .bind(data.clock.now())
+        .bind(limit as u64)
+        .bind(offset as u64)
+        .fetch_all(&data.db)
+        .await?;
+    offload::reassemble(data.attachments.as_ref(), &mut notes).await?;
+
+    let note_responses = notes.iter().map(filter_db_record).collect::<Vec<_>>();
+
+    let canaries = CanaryHits(data.canaries.hits(notes.iter().map(|note| note.id)));
+    Ok((
+        Extension(canaries),
+        Json(json!({
+            "status": "success",
+            "results": note_responses.len(),
+            "notes": note_responses,
+        })),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::TestApp;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn rejects_anonymous_requests_once_accounts_are_enabled() {
+        let app = TestApp::with_accounts();
+        let (status, _) = app.get("/api/categories").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = app.post("/api/categories", json!({ "name": "Work" })).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,262 @@
+//! Fault injection for resilience testing in staging.
+//!
+//! The layer is only installed when `CHAOS_ENABLED=true` at startup; once
+//! installed, faults are tuned at runtime through `/api/admin/chaos`.
+
+use std::{collections::HashMap, sync::Arc, sync::RwLock, time::Duration};
+
+use axum::{
+    extract::{MatchedPath, State},
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::Connection;
+use utoipa::ToSchema;
+
+use crate::AppState;
+
+/// Faults for one route. Rates are probabilities between 0.0 and 1.0.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct FaultRule {
+    #[serde(default)]
+    pub latency_ms: u64,
+    #[serde(default)]
+    pub latency_rate: f64,
+    #[serde(default)]
+    pub error_rate: f64,
+    #[serde(default)]
+    pub db_drop_rate: f64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct ChaosConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub default: FaultRule,
+    /// Per-route overrides keyed by route pattern, e.g. `/api/notes/:id`.
+    #[serde(default)]
+    pub routes: HashMap<String, FaultRule>,
+}
+
+#[derive(Debug, Default)]
+pub struct Chaos {
+    config: RwLock<ChaosConfig>,
+}
+
+impl Chaos {
+    pub fn config(&self) -> ChaosConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    pub fn set_config(&self, config: ChaosConfig) {
+        *self.config.write().unwrap() = config;
+    }
+
+    fn rule_for(&self, route: &str) -> Option<FaultRule> {
+        let config = self.config.read().unwrap();
+        if !config.enabled {
+            return None;
+        }
+        Some(config.routes.get(route).unwrap_or(&config.default).clone())
+    }
+}
+
+pub async fn inject_faults<B>(
+    State(data): State<Arc<AppState>>,
+    matched_path: Option<MatchedPath>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(chaos) = data.chaos.as_ref() else {
+        return next.run(req).await;
+    };
+    let route = matched_path
+        .as_ref()
+        .map(MatchedPath::as_str)
+        .unwrap_or_else(|| req.uri().path());
+    let Some(rule) = chaos.rule_for(route) else {
+        return next.run(req).await;
+    };
+
+    // Decide every fault up front so the RNG is not held across an await.
+    let (add_latency, fail, drop_db) = {
+        let mut rng = rand::thread_rng();
+        (
+            rule.latency_ms > 0 && rng.gen_bool(rule.latency_rate.clamp(0.0, 1.0)),
+            rng.gen_bool(rule.error_rate.clamp(0.0, 1.0)),
+            rng.gen_bool(rule.db_drop_rate.clamp(0.0, 1.0)),
+        )
+    };
+
+    if add_latency {
+        tokio::time::sleep(Duration::from_millis(rule.latency_ms)).await;
+    }
+
+    if drop_db {
+        // Really close a pooled connection so the pool's reconnect path runs too.
+        if let Ok(conn) = data.db.acquire().await {
+            let _ = conn.detach().close().await;
+        }
+        let error_response = json!({
+            "status": "error",
+            "message": "Database error: connection dropped (injected fault)",
+        });
+        return (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response();
+    }
+
+    if fail {
+        let error_response = json!({
+            "status": "error",
+            "message": "Injected fault",
+        });
+        return (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)).into_response();
+    }
+
+    next.run(req).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/chaos",
+    tag = "admin",
+    responses(
+        (status = 200, description = "The fault injection config"),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn get_chaos_handler(State(data): State<Arc<AppState>>) -> impl IntoResponse {
+    let config = data.chaos.as_ref().map(Chaos::config).unwrap_or_default();
+
+    Json(json!({
+        "status": "success",
+        "data": json!({
+            "chaos": config
+        })
+    }))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/admin/chaos",
+    tag = "admin",
+    request_body = ChaosConfig,
+    responses(
+        (status = 200, description = "The new fault injection config"),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn update_chaos_handler(
+    State(data): State<Arc<AppState>>,
+    Json(body): Json<ChaosConfig>,
+) -> impl IntoResponse {
+    if let Some(chaos) = data.chaos.as_ref() {
+        chaos.set_config(body.clone());
+    }
+
+    Json(json!({
+        "status": "success",
+        "data": json!({
+            "chaos": body
+        })
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use crate::testing::TestApp;
+
+    use super::*;
+
+    fn chaos(app: &TestApp) -> &Chaos {
+        app.state.chaos.as_ref().unwrap()
+    }
+
+    fn failing(error_rate: f64) -> FaultRule {
+        FaultRule {
+            error_rate,
+            ..FaultRule::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn faults_are_off_until_enabled() {
+        let app = TestApp::with_chaos();
+        let config = chaos(&app).config();
+        assert!(!config.enabled);
+        assert!(chaos(&app).rule_for("/api/notes").is_none());
+
+        // Rates alone do nothing while disabled.
+        chaos(&app).set_config(ChaosConfig {
+            enabled: false,
+            default: failing(1.0),
+            routes: HashMap::new(),
+        });
+        for _ in 0..20 {
+            assert_eq!(app.get("/api/notes").await.0, StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn routes_fail_at_their_configured_rate() {
+        let app = TestApp::with_chaos();
+        chaos(&app).set_config(ChaosConfig {
+            enabled: true,
+            default: failing(0.5),
+            routes: HashMap::from([("/api/notes/:id".to_string(), failing(1.0))]),
+        });
+
+        // Overrides apply by route pattern.
+        let missing = format!("/api/notes/{}", uuid::Uuid::new_v4());
+        let (status, body) = app.get(&missing).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body["message"], "Injected fault");
+
+        let mut failed = 0;
+        for _ in 0..400 {
+            if app.get("/api/notes").await.0 == StatusCode::INTERNAL_SERVER_ERROR {
+                failed += 1;
+            }
+        }
+        assert!((120..=280).contains(&failed), "{} of 400 failed", failed);
+    }
+
+    #[tokio::test]
+    async fn latency_and_dropped_connections_are_injected() {
+        let app = TestApp::with_chaos();
+        chaos(&app).set_config(ChaosConfig {
+            enabled: true,
+            default: FaultRule {
+                latency_ms: 50,
+                latency_rate: 1.0,
+                ..FaultRule::default()
+            },
+            routes: HashMap::from([(
+                "/api/notes/:id".to_string(),
+                FaultRule {
+                    db_drop_rate: 1.0,
+                    ..FaultRule::default()
+                },
+            )]),
+        });
+
+        let started = Instant::now();
+        assert_eq!(app.get("/api/notes").await.0, StatusCode::OK);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+
+        let missing = format!("/api/notes/{}", uuid::Uuid::new_v4());
+        let (status, body) = app.get(&missing).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(body["message"]
+            .as_str()
+            .unwrap()
+            .contains("connection dropped"));
+    }
+}
@@ -0,0 +1,711 @@
+//! Web clipping for browser extensions.
+//!
+//! `POST /api/clip` fetches a page server-side, finds its main article the way
+//! readability tools do (paragraph text scored into the containers holding
+//! it), converts that to Markdown and saves it as a note. Where the note came
+//! from is kept in `note_sources` and embedded with `?expand=source`. The same
+//! host restrictions as link previews apply, so clips cannot reach internal
+//! addresses.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use chrono::{DateTime, Utc};
+use reqwest::Url;
+use scraper::{ElementRef, Html, Node, Selector};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::broadcast::{self, error::RecvError};
+use utoipa::ToSchema;
+
+use crate::{
+    auth::NoteScope,
+    category,
+    error::AppError,
+    events::{NoteEvent, NoteEventKind},
+    handler::{filter_db_record, new_note},
+    link_preview::{parse_metadata, LinkPreviewOptions},
+    model::NoteId,
+    moderation,
+    repository::insert_note,
+    schema::CreateNoteSchema,
+    AppState,
+};
+
+/// Longer pages are cut off rather than refused.
+const MAX_PAGE_BYTES: usize = 2 * 1024 * 1024;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Articles shorter than this are most likely a failed extraction.
+const MIN_ARTICLE_CHARS: usize = 100;
+const MAX_TITLE_CHARS: usize = 255;
+
+/// Never part of an article.
+const SKIPPED: &[&str] = &[
+    "script", "style", "noscript", "template", "nav", "aside", "footer", "header", "form",
+    "button", "input", "select", "textarea", "iframe", "svg", "canvas",
+];
+/// Rendered as the blocks of their children.
+const CONTAINERS: &[&str] = &[
+    "html",
+    "body",
+    "main",
+    "article",
+    "section",
+    "div",
+    "figure",
+    "figcaption",
+    "table",
+    "thead",
+    "tbody",
+    "tfoot",
+    "dl",
+    "dt",
+    "dd",
+    "details",
+    "summary",
+    "center",
+    "li",
+];
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ClipSchema {
+    pub url: String,
+    /// Overrides the page title.
+    pub title: Option<String>,
+    pub category: Option<String>,
+    pub published: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct NoteSource {
+    pub url: String,
+    pub site_name: Option<String>,
+    pub author: Option<String>,
+    pub excerpt: Option<String>,
+    pub clipped_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default)]
+pub struct Article {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub site_name: Option<String>,
+    pub excerpt: Option<String>,
+    pub markdown: String,
+}
+
+fn class_weight(element: ElementRef) -> f64 {
+    let names = format!(
+        "{} {}",
+        element.value().attr("class").unwrap_or_default(),
+        element.value().attr("id").unwrap_or_default()
+    )
+    .to_ascii_lowercase();
+    let mut weight = 0.0;
+    if [
+        "article", "body", "content", "entry", "main", "post", "story", "text",
+    ]
+    .iter()
+    .any(|hint| names.contains(hint))
+    {
+        weight += 25.0;
+    }
+    if [
+        "comment", "footer", "sidebar", "menu", "share", "related", "promo", "social", "banner",
+        "widget",
+    ]
+    .iter()
+    .any(|hint| names.contains(hint))
+    {
+        weight -= 25.0;
+    }
+    weight
+}
+
+fn text_len(element: ElementRef) -> usize {
+    element.text().map(|text| text.trim().chars().count()).sum()
+}
+
+fn link_density(element: ElementRef) -> f64 {
+    let total = text_len(element);
+    if total == 0 {
+        return 0.0;
+    }
+    let links = Selector::parse("a").unwrap();
+    let linked: usize = element.select(&links).map(text_len).sum();
+    linked as f64 / total as f64
+}
+
+/// The element holding the article: paragraphs are scored by length and
+/// commas, each score is credited to the paragraph's parent and, halved, to
+/// its grandparent, and the best container after discounting links wins.
+fn article_root(document: &Html) -> Option<ElementRef<'_>> {
+    let paragraphs = Selector::parse("p, pre, td, blockquote").unwrap();
+    let mut scores = HashMap::new();
+    for paragraph in document.select(&paragraphs) {
+        let text = paragraph.text().collect::<String>();
+        let len = text.trim().chars().count();
+        if len < 25 {
+            continue;
+        }
+        let score = 1.0 + text.matches(',').count() as f64 + (len / 100).min(3) as f64;
+        let parent = paragraph.parent().and_then(ElementRef::wrap);
+        let grandparent = parent.and_then(|parent| parent.parent().and_then(ElementRef::wrap));
+        for (ancestor, share) in [(parent, 1.0), (grandparent, 0.5)] {
+            if let Some(ancestor) = ancestor {
+                *scores
+                    .entry(ancestor.id())
+                    .or_insert_with(|| class_weight(ancestor)) += score * share;
+            }
+        }
+    }
+
+    let best = scores
+        .into_iter()
+        .filter_map(|(id, score)| {
+            let element = ElementRef::wrap(document.tree.get(id)?)?;
+            Some((element, score * (1.0 - link_density(element))))
+        })
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(element, _)| element);
+    best.or_else(|| {
+        let fallback = Selector::parse("article, main, body").unwrap();
+        document.select(&fallback).next()
+    })
+}
+
+/// Appends `text` with its whitespace collapsed, as browsers render it.
+fn push_text(out: &mut String, text: &str) {
+    for (i, word) in text.split_whitespace().enumerate() {
+        let starts_with_space = i > 0 || text.starts_with(char::is_whitespace);
+        if starts_with_space && !out.is_empty() && !out.ends_with([' ', '\n']) {
+            out.push(' ');
+        }
+        out.push_str(word);
+    }
+    if text.ends_with(char::is_whitespace) && !out.is_empty() && !out.ends_with([' ', '\n']) {
+        out.push(' ');
+    }
+}
+
+/// Wraps `inner` in `mark`, keeping surrounding spaces outside the markers.
+fn push_marked(out: &mut String, inner: &str, mark: &str) {
+    let trimmed = inner.trim();
+    if trimmed.is_empty() {
+        return push_text(out, inner);
+    }
+    if inner.starts_with(' ') && !out.ends_with([' ', '\n']) && !out.is_empty() {
+        out.push(' ');
+    }
+    out.push_str(mark);
+    out.push_str(trimmed);
+    out.push_str(mark);
+    if inner.ends_with(' ') {
+        out.push(' ');
+    }
+}
+
+fn resolve(base: &Url, href: &str) -> Option<String> {
+    let url = base.join(href.trim()).ok()?;
+    matches!(url.scheme(), "http" | "https" | "mailto").then(|| url.to_string())
+}
+
+fn inline(element: ElementRef, base: &Url, out: &mut String) {
+    for child in element.children() {
+        match child.value() {
+            Node::Text(text) => push_text(out, text),
+            Node::Element(_) => {
+                if let Some(child) = ElementRef::wrap(child) {
+                    inline_element(child, base, out);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn inline_element(element: ElementRef, base: &Url, out: &mut String) {
+    match element.value().name() {
+        name if SKIPPED.contains(&name) => {}
+        "br" => {
+            let trimmed = out.trim_end_matches(' ').len();
+            out.truncate(trimmed);
+            out.push_str("  \n");
+        }
+        "strong" | "b" => {
+            let mut inner = String::new();
+            inline(element, base, &mut inner);
+            push_marked(out, &inner, "**");
+        }
+        "em" | "i" => {
+            let mut inner = String::new();
+            inline(element, base, &mut inner);
+            push_marked(out, &inner, "*");
+        }
+        "code" => {
+            let code = element.text().collect::<String>();
+            push_marked(out, &code.replace('`', "'"), "`");
+        }
+        "img" => {
+            let src = element
+                .value()
+                .attr("src")
+                .or_else(|| element.value().attr("data-src"))
+                .and_then(|src| resolve(base, src));
+            if let Some(src) = src {
+                let alt = element.value().attr("alt").unwrap_or_default().trim();
+                push_text(out, " ");
+                out.push_str(&format!("![{}]({})", alt, src));
+            }
+        }
+        "a" => {
+            let mut inner = String::new();
+            inline(element, base, &mut inner);
+            let href = element
+                .value()
+                .attr("href")
+                .filter(|href| !href.starts_with('#'))
+                .and_then(|href| resolve(base, href));
+            match href {
+                Some(href) if !inner.trim().is_empty() => {
+                    if inner.starts_with(' ') {
+                        push_text(out, " ");
+                    }
+                    out.push_str(&format!("[{}]({})", inner.trim(), href));
+                    if inner.ends_with(' ') {
+                        out.push(' ');
+                    }
+                }
+                _ => push_text(out, &inner),
+            }
+        }
+        _ => inline(element, base, out),
+    }
+}
+
+fn list(element: ElementRef, base: &Url, ordered: bool) -> String {
+    let mut number = element
+        .value()
+        .attr("start")
+        .and_then(|start| start.parse().ok())
+        .unwrap_or(1usize);
+    let mut items = Vec::new();
+    for item in element.children().filter_map(ElementRef::wrap) {
+        if item.value().name() != "li" {
+            continue;
+        }
+        let marker = if ordered {
+            format!("{}. ", number)
+        } else {
+            "- ".to_string()
+        };
+        number += 1;
+        let indent = " ".repeat(marker.len());
+        let body = blocks(item, base).join("\n");
+        let lines = body
+            .lines()
+            .enumerate()
+            .map(|(i, line)| match (i, line.is_empty()) {
+                (0, _) => format!("{}{}", marker, line),
+                (_, true) => String::new(),
+                _ => format!("{}{}", indent, line),
+            })
+            .collect::<Vec<_>>();
+        if !lines.is_empty() {
+            items.push(lines.join("\n"));
+        }
+    }
+    items.join("\n")
+}
+
+/// Renders `element` if it is a block, or returns `None` for inline content.
+fn block(element: ElementRef, base: &Url) -> Option<Vec<String>> {
+    let name = element.value().name();
+    let rendered = match name {
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+            let mut text = String::new();
+            inline(element, base, &mut text);
+            let level = name[1..].parse::<usize>().unwrap();
+            vec![format!("{} {}", "#".repeat(level), text.trim())]
+        }
+        "p" => {
+            let mut text = String::new();
+            inline(element, base, &mut text);
+            vec![text.trim().to_string()]
+        }
+        "pre" => {
+            let code = element.text().collect::<String>();
+            vec![format!("```\n{}\n```", code.trim_end_matches('\n'))]
+        }
+        "blockquote" => {
+            let quoted = blocks(element, base).join("\n\n");
+            let lines = quoted
+                .lines()
+                .map(|line| match line {
+                    "" => ">".to_string(),
+                    line => format!("> {}", line),
+                })
+                .collect::<Vec<_>>();
+            vec![lines.join("\n")]
+        }
+        "ul" | "ol" => vec![list(element, base, name == "ol")],
+        "hr" => vec!["---".to_string()],
+        "tr" => {
+            let cells = element
+                .children()
+                .filter_map(ElementRef::wrap)
+                .map(|cell| {
+                    let mut text = String::new();
+                    inline(cell, base, &mut text);
+                    text.trim().to_string()
+                })
+                .filter(|cell| !cell.is_empty())
+                .collect::<Vec<_>>();
+            vec![cells.join(" | ")]
+        }
+        name if CONTAINERS.contains(&name) => blocks(element, base),
+        _ => return None,
+    };
+    Some(rendered)
+}
+
+/// The Markdown blocks of the children of `element`; runs of inline content
+/// between blocks become paragraphs.
+fn blocks(element: ElementRef, base: &Url) -> Vec<String> {
+    let mut rendered = Vec::new();
+    let mut paragraph = String::new();
+    let flush = |paragraph: &mut String, rendered: &mut Vec<String>| {
+        let text = paragraph.trim();
+        if !text.is_empty() {
+            rendered.push(text.to_string());
+        }
+        paragraph.clear();
+    };
+    for child in element.children() {
+        match child.value() {
+            Node::Text(text) => push_text(&mut paragraph, text),
+            Node::Element(_) => {
+                let Some(child) = ElementRef::wrap(child) else {
+                    continue;
+                };
+                if SKIPPED.contains(&child.value().name()) {
+                    continue;
+                }
+                match block(child, base) {
+                    Some(children) => {
+                        flush(&mut paragraph, &mut rendered);
+                        rendered.extend(children.into_iter().filter(|block| !block.is_empty()));
+                    }
+                    None => inline_element(child, base, &mut paragraph),
+                }
+            }
+            _ => {}
+        }
+    }
+    flush(&mut paragraph, &mut rendered);
+    rendered
+}
+
+/// Extracts the readable article of `html`, fetched from `base`.
+pub fn extract(html: &str, base: &Url) -> Article {
+    let document = Html::parse_document(html);
+    let page = parse_metadata(html);
+    let author = Selector::parse(r#"meta[name="author"]"#).unwrap();
+    let heading = Selector::parse("h1").unwrap();
+
+    let markdown = article_root(&document)
+        .map(|root| blocks(root, base).join("\n\n"))
+        .unwrap_or_default();
+    Article {
+        title: page.title.or_else(|| {
+            document
+                .select(&heading)
+                .next()
+                .map(|h1| h1.text().collect::<String>().trim().to_string())
+                .filter(|title| !title.is_empty())
+        }),
+        author: document
+            .select(&author)
+            .next()
+            .and_then(|meta| meta.value().attr("content"))
+            .map(|author| author.trim().to_string())
+            .filter(|author| !author.is_empty()),
+        site_name: page.site_name,
+        excerpt: page.description,
+        markdown,
+    }
+}
+
+async fn fetch_page(data: &AppState, url: &Url) -> Result<String, AppError> {
+    let bad_gateway = |message: String| {
+        AppError::Response(
+            StatusCode::BAD_GATEWAY,
+            Json(json!({
+                "status": "fail",
+                "message": message,
+            })),
+        )
+    };
+    let request = data.http.get(url.clone()).timeout(FETCH_TIMEOUT);
+    let mut response = data
+        .http
+        .send("clipper", request)
+        .await
+        .map_err(|err| bad_gateway(format!("Failed to fetch {}: {}", url, err)))?;
+    if !response.status().is_success() {
+        return Err(bad_gateway(format!(
+            "Fetching {} answered {}",
+            url,
+            response.status()
+        )));
+    }
+    let is_html = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains("html"));
+    if !is_html {
+        return Err(AppError::Validation(format!("{} is not an HTML page", url)));
+    }
+    let mut body = Vec::new();
+    while let Ok(Some(chunk)) = response.chunk().await {
+        body.extend_from_slice(&chunk);
+        if body.len() >= MAX_PAGE_BYTES {
+            break;
+        }
+    }
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/clip",
+    tag = "clip",
+    request_body = ClipSchema,
+    responses(
+        (status = 201, description = "The clipped note", body = NoteResponse),
+        (status = 400, description = "Invalid or denied URL", body = ErrorResponse),
+        (status = 409, description = "A note with that title already exists", body = ErrorResponse),
+        (status = 422, description = "No article found on the page, or unknown category", body = ErrorResponse),
+        (status = 502, description = "The page could not be fetched", body = ErrorResponse),
+    ),
+)]
+pub async fn clip_handler(
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+    Json(body): Json<ClipSchema>,
+) -> Result<impl IntoResponse, AppError> {
+    let url = Url::parse(body.url.trim())
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+        .ok_or_else(|| AppError::Validation(format!("Invalid URL '{}'", body.url)))?;
+    if !LinkPreviewOptions::default().permits(&url) {
+        return Err(AppError::Validation(format!(
+            "Clipping from {} is not allowed",
+            url.host_str().unwrap_or_default()
+        )));
+    }
+
+    let html = fetch_page(&data, &url).await?;
+    // Parsing is CPU-bound and pages can be large.
+    let base = url.clone();
+    let article = tokio::task::spawn_blocking(move || extract(&html, &base))
+        .await
+        .unwrap();
+    if article.markdown.chars().count() < MIN_ARTICLE_CHARS {
+        return Err(AppError::Response(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(json!({
+                "status": "fail",
+                "message": format!("No readable article found at {}", url),
+            })),
+        ));
+    }
+
+    let title = body
+        .title
+        .map(|title| title.trim().to_string())
+        .filter(|title| !title.is_empty())
+        .or(article.title)
+        .unwrap_or_else(|| url.to_string())
+        .chars()
+        .take(MAX_TITLE_CHARS)
+        .collect();
+    let mut note_body = CreateNoteSchema {
+        title,
+        content: article.markdown,
+        category: body.category,
+        published: body.published,
+        expires_at: None,
+    };
+    data.hooks.before_create(&mut note_body).await?;
+    let verdict =
+        moderation::screen(&data, Some(&note_body.title), Some(&note_body.content)).await?;
+    if verdict.unpublishes() {
+        note_body.published = Some(false);
+    }
+
+    let mut note = new_note(
+        &data,
+        NoteId::from(data.ids.generate()),
+        scope.owner(),
+        note_body,
+    )?;
+    category::assign(data.notes.as_ref(), &mut note).await?;
+    let source = NoteSource {
+        url: url.to_string(),
+        site_name: article.site_name,
+        author: article.author,
+        excerpt: article.excerpt,
+        clipped_at: data.clock.now(),
+    };
+
+    let mut tx = data.db.begin().await?;
+    if let Err(err) = insert_note(&mut tx, &note).await {
+        if err.to_string().contains("Duplicate entry") {
+            return Err(AppError::Conflict(
+                "Note with that title already exists".to_string(),
+            ));
+        }
+        return Err(err.into());
+    }
+    sqlx::query(
+        r#"INSERT INTO note_sources (note_id, url, site_name, author, excerpt, clipped_at) VALUES (?, ?, ?, ?, ?, ?)"#,
+    )
+    .bind(note.id)
+    .bind(&source.url)
+    .bind(&source.site_name)
+    .bind(&source.author)
+    .bind(&source.excerpt)
+    .bind(source.clipped_at)
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    data.cache.missing_notes.forget(note.id);
+    data.events.publish(
+        NoteEventKind::Created,
+        note.id,
+        note.user_id,
+        data.clock.now(),
+    );
+    moderation::enqueue(&data, note.id, &verdict).await?;
+
+    let mut record = serde_json::to_value(filter_db_record(&note)).unwrap();
+    if let serde_json::Value::Object(record) = &mut record {
+        record.insert("source".to_string(), json!(source));
+    }
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({
+            "status": "success",
+            "data": json!({
+                "note": record
+            })
+        })),
+    ))
+}
+
+/// Drops the source of deleted notes.
+pub fn spawn_source_cleanup(data: Arc<AppState>, mut events: broadcast::Receiver<NoteEvent>) {
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) if event.kind == NoteEventKind::Deleted => {
+                    let result = sqlx::query("DELETE FROM note_sources WHERE note_id = ?")
+                        .bind(event.note_id)
+                        .execute(&data.db)
+                        .await;
+                    if let Err(err) = result {
+                        tracing::error!(
+                            error = ?err,
+                            note_id = %event.note_id,
+                            "Failed to delete the note source",
+                        );
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!(skipped, "Note source cleanup skipped change events");
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+
+    use crate::testing::TestApp;
+
+    use super::*;
+
+    const PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+  <title>Page title</title>
+  <meta property="og:site_name" content="The Daily">
+  <meta name="author" content=" Ada Lovelace ">
+  <script>track();</script>
+</head>
+<body>
+  <nav><a href="/">Home</a> <a href="/about">About</a></nav>
+  <div class="sidebar"><p>Related: some other story you might like to read, or not.</p></div>
+  <article class="post-content">
+    <h2>Background</h2>
+    <p>The engine, as <em>designed</em>, could compute tables of numbers, with <strong>no</strong> errors at all.</p>
+    <p>More in <a href="/notes/g">note G</a>, which follows.<br>A second line, after a break.</p>
+    <ul><li>First item</li><li>Second item</li></ul>
+    <pre>let x = 1;
+let y = 2;</pre>
+    <img src="diagram.png" alt="Diagram">
+  </article>
+  <footer><p>Copyright notice with a few words, commas, and more commas.</p></footer>
+</body>
+</html>"#;
+
+    #[test]
+    fn extracts_the_article_as_markdown() {
+        let base = Url::parse("https://daily.example.com/2023/engine").unwrap();
+        let article = extract(PAGE, &base);
+        assert_eq!(article.title.as_deref(), Some("Page title"));
+        assert_eq!(article.author.as_deref(), Some("Ada Lovelace"));
+        assert_eq!(article.site_name.as_deref(), Some("The Daily"));
+        assert_eq!(
+            article.markdown,
+            "## Background\n\n\
+             The engine, as *designed*, could compute tables of numbers, with **no** errors at all.\n\n\
+             More in [note G](https://daily.example.com/notes/g), which follows.  \nA second line, after a break.\n\n\
+             - First item\n- Second item\n\n\
+             ```\nlet x = 1;\nlet y = 2;\n```\n\n\
+             ![Diagram](https://daily.example.com/2023/diagram.png)"
+        );
+    }
+
+    #[test]
+    fn only_web_and_mail_links_are_kept() {
+        let base = Url::parse("https://example.com/").unwrap();
+        let html = r##"<body><p>A <a href="javascript:alert(1)">script</a>, a <a href="mailto:a@example.com">mail</a> and a <a href="#top">jump</a>, all in a paragraph long enough to count.</p></body>"##;
+        assert_eq!(
+            extract(html, &base).markdown,
+            "A script, a [mail](mailto:a@example.com) and a jump, all in a paragraph long enough to count."
+        );
+    }
+
+    #[tokio::test]
+    async fn internal_and_non_web_urls_are_refused() {
+        let app = TestApp::new();
+        for url in [
+            "http://127.0.0.1:3306/",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://localhost/admin",
+            "file:///etc/passwd",
+            "not a url",
+        ] {
+            let (status, body) = app.post("/api/clip", json!({ "url": url })).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}: {}", url, body);
+        }
+    }
+}
@@ -0,0 +1,494 @@
+//! Collaborative editing over a WebSocket at `GET /api/notes/{id}/ws`.
+//!
+//! A connection first receives a `snapshot` of the note, then every change
+//! to it: a `patch` with the fields a `PATCH /api/notes/{id}` set, and a
+//! `splice` for each content edit made over another connection. Clients edit
+//! the content by sending splices against the version they last saw. The
+//! sender gets an `ack` with the new version, or a `rejected` with the error
+//! an equivalent PATCH would have answered, such as a 412 when the note has
+//! moved on; the client then applies the changes it was sent and retries.
+//! Splices are saved like any other edit: validated, moderated and published
+//! as `updated` events. With a service-account key they need its
+//! `notes:write` scope, though opening the socket only needs `notes:read`,
+//! and each one counts against the write rate limit.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, State,
+    },
+    http::StatusCode,
+    response::Response,
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    auth::NoteScope,
+    conditional::version_mismatch,
+    error::AppError,
+    handler::filter_db_record,
+    model::{NoteId, NoteModel, NoteModelResponse},
+    rate_limit::{self, RateLimited},
+    schema::UpdateNoteSchema,
+    service::NoteService,
+    service_account::{ServiceAccountPrincipal, SCOPE_NOTES_WRITE},
+    AppState,
+};
+
+/// Changes a connection can fall behind on before it is sent a new snapshot.
+const CHANNEL_CAPACITY: usize = 64;
+
+/// Replaces `delete` characters of the content at character `at` with
+/// `insert`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Splice {
+    pub at: usize,
+    #[serde(default)]
+    pub delete: usize,
+    #[serde(default)]
+    pub insert: String,
+}
+
+impl Splice {
+    /// `None` when the splice reaches past the end of `content`.
+    fn apply(&self, content: &str) -> Option<String> {
+        let offset = |text: &str, chars: usize| {
+            text.char_indices()
+                .map(|(offset, _)| offset)
+                .chain([text.len()])
+                .nth(chars)
+        };
+        let start = offset(content, self.at)?;
+        let end = start + offset(&content[start..], self.delete)?;
+        Some(format!(
+            "{}{}{}",
+            &content[..start],
+            self.insert,
+            &content[end..]
+        ))
+    }
+}
+
+/// A splice sent by a client, based on note version `version`.
+#[derive(Debug, Deserialize)]
+struct SpliceRequest {
+    version: u32,
+    #[serde(flatten)]
+    splice: Splice,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CollabMessage {
+    Snapshot {
+        note: Box<NoteModelResponse>,
+    },
+    /// The fields an edit set, and the version it saved.
+    Patch {
+        version: u32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        title: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        content: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        category: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        published: Option<bool>,
+    },
+    Splice {
+        version: u32,
+        #[serde(flatten)]
+        splice: Splice,
+    },
+    /// The sender's splice was saved as `version`.
+    Ack {
+        version: u32,
+    },
+    /// The sender's message was refused; `error` is the body and `code` the
+    /// status an equivalent request would have been answered with.
+    Rejected {
+        code: u16,
+        error: Value,
+    },
+}
+
+/// Which fields of an [`UpdateNoteSchema`] were set, remembered before it is
+/// applied.
+#[derive(Debug, Clone, Copy)]
+pub struct Edited {
+    title: bool,
+    content: bool,
+    category: bool,
+    published: bool,
+}
+
+impl Edited {
+    /// Only the published flag, as publishing or unpublishing sets it.
+    pub const PUBLISHED: Self = Self {
+        title: false,
+        content: false,
+        category: false,
+        published: true,
+    };
+
+    pub fn of(body: &UpdateNoteSchema) -> Self {
+        Self {
+            title: body.title.is_some(),
+            content: body.content.is_some(),
+            category: body.category.is_some(),
+            published: body.published.is_some(),
+        }
+    }
+
+    /// The edited fields as `note` saved them.
+    pub fn patch(&self, note: &NoteModel) -> CollabMessage {
+        CollabMessage::Patch {
+            version: note.version,
+            title: self.title.then(|| note.title.to_string()),
+            content: self.content.then(|| note.content.clone()),
+            category: self.category.then(|| note.category.clone()),
+            published: self.published.then_some(note.published.is_published()),
+        }
+    }
+}
+
+/// A change and the connection it came from, which is not sent it back.
+#[derive(Debug, Clone)]
+struct Update {
+    from: Option<u64>,
+    message: CollabMessage,
+}
+
+/// The connections open on each note.
+#[derive(Default)]
+pub struct CollabHub {
+    notes: Mutex<HashMap<NoteId, broadcast::Sender<Update>>>,
+    next_connection: AtomicU64,
+}
+
+impl CollabHub {
+    /// Joins the connections of note `id`; returns the new connection's
+    /// number and its receiver.
+    fn join(&self, id: NoteId) -> (u64, broadcast::Receiver<Update>) {
+        let connection = self.next_connection.fetch_add(1, Ordering::Relaxed);
+        let rx = self
+            .notes
+            .lock()
+            .unwrap()
+            .entry(id)
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe();
+        (connection, rx)
+    }
+
+    /// Forgets note `id` once its last connection has closed.
+    fn leave(&self, id: NoteId) {
+        let mut notes = self.notes.lock().unwrap();
+        if notes.get(&id).is_some_and(|tx| tx.receiver_count() == 0) {
+            notes.remove(&id);
+        }
+    }
+
+    /// Whether any connection is open on note `id`.
+    pub fn is_watched(&self, id: NoteId) -> bool {
+        self.notes.lock().unwrap().contains_key(&id)
+    }
+
+    /// Sends `message` to the connections of note `id`.
+    pub fn publish(&self, id: NoteId, message: CollabMessage) {
+        self.send(id, None, message);
+    }
+
+    fn send(&self, id: NoteId, from: Option<u64>, message: CollabMessage) {
+        if let Some(tx) = self.notes.lock().unwrap().get(&id) {
+            let _ = tx.send(Update { from, message });
+        }
+    }
+}
+
+/// What a connection may write, settled by the request that opened it.
+#[derive(Debug, Clone, Copy)]
+struct Writer {
+    /// Whether the credentials that opened it may edit notes.
+    allowed: bool,
+    /// The allowance its splices are charged to, when rate-limited.
+    limit: Option<RateLimited>,
+}
+
+impl Writer {
+    fn new(principal: Option<&ServiceAccountPrincipal>, limit: Option<RateLimited>) -> Self {
+        Self {
+            allowed: principal.is_none_or(|principal| principal.has_scope(SCOPE_NOTES_WRITE)),
+            limit,
+        }
+    }
+
+    /// Refuses a splice the connection may not make now.
+    fn check(&self, data: &AppState) -> Result<(), AppError> {
+        if !self.allowed {
+            return Err(AppError::Response(
+                StatusCode::FORBIDDEN,
+                Json(json!({
+                    "status": "fail",
+                    "message": format!("Service account is missing the '{}' scope", SCOPE_NOTES_WRITE),
+                })),
+            ));
+        }
+        if let Some(limit) = &self.limit {
+            limit.acquire_write(data).map_err(|_| {
+                AppError::Response(StatusCode::TOO_MANY_REQUESTS, Json(rate_limit::slow_down()))
+            })?;
+        }
+        Ok(())
+    }
+}
+
+fn rejected(err: AppError) -> CollabMessage {
+    let (status, Json(error)) = <(StatusCode, Json<Value>)>::from(err);
+    CollabMessage::Rejected {
+        code: status.as_u16(),
+        error,
+    }
+}
+
+async fn snapshot(data: &AppState, id: NoteId) -> Result<CollabMessage, AppError> {
+    let note = data
+        .notes
+        .find(id)
+        .await?
+        .ok_or_else(|| AppError::note_not_found(id))?;
+    Ok(CollabMessage::Snapshot {
+        note: Box::new(filter_db_record(&note)),
+    })
+}
+
+/// Saves `request` as an edit of the content and returns the saved note.
+async fn save_splice(
+    data: &AppState,
+    scope: &NoteScope,
+    id: NoteId,
+    request: &SpliceRequest,
+) -> Result<NoteModel, AppError> {
+    let note = data
+        .notes
+        .find(id)
+        .await?
+        .ok_or_else(|| AppError::note_not_found(id))?;
+    if note.version != request.version {
+        return Err(version_mismatch(note.version).into());
+    }
+    let content = request.splice.apply(&note.content).ok_or_else(|| {
+        AppError::Validation("The splice reaches past the end of the content".to_string())
+    })?;
+
+    let mut body = UpdateNoteSchema {
+        title: None,
+        content: Some(content),
+        category: None,
+        published: None,
+        expires_at: None,
+        expected: None,
+    };
+    let service = NoteService::new(data, scope);
+    let verdict = service.prepare_edit(id, &mut body).await?;
+    let edited = Edited::of(&body);
+    let saved = service
+        .save_edit(id, body, Some(request.version), &verdict)
+        .await?;
+    if edited.published {
+        // Moderation unpublished it, which the splice alone does not say.
+        let unpublished = Edited {
+            content: false,
+            ..edited
+        };
+        data.collab.publish(id, unpublished.patch(&saved));
+    }
+    Ok(saved)
+}
+
+/// Answers a message of connection `connection`, forwarding a saved splice
+/// to the others.
+async fn handle_text(
+    data: &AppState,
+    scope: &NoteScope,
+    writer: &Writer,
+    id: NoteId,
+    connection: u64,
+    text: &str,
+) -> CollabMessage {
+    let request = match serde_json::from_str::<SpliceRequest>(text) {
+        Ok(request) => request,
+        Err(err) => return rejected(AppError::Validation(format!("Invalid splice: {}", err))),
+    };
+    if let Err(err) = writer.check(data) {
+        return rejected(err);
+    }
+    match save_splice(data, scope, id, &request).await {
+        Ok(saved) => {
+            data.collab.send(
+                id,
+                Some(connection),
+                CollabMessage::Splice {
+                    version: saved.version,
+                    splice: request.splice,
+                },
+            );
+            CollabMessage::Ack {
+                version: saved.version,
+            }
+        }
+        Err(err) => rejected(err),
+    }
+}
+
+async fn send(socket: &mut WebSocket, message: &CollabMessage) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(message).expect("collab messages serialize");
+    socket.send(Message::Text(text)).await
+}
+
+async fn serve(
+    mut socket: WebSocket,
+    data: Arc<AppState>,
+    scope: NoteScope,
+    writer: Writer,
+    id: NoteId,
+) {
+    // Join before taking the snapshot so no change falls in between.
+    let (connection, mut rx) = data.collab.join(id);
+    let mut resync = true;
+    loop {
+        if resync {
+            let message = snapshot(&data, id).await.unwrap_or_else(rejected);
+            if send(&mut socket, &message).await.is_err() {
+                break;
+            }
+            resync = false;
+        }
+        let reply = tokio::select! {
+            update = rx.recv() => match update {
+                Ok(update) if update.from == Some(connection) => continue,
+                Ok(update) => update.message,
+                Err(RecvError::Lagged(_)) => {
+                    resync = true;
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    handle_text(&data, &scope, &writer, id, connection, &text).await
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                // Pings are answered by axum.
+                Some(Ok(_)) => continue,
+            },
+        };
+        if send(&mut socket, &reply).await.is_err() {
+            break;
+        }
+    }
+    drop(rx);
+    data.collab.leave(id);
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/notes/{id}/ws",
+    tag = "notes",
+    params(("id" = Uuid, Path, description = "Note ID")),
+    responses(
+        (status = 101, description = "Switches to a WebSocket of changes to the note"),
+        (status = 404, description = "Note not found", body = ErrorResponse),
+    ),
+)]
+pub async fn note_socket_handler(
+    Path(id): Path<uuid::Uuid>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+    principal: Option<Extension<ServiceAccountPrincipal>>,
+    limit: Option<Extension<RateLimited>>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, AppError> {
+    let id = NoteId::from(id);
+    scope.check(&data, id).await?;
+    if data.notes.find(id).await?.is_none() {
+        return Err(AppError::note_not_found(id));
+    }
+    let writer = Writer::new(principal.as_deref(), limit.map(|Extension(limit)| limit));
+    Ok(ws.on_upgrade(move |socket| serve(socket, data, scope, writer, id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::{model::BinaryId, repository::NoteRepository, testing::TestApp};
+
+    use super::*;
+
+    fn principal(scopes: &[&str]) -> ServiceAccountPrincipal {
+        ServiceAccountPrincipal {
+            id: BinaryId(uuid::Uuid::new_v4()),
+            name: "bot".to_string(),
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn splices_apply_by_character() {
+        let splice = Splice {
+            at: 1,
+            delete: 2,
+            insert: "ö".to_string(),
+        };
+        assert_eq!(splice.apply("réal").as_deref(), Some("röl"));
+        assert_eq!(splice.apply("r"), None);
+    }
+
+    #[tokio::test]
+    async fn splices_need_the_write_scope() {
+        let app = TestApp::new();
+        let (_, body) = app
+            .post(
+                "/api/notes",
+                json!({ "title": "Shared", "content": "Hello" }),
+            )
+            .await;
+        let id: NoteId = body["data"]["note"]["id"]
+            .as_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let scope = NoteScope(None);
+        let splice = r#"{ "version": 1, "at": 5, "insert": "!" }"#;
+
+        let reader = Writer::new(Some(&principal(&["notes:read"])), None);
+        let reply = handle_text(&app.state, &scope, &reader, id, 0, splice).await;
+        assert!(
+            matches!(reply, CollabMessage::Rejected { code: 403, .. }),
+            "{:?}",
+            reply
+        );
+        assert_eq!(app.notes.find(id).await.unwrap().unwrap().content, "Hello");
+
+        let writer = Writer::new(Some(&principal(&["notes:read", "notes:write"])), None);
+        let reply = handle_text(&app.state, &scope, &writer, id, 0, splice).await;
+        assert!(
+            matches!(reply, CollabMessage::Ack { version: 2 }),
+            "{:?}",
+            reply
+        );
+        assert_eq!(app.notes.find(id).await.unwrap().unwrap().content, "Hello!");
+    }
+}
@@ -0,0 +1,255 @@
+//! Compression of large note content at rest.
+//!
+//! Content above [`COMPRESSION_THRESHOLD`] bytes is stored zstd-compressed in
+//! `content_zstd`, with `content_encoding` set to `zstd`. `content` then keeps
+//! only its first [`SEARCH_EXCERPT_BYTES`], so full-text search still finds
+//! the note by its opening. Every write of note content goes through [`StoredContent`] and
+//! every `NoteModel` read decompresses, so handlers only ever see plain text.
+//! Notes written before compression existed are converted by [`backfill`].
+
+use std::{
+    io::{self, Read},
+    time::Duration,
+};
+
+use sqlx::{
+    mysql::{MySqlArguments, MySqlPool},
+    query::Query,
+    MySql,
+};
+
+use crate::{model::NoteId, preview::preview};
+
+/// Has to stay below the content limit (`NOTE_MAX_CONTENT_BYTES`, by default
+/// [`crate::validation::DEFAULT_MAX_CONTENT_BYTES`]), or no note is ever big
+/// enough to be compressed.
+pub const COMPRESSION_THRESHOLD: usize = 16 * 1024;
+/// Plain text kept in `content` for compressed notes, for search.
+pub const SEARCH_EXCERPT_BYTES: usize = 4 * 1024;
+const _: () = assert!(COMPRESSION_THRESHOLD < crate::validation::DEFAULT_MAX_CONTENT_BYTES);
+const _: () = assert!(SEARCH_EXCERPT_BYTES < COMPRESSION_THRESHOLD);
+pub(crate) const LEVEL: i32 = 3;
+
+pub const PLAIN: &str = "plain";
+pub const ZSTD: &str = "zstd";
+
+/// Note content as written to the `content`, `content_encoding`,
+/// `content_zstd` and `preview` columns.
+pub enum StoredContent<'a> {
+    Plain(&'a str),
+    Zstd {
+        compressed: Vec<u8>,
+        excerpt: &'a str,
+        preview: String,
+    },
+}
+
+/// The start of `content` up to [`SEARCH_EXCERPT_BYTES`], cut before a word
+/// where there is one to cut at.
+pub fn search_excerpt(content: &str) -> &str {
+    if content.len() <= SEARCH_EXCERPT_BYTES {
+        return content;
+    }
+    let mut end = SEARCH_EXCERPT_BYTES;
+    while !content.is_char_boundary(end) {
+        end -= 1;
+    }
+    let cut = &content[..end];
+    match cut.rfind(char::is_whitespace) {
+        Some(at) if at > 0 => cut[..at].trim_end(),
+        _ => cut,
+    }
+}
+
+impl<'a> StoredContent<'a> {
+    /// Compresses `content` when it is large enough and compression pays off.
+    pub fn encode(content: &'a str) -> Self {
+        if content.len() <= COMPRESSION_THRESHOLD {
+            return StoredContent::Plain(content);
+        }
+        match zstd::bulk::compress(content.as_bytes(), LEVEL) {
+            Ok(compressed) if compressed.len() + SEARCH_EXCERPT_BYTES < content.len() => {
+                StoredContent::Zstd {
+                    compressed,
+                    excerpt: search_excerpt(content),
+                    preview: preview(content),
+                }
+            }
+            _ => StoredContent::Plain(content),
+        }
+    }
+
+    /// Binds the four content columns, in order.
+    pub fn bind<'q>(
+        self,
+        query: Query<'q, MySql, MySqlArguments>,
+    ) -> Query<'q, MySql, MySqlArguments>
+    where
+        'a: 'q,
+    {
+        match self {
+            StoredContent::Plain(content) => query
+                .bind(content)
+                .bind(PLAIN)
+                .bind(None::<Vec<u8>>)
+                .bind(preview(content)),
+            StoredContent::Zstd {
+                compressed,
+                excerpt,
+                preview,
+            } => query
+                .bind(excerpt)
+                .bind(ZSTD)
+                .bind(Some(compressed))
+                .bind(preview),
+        }
+    }
+
+    /// The compressed blob and the excerpt kept next to it.
+    pub fn compressed(&self) -> Option<(&[u8], &str)> {
+        match self {
+            StoredContent::Plain(_) => None,
+            StoredContent::Zstd {
+                compressed,
+                excerpt,
+                ..
+            } => Some((compressed, excerpt)),
+        }
+    }
+}
+
+/// Streaming decoder over a compressed blob, for callers that must not
+/// materialize the whole content.
+pub fn decoder(compressed: &[u8]) -> io::Result<impl Read + Send + '_> {
+    zstd::stream::read::Decoder::with_buffer(compressed)
+}
+
+/// Uncompressed size recorded in the frame header.
+pub fn decompressed_size(compressed: &[u8]) -> Option<u64> {
+    zstd::zstd_safe::get_frame_content_size(compressed)
+        .ok()
+        .flatten()
+}
+
+pub fn decompress(compressed: &[u8]) -> io::Result<String> {
+    let mut content = String::new();
+    decoder(compressed)?.read_to_string(&mut content)?;
+    Ok(content)
+}
+
+/// Compresses up to `batch` large plain notes with ids after `after`,
+/// returning how many were compressed and the last id examined (`None` once
+/// there are no more). Rows edited meanwhile are skipped, and `updated_at` is
+/// preserved since the content did not change.
+pub async fn backfill(
+    db: &MySqlPool,
+    after: NoteId,
+    batch: u32,
+) -> Result<(u64, Option<NoteId>), sqlx::Error> {
+    let notes = sqlx::query_as::<_, (NoteId, String)>(
+        r#"SELECT id, content FROM notes
+        WHERE id > ? AND content_encoding = 'plain' AND OCTET_LENGTH(content) > ?
+        ORDER BY id LIMIT ?"#,
+    )
+    .bind(after)
+    .bind(COMPRESSION_THRESHOLD as u64)
+    .bind(batch)
+    .fetch_all(db)
+    .await?;
+
+    let mut compressed = 0;
+    for (id, content) in &notes {
+        let stored = StoredContent::encode(content);
+        let Some((blob, excerpt)) = stored.compressed() else {
+            continue;
+        };
+        let result = sqlx::query(
+            r#"UPDATE notes SET content = ?, content_encoding = 'zstd', content_zstd = ?, updated_at = updated_at
+            WHERE id = ? AND content_encoding = 'plain' AND CAST(content AS BINARY) = ?"#,
+        )
+        .bind(excerpt)
+        .bind(blob)
+        .bind(id)
+        .bind(content)
+        .execute(db)
+        .await?;
+        compressed += result.rows_affected();
+    }
+    Ok((compressed, notes.last().map(|(id, _)| *id)))
+}
+
+/// Runs [`backfill`] over the whole table, one batch at a time.
+pub async fn run_backfill(db: MySqlPool) {
+    let mut after = NoteId::from(uuid::Uuid::nil());
+    let mut total = 0;
+    loop {
+        match backfill(&db, after, 100).await {
+            Ok((count, Some(last))) => {
+                total += count;
+                after = last;
+            }
+            Ok((_, None)) => break,
+            Err(err) => {
+                tracing::error!(error = ?err, "Content compression backfill failed");
+                tokio::time::sleep(Duration::from_secs(60)).await;
+            }
+        }
+    }
+    if total > 0 {
+        tracing::info!(notes = total, "Compressed note content");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::DEFAULT_MAX_CONTENT_BYTES;
+
+    #[test]
+    fn small_content_is_stored_plain() {
+        let content = "word ".repeat(COMPRESSION_THRESHOLD / 5);
+        assert!(content.len() <= COMPRESSION_THRESHOLD);
+        assert!(matches!(
+            StoredContent::encode(&content),
+            StoredContent::Plain(plain) if plain == content
+        ));
+    }
+
+    #[test]
+    fn large_content_round_trips_and_keeps_a_searchable_excerpt() {
+        let content = format!("# Build log\n{}", "step ok, cache hit\n".repeat(4000));
+        assert!(content.len() > DEFAULT_MAX_CONTENT_BYTES);
+        let StoredContent::Zstd {
+            compressed,
+            excerpt,
+            preview,
+        } = StoredContent::encode(&content)
+        else {
+            panic!("{} bytes were not compressed", content.len());
+        };
+        assert!(compressed.len() < content.len() / 10);
+        assert_eq!(decompress(&compressed).unwrap(), content);
+        assert_eq!(decompressed_size(&compressed), Some(content.len() as u64));
+
+        assert!(excerpt.starts_with("# Build log\nstep ok"));
+        assert!(excerpt.len() <= SEARCH_EXCERPT_BYTES);
+        assert!(content.starts_with(excerpt));
+        assert!(excerpt.ends_with("ok,") || excerpt.ends_with("cache") || excerpt.ends_with("hit"));
+        assert_eq!(preview, crate::preview::preview(&content));
+    }
+
+    #[test]
+    fn excerpts_end_on_a_character_and_before_a_word() {
+        assert_eq!(search_excerpt("short note"), "short note");
+
+        // The byte limit falls inside an "é".
+        let content = format!("x{}", "é".repeat(SEARCH_EXCERPT_BYTES));
+        assert_eq!(search_excerpt(&content).len(), SEARCH_EXCERPT_BYTES - 1);
+
+        let content = format!("{} word", "a".repeat(SEARCH_EXCERPT_BYTES - 2));
+        assert_eq!(
+            search_excerpt(&content),
+            "a".repeat(SEARCH_EXCERPT_BYTES - 2)
+        );
+    }
+}
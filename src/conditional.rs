@@ -0,0 +1,298 @@
+//! Conditional write primitives for automation clients that race each other.
+//!
+//! `PUT /api/notes/:id` creates or replaces a note at a client-chosen id;
+//! `If-None-Match: *` turns it into create-if-absent and `If-Match: *` into
+//! replace-if-present. `PATCH` bodies carrying `expected` values become a
+//! single compare-and-set UPDATE. Failed preconditions answer 412 with the
+//! exact reason.
+//!
+//! Notes carry a `version`, bumped by every edit and served as their `ETag`.
+//! `PATCH` with `If-Match: "<version>"` only applies to the note at that
+//! version, so concurrent editors cannot overwrite each other's changes.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{
+    auth::NoteScope,
+    error::AppError,
+    handler::filter_db_record,
+    model::{NoteId, NoteModel},
+    schema::{CreateNoteSchema, ExpectedNoteFields},
+    service::NoteService,
+    AppState,
+};
+
+#[derive(Debug, Serialize)]
+pub struct FieldMismatch {
+    pub field: &'static str,
+    pub expected: Value,
+    pub actual: Value,
+}
+
+/// The `ETag` of a note at `version`.
+pub fn etag(version: u32) -> HeaderValue {
+    HeaderValue::from_str(&format!("\"{}\"", version)).unwrap()
+}
+
+/// The versions the `If-Match` header accepts; `None` without the header or
+/// for `*`. Weak and foreign tags are dropped, so they never match.
+pub fn if_match_versions(headers: &HeaderMap) -> Option<Vec<u32>> {
+    let value = header_value(headers, header::IF_MATCH).filter(|value| *value != "*")?;
+    Some(
+        value
+            .split(',')
+            .filter_map(|tag| {
+                tag.trim()
+                    .strip_prefix('"')?
+                    .strip_suffix('"')?
+                    .parse()
+                    .ok()
+            })
+            .collect(),
+    )
+}
+
+pub fn version_mismatch(current: u32) -> (StatusCode, Json<Value>) {
+    let error_response = json!({
+        "status": "fail",
+        "message": "Precondition failed: note has been changed since it was read",
+        "version": current,
+    });
+    (StatusCode::PRECONDITION_FAILED, Json(error_response))
+}
+
+/// How `note` differs from the `expected` fields and version; empty when it
+/// holds them all.
+pub(crate) fn mismatches(
+    note: &NoteModel,
+    expected: Option<&ExpectedNoteFields>,
+    expected_version: Option<u32>,
+) -> Vec<FieldMismatch> {
+    let unchecked = ExpectedNoteFields::default();
+    let expected = expected.unwrap_or(&unchecked);
+    let mut mismatches = Vec::new();
+    let mut check = |field, expected: Option<Value>, actual: Value| {
+        if let Some(expected) = expected {
+            if expected != actual {
+                mismatches.push(FieldMismatch {
+                    field,
+                    expected,
+                    actual,
+                });
+            }
+        }
+    };
+    check(
+        "title",
+        expected.title.as_ref().map(|v| json!(v)),
+        json!(note.title),
+    );
+    check(
+        "content",
+        expected.content.as_ref().map(|v| json!(v)),
+        json!(note.content),
+    );
+    check(
+        "category",
+        expected.category.as_ref().map(|v| json!(v)),
+        json!(note.category),
+    );
+    check(
+        "published",
+        expected.published.map(|v| json!(v)),
+        json!(note.published.is_published()),
+    );
+    check(
+        "version",
+        expected_version.map(|v| json!(v)),
+        json!(note.version),
+    );
+    mismatches
+}
+
+fn precondition_failed(message: String) -> AppError {
+    let error_response = json!({
+        "status": "fail",
+        "message": message,
+    });
+    AppError::Response(StatusCode::PRECONDITION_FAILED, Json(error_response))
+}
+
+/// The 412 for a compare-and-set that found `mismatches`.
+pub fn fields_mismatch(mismatches: Vec<FieldMismatch>) -> AppError {
+    AppError::Response(
+        StatusCode::PRECONDITION_FAILED,
+        Json(json!({
+            "status": "fail",
+            "message": "Precondition failed: note does not match expected values",
+            "mismatches": mismatches,
+        })),
+    )
+}
+
+fn header_value(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/notes/{id}",
+    tag = "notes",
+    params(
+        ("id" = Uuid, Path, description = "Note ID"),
+        ("If-Match" = Option<String>, Header, description = "Replace only if the ETag matches"),
+        ("If-None-Match" = Option<String>, Header, description = "`*` to create only"),
+    ),
+    request_body = CreateNoteSchema,
+    responses(
+        (status = 200, description = "The replaced note", body = NoteResponse),
+        (status = 201, description = "The created note", body = NoteResponse),
+        (status = 400, description = "Invalid precondition", body = ErrorResponse),
+        (status = 404, description = "Note not found", body = ErrorResponse),
+        (status = 409, description = "A note with that title already exists, or the note cannot be published or unpublished from its status", body = ErrorResponse),
+        (status = 412, description = "The precondition failed", body = ErrorResponse),
+        (status = 422, description = "Invalid fields", body = ErrorResponse),
+    ),
+)]
+pub async fn put_note_handler(
+    Path(id): Path<uuid::Uuid>,
+    headers: HeaderMap,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+    Json(body): Json<CreateNoteSchema>,
+) -> Result<impl IntoResponse, AppError> {
+    let id = NoteId::from(id);
+    scope.check(&data, id).await?;
+    let if_none_match = header_value(&headers, header::IF_NONE_MATCH);
+    let if_match = header_value(&headers, header::IF_MATCH);
+
+    for value in [if_none_match, if_match].into_iter().flatten() {
+        if value != "*" {
+            return Err(AppError::Validation(
+                "Only the '*' precondition is supported on PUT".to_string(),
+            ));
+        }
+    }
+
+    // Replace, unless the client demanded creation.
+    if if_none_match.is_none() {
+        if data.notes.find(id).await?.is_some() {
+            let note = NoteService::new(&data, &scope).replace(id, body).await?;
+            let note_response = json!({
+                "status": "success",
+                "data": json!({
+                    "note": filter_db_record(&note)
+                })
+            });
+            return Ok((StatusCode::OK, Json(note_response)));
+        }
+
+        if if_match.is_some() {
+            return Err(precondition_failed(format!(
+                "Precondition failed: note with ID: {} does not exist",
+                id
+            )));
+        }
+    }
+
+    let Some(note) = NoteService::new(&data, &scope).create_at(id, body).await? else {
+        return Err(precondition_failed(format!(
+            "Precondition failed: note with ID: {} already exists",
+            id
+        )));
+    };
+
+    let note_response = json!({
+        "status": "success",
+        "data": json!({
+            "note": filter_db_record(&note)
+        })
+    });
+    Ok((StatusCode::CREATED, Json(note_response)))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Method;
+    use chrono::Duration;
+
+    use crate::{clock::Clock, testing::TestApp};
+
+    use super::*;
+
+    fn note(title: &str, published: bool) -> Option<Value> {
+        Some(json!({ "title": title, "content": "Some content", "published": published }))
+    }
+
+    #[tokio::test]
+    async fn replacing_keeps_the_publication_time() {
+        let app = TestApp::new();
+        let uri = format!("/api/notes/{}", uuid::Uuid::new_v4());
+        let (status, _, body) = app.send(Method::PUT, &uri, None, note("Put", true)).await;
+        assert_eq!(status, StatusCode::CREATED, "{}", body);
+        let published_at = body["data"]["note"]["published_at"].clone();
+        assert_eq!(published_at, json!(app.clock.now()));
+
+        app.clock.advance(Duration::hours(1));
+        let (status, _, body) = app
+            .send(Method::PUT, &uri, None, note("Replaced", true))
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["data"]["note"]["title"], json!("Replaced"));
+        assert_eq!(body["data"]["note"]["version"], json!(2));
+        assert_eq!(body["data"]["note"]["published_at"], published_at);
+    }
+
+    #[tokio::test]
+    async fn replacing_takes_the_publishing_transition() {
+        let app = TestApp::new();
+        let uri = format!("/api/notes/{}", uuid::Uuid::new_v4());
+        let (status, _, _) = app.send(Method::PUT, &uri, None, note("Put", false)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, _, _) = app
+            .send(Method::POST, &format!("{}/archive", uri), None, None)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _, _) = app.send(Method::PUT, &uri, None, note("Put", true)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let (status, _, body) = app.send(Method::PUT, &uri, None, note("Kept", false)).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["data"]["note"]["status"], json!("archived"));
+    }
+
+    #[tokio::test]
+    async fn patches_only_notes_holding_the_expected_fields() {
+        let app = TestApp::new();
+        let uri = format!("/api/notes/{}", uuid::Uuid::new_v4());
+        let (status, _, _) = app.send(Method::PUT, &uri, None, note("Put", false)).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let patch = |expected: &str| {
+            Some(
+                json!({ "title": "Swapped", "expected": { "title": expected, "published": false } }),
+            )
+        };
+        let (status, _, body) = app.send(Method::PATCH, &uri, None, patch("Other")).await;
+        assert_eq!(status, StatusCode::PRECONDITION_FAILED, "{}", body);
+        assert_eq!(body["mismatches"][0]["field"], json!("title"));
+        assert_eq!(body["mismatches"][0]["actual"], json!("Put"));
+
+        let (status, headers, body) = app.send(Method::PATCH, &uri, None, patch("Put")).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(headers[header::ETAG], "\"2\"");
+        assert_eq!(body["data"]["note"]["title"], json!("Swapped"));
+    }
+}
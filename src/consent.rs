@@ -0,0 +1,388 @@
+//! Terms of service that users have to accept.
+//!
+//! Admins publish policy documents with `POST /api/admin/policies`; each one
+//! gets the next version number and supersedes the one before. Once a policy
+//! exists, users are turned away from the API until they accept the latest
+//! version with `POST /api/policy/accept`, which they can read at
+//! `GET /api/policy`. Service accounts and the admin token are not gated.
+//! Every instance reloads the current version each minute, so users may get
+//! through for up to a minute after another instance published a policy.
+
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use axum::{
+    extract::State,
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::mysql::MySqlPool;
+use utoipa::ToSchema;
+
+use crate::{auth::UserPrincipal, error::AppError, model::UserId, state::DbPool, AppState};
+
+/// Paths a user can reach before accepting the current policy.
+fn is_exempt(path: &str) -> bool {
+    path == "/api/health"
+        || path == "/api/policy"
+        || path.starts_with("/api/policy/")
+        || path.starts_with("/api/auth/")
+        || path.starts_with("/.well-known/")
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct PolicyModel {
+    pub version: u32,
+    pub title: String,
+    pub body: String,
+    pub published_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct PolicySummary {
+    pub version: u32,
+    pub title: String,
+    pub published_at: DateTime<Utc>,
+    pub acceptances: i64,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct PublishPolicySchema {
+    pub title: String,
+    pub body: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct AcceptPolicySchema {
+    /// The version the user was shown, so that a policy published in the
+    /// meantime is not accepted unseen.
+    pub version: u32,
+}
+
+/// The current policy version, as last loaded.
+#[derive(Debug, Default)]
+pub struct Consent {
+    current: RwLock<Option<u32>>,
+}
+
+impl Consent {
+    pub fn current(&self) -> Option<u32> {
+        *self.current.read().unwrap()
+    }
+
+    fn observe(&self, version: u32) {
+        let mut current = self.current.write().unwrap();
+        *current = (*current).max(Some(version));
+    }
+
+    pub async fn reload(&self, db: &MySqlPool) -> Result<(), sqlx::Error> {
+        let version =
+            sqlx::query_scalar("SELECT version FROM policy_versions ORDER BY version DESC LIMIT 1")
+                .fetch_optional(db)
+                .await?;
+        *self.current.write().unwrap() = version;
+        Ok(())
+    }
+}
+
+pub fn spawn_reloader(data: Arc<AppState>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let Some(auth) = data.auth.as_ref() else {
+                return;
+            };
+            if let Err(err) = auth.consent.reload(&data.db).await {
+                tracing::error!(error = ?err, "Failed to reload the current policy");
+            }
+        }
+    });
+}
+
+async fn current_policy(db: &MySqlPool) -> Result<Option<PolicyModel>, sqlx::Error> {
+    sqlx::query_as::<_, PolicyModel>("SELECT * FROM policy_versions ORDER BY version DESC LIMIT 1")
+        .fetch_optional(db)
+        .await
+}
+
+/// Whether `user` accepted `version`, or a later one that this instance has
+/// not loaded yet.
+async fn has_accepted(db: &MySqlPool, user: UserId, version: u32) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT EXISTS(SELECT 1 FROM policy_consents WHERE user_id = ? AND version >= ?)",
+    )
+    .bind(user)
+    .bind(version)
+    .fetch_one(db)
+    .await
+}
+
+/// Turns users away until they have accepted the current policy. Runs after
+/// user authentication.
+pub async fn require_consent<B>(
+    State(data): State<Arc<AppState>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(version) = data.auth.as_ref().and_then(|auth| auth.consent.current()) else {
+        return next.run(req).await;
+    };
+    let Some(user) = req
+        .extensions()
+        .get::<UserPrincipal>()
+        .map(|principal| principal.id)
+    else {
+        return next.run(req).await;
+    };
+    if is_exempt(req.uri().path()) {
+        return next.run(req).await;
+    }
+
+    match has_accepted(&data.db, user, version).await {
+        Ok(true) => next.run(req).await,
+        Ok(false) => (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "status": "fail",
+                "message": "Accept the current terms of service at /api/policy first",
+                "policy_version": version,
+            })),
+        )
+            .into_response(),
+        Err(err) => AppError::from(err).into_response(),
+    }
+}
+
+/// The current policy, and for users whether they have accepted it.
+#[utoipa::path(
+    get,
+    path = "/api/policy",
+    tag = "auth",
+    responses(
+        (status = 200, description = "The current policy and when the user accepted it"),
+        (status = 404, description = "No policy has been published", body = ErrorResponse),
+    ),
+)]
+pub async fn policy_handler(
+    State(db): State<DbPool>,
+    principal: Option<Extension<UserPrincipal>>,
+) -> Result<impl IntoResponse, AppError> {
+    let policy = current_policy(&db)
+        .await?
+        .ok_or_else(|| AppError::NotFound("No policy has been published".to_string()))?;
+
+    let accepted_at: Option<DateTime<Utc>> = match &principal {
+        Some(principal) => {
+            sqlx::query_scalar(
+                "SELECT accepted_at FROM policy_consents WHERE user_id = ? AND version = ?",
+            )
+            .bind(principal.id)
+            .bind(policy.version)
+            .fetch_optional(&*db)
+            .await?
+        }
+        None => None,
+    };
+
+    Ok(Json(json!({
+        "status": "success",
+        "data": {
+            "policy": policy,
+            "accepted_at": accepted_at,
+        }
+    })))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/policy/accept",
+    tag = "auth",
+    request_body = AcceptPolicySchema,
+    responses(
+        (status = 200, description = "The acceptance was recorded"),
+        (status = 401, description = "Not logged in as a user", body = ErrorResponse),
+        (status = 404, description = "No policy has been published", body = ErrorResponse),
+        (status = 409, description = "The version is not the current policy", body = ErrorResponse),
+    ),
+)]
+pub async fn accept_policy_handler(
+    State(data): State<Arc<AppState>>,
+    principal: Option<Extension<UserPrincipal>>,
+    Json(body): Json<AcceptPolicySchema>,
+) -> Result<impl IntoResponse, AppError> {
+    let Some(Extension(principal)) = principal else {
+        return Err(AppError::Response(
+            StatusCode::UNAUTHORIZED,
+            Json(json!({
+                "status": "fail",
+                "message": "Log in to accept the terms of service",
+            })),
+        ));
+    };
+    let policy = current_policy(&data.db)
+        .await?
+        .ok_or_else(|| AppError::NotFound("No policy has been published".to_string()))?;
+    if body.version != policy.version {
+        return Err(AppError::Conflict(format!(
+            "Version {} is not the current policy, which is version {}",
+            body.version, policy.version
+        )));
+    }
+    if let Some(auth) = &data.auth {
+        auth.consent.observe(policy.version);
+    }
+
+    // Accepting twice keeps the first acceptance.
+    sqlx::query(
+        "INSERT IGNORE INTO policy_consents (user_id, version, accepted_at) VALUES (?, ?, ?)",
+    )
+    .bind(principal.id)
+    .bind(policy.version)
+    .bind(data.clock.now())
+    .execute(&data.db)
+    .await?;
+    let accepted_at: DateTime<Utc> = sqlx::query_scalar(
+        "SELECT accepted_at FROM policy_consents WHERE user_id = ? AND version = ?",
+    )
+    .bind(principal.id)
+    .bind(policy.version)
+    .fetch_one(&data.db)
+    .await?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "data": {
+            "version": policy.version,
+            "accepted_at": accepted_at,
+        }
+    })))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/policies",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Policy versions with their acceptance counts"),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn list_policies_handler(
+    State(db): State<DbPool>,
+) -> Result<impl IntoResponse, AppError> {
+    let policies = sqlx::query_as::<_, PolicySummary>(
+        r#"SELECT p.version, p.title, p.published_at, COUNT(c.user_id) AS acceptances
+           FROM policy_versions p LEFT JOIN policy_consents c ON c.version = p.version
+           GROUP BY p.version, p.title, p.published_at ORDER BY p.version DESC"#,
+    )
+    .fetch_all(&*db)
+    .await?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "results": policies.len(),
+        "policies": policies,
+    })))
+}
+
+/// Publishes a new version, which every user has to accept before going on.
+#[utoipa::path(
+    post,
+    path = "/api/admin/policies",
+    tag = "admin",
+    request_body = PublishPolicySchema,
+    responses(
+        (status = 201, description = "The published policy"),
+        (status = 400, description = "Missing title or body", body = ErrorResponse),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn publish_policy_handler(
+    State(data): State<Arc<AppState>>,
+    Json(body): Json<PublishPolicySchema>,
+) -> Result<impl IntoResponse, AppError> {
+    let title = body.title.trim();
+    if title.is_empty() || body.body.trim().is_empty() {
+        return Err(AppError::Validation(
+            "A policy needs a title and a body".to_string(),
+        ));
+    }
+
+    let published_at = data.clock.now();
+    let result =
+        sqlx::query("INSERT INTO policy_versions (title, body, published_at) VALUES (?, ?, ?)")
+            .bind(title)
+            .bind(&body.body)
+            .bind(published_at)
+            .execute(&data.db)
+            .await?;
+    let version = result.last_insert_id() as u32;
+    if let Some(auth) = &data.auth {
+        auth.consent.observe(version);
+    }
+    tracing::info!(version, "Published policy");
+
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({
+            "status": "success",
+            "data": {
+                "policy": PolicyModel {
+                    version,
+                    title: title.to_string(),
+                    body: body.body,
+                    published_at,
+                },
+            }
+        })),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_current_version_only_moves_forward() {
+        let consent = Consent::default();
+        assert_eq!(consent.current(), None);
+        consent.observe(2);
+        consent.observe(1);
+        assert_eq!(consent.current(), Some(2));
+    }
+
+    #[test]
+    fn users_can_log_in_and_read_the_policy_before_accepting_it() {
+        for path in [
+            "/api/health",
+            "/api/auth/login",
+            "/api/auth/register",
+            "/api/policy",
+            "/api/policy/accept",
+            "/.well-known/jwks.json",
+        ] {
+            assert!(is_exempt(path), "{}", path);
+        }
+    }
+
+    #[test]
+    fn the_rest_of_the_api_waits_for_consent() {
+        for path in [
+            "/api/notes",
+            "/api/notes/search",
+            "/api/policyholders",
+            "/api/admin/policies",
+            "/api/healthz",
+        ] {
+            assert!(!is_exempt(path), "{}", path);
+        }
+    }
+}
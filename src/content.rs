@@ -0,0 +1,440 @@
+//! Raw, ranged access to note content.
+//!
+//! `GET /api/notes/:id/content` serves the content as `text/plain` and honours
+//! a single `Range: bytes=...` header (or `?offset=&len=`). Ranges larger than
+//! [`STREAM_THRESHOLD`] are streamed in [`CHUNK_SIZE`] slices of one version
+//! of the note, so a multi-megabyte note is never held in memory whole; a note
+//! edited mid-download ends the transfer early rather than mixing versions.
+//! Compressed notes are decoded incrementally the same way.
+
+use std::{
+    io::{self, Read},
+    ops::Range,
+    sync::Arc,
+};
+
+use axum::{
+    body::{self, Body, Bytes},
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use tokio::sync::mpsc;
+
+use crate::{
+    auth::NoteScope,
+    compression,
+    error::AppError,
+    model::NoteId,
+    repository::{ContentSlices, StoredNoteContent},
+    schema::ContentRangeOptions,
+    AppState,
+};
+
+/// Ranges up to this many bytes are read in one query and sent in one piece.
+pub const STREAM_THRESHOLD: u64 = 256 * 1024;
+pub const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// The range set of a `Range` header in bytes; `None` for other units,
+/// which RFC 9110 says to ignore.
+fn bytes_range_spec(value: &str) -> Option<&str> {
+    let (unit, spec) = value.trim().split_once('=')?;
+    unit.trim().eq_ignore_ascii_case("bytes").then_some(spec)
+}
+
+/// Parses a single `bytes=` range against a body of `size` bytes. `None`
+/// means the header is malformed or unsatisfiable.
+fn parse_range_header(value: &str, size: u64) -> Option<Range<u64>> {
+    let spec = bytes_range_spec(value)?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            (size.saturating_sub(suffix), size)
+        }
+        (start, "") => (start.parse().ok()?, size),
+        (start, end) => {
+            let end: u64 = end.parse().ok()?;
+            (start.parse().ok()?, end.saturating_add(1).min(size))
+        }
+    };
+    (start < end).then_some(start..end)
+}
+
+/// The requested byte range, `Ok(None)` for the whole content, or `Err` when
+/// it cannot be satisfied. A `Range` header in another unit is ignored.
+fn requested_range(
+    headers: &HeaderMap,
+    opts: &ContentRangeOptions,
+    size: u64,
+) -> Result<Option<Range<u64>>, ()> {
+    if let Some(value) = headers.get(header::RANGE) {
+        let value = value.to_str().map_err(|_| ())?;
+        if bytes_range_spec(value).is_some() {
+            return parse_range_header(value, size).map(Some).ok_or(());
+        }
+    }
+    if opts.offset.is_none() && opts.len.is_none() {
+        return Ok(None);
+    }
+    let start = opts.offset.unwrap_or(0);
+    let end = opts
+        .len
+        .map_or(size, |len| start.saturating_add(len).min(size));
+    if start >= end {
+        return Err(());
+    }
+    Ok(Some(start..end))
+}
+
+/// Streams `range` chunk by chunk, ending the body early on a database error
+/// so the client sees a truncated transfer rather than silently short content.
+fn stream_range(mut slices: Box<dyn ContentSlices>, id: NoteId, range: Range<u64>) -> Body {
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut start = range.start;
+        while start < range.end {
+            let end = (start + CHUNK_SIZE).min(range.end);
+            match slices.read(start..end).await {
+                Ok(chunk) => {
+                    if sender.send_data(Bytes::from(chunk)).await.is_err() {
+                        return;
+                    }
+                }
+                Err(err) => {
+                    tracing::error!(error = ?err, note_id = %id, "Failed to stream note content");
+                    sender.abort();
+                    return;
+                }
+            }
+            start = end;
+        }
+        let _ = slices.finish().await;
+    });
+    body
+}
+
+/// Decodes a compressed note incrementally, skipping to `range` and sending
+/// it in [`CHUNK_SIZE`] pieces. Only the compressed blob is held in memory.
+/// Decoding runs on the blocking pool and hands chunks over a channel, so a
+/// large note does not stall the runtime's workers.
+fn decompress_range(compressed: Vec<u8>, id: NoteId, range: Range<u64>) -> Body {
+    let (mut sender, body) = Body::channel();
+    let (chunks, mut received) = mpsc::channel::<io::Result<Bytes>>(2);
+    tokio::task::spawn_blocking(move || {
+        let mut decoder = match compression::decoder(&compressed) {
+            Ok(decoder) => decoder,
+            Err(err) => {
+                let _ = chunks.blocking_send(Err(err));
+                return;
+            }
+        };
+        if let Err(err) = io::copy(&mut (&mut decoder).take(range.start), &mut io::sink()) {
+            let _ = chunks.blocking_send(Err(err));
+            return;
+        }
+        let mut remaining = range.end - range.start;
+        let mut chunk = vec![0; CHUNK_SIZE as usize];
+        while remaining > 0 {
+            let want = remaining.min(CHUNK_SIZE) as usize;
+            let read = match decoder.read(&mut chunk[..want]) {
+                Ok(0) => return,
+                Ok(read) => read,
+                Err(err) => {
+                    let _ = chunks.blocking_send(Err(err));
+                    return;
+                }
+            };
+            remaining -= read as u64;
+            // The client went away.
+            if chunks
+                .blocking_send(Ok(Bytes::copy_from_slice(&chunk[..read])))
+                .is_err()
+            {
+                return;
+            }
+        }
+    });
+    tokio::spawn(async move {
+        while let Some(chunk) = received.recv().await {
+            match chunk {
+                Ok(chunk) => {
+                    if sender.send_data(chunk).await.is_err() {
+                        return;
+                    }
+                }
+                Err(err) => {
+                    tracing::error!(error = ?err, note_id = %id, "Failed to decompress note content");
+                    sender.abort();
+                    return;
+                }
+            }
+        }
+    });
+    body
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/notes/{id}/content",
+    tag = "notes",
+    params(
+        ("id" = Uuid, Path, description = "Note ID"),
+        ContentRangeOptions,
+        ("Range" = Option<String>, Header, description = "A single `bytes=` range"),
+    ),
+    responses(
+        (status = 200, description = "The content", body = String, content_type = "text/plain"),
+        (status = 206, description = "The requested range", body = String, content_type = "text/plain"),
+        (status = 404, description = "Note not found", body = ErrorResponse),
+        (status = 416, description = "The range is not satisfiable", body = ErrorResponse),
+    ),
+)]
+pub async fn note_content_handler(
+    Path(id): Path<uuid::Uuid>,
+    Query(opts): Query<ContentRangeOptions>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let id = NoteId::from(id);
+    scope.check(&data, id).await?;
+    let Some(stored) = data.notes.content(id).await? else {
+        return Err(AppError::note_not_found(id));
+    };
+
+    let size = match &stored {
+        StoredNoteContent::Plain { size, .. } => *size,
+        StoredNoteContent::Compressed(compressed) => {
+            match compression::decompressed_size(compressed) {
+                Some(size) => size,
+                None => {
+                    let compressed = compressed.clone();
+                    tokio::task::spawn_blocking(move || compression::decompress(&compressed))
+                        .await
+                        .expect("decompression does not panic")
+                        .map_err(|err| sqlx::Error::Decode(Box::new(err)))?
+                        .len() as u64
+                }
+            }
+        }
+    };
+
+    let Ok(range) = requested_range(&headers, &opts, size) else {
+        return Ok((
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            [(header::CONTENT_RANGE, format!("bytes */{}", size))],
+        )
+            .into_response());
+    };
+    let partial = range.is_some();
+    let range = range.unwrap_or(0..size);
+    let len = range.end - range.start;
+
+    let body = match stored {
+        _ if len == 0 => Body::empty(),
+        StoredNoteContent::Compressed(compressed) => {
+            decompress_range(compressed, id, range.clone())
+        }
+        StoredNoteContent::Plain { mut slices, .. } if len <= STREAM_THRESHOLD => {
+            let content = slices.read(range.clone()).await?;
+            let _ = slices.finish().await;
+            Body::from(content)
+        }
+        StoredNoteContent::Plain { slices, .. } => stream_range(slices, id, range.clone()),
+    };
+
+    let mut response = Response::new(body::boxed(body));
+    let response_headers = response.headers_mut();
+    response_headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    response_headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    response_headers.insert(header::CONTENT_LENGTH, HeaderValue::from(len));
+    if partial {
+        *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+        let content_range = format!("bytes {}-{}/{}", range.start, range.end - 1, size);
+        response
+            .headers_mut()
+            .insert(header::CONTENT_RANGE, content_range.parse().unwrap());
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, Request};
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    use crate::{clock::Clock, testing::TestApp};
+
+    use super::*;
+
+    #[test]
+    fn parses_bounded_ranges() {
+        assert_eq!(parse_range_header("bytes=0-99", 1000), Some(0..100));
+        assert_eq!(parse_range_header(" bytes=10-10 ", 1000), Some(10..11));
+        // Ends past the content are cut short.
+        assert_eq!(parse_range_header("bytes=900-5000", 1000), Some(900..1000));
+        assert_eq!(parse_range_header("Bytes = 0-9", 1000), Some(0..10));
+    }
+
+    #[test]
+    fn parses_open_and_suffix_ranges() {
+        assert_eq!(parse_range_header("bytes=990-", 1000), Some(990..1000));
+        assert_eq!(parse_range_header("bytes=-10", 1000), Some(990..1000));
+        assert_eq!(parse_range_header("bytes=-5000", 1000), Some(0..1000));
+    }
+
+    #[test]
+    fn rejects_malformed_and_unsatisfiable_ranges() {
+        for value in [
+            "bytes=0-9,20-29",
+            "bytes=a-b",
+            "bytes=",
+            "bytes=1000-",
+            "bytes=50-10",
+            "bytes=-0",
+        ] {
+            assert_eq!(parse_range_header(value, 1000), None, "{}", value);
+        }
+        assert_eq!(parse_range_header("bytes=0-", 0), None);
+    }
+
+    #[test]
+    fn ignores_ranges_in_other_units() {
+        let opts = ContentRangeOptions::default();
+        for value in ["items=0-99", "0-99"] {
+            let headers = HeaderMap::from_iter([(header::RANGE, value.parse().unwrap())]);
+            assert_eq!(
+                requested_range(&headers, &opts, 1000),
+                Ok(None),
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_the_requested_range() {
+        let app = TestApp::new();
+        let (status, body) = app
+            .post(
+                "/api/notes",
+                json!({ "title": "Long", "content": "0123456789" }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        let uri = format!(
+            "/api/notes/{}/content",
+            body["data"]["note"]["id"].as_str().unwrap()
+        );
+
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri(&uri)
+            .header(header::RANGE, "bytes=2-5")
+            .body(Body::empty())
+            .unwrap();
+        let (status, headers, _) = app.call(req).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(headers[header::CONTENT_RANGE], "bytes 2-5/10");
+
+        let (status, headers, _) = app.send(Method::GET, &uri, None, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_LENGTH], "10");
+
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri(&uri)
+            .header(header::RANGE, "items=2-5")
+            .body(Body::empty())
+            .unwrap();
+        let (status, headers, _) = app.call(req).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_LENGTH], "10");
+    }
+
+    /// Content above [`STREAM_THRESHOLD`] fits the column, so it is sent in
+    /// slices rather than read at once.
+    #[tokio::test]
+    async fn streams_large_content_in_slices() {
+        let app = TestApp::new();
+        let content: String = (0..STREAM_THRESHOLD + CHUNK_SIZE / 2)
+            .map(|i| char::from(b'a' + (i % 26) as u8))
+            .collect();
+        assert!(content.len() <= crate::validation::MAX_STORED_CONTENT_BYTES);
+        let (status, body) = app
+            .post("/api/notes", json!({ "title": "Huge", "content": content }))
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        let uri = format!(
+            "/api/notes/{}/content",
+            body["data"]["note"]["id"].as_str().unwrap()
+        );
+
+        let read = |range: Option<&str>| {
+            let mut req = Request::builder().method(Method::GET).uri(&uri);
+            if let Some(range) = range {
+                req = req.header(header::RANGE, range);
+            }
+            let router = crate::route::create_router(app.state.clone());
+            async move {
+                let response = router
+                    .oneshot(req.body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                let status = response.status();
+                let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                (status, bytes)
+            }
+        };
+
+        let (status, bytes) = read(None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(bytes, content.as_bytes());
+
+        let (status, bytes) = read(Some("bytes=10-")).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(bytes, content.as_bytes()[10..]);
+    }
+
+    #[tokio::test]
+    async fn expired_content_is_gone() {
+        let app = TestApp::new();
+        let expires_at = app.clock.now() + chrono::Duration::minutes(5);
+        let note = json!({ "title": "Brief", "content": "Soon gone", "expires_at": expires_at });
+        let (status, body) = app.post("/api/notes", note).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        let uri = format!(
+            "/api/notes/{}/content",
+            body["data"]["note"]["id"].as_str().unwrap()
+        );
+
+        assert_eq!(app.get(&uri).await.0, StatusCode::OK);
+        app.clock.advance(chrono::Duration::minutes(5));
+        assert_eq!(app.get(&uri).await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn decompresses_just_the_requested_range() {
+        let content = "0123456789".repeat(30_000);
+        let compressed = zstd::bulk::compress(content.as_bytes(), 3).unwrap();
+        let id = NoteId::from(uuid::Uuid::new_v4());
+
+        let range = 150_000..(150_000 + 2 * CHUNK_SIZE + 7);
+        let body = decompress_range(compressed.clone(), id, range.clone());
+        let bytes = hyper::body::to_bytes(body).await.unwrap();
+        let range = range.start as usize..range.end as usize;
+        assert_eq!(&bytes[..], content[range].as_bytes());
+
+        let corrupt = compressed[..compressed.len() / 2].to_vec();
+        let body = decompress_range(corrupt, id, 0..content.len() as u64);
+        assert!(hyper::body::to_bytes(body).await.is_err());
+    }
+}
@@ -0,0 +1,138 @@
+//! The context of the request being served.
+//!
+//! [`attach`] builds a [`RequestContext`] once authentication has run: the
+//! request ID, who is calling, their preferred locale and the deadline for
+//! answering. Handlers take it as an extractor. Code further down, which has
+//! no request at hand, reads it with [`RequestContext::current`] instead of
+//! having it passed along: the rest of the request runs with the context as
+//! a task-local. So far the outbound HTTP client keeps its requests within
+//! the deadline, note events record the request ID that caused them, and the
+//! request's log span records the caller.
+
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use axum::{
+    extract::{FromRequestParts, State},
+    http::{header, request::Parts, Extensions, HeaderMap, Request},
+    middleware::Next,
+    response::Response,
+};
+use tokio::time::Instant;
+use tower_http::request_id::RequestId;
+
+use crate::{
+    auth::UserPrincipal, model::UserId, service_account::ServiceAccountPrincipal, state::Settings,
+    AppState,
+};
+
+/// The default of `REQUEST_TIMEOUT_SECS`.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+tokio::task_local! {
+    static CURRENT: RequestContext;
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    /// The `x-request-id` of the request.
+    pub request_id: Option<String>,
+    /// The signed-in user.
+    pub user_id: Option<UserId>,
+    /// The name of the calling service account.
+    pub service_account: Option<String>,
+    /// The most preferred language of `Accept-Language`, such as `en-GB`.
+    pub locale: Option<String>,
+    /// When the request should be answered by; see [`Self::remaining`].
+    pub deadline: Instant,
+}
+
+/// The tag with the highest weight in an `Accept-Language` value; the first
+/// of equals. `*` is no preference.
+fn preferred_locale(value: &str) -> Option<String> {
+    let mut best: Option<(&str, f32)> = None;
+    for item in value.split(',') {
+        let mut parts = item.split(';');
+        let tag = parts.next().unwrap_or_default().trim();
+        let weight = parts
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .and_then(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if tag.is_empty() || tag == "*" || weight <= 0.0 {
+            continue;
+        }
+        if best.is_none_or(|(_, best)| weight > best) {
+            best = Some((tag, weight));
+        }
+    }
+    best.map(|(tag, _)| tag.to_string())
+}
+
+impl RequestContext {
+    fn build(headers: &HeaderMap, extensions: &Extensions, timeout: Duration) -> Self {
+        Self {
+            request_id: extensions
+                .get::<RequestId>()
+                .and_then(|id| id.header_value().to_str().ok())
+                .map(str::to_string),
+            user_id: extensions.get::<UserPrincipal>().map(|user| user.id),
+            service_account: extensions
+                .get::<ServiceAccountPrincipal>()
+                .map(|account| account.name.clone()),
+            locale: headers
+                .get(header::ACCEPT_LANGUAGE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(preferred_locale),
+            deadline: Instant::now() + timeout,
+        }
+    }
+
+    /// The context of the request this task is serving, if any. Background
+    /// workers have none.
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(Self::clone).ok()
+    }
+
+    /// Time left until the deadline; zero once it has passed.
+    pub fn remaining(&self) -> Duration {
+        self.deadline.saturating_duration_since(Instant::now())
+    }
+}
+
+/// Builds the context and serves the rest of the request within it.
+pub async fn attach<B>(
+    State(settings): State<Settings>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let context = RequestContext::build(req.headers(), req.extensions(), settings.request_timeout);
+    let span = tracing::Span::current();
+    if let Some(user_id) = context.user_id {
+        span.record("user_id", tracing::field::display(user_id));
+    }
+    if let Some(account) = &context.service_account {
+        span.record("service_account", account.as_str());
+    }
+    req.extensions_mut().insert(context.clone());
+    CURRENT.scope(context, next.run(req)).await
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for RequestContext {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        data: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(match parts.extensions.get::<RequestContext>() {
+            Some(context) => context.clone(),
+            // Routes outside the middleware still get a context.
+            None => RequestContext::build(
+                &parts.headers,
+                &parts.extensions,
+                data.settings.request_timeout,
+            ),
+        })
+    }
+}
@@ -0,0 +1,682 @@
+//! Dead letters: deliveries that failed, kept for inspection and replay.
+//!
+//! Alert webhook deliveries that fail are recorded here with their payload
+//! and error rather than only being logged. Admins list them at
+//! `GET /api/admin/dead-letters`, read one with its payload at
+//! `GET /api/admin/dead-letters/:id`, and send them again one at a time or in
+//! bulk. Every replay is recorded on the letter: success marks it replayed,
+//! failure counts another attempt and keeps the error. Letters live in a
+//! [`DeadLetterStore`]: the `dead_letters` table, or a list in tests.
+//!
+//! Background jobs that ran out of attempts are dead letters too: they are
+//! listed under `failed_jobs` with their last error, and replaying one at
+//! `POST /api/admin/dead-letters/jobs/:id/replay`, or in bulk by `job_ids`,
+//! queues it again with fresh attempts. Its outcome is then recorded on the
+//! job, which fails again or is done.
+
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::mysql::MySqlPool;
+
+use crate::{
+    alerts::Alerts,
+    clock::Clock,
+    error::AppError,
+    schema::{DeadLetterOptions, ReplaySchema},
+    AppState,
+};
+
+/// The most letters one bulk replay may send.
+pub const MAX_REPLAY_BATCH: usize = 100;
+
+/// Binds the status filter (`open`, `replayed` or `all`) twice.
+const SELECT_LETTERS: &str = r#"SELECT id, target, kind, error, attempts, failed_at, replayed_at, last_replay_error FROM dead_letters WHERE (? = 'all' OR (? = 'open') = (replayed_at IS NULL)) ORDER BY id DESC LIMIT ?"#;
+const SELECT_LETTER: &str = r#"SELECT id, target, kind, error, attempts, failed_at, replayed_at, last_replay_error FROM dead_letters WHERE id = ?"#;
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct DeadLetter {
+    pub id: u64,
+    /// The sink the delivery was for, such as `alert-webhook`.
+    pub target: String,
+    pub kind: String,
+    pub error: String,
+    /// Deliveries tried so far, the original one included.
+    pub attempts: u32,
+    pub failed_at: DateTime<Utc>,
+    pub replayed_at: Option<DateTime<Utc>>,
+    pub last_replay_error: Option<String>,
+}
+
+/// Where failed deliveries are kept.
+#[async_trait]
+pub trait DeadLetterStore: Send + Sync {
+    /// Records that `payload` could not be delivered to `target`.
+    async fn record(
+        &self,
+        target: &str,
+        kind: &str,
+        payload: &Value,
+        error: &str,
+    ) -> Result<(), sqlx::Error>;
+
+    /// Letters with `status` `open`, `replayed` or `all`, newest first.
+    async fn list(&self, status: &str, limit: u64) -> Result<Vec<DeadLetter>, sqlx::Error>;
+
+    async fn find(&self, id: u64) -> Result<Option<DeadLetter>, sqlx::Error>;
+
+    /// The payload of letter `id`, as stored.
+    async fn payload(&self, id: u64) -> Result<String, sqlx::Error>;
+
+    /// Counts a replay of letter `id`: replayed at `Ok(at)`, or failed with
+    /// `Err(error)`.
+    async fn record_replay(
+        &self,
+        id: u64,
+        outcome: Result<DateTime<Utc>, &str>,
+    ) -> Result<(), sqlx::Error>;
+}
+
+/// Dead letters in the `dead_letters` table.
+pub struct MySqlDeadLetterStore {
+    db: MySqlPool,
+}
+
+impl MySqlDeadLetterStore {
+    pub fn new(db: MySqlPool) -> Self {
+        Self { db }
+    }
+}
+
+#[async_trait]
+impl DeadLetterStore for MySqlDeadLetterStore {
+    async fn record(
+        &self,
+        target: &str,
+        kind: &str,
+        payload: &Value,
+        error: &str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("INSERT INTO dead_letters (target, kind, payload, error) VALUES (?, ?, ?, ?)")
+            .bind(target)
+            .bind(kind)
+            .bind(payload.to_string())
+            .bind(error)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    async fn list(&self, status: &str, limit: u64) -> Result<Vec<DeadLetter>, sqlx::Error> {
+        sqlx::query_as::<_, DeadLetter>(SELECT_LETTERS)
+            .bind(status)
+            .bind(status)
+            .bind(limit)
+            .fetch_all(&self.db)
+            .await
+    }
+
+    async fn find(&self, id: u64) -> Result<Option<DeadLetter>, sqlx::Error> {
+        sqlx::query_as::<_, DeadLetter>(SELECT_LETTER)
+            .bind(id)
+            .fetch_optional(&self.db)
+            .await
+    }
+
+    async fn payload(&self, id: u64) -> Result<String, sqlx::Error> {
+        sqlx::query_scalar("SELECT payload FROM dead_letters WHERE id = ?")
+            .bind(id)
+            .fetch_one(&self.db)
+            .await
+    }
+
+    async fn record_replay(
+        &self,
+        id: u64,
+        outcome: Result<DateTime<Utc>, &str>,
+    ) -> Result<(), sqlx::Error> {
+        let query = match outcome {
+            Ok(at) => sqlx::query(
+                "UPDATE dead_letters SET attempts = attempts + 1, replayed_at = ?, last_replay_error = NULL WHERE id = ?",
+            )
+            .bind(at),
+            Err(error) => sqlx::query(
+                "UPDATE dead_letters SET attempts = attempts + 1, last_replay_error = ? WHERE id = ?",
+            )
+            .bind(error),
+        };
+        query.bind(id).execute(&self.db).await?;
+        Ok(())
+    }
+}
+
+/// Dead letters in a list, for tests, stamped by `clock`.
+pub struct InMemoryDeadLetterStore {
+    clock: Arc<dyn Clock>,
+    letters: Mutex<Vec<(DeadLetter, String)>>,
+}
+
+impl InMemoryDeadLetterStore {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock,
+            letters: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl DeadLetterStore for InMemoryDeadLetterStore {
+    async fn record(
+        &self,
+        target: &str,
+        kind: &str,
+        payload: &Value,
+        error: &str,
+    ) -> Result<(), sqlx::Error> {
+        let mut letters = self.letters.lock().unwrap();
+        let letter = DeadLetter {
+            id: letters.len() as u64 + 1,
+            target: target.to_string(),
+            kind: kind.to_string(),
+            error: error.to_string(),
+            attempts: 1,
+            failed_at: self.clock.now(),
+            replayed_at: None,
+            last_replay_error: None,
+        };
+        letters.push((letter, payload.to_string()));
+        Ok(())
+    }
+
+    async fn list(&self, status: &str, limit: u64) -> Result<Vec<DeadLetter>, sqlx::Error> {
+        let letters = self.letters.lock().unwrap();
+        Ok(letters
+            .iter()
+            .rev()
+            .map(|(letter, _)| letter)
+            .filter(|letter| status == "all" || (status == "open") == letter.replayed_at.is_none())
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn find(&self, id: u64) -> Result<Option<DeadLetter>, sqlx::Error> {
+        let letters = self.letters.lock().unwrap();
+        Ok(letters
+            .iter()
+            .find(|(letter, _)| letter.id == id)
+            .map(|(letter, _)| letter.clone()))
+    }
+
+    async fn payload(&self, id: u64) -> Result<String, sqlx::Error> {
+        let letters = self.letters.lock().unwrap();
+        letters
+            .iter()
+            .find(|(letter, _)| letter.id == id)
+            .map(|(_, payload)| payload.clone())
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn record_replay(
+        &self,
+        id: u64,
+        outcome: Result<DateTime<Utc>, &str>,
+    ) -> Result<(), sqlx::Error> {
+        let mut letters = self.letters.lock().unwrap();
+        if let Some((letter, _)) = letters.iter_mut().find(|(letter, _)| letter.id == id) {
+            letter.attempts += 1;
+            match outcome {
+                Ok(at) => {
+                    letter.replayed_at = Some(at);
+                    letter.last_replay_error = None;
+                }
+                Err(error) => letter.last_replay_error = Some(error.to_string()),
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn find_letter(letters: &dyn DeadLetterStore, id: u64) -> Result<DeadLetter, AppError> {
+    letters
+        .find(id)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("Dead letter {} not found", id)))
+}
+
+/// Sends letter `id` again through `alerts` and records the outcome. Only a
+/// failure to read or update the letter is an error; a failed delivery is an
+/// outcome.
+async fn replay(
+    letters: &dyn DeadLetterStore,
+    alerts: &Alerts,
+    id: u64,
+    now: DateTime<Utc>,
+) -> Result<(DeadLetter, Option<String>), AppError> {
+    let letter = find_letter(letters, id).await?;
+    if letter.replayed_at.is_some() {
+        return Err(AppError::Conflict(format!(
+            "Dead letter {} was already replayed",
+            id
+        )));
+    }
+    let payload = letters.payload(id).await?;
+
+    let delivered = match serde_json::from_str::<Value>(&payload) {
+        Ok(payload) => alerts.redeliver(&letter.target, &payload).await,
+        Err(err) => Err(format!("stored payload is not JSON: {}", err)),
+    };
+    letters
+        .record_replay(id, delivered.as_ref().map(|()| now).map_err(String::as_str))
+        .await?;
+
+    Ok((find_letter(letters, id).await?, delivered.err()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/dead-letters",
+    tag = "admin",
+    params(DeadLetterOptions),
+    responses(
+        (status = 200, description = "Dead letters and failed jobs, newest first, without their payloads"),
+        (status = 400, description = "Unknown status", body = ErrorResponse),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn list_dead_letters_handler(
+    Query(opts): Query<DeadLetterOptions>,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let status = opts.status.as_deref().unwrap_or("open");
+    if !matches!(status, "open" | "replayed" | "all") {
+        return Err(AppError::Validation(format!(
+            "Unknown status '{}', expected open, replayed or all",
+            status
+        )));
+    }
+    let limit = opts.limit.unwrap_or(50).min(500);
+
+    let letters = data.dead_letters.list(status, limit as u64).await?;
+    // Replayed jobs are queued again rather than marked, so only open ones
+    // are listed.
+    let jobs = match status {
+        "replayed" => Vec::new(),
+        _ => data.jobs.failed(&data.db, limit as u64).await?,
+    };
+
+    Ok(Json(json!({
+        "status": "success",
+        "results": letters.len(),
+        "dead_letters": letters,
+        "failed_jobs": jobs,
+    })))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/dead-letters/{id}",
+    tag = "admin",
+    params(("id" = u64, Path, description = "Dead letter ID")),
+    responses(
+        (status = 200, description = "The dead letter with its payload"),
+        (status = 404, description = "Dead letter not found", body = ErrorResponse),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn get_dead_letter_handler(
+    Path(id): Path<u64>,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let letter = find_letter(data.dead_letters.as_ref(), id).await?;
+    let payload = data.dead_letters.payload(id).await?;
+    // Payloads are written as JSON; anything else is shown as stored.
+    let payload = serde_json::from_str::<Value>(&payload).unwrap_or(Value::String(payload));
+
+    Ok(Json(json!({
+        "status": "success",
+        "data": {
+            "dead_letter": letter,
+            "payload": payload,
+        },
+    })))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/admin/dead-letters/{id}/replay",
+    tag = "admin",
+    params(("id" = u64, Path, description = "Dead letter ID")),
+    responses(
+        (status = 200, description = "The letter was delivered"),
+        (status = 404, description = "Dead letter not found", body = ErrorResponse),
+        (status = 409, description = "The letter was already replayed", body = ErrorResponse),
+        (status = 502, description = "The delivery failed again; the error is recorded on the letter", body = ErrorResponse),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn replay_dead_letter_handler(
+    Path(id): Path<u64>,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let (letter, error) = replay(
+        data.dead_letters.as_ref(),
+        &data.alerts,
+        id,
+        data.clock.now(),
+    )
+    .await?;
+    if let Some(error) = error {
+        return Err(AppError::Response(
+            StatusCode::BAD_GATEWAY,
+            Json(json!({
+                "status": "fail",
+                "message": format!("Replay of dead letter {} failed: {}", id, error),
+            })),
+        ));
+    }
+
+    Ok(Json(json!({
+        "status": "success",
+        "data": {
+            "dead_letter": letter,
+        },
+    })))
+}
+
+fn failed_job_not_found(id: u64) -> AppError {
+    AppError::NotFound(format!("Failed job {} not found", id))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/admin/dead-letters/jobs/{id}/replay",
+    tag = "admin",
+    params(("id" = u64, Path, description = "Job ID")),
+    responses(
+        (status = 200, description = "The job was queued again"),
+        (status = 404, description = "No failed job with that ID", body = ErrorResponse),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn replay_failed_job_handler(
+    Path(id): Path<u64>,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let job = data
+        .jobs
+        .retry(&data.db, id, data.clock.now())
+        .await?
+        .ok_or_else(|| failed_job_not_found(id))?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "data": {
+            "job": job,
+        },
+    })))
+}
+
+/// Replays the letters in order, then queues the jobs again, and reports on
+/// each; one failing does not stop the others.
+#[utoipa::path(
+    post,
+    path = "/api/admin/dead-letters/replay",
+    tag = "admin",
+    request_body = ReplaySchema,
+    responses(
+        (status = 200, description = "The outcome of each replay"),
+        (status = 400, description = "No IDs, or too many", body = ErrorResponse),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn replay_dead_letters_handler(
+    State(data): State<Arc<AppState>>,
+    Json(body): Json<ReplaySchema>,
+) -> Result<impl IntoResponse, AppError> {
+    let count = body.ids.len() + body.job_ids.len();
+    if count == 0 || count > MAX_REPLAY_BATCH {
+        return Err(AppError::Validation(format!(
+            "Replay between 1 and {} dead letters and failed jobs at a time",
+            MAX_REPLAY_BATCH
+        )));
+    }
+
+    let mut results = Vec::with_capacity(count);
+    for id in body.ids {
+        let replayed = replay(
+            data.dead_letters.as_ref(),
+            &data.alerts,
+            id,
+            data.clock.now(),
+        );
+        let result = match replayed.await {
+            Ok((_, None)) => json!({ "id": id, "status": "replayed" }),
+            Ok((_, Some(error))) => json!({ "id": id, "status": "failed", "error": error }),
+            Err(err @ (AppError::NotFound(_) | AppError::Conflict(_))) => {
+                let (_, Json(body)): (StatusCode, Json<Value>) = err.into();
+                json!({ "id": id, "status": "skipped", "error": body["message"] })
+            }
+            Err(err) => return Err(err),
+        };
+        results.push(result);
+    }
+    for id in body.job_ids {
+        let result = match data.jobs.retry(&data.db, id, data.clock.now()).await? {
+            Some(_) => json!({ "job_id": id, "status": "requeued" }),
+            None => {
+                let (_, Json(body)): (StatusCode, Json<Value>) = failed_job_not_found(id).into();
+                json!({ "job_id": id, "status": "skipped", "error": body["message"] })
+            }
+        };
+        results.push(result);
+    }
+
+    Ok(Json(json!({
+        "status": "success",
+        "results": results,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use axum::response::Response;
+    use chrono::TimeZone;
+
+    use crate::{
+        alerts::{Alert, AlertSink},
+        clock::FixedClock,
+        testing::TestApp,
+    };
+
+    use super::*;
+
+    /// A webhook that refuses everything while it is down.
+    #[derive(Default)]
+    struct Webhook {
+        down: AtomicBool,
+        received: Mutex<Vec<Value>>,
+    }
+
+    #[async_trait]
+    impl AlertSink for Webhook {
+        fn name(&self) -> &'static str {
+            "alert-webhook"
+        }
+
+        async fn deliver(&self, alert: &Alert) -> Result<(), String> {
+            self.redeliver(&json!(alert)).await
+        }
+
+        async fn redeliver(&self, payload: &Value) -> Result<(), String> {
+            if self.down.load(Ordering::SeqCst) {
+                return Err("webhook answered 503 Service Unavailable".to_string());
+            }
+            self.received.lock().unwrap().push(payload.clone());
+            Ok(())
+        }
+    }
+
+    fn clock() -> Arc<FixedClock> {
+        Arc::new(FixedClock::new(
+            Utc.with_ymd_and_hms(2023, 5, 3, 12, 0, 0).unwrap(),
+        ))
+    }
+
+    async fn body(response: Response) -> Value {
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn failed_deliveries_are_kept_and_replayed_until_delivered() {
+        let clock = clock();
+        let letters: Arc<dyn DeadLetterStore> =
+            Arc::new(InMemoryDeadLetterStore::new(clock.clone()));
+        let webhook = Arc::new(Webhook::default());
+        webhook.down.store(true, Ordering::SeqCst);
+        let alerts = Alerts::new(vec![webhook.clone()]).with_dead_letters(letters.clone());
+
+        alerts.send(Alert {
+            kind: "anomaly",
+            message: "NoteDeleted spiked".to_string(),
+            context: json!({ "count": 50 }),
+            at: clock.now(),
+        });
+        let mut open = Vec::new();
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+            open = letters.list("open", 50).await.unwrap();
+            if !open.is_empty() {
+                break;
+            }
+        }
+        assert_eq!(open.len(), 1);
+        let id = open[0].id;
+        assert_eq!(open[0].target, "alert-webhook");
+        assert_eq!(open[0].kind, "anomaly");
+        assert_eq!(open[0].attempts, 1);
+        assert!(open[0].error.contains("503"));
+
+        // Still down: the attempt and its error are recorded.
+        clock.advance(chrono::Duration::minutes(5));
+        let (letter, error) = replay(letters.as_ref(), &alerts, id, clock.now())
+            .await
+            .unwrap();
+        assert!(error.unwrap().contains("503"));
+        assert_eq!(letter.attempts, 2);
+        assert!(letter.last_replay_error.is_some());
+        assert_eq!(letter.replayed_at, None);
+
+        webhook.down.store(false, Ordering::SeqCst);
+        let (letter, error) = replay(letters.as_ref(), &alerts, id, clock.now())
+            .await
+            .unwrap();
+        assert_eq!(error, None);
+        assert_eq!(letter.attempts, 3);
+        assert_eq!(letter.last_replay_error, None);
+        assert_eq!(letter.replayed_at, Some(clock.now()));
+        let received = webhook.received.lock().unwrap().clone();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0]["message"], "NoteDeleted spiked");
+
+        assert!(letters.list("open", 50).await.unwrap().is_empty());
+        assert_eq!(letters.list("replayed", 50).await.unwrap().len(), 1);
+        assert!(matches!(
+            replay(letters.as_ref(), &alerts, id, clock.now()).await,
+            Err(AppError::Conflict(_))
+        ));
+        assert!(matches!(
+            replay(letters.as_ref(), &alerts, id + 1, clock.now()).await,
+            Err(AppError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn bulk_replays_report_on_each_letter() {
+        let app = TestApp::new();
+        let letters = &app.state.dead_letters;
+        let payload = json!({ "kind": "anomaly", "message": "Spike" });
+        // Test apps deliver to `recorded`; there is no webhook to replay to.
+        letters
+            .record("recorded", "anomaly", &payload, "timed out")
+            .await
+            .unwrap();
+        letters
+            .record("alert-webhook", "anomaly", &payload, "timed out")
+            .await
+            .unwrap();
+
+        let response = replay_dead_letters_handler(
+            State(app.state.clone()),
+            Json(ReplaySchema {
+                ids: vec![1, 2, 3],
+                job_ids: Vec::new(),
+            }),
+        )
+        .await
+        .unwrap()
+        .into_response();
+        let results = body(response).await["results"].clone();
+        assert_eq!(results[0], json!({ "id": 1, "status": "replayed" }));
+        assert_eq!(results[1]["status"], "failed");
+        assert_eq!(results[1]["error"], "no 'alert-webhook' sink is configured");
+        assert_eq!(results[2]["status"], "skipped");
+        assert_eq!(app.alerts().await, vec![payload]);
+
+        // Replayed letters are skipped the next time round.
+        let response = replay_dead_letters_handler(
+            State(app.state.clone()),
+            Json(ReplaySchema {
+                ids: vec![1],
+                job_ids: Vec::new(),
+            }),
+        )
+        .await
+        .unwrap()
+        .into_response();
+        assert_eq!(body(response).await["results"][0]["status"], "skipped");
+
+        let response = list_dead_letters_handler(
+            Query(DeadLetterOptions {
+                status: Some("replayed".to_string()),
+                limit: None,
+            }),
+            State(app.state.clone()),
+        )
+        .await
+        .unwrap()
+        .into_response();
+        let listed = body(response).await;
+        assert_eq!(listed["results"], 1);
+        assert_eq!(listed["dead_letters"][0]["attempts"], 2);
+    }
+
+    #[tokio::test]
+    async fn bulk_replays_are_bounded() {
+        let app = TestApp::new();
+        for ids in [Vec::new(), (1..=MAX_REPLAY_BATCH as u64 + 1).collect()] {
+            let replayed = replay_dead_letters_handler(
+                State(app.state.clone()),
+                Json(ReplaySchema {
+                    ids,
+                    job_ids: Vec::new(),
+                }),
+            )
+            .await;
+            assert!(matches!(replayed, Err(AppError::Validation(_))));
+        }
+    }
+}
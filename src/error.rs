@@ -0,0 +1,317 @@
+//! The error type of the note handlers.
+//!
+//! Client errors answer `{"status": "fail", "message": ...}` and server errors
+//! `{"status": "error", "message": ...}`, the shape every endpoint used when
+//! building its error tuples by hand. Database errors are logged rather than
+//! shown to the client. Every error body also gets the `request_id` of the
+//! request; see [`crate::request_id`].
+//!
+//! Clients sending `Accept: application/problem+json` get RFC 7807 problem
+//! details instead, from every failure path: [`layer`] rewrites error
+//! responses on the way out, wherever they were built. `message` becomes
+//! `detail`, the path requested becomes `instance`, and other fields such as
+//! `errors` and `request_id` are kept as extension members.
+
+use std::{fmt, sync::Arc};
+
+use axum::{
+    body,
+    http::{header, HeaderValue, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Json, Router,
+};
+use serde_json::{json, Map, Value};
+
+use crate::validation::FieldErrors;
+
+#[derive(Debug)]
+pub enum AppError {
+    NotFound(String),
+    Forbidden(String),
+    Conflict(String),
+    Validation(String),
+    /// Fields of a note payload that failed validation, answered with a 422
+    /// listing each of them under `errors`.
+    InvalidFields(FieldErrors),
+    /// Shared so that errors of coalesced reads convert as well.
+    Database(Arc<sqlx::Error>),
+    /// A response built by a module that still returns
+    /// `(StatusCode, Json<Value>)`, passed through unchanged.
+    Response(StatusCode, Json<Value>),
+}
+
+impl AppError {
+    pub fn note_not_found(id: impl fmt::Display) -> Self {
+        AppError::NotFound(format!("Note with ID: {} not found", id))
+    }
+}
+
+impl From<sqlx::Error> for AppError {
+    fn from(err: sqlx::Error) -> Self {
+        AppError::Database(Arc::new(err))
+    }
+}
+
+impl From<Arc<sqlx::Error>> for AppError {
+    fn from(err: Arc<sqlx::Error>) -> Self {
+        AppError::Database(err)
+    }
+}
+
+impl From<(StatusCode, Json<Value>)> for AppError {
+    fn from((status, body): (StatusCode, Json<Value>)) -> Self {
+        AppError::Response(status, body)
+    }
+}
+
+/// For modules that still return `(StatusCode, Json<Value>)`.
+impl From<AppError> for (StatusCode, Json<Value>) {
+    fn from(err: AppError) -> Self {
+        let (status, message) = match err {
+            AppError::NotFound(message) => (StatusCode::NOT_FOUND, message),
+            AppError::Forbidden(message) => (StatusCode::FORBIDDEN, message),
+            AppError::Conflict(message) => (StatusCode::CONFLICT, message),
+            AppError::Validation(message) => (StatusCode::BAD_REQUEST, message),
+            AppError::InvalidFields(errors) => {
+                let error_response = json!({
+                    "status": "fail",
+                    "message": "Invalid note",
+                    "errors": errors.into_map(),
+                });
+                return (StatusCode::UNPROCESSABLE_ENTITY, Json(error_response));
+            }
+            AppError::Database(err) => {
+                // The details stay in the log: they can name tables, columns
+                // and values that are none of the client's business.
+                tracing::error!(error = %err, "database error");
+                let error_response = json!({
+                    "status": "error",
+                    "message": "Database error",
+                });
+                return (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response));
+            }
+            AppError::Response(status, body) => return (status, body),
+        };
+        let error_response = json!({
+            "status": "fail",
+            "message": message,
+        });
+        (status, Json(error_response))
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        <(StatusCode, Json<Value>)>::from(self).into_response()
+    }
+}
+
+const PROBLEM_JSON: &str = "application/problem+json";
+
+/// Wraps `app` so that clients accepting problem details get their errors as
+/// such. Goes outside [`crate::request_id::layer`], to keep its `request_id`.
+pub fn layer(app: Router) -> Router {
+    app.layer(middleware::from_fn(problem_details))
+}
+
+fn accepts_problem_json<B>(req: &Request<B>) -> bool {
+    req.headers()
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|accept| accept.split(','))
+        .any(|range| {
+            let media_type = range.split(';').next().unwrap_or_default().trim();
+            media_type.eq_ignore_ascii_case(PROBLEM_JSON)
+        })
+}
+
+/// Rewrites error responses as problem details, for clients that asked.
+/// Error bodies that are not JSON objects, such as the plain text of axum's
+/// extractor rejections, become the `detail`.
+async fn problem_details<B>(req: Request<B>, next: Next<B>) -> Response {
+    let wanted = accepts_problem_json(&req);
+    let instance = req.uri().path().to_string();
+    let response = next.run(req).await;
+
+    let status = response.status();
+    if !wanted || !(status.is_client_error() || status.is_server_error()) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let Ok(bytes) = hyper::body::to_bytes(body).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let is_json = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|value| value == "application/json");
+
+    let mut problem = Map::new();
+    problem.insert("type".to_string(), json!("about:blank"));
+    problem.insert(
+        "title".to_string(),
+        json!(status.canonical_reason().unwrap_or("Error")),
+    );
+    problem.insert("status".to_string(), json!(status.as_u16()));
+    match serde_json::from_slice::<Value>(&bytes) {
+        Ok(Value::Object(mut fields)) if is_json => {
+            // `status` is the HTTP status in problem details.
+            fields.remove("status");
+            if let Some(message) = fields.remove("message") {
+                problem.insert("detail".to_string(), message);
+            }
+            for (key, value) in fields {
+                problem.entry(key).or_insert(value);
+            }
+        }
+        _ => {
+            let text = String::from_utf8_lossy(&bytes).trim().to_string();
+            if !text.is_empty() {
+                problem.insert("detail".to_string(), json!(text));
+            }
+        }
+    }
+    problem.insert("instance".to_string(), json!(instance));
+
+    parts
+        .headers
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+    parts.headers.remove(header::CONTENT_LENGTH);
+    let body = Value::Object(problem).to_string();
+    Response::from_parts(parts, body::boxed(body::Full::from(body)))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, routing::get};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn app() -> Router {
+        layer(
+            Router::new()
+                .route(
+                    "/missing",
+                    get(|| async { AppError::note_not_found("abc") }),
+                )
+                .route(
+                    "/invalid",
+                    get(|| async {
+                        AppError::InvalidFields(FieldErrors::single(
+                            "title",
+                            "must not be empty".to_string(),
+                        ))
+                    }),
+                )
+                .route(
+                    "/database",
+                    get(|| async {
+                        AppError::from(sqlx::Error::Protocol(
+                            "Unknown column 'secret' in 'users'".to_string(),
+                        ))
+                    }),
+                )
+                .route(
+                    "/rejected",
+                    get(|| async { (StatusCode::BAD_REQUEST, "Bad query") }),
+                )
+                .route("/ok", get(|| async { Json(json!({"status": "success"})) })),
+        )
+    }
+
+    async fn call(path: &str, accept: Option<&str>) -> (StatusCode, Option<String>, Value) {
+        let mut req = Request::builder().uri(path);
+        if let Some(accept) = accept {
+            req = req.header(header::ACCEPT, accept);
+        }
+        let response = app()
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .map(|value| value.to_str().unwrap().to_string());
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (
+            status,
+            content_type,
+            serde_json::from_slice(&bytes).unwrap_or(Value::Null),
+        )
+    }
+
+    #[tokio::test]
+    async fn errors_are_unchanged_for_other_clients() {
+        for accept in [None, Some("application/json"), Some("*/*")] {
+            let (status, content_type, body) = call("/missing", accept).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            assert_eq!(content_type.as_deref(), Some("application/json"));
+            assert_eq!(
+                body,
+                json!({"status": "fail", "message": "Note with ID: abc not found"})
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn errors_become_problem_details_when_accepted() {
+        let accept = "text/html, Application/Problem+JSON;q=0.9";
+        let (status, content_type, body) = call("/missing", Some(accept)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(content_type.as_deref(), Some(PROBLEM_JSON));
+        assert_eq!(
+            body,
+            json!({
+                "type": "about:blank",
+                "title": "Not Found",
+                "status": 404,
+                "detail": "Note with ID: abc not found",
+                "instance": "/missing",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn problem_details_keep_other_fields_as_extensions() {
+        let (status, _, body) = call("/invalid", Some(PROBLEM_JSON)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["status"], json!(422));
+        assert_eq!(body["detail"], json!("Invalid note"));
+        assert_eq!(body["errors"]["title"], json!("must not be empty"));
+    }
+
+    #[tokio::test]
+    async fn database_errors_are_not_shown_to_clients() {
+        let (status, _, body) = call("/database", None).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            body,
+            json!({"status": "error", "message": "Database error"})
+        );
+
+        let (_, _, body) = call("/database", Some(PROBLEM_JSON)).await;
+        assert_eq!(body["detail"], json!("Database error"));
+        assert!(!body.to_string().contains("secret"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn plain_text_errors_become_the_detail() {
+        let (status, _, body) = call("/rejected", Some(PROBLEM_JSON)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["title"], json!("Bad Request"));
+        assert_eq!(body["detail"], json!("Bad query"));
+    }
+
+    #[tokio::test]
+    async fn successes_are_left_alone() {
+        let (status, content_type, body) = call("/ok", Some(PROBLEM_JSON)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type.as_deref(), Some("application/json"));
+        assert_eq!(body, json!({"status": "success"}));
+    }
+}
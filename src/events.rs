@@ -0,0 +1,440 @@
+//! In-process hub for note change events.
+//!
+//! Every handler that mutates a note publishes here. Events carry a
+//! monotonically increasing sequence number and the most recent ones are
+//! kept in a replay buffer, so a client can resume from the last sequence it
+//! saw. The hub is per instance: with several replicas, clients only see the
+//! changes made through the instance they are connected to.
+//!
+//! Clients follow changes with the `GET /api/notes/stream` event stream, or
+//! with `GET /api/notes/changes/poll` where streaming is not an option.
+
+use std::{collections::VecDeque, convert::Infallible, sync::Mutex, time::Duration};
+
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
+    Json,
+};
+use chrono::{DateTime, Utc};
+use futures_util::{stream, Stream};
+use serde::Serialize;
+use serde_json::json;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    auth::NoteScope,
+    context::RequestContext,
+    error::AppError,
+    model::{NoteId, UserId, Visibility},
+    schema::PollOptions,
+    state::EventBus,
+};
+
+const REPLAY_CAPACITY: usize = 1024;
+const DEFAULT_POLL_WAIT: Duration = Duration::from_secs(30);
+/// Kept under the 60s idle timeout most proxies and load balancers default to.
+const MAX_POLL_WAIT: Duration = Duration::from_secs(55);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NoteEventKind {
+    Created,
+    Updated,
+    /// Moved to the trash; [`NoteEventKind::Deleted`] follows if it is purged.
+    Trashed,
+    Restored,
+    Deleted,
+    /// Follows the [`NoteEventKind::Updated`] of the edit that published
+    /// the note.
+    Published,
+    /// Follows the [`NoteEventKind::Updated`] of the edit that unpublished
+    /// the note.
+    Unpublished,
+    /// A user other than the owner opened the note for the first time; see
+    /// [`crate::read_receipt`]. The note itself is unchanged.
+    Read,
+}
+
+impl NoteEventKind {
+    /// The event of a note going from `before` to `after`, when that
+    /// published or unpublished it.
+    pub fn visibility_change(before: Visibility, after: Visibility) -> Option<Self> {
+        match (before, after) {
+            (Visibility::Draft, Visibility::Published) => Some(Self::Published),
+            (Visibility::Published, Visibility::Draft) => Some(Self::Unpublished),
+            _ => None,
+        }
+    }
+
+    /// The name of the event in the event stream.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Created => "created",
+            Self::Updated => "updated",
+            Self::Trashed => "trashed",
+            Self::Restored => "restored",
+            Self::Deleted => "deleted",
+            Self::Published => "published",
+            Self::Unpublished => "unpublished",
+            Self::Read => "read",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NoteEvent {
+    pub seq: u64,
+    pub kind: NoteEventKind,
+    pub note_id: NoteId,
+    pub at: DateTime<Utc>,
+    /// Who owns the note, to show user accounts only their own changes.
+    #[serde(skip)]
+    pub owner: Option<UserId>,
+    /// The request that caused the change, if a request did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// Returned when a client resumes from a sequence that has been evicted from
+/// the replay buffer, or that this instance never issued (e.g. after a
+/// restart). The client has to resynchronise and continue from `last_seq`.
+#[derive(Debug, Clone, Copy)]
+pub struct CursorExpired {
+    pub last_seq: u64,
+}
+
+struct Replay {
+    next_seq: u64,
+    events: VecDeque<NoteEvent>,
+}
+
+pub struct EventHub {
+    tx: broadcast::Sender<NoteEvent>,
+    replay: Mutex<Replay>,
+}
+
+impl Default for EventHub {
+    fn default() -> Self {
+        let (tx, _) = broadcast::channel(REPLAY_CAPACITY);
+        Self {
+            tx,
+            replay: Mutex::new(Replay {
+                next_seq: 1,
+                events: VecDeque::with_capacity(REPLAY_CAPACITY),
+            }),
+        }
+    }
+}
+
+impl EventHub {
+    pub fn publish(
+        &self,
+        kind: NoteEventKind,
+        note_id: NoteId,
+        owner: Option<UserId>,
+        at: DateTime<Utc>,
+    ) {
+        // Sequencing and sending under one lock keeps the broadcast order
+        // identical to the replay order.
+        let mut replay = self.replay.lock().unwrap();
+        let event = NoteEvent {
+            seq: replay.next_seq,
+            kind,
+            note_id,
+            at,
+            owner,
+            request_id: RequestContext::current().and_then(|context| context.request_id),
+        };
+        replay.next_seq += 1;
+        if replay.events.len() == REPLAY_CAPACITY {
+            replay.events.pop_front();
+        }
+        replay.events.push_back(event.clone());
+        let _ = self.tx.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<NoteEvent> {
+        self.tx.subscribe()
+    }
+
+    /// Sequence number of the most recent event, 0 before the first one.
+    pub fn last_seq(&self) -> u64 {
+        self.replay.lock().unwrap().next_seq - 1
+    }
+
+    /// Events published after `since`, oldest first.
+    pub fn since(&self, since: u64) -> Result<Vec<NoteEvent>, CursorExpired> {
+        let replay = self.replay.lock().unwrap();
+        let last_seq = replay.next_seq - 1;
+        let evicted = replay
+            .events
+            .front()
+            .is_some_and(|oldest| since + 1 < oldest.seq);
+        if evicted || since > last_seq {
+            return Err(CursorExpired { last_seq });
+        }
+        Ok(replay
+            .events
+            .iter()
+            .filter(|event| event.seq > since)
+            .cloned()
+            .collect())
+    }
+}
+
+/// Parses `wait` values such as `30s`, `1500ms`, `1m` or a bare number of
+/// seconds.
+fn parse_wait(wait: &str) -> Option<Duration> {
+    let wait = wait.trim();
+    let split = wait
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(wait.len());
+    let (number, unit) = wait.split_at(split);
+    let number: u64 = number.parse().ok()?;
+    match unit {
+        "ms" => Some(Duration::from_millis(number)),
+        "" | "s" => Some(Duration::from_secs(number)),
+        "m" => Some(Duration::from_secs(number * 60)),
+        _ => None,
+    }
+}
+
+fn cursor_expired(expired: CursorExpired) -> AppError {
+    let error_response = json!({
+        "status": "fail",
+        "message": "Cursor is no longer available, reload notes and resume from the returned cursor",
+        "cursor": expired.last_seq,
+    });
+    AppError::Response(StatusCode::GONE, Json(error_response))
+}
+
+/// `GET /api/notes/changes/poll?since=<cursor>&wait=30s`
+///
+/// Long-polling fallback for clients whose proxies break streaming
+/// responses. Answers immediately when changes after `since` are buffered,
+/// otherwise holds the request until the next change or until `wait` runs
+/// out, in which case `events` is empty. The cursor then only moves past
+/// changes the caller may not see; user accounts are only shown changes to
+/// their own notes. Without `since` the client only sees changes made after
+/// the request arrived.
+#[utoipa::path(
+    get,
+    path = "/api/notes/changes/poll",
+    tag = "notes",
+    params(PollOptions),
+    responses(
+        (status = 200, description = "Changes after the cursor"),
+        (status = 400, description = "Invalid cursor", body = ErrorResponse),
+        (status = 410, description = "The cursor is too old", body = ErrorResponse),
+    ),
+)]
+pub async fn poll_changes_handler(
+    Query(opts): Query<PollOptions>,
+    scope: NoteScope,
+    State(events): State<EventBus>,
+) -> Result<impl IntoResponse, AppError> {
+    let wait = match opts.wait.as_deref() {
+        None => DEFAULT_POLL_WAIT,
+        Some(raw) => parse_wait(raw).ok_or_else(|| {
+            AppError::Validation(format!(
+                "Invalid wait '{}', expected e.g. 30s or 500ms",
+                raw
+            ))
+        })?,
+    }
+    .min(MAX_POLL_WAIT);
+
+    // Subscribe before reading the replay buffer so nothing published in
+    // between is missed.
+    let mut rx = events.subscribe();
+    let mut cursor = opts.since.unwrap_or_else(|| events.last_seq());
+    let mut received = events.since(cursor).map_err(cursor_expired)?;
+    let mut changes = Vec::new();
+
+    let deadline = tokio::time::Instant::now() + wait;
+    loop {
+        // Changes the caller may not see still move the cursor past them.
+        if let Some(event) = received.last() {
+            cursor = event.seq;
+        }
+        changes.extend(
+            received
+                .drain(..)
+                .filter(|event| scope.permits(event.owner)),
+        );
+        if !changes.is_empty() {
+            break;
+        }
+        match tokio::time::timeout_at(deadline, rx.recv()).await {
+            Err(_) | Ok(Err(RecvError::Closed)) => break,
+            Ok(Ok(event)) => {
+                if event.seq > cursor {
+                    received.push(event);
+                    // Pick up the rest of a burst in the same response.
+                    while let Ok(event) = rx.try_recv() {
+                        received.push(event);
+                    }
+                }
+            }
+            Ok(Err(RecvError::Lagged(_))) => {
+                received = events.since(cursor).map_err(cursor_expired)?;
+            }
+        }
+    }
+
+    Ok(Json(json!({
+        "status": "success",
+        "cursor": cursor,
+        "results": changes.len(),
+        "events": changes,
+    })))
+}
+
+/// What a change stream still has to send: buffered events first, then
+/// whatever the hub broadcasts after `last_seq`.
+struct Follow {
+    events: EventBus,
+    scope: NoteScope,
+    rx: broadcast::Receiver<NoteEvent>,
+    pending: VecDeque<NoteEvent>,
+    last_seq: u64,
+}
+
+impl Follow {
+    /// The next event, or `None` once the stream cannot continue without
+    /// missing changes; the client then reconnects with `Last-Event-ID` and
+    /// is told whether it can resume.
+    async fn next(&mut self) -> Option<NoteEvent> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                self.last_seq = event.seq;
+                if self.scope.permits(event.owner) {
+                    return Some(event);
+                }
+                continue;
+            }
+            match self.rx.recv().await {
+                Ok(event) if event.seq > self.last_seq => self.pending.push_back(event),
+                Ok(_) => {}
+                Err(RecvError::Lagged(_)) => {
+                    self.pending = self.events.since(self.last_seq).ok()?.into();
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+/// `GET /api/notes/stream`
+///
+/// Server-sent events named after the change (`created`, `updated`,
+/// `published`, `unpublished`, `trashed`, `restored`, `deleted`), with the
+/// sequence number as the event id and the event as JSON data. A
+/// reconnecting client that sends `Last-Event-ID` first receives the changes
+/// it missed, or a 410 when they are no longer buffered. Without it the
+/// stream starts at the next change. User accounts are only sent changes to
+/// their own notes.
+#[utoipa::path(
+    get,
+    path = "/api/notes/stream",
+    tag = "notes",
+    params(
+        ("Last-Event-ID" = Option<u64>, Header, description = "Resume after this sequence number"),
+    ),
+    responses(
+        (status = 200, description = "An event stream of changes", body = String, content_type = "text/event-stream"),
+        (status = 400, description = "Invalid Last-Event-ID", body = ErrorResponse),
+        (status = 410, description = "The missed changes are no longer available", body = ErrorResponse),
+    ),
+)]
+pub async fn stream_changes_handler(
+    scope: NoteScope,
+    headers: HeaderMap,
+    State(events): State<EventBus>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AppError> {
+    let resume_from = match headers.get("last-event-id") {
+        None => None,
+        Some(value) => Some(
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| value.trim().parse::<u64>().ok())
+                .ok_or_else(|| AppError::Validation("Invalid Last-Event-ID".to_string()))?,
+        ),
+    };
+
+    // Subscribe before reading the replay buffer so nothing published in
+    // between is missed.
+    let rx = events.subscribe();
+    let last_seq = resume_from.unwrap_or_else(|| events.last_seq());
+    let pending = events.since(last_seq).map_err(cursor_expired)?;
+
+    let follow = Follow {
+        events,
+        scope,
+        rx,
+        pending: pending.into(),
+        last_seq,
+    };
+    let events = stream::unfold(follow, |mut follow| async move {
+        let event = follow.next().await?;
+        let sse = Event::default()
+            .id(event.seq.to_string())
+            .event(event.kind.as_str())
+            .json_data(&event)
+            .expect("note events serialize");
+        Some((Ok(sse), follow))
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Method;
+
+    use crate::{
+        model::{BinaryId, UserId},
+        testing::TestApp,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn user_accounts_only_see_changes_to_their_own_notes() {
+        let app = TestApp::with_accounts();
+        let alice = app.token(UserId(BinaryId(uuid::Uuid::new_v4())));
+        let bob = app.token(UserId(BinaryId(uuid::Uuid::new_v4())));
+
+        let body = json!({ "title": "Alice's", "content": "Mine" });
+        let (status, _, body) = app
+            .send(Method::POST, "/api/notes", Some(&alice), Some(body))
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        let alices = body["data"]["note"]["id"].clone();
+        let body = json!({ "title": "Bob's", "content": "Mine" });
+        let (status, _, _) = app
+            .send(Method::POST, "/api/notes", Some(&bob), Some(body))
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let uri = "/api/notes/changes/poll?since=0&wait=0s";
+        let (status, _, body) = app.send(Method::GET, uri, Some(&alice), None).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["results"], json!(1));
+        assert_eq!(body["events"][0]["note_id"], alices);
+        assert_eq!(body["cursor"], json!(2));
+
+        // Bob's own change is the last one, so Alice's cursor moves past it
+        // without showing it.
+        let uri = "/api/notes/changes/poll?since=1&wait=0s";
+        let (status, _, body) = app.send(Method::GET, uri, Some(&alice), None).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["results"], json!(0));
+        assert_eq!(body["cursor"], json!(2));
+    }
+}
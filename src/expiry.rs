@@ -0,0 +1,190 @@
+//! Self-destructing notes, for ephemeral credentials or meeting notes.
+//!
+//! A note created or edited with an `expires_at` vanishes from every read
+//! once that time passes, and the leader purges it for good on its next
+//! sweep, every `NOTE_EXPIRY_SWEEP_SECS`, whether or not it is in the trash.
+//! Purges publish [`NoteEventKind::Deleted`], so tags, attachments and
+//! revisions go as they do for notes purged from the trash. Until then,
+//! reads of a note expiring within [`WARNING_WINDOW`] carry an
+//! `expiry_warning`.
+
+use std::{sync::Arc, time::Duration};
+
+use chrono::{DateTime, Timelike, Utc};
+
+use crate::{
+    error::AppError,
+    events::NoteEventKind,
+    model::{NoteId, NoteModel, UserId},
+    validation::FieldErrors,
+    AppState,
+};
+
+pub const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+/// How long before expiry reads start warning about it.
+pub const WARNING_WINDOW: chrono::Duration = chrono::Duration::hours(24);
+/// Notes purged per statement; a full batch is followed by another at once.
+const BATCH: u32 = 500;
+
+/// `expires_at` truncated to the whole seconds TIMESTAMP columns keep, if it
+/// is still in the future at `now`.
+pub fn check(expires_at: DateTime<Utc>, now: DateTime<Utc>) -> Result<DateTime<Utc>, AppError> {
+    let expires_at = expires_at.with_nanosecond(0).unwrap();
+    if expires_at <= now {
+        return Err(AppError::InvalidFields(FieldErrors::single(
+            "expires_at",
+            "must be in the future".to_string(),
+        )));
+    }
+    Ok(expires_at)
+}
+
+/// Says when the note expires, once that is within [`WARNING_WINDOW`].
+pub fn warning(note: &NoteModel, now: DateTime<Utc>) -> Option<String> {
+    let expires_at = note.expires_at?;
+    let left = expires_at - now;
+    if left > WARNING_WINDOW {
+        return None;
+    }
+    let left = match (left.num_hours(), left.num_minutes()) {
+        (hours, _) if hours > 1 => format!("{} hours", hours),
+        (1, _) => "1 hour".to_string(),
+        (_, minutes) if minutes > 1 => format!("{} minutes", minutes),
+        _ => "less than 2 minutes".to_string(),
+    };
+    Some(format!(
+        "This note expires in {}, at {}",
+        left,
+        expires_at.to_rfc3339()
+    ))
+}
+
+/// Purges expired notes every `interval`, on the leader only.
+pub async fn run(data: Arc<AppState>, interval: Duration) {
+    loop {
+        match purge_expired(&data).await {
+            Ok(purged) if purged == BATCH as usize => continue,
+            Ok(_) => {}
+            Err(err) => tracing::error!(error = ?err, "Failed to purge expired notes"),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Deletes up to [`BATCH`] expired notes, returning how many it looked at.
+async fn purge_expired(data: &AppState) -> Result<usize, sqlx::Error> {
+    let now = data.clock.now();
+    let expired: Vec<(NoteId, Option<UserId>)> = sqlx::query_as(
+        "SELECT id, user_id FROM notes WHERE expires_at <= ? ORDER BY expires_at LIMIT ?",
+    )
+    .bind(now)
+    .bind(BATCH)
+    .fetch_all(&data.db)
+    .await?;
+
+    for (id, owner) in &expired {
+        // Unless its expiry was pushed back since it was selected.
+        let result = sqlx::query("DELETE FROM notes WHERE id = ? AND expires_at <= ?")
+            .bind(id)
+            .bind(now)
+            .execute(&data.db)
+            .await?;
+        if result.rows_affected() > 0 {
+            data.events
+                .publish(NoteEventKind::Deleted, *id, *owner, data.clock.now());
+        }
+    }
+    if !expired.is_empty() {
+        tracing::info!(notes = expired.len(), "Purged expired notes");
+    }
+    Ok(expired.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::{
+        clock::{Clock, FixedClock},
+        model::{NoteStatus, Title},
+    };
+
+    fn frozen() -> FixedClock {
+        FixedClock::new(Utc.with_ymd_and_hms(2023, 5, 3, 12, 0, 0).unwrap())
+    }
+
+    fn note_expiring_at(expires_at: Option<DateTime<Utc>>) -> NoteModel {
+        NoteModel {
+            id: NoteId::from(uuid::Uuid::nil()),
+            title: Title::parse("Meeting notes").unwrap(),
+            content: String::new(),
+            preview: String::new(),
+            category: String::new(),
+            category_id: None,
+            published: false.into(),
+            published_at: None,
+            status: NoteStatus::Draft,
+            expires_at,
+            view_count: 0,
+            last_accessed_at: None,
+            created_at: None,
+            updated_at: None,
+            user_id: None,
+            deleted_at: None,
+            version: 1,
+            content_key: None,
+        }
+    }
+
+    #[test]
+    fn check_accepts_only_future_times() {
+        let clock = frozen();
+        let now = clock.now();
+        assert!(check(now, now).is_err());
+        assert!(check(now - chrono::Duration::seconds(1), now).is_err());
+        assert_eq!(
+            check(now + chrono::Duration::minutes(5), now).unwrap(),
+            now + chrono::Duration::minutes(5)
+        );
+    }
+
+    #[test]
+    fn check_truncates_to_whole_seconds() {
+        let now = frozen().now();
+        let expires_at = now + chrono::Duration::milliseconds(1500);
+        assert_eq!(
+            check(expires_at, now).unwrap(),
+            now + chrono::Duration::seconds(1)
+        );
+        // Truncation can land on now itself.
+        assert!(check(now + chrono::Duration::milliseconds(500), now).is_err());
+    }
+
+    #[test]
+    fn warning_starts_within_the_window() {
+        let clock = frozen();
+        let note = note_expiring_at(Some(clock.now() + chrono::Duration::hours(30)));
+        assert_eq!(warning(&note, clock.now()), None);
+        assert_eq!(warning(&note_expiring_at(None), clock.now()), None);
+
+        clock.advance(chrono::Duration::hours(10));
+        let warned = warning(&note, clock.now()).unwrap();
+        assert!(warned.starts_with("This note expires in 20 hours, at "));
+
+        clock.advance(chrono::Duration::hours(19));
+        assert!(warning(&note, clock.now())
+            .unwrap()
+            .starts_with("This note expires in 1 hour,"));
+
+        clock.advance(chrono::Duration::minutes(50));
+        assert!(warning(&note, clock.now())
+            .unwrap()
+            .starts_with("This note expires in 10 minutes,"));
+
+        clock.advance(chrono::Duration::minutes(9));
+        assert!(warning(&note, clock.now())
+            .unwrap()
+            .starts_with("This note expires in less than 2 minutes,"));
+    }
+}
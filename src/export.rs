@@ -0,0 +1,575 @@
+//! Exports of notes, for archiving outside the API.
+//!
+//! `GET /api/notes/:id/export.html` renders the note as one self-contained
+//! file: styles are inlined and nothing is loaded from elsewhere, so the page
+//! reads the same offline. Content is kept as written (Markdown included) in a
+//! wrapped, preformatted block rather than being rendered. Attachments up to
+//! `EXPORT_EMBED_MAX_BYTES` are embedded as `data:` URIs, images shown
+//! inline; bigger ones are only listed by name.
+//!
+//! `POST /api/exports` exports every note in scope, with its tags, as one
+//! JSON document. That takes a while, so it is an operation (see
+//! [`crate::operation`]) worked on by an `export` job a page of notes at a
+//! time. Cancelling it stops the job before its next page; the notes
+//! exported so far are dropped.
+
+use std::{error::Error, fmt::Write, sync::Arc};
+
+use async_trait::async_trait;
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{header, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use serde_json::json;
+
+use crate::{
+    attachment::AttachmentModel,
+    auth::NoteScope,
+    error::AppError,
+    handler::filter_db_record,
+    job::JobHandler,
+    model::{BinaryId, NoteId, NoteModel, TagModel},
+    ocr::read_attachment,
+    operation,
+    repository::NoteFilter,
+    tag::SELECT_NOTE_TAGS,
+    AppState,
+};
+
+pub const JOB_KIND: &str = "export";
+pub const DEFAULT_EMBED_MAX_BYTES: usize = 1024 * 1024;
+/// Notes read at once, and how often progress is recorded.
+const EXPORT_PAGE_SIZE: usize = 200;
+
+const STYLE: &str = "\
+body{margin:0;background:#f6f6f4;color:#222;font:16px/1.6 -apple-system,BlinkMacSystemFont,\"Segoe UI\",Helvetica,Arial,sans-serif}\
+main{max-width:46rem;margin:2rem auto;padding:2rem 2.5rem;background:#fff;border:1px solid #e2e2de;border-radius:6px}\
+h1{margin:0 0 .5rem;font-size:1.8rem;line-height:1.25}\
+.meta{margin:0 0 1.5rem;color:#6b6b66;font-size:.875rem}\
+.tags{margin:0 0 1.5rem;padding:0;list-style:none}\
+.tags li{display:inline-block;margin:0 .4rem .4rem 0;padding:.1rem .6rem;background:#eef1f6;border-radius:999px;font-size:.8rem}\
+.content{margin:0;white-space:pre-wrap;word-wrap:break-word;font:inherit}\
+.attachments h2{margin:2rem 0 1rem;font-size:1.1rem}\
+figure{margin:0 0 1.5rem}\
+figure img{display:block;max-width:100%;height:auto}\
+figcaption,.attachment{margin:.4rem 0;color:#6b6b66;font-size:.875rem}\
+footer{margin-top:2rem;color:#9a9a94;font-size:.75rem}\
+@media print{body{background:#fff}main{margin:0;border:0;padding:0}}";
+
+/// Escapes `text` for use in HTML text and double-quoted attributes.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn format_time(at: Option<DateTime<Utc>>) -> String {
+    at.map_or_else(
+        || "unknown".to_string(),
+        |at| at.format("%Y-%m-%d %H:%M UTC").to_string(),
+    )
+}
+
+fn format_size(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{} bytes", bytes),
+        1024..=1048575 => format!("{:.1} KB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0)),
+    }
+}
+
+/// The media type of a `data:` URI for `content_type`, which uploads set
+/// freely.
+fn media_type(content_type: &str) -> &str {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    let valid = essence.split_once('/').is_some_and(|(kind, subtype)| {
+        [kind, subtype].iter().all(|part| {
+            !part.is_empty()
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "!#$&-^_.+".contains(c))
+        })
+    });
+    if valid {
+        essence
+    } else {
+        "application/octet-stream"
+    }
+}
+
+/// An attachment of an exported note, with its contents when they are
+/// embedded.
+pub struct ExportedAttachment {
+    pub attachment: AttachmentModel,
+    pub contents: Option<Bytes>,
+}
+
+/// Reads back the `attachments` of up to `max_bytes`; bigger ones, and any
+/// that cannot be read, are only listed.
+async fn load_attachments(
+    data: &AppState,
+    attachments: Vec<AttachmentModel>,
+    max_bytes: usize,
+) -> Vec<ExportedAttachment> {
+    let mut exported = Vec::with_capacity(attachments.len());
+    for attachment in attachments {
+        let contents = if attachment.size_bytes <= max_bytes as u64 {
+            match read_attachment(data, &attachment).await {
+                Ok(contents) => Some(contents),
+                Err(err) => {
+                    tracing::warn!(
+                        attachment_id = %attachment.id,
+                        error = %err,
+                        "Failed to read attachment for export",
+                    );
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        exported.push(ExportedAttachment {
+            attachment,
+            contents,
+        });
+    }
+    exported
+}
+
+fn render_attachment(html: &mut String, exported: &ExportedAttachment) {
+    let attachment = &exported.attachment;
+    let name = escape(&attachment.filename);
+    let size = format_size(attachment.size_bytes);
+    let Some(contents) = &exported.contents else {
+        let _ = writeln!(
+            html,
+            "<p class=\"attachment\">{} &middot; {} &middot; not embedded</p>",
+            name, size
+        );
+        return;
+    };
+    let media_type = media_type(&attachment.content_type);
+    let uri = format!("data:{};base64,{}", media_type, STANDARD.encode(contents));
+    if media_type.starts_with("image/") {
+        let _ = writeln!(
+            html,
+            "<figure><img src=\"{uri}\" alt=\"{name}\"><figcaption>{name} &middot; {size}</figcaption></figure>",
+        );
+    } else {
+        let _ = writeln!(
+            html,
+            "<p class=\"attachment\"><a href=\"{uri}\" download=\"{name}\">{name}</a> &middot; {size}</p>",
+        );
+    }
+}
+
+/// The export of `note` as a complete HTML document.
+pub fn render(
+    note: &NoteModel,
+    tags: &[TagModel],
+    attachments: &[ExportedAttachment],
+    exported_at: DateTime<Utc>,
+) -> String {
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>{title}</title>\n<style>{STYLE}</style>\n</head>\n<body>\n<main>\n\
+         <h1>{title}</h1>\n<p class=\"meta\">{category} &middot; created {created} &middot; updated {updated}</p>\n",
+        title = escape(&note.title),
+        category = escape(&note.category),
+        created = format_time(note.created_at),
+        updated = format_time(note.updated_at),
+    );
+    if !tags.is_empty() {
+        html.push_str("<ul class=\"tags\">");
+        for tag in tags {
+            let _ = write!(html, "<li>{}</li>", escape(&tag.name));
+        }
+        html.push_str("</ul>\n");
+    }
+    let _ = writeln!(
+        html,
+        "<pre class=\"content\">{}</pre>",
+        escape(&note.content)
+    );
+    if !attachments.is_empty() {
+        html.push_str("<section class=\"attachments\">\n<h2>Attachments</h2>\n");
+        for attachment in attachments {
+            render_attachment(&mut html, attachment);
+        }
+        html.push_str("</section>\n");
+    }
+    let _ = write!(
+        html,
+        "<footer>Note {} exported {}</footer>\n</main>\n</body>\n</html>\n",
+        note.id,
+        format_time(Some(exported_at)),
+    );
+    html
+}
+
+/// The title of `note` with anything but letters, digits, `-` and `_`
+/// replaced, falling back to the note ID; the name its exports are saved
+/// under.
+pub(crate) fn file_stem(note: &NoteModel) -> String {
+    let stem = note
+        .title
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect::<String>();
+    let stem = stem.trim_matches('-');
+    if stem.is_empty() {
+        note.id.to_string()
+    } else {
+        stem.chars().take(100).collect()
+    }
+}
+
+/// A download filename for the export.
+fn file_name(note: &NoteModel) -> String {
+    format!("{}.html", file_stem(note))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/notes/{id}/export.html",
+    tag = "notes",
+    params(("id" = Uuid, Path, description = "Note ID")),
+    responses(
+        (status = 200, description = "The note as a standalone HTML file", body = String, content_type = "text/html"),
+        (status = 404, description = "Note not found", body = ErrorResponse),
+    ),
+)]
+pub async fn export_html_handler(
+    Path(id): Path<uuid::Uuid>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    let note_id = NoteId::from(id);
+    let note = data
+        .notes
+        .find(note_id)
+        .await?
+        .filter(|note| scope.permits(note.user_id))
+        .ok_or_else(|| AppError::note_not_found(id))?;
+    let tags = sqlx::query_as::<_, TagModel>(SELECT_NOTE_TAGS)
+        .bind(note_id)
+        .fetch_all(&data.db)
+        .await?;
+    let attachments = sqlx::query_as::<_, AttachmentModel>(
+        "SELECT * FROM attachments WHERE note_id = ? ORDER BY created_at, id",
+    )
+    .bind(note_id)
+    .fetch_all(&data.db)
+    .await?;
+    let attachments =
+        load_attachments(&data, attachments, data.settings.export_embed_max_bytes).await;
+
+    let html = render(&note, &tags, &attachments, data.clock.now());
+    let disposition =
+        HeaderValue::from_str(&format!("attachment; filename=\"{}\"", file_name(&note)))
+            .expect("file names are ASCII without quotes");
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("text/html; charset=utf-8"),
+            ),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        html,
+    )
+        .into_response())
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/exports",
+    tag = "operations",
+    responses(
+        (status = 202, description = "The export operation, polled at its Location"),
+    ),
+)]
+pub async fn export_notes_handler(
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    let operation = operation::start(&data, JOB_KIND, scope.owner(), None).await?;
+    Ok(operation::accepted(&operation))
+}
+
+/// Runs the `export` jobs of `POST /api/exports`.
+pub struct NotesExportJob;
+
+impl NotesExportJob {
+    async fn export(
+        &self,
+        data: &AppState,
+        id: BinaryId,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let Some(export) = operation::find(&data.db, id).await? else {
+            return Ok(());
+        };
+        let owner = export.user_id;
+        let total = data
+            .notes
+            .count_filtered(&NoteFilter::default(), owner)
+            .await?;
+        let Some(cancel) = operation::begin(&data.db, id, total as u32, data.clock.now()).await?
+        else {
+            return Ok(());
+        };
+
+        let mut notes = Vec::new();
+        let mut after = None;
+        loop {
+            if cancel.is_cancelled().await? {
+                // Half an export is kept nowhere, so every note in it is undone.
+                let rolled_back = notes.len() as u32;
+                operation::cancelled(&data.db, id, rolled_back, data.clock.now()).await?;
+                return Ok(());
+            }
+            let page = match after {
+                None => data.notes.list_page(owner, EXPORT_PAGE_SIZE, 0).await?,
+                Some(after) => {
+                    data.notes
+                        .list_after(owner, after, EXPORT_PAGE_SIZE)
+                        .await?
+                }
+            };
+            let Some(last) = page.last() else {
+                break;
+            };
+            after = Some(last.id);
+
+            let ids = page.iter().map(|note| note.id).collect::<Vec<_>>();
+            let mut tags = match data.notes.tags(&ids).await {
+                Ok(tags) => tags,
+                Err(err) => {
+                    let message = format!("Left out the tags of {} notes: {}", ids.len(), err);
+                    operation::record_error(&data.db, id, &message, data.clock.now()).await?;
+                    Default::default()
+                }
+            };
+            for note in &page {
+                let names = tags
+                    .remove(&note.id)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|tag| tag.name)
+                    .collect::<Vec<_>>();
+                let mut exported = json!(filter_db_record(note));
+                exported["tags"] = json!(names);
+                notes.push(exported);
+            }
+            operation::advance(&data.db, id, notes.len() as u32, data.clock.now()).await?;
+        }
+
+        let document = json!({
+            "exported_at": data.clock.now(),
+            "results": notes.len(),
+            "notes": notes,
+        });
+        let key = format!("exports/{}.json", id);
+        data.attachments
+            .put(&key, Bytes::from(document.to_string()))
+            .await?;
+        operation::succeed(&data.db, id, &key, data.clock.now()).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl JobHandler for NotesExportJob {
+    fn kind(&self) -> &'static str {
+        JOB_KIND
+    }
+
+    async fn run(&self, data: &AppState, subject: BinaryId) -> Result<(), String> {
+        self.export(data, subject)
+            .await
+            .map_err(|err| err.to_string())
+    }
+
+    async fn give_up(&self, data: &AppState, subject: BinaryId, _error: &str) {
+        operation::fail(data, subject).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    use crate::{
+        handler::new_note,
+        model::{BinaryId, UserId},
+        schema::CreateNoteSchema,
+        testing::TestApp,
+    };
+
+    use super::*;
+
+    fn note(app: &TestApp, title: &str, content: &str) -> NoteModel {
+        let body = CreateNoteSchema {
+            title: title.to_string(),
+            content: content.to_string(),
+            category: Some("Work & <Play>".to_string()),
+            published: None,
+            expires_at: None,
+        };
+        new_note(&app.state, NoteId::from(uuid::Uuid::new_v4()), None, body).unwrap()
+    }
+
+    fn tag(name: &str) -> TagModel {
+        TagModel {
+            id: BinaryId::from(uuid::Uuid::new_v4()),
+            user_id: None,
+            name: name.to_string(),
+            created_at: Utc.with_ymd_and_hms(2023, 5, 3, 12, 0, 0).unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn renders_a_self_contained_escaped_page() {
+        let app = TestApp::new();
+        let note = note(
+            &app,
+            "<script>alert(1)</script>",
+            "# Plan\n\"quoted\" & 'single'",
+        );
+        let exported_at = Utc.with_ymd_and_hms(2023, 6, 1, 8, 30, 0).unwrap();
+        let html = render(&note, &[tag("a<b")], &[], exported_at);
+
+        assert!(html.contains("<title>&lt;script&gt;alert(1)&lt;/script&gt;</title>"));
+        assert!(html.contains("Work &amp; &lt;Play&gt; &middot; created 2023-05-03 12:00 UTC"));
+        assert!(html.contains("<li>a&lt;b</li>"));
+        assert!(html.contains(
+            "<pre class=\"content\"># Plan\n&quot;quoted&quot; &amp; &#39;single&#39;</pre>"
+        ));
+        assert!(html.contains("exported 2023-06-01 08:30 UTC"));
+        assert!(!html.contains("<script>"));
+        // Nothing is loaded from elsewhere.
+        for reference in ["src=", "href=", "url(", "@import"] {
+            assert!(!html.contains(reference), "{}", reference);
+        }
+        // Without tags there is no empty list.
+        assert!(!render(&note, &[], &[], exported_at).contains("class=\"tags\""));
+    }
+
+    fn attachment(
+        note: &NoteModel,
+        filename: &str,
+        content_type: &str,
+        size: usize,
+    ) -> AttachmentModel {
+        let id = BinaryId::from(uuid::Uuid::new_v4());
+        AttachmentModel {
+            id,
+            note_id: note.id,
+            filename: filename.to_string(),
+            content_type: content_type.to_string(),
+            size_bytes: size as u64,
+            sha256: String::new(),
+            storage_key: format!("{}/{}", note.id, id),
+            attachment_text: None,
+            text_status: None,
+            voice_note: false,
+            transcript: None,
+            transcript_status: None,
+            created_at: Utc.with_ymd_and_hms(2023, 5, 3, 12, 0, 0).unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn only_attachments_under_the_cap_are_embedded() {
+        let app = TestApp::new();
+        let note = note(&app, "Trip", "Photos attached");
+        let photo = attachment(&note, "beach.png", "image/png", 4);
+        let notes = attachment(&note, "<notes>.txt", "text/plain\"><b", 5);
+        let video = attachment(&note, "surf.mp4", "video/mp4", 64);
+        for (attachment, contents) in [
+            (&photo, &b"\x89PNG"[..]),
+            (&notes, b"hello"),
+            (&video, &[0; 64]),
+        ] {
+            app.state
+                .attachments
+                .put(&attachment.storage_key, Bytes::copy_from_slice(contents))
+                .await
+                .unwrap();
+        }
+
+        let exported = load_attachments(&app.state, vec![photo, notes, video], 16).await;
+        assert!(exported[0].contents.is_some());
+        assert!(exported[1].contents.is_some());
+        assert!(exported[2].contents.is_none());
+        let html = render(&note, &[], &exported, Utc::now());
+        assert!(html.contains("<img src=\"data:image/png;base64,iVBORw==\" alt=\"beach.png\">"));
+        // The content type of an upload does not get to break out.
+        assert!(html.contains(
+            "<a href=\"data:application/octet-stream;base64,aGVsbG8=\" download=\"&lt;notes&gt;.txt\">"
+        ));
+        assert!(html.contains("surf.mp4 &middot; 64 bytes &middot; not embedded"));
+        assert!(!html.contains("data:video/mp4"));
+        for exported in &exported {
+            let key = &exported.attachment.storage_key;
+            app.state.attachments.delete(key).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn file_names_keep_only_safe_characters() {
+        let app = TestApp::new();
+        assert_eq!(
+            file_name(&note(&app, "Q3 \"plan\" / draft_2", "x")),
+            "Q3--plan----draft_2.html"
+        );
+        let untitled = note(&app, "¿¡", "x");
+        assert_eq!(file_name(&untitled), format!("{}.html", untitled.id));
+    }
+
+    #[tokio::test]
+    async fn other_users_notes_are_not_exported() {
+        let app = TestApp::with_accounts();
+        let owner = app.token(UserId(BinaryId(uuid::Uuid::new_v4())));
+        let other = app.token(UserId(BinaryId(uuid::Uuid::new_v4())));
+        let note = json!({ "title": "Mine", "content": "Private" });
+        let (status, _, body) = app
+            .send(Method::POST, "/api/notes", Some(&owner), Some(note))
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        let uri = format!(
+            "/api/notes/{}/export.html",
+            body["data"]["note"]["id"].as_str().unwrap()
+        );
+
+        let (status, _, _) = app.send(Method::GET, &uri, Some(&other), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let missing = format!("/api/notes/{}/export.html", uuid::Uuid::new_v4());
+        let (status, _, _) = app.send(Method::GET, &missing, Some(&owner), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,295 @@
+//! GraphQL API at `/api/graphql`, alongside the REST handlers.
+//!
+//! Queries and mutations mirror the note endpoints and go through the same
+//! code paths (validation, hooks, moderation, change events), so the two
+//! APIs behave alike; requests are authenticated the same way too. Errors
+//! carry the HTTP status the REST API would have answered under
+//! `extensions.status`. The GraphQL Playground is served on `GET` when
+//! `GRAPHQL_PLAYGROUND=true`.
+
+use std::sync::Arc;
+
+use async_graphql::{
+    http::{playground_source, GraphQLPlaygroundConfig},
+    parser::{parse_query, types::OperationType},
+    Context, EmptySubscription, ErrorExtensions, InputObject, MaybeUndefined, Object, Schema, ID,
+};
+use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+    Extension, Json,
+};
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+
+use crate::{
+    auth::NoteScope,
+    error::AppError,
+    handler::filter_db_record,
+    model::{NoteId, NoteModelResponse},
+    schema::{CreateNoteSchema, UpdateNoteSchema},
+    service::NoteService,
+    service_account::{ServiceAccountPrincipal, SCOPE_NOTES_READ, SCOPE_NOTES_WRITE},
+    AppState,
+};
+
+pub type NoteSchema = Schema<QueryRoot, MutationRoot, EmptySubscription>;
+
+pub fn schema() -> NoteSchema {
+    Schema::build(QueryRoot, MutationRoot, EmptySubscription).finish()
+}
+
+impl From<AppError> for async_graphql::Error {
+    fn from(err: AppError) -> Self {
+        let (status, Json(body)): (StatusCode, Json<Value>) = err.into();
+        let message = body["message"].as_str().unwrap_or_default().to_string();
+        async_graphql::Error::new(message).extend_with(|_, e| e.set("status", status.as_u16()))
+    }
+}
+
+fn note_id(id: &ID) -> async_graphql::Result<NoteId> {
+    id.parse::<NoteId>()
+        .map_err(|_| AppError::Validation(format!("Invalid note ID '{}'", id.as_str())).into())
+}
+
+fn request<'a>(ctx: &Context<'a>) -> async_graphql::Result<(&'a Arc<AppState>, &'a NoteScope)> {
+    Ok((ctx.data::<Arc<AppState>>()?, ctx.data::<NoteScope>()?))
+}
+
+#[derive(InputObject)]
+pub struct CreateNoteInput {
+    pub title: String,
+    pub content: String,
+    pub category: Option<String>,
+    pub published: Option<bool>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Fields left out are left unchanged.
+#[derive(InputObject)]
+pub struct UpdateNoteInput {
+    pub title: Option<String>,
+    pub content: Option<String>,
+    pub category: Option<String>,
+    pub published: Option<bool>,
+    /// `null` for the note not to expire.
+    pub expires_at: MaybeUndefined<DateTime<Utc>>,
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// A page of notes, by id.
+    async fn notes(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = 1)] page: usize,
+        #[graphql(default = 10)] limit: usize,
+    ) -> async_graphql::Result<Vec<NoteModelResponse>> {
+        let (data, scope) = request(ctx)?;
+        let offset = (page.max(1) - 1) * limit;
+        let notes = data
+            .notes
+            .list_page(scope.owner(), limit, offset)
+            .await
+            .map_err(AppError::from)?;
+        Ok(notes.iter().map(filter_db_record).collect())
+    }
+
+    async fn note(
+        &self,
+        ctx: &Context<'_>,
+        id: ID,
+    ) -> async_graphql::Result<Option<NoteModelResponse>> {
+        let (data, scope) = request(ctx)?;
+        let note = data
+            .notes
+            .find(note_id(&id)?)
+            .await
+            .map_err(AppError::from)?
+            .filter(|note| scope.permits(note.user_id));
+        if let Some(note) = &note {
+            data.write_buffer.record_view(note.id, data.clock.now());
+        }
+        Ok(note.as_ref().map(filter_db_record))
+    }
+}
+
+pub struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    async fn create_note(
+        &self,
+        ctx: &Context<'_>,
+        input: CreateNoteInput,
+    ) -> async_graphql::Result<NoteModelResponse> {
+        let (data, scope) = request(ctx)?;
+        let body = CreateNoteSchema {
+            title: input.title,
+            content: input.content,
+            category: input.category,
+            published: input.published,
+            expires_at: input.expires_at,
+        };
+        let note = NoteService::new(data, scope).create(body).await?;
+        Ok(filter_db_record(&note))
+    }
+
+    /// With `expected_version`, only updates the note at that version, like
+    /// `If-Match` on `PATCH`.
+    async fn update_note(
+        &self,
+        ctx: &Context<'_>,
+        id: ID,
+        input: UpdateNoteInput,
+        expected_version: Option<u32>,
+    ) -> async_graphql::Result<NoteModelResponse> {
+        let (data, scope) = request(ctx)?;
+        let id = note_id(&id)?;
+        let body = UpdateNoteSchema {
+            title: input.title,
+            content: input.content,
+            category: input.category,
+            published: input.published,
+            expires_at: input.expires_at.into(),
+            expected: None,
+        };
+        let note = NoteService::new(data, scope)
+            .edit(id, body, expected_version)
+            .await?;
+        Ok(filter_db_record(&note))
+    }
+
+    /// Publishes the note, or unpublishes it with `published: false`.
+    /// Moderation can keep it unpublished.
+    async fn publish_note(
+        &self,
+        ctx: &Context<'_>,
+        id: ID,
+        #[graphql(default = true)] published: bool,
+    ) -> async_graphql::Result<NoteModelResponse> {
+        let (data, scope) = request(ctx)?;
+        let note = NoteService::new(data, scope)
+            .publish(note_id(&id)?, published)
+            .await?;
+        Ok(filter_db_record(&note))
+    }
+
+    /// Moves the note to the trash.
+    async fn delete_note(&self, ctx: &Context<'_>, id: ID) -> async_graphql::Result<bool> {
+        let (data, scope) = request(ctx)?;
+        NoteService::new(data, scope).trash(note_id(&id)?).await?;
+        Ok(true)
+    }
+}
+
+/// Executes a GraphQL request; see the schema at `GET /api/graphql` with the
+/// playground enabled.
+#[utoipa::path(
+    post,
+    path = "/api/graphql",
+    tag = "notes",
+    responses(
+        (status = 200, description = "The GraphQL response, errors included"),
+    ),
+)]
+pub async fn graphql_handler(
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+    Extension(schema): Extension<NoteSchema>,
+    principal: Option<Extension<ServiceAccountPrincipal>>,
+    req: GraphQLRequest,
+) -> Response {
+    let req = req.into_inner();
+    if let Some(Extension(principal)) = principal {
+        if let Err(response) = principal.require(required_scope(&req)) {
+            return response.into_response();
+        }
+    }
+    GraphQLResponse::from(schema.execute(req.data(data).data(scope)).await).into_response()
+}
+
+/// The service-account scope the operation `req` runs needs: mutations
+/// write, everything else reads. A document that does not parse needs
+/// reading only, since executing it just reports the syntax error.
+fn required_scope(req: &async_graphql::Request) -> &'static str {
+    let Ok(document) = parse_query(&req.query) else {
+        return SCOPE_NOTES_READ;
+    };
+    let writes = document
+        .operations
+        .iter()
+        .filter(|(name, _)| match &req.operation_name {
+            Some(wanted) => name.is_some_and(|name| name.as_str() == wanted),
+            None => true,
+        })
+        .any(|(_, operation)| operation.node.ty == OperationType::Mutation);
+    if writes {
+        SCOPE_NOTES_WRITE
+    } else {
+        SCOPE_NOTES_READ
+    }
+}
+
+pub async fn playground_handler() -> impl IntoResponse {
+    Html(playground_source(GraphQLPlaygroundConfig::new(
+        "/api/graphql",
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use crate::{
+        model::{BinaryId, UserId},
+        testing::TestApp,
+    };
+
+    const QUERY: &str = "{ notes { id title } }";
+
+    #[tokio::test]
+    async fn rejects_anonymous_requests_once_accounts_are_enabled() {
+        let app = TestApp::with_accounts();
+        let (status, _) = app.post("/api/graphql", json!({ "query": QUERY })).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let token = app.token(UserId(BinaryId(uuid::Uuid::new_v4())));
+        let (status, _, body) = app
+            .send(
+                Method::POST,
+                "/api/graphql",
+                Some(&token),
+                Some(json!({ "query": QUERY })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["notes"], json!([]), "{}", body);
+    }
+
+    #[test]
+    fn mutations_need_the_write_scope() {
+        use super::required_scope;
+        use crate::service_account::{SCOPE_NOTES_READ, SCOPE_NOTES_WRITE};
+
+        let scope = |query: &str| required_scope(&async_graphql::Request::new(query));
+        assert_eq!(scope(QUERY), SCOPE_NOTES_READ);
+        assert_eq!(scope("query { notes { id } }"), SCOPE_NOTES_READ);
+        assert_eq!(scope("not graphql"), SCOPE_NOTES_READ);
+        assert_eq!(
+            scope("mutation { deleteNote(id: \"x\") }"),
+            SCOPE_NOTES_WRITE
+        );
+
+        let both = "query List { notes { id } } mutation Drop { deleteNote(id: \"x\") }";
+        let named =
+            |name: &str| required_scope(&async_graphql::Request::new(both).operation_name(name));
+        assert_eq!(named("List"), SCOPE_NOTES_READ);
+        assert_eq!(named("Drop"), SCOPE_NOTES_WRITE);
+    }
+}
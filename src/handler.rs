@@ -1,56 +1,363 @@
-use std::sync::Arc;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use axum::{
-    extract::{Path, Query, State},
-    http::StatusCode,
-    response::IntoResponse,
-    Json,
+    extract::{OriginalUri, Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Extension, Json,
 };
+use chrono::Timelike;
+use reqwest::Url;
 use serde_json::{json, Value};
 
 use crate::{
-    model::{NoteModel, NoteModelResponse},
-    schema::{CreateNoteSchema, FilterOptions, UpdateNoteSchema},
+    auth::NoteScope,
+    canary::CanaryHits,
+    collab::Edited,
+    conditional::{etag, if_match_versions, version_mismatch},
+    error::AppError,
+    expiry,
+    loader::{Expansion, Loaders},
+    model::{NoteId, NoteModel, NoteModelResponse, NoteStatus, Title, Transition, UserId},
+    preview::preview,
+    repository::{NoteFilter, NoteSort},
+    schema::{
+        CreateNoteSchema, ExpandOptions, FilterOptions, SearchOptions,
+        UpdateNoteSchema,
+    },
+    service::NoteService,
+    state::{DbPool, Settings},
+    validation::FieldErrors,
     AppState,
 };
 
-fn filter_db_record(note: &NoteModel) -> NoteModelResponse {
+/// The most notes one page can hold.
+pub const MAX_PAGE_SIZE: usize = 100;
+
+/// How many rows come before page `page` (from 1) of `limit` rows each; a
+/// page too far out to count to is a bad request.
+pub fn page_offset(page: Option<usize>, limit: usize) -> Result<usize, AppError> {
+    (page.unwrap_or(1).max(1) - 1)
+        .checked_mul(limit)
+        .ok_or_else(|| AppError::Validation("Query parameter 'page' is too large".to_string()))
+}
+
+pub fn filter_db_record(note: &NoteModel) -> NoteModelResponse {
     NoteModelResponse {
-        id: note.id.to_owned(),
-        title: note.title.to_owned(),
+        id: note.id.to_string(),
+        title: note.title.to_string(),
         content: note.content.to_owned(),
+        preview: note.preview.to_owned(),
         category: note.category.to_owned(),
-        published: note.published != 0,
+        category_id: note.category_id.map(|id| id.to_string()),
+        published: note.published.is_published(),
+        published_at: note.published_at,
+        status: note.status.to_string(),
+        expires_at: note.expires_at,
+        view_count: note.view_count,
+        last_accessed_at: note.last_accessed_at,
         created_at: note.created_at.unwrap(),
         updated_at: note.updated_at.unwrap(),
+        version: note.version,
+        deleted_at: note.deleted_at,
+        content_location: note.content_key.clone(),
+    }
+}
+
+/// List cursors are the hex of the last note id, which clients must treat
+/// as opaque.
+pub fn encode_cursor(id: NoteId) -> String {
+    hex::encode(id.0 .0.as_bytes())
+}
+
+pub fn decode_cursor(cursor: &str) -> Result<NoteId, AppError> {
+    hex::decode(cursor)
+        .ok()
+        .and_then(|bytes| uuid::Uuid::from_slice(&bytes).ok())
+        .map(NoteId::from)
+        .ok_or_else(|| AppError::Validation(format!("Invalid cursor '{}'", cursor)))
+}
+
+/// `uri` with its paging parameters replaced by `param=value`.
+fn page_url(uri: &Uri, param: &str, value: &str) -> String {
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    let mut url = Url::parse("http://localhost")
+        .and_then(|base| base.join(path))
+        .expect("request paths are valid URLs");
+    let kept = url
+        .query_pairs()
+        .filter(|(key, _)| key != "page" && key != "after")
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect::<Vec<_>>();
+    url.query_pairs_mut()
+        .clear()
+        .extend_pairs(kept)
+        .append_pair(param, value);
+    format!("{}?{}", url.path(), url.query().unwrap_or_default())
+}
+
+/// The `Link` header (RFC 8288) of a list page: the `first`, `prev`, `next`
+/// and `last` pages by number, or the `next` one by cursor when paging by
+/// cursor.
+fn page_links(
+    uri: &Uri,
+    page: Option<usize>,
+    total_pages: usize,
+    next_cursor: Option<&str>,
+) -> Option<HeaderValue> {
+    let mut links = Vec::new();
+    match page {
+        Some(page) => {
+            let last = total_pages.max(1);
+            links.push(("first", page_url(uri, "page", "1")));
+            if page > 1 {
+                links.push(("prev", page_url(uri, "page", &(page - 1).to_string())));
+            }
+            if page < last {
+                links.push(("next", page_url(uri, "page", &(page + 1).to_string())));
+            }
+            links.push(("last", page_url(uri, "page", &last.to_string())));
+        }
+        None => links.extend(next_cursor.map(|cursor| ("next", page_url(uri, "after", cursor)))),
     }
+    let header = links
+        .iter()
+        .map(|(rel, url)| format!("<{}>; rel=\"{}\"", url, rel))
+        .collect::<Vec<_>>()
+        .join(", ");
+    (!header.is_empty())
+        .then(|| HeaderValue::from_str(&header).ok())
+        .flatten()
+}
+
+fn parse_expansions(expand: Option<&str>) -> Result<Vec<Expansion>, AppError> {
+    Expansion::parse_list(expand).map_err(AppError::Validation)
+}
+
+/// Serializes notes, embedding any requested relations resolved in one
+/// batched query per relation, and warning of notes about to expire.
+async fn expanded_records(
+    data: &AppState,
+    notes: &[NoteModel],
+    expansions: &[Expansion],
+) -> Result<Vec<Value>, AppError> {
+    let ids = notes.iter().map(|note| note.id).collect::<Vec<_>>();
+    let mut fields = Loaders::new(&data.notes).expand(expansions, &ids).await?;
+    let now = data.clock.now();
+
+    Ok(notes
+        .iter()
+        .map(|note| {
+            let mut record = serde_json::to_value(filter_db_record(note)).unwrap();
+            if let Value::Object(record) = &mut record {
+                if let Some(extra) = fields.remove(&note.id) {
+                    record.extend(extra);
+                }
+                if let Some(warning) = expiry::warning(note, now) {
+                    record.insert("expiry_warning".to_string(), warning.into());
+                }
+            }
+            record
+        })
+        .collect())
+}
+
+/// Builds the row for a new note. Timestamps are assigned here rather than by
+/// column defaults so the response can be built without reading the row back.
+/// TIMESTAMP columns only keep whole seconds.
+pub(crate) fn new_note(
+    data: &AppState,
+    id: NoteId,
+    user_id: Option<UserId>,
+    body: CreateNoteSchema,
+) -> Result<NoteModel, AppError> {
+    let now = data.clock.now().with_nanosecond(0).unwrap();
+    let published = body.published.unwrap_or(false);
+    Ok(NoteModel {
+        id,
+        title: Title::parse(body.title).map_err(title_error)?,
+        preview: preview(&body.content),
+        content: body.content,
+        category: body.category.unwrap_or_default(),
+        category_id: None,
+        published: published.into(),
+        published_at: published.then_some(now),
+        status: if published {
+            NoteStatus::Published
+        } else {
+            NoteStatus::Draft
+        },
+        expires_at: body
+            .expires_at
+            .map(|at| expiry::check(at, now))
+            .transpose()?,
+        view_count: 0,
+        last_accessed_at: None,
+        created_at: Some(now),
+        updated_at: Some(now),
+        user_id,
+        deleted_at: None,
+        version: 1,
+        content_key: None,
+    })
 }
 
+/// A field error about the title, for titles not checked by validation.
+pub(crate) fn title_error(problem: String) -> AppError {
+    AppError::InvalidFields(FieldErrors::single("title", problem))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/notes",
+    tag = "notes",
+    params(FilterOptions),
+    responses(
+        (status = 200, description = "A page of notes, with a `Link` header to the pages around it", body = NoteListResponse),
+    ),
+)]
 pub async fn note_list_handler(
+    OriginalUri(uri): OriginalUri,
     opts: Option<Query<FilterOptions>>,
+    scope: NoteScope,
     State(data): State<Arc<AppState>>,
-) -> Result<impl IntoResponse, (StatusCode, Json<Value>)> {
+) -> Result<impl IntoResponse, AppError> {
     let Query(opts) = opts.unwrap_or_default();
-    let limit = opts.limit.unwrap_or(10);
-    let offset = (opts.page.unwrap_or(1) - 1) * limit;
-
-    let notes = sqlx::query_as::<_, NoteModel>("SELECT * FROM notes ORDER by id LIMIT ? OFFSET ?")
-        .bind(limit as i32)
-        .bind(offset as i32)
-        .fetch_all(&data.db)
-        .await
-        .map_err(|e| {
-            let error_response = json!({
-            "status": "fail",
-            "message": format!("Database error: {}", e),
-        });
-            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_response))
-        })?;
+    let expansions = parse_expansions(opts.expand.as_deref())?;
+    let limit = opts.limit.unwrap_or(10).min(MAX_PAGE_SIZE);
+    let page = opts.page.unwrap_or(1).max(1);
+    let offset = page_offset(opts.page, limit)?;
+
+    let filter = NoteFilter {
+        tag: opts.tag,
+        category: opts.category,
+        published: opts.published,
+        status: opts
+            .status
+            .as_deref()
+            .map(str::parse)
+            .transpose()
+            .map_err(AppError::Validation)?,
+        created_after: opts.created_after,
+        created_before: opts.created_before,
+    };
+
+    let sort = NoteSort::parse(opts.sort.as_deref(), opts.order.as_deref())
+        .map_err(AppError::Validation)?;
+    if !sort.is_default() && opts.after.is_some() {
+        return Err(AppError::Validation(
+            "Cursors only page notes by id; use 'page' with 'sort' or 'order'".to_string(),
+        ));
+    }
+
+    let notes = match (scope.owner(), opts.after.as_deref()) {
+        _ if !filter.is_empty() || !sort.is_default() => {
+            let after = opts.after.as_deref().map(decode_cursor).transpose()?;
+            data.notes
+                .list_filtered(&filter, sort, scope.owner(), after, limit, offset)
+                .await?
+        }
+        (owner, Some(cursor)) => {
+            data.notes
+                .list_after(owner, decode_cursor(cursor)?, limit)
+                .await?
+        }
+        (Some(user_id), None) => data.notes.list_page(Some(user_id), limit, offset).await?,
+        (None, None) => {
+            data.coalescing
+                .pages
+                .run((limit, offset), || async {
+                    data.notes
+                        .list_page(None, limit, offset)
+                        .await
+                        .map_err(Arc::new)
+                })
+                .await?
+        }
+    };
+    // A short page is the last one.
+    let next_cursor = (sort.is_default() && notes.len() == limit)
+        .then(|| notes.last().map(|note| encode_cursor(note.id)))
+        .flatten();
+
+    let total = data.notes.count_filtered(&filter, scope.owner()).await? as usize;
+    let total_pages = total.div_ceil(limit.max(1));
+    // Cursor pages have no number.
+    let page = opts.after.is_none().then_some(page);
+    let mut headers = HeaderMap::new();
+    if let Some(links) = page_links(&uri, page, total_pages, next_cursor.as_deref()) {
+        headers.insert(header::LINK, links);
+    }
+
+    let mut note_responses = expanded_records(&data, &notes, &expansions).await?;
+    if !opts.include_content.unwrap_or(false) {
+        for record in &mut note_responses {
+            if let Value::Object(record) = record {
+                record.remove("content");
+            }
+        }
+    }
 
-    let note_responses = notes
+    let json_responses = json!({
+        "status": "success",
+        "results": note_responses.len(),
+        "notes": note_responses,
+        "next_cursor": next_cursor,
+        "total": total,
+        "total_pages": total_pages,
+        "page": page,
+        "limit": limit,
+    });
+
+    let canaries = CanaryHits(data.canaries.hits(notes.iter().map(|note| note.id)));
+    Ok((
+        StatusCode::OK,
+        Extension(canaries),
+        headers,
+        Json(json_responses),
+    ))
+}
+
+/// Notes matching `q` in their own text or the text read from their
+/// attachments, most relevant first, with previews instead of content.
+#[utoipa::path(
+    get,
+    path = "/api/notes/search",
+    tag = "notes",
+    params(SearchOptions),
+    responses(
+        (status = 200, description = "Matching notes", body = NoteListResponse),
+    ),
+)]
+pub async fn search_notes_handler(
+    Query(opts): Query<SearchOptions>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let Some(q) = opts.q.as_deref().map(str::trim).filter(|q| !q.is_empty()) else {
+        return Err(AppError::Validation(
+            "Query parameter 'q' is required".to_string(),
+        ));
+    };
+    let limit = opts.limit.unwrap_or(10).min(MAX_PAGE_SIZE);
+    let offset = page_offset(opts.page, limit)?;
+
+    let hits = data.notes.search(q, scope.owner(), limit, offset).await?;
+
+    let note_responses = hits
         .iter()
-        .map(filter_db_record)
-        .collect::<Vec<NoteModelResponse>>();
+        .map(|(note, score)| {
+            let mut record = serde_json::to_value(filter_db_record(note)).unwrap();
+            if let Value::Object(record) = &mut record {
+                record.remove("content");
+                record.insert("score".to_string(), json!(score));
+            }
+            record
+        })
+        .collect::<Vec<_>>();
 
     let json_responses = json!({
         "status": "success",
@@ -58,53 +365,27 @@ pub async fn note_list_handler(
         "notes": note_responses,
     });
 
-    Ok((StatusCode::OK, Json(json_responses)))
+    let canaries = CanaryHits(data.canaries.hits(hits.iter().map(|(note, _)| note.id)));
+    Ok((StatusCode::OK, Extension(canaries), Json(json_responses)))
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/notes",
+    tag = "notes",
+    request_body = CreateNoteSchema,
+    responses(
+        (status = 200, description = "The created note", body = NoteResponse),
+        (status = 409, description = "A note with that title already exists", body = ErrorResponse),
+        (status = 422, description = "Invalid fields, or rejected by moderation", body = ErrorResponse),
+    ),
+)]
 pub async fn create_note_handler(
+    scope: NoteScope,
     State(data): State<Arc<AppState>>,
     Json(body): Json<CreateNoteSchema>,
-) -> Result<impl IntoResponse, (StatusCode, Json<Value>)> {
-    let user_id = uuid::Uuid::new_v4().to_string();
-
-    let query_result =
-        sqlx::query(r#"INSERT INTO notes (id,title,content,category) VALUES (?, ?, ?, ?)"#)
-            .bind(user_id.clone())
-            .bind(body.title.to_string())
-            .bind(body.content.to_string())
-            .bind(body.category.to_owned().unwrap_or_default())
-            .execute(&data.db)
-            .await
-            .map_err(|err: sqlx::Error| err.to_string());
-
-    if let Err(err) = query_result {
-        if err.contains("Duplicate entry") {
-            let error_response = json!({
-                "status": "fail",
-                "message": "Note with that title already exists",
-            });
-            return Err((StatusCode::CONFLICT, Json(error_response)));
-        }
-
-        return Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({
-                "status": "error",
-                "message": format!("{:?}", err)
-            })),
-        ));
-    }
-
-    let note = sqlx::query_as::<_, NoteModel>("SELECT * FROM notes WHERE id = ?")
-        .bind(user_id)
-        .fetch_one(&data.db)
-        .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({"status": "error","message": format!("{:?}", e)})),
-            )
-        })?;
+) -> Result<impl IntoResponse, AppError> {
+    let note = NoteService::new(&data, &scope).create(body).await?;
 
     let note_response = json!({
         "status": "success",
@@ -116,159 +397,651 @@ pub async fn create_note_handler(
     Ok(Json(note_response))
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/notes/{id}",
+    tag = "notes",
+    params(
+        ("id" = Uuid, Path, description = "Note ID"),
+        ExpandOptions,
+    ),
+    responses(
+        (status = 200, description = "The note", body = NoteResponse,
+            headers(("ETag" = String, description = "The version of the note, for `If-Match`"))),
+        (status = 404, description = "Note not found", body = ErrorResponse),
+    ),
+)]
 pub async fn get_note_handler(
     Path(id): Path<uuid::Uuid>,
+    Query(opts): Query<ExpandOptions>,
+    scope: NoteScope,
     State(data): State<Arc<AppState>>,
-) -> Result<impl IntoResponse, (StatusCode, Json<Value>)> {
-    let query_result = sqlx::query_as::<_, NoteModel>("SELECT * FROM notes WHERE id = ?")
-        .bind(id.to_string())
-        .fetch_one(&data.db)
+) -> Result<impl IntoResponse, AppError> {
+    let expansions = parse_expansions(opts.expand.as_deref())?;
+
+    let note_id = NoteId::from(id);
+    if data.cache.missing_notes.is_missing(note_id) {
+        return Err(AppError::note_not_found(id));
+    }
+
+    let query_result = data
+        .coalescing
+        .notes
+        .run(note_id, || async {
+            if let Some(cache) = &data.cache.notes {
+                if let Some(note) = cache.get(note_id).await {
+                    return Ok(note);
+                }
+            }
+            let note = data
+                .notes
+                .find(note_id)
+                .await
+                .and_then(|note| note.ok_or(sqlx::Error::RowNotFound))
+                .map_err(Arc::new)?;
+            if let Some(cache) = &data.cache.notes {
+                cache.put(&note).await;
+            }
+            Ok(note)
+        })
         .await;
 
-    return match query_result {
+    match query_result {
+        // Cached notes may have expired since.
+        Ok(note) if !scope.permits(note.user_id) || note.is_expired(data.clock.now()) => {
+            Err(AppError::note_not_found(id))
+        }
         Ok(note) => {
+            data.write_buffer.record_view(note.id, data.clock.now());
+
+            let version = note.version;
+            let mut records = expanded_records(&data, &[note], &expansions).await?;
             let note_response = json!({
                 "status": "success",
                 "data": serde_json::json!({
-                    "note": filter_db_record(&note)
+                    "note": records.remove(0)
                 })
             });
-            Ok(Json(note_response))
-        }
-        Err(sqlx::Error::RowNotFound) => {
-            let error_response = json!({
-                "status": "fail",
-                "message": format!("Note with ID: {} not found", id)
-            });
-            Err((StatusCode::NOT_FOUND, Json(error_response)))
+            Ok(([(header::ETAG, etag(version))], Json(note_response)))
         }
-        Err(e) => {
-            Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({"status": "error","message": format!("{:?}", e)})),
-            ))
+        Err(e) if matches!(*e, sqlx::Error::RowNotFound) => {
+            data.cache.missing_notes.record_missing(note_id);
+            Err(AppError::note_not_found(id))
         }
-    };
+        Err(e) => Err(e.into()),
+    }
 }
 
+#[utoipa::path(
+    patch,
+    path = "/api/notes/{id}",
+    tag = "notes",
+    params(
+        ("id" = Uuid, Path, description = "Note ID"),
+        ("If-Match" = Option<String>, Header, description = "Only edit the note at this `ETag`"),
+    ),
+    request_body = UpdateNoteSchema,
+    responses(
+        (status = 200, description = "The updated note", body = NoteResponse),
+        (status = 404, description = "Note not found", body = ErrorResponse),
+        (status = 409, description = "A note with that title already exists, or the note cannot be published or unpublished from its status", body = ErrorResponse),
+        (status = 412, description = "The note no longer has the expected fields or version", body = ErrorResponse),
+        (status = 422, description = "Invalid fields", body = ErrorResponse),
+    ),
+)]
 pub async fn edit_note_handler(
     Path(id): Path<uuid::Uuid>,
+    scope: NoteScope,
     State(data): State<Arc<AppState>>,
-    Json(body): Json<UpdateNoteSchema>,
-) -> Result<impl IntoResponse, (StatusCode, Json<Value>)> {
-    let query_result = sqlx::query_as::<_, NoteModel>("SELECT * FROM notes WHERE id = ?")
-        .bind(id.to_string())
-        .fetch_one(&data.db)
-        .await;
-
-    let note = match query_result {
-        Ok(note) => note,
-        Err(sqlx::Error::RowNotFound) => {
-            let error_response = json!({
-                "status": "fail",
-                "message": format!("Note with ID: {} not found", id)
-            });
-            return Err((StatusCode::NOT_FOUND, Json(error_response)));
-        }
-        Err(e) => {
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({"status": "error","message": format!("{:?}", e)})),
-            ));
+    headers: HeaderMap,
+    Json(mut body): Json<UpdateNoteSchema>,
+) -> Result<Response, AppError> {
+    let service = NoteService::new(&data, &scope);
+    let verdict = service.prepare_edit(NoteId::from(id), &mut body).await?;
+    let expected_version = match if_match_versions(&headers) {
+        None => None,
+        Some(versions) => {
+            let Some(note) = data.notes.find(NoteId::from(id)).await? else {
+                return Err(AppError::note_not_found(id));
+            };
+            if !versions.contains(&note.version) {
+                return Err(version_mismatch(note.version).into());
+            }
+            Some(note.version)
         }
     };
 
-    let published = body.published.unwrap_or(note.published != 0);
-    let i8_publised = published as i8;
+    let edited = Edited::of(&body);
+    let updated_note = service
+        .save_edit(NoteId::from(id), body, expected_version, &verdict)
+        .await?;
+    data.collab
+        .publish(updated_note.id, edited.patch(&updated_note));
 
-    let update_result = sqlx::query(
-        r#"UPDATE notes SET title = ?, content = ?, category = ?, published = ? WHERE id = ?"#,
+    let note_response = json!({
+        "status": "success",
+        "data": serde_json::json!({
+            "note": filter_db_record(&updated_note)
+        })
+    });
+
+    Ok((
+        [(header::ETAG, etag(updated_note.version))],
+        Json(note_response),
     )
-        .bind(body.title.to_owned().unwrap_or_else(|| note.title.clone()))
-        .bind(
-            body.content
-                .to_owned()
-                .unwrap_or_else(|| note.content.clone()),
-        )
-        .bind(
-            body.category
-                .to_owned()
-                .unwrap_or_else(|| note.category.clone()),
-        )
-        .bind(i8_publised)
-        .bind(id.to_string())
-        .execute(&data.db)
-        .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({"status": "error","message": format!("{:?}", e)})),
-            )
-        })?;
+        .into_response())
+}
 
-    if update_result.rows_affected() == 0 {
-        let error_response = json!({
-            "status": "fail",
-            "message": format!("Note with ID: {} not found", id)
-        });
-        return Err((StatusCode::NOT_FOUND, Json(error_response)));
-    }
+#[utoipa::path(
+    delete,
+    path = "/api/notes/{id}",
+    tag = "notes",
+    params(("id" = Uuid, Path, description = "Note ID")),
+    responses(
+        (status = 204, description = "The note was moved to the trash"),
+        (status = 404, description = "Note not found", body = ErrorResponse),
+    ),
+)]
+pub async fn delete_note_handler(
+    Path(id): Path<uuid::Uuid>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    NoteService::new(&data, &scope)
+        .trash(NoteId::from(id))
+        .await?;
 
-    let updated_note = sqlx::query_as::<_, NoteModel>("SELECT * FROM notes WHERE id = ?")
-        .bind(id.to_string())
-        .fetch_one(&data.db)
-        .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({"status": "error","message": format!("{:?}", e)})),
-            )
-        })?;
+    Ok(StatusCode::NO_CONTENT)
+}
 
+/// The note as saved by one of the actions on it, with its `ETag`.
+fn saved_note(note: &NoteModel) -> Response {
     let note_response = json!({
         "status": "success",
-        "data": serde_json::json!({
-            "note": filter_db_record(&updated_note)
+        "data": json!({
+            "note": filter_db_record(note)
         })
     });
+    ([(header::ETAG, etag(note.version))], Json(note_response)).into_response()
+}
 
-    Ok(Json(note_response))
+async fn set_published(
+    data: &AppState,
+    scope: &NoteScope,
+    id: NoteId,
+    published: bool,
+) -> Result<Response, AppError> {
+    let note = NoteService::new(data, scope).publish(id, published).await?;
+    Ok(saved_note(&note))
 }
 
-pub async fn delete_note_handler(
+/// Publishes a draft, recording `published_at`. Publishing screens the note
+/// as it stands, so moderation can keep it unpublished.
+#[utoipa::path(
+    post,
+    path = "/api/notes/{id}/publish",
+    tag = "notes",
+    params(("id" = Uuid, Path, description = "Note ID")),
+    responses(
+        (status = 200, description = "The note as saved", body = NoteResponse),
+        (status = 404, description = "Note not found", body = ErrorResponse),
+        (status = 409, description = "The note is in review or archived", body = ErrorResponse),
+        (status = 422, description = "Rejected by moderation", body = ErrorResponse),
+    ),
+)]
+pub async fn publish_note_handler(
     Path(id): Path<uuid::Uuid>,
+    scope: NoteScope,
     State(data): State<Arc<AppState>>,
-) -> Result<impl IntoResponse, (StatusCode, Json<Value>)> {
-    let query_result = sqlx::query!(r#"DELETE FROM notes WHERE id = ?"#, id.to_string())
-        .execute(&data.db)
-        .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({
-                    "status": "error",
-                    "message": format!("{:?}", e)})),
-            )
-        })?;
+) -> Result<Response, AppError> {
+    set_published(&data, &scope, NoteId::from(id), true).await
+}
 
-    if query_result.rows_affected() == 0 {
-        let error_response = json!({
-            "status": "fail",
-            "message": format!("Note with ID: {} not found", id)
-        });
-        return Err((StatusCode::NOT_FOUND, Json(error_response)));
-    }
+/// Turns the note back into a draft, clearing `published_at`.
+#[utoipa::path(
+    post,
+    path = "/api/notes/{id}/unpublish",
+    tag = "notes",
+    params(("id" = Uuid, Path, description = "Note ID")),
+    responses(
+        (status = 200, description = "The note as saved", body = NoteResponse),
+        (status = 404, description = "Note not found", body = ErrorResponse),
+    ),
+)]
+pub async fn unpublish_note_handler(
+    Path(id): Path<uuid::Uuid>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    set_published(&data, &scope, NoteId::from(id), false).await
+}
 
-    Ok(StatusCode::NO_CONTENT)
+async fn transition_note(
+    data: &AppState,
+    scope: &NoteScope,
+    id: NoteId,
+    transition: Transition,
+) -> Result<Response, AppError> {
+    let note = NoteService::new(data, scope)
+        .transition(id, transition)
+        .await?;
+    Ok(saved_note(&note))
+}
+
+/// Sends a draft for review.
+#[utoipa::path(
+    post,
+    path = "/api/notes/{id}/submit",
+    tag = "notes",
+    params(("id" = Uuid, Path, description = "Note ID")),
+    responses(
+        (status = 200, description = "The note as saved", body = NoteResponse),
+        (status = 404, description = "Note not found", body = ErrorResponse),
+        (status = 409, description = "The note is not a draft", body = ErrorResponse),
+    ),
+)]
+pub async fn submit_note_handler(
+    Path(id): Path<uuid::Uuid>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    transition_note(&data, &scope, NoteId::from(id), Transition::Submit).await
+}
+
+/// Publishes a note in review. Like publishing a draft, moderation can keep
+/// it unpublished, and then in review.
+#[utoipa::path(
+    post,
+    path = "/api/notes/{id}/approve",
+    tag = "notes",
+    params(("id" = Uuid, Path, description = "Note ID")),
+    responses(
+        (status = 200, description = "The note as saved", body = NoteResponse),
+        (status = 404, description = "Note not found", body = ErrorResponse),
+        (status = 409, description = "The note is not in review", body = ErrorResponse),
+        (status = 422, description = "Rejected by moderation", body = ErrorResponse),
+    ),
+)]
+pub async fn approve_note_handler(
+    Path(id): Path<uuid::Uuid>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    transition_note(&data, &scope, NoteId::from(id), Transition::Approve).await
+}
+
+/// Sends a note in review back to draft.
+#[utoipa::path(
+    post,
+    path = "/api/notes/{id}/reject",
+    tag = "notes",
+    params(("id" = Uuid, Path, description = "Note ID")),
+    responses(
+        (status = 200, description = "The note as saved", body = NoteResponse),
+        (status = 404, description = "Note not found", body = ErrorResponse),
+        (status = 409, description = "The note is not in review", body = ErrorResponse),
+    ),
+)]
+pub async fn reject_note_handler(
+    Path(id): Path<uuid::Uuid>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    transition_note(&data, &scope, NoteId::from(id), Transition::Reject).await
+}
+
+/// Archives a draft or published note, unpublishing it.
+#[utoipa::path(
+    post,
+    path = "/api/notes/{id}/archive",
+    tag = "notes",
+    params(("id" = Uuid, Path, description = "Note ID")),
+    responses(
+        (status = 200, description = "The note as saved", body = NoteResponse),
+        (status = 404, description = "Note not found", body = ErrorResponse),
+        (status = 409, description = "The note is in review or already archived", body = ErrorResponse),
+    ),
+)]
+pub async fn archive_note_handler(
+    Path(id): Path<uuid::Uuid>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    transition_note(&data, &scope, NoteId::from(id), Transition::Archive).await
 }
 
-pub async fn health_checker_handler() -> impl IntoResponse {
+/// Brings an archived note back as a draft.
+#[utoipa::path(
+    post,
+    path = "/api/notes/{id}/unarchive",
+    tag = "notes",
+    params(("id" = Uuid, Path, description = "Note ID")),
+    responses(
+        (status = 200, description = "The note as saved", body = NoteResponse),
+        (status = 404, description = "Note not found", body = ErrorResponse),
+        (status = 409, description = "The note is not archived", body = ErrorResponse),
+    ),
+)]
+pub async fn unarchive_note_handler(
+    Path(id): Path<uuid::Uuid>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    transition_note(&data, &scope, NoteId::from(id), Transition::Unarchive).await
+}
+
+/// How long readiness waits on the database before calling it unreachable.
+const READINESS_DB_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Liveness: answers while the process runs, without touching the database.
+/// Also served at `/api/health`.
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "health",
+    responses(
+        (status = 200, description = "The API is up"),
+    ),
+    security(()),
+)]
+pub async fn health_checker_handler(State(data): State<Arc<AppState>>) -> impl IntoResponse {
     const MESSAGE: &str = "OK";
 
     let json_response = json!({
         "status": "success",
-        "message": MESSAGE
+        "message": MESSAGE,
+        "warmup": data.warmup,
     });
 
     Json(json_response)
 }
+
+/// Readiness: whether the database answers, how fast, and how busy the
+/// connection pool is. Load balancers stop routing to an instance answering
+/// 503.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "health",
+    responses(
+        (status = 200, description = "The database is reachable"),
+        (status = 503, description = "The database is unreachable; status is `degraded`"),
+    ),
+    security(()),
+)]
+pub async fn readiness_handler(
+    State(db): State<DbPool>,
+    State(settings): State<Settings>,
+) -> impl IntoResponse {
+    let started = Instant::now();
+    let ping = tokio::time::timeout(
+        READINESS_DB_TIMEOUT,
+        sqlx::query("SELECT 1").execute(&*db),
+    )
+    .await;
+    let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+    let reachable = matches!(ping, Ok(Ok(_)));
+    let database = match ping {
+        Ok(Ok(_)) => json!({"reachable": true, "latency_ms": latency_ms}),
+        Ok(Err(err)) => json!({"reachable": false, "error": err.to_string()}),
+        Err(_) => json!({
+            "reachable": false,
+            "error": format!("no answer within {}s", READINESS_DB_TIMEOUT.as_secs()),
+        }),
+    };
+
+    let size = db.size();
+    let idle = db.num_idle() as u32;
+    let max = settings.db_max_connections;
+    let in_use = size.saturating_sub(idle);
+    let json_response = json!({
+        "status": if reachable { "ok" } else { "degraded" },
+        "database": database,
+        "pool": {
+            "size": size,
+            "idle": idle,
+            "in_use": in_use,
+            "max": max,
+            "utilization": f64::from(in_use) / f64::from(max.max(1)),
+        },
+    });
+
+    let status = if reachable {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(json_response))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Method, http::Request};
+    use chrono::Duration;
+
+    use crate::{
+        clock::Clock,
+        model::{BinaryId, CategoryModel, CategoryName, UserId},
+        testing::TestApp,
+    };
+
+    use super::*;
+
+    async fn create(app: &TestApp, title: &str) -> Value {
+        let (status, body) = app
+            .post(
+                "/api/notes",
+                json!({ "title": title, "content": "Some content" }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        body["data"]["note"].clone()
+    }
+
+    #[tokio::test]
+    async fn creates_and_reads_a_note() {
+        let app = TestApp::new();
+        let note = create(&app, "First").await;
+        assert_eq!(note["version"], json!(1));
+
+        let uri = format!("/api/notes/{}", note["id"].as_str().unwrap());
+        let (status, headers, body) = app.send(Method::GET, &uri, None, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::ETAG], "\"1\"");
+        assert_eq!(body["data"]["note"]["title"], json!("First"));
+        assert_eq!(body["data"]["note"]["content"], json!("Some content"));
+    }
+
+    #[tokio::test]
+    async fn rejects_blank_titles() {
+        let app = TestApp::new();
+        let (status, _) = app
+            .post(
+                "/api/notes",
+                json!({ "title": " ", "content": "Some content" }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn pages_through_the_list() {
+        let app = TestApp::new();
+        for i in 0..3 {
+            create(&app, &format!("Note {}", i)).await;
+        }
+
+        let (status, body) = app.get("/api/notes?page=0&limit=2").await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["page"], json!(1));
+        assert_eq!(body["results"], json!(2));
+        assert_eq!(body["total"], json!(3));
+        assert_eq!(body["total_pages"], json!(2));
+        assert!(body["notes"][0].get("content").is_none());
+
+        let cursor = body["next_cursor"].as_str().unwrap();
+        let (_, body) = app.get(&format!("/api/notes?after={}", cursor)).await;
+        assert_eq!(body["results"], json!(1));
+        assert_eq!(body["page"], Value::Null);
+
+        let (_, body) = app.get("/api/notes?limit=1000").await;
+        assert_eq!(body["limit"], json!(MAX_PAGE_SIZE));
+    }
+
+    #[tokio::test]
+    async fn rejects_pages_too_far_out_to_count() {
+        let app = TestApp::new();
+        let uri = format!("/api/notes?page={}&limit=100", usize::MAX);
+        let (status, _) = app.get(&uri).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let uri = format!("/api/notes/search?q=note&page={}", usize::MAX);
+        let (status, _) = app.get(&uri).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn edits_only_the_expected_version() {
+        let app = TestApp::new();
+        let note = create(&app, "Draft").await;
+        let uri = format!("/api/notes/{}", note["id"].as_str().unwrap());
+
+        let edit = |if_match: &'static str, title: &'static str| {
+            Request::builder()
+                .method(Method::PATCH)
+                .uri(&uri)
+                .header(header::IF_MATCH, if_match)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(json!({ "title": title }).to_string()))
+                .unwrap()
+        };
+        let (status, headers, body) = app.call(edit("\"1\"", "Edited")).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(headers[header::ETAG], "\"2\"");
+        assert_eq!(body["data"]["note"]["title"], json!("Edited"));
+
+        let (status, _, _) = app.call(edit("\"1\"", "Stale")).await;
+        assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+        let (_, body) = app.get(&uri).await;
+        assert_eq!(body["data"]["note"]["title"], json!("Edited"));
+    }
+
+    #[tokio::test]
+    async fn trashed_notes_are_gone() {
+        let app = TestApp::new();
+        let note = create(&app, "Doomed").await;
+        let uri = format!("/api/notes/{}", note["id"].as_str().unwrap());
+
+        let (status, _, _) = app.send(Method::DELETE, &uri, None, None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = app.get(&uri).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _, _) = app.send(Method::DELETE, &uri, None, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn expired_notes_are_gone() {
+        let app = TestApp::new();
+        let expires_at = app.clock.now() + Duration::hours(1);
+        let (status, body) = app
+            .post(
+                "/api/notes",
+                json!({ "title": "Brief", "content": "Soon gone", "expires_at": expires_at }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        let uri = format!(
+            "/api/notes/{}",
+            body["data"]["note"]["id"].as_str().unwrap()
+        );
+
+        assert_eq!(app.get(&uri).await.0, StatusCode::OK);
+        app.clock.advance(Duration::hours(2));
+        assert_eq!(app.get(&uri).await.0, StatusCode::NOT_FOUND);
+        let (_, body) = app.get("/api/notes").await;
+        assert_eq!(body["results"], json!(0));
+    }
+
+    #[tokio::test]
+    async fn expands_tags() {
+        let app = TestApp::new();
+        let note = create(&app, "Tagged").await;
+        let id = note["id"].as_str().unwrap();
+        app.notes.tag(id.parse().unwrap(), "work");
+
+        let (status, body) = app.get(&format!("/api/notes/{}?expand=tags", id)).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["data"]["note"]["tags"][0]["name"], json!("work"));
+
+        let (status, _) = app.get(&format!("/api/notes/{}?expand=nope", id)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn files_notes_under_existing_categories() {
+        let app = TestApp::new();
+        app.notes.add_category(CategoryModel {
+            id: BinaryId(uuid::Uuid::new_v4()),
+            user_id: None,
+            name: CategoryName::parse("Work").unwrap(),
+            created_at: app.clock.now(),
+        });
+
+        let (status, body) = app
+            .post(
+                "/api/notes",
+                json!({ "title": "Filed", "content": "Some content", "category": "work" }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["data"]["note"]["category"], json!("Work"));
+
+        let (status, _) = app
+            .post(
+                "/api/notes",
+                json!({ "title": "Lost", "content": "Some content", "category": "Play" }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn users_only_see_their_own_notes() {
+        let app = TestApp::with_accounts();
+        let alice = app.token(UserId(BinaryId(uuid::Uuid::new_v4())));
+        let bob = app.token(UserId(BinaryId(uuid::Uuid::new_v4())));
+
+        let (status, _) = app.get("/api/notes").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let body = json!({ "title": "Private", "content": "Some content" });
+        let (status, _, body) = app
+            .send(Method::POST, "/api/notes", Some(&alice), Some(body))
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        let uri = format!(
+            "/api/notes/{}",
+            body["data"]["note"]["id"].as_str().unwrap()
+        );
+
+        let (status, _, _) = app.send(Method::GET, &uri, Some(&alice), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _, _) = app.send(Method::GET, &uri, Some(&bob), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (_, _, body) = app.send(Method::GET, "/api/notes", Some(&bob), None).await;
+        assert_eq!(body["results"], json!(0));
+    }
+
+    #[tokio::test]
+    async fn readiness_reports_the_pool_against_its_configured_size() {
+        let app = TestApp::new();
+        let (status, body) = app.get("/readyz").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["status"], json!("degraded"));
+        assert_eq!(body["database"]["reachable"], json!(false));
+        assert_eq!(
+            body["pool"]["max"],
+            json!(app.state.settings.db_max_connections)
+        );
+    }
+}
@@ -0,0 +1,241 @@
+//! Deployment-specific hooks around the note lifecycle.
+//!
+//! Forks register [`NoteHook`] implementations in `main.rs` instead of
+//! patching the handlers. Pre-hooks run in the request, in registration
+//! order, before moderation and the write: they may rewrite the payload
+//! (enrich) or reject it (validate). What they leave is validated like the
+//! request itself, so a rewrite into an invalid note answers 422. Post-hooks are fed from the change event
+//! stream after the write committed (notify), so they cover every path that
+//! changes a note and can never fail a request. Review requests and decisions
+//! reach `after_review` the same way, to notify reviewers and note owners.
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use axum::{http::StatusCode, Json};
+use serde_json::{json, Value};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    events::NoteEvent,
+    model::NoteId,
+    review::ReviewModel,
+    schema::{CreateNoteSchema, UpdateNoteSchema},
+};
+
+/// Why a pre-hook refused a change; answered as 422 unless overridden.
+#[derive(Debug)]
+pub struct HookRejection {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl HookRejection {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+            message: message.into(),
+        }
+    }
+}
+
+/// Every method defaults to a no-op, so hooks implement only what they need.
+#[async_trait]
+pub trait NoteHook: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Runs before a note is created, including `PUT` to a new or existing id.
+    /// The note is validated again afterwards.
+    async fn before_create(&self, _note: &mut CreateNoteSchema) -> Result<(), HookRejection> {
+        Ok(())
+    }
+
+    /// Runs before a note is edited; the changes are validated again
+    /// afterwards.
+    async fn before_update(
+        &self,
+        _id: NoteId,
+        _changes: &mut UpdateNoteSchema,
+    ) -> Result<(), HookRejection> {
+        Ok(())
+    }
+
+    async fn before_delete(&self, _id: NoteId) -> Result<(), HookRejection> {
+        Ok(())
+    }
+
+    async fn after_change(&self, _event: &NoteEvent) {}
+
+    /// Runs when a reviewer is asked to review a note, and when they decide.
+    async fn after_review(&self, _review: &ReviewModel) {}
+}
+
+#[derive(Default)]
+pub struct Hooks {
+    hooks: Vec<Arc<dyn NoteHook>>,
+}
+
+fn rejected(hook: &dyn NoteHook, rejection: HookRejection) -> (StatusCode, Json<Value>) {
+    tracing::warn!(hook = hook.name(), reason = %rejection.message, "Hook rejected a change");
+    let error_response = json!({
+        "status": "fail",
+        "message": rejection.message,
+    });
+    (rejection.status, Json(error_response))
+}
+
+impl Hooks {
+    pub fn register(&mut self, hook: impl NoteHook + 'static) {
+        self.register_shared(Arc::new(hook));
+    }
+
+    /// Registers a hook the caller keeps a handle to.
+    pub fn register_shared(&mut self, hook: Arc<dyn NoteHook>) {
+        tracing::info!(hook = hook.name(), "Registered note hook");
+        self.hooks.push(hook);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    pub async fn before_create(
+        &self,
+        note: &mut CreateNoteSchema,
+    ) -> Result<(), (StatusCode, Json<Value>)> {
+        for hook in &self.hooks {
+            hook.before_create(note)
+                .await
+                .map_err(|rejection| rejected(hook.as_ref(), rejection))?;
+        }
+        Ok(())
+    }
+
+    pub async fn before_update(
+        &self,
+        id: NoteId,
+        changes: &mut UpdateNoteSchema,
+    ) -> Result<(), (StatusCode, Json<Value>)> {
+        for hook in &self.hooks {
+            hook.before_update(id, changes)
+                .await
+                .map_err(|rejection| rejected(hook.as_ref(), rejection))?;
+        }
+        Ok(())
+    }
+
+    pub async fn before_delete(&self, id: NoteId) -> Result<(), (StatusCode, Json<Value>)> {
+        for hook in &self.hooks {
+            hook.before_delete(id)
+                .await
+                .map_err(|rejection| rejected(hook.as_ref(), rejection))?;
+        }
+        Ok(())
+    }
+
+    /// Hands a review request or decision to every hook's `after_review`,
+    /// in the background.
+    pub fn after_review(&self, review: &ReviewModel) {
+        if self.hooks.is_empty() {
+            return;
+        }
+        let hooks = self.hooks.clone();
+        let review = review.clone();
+        tokio::spawn(async move {
+            for hook in &hooks {
+                hook.after_review(&review).await;
+            }
+        });
+    }
+}
+
+/// Feeds change events to every hook's `after_change`.
+pub fn spawn_dispatcher(hooks: &Hooks, mut events: broadcast::Receiver<NoteEvent>) {
+    if hooks.is_empty() {
+        return;
+    }
+    let hooks = hooks.hooks.clone();
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    for hook in &hooks {
+                        hook.after_change(&event).await;
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!(skipped, "Note hooks skipped change events");
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Method;
+    use serde_json::json;
+
+    use crate::testing::TestApp;
+
+    use super::*;
+
+    /// Blanks the title of notes whose content is "untitled".
+    struct Untitle;
+
+    #[async_trait]
+    impl NoteHook for Untitle {
+        fn name(&self) -> &'static str {
+            "untitle"
+        }
+
+        async fn before_create(&self, note: &mut CreateNoteSchema) -> Result<(), HookRejection> {
+            if note.content == "untitled" {
+                note.title = String::new();
+            }
+            Ok(())
+        }
+
+        async fn before_update(
+            &self,
+            _id: NoteId,
+            changes: &mut UpdateNoteSchema,
+        ) -> Result<(), HookRejection> {
+            if changes.content.as_deref() == Some("untitled") {
+                changes.title = Some(String::new());
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_hook_output_is_refused() {
+        let mut hooks = Hooks::default();
+        hooks.register(Untitle);
+        let app = TestApp::with_hooks(hooks);
+
+        let (status, body) = app
+            .post(
+                "/api/notes",
+                json!({ "title": "Blank", "content": "untitled" }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{}", body);
+        assert!(body["errors"]["title"].is_string(), "{}", body);
+
+        let (status, body) = app
+            .post("/api/notes", json!({ "title": "Named", "content": "x" }))
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        let uri = format!(
+            "/api/notes/{}",
+            body["data"]["note"]["id"].as_str().unwrap()
+        );
+        let changes = json!({ "content": "untitled" });
+        let (status, _, body) = app.send(Method::PATCH, &uri, None, Some(changes)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{}", body);
+        assert!(body["errors"]["title"].is_string(), "{}", body);
+        assert_eq!(app.get(&uri).await.1["data"]["note"]["content"], "x");
+    }
+}
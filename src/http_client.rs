@@ -0,0 +1,438 @@
+//! The one outbound HTTP client, shared by every integration (secrets
+//! backends, alert webhooks, CAPTCHA verification, external moderation).
+//!
+//! Requests are tagged with a service name. Each service gets retries with
+//! exponential backoff, its own circuit breaker, and counters served at
+//! `GET /api/admin/http-clients`, so a slow or failing dependency is visible
+//! and cannot tie up request handlers.
+//!
+//! Services that fetch URLs from users or admins, listed in
+//! [`GUARDED_SERVICES`], go through the SSRF guard of [`crate::ssrf`].
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{extract::State, response::IntoResponse, Json};
+use rand::Rng;
+use reqwest::{IntoUrl, Method, Request, RequestBuilder, Response};
+use serde_json::{json, Value};
+
+use crate::{
+    context::RequestContext,
+    ssrf::{self, GuardedResolver, OutboundGuard},
+    AppState,
+};
+
+/// Services whose requests may only reach addresses the guard permits.
+pub const GUARDED_SERVICES: &[&str] = &["alert-webhook", "clipper", "link-check", "link-preview"];
+
+pub struct HttpClientOptions {
+    /// Whole-request timeout; a request builder can set a shorter one.
+    pub timeout: Duration,
+    pub connect_timeout: Duration,
+    /// Additional attempts after the first, for requests sent with
+    /// [`HttpClient::send`].
+    pub retries: u32,
+    /// Backoff before the first retry, doubled for each one after it.
+    pub retry_backoff: Duration,
+    /// Consecutive failures after which a service's circuit opens.
+    pub breaker_threshold: u32,
+    /// How long an open circuit fails requests before letting one through.
+    pub breaker_cooldown: Duration,
+    /// Applied to requests of the [`GUARDED_SERVICES`].
+    pub guard: OutboundGuard,
+}
+
+impl Default for HttpClientOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            connect_timeout: Duration::from_secs(3),
+            retries: 2,
+            retry_backoff: Duration::from_millis(200),
+            breaker_threshold: 5,
+            breaker_cooldown: Duration::from_secs(30),
+            guard: OutboundGuard::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum HttpError {
+    /// The service failed too often recently; the request was not sent.
+    CircuitOpen(String),
+    /// The SSRF guard refused the URL; the request was not sent.
+    Blocked(String),
+    Request(reqwest::Error),
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpError::CircuitOpen(service) => write!(f, "circuit open for '{}'", service),
+            HttpError::Blocked(reason) => write!(f, "blocked: {}", reason),
+            HttpError::Request(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+impl From<reqwest::Error> for HttpError {
+    fn from(err: reqwest::Error) -> Self {
+        HttpError::Request(err)
+    }
+}
+
+#[derive(Default)]
+struct ServiceStats {
+    requests: u64,
+    failures: u64,
+    retries: u64,
+    rejected: u64,
+    circuit_opened: u64,
+    total_latency: Duration,
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+    last_error: Option<String>,
+}
+
+pub struct HttpClient {
+    client: reqwest::Client,
+    /// Resolves and redirects through the guard.
+    guarded: reqwest::Client,
+    options: HttpClientOptions,
+    services: Mutex<HashMap<String, ServiceStats>>,
+}
+
+/// Whether a response means the service is unhealthy (and worth retrying).
+fn is_failure(status: reqwest::StatusCode) -> bool {
+    status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+}
+
+impl HttpClient {
+    pub fn new(options: HttpClientOptions) -> Self {
+        let builder = || {
+            reqwest::Client::builder()
+                .timeout(options.timeout)
+                .connect_timeout(options.connect_timeout)
+                .user_agent(concat!(
+                    env!("CARGO_PKG_NAME"),
+                    "/",
+                    env!("CARGO_PKG_VERSION")
+                ))
+        };
+        let client = builder().build().expect("reqwest client");
+        let guard = Arc::new(options.guard.clone());
+        let guarded = builder()
+            .dns_resolver(Arc::new(GuardedResolver::new(guard.clone())))
+            .redirect(ssrf::redirect_policy(guard))
+            .build()
+            .expect("reqwest client");
+        Self {
+            client,
+            guarded,
+            options,
+            services: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn request(&self, method: Method, url: impl IntoUrl) -> RequestBuilder {
+        self.client.request(method, url)
+    }
+
+    pub fn get(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.client.get(url)
+    }
+
+    pub fn post(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.client.post(url)
+    }
+
+    /// Sends `request`, retrying transport errors, 5xx and 429 answers. Only
+    /// for requests that are safe to repeat.
+    pub async fn send(
+        &self,
+        service: &str,
+        request: RequestBuilder,
+    ) -> Result<Response, HttpError> {
+        self.execute(service, request.build()?, self.options.retries)
+            .await
+    }
+
+    /// Sends `request` exactly once, for requests that must not be repeated
+    /// (single-use tokens, non-idempotent writes).
+    pub async fn send_once(
+        &self,
+        service: &str,
+        request: RequestBuilder,
+    ) -> Result<Response, HttpError> {
+        self.execute(service, request.build()?, 0).await
+    }
+
+    async fn execute(
+        &self,
+        service: &str,
+        mut request: Request,
+        retries: u32,
+    ) -> Result<Response, HttpError> {
+        let guarded = GUARDED_SERVICES.contains(&service);
+        if guarded {
+            self.options
+                .guard
+                .check_url(request.url())
+                .map_err(HttpError::Blocked)?;
+        }
+        let client = if guarded { &self.guarded } else { &self.client };
+        // Within a request, give up when the request has to be answered.
+        if let Some(context) = RequestContext::current() {
+            let timeout = request.timeout_mut();
+            *timeout = Some(
+                timeout
+                    .unwrap_or(self.options.timeout)
+                    .min(context.remaining()),
+            );
+        }
+
+        let mut attempt = 0;
+        loop {
+            self.admit(service)?;
+            // Streaming bodies cannot be cloned and so are never retried.
+            let next = (attempt < retries).then(|| request.try_clone()).flatten();
+            let started = Instant::now();
+            let result = client.execute(request).await;
+            let failure = match &result {
+                Ok(response) if is_failure(response.status()) => {
+                    Some(format!("answered {}", response.status()))
+                }
+                Ok(_) => None,
+                Err(err) => Some(err.to_string()),
+            };
+            self.record(service, started.elapsed(), failure.clone());
+
+            match (failure, next) {
+                (Some(failure), Some(next)) => {
+                    attempt += 1;
+                    tracing::warn!(service, %failure, attempt, retries, "Request failed, retrying");
+                    self.services
+                        .lock()
+                        .unwrap()
+                        .entry(service.to_string())
+                        .or_default()
+                        .retries += 1;
+                    let backoff = self.options.retry_backoff * 2u32.pow(attempt - 1);
+                    let jitter = rand::thread_rng().gen_range(0..=backoff.as_millis() as u64 / 2);
+                    tokio::time::sleep(backoff + Duration::from_millis(jitter)).await;
+                    request = next;
+                }
+                _ => return result.map_err(HttpError::from),
+            }
+        }
+    }
+
+    fn admit(&self, service: &str) -> Result<(), HttpError> {
+        let mut services = self.services.lock().unwrap();
+        let stats = services.entry(service.to_string()).or_default();
+        match stats.open_until {
+            Some(until) if until > Instant::now() => {
+                stats.rejected += 1;
+                Err(HttpError::CircuitOpen(service.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn record(&self, service: &str, latency: Duration, failure: Option<String>) {
+        let mut services = self.services.lock().unwrap();
+        let stats = services.entry(service.to_string()).or_default();
+        stats.requests += 1;
+        stats.total_latency += latency;
+        let Some(failure) = failure else {
+            stats.consecutive_failures = 0;
+            stats.open_until = None;
+            return;
+        };
+        stats.failures += 1;
+        stats.consecutive_failures += 1;
+        stats.last_error = Some(failure);
+        // A failed trial request after the cooldown reopens the circuit.
+        if stats.consecutive_failures >= self.options.breaker_threshold {
+            if stats.open_until.is_none() {
+                tracing::error!(
+                    service,
+                    failures = stats.consecutive_failures,
+                    "Circuit opened"
+                );
+                stats.circuit_opened += 1;
+            }
+            stats.open_until = Some(Instant::now() + self.options.breaker_cooldown);
+        }
+    }
+
+    pub fn snapshot(&self) -> Value {
+        let now = Instant::now();
+        let services = self.services.lock().unwrap();
+        let report = services
+            .iter()
+            .map(|(service, stats)| {
+                let average_ms = match stats.requests {
+                    0 => 0,
+                    requests => stats.total_latency.as_millis() as u64 / requests,
+                };
+                let open_for = stats
+                    .open_until
+                    .filter(|until| *until > now)
+                    .map(|until| (until - now).as_secs());
+                (
+                    service.clone(),
+                    json!({
+                        "requests": stats.requests,
+                        "failures": stats.failures,
+                        "retries": stats.retries,
+                        "rejected": stats.rejected,
+                        "circuit_opened": stats.circuit_opened,
+                        "circuit_open_for_secs": open_for,
+                        "average_latency_ms": average_ms,
+                        "last_error": stats.last_error,
+                    }),
+                )
+            })
+            .collect::<serde_json::Map<_, _>>();
+        Value::Object(report)
+    }
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        Self::new(HttpClientOptions::default())
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/http-clients",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Outbound HTTP client statistics"),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn http_clients_handler(State(data): State<Arc<AppState>>) -> impl IntoResponse {
+    Json(json!({
+        "status": "success",
+        "data": data.http.snapshot(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::atomic::{AtomicU32, Ordering},
+    };
+
+    use axum::{http::StatusCode, routing::get, Router};
+
+    use super::*;
+
+    /// Answers 503 to the first `failures` requests, then 200; returns its
+    /// address and the number of requests it has seen.
+    async fn flaky_server(failures: u32) -> (SocketAddr, Arc<AtomicU32>) {
+        let seen = Arc::new(AtomicU32::new(0));
+        let counter = seen.clone();
+        let app = Router::new().route(
+            "/",
+            get(move || {
+                let seen = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                async move {
+                    if seen <= failures {
+                        StatusCode::SERVICE_UNAVAILABLE
+                    } else {
+                        StatusCode::OK
+                    }
+                }
+            }),
+        );
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (addr, seen)
+    }
+
+    fn client(retries: u32, breaker_threshold: u32) -> HttpClient {
+        HttpClient::new(HttpClientOptions {
+            retries,
+            retry_backoff: Duration::from_millis(1),
+            breaker_threshold,
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn retries_failures_until_one_succeeds() {
+        let (addr, seen) = flaky_server(2).await;
+        let http = client(2, 10);
+        let response = http
+            .send("test", http.get(format!("http://{}/", addr)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(seen.load(Ordering::SeqCst), 3);
+        assert_eq!(http.snapshot()["test"]["retries"], json!(2));
+    }
+
+    #[tokio::test]
+    async fn send_once_never_retries() {
+        let (addr, seen) = flaky_server(1).await;
+        let http = client(2, 10);
+        let response = http
+            .send_once("test", http.get(format!("http://{}/", addr)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(seen.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn an_open_circuit_fails_requests_without_sending_them() {
+        let (addr, seen) = flaky_server(u32::MAX).await;
+        let http = client(0, 2);
+        let url = format!("http://{}/", addr);
+        for _ in 0..2 {
+            http.send("test", http.get(&url)).await.unwrap();
+        }
+        let err = http.send("test", http.get(&url)).await.unwrap_err();
+        assert!(matches!(err, HttpError::CircuitOpen(_)), "{}", err);
+        assert_eq!(seen.load(Ordering::SeqCst), 2);
+        // Other services have circuits of their own.
+        assert!(http.send("other", http.get(&url)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn guarded_services_cannot_reach_internal_addresses() {
+        let (addr, seen) = flaky_server(0).await;
+        let http = client(0, 10);
+        let url = format!("http://{}/", addr);
+        for service in GUARDED_SERVICES {
+            let err = http.send(service, http.get(&url)).await.unwrap_err();
+            assert!(matches!(err, HttpError::Blocked(_)), "{}: {}", service, err);
+        }
+        let err = http
+            .send(
+                "clipper",
+                http.get("http://169.254.169.254/latest/meta-data"),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HttpError::Blocked(_)), "{}", err);
+        assert_eq!(seen.load(Ordering::SeqCst), 0);
+
+        // Configured integrations are trusted with any address.
+        assert!(http.send("secrets", http.get(&url)).await.is_ok());
+    }
+}
@@ -0,0 +1,302 @@
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use rand::Rng;
+use uuid::{timestamp::context::ContextV7, Timestamp, Uuid};
+
+use crate::clock::Clock;
+
+/// Source of identifiers for new rows.
+///
+/// Every strategy produces 128 bits rendered as a hyphenated UUID string, so
+/// the external id format does not depend on the configured strategy.
+pub trait IdGenerator: Send + Sync {
+    fn generate(&self) -> Uuid;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdStrategy {
+    UuidV4,
+    UuidV7,
+    Ulid,
+    Snowflake,
+}
+
+impl FromStr for IdStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "uuidv4" | "uuid_v4" | "v4" => Ok(Self::UuidV4),
+            "uuidv7" | "uuid_v7" | "v7" => Ok(Self::UuidV7),
+            "ulid" => Ok(Self::Ulid),
+            "snowflake" => Ok(Self::Snowflake),
+            other => Err(format!(
+                "unknown id strategy '{}', expected uuidv4, uuidv7, ulid or snowflake",
+                other
+            )),
+        }
+    }
+}
+
+pub fn generator(
+    strategy: IdStrategy,
+    clock: Arc<dyn Clock>,
+    worker_id: u16,
+) -> Arc<dyn IdGenerator> {
+    match strategy {
+        IdStrategy::UuidV4 => Arc::new(UuidV4Generator),
+        IdStrategy::UuidV7 => Arc::new(UuidV7Generator::new(clock)),
+        IdStrategy::Ulid => Arc::new(UlidGenerator::new(clock)),
+        IdStrategy::Snowflake => Arc::new(SnowflakeGenerator::new(clock, worker_id)),
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UuidV4Generator;
+
+impl IdGenerator for UuidV4Generator {
+    fn generate(&self) -> Uuid {
+        Uuid::new_v4()
+    }
+}
+
+/// Time-ordered UUIDv7 ids, monotonic within a millisecond.
+pub struct UuidV7Generator {
+    clock: Arc<dyn Clock>,
+    context: Mutex<ContextV7>,
+}
+
+impl UuidV7Generator {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock,
+            context: Mutex::new(ContextV7::new()),
+        }
+    }
+}
+
+impl IdGenerator for UuidV7Generator {
+    fn generate(&self) -> Uuid {
+        let now = self.clock.now();
+        let context = self.context.lock().unwrap();
+        let ts = Timestamp::from_unix(
+            &*context,
+            now.timestamp() as u64,
+            now.timestamp_subsec_nanos(),
+        );
+        Uuid::new_v7(ts)
+    }
+}
+
+/// ULIDs: a 48-bit millisecond timestamp followed by 80 random bits. Within
+/// one millisecond the random part is incremented, as the spec's monotonic
+/// mode describes.
+pub struct UlidGenerator {
+    clock: Arc<dyn Clock>,
+    last: Mutex<u128>,
+}
+
+impl UlidGenerator {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock,
+            last: Mutex::new(0),
+        }
+    }
+}
+
+impl IdGenerator for UlidGenerator {
+    fn generate(&self) -> Uuid {
+        const RANDOM_BITS: u32 = 80;
+        const RANDOM_MASK: u128 = (1 << RANDOM_BITS) - 1;
+
+        let millis = self.clock.now().timestamp_millis().max(0) as u128 & ((1 << 48) - 1);
+        let mut last = self.last.lock().unwrap();
+        let next = if *last >> RANDOM_BITS == millis && *last & RANDOM_MASK != RANDOM_MASK {
+            *last + 1
+        } else {
+            (millis << RANDOM_BITS) | (rand::thread_rng().gen::<u128>() & RANDOM_MASK)
+        };
+        *last = next;
+        Uuid::from_u128(next)
+    }
+}
+
+/// Twitter-style snowflakes: 41 bits of milliseconds since [`SNOWFLAKE_EPOCH_MS`],
+/// a 10-bit worker id and a 12-bit per-millisecond sequence. The 64-bit value
+/// occupies the high half of the id so ids still sort by creation time.
+pub struct SnowflakeGenerator {
+    clock: Arc<dyn Clock>,
+    worker_id: u64,
+    state: Mutex<(i64, u64)>,
+}
+
+/// 2023-05-03T00:00:00Z, the project's epoch.
+pub const SNOWFLAKE_EPOCH_MS: i64 = 1_683_072_000_000;
+/// Worker ids take 10 bits.
+pub const SNOWFLAKE_MAX_WORKER_ID: u16 = 0x3ff;
+
+impl SnowflakeGenerator {
+    /// Only the low 10 bits of `worker_id` are used; configuration refuses
+    /// ids over [`SNOWFLAKE_MAX_WORKER_ID`].
+    pub fn new(clock: Arc<dyn Clock>, worker_id: u16) -> Self {
+        Self {
+            clock,
+            worker_id: u64::from(worker_id & SNOWFLAKE_MAX_WORKER_ID),
+            state: Mutex::new((0, 0)),
+        }
+    }
+}
+
+impl IdGenerator for SnowflakeGenerator {
+    fn generate(&self) -> Uuid {
+        let mut state = self.state.lock().unwrap();
+        let (last_ms, sequence) = *state;
+        let mut millis = (self.clock.now().timestamp_millis() - SNOWFLAKE_EPOCH_MS).max(last_ms);
+
+        let sequence = if millis == last_ms {
+            let next = (sequence + 1) & 0xfff;
+            if next == 0 {
+                // Sequence exhausted for this millisecond: borrow the next one
+                // rather than spinning on a clock that may be frozen.
+                millis += 1;
+            }
+            next
+        } else {
+            0
+        };
+        *state = (millis, sequence);
+
+        let snowflake = ((millis as u64) << 22) | (self.worker_id << 12) | sequence;
+        Uuid::from_u128(u128::from(snowflake) << 64)
+    }
+}
+
+/// Hands out `00000000-0000-0000-0000-000000000001`, `...0002`, ... so runs
+/// are reproducible.
+#[derive(Debug, Default)]
+pub struct SequentialIdGenerator {
+    next: AtomicU64,
+}
+
+impl IdGenerator for SequentialIdGenerator {
+    fn generate(&self) -> Uuid {
+        Uuid::from_u128(u128::from(self.next.fetch_add(1, Ordering::Relaxed)) + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, thread};
+
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::clock::{FixedClock, SystemClock};
+
+    fn frozen() -> Arc<dyn Clock> {
+        Arc::new(FixedClock::new(
+            Utc.with_ymd_and_hms(2023, 5, 3, 12, 0, 0).unwrap(),
+        ))
+    }
+
+    /// `count` ids from `generator`, checked to be strictly increasing.
+    fn increasing(generator: &dyn IdGenerator, count: usize) -> Vec<Uuid> {
+        let ids = (0..count).map(|_| generator.generate()).collect::<Vec<_>>();
+        for pair in ids.windows(2) {
+            assert!(pair[0] < pair[1], "{} then {}", pair[0], pair[1]);
+        }
+        ids
+    }
+
+    #[test]
+    fn time_ordered_ids_increase_within_one_millisecond() {
+        let clock = frozen();
+        let millis = clock.now().timestamp_millis();
+
+        let ids = increasing(&UuidV7Generator::new(clock.clone()), 1000);
+        assert!(ids.iter().all(|id| id.get_version_num() == 7));
+
+        let ids = increasing(&UlidGenerator::new(clock.clone()), 1000);
+        assert!(ids.iter().all(|id| id.as_u128() >> 80 == millis as u128));
+
+        // More than the 4096 of one millisecond's sequence.
+        let ids = increasing(&SnowflakeGenerator::new(clock, 7), 5000);
+        assert_eq!(
+            ids[0].as_u128() >> 86,
+            (millis - SNOWFLAKE_EPOCH_MS) as u128
+        );
+    }
+
+    #[test]
+    fn sequential_ids_count_up_from_one() {
+        let generator = SequentialIdGenerator::default();
+        assert_eq!(generator.generate(), Uuid::from_u128(1));
+        assert_eq!(generator.generate(), Uuid::from_u128(2));
+        increasing(&generator, 100);
+    }
+
+    #[test]
+    fn ids_are_unique_across_threads() {
+        const THREADS: usize = 8;
+        const PER_THREAD: usize = 2000;
+
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        let generators: [(&str, Arc<dyn IdGenerator>); 5] = [
+            ("v4", generator(IdStrategy::UuidV4, clock.clone(), 1)),
+            ("v7", generator(IdStrategy::UuidV7, clock.clone(), 1)),
+            ("ulid", generator(IdStrategy::Ulid, clock.clone(), 1)),
+            ("snowflake", generator(IdStrategy::Snowflake, clock, 1)),
+            ("sequential", Arc::new(SequentialIdGenerator::default())),
+        ];
+        for (name, generator) in generators {
+            let handles = (0..THREADS)
+                .map(|_| {
+                    let generator = generator.clone();
+                    thread::spawn(move || {
+                        (0..PER_THREAD)
+                            .map(|_| generator.generate())
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+            let ids = handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap())
+                .collect::<HashSet<_>>();
+            assert_eq!(ids.len(), THREADS * PER_THREAD, "{}", name);
+        }
+    }
+
+    #[test]
+    fn snowflake_worker_ids_stay_in_their_ten_bits() {
+        let worker = |id: Uuid| (id.as_u128() >> 64 >> 12) as u64 & 0x3ff;
+        let timestamp = |id: Uuid| id.as_u128() >> 86;
+
+        let first = SnowflakeGenerator::new(frozen(), 0).generate();
+        let last = SnowflakeGenerator::new(frozen(), SNOWFLAKE_MAX_WORKER_ID).generate();
+        assert_eq!(worker(first), 0);
+        assert_eq!(worker(last), u64::from(SNOWFLAKE_MAX_WORKER_ID));
+        assert_eq!(timestamp(first), timestamp(last));
+
+        // Bigger ids do not reach into the timestamp.
+        let over = SnowflakeGenerator::new(frozen(), SNOWFLAKE_MAX_WORKER_ID + 2).generate();
+        assert_eq!(worker(over), 1);
+        assert_eq!(timestamp(over), timestamp(first));
+    }
+
+    #[test]
+    fn strategies_parse_by_their_names() {
+        assert_eq!("uuid_v7".parse(), Ok(IdStrategy::UuidV7));
+        assert_eq!("ULID".parse(), Ok(IdStrategy::Ulid));
+        assert_eq!("v4".parse(), Ok(IdStrategy::UuidV4));
+        assert_eq!("snowflake".parse(), Ok(IdStrategy::Snowflake));
+        assert!("uuid".parse::<IdStrategy>().is_err());
+    }
+}
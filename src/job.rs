@@ -0,0 +1,358 @@
+//! Background jobs queued in the `jobs` table.
+//!
+//! Work too slow for a request, such as extracting the text of an
+//! attachment, is queued with [`Jobs::enqueue`] in the transaction that
+//! creates its subject and run by a [`JobHandler`] registered for its kind.
+//! The elected leader works through the queue; failed jobs are retried with
+//! a growing delay until [`MAX_ATTEMPTS`], after which the handler is told to
+//! give up. Jobs that gave up are listed and replayed with the dead letters;
+//! see [`crate::dead_letter`].
+
+use std::{collections::HashMap, str::FromStr, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{mysql::MySqlPool, MySql, MySqlExecutor, QueryBuilder};
+
+use crate::{
+    model::{text_enum, BinaryId},
+    AppState,
+};
+
+pub const MAX_ATTEMPTS: u32 = 5;
+/// How often an empty queue is checked for new jobs.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Finished jobs are deleted after this long; failed ones are kept.
+const DONE_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+/// How long a handler gets before its job counts as failed.
+const JOB_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// Errors are cut to fit `last_error`.
+const MAX_ERROR_CHARS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Done,
+    /// Out of attempts.
+    Failed,
+}
+
+impl JobStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Pending => "pending",
+            JobStatus::Running => "running",
+            JobStatus::Done => "done",
+            JobStatus::Failed => "failed",
+        }
+    }
+}
+
+impl FromStr for JobStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(JobStatus::Pending),
+            "running" => Ok(JobStatus::Running),
+            "done" => Ok(JobStatus::Done),
+            "failed" => Ok(JobStatus::Failed),
+            other => Err(format!(
+                "Unknown job status '{}', expected pending, running, done or failed",
+                other
+            )),
+        }
+    }
+}
+
+text_enum!(JobStatus);
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Job {
+    pub id: u64,
+    pub kind: String,
+    /// What the job works on, such as an attachment.
+    pub subject_id: BinaryId,
+    pub status: JobStatus,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub run_after: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Runs the jobs of one kind.
+#[async_trait]
+pub trait JobHandler: Send + Sync {
+    fn kind(&self) -> &'static str;
+
+    async fn run(&self, data: &AppState, subject: BinaryId) -> Result<(), String>;
+
+    /// Called once the job has failed [`MAX_ATTEMPTS`] times.
+    async fn give_up(&self, _data: &AppState, _subject: BinaryId, _error: &str) {}
+}
+
+#[derive(Default)]
+pub struct Jobs {
+    handlers: HashMap<&'static str, Arc<dyn JobHandler>>,
+}
+
+impl Jobs {
+    pub fn register(&mut self, handler: impl JobHandler + 'static) {
+        tracing::info!(kind = %handler.kind(), "Registered job handler");
+        self.handlers.insert(handler.kind(), Arc::new(handler));
+    }
+
+    /// Whether jobs of `kind` are run here; there is no point queueing them
+    /// otherwise.
+    pub fn handles(&self, kind: &str) -> bool {
+        self.handlers.contains_key(kind)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
+    }
+
+    pub async fn enqueue(
+        &self,
+        db: impl MySqlExecutor<'_>,
+        kind: &str,
+        subject: BinaryId,
+        now: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO jobs (kind, subject_id, status, attempts, run_after, created_at) VALUES (?, ?, 'pending', 0, ?, ?)",
+        )
+        .bind(kind)
+        .bind(subject)
+        .bind(now)
+        .bind(now)
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    /// Jobs that ran out of attempts, most recently created first.
+    pub async fn failed(&self, db: &MySqlPool, limit: u64) -> Result<Vec<Job>, sqlx::Error> {
+        sqlx::query_as::<_, Job>(
+            "SELECT * FROM jobs WHERE status = 'failed' ORDER BY id DESC LIMIT ?",
+        )
+        .bind(limit)
+        .fetch_all(db)
+        .await
+    }
+
+    /// Queues failed job `id` again, due at `now` with a fresh set of
+    /// attempts. Its last error is kept until it runs. `None` when there is
+    /// no such failed job.
+    pub async fn retry(
+        &self,
+        db: &MySqlPool,
+        id: u64,
+        now: DateTime<Utc>,
+    ) -> Result<Option<Job>, sqlx::Error> {
+        let requeued = sqlx::query(
+            "UPDATE jobs SET status = 'pending', attempts = 0, run_after = ? WHERE id = ? AND status = 'failed'",
+        )
+        .bind(now)
+        .bind(id)
+        .execute(db)
+        .await?;
+        if requeued.rows_affected() == 0 {
+            return Ok(None);
+        }
+        sqlx::query_as::<_, Job>("SELECT * FROM jobs WHERE id = ?")
+            .bind(id)
+            .fetch_optional(db)
+            .await
+    }
+
+    /// Takes the oldest due job of a kind handled here, marking it running.
+    async fn claim(&self, data: &AppState) -> Result<Option<Job>, sqlx::Error> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        loop {
+            let mut query = QueryBuilder::<MySql>::new(
+                "SELECT * FROM jobs WHERE status = 'pending' AND run_after <= ",
+            );
+            query.push_bind(data.clock.now()).push(" AND kind IN (");
+            let mut kinds = query.separated(", ");
+            for kind in self.handlers.keys() {
+                kinds.push_bind(*kind);
+            }
+            query.push(") ORDER BY run_after, id LIMIT 1");
+
+            let Some(job) = query
+                .build_query_as::<Job>()
+                .fetch_optional(&data.db)
+                .await?
+            else {
+                return Ok(None);
+            };
+            let claimed = sqlx::query(
+                "UPDATE jobs SET status = 'running', attempts = attempts + 1 WHERE id = ? AND status = 'pending'",
+            )
+            .bind(job.id)
+            .execute(&data.db)
+            .await?;
+            if claimed.rows_affected() == 1 {
+                return Ok(Some(Job {
+                    status: JobStatus::Running,
+                    attempts: job.attempts + 1,
+                    ..job
+                }));
+            }
+            // Taken by a leader that has not noticed it lost its lease yet.
+        }
+    }
+
+    async fn finish(&self, data: &AppState, job: &Job, outcome: Result<(), String>) {
+        let result = match outcome {
+            Ok(()) => {
+                sqlx::query("UPDATE jobs SET status = 'done', last_error = NULL WHERE id = ?")
+                    .bind(job.id)
+                    .execute(&data.db)
+                    .await
+            }
+            Err(err) => {
+                let err = err.chars().take(MAX_ERROR_CHARS).collect::<String>();
+                tracing::warn!(
+                    job_id = job.id,
+                    kind = %job.kind,
+                    attempt = job.attempts,
+                    error = %err,
+                    "Job failed",
+                );
+                let (status, run_after) = if job.attempts >= MAX_ATTEMPTS {
+                    if let Some(handler) = self.handlers.get(job.kind.as_str()) {
+                        handler.give_up(data, job.subject_id, &err).await;
+                    }
+                    (JobStatus::Failed, job.run_after)
+                } else {
+                    let delay = chrono::Duration::from_std(retry_delay(job.attempts))
+                        .unwrap_or_else(|_| chrono::Duration::hours(1));
+                    (JobStatus::Pending, data.clock.now() + delay)
+                };
+                sqlx::query(
+                    "UPDATE jobs SET status = ?, last_error = ?, run_after = ? WHERE id = ?",
+                )
+                .bind(status)
+                .bind(err)
+                .bind(run_after)
+                .bind(job.id)
+                .execute(&data.db)
+                .await
+            }
+        };
+        if let Err(err) = result {
+            tracing::error!(
+                job_id = job.id,
+                kind = %job.kind,
+                error = ?err,
+                "Failed to record the job outcome",
+            );
+        }
+    }
+
+    async fn prune(&self, data: &AppState) {
+        let cutoff =
+            data.clock.now() - chrono::Duration::from_std(DONE_RETENTION).expect("retention fits");
+        let result =
+            sqlx::query("DELETE FROM jobs WHERE status = 'done' AND run_after < ? LIMIT 1000")
+                .bind(cutoff)
+                .execute(&data.db)
+                .await;
+        if let Err(err) = result {
+            tracing::error!(error = ?err, "Failed to delete finished jobs");
+        }
+    }
+
+    /// Runs one due job, returning whether there was one.
+    async fn run_next(&self, data: &AppState) -> Result<bool, sqlx::Error> {
+        let Some(job) = self.claim(data).await? else {
+            return Ok(false);
+        };
+        let Some(handler) = self.handlers.get(job.kind.as_str()) else {
+            return Ok(true);
+        };
+        let outcome = tokio::time::timeout(JOB_TIMEOUT, handler.run(data, job.subject_id))
+            .await
+            .unwrap_or_else(|_| Err(format!("timed out after {:?}", JOB_TIMEOUT)));
+        self.finish(data, &job, outcome).await;
+        Ok(true)
+    }
+}
+
+/// How long to wait after the `attempts`th failure: doubling from
+/// [`FIRST_RETRY_DELAY`] up to [`MAX_RETRY_DELAY`].
+fn retry_delay(attempts: u32) -> Duration {
+    FIRST_RETRY_DELAY
+        .saturating_mul(1 << attempts.saturating_sub(1).min(16))
+        .min(MAX_RETRY_DELAY)
+}
+
+/// Works through the queue, polling every `interval` once it is empty. Runs
+/// on the leader, so jobs left running by a previous leader are requeued
+/// first.
+pub async fn run(data: Arc<AppState>, interval: Duration) {
+    match sqlx::query("UPDATE jobs SET status = 'pending' WHERE status = 'running'")
+        .execute(&data.db)
+        .await
+    {
+        Ok(requeued) if requeued.rows_affected() > 0 => {
+            tracing::warn!(jobs = requeued.rows_affected(), "Requeued interrupted jobs");
+        }
+        Ok(_) => {}
+        Err(err) => tracing::error!(error = ?err, "Failed to requeue interrupted jobs"),
+    }
+    loop {
+        match data.jobs.run_next(&data).await {
+            Ok(true) => continue,
+            Ok(false) => data.jobs.prune(&data).await,
+            Err(err) => tracing::error!(error = ?err, "Failed to take the next job"),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Noop;
+
+    #[async_trait]
+    impl JobHandler for Noop {
+        fn kind(&self) -> &'static str {
+            "noop"
+        }
+
+        async fn run(&self, _data: &AppState, _subject: BinaryId) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn retries_back_off_up_to_an_hour() {
+        assert_eq!(retry_delay(1), Duration::from_secs(30));
+        assert_eq!(retry_delay(2), Duration::from_secs(60));
+        assert_eq!(retry_delay(4), Duration::from_secs(240));
+        assert_eq!(retry_delay(MAX_ATTEMPTS + 20), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn only_registered_kinds_are_handled() {
+        let mut jobs = Jobs::default();
+        assert!(jobs.is_empty());
+        jobs.register(Noop);
+        assert!(jobs.handles("noop"));
+        assert!(!jobs.handles("ocr"));
+        assert_eq!("running".parse::<JobStatus>(), Ok(JobStatus::Running));
+        assert!("stuck".parse::<JobStatus>().is_err());
+    }
+}
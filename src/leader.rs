@@ -0,0 +1,315 @@
+//! Leader election over the `job_locks` lease table.
+//!
+//! Every instance campaigns for the `leader` lease; the winner renews it well
+//! before it expires and the others keep trying, so when the leader dies one
+//! of them takes over within one lease. Singleton tasks registered with
+//! [`spawn_singleton`] run only while this instance leads and are aborted as
+//! soon as leadership is lost.
+
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{extract::State, response::IntoResponse, Json};
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use tokio::sync::watch;
+
+use crate::{
+    clock::Clock,
+    error::AppError,
+    lock::{DistributedLock, LockRow},
+    AppState,
+};
+
+const LEADER_LOCK: &str = "leader";
+
+pub struct Leadership {
+    locks: Arc<DistributedLock>,
+    clock: Arc<dyn Clock>,
+    lease: Duration,
+    leading: watch::Sender<bool>,
+    since: Mutex<Option<DateTime<Utc>>>,
+    singletons: Mutex<Vec<&'static str>>,
+}
+
+impl Leadership {
+    pub fn new(locks: Arc<DistributedLock>, clock: Arc<dyn Clock>, lease: Duration) -> Self {
+        Self {
+            locks,
+            clock,
+            lease,
+            leading: watch::channel(false).0,
+            since: Mutex::new(None),
+            singletons: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn is_leader(&self) -> bool {
+        *self.leading.borrow()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.leading.subscribe()
+    }
+
+    fn set_leading(&self, leading: bool) {
+        if self.is_leader() == leading {
+            return;
+        }
+        *self.since.lock().unwrap() = leading.then(|| self.clock.now());
+        if leading {
+            tracing::info!(instance = self.locks.owner(), "Elected leader");
+        } else {
+            tracing::warn!(instance = self.locks.owner(), "Lost leadership");
+        }
+        self.leading.send_replace(leading);
+    }
+
+    /// Campaigns for the leader lease every third of a lease. A failed renewal
+    /// steps down at once: the lease may lapse before the next attempt, and two
+    /// leaders are worse than none for a moment.
+    pub fn spawn_campaign(self: &Arc<Self>) {
+        let leadership = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(leadership.lease / 3);
+            loop {
+                ticker.tick().await;
+                leadership.campaign().await;
+            }
+        });
+    }
+
+    async fn campaign(&self) {
+        match self.locks.try_acquire(LEADER_LOCK, self.lease).await {
+            Ok(leading) => self.set_leading(leading),
+            Err(err) => {
+                tracing::error!(error = ?err, "Failed to renew leader lease");
+                self.set_leading(false);
+            }
+        }
+    }
+}
+
+/// Waits until leadership is `want`; false once the election has stopped.
+async fn wait_until(leading: &mut watch::Receiver<bool>, want: bool) -> bool {
+    leading.wait_for(|leading| *leading == want).await.is_ok()
+}
+
+/// Runs `task` on the elected leader only, restarting it whenever this
+/// instance (re)gains leadership and aborting it when leadership is lost.
+pub fn spawn_singleton<F, Fut>(leadership: &Arc<Leadership>, name: &'static str, task: F)
+where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    leadership.singletons.lock().unwrap().push(name);
+    let mut leading = leadership.subscribe();
+    tokio::spawn(async move {
+        loop {
+            if !wait_until(&mut leading, true).await {
+                return;
+            }
+            tracing::info!(task = name, "Starting singleton task");
+            let mut running = tokio::spawn(task());
+            tokio::select! {
+                _ = wait_until(&mut leading, false) => {
+                    running.abort();
+                    tracing::warn!(task = name, "Stopped singleton task after losing leadership");
+                }
+                result = &mut running => {
+                    if let Err(err) = result {
+                        tracing::error!(task = name, error = ?err, "Singleton task failed");
+                    }
+                    // Finished tasks are not restarted until the next term.
+                    if !wait_until(&mut leading, false).await {
+                        return;
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/leader",
+    tag = "admin",
+    responses(
+        (status = 200, description = "The current leader"),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn leader_handler(
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let lease = sqlx::query_as::<_, LockRow>("SELECT * FROM job_locks WHERE name = ?")
+        .bind(LEADER_LOCK)
+        .fetch_optional(&data.db)
+        .await?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "data": json!({
+            "instance": data.locks.owner(),
+            "is_leader": data.leadership.is_leader(),
+            "leader_since": *data.leadership.since.lock().unwrap(),
+            "lease_seconds": data.leadership.lease.as_secs(),
+            "lease": lease,
+            "singletons": *data.leadership.singletons.lock().unwrap(),
+        })
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use chrono::TimeZone;
+
+    use crate::{
+        clock::FixedClock,
+        lock::{DistributedLock, InMemoryLeaseStore},
+        testing::unreachable_pool,
+    };
+
+    use super::*;
+
+    const LEASE: Duration = Duration::from_secs(30);
+
+    fn clock() -> Arc<FixedClock> {
+        Arc::new(FixedClock::new(
+            Utc.with_ymd_and_hms(2023, 5, 3, 12, 0, 0).unwrap(),
+        ))
+    }
+
+    fn candidates(clock: &Arc<FixedClock>) -> (Arc<Leadership>, Arc<Leadership>) {
+        let store = Arc::new(InMemoryLeaseStore::new(clock.clone()));
+        let candidate = |owner: &str| {
+            let locks = DistributedLock::with_store(store.clone(), owner.to_string());
+            Arc::new(Leadership::new(Arc::new(locks), clock.clone(), LEASE))
+        };
+        (candidate("a"), candidate("b"))
+    }
+
+    /// Lets spawned tasks catch up with a change of leadership.
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    #[tokio::test]
+    async fn one_candidate_leads_until_its_lease_lapses() {
+        let clock = clock();
+        let (a, b) = candidates(&clock);
+
+        a.campaign().await;
+        b.campaign().await;
+        assert!(a.is_leader());
+        assert!(!b.is_leader());
+        assert_eq!(*a.since.lock().unwrap(), Some(clock.now()));
+
+        // Renewals within the lease keep the leader.
+        clock.advance(chrono::Duration::seconds(10));
+        a.campaign().await;
+        clock.advance(chrono::Duration::seconds(25));
+        b.campaign().await;
+        assert!(a.is_leader());
+        assert!(!b.is_leader());
+
+        // The leader stops renewing; the next candidate takes over.
+        clock.advance(chrono::Duration::seconds(5));
+        b.campaign().await;
+        assert!(b.is_leader());
+        assert_eq!(*b.since.lock().unwrap(), Some(clock.now()));
+        a.campaign().await;
+        assert!(!a.is_leader());
+        assert_eq!(*a.since.lock().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn a_failed_renewal_steps_down() {
+        let locks = Arc::new(DistributedLock::new(unreachable_pool(), "a".to_string()));
+        let leadership = Leadership::new(locks, clock(), LEASE);
+        leadership.set_leading(true);
+
+        leadership.campaign().await;
+        assert!(!leadership.is_leader());
+        assert_eq!(*leadership.since.lock().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn singletons_run_only_while_leading() {
+        let clock = clock();
+        let (a, _) = candidates(&clock);
+        let starts = Arc::new(AtomicUsize::new(0));
+        let running = Arc::new(AtomicUsize::new(0));
+
+        struct Running(Arc<AtomicUsize>);
+        impl Drop for Running {
+            fn drop(&mut self) {
+                self.0.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+        spawn_singleton(&a, "digest", {
+            let (starts, running) = (starts.clone(), running.clone());
+            move || {
+                let (starts, running) = (starts.clone(), running.clone());
+                async move {
+                    starts.fetch_add(1, Ordering::SeqCst);
+                    running.fetch_add(1, Ordering::SeqCst);
+                    let _running = Running(running);
+                    std::future::pending::<()>().await;
+                }
+            }
+        });
+        assert_eq!(*a.singletons.lock().unwrap(), vec!["digest"]);
+
+        settle().await;
+        assert_eq!(starts.load(Ordering::SeqCst), 0);
+
+        a.set_leading(true);
+        settle().await;
+        assert_eq!(running.load(Ordering::SeqCst), 1);
+
+        a.set_leading(false);
+        settle().await;
+        assert_eq!(running.load(Ordering::SeqCst), 0);
+
+        // A new term starts the task again.
+        a.set_leading(true);
+        settle().await;
+        assert_eq!(starts.load(Ordering::SeqCst), 2);
+        assert_eq!(running.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn finished_singletons_wait_for_the_next_term() {
+        let clock = clock();
+        let (a, _) = candidates(&clock);
+        let runs = Arc::new(AtomicUsize::new(0));
+        spawn_singleton(&a, "backfill", {
+            let runs = runs.clone();
+            move || {
+                let runs = runs.clone();
+                async move {
+                    runs.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        });
+
+        a.set_leading(true);
+        settle().await;
+        // Renewing the lease is not a new term.
+        a.campaign().await;
+        settle().await;
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        a.set_leading(false);
+        settle().await;
+        a.set_leading(true);
+        settle().await;
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+}
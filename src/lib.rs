@@ -0,0 +1,151 @@
+pub mod admin;
+pub mod advisor;
+pub mod alerts;
+pub mod anomaly;
+pub mod archive;
+pub mod attachment;
+pub mod auth;
+pub mod bulk;
+pub mod canary;
+pub mod category;
+pub mod chaos;
+pub mod clipper;
+pub mod client_ip;
+pub mod clock;
+pub mod collab;
+pub mod compression;
+pub mod conditional;
+pub mod config;
+pub mod consent;
+pub mod content;
+pub mod context;
+pub mod dead_letter;
+pub mod error;
+pub mod events;
+pub mod expiry;
+pub mod export;
+pub mod graphql;
+pub mod handler;
+pub mod hooks;
+pub mod http_client;
+pub mod id;
+pub mod job;
+pub mod leader;
+pub mod link_check;
+pub mod link_preview;
+pub mod listener;
+pub mod load_test;
+pub mod loader;
+pub mod lock;
+pub mod lockout;
+pub mod model;
+pub mod moderation;
+pub mod negative_cache;
+pub mod note_cache;
+pub mod note_index;
+pub mod ocr;
+pub mod offload;
+pub mod openapi;
+pub mod operation;
+pub mod plugin;
+pub mod preview;
+pub mod rate_limit;
+pub mod read_receipt;
+pub mod report;
+pub mod repository;
+pub mod request_id;
+pub mod review;
+pub mod revision;
+pub mod route;
+pub mod schema;
+pub mod scripting;
+pub mod secrets;
+pub mod service;
+pub mod service_account;
+pub mod shutdown;
+pub mod signing_key;
+pub mod single_flight;
+pub mod ssrf;
+pub mod state;
+pub mod summary;
+pub mod tag;
+pub mod telemetry;
+#[cfg(test)]
+mod testing;
+pub mod transcription;
+pub mod trash;
+pub mod validation;
+pub mod warmup;
+pub mod write_buffer;
+
+use std::sync::Arc;
+
+use alerts::Alerts;
+use anomaly::AnomalyDetector;
+use attachment::AttachmentStorage;
+use auth::JwtAuth;
+use canary::Canaries;
+use chaos::Chaos;
+use clock::Clock;
+use collab::CollabHub;
+use dead_letter::DeadLetterStore;
+use hooks::Hooks;
+use http_client::HttpClient;
+use id::IdGenerator;
+use job::Jobs;
+use leader::Leadership;
+use lock::DistributedLock;
+use lockout::LoginGuard;
+use metrics_exporter_prometheus::PrometheusHandle;
+use moderation::Moderation;
+use plugin::Plugins;
+use rate_limit::RateLimiter;
+use repository::NoteRepository;
+use scripting::ScriptHooks;
+use secrets::CachedSecrets;
+use single_flight::ReadCoalescing;
+use sqlx::mysql::MySqlPool;
+use state::{Cache, EventBus, Settings};
+use warmup::WarmupReport;
+use write_buffer::WriteBuffer;
+
+pub struct AppState {
+    pub db: MySqlPool,
+    /// What the note handlers read and write notes through.
+    pub notes: Arc<dyn NoteRepository>,
+    pub write_buffer: WriteBuffer,
+    pub warmup: WarmupReport,
+    /// User accounts, enabled by `JWT_SECRET`.
+    pub auth: Option<JwtAuth>,
+    pub chaos: Option<Chaos>,
+    pub clock: Arc<dyn Clock>,
+    pub ids: Arc<dyn IdGenerator>,
+    pub events: EventBus,
+    /// WebSocket connections open on each note.
+    pub collab: CollabHub,
+    pub secrets: Arc<CachedSecrets>,
+    pub http: Arc<HttpClient>,
+    pub alerts: Arc<Alerts>,
+    /// Failed deliveries, for replay.
+    pub dead_letters: Arc<dyn DeadLetterStore>,
+    pub anomalies: Arc<AnomalyDetector>,
+    /// Where the files attached to notes are kept.
+    pub attachments: Arc<dyn AttachmentStorage>,
+    /// Set when a `RATE_LIMIT_*_PER_MINUTE` is.
+    pub rate_limits: Option<RateLimiter>,
+    pub logins: LoginGuard,
+    pub canaries: Arc<Canaries>,
+    pub moderation: Arc<Moderation>,
+    pub locks: Arc<DistributedLock>,
+    pub leadership: Arc<Leadership>,
+    pub coalescing: ReadCoalescing,
+    pub cache: Cache,
+    pub hooks: Hooks,
+    pub scripts: Arc<ScriptHooks>,
+    pub plugins: Plugins,
+    /// Handlers of the background jobs run by the leader.
+    pub jobs: Jobs,
+    /// Renders the metrics served at `/metrics`.
+    pub metrics: PrometheusHandle,
+    pub settings: Settings,
+}
@@ -0,0 +1,257 @@
+//! Periodic re-validation of the links stored in `note_link_previews`.
+//!
+//! The leader re-checks links whose last check is older than the check
+//! interval, recording the HTTP status, where a redirect ended up, and
+//! whether the link is broken. Results are filterable at
+//! `GET /api/notes/:id/links?status=` and reported across all notes at
+//! `GET /api/admin/links/broken`.
+
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Query, State},
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use reqwest::{Method, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use utoipa::IntoParams;
+
+use crate::{
+    error::AppError,
+    handler::page_offset,
+    http_client::HttpError,
+    link_preview::LinkPreviewOptions,
+    model::{text_enum, NoteId},
+    AppState,
+};
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+const BATCH: u32 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkStatus {
+    Ok,
+    /// Answers successfully, but only after redirecting elsewhere.
+    Redirected,
+    /// Unreachable, or answered with a client or server error.
+    Broken,
+}
+
+impl LinkStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LinkStatus::Ok => "ok",
+            LinkStatus::Redirected => "redirected",
+            LinkStatus::Broken => "broken",
+        }
+    }
+}
+
+impl FromStr for LinkStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ok" => Ok(LinkStatus::Ok),
+            "redirected" => Ok(LinkStatus::Redirected),
+            "broken" => Ok(LinkStatus::Broken),
+            other => Err(format!(
+                "Unknown link status '{}', expected ok, redirected or broken",
+                other
+            )),
+        }
+    }
+}
+
+text_enum!(LinkStatus);
+
+pub struct LinkCheck {
+    pub status: LinkStatus,
+    pub http_status: Option<u16>,
+    pub redirect_url: Option<String>,
+}
+
+/// Checks `url` with `HEAD`, retrying with `GET` for servers that do not
+/// support it. `None` when the result says nothing about the link (its
+/// service is throttling us or its circuit is open).
+pub async fn check(data: &AppState, url: &Url) -> Option<LinkCheck> {
+    let mut response = None;
+    for method in [Method::HEAD, Method::GET] {
+        let mut request = data
+            .http
+            .request(method.clone(), url.clone())
+            .timeout(CHECK_TIMEOUT);
+        if method == Method::GET {
+            // The body is never read.
+            request = request.header(reqwest::header::RANGE, "bytes=0-0");
+        }
+        match data.http.send("link-check", request).await {
+            Ok(answer)
+                if method == Method::HEAD
+                    && matches!(
+                        answer.status(),
+                        StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED
+                    ) => {}
+            Ok(answer) => {
+                response = Some(Ok(answer));
+                break;
+            }
+            Err(HttpError::CircuitOpen(_)) => return None,
+            Err(err) => {
+                response = Some(Err(err));
+                break;
+            }
+        }
+    }
+
+    match response? {
+        Ok(answer) if answer.status() == StatusCode::TOO_MANY_REQUESTS => None,
+        Ok(answer) => {
+            let http_status = Some(answer.status().as_u16());
+            if answer.status().is_client_error() || answer.status().is_server_error() {
+                return Some(LinkCheck {
+                    status: LinkStatus::Broken,
+                    http_status,
+                    redirect_url: None,
+                });
+            }
+            let redirect_url = (answer.url() != url).then(|| answer.url().to_string());
+            Some(LinkCheck {
+                status: if redirect_url.is_some() {
+                    LinkStatus::Redirected
+                } else {
+                    LinkStatus::Ok
+                },
+                http_status,
+                redirect_url,
+            })
+        }
+        Err(_) => Some(LinkCheck {
+            status: LinkStatus::Broken,
+            http_status: None,
+            redirect_url: None,
+        }),
+    }
+}
+
+/// Re-checks up to [`BATCH`] links last checked more than `interval` ago,
+/// returning how many were examined.
+pub async fn check_due(
+    data: &AppState,
+    options: &LinkPreviewOptions,
+    interval: Duration,
+) -> Result<usize, sqlx::Error> {
+    let due = sqlx::query_as::<_, (NoteId, String, String)>(
+        r#"SELECT note_id, url_hash, url FROM note_link_previews
+        WHERE checked_at IS NULL OR checked_at < NOW() - INTERVAL ? SECOND
+        ORDER BY checked_at IS NOT NULL, checked_at LIMIT ?"#,
+    )
+    .bind(interval.as_secs())
+    .bind(BATCH)
+    .fetch_all(&data.db)
+    .await?;
+
+    for (note_id, hash, url) in &due {
+        let result = match Url::parse(url) {
+            // Links on domains denied since they were stored are not checked.
+            Ok(url) if options.permits(&url) => check(data, &url).await,
+            _ => None,
+        };
+        let query = match result {
+            Some(result) => sqlx::query(
+                r#"UPDATE note_link_previews SET check_status = ?, http_status = ?, redirect_url = ?, checked_at = NOW()
+                WHERE note_id = ? AND url_hash = ?"#,
+            )
+            .bind(result.status)
+            .bind(result.http_status)
+            .bind(result.redirect_url),
+            // Inconclusive: keep the last result and try again next round.
+            None => sqlx::query(
+                "UPDATE note_link_previews SET checked_at = NOW() WHERE note_id = ? AND url_hash = ?",
+            ),
+        };
+        query.bind(note_id).bind(hash).execute(&data.db).await?;
+    }
+    Ok(due.len())
+}
+
+/// Checks due links in batches, then sleeps for a tenth of `interval`.
+pub async fn run(data: Arc<AppState>, options: Arc<LinkPreviewOptions>, interval: Duration) {
+    loop {
+        match check_due(&data, &options, interval).await {
+            Ok(checked) if checked == BATCH as usize => continue,
+            Ok(_) => {}
+            Err(err) => tracing::error!(error = ?err, "Failed to check links"),
+        }
+        tokio::time::sleep(interval / 10).await;
+    }
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct BrokenLink {
+    pub note_id: NoteId,
+    pub note_title: String,
+    pub url: String,
+    pub http_status: Option<u16>,
+    pub checked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct BrokenLinksOptions {
+    pub page: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+/// Broken links across all notes, most recently checked first.
+#[utoipa::path(
+    get,
+    path = "/api/admin/links/broken",
+    tag = "admin",
+    params(BrokenLinksOptions),
+    responses(
+        (status = 200, description = "Broken links across all notes"),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn broken_links_handler(
+    opts: Option<Query<BrokenLinksOptions>>,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let Query(opts) = opts.unwrap_or_default();
+    let limit = opts.limit.unwrap_or(50);
+    let offset = page_offset(opts.page, limit)?;
+    let now = data.clock.now();
+
+    let links = sqlx::query_as::<_, BrokenLink>(
+        r#"SELECT links.note_id, notes.title AS note_title, links.url, links.http_status, links.checked_at
+        FROM note_link_previews links JOIN notes ON notes.id = links.note_id
+        WHERE links.check_status = 'broken' AND notes.deleted_at IS NULL
+            AND (notes.expires_at IS NULL OR notes.expires_at > ?)
+        ORDER BY links.checked_at DESC LIMIT ? OFFSET ?"#,
+    )
+    .bind(now)
+    .bind(limit as u64)
+    .bind(offset as u64)
+    .fetch_all(&data.db)
+    .await?;
+    let total = sqlx::query_scalar::<_, i64>(
+        r#"SELECT COUNT(*) FROM note_link_previews links JOIN notes ON notes.id = links.note_id
+        WHERE links.check_status = 'broken' AND notes.deleted_at IS NULL
+            AND (notes.expires_at IS NULL OR notes.expires_at > ?)"#,
+    )
+    .bind(now)
+    .fetch_one(&data.db)
+    .await?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "total": total,
+        "results": links.len(),
+        "links": links,
+    })))
+}
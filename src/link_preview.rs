@@ -0,0 +1,541 @@
+//! Link previews for URLs in note content.
+//!
+//! When enabled (`LINK_PREVIEWS=true`), a background worker follows the change
+//! events: for every created or updated note it fetches the OpenGraph metadata
+//! of each linked page through the shared HTTP client and stores it in
+//! `note_link_previews`, served at `GET /api/notes/:id/links`. Since the
+//! server fetches user-supplied URLs, hosts that are IP literals or
+//! `localhost` are never fetched, and deployments can restrict the domains
+//! further with allow and deny lists.
+
+use std::{
+    collections::HashSet,
+    net::IpAddr,
+    str::FromStr,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use axum::{
+    extract::{Path, Query, State},
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    auth::NoteScope,
+    error::AppError,
+    events::{NoteEvent, NoteEventKind},
+    link_check::LinkStatus,
+    model::{text_enum, NoteId},
+    schema::LinkFilterOptions,
+    AppState,
+};
+
+/// Pages larger than this are cut off; the metadata lives in `<head>`.
+const MAX_PAGE_BYTES: usize = 256 * 1024;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct LinkPreviewOptions {
+    /// When non-empty, only these domains (and their subdomains) are fetched.
+    pub allow: Vec<String>,
+    /// Never fetched, even when allowed.
+    pub deny: Vec<String>,
+    /// Links beyond this many per note are ignored.
+    pub max_links: usize,
+}
+
+impl Default for LinkPreviewOptions {
+    fn default() -> Self {
+        Self {
+            allow: Vec::new(),
+            deny: Vec::new(),
+            max_links: 10,
+        }
+    }
+}
+
+fn matches_domain(host: &str, domain: &str) -> bool {
+    host == domain
+        || host
+            .strip_suffix(domain)
+            .is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+impl LinkPreviewOptions {
+    /// Whether the host of `url` may be fetched.
+    pub fn permits(&self, url: &reqwest::Url) -> bool {
+        let Some(host) = url.host_str() else {
+            return false;
+        };
+        let host = host.to_ascii_lowercase();
+        if host == "localhost"
+            || host.ends_with(".localhost")
+            || host
+                .trim_matches(|c| c == '[' || c == ']')
+                .parse::<IpAddr>()
+                .is_ok()
+        {
+            return false;
+        }
+        if self.deny.iter().any(|domain| matches_domain(&host, domain)) {
+            return false;
+        }
+        self.allow.is_empty()
+            || self
+                .allow
+                .iter()
+                .any(|domain| matches_domain(&host, domain))
+    }
+}
+
+/// Parses a comma-separated domain list, as used by the `LINK_PREVIEW_*`
+/// environment variables.
+pub fn parse_domains(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|domain| domain.trim().trim_start_matches('.').to_ascii_lowercase())
+        .filter(|domain| !domain.is_empty())
+        .collect()
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FetchStatus {
+    Ok,
+    /// The page could not be fetched or had no metadata.
+    #[default]
+    Failed,
+}
+
+impl FetchStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FetchStatus::Ok => "ok",
+            FetchStatus::Failed => "failed",
+        }
+    }
+}
+
+impl FromStr for FetchStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ok" => Ok(FetchStatus::Ok),
+            "failed" => Ok(FetchStatus::Failed),
+            other => Err(format!("Unknown fetch status '{}'", other)),
+        }
+    }
+}
+
+text_enum!(FetchStatus);
+
+#[derive(Debug, Default, Serialize, sqlx::FromRow)]
+pub struct LinkPreview {
+    pub url: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub image_url: Option<String>,
+    pub site_name: Option<String>,
+    pub status: FetchStatus,
+    pub fetched_at: DateTime<Utc>,
+    /// Result of the last link check, see `link_check`; `None` until checked.
+    pub check_status: Option<LinkStatus>,
+    pub http_status: Option<u16>,
+    pub redirect_url: Option<String>,
+    pub checked_at: Option<DateTime<Utc>>,
+}
+
+/// The distinct http(s) URLs in `content`, in order of appearance.
+pub fn extract_urls(content: &str, limit: usize) -> Vec<reqwest::Url> {
+    static URL: OnceLock<Regex> = OnceLock::new();
+    let pattern = URL.get_or_init(|| Regex::new(r#"https?://[^\s<>()\[\]{}"'`]+"#).unwrap());
+    let mut seen = HashSet::new();
+    pattern
+        .find_iter(content)
+        .map(|found| {
+            found
+                .as_str()
+                .trim_end_matches(['.', ',', ';', ':', '!', '?', '*', '_'])
+        })
+        .filter_map(|url| reqwest::Url::parse(url).ok())
+        .filter(|url| seen.insert(url.to_string()))
+        .take(limit)
+        .collect()
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+        .trim()
+        .to_string()
+}
+
+#[derive(Debug, Default)]
+pub struct PageMetadata {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub image_url: Option<String>,
+    pub site_name: Option<String>,
+}
+
+/// Reads the OpenGraph tags of `html`, falling back to `<title>` and the
+/// description meta tag.
+pub fn parse_metadata(html: &str) -> PageMetadata {
+    static META: OnceLock<Regex> = OnceLock::new();
+    static ATTRIBUTE: OnceLock<Regex> = OnceLock::new();
+    static TITLE: OnceLock<Regex> = OnceLock::new();
+    let meta = META.get_or_init(|| Regex::new(r"(?is)<meta\s[^>]*>").unwrap());
+    let attribute = ATTRIBUTE
+        .get_or_init(|| Regex::new(r#"(?is)([a-z:-]+)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap());
+    let title_tag = TITLE.get_or_init(|| Regex::new(r"(?is)<title[^>]*>(.*?)</title>").unwrap());
+
+    let mut page = PageMetadata::default();
+    let mut fallback_description = None;
+    for tag in meta.find_iter(html) {
+        let (mut key, mut content) = (None, None);
+        for attr in attribute.captures_iter(tag.as_str()) {
+            let value = attr.get(2).or_else(|| attr.get(3)).map(|m| m.as_str());
+            match attr[1].to_ascii_lowercase().as_str() {
+                "property" | "name" => key = value.map(str::to_ascii_lowercase),
+                "content" => content = value.map(decode_entities),
+                _ => {}
+            }
+        }
+        let (Some(key), Some(content)) = (key, content.filter(|c| !c.is_empty())) else {
+            continue;
+        };
+        let slot = match key.as_str() {
+            "og:title" => &mut page.title,
+            "og:description" => &mut page.description,
+            "og:image" => &mut page.image_url,
+            "og:site_name" => &mut page.site_name,
+            "description" => &mut fallback_description,
+            _ => continue,
+        };
+        slot.get_or_insert(content);
+    }
+    if page.title.is_none() {
+        page.title = title_tag
+            .captures(html)
+            .map(|captures| decode_entities(&captures[1]))
+            .filter(|title| !title.is_empty());
+    }
+    page.description = page.description.or(fallback_description);
+    page
+}
+
+async fn fetch(data: &AppState, url: &reqwest::Url) -> Option<LinkPreview> {
+    let request = data.http.get(url.clone()).timeout(FETCH_TIMEOUT);
+    let mut response = data.http.send("link-preview", request).await.ok()?;
+    let is_html = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains("html"));
+    if !response.status().is_success() || !is_html {
+        return None;
+    }
+    let mut body = Vec::new();
+    while let Ok(Some(chunk)) = response.chunk().await {
+        body.extend_from_slice(&chunk);
+        if body.len() >= MAX_PAGE_BYTES {
+            break;
+        }
+    }
+    let html = String::from_utf8_lossy(&body);
+    let page = parse_metadata(&html);
+    if page.title.is_none() && page.description.is_none() && page.image_url.is_none() {
+        return None;
+    }
+    Some(LinkPreview {
+        url: url.to_string(),
+        title: page.title,
+        description: page.description,
+        // Relative image paths are resolved against the page.
+        image_url: page
+            .image_url
+            .and_then(|image| url.join(&image).ok().map(String::from)),
+        site_name: page.site_name,
+        status: FetchStatus::Ok,
+        fetched_at: Utc::now(),
+        ..Default::default()
+    })
+}
+
+fn url_hash(url: &str) -> String {
+    hex::encode(Sha256::digest(url.as_bytes()))
+}
+
+/// Brings the stored previews of `id` in line with the links in `content`:
+/// new links are fetched, removed ones dropped, known ones kept as they are.
+pub async fn refresh(
+    data: &AppState,
+    options: &LinkPreviewOptions,
+    id: NoteId,
+    content: &str,
+) -> Result<(), sqlx::Error> {
+    let urls = extract_urls(content, options.max_links)
+        .into_iter()
+        .filter(|url| options.permits(url))
+        .collect::<Vec<_>>();
+    let hashes = urls
+        .iter()
+        .map(|url| url_hash(url.as_str()))
+        .collect::<Vec<_>>();
+
+    let stored = sqlx::query_scalar::<_, String>(
+        "SELECT url_hash FROM note_link_previews WHERE note_id = ?",
+    )
+    .bind(id)
+    .fetch_all(&data.db)
+    .await?;
+    for hash in stored.iter().filter(|hash| !hashes.contains(hash)) {
+        sqlx::query("DELETE FROM note_link_previews WHERE note_id = ? AND url_hash = ?")
+            .bind(id)
+            .bind(hash)
+            .execute(&data.db)
+            .await?;
+    }
+
+    for (url, hash) in urls.iter().zip(&hashes) {
+        if stored.contains(hash) {
+            continue;
+        }
+        let preview = fetch(data, url).await.unwrap_or_else(|| LinkPreview {
+            url: url.to_string(),
+            status: FetchStatus::Failed,
+            fetched_at: Utc::now(),
+            ..Default::default()
+        });
+        sqlx::query(
+            r#"INSERT INTO note_link_previews (note_id, url_hash, url, title, description, image_url, site_name, status, fetched_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+            ON DUPLICATE KEY UPDATE title = VALUES(title), description = VALUES(description),
+                image_url = VALUES(image_url), site_name = VALUES(site_name),
+                status = VALUES(status), fetched_at = VALUES(fetched_at)"#,
+        )
+        .bind(id)
+        .bind(hash)
+        .bind(&preview.url)
+        .bind(&preview.title)
+        .bind(&preview.description)
+        .bind(&preview.image_url)
+        .bind(&preview.site_name)
+        .bind(preview.status)
+        .bind(preview.fetched_at)
+        .execute(&data.db)
+        .await?;
+    }
+    Ok(())
+}
+
+async fn handle(
+    data: &AppState,
+    options: &LinkPreviewOptions,
+    event: &NoteEvent,
+) -> Result<(), sqlx::Error> {
+    if event.kind == NoteEventKind::Read {
+        return Ok(());
+    }
+    if event.kind == NoteEventKind::Deleted {
+        sqlx::query("DELETE FROM note_link_previews WHERE note_id = ?")
+            .bind(event.note_id)
+            .execute(&data.db)
+            .await?;
+        return Ok(());
+    }
+    match data.notes.find(event.note_id).await? {
+        Some(note) => refresh(data, options, note.id, &note.content).await,
+        None => Ok(()),
+    }
+}
+
+/// Refreshes link previews as notes change, one note at a time so slow sites
+/// delay previews rather than piling up fetches.
+pub fn spawn_worker(
+    data: Arc<AppState>,
+    options: Arc<LinkPreviewOptions>,
+    mut events: broadcast::Receiver<NoteEvent>,
+) {
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    if let Err(err) = handle(&data, &options, &event).await {
+                        tracing::error!(
+                            error = ?err,
+                            note_id = %event.note_id,
+                            "Failed to refresh link previews",
+                        );
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!(skipped, "Link previews skipped change events");
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/notes/{id}/links",
+    tag = "notes",
+    params(
+        ("id" = Uuid, Path, description = "Note ID"),
+        LinkFilterOptions,
+    ),
+    responses(
+        (status = 200, description = "Previews of the links in the note"),
+        (status = 400, description = "Unknown link status", body = ErrorResponse),
+        (status = 404, description = "Note not found", body = ErrorResponse),
+    ),
+)]
+pub async fn note_links_handler(
+    Path(id): Path<uuid::Uuid>,
+    opts: Option<Query<LinkFilterOptions>>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    scope.check(&data, NoteId::from(id)).await?;
+    let Query(opts) = opts.unwrap_or_default();
+    let status = opts
+        .status
+        .as_deref()
+        .map(str::parse::<LinkStatus>)
+        .transpose()
+        .map_err(AppError::Validation)?;
+    let note_id = NoteId::from(id);
+    if data.notes.find(note_id).await?.is_none() {
+        return Err(AppError::note_not_found(id));
+    }
+
+    let links = sqlx::query_as::<_, LinkPreview>(
+        r#"SELECT url, title, description, image_url, site_name, status, fetched_at,
+            check_status, http_status, redirect_url, checked_at
+        FROM note_link_previews WHERE note_id = ? AND (? IS NULL OR check_status = ?) ORDER BY url"#,
+    )
+    .bind(note_id)
+    .bind(status)
+    .bind(status)
+    .fetch_all(&data.db)
+    .await?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "results": links.len(),
+        "links": links,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use chrono::Duration;
+
+    use crate::{clock::Clock, testing::TestApp};
+
+    use super::*;
+
+    fn url(url: &str) -> reqwest::Url {
+        reqwest::Url::parse(url).unwrap()
+    }
+
+    #[test]
+    fn ip_literals_and_localhost_are_never_fetched() {
+        let options = LinkPreviewOptions::default();
+        for denied in [
+            "http://127.0.0.1/",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://10.0.0.1:8080/",
+            "http://[::1]/",
+            "http://localhost:3000/",
+            "http://api.localhost/",
+            "file:///etc/passwd",
+        ] {
+            assert!(!options.permits(&url(denied)), "{}", denied);
+        }
+        assert!(options.permits(&url("https://example.com/page")));
+    }
+
+    #[test]
+    fn allow_and_deny_lists_cover_subdomains() {
+        let options = LinkPreviewOptions {
+            allow: parse_domains("example.com, .docs.rs"),
+            deny: parse_domains("private.example.com"),
+            ..Default::default()
+        };
+        assert!(options.permits(&url("https://example.com/")));
+        assert!(options.permits(&url("https://blog.example.com/")));
+        assert!(options.permits(&url("https://docs.rs/axum")));
+        assert!(!options.permits(&url("https://notexample.com/")));
+        assert!(!options.permits(&url("https://private.example.com/")));
+        assert!(!options.permits(&url("https://a.private.example.com/")));
+    }
+
+    #[test]
+    fn extracts_distinct_urls_without_trailing_punctuation() {
+        let urls = extract_urls(
+            "See https://example.com/a, (https://example.com/b) and https://example.com/a.",
+            10,
+        );
+        let urls = urls.iter().map(reqwest::Url::as_str).collect::<Vec<_>>();
+        assert_eq!(urls, ["https://example.com/a", "https://example.com/b"]);
+        assert_eq!(extract_urls("http://a.com http://b.com", 1).len(), 1);
+    }
+
+    #[test]
+    fn reads_opengraph_tags_before_the_fallbacks() {
+        let page = parse_metadata(
+            r#"<html><head><title>Plain</title>
+            <meta name="description" content="Fallback">
+            <meta property="og:title" content="Tom &amp; Jerry">
+            <meta content='Site' property='og:site_name'>
+            </head></html>"#,
+        );
+        assert_eq!(page.title.as_deref(), Some("Tom & Jerry"));
+        assert_eq!(page.site_name.as_deref(), Some("Site"));
+        assert_eq!(page.description.as_deref(), Some("Fallback"));
+        assert_eq!(page.image_url, None);
+
+        let page = parse_metadata("<title> Only a title </title>");
+        assert_eq!(page.title.as_deref(), Some("Only a title"));
+    }
+
+    #[tokio::test]
+    async fn links_of_expired_notes_are_gone() {
+        let app = TestApp::new();
+        let expires_at = app.clock.now() + Duration::hours(1);
+        let (status, body) = app
+            .post(
+                "/api/notes",
+                json!({
+                    "title": "Brief",
+                    "content": "See https://example.com",
+                    "expires_at": expires_at,
+                }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        let uri = format!(
+            "/api/notes/{}/links",
+            body["data"]["note"]["id"].as_str().unwrap()
+        );
+
+        app.clock.advance(Duration::hours(2));
+        let (status, _) = app.get(&uri).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
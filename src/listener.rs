@@ -0,0 +1,89 @@
+use std::{io, net::SocketAddr, net::TcpListener};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// First file descriptor passed by systemd socket activation (SD_LISTEN_FDS_START).
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ListenerOptions {
+    pub addr: SocketAddr,
+    pub reuse_port: bool,
+    pub backlog: i32,
+}
+
+/// Returns the socket the server should accept on.
+///
+/// A socket handed over by systemd (`LISTEN_FDS`/`LISTEN_PID`) always wins, so
+/// a new release can take over a listener that never closes. Otherwise a fresh
+/// socket is bound, optionally with `SO_REUSEPORT` so several instances can
+/// share the port while one of them drains.
+pub fn bind(options: &ListenerOptions) -> io::Result<TcpListener> {
+    if let Some(listener) = from_socket_activation()? {
+        tracing::info!("Using listener passed by socket activation");
+        return Ok(listener);
+    }
+
+    let socket = Socket::new(
+        Domain::for_address(options.addr),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    socket.set_reuse_address(true)?;
+    if options.reuse_port {
+        set_reuse_port(&socket)?;
+    }
+    socket.bind(&options.addr.into())?;
+    socket.listen(options.backlog)?;
+    socket.set_nonblocking(true)?;
+
+    Ok(socket.into())
+}
+
+#[cfg(unix)]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(unix))]
+fn set_reuse_port(_socket: &Socket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is only supported on unix platforms",
+    ))
+}
+
+#[cfg(unix)]
+fn from_socket_activation() -> io::Result<Option<TcpListener>> {
+    use std::os::unix::io::FromRawFd;
+
+    let listen_pid = std::env::var("LISTEN_PID").ok();
+    let listen_fds = std::env::var("LISTEN_FDS").ok();
+    let (Some(pid), Some(fds)) = (listen_pid, listen_fds) else {
+        return Ok(None);
+    };
+
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return Ok(None);
+    }
+    if fds.parse::<i32>().unwrap_or(0) < 1 {
+        return Ok(None);
+    }
+
+    // Keep child processes from trying to adopt the same descriptors.
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    // SAFETY: systemd guarantees SD_LISTEN_FDS_START is an open, listening
+    // socket owned by this process when LISTEN_PID matches our pid.
+    let listener = unsafe { TcpListener::from_raw_fd(SD_LISTEN_FDS_START) };
+    listener.set_nonblocking(true)?;
+    Ok(Some(listener))
+}
+
+#[cfg(not(unix))]
+fn from_socket_activation() -> io::Result<Option<TcpListener>> {
+    Ok(None)
+}
@@ -0,0 +1,83 @@
+//! Synthetic endpoints mounted only when the server runs with `--load-test`.
+//!
+//! Comparing their latency separates framework overhead (`no-db`) from MySQL
+//! read and write latency when planning capacity.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde_json::json;
+
+use crate::{
+    compression::StoredContent,
+    error::AppError,
+    model::{BinaryId, NoteId, NoteModel, NoteStatus, UserId},
+    repository::{INSERT_NOTE, SELECT_NOTES_PAGE},
+    AppState,
+};
+
+pub fn create_router(app_state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/api/load-test/no-db", get(no_db_handler))
+        .route("/api/load-test/db-read", get(db_read_handler))
+        .route("/api/load-test/db-write", post(db_write_handler))
+        .with_state(app_state)
+}
+
+async fn no_db_handler() -> impl IntoResponse {
+    Json(json!({
+        "status": "success",
+        "message": "OK",
+    }))
+}
+
+async fn db_read_handler(State(data): State<Arc<AppState>>) -> Result<impl IntoResponse, AppError> {
+    let notes = sqlx::query_as::<_, NoteModel>(SELECT_NOTES_PAGE)
+        .bind(data.clock.now())
+        .bind(10)
+        .bind(0)
+        .fetch_all(&data.db)
+        .await?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "results": notes.len(),
+    })))
+}
+
+/// Runs the real insert statement inside a transaction that is always rolled
+/// back, so load tests exercise the write path without leaving rows behind.
+async fn db_write_handler(
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let id = NoteId::from(data.ids.generate());
+    let mut tx = data.db.begin().await?;
+
+    let query = sqlx::query(INSERT_NOTE)
+        .bind(id)
+        .bind(format!("load-test {}", id));
+    StoredContent::Plain("load-test")
+        .bind(query)
+        .bind("load-test")
+        .bind(None::<BinaryId>)
+        .bind(false)
+        .bind(None::<DateTime<Utc>>)
+        .bind(NoteStatus::Draft)
+        .bind(None::<DateTime<Utc>>)
+        .bind(data.clock.now())
+        .bind(data.clock.now())
+        .bind(None::<UserId>)
+        .execute(&mut tx)
+        .await?;
+
+    tx.rollback().await?;
+
+    Ok((StatusCode::OK, Json(json!({"status": "success"}))))
+}
@@ -0,0 +1,303 @@
+//! Dataloader-style batching for note relations.
+//!
+//! A [`Loader`] collects the note ids requested by concurrently running
+//! callers, resolves them with a single [`NoteRepository`] call per relation
+//! and caches the result for the rest of the request. REST `?expand=`
+//! handling passes a whole page of ids at once; per-field resolvers call
+//! [`Loader::load_one`] and are batched transparently as long as they are
+//! polled concurrently.
+
+use std::{
+    collections::HashMap,
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use sqlx::{MySql, QueryBuilder};
+
+use crate::{
+    clipper::NoteSource,
+    model::{NoteId, TagModel},
+    repository::NoteRepository,
+};
+
+/// A relation that can be fetched for many notes in one query.
+#[async_trait]
+pub trait Relation: Send + Sync + 'static {
+    const NAME: &'static str;
+    type Value: Clone + Send + Sync + Serialize;
+
+    /// Returns values for the ids that have one; missing ids have none.
+    async fn load(
+        notes: &dyn NoteRepository,
+        ids: &[NoteId],
+    ) -> Result<HashMap<NoteId, Self::Value>, sqlx::Error>;
+}
+
+struct LoaderState<V> {
+    cache: HashMap<NoteId, Option<V>>,
+    pending: Vec<NoteId>,
+}
+
+pub struct Loader<R: Relation> {
+    notes: Arc<dyn NoteRepository>,
+    state: Mutex<LoaderState<R::Value>>,
+    dispatch: tokio::sync::Mutex<()>,
+    relation: PhantomData<R>,
+}
+
+impl<R: Relation> Loader<R> {
+    pub fn new(notes: Arc<dyn NoteRepository>) -> Self {
+        Self {
+            notes,
+            state: Mutex::new(LoaderState {
+                cache: HashMap::new(),
+                pending: Vec::new(),
+            }),
+            dispatch: tokio::sync::Mutex::new(()),
+            relation: PhantomData,
+        }
+    }
+
+    pub async fn load_one(&self, id: NoteId) -> Result<Option<R::Value>, sqlx::Error> {
+        Ok(self.load_many(&[id]).await?.remove(&id))
+    }
+
+    pub async fn load_many(
+        &self,
+        ids: &[NoteId],
+    ) -> Result<HashMap<NoteId, R::Value>, sqlx::Error> {
+        if let Some(values) = self.enqueue(ids) {
+            return Ok(values);
+        }
+
+        // Give other callers polled alongside this one a chance to enqueue
+        // their ids before the batch is dispatched.
+        tokio::task::yield_now().await;
+
+        let _dispatch = self.dispatch.lock().await;
+        let keys = {
+            let mut state = self.state.lock().unwrap();
+            let mut keys = std::mem::take(&mut state.pending);
+            // A failed earlier dispatch may have dropped our ids from the queue.
+            keys.extend(ids.iter().filter(|id| !state.cache.contains_key(id)));
+            keys.sort();
+            keys.dedup();
+            keys
+        };
+
+        if !keys.is_empty() {
+            let mut loaded = R::load(self.notes.as_ref(), &keys).await?;
+            let mut state = self.state.lock().unwrap();
+            for key in keys {
+                let value = loaded.remove(&key);
+                state.cache.insert(key, value);
+            }
+        }
+
+        Ok(self.cached(ids))
+    }
+
+    /// Queues uncached ids, or returns the answer directly when every id is
+    /// already cached.
+    fn enqueue(&self, ids: &[NoteId]) -> Option<HashMap<NoteId, R::Value>> {
+        let mut state = self.state.lock().unwrap();
+        let missing = ids
+            .iter()
+            .filter(|id| !state.cache.contains_key(id))
+            .copied()
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            drop(state);
+            return Some(self.cached(ids));
+        }
+        state.pending.extend(missing);
+        None
+    }
+
+    fn cached(&self, ids: &[NoteId]) -> HashMap<NoteId, R::Value> {
+        let state = self.state.lock().unwrap();
+        ids.iter()
+            .filter_map(|id| {
+                state
+                    .cache
+                    .get(id)
+                    .cloned()
+                    .flatten()
+                    .map(|value| (*id, value))
+            })
+            .collect()
+    }
+}
+
+/// Builds `<prefix> (?, ?, ...)` for an `IN` list of note ids.
+pub fn in_list<'a>(prefix: &str, ids: &'a [NoteId]) -> QueryBuilder<'a, MySql> {
+    let mut query = QueryBuilder::new(prefix);
+    query.push(" (");
+    let mut separated = query.separated(", ");
+    for id in ids {
+        separated.push_bind(*id);
+    }
+    query.push(")");
+    query
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct TrendingRank {
+    pub position: i32,
+    pub view_count: i64,
+}
+
+/// The note's position in the materialized trending list, if any.
+pub struct Trending;
+
+#[async_trait]
+impl Relation for Trending {
+    const NAME: &'static str = "trending";
+    type Value = TrendingRank;
+
+    async fn load(
+        notes: &dyn NoteRepository,
+        ids: &[NoteId],
+    ) -> Result<HashMap<NoteId, Self::Value>, sqlx::Error> {
+        notes.trending(ids).await
+    }
+}
+
+/// Where a clipped note was clipped from, if it was.
+pub struct Source;
+
+#[async_trait]
+impl Relation for Source {
+    const NAME: &'static str = "source";
+    type Value = NoteSource;
+
+    async fn load(
+        notes: &dyn NoteRepository,
+        ids: &[NoteId],
+    ) -> Result<HashMap<NoteId, Self::Value>, sqlx::Error> {
+        notes.sources(ids).await
+    }
+}
+
+/// The tags of the note, by name.
+pub struct Tags;
+
+#[async_trait]
+impl Relation for Tags {
+    const NAME: &'static str = "tags";
+    type Value = Vec<TagModel>;
+
+    async fn load(
+        notes: &dyn NoteRepository,
+        ids: &[NoteId],
+    ) -> Result<HashMap<NoteId, Self::Value>, sqlx::Error> {
+        notes.tags(ids).await
+    }
+}
+
+/// Per-request set of loaders, so every relation is fetched at most once per
+/// batch of ids no matter how many callers ask for it.
+pub struct Loaders {
+    pub trending: Loader<Trending>,
+    pub source: Loader<Source>,
+    pub tags: Loader<Tags>,
+}
+
+impl Loaders {
+    pub fn new(notes: &Arc<dyn NoteRepository>) -> Self {
+        Self {
+            trending: Loader::new(notes.clone()),
+            source: Loader::new(notes.clone()),
+            tags: Loader::new(notes.clone()),
+        }
+    }
+}
+
+/// A relation clients can ask to embed with `?expand=`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expansion {
+    Trending,
+    Source,
+    Tags,
+}
+
+impl Expansion {
+    pub const SUPPORTED: &'static [&'static str] = &[Trending::NAME, Source::NAME, Tags::NAME];
+
+    /// Parses a comma-separated `expand` parameter, rejecting unknown names.
+    pub fn parse_list(expand: Option<&str>) -> Result<Vec<Expansion>, String> {
+        let mut expansions = Vec::new();
+        for name in expand
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            let expansion = match name {
+                Trending::NAME => Expansion::Trending,
+                Source::NAME => Expansion::Source,
+                Tags::NAME => Expansion::Tags,
+                other => {
+                    return Err(format!(
+                        "Unknown expansion '{}'. Supported: {}",
+                        other,
+                        Self::SUPPORTED.join(", ")
+                    ))
+                }
+            };
+            if !expansions.contains(&expansion) {
+                expansions.push(expansion);
+            }
+        }
+        Ok(expansions)
+    }
+}
+
+fn to_value<T: Serialize>(value: Option<T>) -> Value {
+    value.map_or(Value::Null, |value| {
+        serde_json::to_value(value).unwrap_or(Value::Null)
+    })
+}
+
+impl Loaders {
+    /// Resolves the requested expansions for `ids`, one query per relation,
+    /// and returns the fields to embed into each note.
+    pub async fn expand(
+        &self,
+        expansions: &[Expansion],
+        ids: &[NoteId],
+    ) -> Result<HashMap<NoteId, Map<String, Value>>, sqlx::Error> {
+        let mut fields: HashMap<NoteId, Map<String, Value>> =
+            ids.iter().map(|id| (*id, Map::new())).collect();
+
+        for expansion in expansions {
+            match expansion {
+                Expansion::Trending => {
+                    let mut loaded = self.trending.load_many(ids).await?;
+                    for (id, map) in fields.iter_mut() {
+                        map.insert(Trending::NAME.to_string(), to_value(loaded.remove(id)));
+                    }
+                }
+                Expansion::Source => {
+                    let mut loaded = self.source.load_many(ids).await?;
+                    for (id, map) in fields.iter_mut() {
+                        map.insert(Source::NAME.to_string(), to_value(loaded.remove(id)));
+                    }
+                }
+                Expansion::Tags => {
+                    let mut loaded = self.tags.load_many(ids).await?;
+                    for (id, map) in fields.iter_mut() {
+                        let tags = loaded.remove(id).unwrap_or_default();
+                        map.insert(Tags::NAME.to_string(), to_value(Some(tags)));
+                    }
+                }
+            }
+        }
+
+        Ok(fields)
+    }
+}
@@ -0,0 +1,399 @@
+//! Lease-based distributed locks for scheduled jobs.
+//!
+//! A lock is a row in `job_locks` owned by one instance until its lease
+//! expires. Holders renew by acquiring again before the lease runs out;
+//! when a holder dies its lease lapses and another instance takes over.
+//! Lease times come from the database clock so replicas with skewed clocks
+//! still agree on expiry. [`MySqlLeaseStore`] keeps the leases;
+//! [`InMemoryLeaseStore`] keeps them in a map, on a [`Clock`], so elections
+//! can be exercised without a database.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use axum::{extract::State, response::IntoResponse, Json};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::mysql::MySqlPool;
+
+use crate::{clock::Clock, error::AppError, AppState};
+
+#[derive(Debug, Default)]
+pub struct LockStats {
+    pub acquired: AtomicU64,
+    pub renewed: AtomicU64,
+    pub taken_over: AtomicU64,
+    pub contended: AtomicU64,
+    pub errors: AtomicU64,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct LockRow {
+    pub name: String,
+    pub owner: String,
+    pub acquired_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Where leases are kept.
+#[async_trait]
+pub trait LeaseStore: Send + Sync {
+    /// Takes `name` for `owner` if it is free or its lease has expired, or
+    /// renews it if `owner` holds it. Returns the previous owner when the
+    /// lease is now `owner`'s, `None` when it is held by someone else.
+    async fn acquire(
+        &self,
+        name: &str,
+        owner: &str,
+        lease: Duration,
+    ) -> Result<Option<Option<String>>, sqlx::Error>;
+
+    /// Drops `name` if `owner` holds it.
+    async fn release(&self, name: &str, owner: &str) -> Result<(), sqlx::Error>;
+}
+
+/// Leases in `job_locks`, timed by the database clock.
+pub struct MySqlLeaseStore {
+    db: MySqlPool,
+}
+
+impl MySqlLeaseStore {
+    pub fn new(db: MySqlPool) -> Self {
+        Self { db }
+    }
+}
+
+#[async_trait]
+impl LeaseStore for MySqlLeaseStore {
+    async fn acquire(
+        &self,
+        name: &str,
+        owner: &str,
+        lease: Duration,
+    ) -> Result<Option<Option<String>>, sqlx::Error> {
+        let mut tx = self.db.begin().await?;
+        let previous: Option<String> =
+            sqlx::query_scalar("SELECT owner FROM job_locks WHERE name = ? FOR UPDATE")
+                .bind(name)
+                .fetch_optional(&mut tx)
+                .await?;
+
+        // Assignments are evaluated left to right, so `expires_at` is only
+        // extended once `owner` has become (or already was) us.
+        sqlx::query(
+            r#"INSERT INTO job_locks (name, owner, acquired_at, expires_at)
+            VALUES (?, ?, NOW(3), NOW(3) + INTERVAL ? MICROSECOND)
+            ON DUPLICATE KEY UPDATE
+                acquired_at = IF(owner <> VALUES(owner) AND expires_at <= NOW(3), NOW(3), acquired_at),
+                owner = IF(expires_at <= NOW(3), VALUES(owner), owner),
+                expires_at = IF(owner = VALUES(owner), VALUES(expires_at), expires_at)"#,
+        )
+        .bind(name)
+        .bind(owner)
+        .bind(lease.as_micros() as u64)
+        .execute(&mut tx)
+        .await?;
+
+        let holder: String = sqlx::query_scalar("SELECT owner FROM job_locks WHERE name = ?")
+            .bind(name)
+            .fetch_one(&mut tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok((holder == owner).then_some(previous))
+    }
+
+    async fn release(&self, name: &str, owner: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM job_locks WHERE name = ? AND owner = ?")
+            .bind(name)
+            .bind(owner)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Leases in a map, for tests, timed by `clock` instead of the database.
+pub struct InMemoryLeaseStore {
+    clock: Arc<dyn Clock>,
+    leases: Mutex<HashMap<String, (String, DateTime<Utc>)>>,
+}
+
+impl InMemoryLeaseStore {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock,
+            leases: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl LeaseStore for InMemoryLeaseStore {
+    async fn acquire(
+        &self,
+        name: &str,
+        owner: &str,
+        lease: Duration,
+    ) -> Result<Option<Option<String>>, sqlx::Error> {
+        let now = self.clock.now();
+        let expires_at = chrono::Duration::from_std(lease)
+            .ok()
+            .and_then(|lease| now.checked_add_signed(lease))
+            .unwrap_or(DateTime::<Utc>::MAX_UTC);
+        let mut leases = self.leases.lock().unwrap();
+        let previous = leases.get(name).cloned();
+        match previous {
+            Some((holder, held_until)) if holder != owner && held_until > now => Ok(None),
+            previous => {
+                leases.insert(name.to_string(), (owner.to_string(), expires_at));
+                Ok(Some(previous.map(|(holder, _)| holder)))
+            }
+        }
+    }
+
+    async fn release(&self, name: &str, owner: &str) -> Result<(), sqlx::Error> {
+        let mut leases = self.leases.lock().unwrap();
+        if leases.get(name).is_some_and(|(holder, _)| holder == owner) {
+            leases.remove(name);
+        }
+        Ok(())
+    }
+}
+
+pub struct DistributedLock {
+    store: Arc<dyn LeaseStore>,
+    owner: String,
+    stats: Mutex<HashMap<String, Arc<LockStats>>>,
+}
+
+impl DistributedLock {
+    pub fn new(db: MySqlPool, owner: String) -> Self {
+        Self::with_store(Arc::new(MySqlLeaseStore::new(db)), owner)
+    }
+
+    /// Keeps leases in `store` instead of `job_locks`.
+    pub fn with_store(store: Arc<dyn LeaseStore>, owner: String) -> Self {
+        Self {
+            store,
+            owner,
+            stats: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+
+    fn stats(&self, name: &str) -> Arc<LockStats> {
+        self.stats
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Acquires or renews `name` for `lease`. Returns false while another
+    /// instance holds an unexpired lease.
+    pub async fn try_acquire(&self, name: &str, lease: Duration) -> Result<bool, sqlx::Error> {
+        let stats = self.stats(name);
+        let result = self.store.acquire(name, &self.owner, lease).await;
+        match &result {
+            Ok(Some(previous)) if previous.as_deref() == Some(self.owner.as_str()) => {
+                stats.renewed.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(Some(None)) => {
+                stats.acquired.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(Some(Some(previous))) => {
+                tracing::warn!(lock = name, previous_owner = %previous, "Took over expired lock");
+                stats.acquired.fetch_add(1, Ordering::Relaxed);
+                stats.taken_over.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(None) => {
+                stats.contended.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(_) => {
+                stats.errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        result.map(|outcome| outcome.is_some())
+    }
+
+    /// Gives up `name` early, if we hold it.
+    pub async fn release(&self, name: &str) -> Result<(), sqlx::Error> {
+        self.store.release(name, &self.owner).await
+    }
+
+    pub fn snapshot(&self) -> Value {
+        let stats = self.stats.lock().unwrap();
+        let report = stats
+            .iter()
+            .map(|(name, stats)| {
+                json!({
+                    "name": name,
+                    "acquired": stats.acquired.load(Ordering::Relaxed),
+                    "renewed": stats.renewed.load(Ordering::Relaxed),
+                    "taken_over": stats.taken_over.load(Ordering::Relaxed),
+                    "contended": stats.contended.load(Ordering::Relaxed),
+                    "errors": stats.errors.load(Ordering::Relaxed),
+                })
+            })
+            .collect::<Vec<_>>();
+        Value::Array(report)
+    }
+}
+
+/// Identifies this instance as a lock owner: `INSTANCE_ID`, or the host name
+/// plus a random suffix so restarts do not inherit their predecessor's leases.
+pub fn instance_id() -> String {
+    if let Ok(id) = std::env::var("INSTANCE_ID") {
+        return id;
+    }
+    let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "instance".to_string());
+    format!(
+        "{}-{}",
+        host,
+        &uuid::Uuid::new_v4().simple().to_string()[..8]
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/locks",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Held distributed locks"),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn locks_handler(
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let locks = sqlx::query_as::<_, LockRow>("SELECT * FROM job_locks ORDER BY name")
+        .fetch_all(&data.db)
+        .await?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "instance": data.locks.owner(),
+        "locks": locks,
+        "stats": data.locks.snapshot(),
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use crate::{clock::FixedClock, testing::unreachable_pool};
+
+    use super::*;
+
+    const LEASE: Duration = Duration::from_secs(30);
+
+    fn clock() -> Arc<FixedClock> {
+        Arc::new(FixedClock::new(
+            Utc.with_ymd_and_hms(2023, 5, 3, 12, 0, 0).unwrap(),
+        ))
+    }
+
+    fn instances(clock: &Arc<FixedClock>) -> (DistributedLock, DistributedLock) {
+        let store = Arc::new(InMemoryLeaseStore::new(clock.clone()));
+        (
+            DistributedLock::with_store(store.clone(), "a".to_string()),
+            DistributedLock::with_store(store, "b".to_string()),
+        )
+    }
+
+    fn stats(lock: &DistributedLock, name: &str) -> Value {
+        lock.snapshot()
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|stats| stats["name"] == name)
+            .cloned()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn one_instance_holds_a_lease_until_it_lets_go() {
+        let clock = clock();
+        let (a, b) = instances(&clock);
+
+        assert!(a.try_acquire("digest", LEASE).await.unwrap());
+        assert!(!b.try_acquire("digest", LEASE).await.unwrap());
+        // Renewing keeps the lease.
+        clock.advance(chrono::Duration::seconds(20));
+        assert!(a.try_acquire("digest", LEASE).await.unwrap());
+        clock.advance(chrono::Duration::seconds(20));
+        assert!(!b.try_acquire("digest", LEASE).await.unwrap());
+        // Other names are not held.
+        assert!(b.try_acquire("expiry", LEASE).await.unwrap());
+
+        // Only the holder can release.
+        b.release("digest").await.unwrap();
+        assert!(!b.try_acquire("digest", LEASE).await.unwrap());
+        a.release("digest").await.unwrap();
+        assert!(b.try_acquire("digest", LEASE).await.unwrap());
+
+        let a = stats(&a, "digest");
+        assert_eq!(
+            (a["acquired"].as_u64(), a["renewed"].as_u64()),
+            (Some(1), Some(1))
+        );
+        let b = stats(&b, "digest");
+        assert_eq!(
+            (b["acquired"].as_u64(), b["contended"].as_u64()),
+            (Some(1), Some(3))
+        );
+        assert_eq!(b["taken_over"], 0);
+    }
+
+    #[tokio::test]
+    async fn expired_leases_are_taken_over() {
+        let clock = clock();
+        let (a, b) = instances(&clock);
+
+        assert!(a.try_acquire("digest", LEASE).await.unwrap());
+        clock.advance(chrono::Duration::seconds(29));
+        assert!(!b.try_acquire("digest", LEASE).await.unwrap());
+        clock.advance(chrono::Duration::seconds(1));
+        assert!(b.try_acquire("digest", LEASE).await.unwrap());
+        assert_eq!(stats(&b, "digest")["taken_over"], 1);
+
+        // The old holder has lost it until the new lease runs out in turn.
+        assert!(!a.try_acquire("digest", LEASE).await.unwrap());
+        clock.advance(chrono::Duration::seconds(30));
+        assert!(a.try_acquire("digest", LEASE).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn a_lapsed_lease_nobody_took_is_renewed() {
+        let clock = clock();
+        let (a, _) = instances(&clock);
+
+        assert!(a.try_acquire("digest", LEASE).await.unwrap());
+        clock.advance(chrono::Duration::minutes(5));
+        assert!(a.try_acquire("digest", LEASE).await.unwrap());
+        let stats = stats(&a, "digest");
+        assert_eq!(stats["renewed"], 1);
+        assert_eq!(stats["taken_over"], 0);
+    }
+
+    #[tokio::test]
+    async fn database_errors_are_counted() {
+        let lock = DistributedLock::new(unreachable_pool(), "a".to_string());
+        assert!(lock.try_acquire("digest", LEASE).await.is_err());
+        assert_eq!(stats(&lock, "digest")["errors"], 1);
+    }
+}
@@ -0,0 +1,377 @@
+//! Credential-stuffing defenses for admin tokens and service-account keys.
+//!
+//! Failed authentications are counted per client address and per service
+//! account; the admin token is shared, so its failures only count against
+//! the address. After a few failures every further attempt is delayed
+//! progressively (and must carry a CAPTCHA token when a verifier is
+//! configured); after more, the address or account is locked out for a
+//! while. Counters reset on success or once no failure has been seen for
+//! `reset_after`.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use axum::{
+    http::{header, Request, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::{json, Value};
+
+use crate::{
+    alerts::{request_context, Alert},
+    client_ip::client_ip,
+    http_client::HttpClient,
+    AppState,
+};
+
+pub const CAPTCHA_TOKEN_HEADER: &str = "x-captcha-token";
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum LockoutKey {
+    Ip(IpAddr),
+    Account(String),
+}
+
+impl LockoutKey {
+    fn describe(&self) -> Value {
+        match self {
+            LockoutKey::Ip(ip) => json!({ "client_ip": ip }),
+            LockoutKey::Account(account) => json!({ "account": account }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LockoutOptions {
+    /// Failures after which attempts are delayed and challenged.
+    pub delay_after: u32,
+    /// Failures after which the key is locked out.
+    pub lockout_after: u32,
+    pub lockout_for: Duration,
+    pub max_delay: Duration,
+    pub reset_after: Duration,
+}
+
+impl Default for LockoutOptions {
+    fn default() -> Self {
+        Self {
+            delay_after: 3,
+            lockout_after: 10,
+            lockout_for: Duration::from_secs(15 * 60),
+            max_delay: Duration::from_secs(8),
+            reset_after: Duration::from_secs(15 * 60),
+        }
+    }
+}
+
+/// Verifies a CAPTCHA response token for a client.
+#[async_trait]
+pub trait ChallengeVerifier: Send + Sync {
+    async fn verify(&self, token: &str, client_ip: Option<IpAddr>) -> bool;
+}
+
+/// Verifier for the `siteverify` API shared by reCAPTCHA, hCaptcha and
+/// Turnstile.
+pub struct SiteVerifyChallenge {
+    http: Arc<HttpClient>,
+    url: String,
+    secret: String,
+}
+
+impl SiteVerifyChallenge {
+    pub fn new(http: Arc<HttpClient>, url: String, secret: String) -> Self {
+        Self { http, url, secret }
+    }
+}
+
+#[async_trait]
+impl ChallengeVerifier for SiteVerifyChallenge {
+    async fn verify(&self, token: &str, client_ip: Option<IpAddr>) -> bool {
+        let mut form = vec![
+            ("secret", self.secret.clone()),
+            ("response", token.to_string()),
+        ];
+        if let Some(ip) = client_ip {
+            form.push(("remoteip", ip.to_string()));
+        }
+        // Response tokens are single-use, so a retry would always fail.
+        let request = self.http.post(&self.url).form(&form);
+        let response = match self.http.send_once("captcha", request).await {
+            Ok(response) => response,
+            Err(err) => {
+                tracing::error!(error = %err, "CAPTCHA verification failed");
+                return false;
+            }
+        };
+        response
+            .json::<Value>()
+            .await
+            .is_ok_and(|body| body["success"].as_bool() == Some(true))
+    }
+}
+
+struct Attempts {
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+pub enum Admission {
+    Allowed { delay: Duration, challenge: bool },
+    Locked(Duration),
+}
+
+pub struct LoginGuard {
+    options: LockoutOptions,
+    attempts: Mutex<HashMap<LockoutKey, Attempts>>,
+    challenge: Option<Box<dyn ChallengeVerifier>>,
+}
+
+impl LoginGuard {
+    pub fn new(options: LockoutOptions, challenge: Option<Box<dyn ChallengeVerifier>>) -> Self {
+        Self {
+            options,
+            attempts: Mutex::new(HashMap::new()),
+            challenge,
+        }
+    }
+
+    pub fn check(&self, keys: &[LockoutKey]) -> Admission {
+        let now = Instant::now();
+        let attempts = self.attempts.lock().unwrap();
+        let mut delay = Duration::ZERO;
+        let mut challenge = false;
+        for attempt in keys.iter().filter_map(|key| attempts.get(key)) {
+            if let Some(until) = attempt.locked_until.filter(|until| *until > now) {
+                return Admission::Locked(until - now);
+            }
+            if now.duration_since(attempt.last_failure) > self.options.reset_after {
+                continue;
+            }
+            if attempt.failures >= self.options.delay_after {
+                let exponent = (attempt.failures - self.options.delay_after).min(16);
+                delay = delay.max(Duration::from_secs(1 << exponent).min(self.options.max_delay));
+                challenge |= self.challenge.is_some();
+            }
+        }
+        Admission::Allowed { delay, challenge }
+    }
+
+    /// Counts a failure for every key and returns the keys that just became
+    /// locked out.
+    pub fn record_failure(&self, keys: &[LockoutKey]) -> Vec<LockoutKey> {
+        let now = Instant::now();
+        let mut attempts = self.attempts.lock().unwrap();
+        let reset_after = self.options.reset_after;
+        attempts.retain(|_, attempt| {
+            now.duration_since(attempt.last_failure) <= reset_after
+                || attempt.locked_until.is_some_and(|until| until > now)
+        });
+
+        let mut locked = Vec::new();
+        for key in keys {
+            let attempt = attempts.entry(key.clone()).or_insert(Attempts {
+                failures: 0,
+                last_failure: now,
+                locked_until: None,
+            });
+            if attempt.locked_until.is_some_and(|until| until <= now) {
+                attempt.failures = 0;
+                attempt.locked_until = None;
+            }
+            attempt.failures += 1;
+            attempt.last_failure = now;
+            if attempt.failures >= self.options.lockout_after && attempt.locked_until.is_none() {
+                attempt.locked_until = Some(now + self.options.lockout_for);
+                locked.push(key.clone());
+            }
+        }
+        locked
+    }
+
+    pub fn record_success(&self, keys: &[LockoutKey]) {
+        let mut attempts = self.attempts.lock().unwrap();
+        for key in keys {
+            attempts.remove(key);
+        }
+    }
+}
+
+/// Keys an authentication attempt counts against: the client address plus
+/// the account, when one is known.
+pub fn lockout_keys<B>(
+    data: &AppState,
+    req: &Request<B>,
+    account: Option<&str>,
+) -> Vec<LockoutKey> {
+    client_ip(req, data.settings.trust_forwarded_for)
+        .map(LockoutKey::Ip)
+        .into_iter()
+        .chain(account.map(|account| LockoutKey::Account(account.to_string())))
+        .collect()
+}
+
+/// Runs before credentials are checked: refuses locked-out keys, demands a
+/// CAPTCHA when one is due and applies the progressive delay.
+pub async fn admit<B>(
+    data: &AppState,
+    req: &Request<B>,
+    keys: &[LockoutKey],
+) -> Result<(), Response> {
+    let (delay, challenge) = match data.logins.check(keys) {
+        Admission::Locked(remaining) => {
+            let error_response = json!({
+                "status": "fail",
+                "message": "Too many failed attempts, try again later",
+            });
+            return Err((
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, remaining.as_secs().max(1).to_string())],
+                Json(error_response),
+            )
+                .into_response());
+        }
+        Admission::Allowed { delay, challenge } => (delay, challenge),
+    };
+
+    if let (true, Some(verifier)) = (challenge, data.logins.challenge.as_deref()) {
+        let token = req
+            .headers()
+            .get(CAPTCHA_TOKEN_HEADER)
+            .and_then(|value| value.to_str().ok());
+        let passed = match token {
+            Some(token) => {
+                verifier
+                    .verify(token, client_ip(req, data.settings.trust_forwarded_for))
+                    .await
+            }
+            None => false,
+        };
+        if !passed {
+            let error_response = json!({
+                "status": "fail",
+                "message": "CAPTCHA challenge required",
+                "challenge_header": CAPTCHA_TOKEN_HEADER,
+            });
+            return Err((StatusCode::UNAUTHORIZED, Json(error_response)).into_response());
+        }
+    }
+
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+    Ok(())
+}
+
+/// Records a failed attempt and raises a security alert for new lockouts.
+pub fn record_failure<B>(data: &AppState, req: &Request<B>, keys: &[LockoutKey]) {
+    for key in data.logins.record_failure(keys) {
+        data.alerts.send(Alert {
+            kind: "lockout",
+            message: "Locked out after repeated authentication failures".to_string(),
+            context: json!({
+                "key": key.describe(),
+                "request": request_context(req, data.settings.trust_forwarded_for),
+            }),
+            at: data.clock.now(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, thread::sleep};
+
+    use super::*;
+
+    fn guard(options: LockoutOptions) -> LoginGuard {
+        LoginGuard::new(options, None)
+    }
+
+    fn ip(n: u8) -> LockoutKey {
+        LockoutKey::Ip(IpAddr::V4(Ipv4Addr::new(10, 0, 0, n)))
+    }
+
+    fn delay(guard: &LoginGuard, keys: &[LockoutKey]) -> Duration {
+        match guard.check(keys) {
+            Admission::Allowed { delay, .. } => delay,
+            Admission::Locked(_) => panic!("locked out"),
+        }
+    }
+
+    #[test]
+    fn delays_grow_with_failures_up_to_the_maximum() {
+        let guard = guard(LockoutOptions {
+            delay_after: 2,
+            lockout_after: 100,
+            max_delay: Duration::from_secs(4),
+            ..Default::default()
+        });
+        let keys = [ip(1)];
+        let mut delays = Vec::new();
+        for _ in 0..6 {
+            delays.push(delay(&guard, &keys).as_secs());
+            guard.record_failure(&keys);
+        }
+        assert_eq!(delays, [0, 0, 1, 2, 4, 4]);
+        assert_eq!(delay(&guard, &[ip(2)]), Duration::ZERO);
+    }
+
+    #[test]
+    fn locks_out_until_the_lockout_expires() {
+        let guard = guard(LockoutOptions {
+            lockout_after: 3,
+            lockout_for: Duration::from_millis(50),
+            ..Default::default()
+        });
+        let keys = [ip(1), LockoutKey::Account("ci".to_string())];
+        assert!(guard.record_failure(&keys).is_empty());
+        assert!(guard.record_failure(&keys).is_empty());
+        assert_eq!(guard.record_failure(&keys), keys);
+        assert!(matches!(guard.check(&keys), Admission::Locked(_)));
+        // Either key alone is enough to be turned away.
+        assert!(matches!(guard.check(&[ip(1)]), Admission::Locked(_)));
+        assert!(matches!(guard.check(&[ip(2)]), Admission::Allowed { .. }));
+
+        sleep(Duration::from_millis(60));
+        assert!(matches!(guard.check(&keys), Admission::Allowed { .. }));
+        // The count starts over once the lockout has run out.
+        assert!(guard.record_failure(&keys).is_empty());
+    }
+
+    #[test]
+    fn failures_are_forgotten_after_a_quiet_period() {
+        let guard = guard(LockoutOptions {
+            delay_after: 1,
+            reset_after: Duration::from_millis(50),
+            ..Default::default()
+        });
+        let keys = [ip(1)];
+        guard.record_failure(&keys);
+        assert_eq!(delay(&guard, &keys), Duration::from_secs(1));
+
+        sleep(Duration::from_millis(60));
+        assert_eq!(delay(&guard, &keys), Duration::ZERO);
+    }
+
+    #[test]
+    fn success_resets_the_count() {
+        let guard = guard(LockoutOptions {
+            delay_after: 1,
+            ..Default::default()
+        });
+        let keys = [ip(1)];
+        guard.record_failure(&keys);
+        guard.record_failure(&keys);
+        assert_eq!(delay(&guard, &keys), Duration::from_secs(2));
+
+        guard.record_success(&keys);
+        assert_eq!(delay(&guard, &keys), Duration::ZERO);
+    }
+}
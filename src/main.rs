@@ -1,56 +1,435 @@
-mod handler;
-mod model;
-mod route;
-mod schema;
-
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
 use axum::http::{
-    header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE},
-    HeaderValue, Method,
+    header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE, ETAG, IF_MATCH, IF_NONE_MATCH},
+    HeaderName, Method,
 };
 use dotenv::dotenv;
-use route::create_router;
+use rust_axum_mysql::{
+    alerts::Alerts,
+    anomaly::{self, AnomalyDetector},
+    archive::ZipExportJob,
+    attachment::{self, AttachmentStorage, LocalDiskStorage},
+    auth::JwtAuth,
+    canary::{self, Canaries},
+    chaos::Chaos,
+    clipper,
+    clock::{Clock, FixedClock, SystemClock},
+    collab::CollabHub,
+    compression,
+    config::Config,
+    consent,
+    dead_letter::{DeadLetterStore, MySqlDeadLetterStore},
+    error, expiry,
+    export::NotesExportJob,
+    hooks::{self, Hooks},
+    http_client::HttpClient,
+    id::{self, IdGenerator, SequentialIdGenerator},
+    job::{self, Jobs},
+    leader::{self, Leadership},
+    link_check, link_preview,
+    listener::{self, ListenerOptions},
+    load_test,
+    lock::{self, DistributedLock},
+    lockout::{ChallengeVerifier, LockoutOptions, LoginGuard, SiteVerifyChallenge},
+    moderation::{self, ExternalModerator, Moderation},
+    negative_cache::NegativeCache,
+    note_cache::{self, NoteCache},
+    ocr::{self, OcrJob},
+    offload::{self, ContentOffload},
+    plugin::Plugins,
+    rate_limit::RateLimiter,
+    read_receipt,
+    repository::MySqlNoteRepository,
+    request_id, revision,
+    route::create_router,
+    scripting::{self, ScriptHooks},
+    secrets,
+    shutdown::Shutdown,
+    signing_key,
+    single_flight::ReadCoalescing,
+    state::{Cache, EventBus, Settings},
+    summary, tag, telemetry,
+    transcription::{ExternalTranscription, TranscriptionJob},
+    warmup::{self, WarmupStats},
+    write_buffer::WriteBuffer,
+    AppState,
+};
 use tower_http::cors::CorsLayer;
 
-use sqlx::mysql::{MySqlPool, MySqlPoolOptions};
+use sqlx::mysql::MySqlPoolOptions;
 
-pub struct AppState {
-    db: MySqlPool,
-}
+/// Deployment-specific note hooks (validation, enrichment, notifications) are
+/// registered here; see `hooks::NoteHook`.
+fn register_hooks(_hooks: &mut Hooks) {}
+
+/// Optional subsystems are registered here, each behind its cargo feature;
+/// see `plugin::Plugin`.
+fn register_plugins(_plugins: &mut Plugins) {}
 
 #[tokio::main]
 async fn main() {
     dotenv().ok();
+    request_id::init_tracing();
+
+    let load_test_mode = std::env::args().any(|arg| arg == "--load-test");
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            tracing::error!(error = %err, "Invalid configuration");
+            std::process::exit(1);
+        }
+    };
+
+    // Test mode: FROZEN_TIME pins the clock and DETERMINISTIC_IDS makes ids sequential.
+    let clock: Arc<dyn Clock> = match config.frozen_time {
+        Some(now) => {
+            tracing::warn!(%now, "Clock frozen");
+            Arc::new(FixedClock::new(now.into()))
+        }
+        None => Arc::new(SystemClock),
+    };
+    let ids: Arc<dyn IdGenerator> = if config.deterministic_ids {
+        tracing::warn!("Generating sequential ids");
+        Arc::new(SequentialIdGenerator::default())
+    } else {
+        id::generator(
+            config.id_strategy,
+            clock.clone(),
+            config.snowflake_worker_id,
+        )
+    };
+
+    let warmup_options = config.warmup;
+    let warmup_stats = Arc::new(WarmupStats::default());
+
+    let http = Arc::new(HttpClient::new(config.http_client));
 
-    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let pool = match MySqlPoolOptions::new()
-        .max_connections(10)
-        .connect(&database_url)
+    let secrets = match secrets::from_env(http.clone()) {
+        Ok(secrets) => Arc::new(secrets),
+        Err(err) => {
+            tracing::error!(error = %err, "Invalid secrets configuration");
+            std::process::exit(1);
+        }
+    };
+    let mut connect_options = config.database;
+    // The password can live in the secrets backend instead of DATABASE_URL.
+    if let Some(name) = config.database_password_secret {
+        match secrets.get(&name).await {
+            Ok(password) => connect_options = connect_options.password(&password),
+            Err(err) => {
+                tracing::error!(error = %err, "Failed to read the database password");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let pool_options = MySqlPoolOptions::new().max_connections(config.db_max_connections);
+    let pool = match warmup::configure(pool_options, &warmup_options, warmup_stats.clone())
+        .connect_with(connect_options)
         .await
     {
         Ok(pool) => {
-            println!("✅Connection to the database is successful!");
+            tracing::info!("Connection to the database is successful!");
             pool
         }
         Err(err) => {
-            println!("🔥 Failed to connect to the database: {:?}", err);
+            tracing::error!(error = ?err, "Failed to connect to the database");
             std::process::exit(1);
         }
     };
 
+    // The migrations in `migrations/` are embedded at build time. Skip them
+    // when the schema is managed outside the app, e.g. with `sqlx migrate run`.
+    if std::env::args().any(|arg| arg == "--skip-migrations") {
+        tracing::warn!("Skipping database migrations");
+    } else if let Err(err) = sqlx::migrate!().run(&pool).await {
+        tracing::error!(error = ?err, "Failed to run database migrations");
+        std::process::exit(1);
+    }
+
+    let warmup = warmup::run(&pool, &warmup_options, &warmup_stats).await;
+
+    let write_buffer = WriteBuffer::spawn(pool.clone(), config.write_buffer);
+    let pending_writes = write_buffer.clone();
+
+    let summary_refresh_interval = config.summary_refresh_interval;
+    let locks = Arc::new(DistributedLock::new(pool.clone(), lock::instance_id()));
+    tracing::info!(instance = locks.owner(), "Instance started");
+    summary::spawn_refresher(
+        pool.clone(),
+        clock.clone(),
+        locks.clone(),
+        summary_refresh_interval,
+    );
+
+    let leadership = Arc::new(Leadership::new(
+        locks.clone(),
+        clock.clone(),
+        config.leader_lease,
+    ));
+    leadership.spawn_campaign();
+
+    let mut plugins = Plugins::default();
+    register_plugins(&mut plugins);
+    if let Err(err) = plugins.migrate(&pool, &locks).await {
+        tracing::error!(error = ?err, "Failed to migrate plugins");
+        std::process::exit(1);
+    }
+
+    let backfill_pool = pool.clone();
+    leader::spawn_singleton(&leadership, "content-compression-backfill", move || {
+        compression::run_backfill(backfill_pool.clone())
+    });
+
+    let dead_letters: Arc<dyn DeadLetterStore> = Arc::new(MySqlDeadLetterStore::new(pool.clone()));
+    let alerts = Arc::new(
+        Alerts::with_webhook(http.clone(), config.alert_webhook_url)
+            .with_dead_letters(dead_letters.clone()),
+    );
+    let anomalies = Arc::new(AnomalyDetector::new(
+        config.anomaly,
+        alerts.clone(),
+        clock.clone(),
+    ));
+
+    let challenge = config.captcha.map(|(url, secret)| {
+        Box::new(SiteVerifyChallenge::new(http.clone(), url, secret)) as Box<dyn ChallengeVerifier>
+    });
+    let logins = LoginGuard::new(LockoutOptions::default(), challenge);
+
+    let canaries = Arc::new(Canaries::default());
+    if let Err(err) = canaries.reload(&pool).await {
+        tracing::error!(error = ?err, "Failed to load canary notes");
+    }
+    canary::spawn_reloader(pool.clone(), canaries.clone(), Duration::from_secs(60));
+
+    let moderation = Arc::new(Moderation::new(
+        config
+            .moderation_api_url
+            .map(|url| ExternalModerator::new(http.clone(), url)),
+    ));
+    if let Err(err) = moderation.reload(&pool).await {
+        tracing::error!(error = ?err, "Failed to load moderation rules");
+    }
+    moderation::spawn_reloader(pool.clone(), moderation.clone(), Duration::from_secs(60));
+
+    let note_cache = match config.redis_url {
+        Some(url) => match NoteCache::connect(&url, config.note_cache_ttl).await {
+            Ok(cache) => Some(cache),
+            Err(err) => {
+                tracing::error!(
+                    error = %err,
+                    "Failed to connect to Redis, notes will not be cached",
+                );
+                None
+            }
+        },
+        None => None,
+    };
+
     let cors = CorsLayer::new()
-        .allow_origin("http://localhost:3000".parse::<HeaderValue>().unwrap())
-        .allow_methods([Method::GET, Method::POST, Method::PATCH, Method::DELETE])
+        .allow_origin(config.cors_origins)
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::PATCH,
+            Method::DELETE,
+        ])
         .allow_credentials(true)
-        .allow_headers([AUTHORIZATION, ACCEPT, CONTENT_TYPE]);
+        .allow_headers([AUTHORIZATION, ACCEPT, CONTENT_TYPE, IF_MATCH, IF_NONE_MATCH])
+        .expose_headers([ETAG, HeaderName::from_static("x-request-id")]);
 
-    let app = create_router(Arc::new(AppState { db: pool.clone() }))
-        .layer(cors);
+    let metrics = match telemetry::install() {
+        Ok(handle) => handle,
+        Err(err) => {
+            tracing::error!(error = ?err, "Failed to install the metrics recorder");
+            std::process::exit(1);
+        }
+    };
 
-    println!("🚀 Server started successfully");
-    axum::Server::bind(&"0.0.0.0:8000".parse().unwrap())
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+    let mut hooks = Hooks::default();
+    register_hooks(&mut hooks);
+
+    let scripts = Arc::new(ScriptHooks::default());
+    scripting::spawn_reloader(pool.clone(), scripts.clone(), Duration::from_secs(60));
+    hooks.register_shared(scripts.clone());
+
+    let mut jobs = Jobs::default();
+    jobs.register(NotesExportJob);
+    jobs.register(ZipExportJob);
+    if let Some(options) = config.ocr {
+        jobs.register(OcrJob::new(ocr::provider(options, http.clone())));
+    }
+    if let Some(url) = config.transcription_api_url {
+        jobs.register(TranscriptionJob::new(Arc::new(ExternalTranscription::new(
+            http.clone(),
+            url,
+        ))));
+    }
+
+    let attachments: Arc<dyn AttachmentStorage> =
+        Arc::new(LocalDiskStorage::new(config.attachment_dir));
+    let app_state = Arc::new(AppState {
+        db: pool.clone(),
+        notes: Arc::new(MySqlNoteRepository::new(
+            pool.clone(),
+            clock.clone(),
+            attachments.clone(),
+        )),
+        write_buffer,
+        warmup,
+        auth: config
+            .jwt
+            .map(|(secret, ttl)| JwtAuth::new(secret.as_bytes(), ttl)),
+        chaos: config.chaos_enabled.then(Chaos::default),
+        clock,
+        ids,
+        events: EventBus::default(),
+        collab: CollabHub::default(),
+        secrets,
+        http,
+        alerts,
+        dead_letters,
+        anomalies,
+        attachments,
+        rate_limits: config
+            .rate_limit
+            .is_enabled()
+            .then(|| RateLimiter::new(config.rate_limit)),
+        logins,
+        canaries,
+        moderation,
+        locks,
+        leadership,
+        coalescing: ReadCoalescing::default(),
+        cache: Cache {
+            notes: note_cache.clone(),
+            missing_notes: Arc::new(NegativeCache::new(config.negative_cache_ttl, 100_000)),
+            note_index: Arc::default(),
+            tag_cloud: Arc::default(),
+        },
+        hooks,
+        scripts,
+        plugins,
+        jobs,
+        metrics,
+        settings: Settings {
+            admin_token: config.admin_token,
+            trust_forwarded_for: config.trust_forwarded_for,
+            max_content_bytes: config.max_content_bytes,
+            max_attachment_bytes: config.max_attachment_bytes,
+            export_embed_max_bytes: config.export_embed_max_bytes,
+            graphql_playground: config.graphql_playground,
+            summary_refresh_interval,
+            db_max_connections: config.db_max_connections,
+            request_timeout: config.request_timeout,
+        },
+    });
+    if let Some(auth) = &app_state.auth {
+        if let Err(err) = auth.keys.reload(&pool, app_state.clock.now()).await {
+            tracing::error!(error = ?err, "Failed to load the JWT signing keys");
+            std::process::exit(1);
+        }
+        signing_key::spawn_reloader(app_state.clone(), Duration::from_secs(60));
+
+        if let Err(err) = auth.consent.reload(&pool).await {
+            tracing::error!(error = ?err, "Failed to load the current policy");
+        }
+        consent::spawn_reloader(app_state.clone(), Duration::from_secs(60));
+    }
+    anomaly::spawn_event_listener(app_state.anomalies.clone(), app_state.events.subscribe());
+    attachment::spawn_note_cleanup(app_state.clone(), app_state.events.subscribe());
+    hooks::spawn_dispatcher(&app_state.hooks, app_state.events.subscribe());
+    clipper::spawn_source_cleanup(app_state.clone(), app_state.events.subscribe());
+    tag::spawn_note_cleanup(app_state.clone(), app_state.events.subscribe());
+    revision::spawn_note_cleanup(app_state.clone(), app_state.events.subscribe());
+    read_receipt::spawn_note_cleanup(app_state.clone(), app_state.events.subscribe());
+    offload::spawn_note_cleanup(app_state.clone(), app_state.events.subscribe());
+    if let Some(threshold) = config.content_offload_bytes {
+        let offload = Arc::new(ContentOffload::new(
+            pool.clone(),
+            app_state.attachments.clone(),
+            threshold,
+        ));
+        offload::spawn_offloader(offload.clone(), app_state.events.subscribe());
+        leader::spawn_singleton(&app_state.leadership, "content-offload", move || {
+            offload::run_sweep(offload.clone())
+        });
+    }
+    if let Some(cache) = note_cache {
+        note_cache::spawn_invalidator(cache, app_state.events.subscribe());
+    }
+    let sweep_interval = config.expiry_sweep_interval;
+    let sweeper_state = app_state.clone();
+    leader::spawn_singleton(&app_state.leadership, "note-expiry", move || {
+        expiry::run(sweeper_state.clone(), sweep_interval)
+    });
+    if let Some(options) = config.link_previews {
+        let options = Arc::new(options);
+        link_preview::spawn_worker(
+            app_state.clone(),
+            options.clone(),
+            app_state.events.subscribe(),
+        );
+
+        let check_interval = config.link_check_interval;
+        let checker_state = app_state.clone();
+        leader::spawn_singleton(&app_state.leadership, "link-check", move || {
+            link_check::run(checker_state.clone(), options.clone(), check_interval)
+        });
+    }
+    if !app_state.jobs.is_empty() {
+        let job_state = app_state.clone();
+        leader::spawn_singleton(&app_state.leadership, "jobs", move || {
+            job::run(job_state.clone(), job::POLL_INTERVAL)
+        });
+    }
+    app_state.plugins.start(&app_state);
+
+    let mut app = create_router(app_state.clone());
+    if load_test_mode {
+        tracing::warn!("Load-test mode enabled, synthetic endpoints mounted under /api/load-test");
+        app = app.merge(load_test::create_router(app_state));
+    }
+    let app = error::layer(request_id::layer(app.layer(cors)));
+
+    let listener_options = ListenerOptions {
+        addr: config.bind_addr,
+        reuse_port: config.so_reuseport,
+        backlog: 1024,
+    };
+    let listener = match listener::bind(&listener_options) {
+        Ok(listener) => listener,
+        Err(err) => {
+            tracing::error!(error = ?err, "Failed to bind listener");
+            std::process::exit(1);
+        }
+    };
+
+    let shutdown = Shutdown::listen();
+    let server = axum::Server::from_tcp(listener)
+        .unwrap()
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown.clone().requested());
+    let drain_timeout = config.shutdown_drain_timeout;
+    let drain_deadline = async move {
+        shutdown.requested().await;
+        tokio::time::sleep(drain_timeout).await;
+    };
+
+    tracing::info!("Server started successfully");
+    tokio::select! {
+        result = server => if let Err(err) = result {
+            tracing::error!(error = ?err, "Server error");
+        },
+        _ = drain_deadline => {
+            tracing::warn!("Drain timeout reached, dropping the remaining connections");
+        }
+    }
+
+    pending_writes.flush().await;
+    pool.close().await;
+    tracing::info!("Server stopped");
 }
@@ -1,24 +1,694 @@
-use serde::{Serialize, Deserialize};
+use std::{fmt, str::FromStr};
+
+use async_graphql::SimpleObject;
+use serde::{Serialize, Deserialize, Deserializer, Serializer};
 use chrono::{DateTime,Utc};
+use sqlx::{
+    encode::IsNull,
+    error::BoxDynError,
+    mysql::{MySqlRow, MySqlTypeInfo, MySqlValueRef},
+    Decode, Encode, FromRow, MySql, Row, Type,
+};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    compression, preview,
+    validation::{MAX_CATEGORY_CHARS, MAX_TITLE_CHARS},
+};
+
+/// A UUID stored as `BINARY(16)` but serialized as the usual hyphenated
+/// string, so the compact storage is invisible to API clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BinaryId(pub Uuid);
+
+impl From<Uuid> for BinaryId {
+    fn from(id: Uuid) -> Self {
+        Self(id)
+    }
+}
+
+impl fmt::Display for BinaryId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.hyphenated().fmt(f)
+    }
+}
+
+impl FromStr for BinaryId {
+    type Err = uuid::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Uuid::parse_str(s).map(Self)
+    }
+}
+
+impl Serialize for BinaryId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for BinaryId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl Type<MySql> for BinaryId {
+    fn type_info() -> MySqlTypeInfo {
+        <&[u8] as Type<MySql>>::type_info()
+    }
+
+    fn compatible(ty: &MySqlTypeInfo) -> bool {
+        <&[u8] as Type<MySql>>::compatible(ty)
+    }
+}
+
+impl Encode<'_, MySql> for BinaryId {
+    fn encode_by_ref(&self, buf: &mut Vec<u8>) -> IsNull {
+        <&[u8] as Encode<MySql>>::encode(self.0.as_bytes().as_slice(), buf)
+    }
+}
+
+impl<'r> Decode<'r, MySql> for BinaryId {
+    fn decode(value: MySqlValueRef<'r>) -> Result<Self, BoxDynError> {
+        let bytes = <&[u8] as Decode<MySql>>::decode(value)?;
+        Ok(Self(Uuid::from_slice(bytes)?))
+    }
+}
+
+/// The id of a note. A distinct type from [`UserId`], so that one cannot be
+/// bound where the other belongs; both are stored as a [`BinaryId`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct NoteId(pub BinaryId);
+
+/// The id of a user account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct UserId(pub BinaryId);
+
+/// Conversions and the column type of an id stored as a [`BinaryId`].
+macro_rules! binary_id_newtype {
+    ($name:ident) => {
+        impl Type<MySql> for $name {
+            fn type_info() -> MySqlTypeInfo {
+                <BinaryId as Type<MySql>>::type_info()
+            }
+
+            fn compatible(ty: &MySqlTypeInfo) -> bool {
+                <BinaryId as Type<MySql>>::compatible(ty)
+            }
+        }
+
+        impl Encode<'_, MySql> for $name {
+            fn encode_by_ref(&self, buf: &mut Vec<u8>) -> IsNull {
+                self.0.encode_by_ref(buf)
+            }
+        }
+
+        impl<'r> Decode<'r, MySql> for $name {
+            fn decode(value: MySqlValueRef<'r>) -> Result<Self, BoxDynError> {
+                BinaryId::decode(value).map(Self)
+            }
+        }
+
+        impl From<Uuid> for $name {
+            fn from(id: Uuid) -> Self {
+                Self(BinaryId(id))
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
 
-#[derive(Serialize, Deserialize, Debug, sqlx::FromRow)]
+        impl FromStr for $name {
+            type Err = uuid::Error;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                s.parse().map(Self)
+            }
+        }
+    };
+}
+
+binary_id_newtype!(NoteId);
+binary_id_newtype!(UserId);
+
+/// Conversions and the column type of a text field checked by `parse`.
+macro_rules! text_newtype {
+    ($name:ident) => {
+        impl $name {
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl std::ops::Deref for $name {
+            type Target = str;
+
+            fn deref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+
+        impl TryFrom<String> for $name {
+            type Error = String;
+
+            fn try_from(value: String) -> Result<Self, String> {
+                Self::parse(value)
+            }
+        }
+
+        impl From<$name> for String {
+            fn from(value: $name) -> String {
+                value.0
+            }
+        }
+
+        /// Stored values were checked when they were written.
+        impl Type<MySql> for $name {
+            fn type_info() -> MySqlTypeInfo {
+                <String as Type<MySql>>::type_info()
+            }
+
+            fn compatible(ty: &MySqlTypeInfo) -> bool {
+                <String as Type<MySql>>::compatible(ty)
+            }
+        }
+
+        impl Encode<'_, MySql> for $name {
+            fn encode_by_ref(&self, buf: &mut Vec<u8>) -> IsNull {
+                <&str as Encode<MySql>>::encode(self.0.as_str(), buf)
+            }
+        }
+
+        impl<'r> Decode<'r, MySql> for $name {
+            fn decode(value: MySqlValueRef<'r>) -> Result<Self, BoxDynError> {
+                String::decode(value).map(Self)
+            }
+        }
+    };
+}
+
+/// A note title: not blank, and at most [`MAX_TITLE_CHARS`] characters.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Title(String);
+
+impl Title {
+    /// The problem with `title` as a message about the field when it is not
+    /// a valid title.
+    pub fn parse(title: impl Into<String>) -> Result<Self, String> {
+        let title = title.into();
+        if title.trim().is_empty() {
+            Err("must not be empty".to_string())
+        } else if title.chars().count() > MAX_TITLE_CHARS {
+            Err(format!("must be at most {} characters", MAX_TITLE_CHARS))
+        } else {
+            Ok(Self(title))
+        }
+    }
+}
+
+text_newtype!(Title);
+
+/// The name of a category, trimmed: not blank, and at most
+/// [`MAX_CATEGORY_CHARS`] characters.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct CategoryName(String);
+
+impl CategoryName {
+    /// Like [`Title::parse`].
+    pub fn parse(name: impl Into<String>) -> Result<Self, String> {
+        let name = name.into();
+        let name = name.trim();
+        if name.is_empty() {
+            Err("must not be blank".to_string())
+        } else if name.chars().count() > MAX_CATEGORY_CHARS {
+            Err(format!("must be at most {} characters", MAX_CATEGORY_CHARS))
+        } else {
+            Ok(Self(name.to_string()))
+        }
+    }
+}
+
+text_newtype!(CategoryName);
+
+/// The column type of an enum stored as the text of its `as_str`, and read
+/// back with its `FromStr`. The columns have CHECK constraints to the same
+/// values, so a row that does not parse is a decode error.
+macro_rules! text_enum {
+    ($name:ty) => {
+        impl sqlx::Type<sqlx::MySql> for $name {
+            fn type_info() -> sqlx::mysql::MySqlTypeInfo {
+                <str as sqlx::Type<sqlx::MySql>>::type_info()
+            }
+
+            fn compatible(ty: &sqlx::mysql::MySqlTypeInfo) -> bool {
+                <str as sqlx::Type<sqlx::MySql>>::compatible(ty)
+            }
+        }
+
+        impl sqlx::Encode<'_, sqlx::MySql> for $name {
+            fn encode_by_ref(&self, buf: &mut Vec<u8>) -> sqlx::encode::IsNull {
+                <&str as sqlx::Encode<sqlx::MySql>>::encode(self.as_str(), buf)
+            }
+        }
+
+        impl<'r> sqlx::Decode<'r, sqlx::MySql> for $name {
+            fn decode(
+                value: sqlx::mysql::MySqlValueRef<'r>,
+            ) -> Result<Self, sqlx::error::BoxDynError> {
+                Ok(<&str as sqlx::Decode<sqlx::MySql>>::decode(value)?.parse::<$name>()?)
+            }
+        }
+    };
+}
+
+pub(crate) use text_enum;
+
+/// Whether a note is published, stored in its `published` BOOLEAN column.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    #[default]
+    Draft,
+    Published,
+}
+
+impl Visibility {
+    pub fn is_published(self) -> bool {
+        self == Visibility::Published
+    }
+}
+
+impl From<bool> for Visibility {
+    fn from(published: bool) -> Self {
+        if published {
+            Visibility::Published
+        } else {
+            Visibility::Draft
+        }
+    }
+}
+
+impl Type<MySql> for Visibility {
+    fn type_info() -> MySqlTypeInfo {
+        <bool as Type<MySql>>::type_info()
+    }
+
+    fn compatible(ty: &MySqlTypeInfo) -> bool {
+        <bool as Type<MySql>>::compatible(ty)
+    }
+}
+
+impl Encode<'_, MySql> for Visibility {
+    fn encode_by_ref(&self, buf: &mut Vec<u8>) -> IsNull {
+        <bool as Encode<MySql>>::encode(self.is_published(), buf)
+    }
+}
+
+impl<'r> Decode<'r, MySql> for Visibility {
+    fn decode(value: MySqlValueRef<'r>) -> Result<Self, BoxDynError> {
+        bool::decode(value).map(Self::from)
+    }
+}
+
+/// Where a note is in its editorial workflow. Notes move between statuses
+/// only by a [`Transition`]; a note is published exactly when its status is
+/// [`NoteStatus::Published`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NoteStatus {
+    #[default]
+    Draft,
+    InReview,
+    Published,
+    Archived,
+}
+
+impl NoteStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NoteStatus::Draft => "draft",
+            NoteStatus::InReview => "in_review",
+            NoteStatus::Published => "published",
+            NoteStatus::Archived => "archived",
+        }
+    }
+}
+
+impl fmt::Display for NoteStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for NoteStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "draft" => Ok(NoteStatus::Draft),
+            "in_review" => Ok(NoteStatus::InReview),
+            "published" => Ok(NoteStatus::Published),
+            "archived" => Ok(NoteStatus::Archived),
+            other => Err(format!(
+                "Unknown status '{}', expected draft, in_review, published or archived",
+                other
+            )),
+        }
+    }
+}
+
+text_enum!(NoteStatus);
+
+/// A move between [`NoteStatus`]es, the only ones a note can make.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    /// Draft to in review.
+    Submit,
+    /// In review to published.
+    Approve,
+    /// In review back to draft.
+    Reject,
+    /// Draft to published, skipping review.
+    Publish,
+    /// Published back to draft.
+    Unpublish,
+    /// Draft or published to archived.
+    Archive,
+    /// Archived back to draft.
+    Unarchive,
+}
+
+impl Transition {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Transition::Submit => "submit",
+            Transition::Approve => "approve",
+            Transition::Reject => "reject",
+            Transition::Publish => "publish",
+            Transition::Unpublish => "unpublish",
+            Transition::Archive => "archive",
+            Transition::Unarchive => "unarchive",
+        }
+    }
+
+    /// The statuses a note can take this transition from.
+    pub fn sources(self) -> &'static [NoteStatus] {
+        match self {
+            Transition::Submit | Transition::Publish => &[NoteStatus::Draft],
+            Transition::Approve | Transition::Reject => &[NoteStatus::InReview],
+            Transition::Unpublish => &[NoteStatus::Published],
+            Transition::Archive => &[NoteStatus::Draft, NoteStatus::Published],
+            Transition::Unarchive => &[NoteStatus::Archived],
+        }
+    }
+
+    pub fn target(self) -> NoteStatus {
+        match self {
+            Transition::Submit => NoteStatus::InReview,
+            Transition::Approve | Transition::Publish => NoteStatus::Published,
+            Transition::Reject | Transition::Unpublish | Transition::Unarchive => NoteStatus::Draft,
+            Transition::Archive => NoteStatus::Archived,
+        }
+    }
+
+    /// The status a note at `from` ends up in, or why it cannot take this
+    /// transition.
+    pub fn apply(self, from: NoteStatus) -> Result<NoteStatus, String> {
+        if self.sources().contains(&from) {
+            Ok(self.target())
+        } else {
+            Err(format!("Cannot {} a note that is {}", self.as_str(), from))
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct NoteModel {
-    pub id: String,
-    pub title: String,
+    pub id: NoteId,
+    pub title: Title,
     pub content: String,
+    pub preview: String,
+    /// The name of the category, kept in step with it; empty when none.
     pub category: String,
-    pub published: i8,
+    /// Absent from notes cached before categories were stored.
+    #[serde(default)]
+    pub category_id: Option<BinaryId>,
+    pub published: Visibility,
+    /// When the note was last published; `None` while it is a draft, and for
+    /// notes cached before it was stored.
+    #[serde(default)]
+    pub published_at: Option<DateTime<Utc>>,
+    /// Kept in step with `published`. Notes cached before it was stored no
+    /// longer deserialize, which counts as a cache miss.
+    pub status: NoteStatus,
+    /// When the note self-destructs; `None` for notes that do not expire.
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    pub view_count: u64,
+    pub last_accessed_at: Option<DateTime<Utc>>,
     pub created_at: Option<DateTime<Utc>>,
     pub updated_at: Option<DateTime<Utc>>,
+    /// The user who owns the note; `None` for notes written without a user
+    /// account.
+    pub user_id: Option<UserId>,
+    /// When the note was moved to the trash; `None` for live notes.
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// Starts at 1 and is bumped by every edit.
+    pub version: u32,
+    /// Where the content is stored when it is offloaded from the table; see
+    /// [`crate::offload`].
+    #[serde(default)]
+    pub content_key: Option<String>,
+}
+
+impl NoteModel {
+    /// Whether the note has self-destructed by `now`, though it may not be
+    /// purged yet.
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+
+    /// Publishes or unpublishes the note at `now`. Publishing a published
+    /// note keeps the time it was first published. Unpublishing turns it back
+    /// into a draft; notes that were not published keep their status.
+    pub fn set_published(&mut self, published: bool, now: DateTime<Utc>) {
+        self.published_at = match (published, self.published_at) {
+            (true, Some(at)) if self.published.is_published() => Some(at),
+            (true, _) => Some(now),
+            (false, _) => None,
+        };
+        if published {
+            self.status = NoteStatus::Published;
+        } else if self.status == NoteStatus::Published {
+            self.status = NoteStatus::Draft;
+        }
+        self.published = published.into();
+    }
+
+    /// Moves the note to `status` at `now`, publishing or unpublishing it to
+    /// match.
+    pub fn set_status(&mut self, status: NoteStatus, now: DateTime<Utc>) {
+        self.set_published(status == NoteStatus::Published, now);
+        self.status = status;
+    }
+
+    /// The transition that publishes or unpublishes the note, if `published`
+    /// changes whether it is.
+    pub fn publishing(&self, published: bool) -> Option<Transition> {
+        match (self.published.is_published(), published) {
+            (false, true) => Some(Transition::Publish),
+            (true, false) => Some(Transition::Unpublish),
+            _ => None,
+        }
+    }
+}
+
+/// The plain text of a row with `content`, `content_encoding` and
+/// `content_zstd` columns, decompressed when stored with
+/// `content_encoding = 'zstd'`. Rows offloaded to storage only have their
+/// excerpt here; see [`crate::offload::reassemble`].
+pub(crate) fn read_content(row: &MySqlRow) -> Result<String, sqlx::Error> {
+    let encoding: String = row.try_get("content_encoding")?;
+    if encoding == compression::ZSTD {
+        let compressed: Vec<u8> = row.try_get("content_zstd")?;
+        compression::decompress(&compressed).map_err(|err| sqlx::Error::ColumnDecode {
+            index: "content_zstd".to_string(),
+            source: Box::new(err),
+        })
+    } else {
+        row.try_get("content")
+    }
+}
+
+/// Decompresses content stored with `content_encoding = 'zstd'`, and computes
+/// the preview of rows written before previews were stored.
+impl<'r> FromRow<'r, MySqlRow> for NoteModel {
+    fn from_row(row: &'r MySqlRow) -> Result<Self, sqlx::Error> {
+        let content = read_content(row)?;
+        let preview = row
+            .try_get::<Option<String>, _>("preview")?
+            .unwrap_or_else(|| preview::preview(&content));
+        Ok(Self {
+            id: row.try_get("id")?,
+            title: row.try_get("title")?,
+            content,
+            preview,
+            category: row.try_get("category")?,
+            category_id: row.try_get("category_id")?,
+            published: row.try_get("published")?,
+            published_at: row.try_get("published_at")?,
+            status: row.try_get("status")?,
+            expires_at: row.try_get("expires_at")?,
+            view_count: row.try_get("view_count")?,
+            last_accessed_at: row.try_get("last_accessed_at")?,
+            created_at: row.try_get("created_at")?,
+            updated_at: row.try_get("updated_at")?,
+            user_id: row.try_get("user_id")?,
+            deleted_at: row.try_get("deleted_at")?,
+            version: row.try_get("version")?,
+            content_key: row.try_get("content_key")?,
+        })
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema, SimpleObject)]
+#[graphql(name = "Note")]
 pub struct NoteModelResponse {
     pub id: String,
     pub title: String,
     pub content: String,
+    pub preview: String,
     pub category: String,
+    pub category_id: Option<String>,
     pub published: bool,
+    /// Unset while the note is a draft.
+    pub published_at: Option<DateTime<Utc>>,
+    /// `draft`, `in_review`, `published` or `archived`.
+    pub status: String,
+    /// Unset for notes that do not expire.
+    pub expires_at: Option<DateTime<Utc>>,
+    pub view_count: u64,
+    pub last_accessed_at: Option<DateTime<Utc>>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
-}
\ No newline at end of file
+    /// Also sent as the `ETag` of the note; see [`crate::conditional`].
+    pub version: u32,
+    /// Only set on notes in the trash.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// The storage key of the content, only set when it is kept out of the
+    /// database.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_location: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, FromRow, ToSchema)]
+pub struct TagModel {
+    #[schema(value_type = String, format = Uuid)]
+    pub id: BinaryId,
+    /// Owner of the tag, the same as of the notes it labels.
+    #[serde(skip_serializing)]
+    #[schema(value_type = Option<String>, format = Uuid)]
+    pub user_id: Option<UserId>,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, FromRow, ToSchema)]
+pub struct CategoryModel {
+    #[schema(value_type = String, format = Uuid)]
+    pub id: BinaryId,
+    /// Owner of the category, the same as of the notes in it.
+    #[serde(skip_serializing)]
+    #[schema(value_type = Option<String>, format = Uuid)]
+    pub user_id: Option<UserId>,
+    #[schema(value_type = String)]
+    pub name: CategoryName,
+    pub created_at: DateTime<Utc>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATUSES: [NoteStatus; 4] = [
+        NoteStatus::Draft,
+        NoteStatus::InReview,
+        NoteStatus::Published,
+        NoteStatus::Archived,
+    ];
+
+    #[test]
+    fn transitions_move_between_the_allowed_statuses() {
+        use NoteStatus::*;
+        let legal = [
+            (Transition::Submit, Draft, InReview),
+            (Transition::Approve, InReview, Published),
+            (Transition::Reject, InReview, Draft),
+            (Transition::Publish, Draft, Published),
+            (Transition::Unpublish, Published, Draft),
+            (Transition::Archive, Draft, Archived),
+            (Transition::Archive, Published, Archived),
+            (Transition::Unarchive, Archived, Draft),
+        ];
+        for (transition, from, to) in legal {
+            assert_eq!(transition.apply(from), Ok(to), "{:?}", transition);
+        }
+    }
+
+    #[test]
+    fn transitions_refuse_every_other_move() {
+        let transitions = [
+            Transition::Submit,
+            Transition::Approve,
+            Transition::Reject,
+            Transition::Publish,
+            Transition::Unpublish,
+            Transition::Archive,
+            Transition::Unarchive,
+        ];
+        for transition in transitions {
+            for from in STATUSES {
+                if transition.sources().contains(&from) {
+                    continue;
+                }
+                assert_eq!(
+                    transition.apply(from),
+                    Err(format!(
+                        "Cannot {} a note that is {}",
+                        transition.as_str(),
+                        from
+                    ))
+                );
+            }
+        }
+        // Archived notes can only be unarchived, in review ones only decided.
+        assert!(Transition::Publish.apply(NoteStatus::Archived).is_err());
+        assert!(Transition::Publish.apply(NoteStatus::InReview).is_err());
+        assert!(Transition::Archive.apply(NoteStatus::InReview).is_err());
+    }
+
+    #[test]
+    fn statuses_round_trip_through_text() {
+        for status in STATUSES {
+            assert_eq!(status.as_str().parse::<NoteStatus>(), Ok(status));
+        }
+        assert!("deleted".parse::<NoteStatus>().is_err());
+    }
+}
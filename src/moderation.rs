@@ -0,0 +1,695 @@
+//! Content moderation on write.
+//!
+//! Titles and contents are screened against blocklist words and regex rules
+//! managed through the admin API, and optionally by an external moderation
+//! service (`MODERATION_API_URL`). The strongest matching action wins:
+//!
+//! * `reject` refuses the write with 422,
+//! * `unpublish` stores the note unpublished and queues it for review,
+//! * `flag` stores the note unchanged and queues it for review.
+//!
+//! Moderators work through the review queue under `/api/admin/moderation`.
+
+use std::{
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::mysql::MySqlPool;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    auth::NoteScope,
+    error::AppError,
+    events::NoteEventKind,
+    http_client::HttpClient,
+    model::{text_enum, NoteId, Transition},
+    repository::DELETE_NOTE,
+    service::NoteService,
+    state::DbPool,
+    AppState,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ModerationAction {
+    Allow,
+    Flag,
+    Unpublish,
+    Reject,
+}
+
+impl ModerationAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ModerationAction::Allow => "allow",
+            ModerationAction::Flag => "flag",
+            ModerationAction::Unpublish => "unpublish",
+            ModerationAction::Reject => "reject",
+        }
+    }
+}
+
+impl FromStr for ModerationAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(ModerationAction::Allow),
+            "flag" => Ok(ModerationAction::Flag),
+            "unpublish" => Ok(ModerationAction::Unpublish),
+            "reject" => Ok(ModerationAction::Reject),
+            other => Err(format!(
+                "Unknown moderation action '{}', expected flag, unpublish or reject",
+                other
+            )),
+        }
+    }
+}
+
+text_enum!(ModerationAction);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleKind {
+    /// Case-insensitive whole word or phrase.
+    Word,
+    Regex,
+}
+
+impl RuleKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RuleKind::Word => "word",
+            RuleKind::Regex => "regex",
+        }
+    }
+}
+
+impl FromStr for RuleKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "word" => Ok(RuleKind::Word),
+            "regex" => Ok(RuleKind::Regex),
+            other => Err(format!("Unknown rule kind '{}'", other)),
+        }
+    }
+}
+
+text_enum!(RuleKind);
+
+/// Where a flagged note is in review.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReviewStatus {
+    Pending,
+    Approved,
+    Removed,
+}
+
+impl ReviewStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReviewStatus::Pending => "pending",
+            ReviewStatus::Approved => "approved",
+            ReviewStatus::Removed => "removed",
+        }
+    }
+}
+
+impl FromStr for ReviewStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(ReviewStatus::Pending),
+            "approved" => Ok(ReviewStatus::Approved),
+            "removed" => Ok(ReviewStatus::Removed),
+            other => Err(format!(
+                "Unknown review status '{}', expected pending, approved or removed",
+                other
+            )),
+        }
+    }
+}
+
+text_enum!(ReviewStatus);
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct ModerationRule {
+    pub id: u64,
+    pub kind: RuleKind,
+    pub pattern: String,
+    pub action: ModerationAction,
+    pub created_at: DateTime<Utc>,
+}
+
+struct CompiledRule {
+    id: u64,
+    regex: Regex,
+    action: ModerationAction,
+}
+
+fn compile(kind: RuleKind, pattern: &str) -> Result<Regex, String> {
+    let source = match kind {
+        RuleKind::Word => format!(r"\b{}\b", regex::escape(pattern.trim())),
+        RuleKind::Regex => pattern.to_string(),
+    };
+    RegexBuilder::new(&source)
+        .case_insensitive(kind == RuleKind::Word)
+        .size_limit(1 << 20)
+        .build()
+        .map_err(|err| format!("Invalid pattern: {}", err))
+}
+
+/// Outcome of screening a write.
+#[derive(Debug, Clone, Serialize)]
+pub struct Verdict {
+    pub action: ModerationAction,
+    pub reasons: Vec<String>,
+}
+
+impl Verdict {
+    pub fn allow() -> Self {
+        Verdict {
+            action: ModerationAction::Allow,
+            reasons: Vec::new(),
+        }
+    }
+
+    fn escalate(&mut self, action: ModerationAction, reason: String) {
+        self.action = self.action.max(action);
+        self.reasons.push(reason);
+    }
+
+    pub fn unpublishes(&self) -> bool {
+        self.action == ModerationAction::Unpublish
+    }
+}
+
+/// Optional external moderation service. It receives
+/// `{"title": ..., "content": ...}` and answers `{"action": ..., "reason": ...}`.
+pub struct ExternalModerator {
+    http: Arc<HttpClient>,
+    url: String,
+}
+
+impl ExternalModerator {
+    pub fn new(http: Arc<HttpClient>, url: String) -> Self {
+        Self { http, url }
+    }
+
+    async fn review(&self, title: Option<&str>, content: Option<&str>) -> Result<Value, String> {
+        let request = self
+            .http
+            .post(&self.url)
+            .timeout(Duration::from_secs(2))
+            .json(&json!({ "title": title, "content": content }));
+        let response = self
+            .http
+            .send("moderation", request)
+            .await
+            .map_err(|err| err.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("moderation API answered {}", response.status()));
+        }
+        response.json().await.map_err(|err| err.to_string())
+    }
+}
+
+#[derive(Default)]
+pub struct Moderation {
+    rules: RwLock<Vec<CompiledRule>>,
+    external: Option<ExternalModerator>,
+}
+
+impl Moderation {
+    pub fn new(external: Option<ExternalModerator>) -> Self {
+        Self {
+            rules: RwLock::new(Vec::new()),
+            external,
+        }
+    }
+
+    pub async fn reload(&self, db: &MySqlPool) -> Result<(), sqlx::Error> {
+        let rules = sqlx::query_as::<_, ModerationRule>("SELECT * FROM moderation_rules")
+            .fetch_all(db)
+            .await?;
+        let compiled = rules
+            .iter()
+            .filter_map(|rule| {
+                let compiled = CompiledRule {
+                    id: rule.id,
+                    regex: compile(rule.kind, &rule.pattern).ok()?,
+                    action: rule.action,
+                };
+                Some(compiled)
+            })
+            .collect();
+        *self.rules.write().unwrap() = compiled;
+        Ok(())
+    }
+
+    /// Screens the fields a write sets; unchanged fields were screened when
+    /// they were written.
+    pub async fn screen(&self, title: Option<&str>, content: Option<&str>) -> Verdict {
+        let mut verdict = Verdict::allow();
+        {
+            let rules = self.rules.read().unwrap();
+            for rule in rules.iter() {
+                for (field, text) in [("title", title), ("content", content)] {
+                    if text.is_some_and(|text| rule.regex.is_match(text)) {
+                        verdict.escalate(
+                            rule.action,
+                            format!("{} matched moderation rule {}", field, rule.id),
+                        );
+                    }
+                }
+            }
+        }
+
+        if let Some(external) = &self.external {
+            match external.review(title, content).await {
+                Ok(body) => {
+                    let action = body["action"]
+                        .as_str()
+                        .and_then(|action| action.parse().ok())
+                        .unwrap_or(ModerationAction::Allow);
+                    if action != ModerationAction::Allow {
+                        let reason = body["reason"]
+                            .as_str()
+                            .unwrap_or("flagged by moderation API");
+                        verdict.escalate(action, reason.to_string());
+                    }
+                }
+                // Fail open: an outage of the moderation API must not block
+                // every write.
+                Err(err) => tracing::warn!(error = %err, "Moderation API unavailable"),
+            }
+        }
+
+        verdict
+    }
+}
+
+pub fn spawn_reloader(db: MySqlPool, moderation: Arc<Moderation>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(err) = moderation.reload(&db).await {
+                tracing::error!(error = ?err, "Failed to reload moderation rules");
+            }
+        }
+    });
+}
+
+/// Screens a write, refusing it outright when a `reject` rule matched.
+pub async fn screen(
+    data: &AppState,
+    title: Option<&str>,
+    content: Option<&str>,
+) -> Result<Verdict, AppError> {
+    let verdict = data.moderation.screen(title, content).await;
+    if verdict.action == ModerationAction::Reject {
+        let error_response = json!({
+            "status": "fail",
+            "message": "Note was rejected by content moderation",
+            "reasons": verdict.reasons,
+        });
+        return Err(AppError::Response(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(error_response),
+        ));
+    }
+    Ok(verdict)
+}
+
+/// Queues a written note for review when its verdict asks for it.
+pub async fn enqueue(data: &AppState, note_id: NoteId, verdict: &Verdict) -> Result<(), AppError> {
+    if verdict.action == ModerationAction::Allow {
+        return Ok(());
+    }
+    sqlx::query(
+        "INSERT INTO moderation_queue (note_id, action, reasons, created_at) VALUES (?, ?, ?, ?)",
+    )
+    .bind(note_id)
+    .bind(verdict.action.as_str())
+    .bind(json!(verdict.reasons).to_string())
+    .bind(data.clock.now())
+    .execute(&data.db)
+    .await?;
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateRuleSchema {
+    pub kind: RuleKind,
+    pub pattern: String,
+    pub action: ModerationAction,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/moderation/rules",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Moderation rules"),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn list_rules_handler(State(db): State<DbPool>) -> Result<impl IntoResponse, AppError> {
+    let rules = sqlx::query_as::<_, ModerationRule>("SELECT * FROM moderation_rules ORDER BY id")
+        .fetch_all(&*db)
+        .await?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "results": rules.len(),
+        "rules": rules,
+    })))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/admin/moderation/rules",
+    tag = "admin",
+    request_body = CreateRuleSchema,
+    responses(
+        (status = 201, description = "The created rule"),
+        (status = 400, description = "Invalid pattern", body = ErrorResponse),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn create_rule_handler(
+    State(data): State<Arc<AppState>>,
+    Json(body): Json<CreateRuleSchema>,
+) -> Result<impl IntoResponse, AppError> {
+    if body.action == ModerationAction::Allow {
+        return Err(AppError::Validation(
+            "Rules must flag, unpublish or reject".to_string(),
+        ));
+    }
+    if body.pattern.trim().is_empty() {
+        return Err(AppError::Validation(
+            "Pattern must not be empty".to_string(),
+        ));
+    }
+    compile(body.kind, &body.pattern).map_err(AppError::Validation)?;
+
+    let result = sqlx::query(
+        "INSERT INTO moderation_rules (kind, pattern, action, created_at) VALUES (?, ?, ?, ?)",
+    )
+    .bind(body.kind.as_str())
+    .bind(&body.pattern)
+    .bind(body.action.as_str())
+    .bind(data.clock.now())
+    .execute(&data.db)
+    .await?;
+
+    data.moderation.reload(&data.db).await?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({
+            "status": "success",
+            "data": json!({ "id": result.last_insert_id() })
+        })),
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/admin/moderation/rules/{id}",
+    tag = "admin",
+    params(("id" = u64, Path, description = "Rule ID")),
+    responses(
+        (status = 204, description = "The rule was deleted"),
+        (status = 404, description = "Rule not found", body = ErrorResponse),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn delete_rule_handler(
+    Path(id): Path<u64>,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let result = sqlx::query("DELETE FROM moderation_rules WHERE id = ?")
+        .bind(id)
+        .execute(&data.db)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound(format!(
+            "Moderation rule with ID: {} not found",
+            id
+        )));
+    }
+
+    data.moderation.reload(&data.db).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct QueueEntry {
+    pub id: u64,
+    pub note_id: NoteId,
+    pub action: ModerationAction,
+    #[sqlx(try_from = "String")]
+    pub reasons: JsonText,
+    pub status: ReviewStatus,
+    pub created_at: DateTime<Utc>,
+    pub resolved_at: Option<DateTime<Utc>>,
+}
+
+/// A JSON document stored in a TEXT column.
+#[derive(Debug, Serialize)]
+#[serde(transparent)]
+pub struct JsonText(Value);
+
+impl TryFrom<String> for JsonText {
+    type Error = serde_json::Error;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        serde_json::from_str(&text).map(JsonText)
+    }
+}
+
+#[derive(Debug, Deserialize, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct QueueOptions {
+    pub status: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/moderation/queue",
+    tag = "admin",
+    params(QueueOptions),
+    responses(
+        (status = 200, description = "Flagged notes awaiting review"),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn review_queue_handler(
+    Query(opts): Query<QueueOptions>,
+    State(db): State<DbPool>,
+) -> Result<impl IntoResponse, AppError> {
+    let status = opts
+        .status
+        .as_deref()
+        .map(str::parse::<ReviewStatus>)
+        .transpose()
+        .map_err(AppError::Validation)?
+        .unwrap_or(ReviewStatus::Pending);
+    let entries = sqlx::query_as::<_, QueueEntry>(
+        "SELECT * FROM moderation_queue WHERE status = ? ORDER BY created_at, id LIMIT 100",
+    )
+    .bind(status)
+    .fetch_all(&*db)
+    .await?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "results": entries.len(),
+        "entries": entries,
+    })))
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Decision {
+    /// Keep the note; optionally publish it again.
+    Approve,
+    /// Delete the note.
+    Remove,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ResolveSchema {
+    pub decision: Decision,
+    /// Publishes the note again on approval, when it is still a draft.
+    #[serde(default)]
+    pub republish: bool,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/admin/moderation/queue/{id}/resolve",
+    tag = "admin",
+    params(("id" = u64, Path, description = "Queue entry ID")),
+    request_body = ResolveSchema,
+    responses(
+        (status = 200, description = "The entry was resolved"),
+        (status = 404, description = "Entry not found", body = ErrorResponse),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn resolve_entry_handler(
+    Path(id): Path<u64>,
+    State(data): State<Arc<AppState>>,
+    Json(body): Json<ResolveSchema>,
+) -> Result<impl IntoResponse, AppError> {
+    let entry = sqlx::query_as::<_, QueueEntry>(
+        "SELECT * FROM moderation_queue WHERE id = ? AND status = 'pending'",
+    )
+    .bind(id)
+    .fetch_optional(&data.db)
+    .await?;
+    let Some(entry) = entry else {
+        return Err(AppError::NotFound(format!(
+            "Pending review with ID: {} not found",
+            id
+        )));
+    };
+
+    if matches!(body.decision, Decision::Approve) && body.republish {
+        // The moderator acts on a note they may not own, and only drafts are
+        // published again.
+        NoteService::new(&data, &NoteScope(None))
+            .moderate(entry.note_id, Transition::Publish)
+            .await?;
+    }
+
+    let owner = data.notes.find_owner(entry.note_id).await?.flatten();
+    let now = data.clock.now();
+    let mut tx = data.db.begin().await?;
+    let (status, events) = match body.decision {
+        Decision::Approve => (ReviewStatus::Approved, vec![]),
+        Decision::Remove => {
+            sqlx::query(DELETE_NOTE)
+                .bind(entry.note_id)
+                .execute(&mut tx)
+                .await?;
+            (ReviewStatus::Removed, vec![NoteEventKind::Deleted])
+        }
+    };
+    // Other pending reviews of the same note are settled by this decision.
+    sqlx::query(
+        "UPDATE moderation_queue SET status = ?, resolved_at = ? WHERE note_id = ? AND status = 'pending'",
+    )
+    .bind(status)
+    .bind(now)
+    .bind(entry.note_id)
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    for kind in events {
+        data.events.publish(kind, entry.note_id, owner, now);
+    }
+    Ok(Json(json!({
+        "status": "success",
+        "data": json!({
+            "id": id,
+            "note_id": entry.note_id,
+            "resolution": status,
+        })
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::TestApp;
+
+    use super::*;
+
+    fn rule(id: u64, kind: RuleKind, pattern: &str, action: ModerationAction) -> CompiledRule {
+        CompiledRule {
+            id,
+            regex: compile(kind, pattern).unwrap(),
+            action,
+        }
+    }
+
+    #[tokio::test]
+    async fn the_strongest_matching_rule_wins() {
+        let moderation = Moderation::new(None);
+        *moderation.rules.write().unwrap() = vec![
+            rule(1, RuleKind::Word, "spam", ModerationAction::Flag),
+            rule(
+                2,
+                RuleKind::Regex,
+                r"\d{4}-\d{4}",
+                ModerationAction::Unpublish,
+            ),
+        ];
+
+        let verdict = moderation
+            .screen(Some("Cheap SPAM"), Some("Call 5555-1234"))
+            .await;
+        assert_eq!(verdict.action, ModerationAction::Unpublish);
+        assert_eq!(
+            verdict.reasons,
+            [
+                "title matched moderation rule 1",
+                "content matched moderation rule 2"
+            ]
+        );
+
+        // Words only match whole.
+        let verdict = moderation.screen(Some("Spammers"), None).await;
+        assert_eq!(verdict.action, ModerationAction::Allow);
+    }
+
+    #[test]
+    fn word_patterns_are_taken_literally() {
+        let regex = compile(RuleKind::Word, "v1.2").unwrap();
+        assert!(regex.is_match("upgrade to V1.2 now"));
+        assert!(!regex.is_match("upgrade to v132 now"));
+        assert!(compile(RuleKind::Regex, "(unclosed").is_err());
+    }
+
+    #[tokio::test]
+    async fn rejected_notes_are_not_written() {
+        let app = TestApp::new();
+        *app.state.moderation.rules.write().unwrap() = vec![rule(
+            1,
+            RuleKind::Word,
+            "forbidden",
+            ModerationAction::Reject,
+        )];
+
+        let (status, body) = app
+            .post(
+                "/api/notes",
+                json!({ "title": "Forbidden", "content": "Fruit" }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{}", body);
+        assert_eq!(body["reasons"], json!(["title matched moderation rule 1"]));
+        let (_, body) = app.get("/api/notes").await;
+        assert_eq!(body["results"], json!(0));
+    }
+}
@@ -0,0 +1,128 @@
+//! Short-lived cache of note ids known not to exist.
+//!
+//! Scrapers probing random ids would otherwise cost one query per request.
+//! Misses are remembered for a few seconds and forgotten when a note is
+//! created under that id on this instance; on other replicas a stale entry
+//! lasts at most one TTL.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::model::NoteId;
+
+pub struct NegativeCache {
+    ttl: Duration,
+    capacity: usize,
+    missing: Mutex<HashMap<NoteId, Instant>>,
+}
+
+impl NegativeCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            missing: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_missing(&self, id: NoteId) -> bool {
+        self.missing
+            .lock()
+            .unwrap()
+            .get(&id)
+            .is_some_and(|expires| *expires > Instant::now())
+    }
+
+    pub fn record_missing(&self, id: NoteId) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let mut missing = self.missing.lock().unwrap();
+        if missing.len() >= self.capacity {
+            missing.retain(|_, expires| *expires > now);
+        }
+        // Still full of live entries: a flood of probes, not worth tracking.
+        if missing.len() < self.capacity {
+            missing.insert(id, now + self.ttl);
+        }
+    }
+
+    pub fn forget(&self, id: NoteId) {
+        self.missing.lock().unwrap().remove(&id);
+    }
+}
+
+impl Default for NegativeCache {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(10), 100_000)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::TestApp;
+
+    fn id() -> NoteId {
+        NoteId::from(uuid::Uuid::new_v4())
+    }
+
+    #[test]
+    fn misses_are_forgotten_after_the_ttl() {
+        let cache = NegativeCache::new(Duration::from_millis(50), 10);
+        let (probed, other) = (id(), id());
+        cache.record_missing(probed);
+        assert!(cache.is_missing(probed));
+        assert!(!cache.is_missing(other));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!cache.is_missing(probed));
+    }
+
+    #[test]
+    fn a_zero_ttl_remembers_nothing() {
+        let cache = NegativeCache::new(Duration::ZERO, 10);
+        let probed = id();
+        cache.record_missing(probed);
+        assert!(!cache.is_missing(probed));
+    }
+
+    #[test]
+    fn a_full_cache_only_makes_room_from_expired_misses() {
+        let cache = NegativeCache::new(Duration::from_millis(50), 2);
+        let (first, second, third) = (id(), id(), id());
+        cache.record_missing(first);
+        cache.record_missing(second);
+        cache.record_missing(third);
+        assert!(cache.is_missing(first) && cache.is_missing(second));
+        assert!(!cache.is_missing(third));
+
+        std::thread::sleep(Duration::from_millis(60));
+        cache.record_missing(third);
+        assert!(cache.is_missing(third));
+    }
+
+    #[tokio::test]
+    async fn creating_a_note_forgets_its_miss() {
+        let app = TestApp::new();
+        let probed = id();
+        let uri = format!("/api/notes/{}", probed);
+        let (status, _) = app.get(&uri).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(app.state.cache.missing_notes.is_missing(probed));
+
+        let note = json!({ "title": "Late", "content": "Created after a probe" });
+        let (status, _, body) = app.send(Method::PUT, &uri, None, Some(note)).await;
+        assert_eq!(status, StatusCode::CREATED, "{}", body);
+        let (status, body) = app.get(&uri).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["data"]["note"]["title"], json!("Late"));
+    }
+}
@@ -0,0 +1,263 @@
+//! Optional Redis cache for single-note reads, enabled by `REDIS_URL`.
+//!
+//! `GET /api/notes/:id` looks the note up here before MySQL, and stores what
+//! it loads for `NOTE_CACHE_TTL_SECS` ([`DEFAULT_TTL`] by default). Every
+//! change event for a note evicts it, so instances sharing the Redis see each
+//! other's writes; the TTL bounds how stale view counts (flushed by the write
+//! buffer without an event) and writes made outside the API can get.
+//! Entries live in a [`CacheStore`]: Redis, or a map in tests.
+//!
+//! The cache never fails a read: when Redis errors or is slower than
+//! [`TIMEOUT`], the note is read from MySQL as if it were not cached.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use redis::{aio::ConnectionManager, AsyncCommands, RedisResult};
+use tokio::{
+    sync::broadcast::{self, error::RecvError},
+    time::timeout,
+};
+
+use crate::{
+    events::{NoteEvent, NoteEventKind},
+    model::{NoteId, NoteModel},
+};
+
+pub const DEFAULT_TTL: Duration = Duration::from_secs(60);
+/// How long a cache call may take before MySQL is asked instead.
+pub const TIMEOUT: Duration = Duration::from_millis(50);
+
+/// Where cached notes are kept, as JSON by key.
+#[async_trait]
+pub trait CacheStore: Send + Sync {
+    async fn get(&self, key: &str) -> RedisResult<Option<String>>;
+
+    async fn set(&self, key: &str, value: String, ttl: Duration) -> RedisResult<()>;
+
+    async fn del(&self, key: &str) -> RedisResult<()>;
+}
+
+#[async_trait]
+impl CacheStore for ConnectionManager {
+    async fn get(&self, key: &str) -> RedisResult<Option<String>> {
+        AsyncCommands::get(&mut self.clone(), key).await
+    }
+
+    async fn set(&self, key: &str, value: String, ttl: Duration) -> RedisResult<()> {
+        AsyncCommands::set_ex(&mut self.clone(), key, value, ttl.as_secs() as usize).await
+    }
+
+    async fn del(&self, key: &str) -> RedisResult<()> {
+        AsyncCommands::del(&mut self.clone(), key).await
+    }
+}
+
+/// Entries in a map, for tests.
+#[derive(Default)]
+pub struct InMemoryCacheStore {
+    entries: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+#[async_trait]
+impl CacheStore for InMemoryCacheStore {
+    async fn get(&self, key: &str) -> RedisResult<Option<String>> {
+        let entries = self.entries.lock().unwrap();
+        Ok(entries
+            .get(key)
+            .filter(|(_, expires_at)| *expires_at > Instant::now())
+            .map(|(value, _)| value.clone()))
+    }
+
+    async fn set(&self, key: &str, value: String, ttl: Duration) -> RedisResult<()> {
+        let expires_at = Instant::now() + ttl;
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), (value, expires_at));
+        Ok(())
+    }
+
+    async fn del(&self, key: &str) -> RedisResult<()> {
+        self.entries.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct NoteCache {
+    store: Arc<dyn CacheStore>,
+    ttl: Duration,
+}
+
+fn key(id: NoteId) -> String {
+    format!("note:{}", id)
+}
+
+impl NoteCache {
+    pub async fn connect(url: &str, ttl: Duration) -> RedisResult<Self> {
+        let client = redis::Client::open(url)?;
+        let conn = ConnectionManager::new(client).await?;
+        Ok(Self::with_store(Arc::new(conn), ttl))
+    }
+
+    /// Keeps notes in `store` instead of Redis.
+    pub fn with_store(store: Arc<dyn CacheStore>, ttl: Duration) -> Self {
+        Self { store, ttl }
+    }
+
+    /// The cached note, if any.
+    pub async fn get(&self, id: NoteId) -> Option<NoteModel> {
+        let cached = match timeout(TIMEOUT, self.store.get(&key(id))).await {
+            Ok(Ok(cached)) => cached,
+            Ok(Err(err)) => {
+                tracing::warn!(note_id = %id, error = %err, "Failed to read note from the cache");
+                return None;
+            }
+            Err(_) => return None,
+        };
+        cached.and_then(|json| serde_json::from_str(&json).ok())
+    }
+
+    pub async fn put(&self, note: &NoteModel) {
+        let Ok(json) = serde_json::to_string(note) else {
+            return;
+        };
+        let key = key(note.id);
+        let stored = self.store.set(&key, json, self.ttl);
+        if let Ok(Err(err)) = timeout(TIMEOUT, stored).await {
+            tracing::warn!(note_id = %note.id, error = %err, "Failed to cache note");
+        }
+    }
+
+    pub async fn evict(&self, id: NoteId) {
+        if let Err(err) = self.store.del(&key(id)).await {
+            tracing::error!(note_id = %id, error = %err, "Failed to evict note from the cache");
+        }
+    }
+}
+
+/// Evicts notes from the cache as they change.
+pub fn spawn_invalidator(cache: NoteCache, mut events: broadcast::Receiver<NoteEvent>) {
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                // Reads leave the note as it was.
+                Ok(event) if event.kind == NoteEventKind::Read => {}
+                Ok(event) => cache.evict(event.note_id).await,
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!(
+                        skipped,
+                        "Note cache missed change events; those notes stay cached until their TTL"
+                    );
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::{json, Value};
+
+    use crate::testing::TestApp;
+
+    use super::*;
+
+    fn cache(app: &TestApp) -> &NoteCache {
+        app.state.cache.notes.as_ref().unwrap()
+    }
+
+    /// Creates a note and reads it once, which caches it.
+    async fn cached_note(app: &TestApp, title: &str) -> (NoteId, String) {
+        let id = NoteId::from(uuid::Uuid::new_v4());
+        let uri = format!("/api/notes/{}", id);
+        let body = json!({ "title": title, "content": "Agenda to follow" });
+        let (status, _, _) = app.send(Method::PUT, &uri, None, Some(body)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        // Lets the creation's own eviction go first.
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(app.get(&uri).await.0, StatusCode::OK);
+        assert_eq!(cache(app).get(id).await.unwrap().title.as_str(), title);
+        (id, uri)
+    }
+
+    /// Eviction follows the change event; waits for it to land.
+    async fn evicted(app: &TestApp, id: NoteId) -> bool {
+        for _ in 0..10 {
+            if cache(app).get(id).await.is_none() {
+                return true;
+            }
+            tokio::task::yield_now().await;
+        }
+        false
+    }
+
+    fn title(body: &Value) -> &Value {
+        &body["data"]["note"]["title"]
+    }
+
+    #[tokio::test]
+    async fn edits_evict_the_cached_note() {
+        let app = TestApp::with_note_cache();
+        let (id, uri) = cached_note(&app, "Standup").await;
+
+        let body = json!({ "title": "Standup, moved to 10:00", "content": "Agenda to follow" });
+        let (status, _, _) = app.send(Method::PUT, &uri, None, Some(body)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(evicted(&app, id).await);
+        assert_eq!(title(&app.get(&uri).await.1), "Standup, moved to 10:00");
+    }
+
+    #[tokio::test]
+    async fn trashed_notes_are_evicted() {
+        let app = TestApp::with_note_cache();
+        let (id, uri) = cached_note(&app, "Standup").await;
+
+        let (status, _, _) = app.send(Method::DELETE, &uri, None, None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(evicted(&app, id).await);
+        assert_eq!(app.get(&uri).await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn restored_notes_are_evicted() {
+        let app = TestApp::with_note_cache();
+        let (id, uri) = cached_note(&app, "Standup").await;
+        let stale = cache(&app).get(id).await.unwrap();
+        let (status, _, _) = app.send(Method::DELETE, &uri, None, None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(evicted(&app, id).await);
+
+        // A read that raced the trash cached the note again.
+        cache(&app).put(&stale).await;
+        let (status, _, _) = app
+            .send(Method::POST, &format!("{}/restore", uri), None, None)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(evicted(&app, id).await);
+        let (status, body) = app.get(&uri).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(title(&body), "Standup");
+    }
+
+    #[tokio::test]
+    async fn reads_leave_the_cached_note_alone() {
+        let app = TestApp::with_note_cache();
+        let (id, _) = cached_note(&app, "Standup").await;
+        let note = cache(&app).get(id).await.unwrap();
+        app.state
+            .events
+            .publish(NoteEventKind::Read, id, None, app.state.clock.now());
+        assert!(!evicted(&app, id).await);
+        assert_eq!(cache(&app).get(id).await.unwrap().version, note.version);
+    }
+}
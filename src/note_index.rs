@@ -0,0 +1,196 @@
+//! `GET /api/notes/index`: every note's id, title, category and last update,
+//! for sidebars and tree views.
+//!
+//! The query is served entirely from `idx_notes_sidebar`, or for user
+//! accounts, which only get their own notes, from `idx_notes_user`. The
+//! rendered body is cached per owner until a note changes on this instance or
+//! [`CACHE_TTL`] passes (writes through other replicas), and carries an ETag
+//! so unchanged sidebars cost a 304.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::mysql::MySqlPool;
+
+use crate::{
+    auth::NoteScope,
+    error::AppError,
+    model::{NoteId, UserId},
+    AppState,
+};
+
+pub const CACHE_TTL: Duration = Duration::from_secs(5);
+/// Sidebars beyond this many notes should page through `/api/notes` instead.
+pub const MAX_ENTRIES: i64 = 5000;
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct IndexEntry {
+    pub id: NoteId,
+    pub title: String,
+    pub category: String,
+    pub updated_at: DateTime<Utc>,
+}
+
+struct CachedIndex {
+    seq: u64,
+    built_at: Instant,
+    etag: String,
+    body: Bytes,
+}
+
+impl CachedIndex {
+    fn is_fresh(&self, seq: u64) -> bool {
+        self.seq == seq && self.built_at.elapsed() < CACHE_TTL
+    }
+}
+
+/// Indexes by owner, `None` for the index of every note.
+#[derive(Default)]
+pub struct NoteIndexCache {
+    cached: Mutex<HashMap<Option<UserId>, CachedIndex>>,
+}
+
+impl NoteIndexCache {
+    fn get(&self, owner: Option<UserId>, seq: u64) -> Option<(String, Bytes)> {
+        self.cached
+            .lock()
+            .unwrap()
+            .get(&owner)
+            .filter(|cached| cached.is_fresh(seq))
+            .map(|cached| (cached.etag.clone(), cached.body.clone()))
+    }
+
+    fn put(&self, owner: Option<UserId>, cached: CachedIndex) {
+        let mut indexes = self.cached.lock().unwrap();
+        // Stale indexes are dropped, so only owners seen since the last
+        // change are kept.
+        indexes.retain(|_, index| index.is_fresh(cached.seq));
+        indexes.insert(owner, cached);
+    }
+}
+
+/// The index of the notes of `owner`, or of every note, live at `now`.
+async fn build(
+    db: &MySqlPool,
+    owner: Option<UserId>,
+    now: DateTime<Utc>,
+) -> Result<(String, Bytes), sqlx::Error> {
+    let entries = sqlx::query_as::<_, IndexEntry>(
+        "SELECT id, title, COALESCE(category, '') AS category, updated_at FROM notes WHERE deleted_at IS NULL AND (expires_at IS NULL OR expires_at > ?) AND (? IS NULL OR user_id = ?) ORDER BY title LIMIT ?",
+    )
+    .bind(now)
+    .bind(owner)
+    .bind(owner)
+    .bind(MAX_ENTRIES)
+    .fetch_all(db)
+    .await?;
+
+    let body = serde_json::to_vec(&json!({
+        "status": "success",
+        "results": entries.len(),
+        "truncated": entries.len() as i64 == MAX_ENTRIES,
+        "notes": entries,
+    }))
+    .unwrap();
+    let etag = format!("\"{}\"", &hex::encode(Sha256::digest(&body))[..32]);
+    Ok((etag, Bytes::from(body)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/notes/index",
+    tag = "notes",
+    responses(
+        (status = 200, description = "Every note ID and title, or for user accounts those of their own notes"),
+        (status = 304, description = "Unchanged since the given ETag"),
+    ),
+)]
+pub async fn note_index_handler(
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let owner = scope.owner();
+    // Read before querying, so a change racing the query invalidates it.
+    let seq = data.events.last_seq();
+    let (etag, body) = match data.cache.note_index.get(owner, seq) {
+        Some(cached) => cached,
+        None => {
+            let (etag, body) = build(&data.db, owner, data.clock.now()).await?;
+            data.cache.note_index.put(
+                owner,
+                CachedIndex {
+                    seq,
+                    built_at: Instant::now(),
+                    etag: etag.clone(),
+                    body: body.clone(),
+                },
+            );
+            (etag, body)
+        }
+    };
+
+    let not_modified = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.split(',').any(|tag| tag.trim() == etag));
+    if not_modified {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
+    Ok((
+        [
+            (header::ETAG, etag),
+            (header::CONTENT_TYPE, "application/json".to_string()),
+            (header::CACHE_CONTROL, "no-cache".to_string()),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::BinaryId;
+
+    use super::*;
+
+    fn index(seq: u64, etag: &str) -> CachedIndex {
+        CachedIndex {
+            seq,
+            built_at: Instant::now(),
+            etag: etag.to_string(),
+            body: Bytes::new(),
+        }
+    }
+
+    #[test]
+    fn caches_each_owners_index_apart() {
+        let cache = NoteIndexCache::default();
+        let alice = Some(UserId(BinaryId(uuid::Uuid::new_v4())));
+        let bob = Some(UserId(BinaryId(uuid::Uuid::new_v4())));
+        cache.put(None, index(1, "every"));
+        cache.put(alice, index(1, "alice"));
+
+        assert_eq!(cache.get(None, 1).unwrap().0, "every");
+        assert_eq!(cache.get(alice, 1).unwrap().0, "alice");
+        assert!(cache.get(bob, 1).is_none());
+        assert!(cache.get(alice, 2).is_none());
+
+        // A change drops the indexes built before it.
+        cache.put(bob, index(2, "bob"));
+        assert_eq!(cache.cached.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,286 @@
+//! Text extraction from image attachments.
+//!
+//! With `OCR_PROVIDER` set, every uploaded image queues an `ocr` job (see
+//! [`crate::job`]). The job reads the image back from attachment storage,
+//! runs it through the [`OcrProvider`] and stores what it reads in the
+//! `attachment_text` column, which note search matches alongside the note
+//! itself. `tesseract` runs the tesseract CLI on this host; `external` posts
+//! the image to `OCR_API_URL`.
+
+use std::{path::PathBuf, process::Stdio, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use axum::{
+    body::Bytes,
+    http::{header, HeaderValue},
+};
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::{
+    attachment::AttachmentModel,
+    http_client::HttpClient,
+    job::{JobHandler, JobStatus},
+    model::BinaryId,
+    AppState,
+};
+
+pub const JOB_KIND: &str = "ocr";
+/// Longer text is cut off; it is there to be searched, not read.
+pub const MAX_TEXT_CHARS: usize = 64 * 1024;
+const EXTERNAL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Image formats tesseract and the usual OCR services read.
+const IMAGE_TYPES: &[&str] = &[
+    "image/png",
+    "image/jpeg",
+    "image/tiff",
+    "image/bmp",
+    "image/gif",
+    "image/webp",
+];
+
+pub enum OcrOptions {
+    Tesseract { binary: PathBuf, languages: String },
+    External { url: String },
+}
+
+/// Reads the text in an image.
+#[async_trait]
+pub trait OcrProvider: Send + Sync {
+    async fn extract(&self, image: Bytes, content_type: &str) -> Result<String, String>;
+}
+
+pub fn provider(options: OcrOptions, http: Arc<HttpClient>) -> Arc<dyn OcrProvider> {
+    match options {
+        OcrOptions::Tesseract { binary, languages } => {
+            Arc::new(TesseractOcr::new(binary, languages))
+        }
+        OcrOptions::External { url } => Arc::new(ExternalOcr::new(http, url)),
+    }
+}
+
+/// Whether attachments of `content_type` go through OCR.
+pub fn is_ocr_image(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    IMAGE_TYPES
+        .iter()
+        .any(|image_type| essence.eq_ignore_ascii_case(image_type))
+}
+
+/// Runs `tesseract stdin stdout`, so images never touch the disk.
+pub struct TesseractOcr {
+    binary: PathBuf,
+    /// `+`-separated, as tesseract's `-l` takes them.
+    languages: String,
+}
+
+impl TesseractOcr {
+    pub fn new(binary: impl Into<PathBuf>, languages: String) -> Self {
+        Self {
+            binary: binary.into(),
+            languages,
+        }
+    }
+}
+
+#[async_trait]
+impl OcrProvider for TesseractOcr {
+    async fn extract(&self, image: Bytes, _content_type: &str) -> Result<String, String> {
+        let mut child = tokio::process::Command::new(&self.binary)
+            .args(["stdin", "stdout", "-l", &self.languages])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|err| format!("failed to start {}: {}", self.binary.display(), err))?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let write = async move {
+            let written = stdin.write_all(&image).await;
+            drop(stdin);
+            written
+        };
+        let (written, output) = tokio::join!(write, child.wait_with_output());
+        let output = output.map_err(|err| format!("tesseract failed: {}", err))?;
+        if !output.status.success() {
+            return Err(format!(
+                "tesseract exited with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        written.map_err(|err| format!("failed to pass the image to tesseract: {}", err))?;
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+/// An OCR service that takes the image as the request body, with its content
+/// type, and answers `{"text": ...}`.
+pub struct ExternalOcr {
+    http: Arc<HttpClient>,
+    url: String,
+}
+
+impl ExternalOcr {
+    pub fn new(http: Arc<HttpClient>, url: String) -> Self {
+        Self { http, url }
+    }
+}
+
+#[derive(Deserialize)]
+struct ExternalOcrResponse {
+    text: String,
+}
+
+#[async_trait]
+impl OcrProvider for ExternalOcr {
+    async fn extract(&self, image: Bytes, content_type: &str) -> Result<String, String> {
+        let content_type = HeaderValue::from_str(content_type)
+            .unwrap_or(HeaderValue::from_static("application/octet-stream"));
+        let request = self
+            .http
+            .post(&self.url)
+            .timeout(EXTERNAL_TIMEOUT)
+            .header(header::CONTENT_TYPE, content_type)
+            .body(image);
+        let response = self
+            .http
+            .send("ocr", request)
+            .await
+            .map_err(|err| err.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("OCR API answered {}", response.status()));
+        }
+        let response: ExternalOcrResponse = response.json().await.map_err(|err| err.to_string())?;
+        Ok(response.text)
+    }
+}
+
+/// Whitespace collapsed and cut to [`MAX_TEXT_CHARS`]; `None` when nothing
+/// was read.
+fn clean_text(text: &str) -> Option<String> {
+    let mut cleaned = String::new();
+    for word in text.split_whitespace() {
+        if cleaned.len() + word.len() + 1 > MAX_TEXT_CHARS {
+            break;
+        }
+        if !cleaned.is_empty() {
+            cleaned.push(' ');
+        }
+        cleaned.push_str(word);
+    }
+    (!cleaned.is_empty()).then_some(cleaned)
+}
+
+/// Reads the contents of `attachment` back from storage.
+pub async fn read_attachment(
+    data: &AppState,
+    attachment: &AttachmentModel,
+) -> Result<Bytes, String> {
+    let mut reader = data
+        .attachments
+        .open(&attachment.storage_key)
+        .await
+        .map_err(|err| err.to_string())?
+        .ok_or_else(|| format!("attachment {} is missing from storage", attachment.id))?;
+    let mut contents = Vec::with_capacity(attachment.size_bytes as usize);
+    reader
+        .read_to_end(&mut contents)
+        .await
+        .map_err(|err| err.to_string())?;
+    Ok(Bytes::from(contents))
+}
+
+/// Runs the `ocr` jobs queued for image uploads.
+pub struct OcrJob {
+    provider: Arc<dyn OcrProvider>,
+}
+
+impl OcrJob {
+    pub fn new(provider: Arc<dyn OcrProvider>) -> Self {
+        Self { provider }
+    }
+}
+
+#[async_trait]
+impl JobHandler for OcrJob {
+    fn kind(&self) -> &'static str {
+        JOB_KIND
+    }
+
+    async fn run(&self, data: &AppState, subject: BinaryId) -> Result<(), String> {
+        let attachment =
+            sqlx::query_as::<_, AttachmentModel>("SELECT * FROM attachments WHERE id = ?")
+                .bind(subject)
+                .fetch_optional(&data.db)
+                .await
+                .map_err(|err| err.to_string())?;
+        // Gone with its note.
+        let Some(attachment) = attachment else {
+            return Ok(());
+        };
+        let image = read_attachment(data, &attachment).await?;
+        let text = self
+            .provider
+            .extract(image, &attachment.content_type)
+            .await?;
+        sqlx::query("UPDATE attachments SET attachment_text = ?, text_status = ? WHERE id = ?")
+            .bind(clean_text(&text))
+            .bind(JobStatus::Done)
+            .bind(subject)
+            .execute(&data.db)
+            .await
+            .map_err(|err| err.to_string())?;
+        Ok(())
+    }
+
+    async fn give_up(&self, data: &AppState, subject: BinaryId, _error: &str) {
+        let result = sqlx::query("UPDATE attachments SET text_status = ? WHERE id = ?")
+            .bind(JobStatus::Failed)
+            .bind(subject)
+            .execute(&data.db)
+            .await;
+        if let Err(err) = result {
+            tracing::error!(attachment_id = %subject, error = ?err, "Failed to mark OCR failed");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_raster_images_are_read() {
+        assert!(is_ocr_image("image/png"));
+        assert!(is_ocr_image("IMAGE/JPEG; charset=binary"));
+        assert!(!is_ocr_image("image/svg+xml"));
+        assert!(!is_ocr_image("application/pdf"));
+        assert!(!is_ocr_image(""));
+    }
+
+    #[test]
+    fn text_is_collapsed_and_capped() {
+        assert_eq!(
+            clean_text("  Boarding\npass\n\n  GATE 12 "),
+            Some("Boarding pass GATE 12".to_string())
+        );
+        assert_eq!(clean_text(" \n\x0c"), None);
+
+        let long = "word ".repeat(MAX_TEXT_CHARS);
+        let cleaned = clean_text(&long).unwrap();
+        assert!(cleaned.len() <= MAX_TEXT_CHARS);
+        assert!(cleaned.ends_with("word"));
+    }
+
+    #[tokio::test]
+    async fn a_missing_tesseract_is_an_error() {
+        let ocr = TesseractOcr::new("/nonexistent/tesseract", "eng".to_string());
+        let err = ocr
+            .extract(Bytes::from_static(b"\x89PNG"), "image/png")
+            .await
+            .unwrap_err();
+        assert!(err.contains("failed to start"), "{}", err);
+    }
+}
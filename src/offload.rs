@@ -0,0 +1,345 @@
+//! Offload of oversized note bodies to attachment storage.
+//!
+//! With `CONTENT_OFFLOAD_BYTES` set, notes whose content takes more than that
+//! many bytes in the table, compressed or not, are moved out of it: the body
+//! goes zstd-compressed to the [`AttachmentStorage`] under
+//! `notes/{id}/{sha256}`, and the row keeps `content_encoding = 'external'`,
+//! that key in `content_key`, and the same search excerpt and preview as a
+//! compressed note. Reads put the body back together (see [`reassemble`]), so
+//! handlers still see the whole content, and note responses carry the key as
+//! `content_location`.
+//!
+//! Writes are left as they are. Notes are offloaded as their change events
+//! come in, and by a sweep over the table for the rest; an edit writes the
+//! body back to the table, to be offloaded again. Keys are content-addressed,
+//! so revisions keep pointing at the body they recorded. The bodies of a note
+//! go when it is purged.
+
+use std::{error::Error, io, sync::Arc, time::Duration};
+
+use axum::body::Bytes;
+use sha2::{Digest, Sha256};
+use sqlx::{mysql::MySqlPool, Row};
+use tokio::{
+    io::AsyncReadExt,
+    sync::broadcast::{self, error::RecvError},
+};
+
+use crate::{
+    attachment::AttachmentStorage,
+    compression,
+    events::{NoteEvent, NoteEventKind},
+    model::{read_content, NoteId, NoteModel},
+    AppState,
+};
+
+pub const EXTERNAL: &str = "external";
+
+const SELECT_OVERSIZED_NOTES: &str = r#"SELECT id FROM notes
+    WHERE id > ? AND content_encoding <> 'external'
+    AND OCTET_LENGTH(content) + IFNULL(OCTET_LENGTH(content_zstd), 0) > ?
+    ORDER BY id LIMIT ?"#;
+const SELECT_OVERSIZED_NOTE: &str = r#"SELECT version, content, content_encoding, content_zstd FROM notes
+    WHERE id = ? AND content_encoding <> 'external'
+    AND OCTET_LENGTH(content) + IFNULL(OCTET_LENGTH(content_zstd), 0) > ?"#;
+/// Only applies while the note is at the version that was read, and leaves
+/// `updated_at` alone since the content did not change.
+const OFFLOAD_NOTE: &str = r#"UPDATE notes SET content = ?, content_encoding = 'external', content_zstd = NULL, content_key = ?, updated_at = updated_at
+    WHERE id = ? AND version = ? AND content_encoding <> 'external'"#;
+
+type OffloadError = Box<dyn Error + Send + Sync>;
+
+fn note_prefix(id: NoteId) -> String {
+    format!("notes/{}", id)
+}
+
+/// The key the body `content` of note `id` is stored under.
+pub fn content_key(id: NoteId, content: &str) -> String {
+    format!(
+        "{}/{}",
+        note_prefix(id),
+        hex::encode(Sha256::digest(content.as_bytes()))
+    )
+}
+
+/// Stores `compressed`, the body `content` of note `id`, returning its key.
+async fn store(
+    storage: &dyn AttachmentStorage,
+    id: NoteId,
+    content: &str,
+    compressed: Vec<u8>,
+) -> io::Result<String> {
+    let key = content_key(id, content);
+    storage.put(&key, Bytes::from(compressed)).await?;
+    Ok(key)
+}
+
+/// The compressed body stored under `key`.
+pub async fn load(storage: &dyn AttachmentStorage, key: &str) -> io::Result<Vec<u8>> {
+    let mut reader = storage.open(key).await?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("offloaded content {} is missing from storage", key),
+        )
+    })?;
+    let mut compressed = Vec::new();
+    reader.read_to_end(&mut compressed).await?;
+    Ok(compressed)
+}
+
+/// The body stored under `key`, as text.
+pub async fn read(storage: &dyn AttachmentStorage, key: &str) -> Result<String, sqlx::Error> {
+    let compressed = load(storage, key).await.map_err(decode_error)?;
+    tokio::task::spawn_blocking(move || compression::decompress(&compressed))
+        .await
+        .expect("decompression does not panic")
+        .map_err(decode_error)
+}
+
+fn decode_error(err: io::Error) -> sqlx::Error {
+    sqlx::Error::ColumnDecode {
+        index: "content_key".to_string(),
+        source: Box::new(err),
+    }
+}
+
+/// Puts the offloaded bodies of `notes` back in their `content`.
+pub async fn reassemble(
+    storage: &dyn AttachmentStorage,
+    notes: &mut [NoteModel],
+) -> Result<(), sqlx::Error> {
+    for note in notes {
+        if let Some(key) = &note.content_key {
+            note.content = read(storage, key).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Moves note bodies over a size to attachment storage.
+pub struct ContentOffload {
+    db: MySqlPool,
+    storage: Arc<dyn AttachmentStorage>,
+    threshold: usize,
+}
+
+impl ContentOffload {
+    /// Offloads content taking more than `threshold` bytes in the table.
+    pub fn new(db: MySqlPool, storage: Arc<dyn AttachmentStorage>, threshold: usize) -> Self {
+        Self {
+            db,
+            storage,
+            threshold,
+        }
+    }
+
+    /// Offloads the content of note `id` if it is over the threshold.
+    /// `false` when it was left in the table.
+    pub async fn offload(&self, id: NoteId) -> Result<bool, OffloadError> {
+        let row = sqlx::query(SELECT_OVERSIZED_NOTE)
+            .bind(id)
+            .bind(self.threshold as u64)
+            .fetch_optional(&self.db)
+            .await?;
+        let Some(row) = row else {
+            return Ok(false);
+        };
+        let version: u32 = row.try_get("version")?;
+        let content = read_content(&row)?;
+        let compressed = match row.try_get::<Option<Vec<u8>>, _>("content_zstd")? {
+            Some(compressed) => compressed,
+            None => {
+                let plain = content.clone();
+                tokio::task::spawn_blocking(move || {
+                    zstd::bulk::compress(plain.as_bytes(), compression::LEVEL)
+                })
+                .await
+                .expect("compression does not panic")?
+            }
+        };
+
+        let key = store(self.storage.as_ref(), id, &content, compressed).await?;
+        let result = sqlx::query(OFFLOAD_NOTE)
+            .bind(compression::search_excerpt(&content))
+            .bind(&key)
+            .bind(id)
+            .bind(version)
+            .execute(&self.db)
+            .await?;
+        // An edit in between keeps the note in the table until its own event
+        // comes in; the stored body goes when the note does.
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Offloads up to `batch` oversized notes with ids after `after`,
+    /// returning how many were offloaded and the last id examined (`None`
+    /// once there are no more).
+    pub async fn sweep(
+        &self,
+        after: NoteId,
+        batch: u32,
+    ) -> Result<(u64, Option<NoteId>), OffloadError> {
+        let ids: Vec<NoteId> = sqlx::query_scalar(SELECT_OVERSIZED_NOTES)
+            .bind(after)
+            .bind(self.threshold as u64)
+            .bind(batch)
+            .fetch_all(&self.db)
+            .await?;
+        let mut offloaded = 0;
+        for &id in &ids {
+            if self.offload(id).await? {
+                offloaded += 1;
+            }
+        }
+        Ok((offloaded, ids.last().copied()))
+    }
+}
+
+/// Runs [`ContentOffload::sweep`] over the whole table, one batch at a time.
+pub async fn run_sweep(offload: Arc<ContentOffload>) {
+    let mut after = NoteId::from(uuid::Uuid::nil());
+    let mut total = 0;
+    loop {
+        match offload.sweep(after, 100).await {
+            Ok((count, Some(last))) => {
+                total += count;
+                after = last;
+            }
+            Ok((_, None)) => break,
+            Err(err) => {
+                tracing::error!(error = ?err, "Content offload sweep failed");
+                tokio::time::sleep(Duration::from_secs(60)).await;
+            }
+        }
+    }
+    if total > 0 {
+        tracing::info!(notes = total, "Offloaded note content");
+    }
+}
+
+/// Offloads notes as they are written.
+pub fn spawn_offloader(offload: Arc<ContentOffload>, mut events: broadcast::Receiver<NoteEvent>) {
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event)
+                    if matches!(event.kind, NoteEventKind::Created | NoteEventKind::Updated) =>
+                {
+                    if let Err(err) = offload.offload(event.note_id).await {
+                        tracing::error!(
+                            error = ?err,
+                            note_id = %event.note_id,
+                            "Failed to offload note content",
+                        );
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!(skipped, "Content offload skipped change events");
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+/// Removes the offloaded bodies of purged notes.
+pub fn spawn_note_cleanup(data: Arc<AppState>, mut events: broadcast::Receiver<NoteEvent>) {
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) if event.kind == NoteEventKind::Deleted => {
+                    let prefix = note_prefix(event.note_id);
+                    if let Err(err) = data.attachments.delete_all(&prefix).await {
+                        tracing::error!(
+                            error = ?err,
+                            note_id = %event.note_id,
+                            "Failed to delete offloaded note content",
+                        );
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!(skipped, "Offloaded content cleanup skipped change events");
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        attachment::LocalDiskStorage,
+        handler::{filter_db_record, new_note},
+        schema::CreateNoteSchema,
+        testing::TestApp,
+    };
+
+    use super::*;
+
+    fn note(app: &TestApp, content: &str) -> NoteModel {
+        let body = CreateNoteSchema {
+            title: "Build log".to_string(),
+            content: content.to_string(),
+            category: None,
+            published: None,
+            expires_at: None,
+        };
+        new_note(&app.state, NoteId::from(uuid::Uuid::new_v4()), None, body).unwrap()
+    }
+
+    fn storage() -> LocalDiskStorage {
+        LocalDiskStorage::new(
+            std::env::temp_dir().join(format!("rust-axum-mysql-offload-{}", uuid::Uuid::new_v4())),
+        )
+    }
+
+    #[test]
+    fn keys_follow_the_note_and_its_content() {
+        let id = NoteId::from(uuid::Uuid::new_v4());
+        let other = NoteId::from(uuid::Uuid::new_v4());
+        let key = content_key(id, "a long log");
+        assert!(key.starts_with(&format!("notes/{}/", id)));
+        assert_eq!(key, content_key(id, "a long log"));
+        assert_ne!(key, content_key(id, "a longer log"));
+        assert_ne!(key, content_key(other, "a long log"));
+    }
+
+    #[tokio::test]
+    async fn offloaded_notes_are_reassembled_and_show_where_they_live() {
+        let app = TestApp::new();
+        let storage = storage();
+        let content = "line of a long build log\n".repeat(2_000);
+        let mut note = note(&app, &content);
+        let compressed = zstd::bulk::compress(content.as_bytes(), compression::LEVEL).unwrap();
+        let key = store(&storage, note.id, &content, compressed)
+            .await
+            .unwrap();
+        note.content = compression::search_excerpt(&content).to_string();
+        note.content_key = Some(key.clone());
+
+        let mut notes = [note];
+        reassemble(&storage, &mut notes).await.unwrap();
+        assert_eq!(notes[0].content, content);
+        assert_eq!(
+            filter_db_record(&notes[0]).content_location,
+            Some(key.clone())
+        );
+
+        storage.delete_all(&note_prefix(notes[0].id)).await.unwrap();
+        assert!(reassemble(&storage, &mut notes).await.is_err());
+        // Nothing left to delete is not an error.
+        storage.delete_all(&note_prefix(notes[0].id)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn notes_kept_in_the_table_are_left_alone() {
+        let app = TestApp::new();
+        let mut notes = [note(&app, "Fits in a row")];
+        reassemble(&storage(), &mut notes).await.unwrap();
+        assert_eq!(notes[0].content, "Fits in a row");
+        assert_eq!(filter_db_record(&notes[0]).content_location, None);
+    }
+}
@@ -0,0 +1,322 @@
+//! The OpenAPI description of the API, served at `/api/openapi.json` and
+//! browsable with Swagger UI at `/api/docs`.
+//!
+//! Request bodies and query parameters are documented from the types the
+//! handlers deserialize. Responses are wrapped in the usual
+//! `{"status": ..., "data": ...}` envelope, which the `*Response` types below
+//! exist only to describe. Plugin routes are not part of the spec.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use utoipa::{
+    openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+    Modify, OpenApi, ToSchema,
+};
+
+use crate::{
+    advisor, anomaly, archive, attachment, auth, bulk, canary, category, chaos, clipper, collab,
+    conditional, consent, content, dead_letter, events, export, graphql, handler, http_client,
+    leader, link_check, link_preview, lock, model, moderation, note_index, operation, plugin,
+    read_receipt, report, review, revision, schema, scripting, service_account, signing_key,
+    single_flight, summary, tag, telemetry, trash,
+};
+
+/// Sent as RFC 7807 problem details (`type`, `title`, `status`, `detail`,
+/// `instance`) instead to clients accepting `application/problem+json`.
+#[derive(Serialize, ToSchema)]
+pub struct ErrorResponse {
+    /// `fail` for client errors, `error` for server errors.
+    pub status: String,
+    pub message: String,
+    /// Messages keyed by the field they are about, for invalid notes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub errors: Option<std::collections::BTreeMap<String, String>>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct NoteResponse {
+    pub status: String,
+    pub data: NoteData,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct NoteData {
+    pub note: model::NoteModelResponse,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct NoteListResponse {
+    pub status: String,
+    pub results: usize,
+    pub notes: Vec<model::NoteModelResponse>,
+    /// Pass as `after` to fetch the next page; absent on the last page.
+    pub next_cursor: Option<String>,
+    /// Notes matching the filters, across all pages.
+    pub total: usize,
+    pub total_pages: usize,
+    /// Absent when paging by cursor.
+    pub page: Option<usize>,
+    pub limit: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TagResponse {
+    pub status: String,
+    pub data: TagData,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TagData {
+    pub tag: model::TagModel,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TagListResponse {
+    pub status: String,
+    pub results: usize,
+    pub tags: Vec<model::TagModel>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CategoryResponse {
+    pub status: String,
+    pub data: CategoryData,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CategoryData {
+    pub category: model::CategoryModel,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CategoryListResponse {
+    pub status: String,
+    pub results: usize,
+    pub categories: Vec<model::CategoryModel>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TokenResponse {
+    pub status: String,
+    pub data: TokenData,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TokenData {
+    pub user: UserData,
+    /// Send as `Authorization: Bearer <token>`.
+    pub token: String,
+    /// Seconds until the token expires.
+    pub expires_in: u64,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct UserData {
+    #[schema(format = Uuid)]
+    pub id: String,
+    pub email: String,
+    pub created_at: DateTime<Utc>,
+}
+
+struct SecurityAddon;
+
+impl Modify for SecurityAddon {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .description(Some("A user token or a service-account key"))
+                    .build(),
+            ),
+        );
+        components.add_security_scheme(
+            "admin_token",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("x-admin-token"))),
+        );
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Notes API"),
+    paths(
+        handler::health_checker_handler,
+        handler::readiness_handler,
+        telemetry::metrics_handler,
+        auth::register_handler,
+        auth::login_handler,
+        signing_key::jwks_handler,
+        consent::policy_handler,
+        consent::accept_policy_handler,
+        clipper::clip_handler,
+        collab::note_socket_handler,
+        bulk::bulk_create_handler,
+        bulk::bulk_delete_handler,
+        handler::note_list_handler,
+        handler::create_note_handler,
+        summary::note_stats_handler,
+        summary::trending_notes_handler,
+        summary::category_facets_handler,
+        note_index::note_index_handler,
+        handler::search_notes_handler,
+        events::poll_changes_handler,
+        events::stream_changes_handler,
+        trash::list_trash_handler,
+        handler::get_note_handler,
+        conditional::put_note_handler,
+        handler::edit_note_handler,
+        handler::delete_note_handler,
+        handler::publish_note_handler,
+        handler::unpublish_note_handler,
+        handler::submit_note_handler,
+        handler::approve_note_handler,
+        handler::reject_note_handler,
+        handler::archive_note_handler,
+        handler::unarchive_note_handler,
+        attachment::upload_attachment_handler,
+        attachment::list_attachments_handler,
+        attachment::download_attachment_handler,
+        read_receipt::note_reads_handler,
+        review::request_review_handler,
+        review::note_reviews_handler,
+        review::my_reviews_handler,
+        review::get_review_handler,
+        review::approve_review_handler,
+        review::reject_review_handler,
+        content::note_content_handler,
+        export::export_html_handler,
+        export::export_notes_handler,
+        archive::export_zip_handler,
+        operation::get_operation_handler,
+        operation::cancel_operation_handler,
+        operation::operation_result_handler,
+        graphql::graphql_handler,
+        link_preview::note_links_handler,
+        report::report_note_handler,
+        trash::restore_note_handler,
+        revision::list_revisions_handler,
+        revision::revision_diff_handler,
+        revision::restore_to_handler,
+        trash::purge_note_handler,
+        tag::note_tags_handler,
+        tag::set_note_tags_handler,
+        tag::list_tags_handler,
+        tag::tag_cloud_handler,
+        tag::create_tag_handler,
+        tag::get_tag_handler,
+        tag::rename_tag_handler,
+        tag::delete_tag_handler,
+        category::list_categories_handler,
+        category::create_category_handler,
+        category::get_category_handler,
+        category::rename_category_handler,
+        category::delete_category_handler,
+        category::category_notes_handler,
+        advisor::index_advisor_handler,
+        anomaly::anomalies_handler,
+        anomaly::lift_throttles_handler,
+        http_client::http_clients_handler,
+        leader::leader_handler,
+        link_check::broken_links_handler,
+        lock::locks_handler,
+        signing_key::rotate_signing_key_handler,
+        plugin::plugins_handler,
+        consent::list_policies_handler,
+        consent::publish_policy_handler,
+        single_flight::single_flight_handler,
+        canary::list_canaries_handler,
+        canary::create_canary_handler,
+        canary::delete_canary_handler,
+        dead_letter::list_dead_letters_handler,
+        dead_letter::get_dead_letter_handler,
+        dead_letter::replay_dead_letter_handler,
+        dead_letter::replay_dead_letters_handler,
+        dead_letter::replay_failed_job_handler,
+        moderation::list_rules_handler,
+        moderation::create_rule_handler,
+        moderation::delete_rule_handler,
+        moderation::review_queue_handler,
+        moderation::resolve_entry_handler,
+        report::list_reports_handler,
+        report::resolve_report_handler,
+        scripting::list_scripts_handler,
+        scripting::create_script_handler,
+        scripting::test_script_handler,
+        scripting::deactivate_script_handler,
+        scripting::script_versions_handler,
+        scripting::activate_script_handler,
+        service_account::list_service_accounts_handler,
+        service_account::create_service_account_handler,
+        service_account::revoke_service_account_handler,
+        service_account::rotate_service_account_key_handler,
+        chaos::get_chaos_handler,
+        chaos::update_chaos_handler,
+    ),
+    components(schemas(
+        schema::CreateNoteSchema,
+        schema::UpdateNoteSchema,
+        schema::ExpectedNoteFields,
+        schema::TagSchema,
+        schema::NoteTagsSchema,
+        schema::CategorySchema,
+        schema::ReplaySchema,
+        model::NoteModelResponse,
+        model::TagModel,
+        model::CategoryModel,
+        auth::CredentialsSchema,
+        clipper::ClipSchema,
+        archive::ZipExportSchema,
+        consent::AcceptPolicySchema,
+        consent::PublishPolicySchema,
+        canary::CreateCanarySchema,
+        moderation::CreateRuleSchema,
+        moderation::RuleKind,
+        moderation::ModerationAction,
+        moderation::ResolveSchema,
+        moderation::Decision,
+        report::CreateReportSchema,
+        report::ReportReason,
+        report::ResolveReportSchema,
+        report::ReportAction,
+        attachment::AttachmentUpload,
+        review::RequestReviewSchema,
+        review::ReviewDecisionSchema,
+        scripting::CreateScriptSchema,
+        scripting::ScriptHook,
+        scripting::ActivateScriptSchema,
+        scripting::TestScriptSchema,
+        service_account::CreateServiceAccountSchema,
+        chaos::ChaosConfig,
+        chaos::FaultRule,
+        ErrorResponse,
+        NoteResponse,
+        NoteData,
+        NoteListResponse,
+        TagResponse,
+        TagData,
+        TagListResponse,
+        CategoryResponse,
+        CategoryData,
+        CategoryListResponse,
+        TokenResponse,
+        TokenData,
+        UserData,
+    )),
+    modifiers(&SecurityAddon),
+    security(("bearer" = [])),
+    tags(
+        (name = "health", description = "Liveness, readiness and metrics"),
+        (name = "auth", description = "User accounts, enabled with `JWT_SECRET`"),
+        (name = "notes", description = "Notes, scoped to their owner for users"),
+        (name = "tags", description = "Tags and the tags of notes"),
+        (name = "categories", description = "Categories and the notes in them"),
+        (name = "reviews", description = "Review requests and reviewers' decisions"),
+        (name = "clip", description = "Saving web pages as notes"),
+        (name = "operations", description = "Long-running work, started with 202 Accepted and polled"),
+        (name = "admin", description = "Operations, authenticated with `x-admin-token`"),
+    )
+)]
+pub struct ApiDoc;
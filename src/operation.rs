@@ -0,0 +1,626 @@
+//! Long-running operations, started by a request and polled until done.
+//!
+//! Work too long to answer in one request, such as exporting every note (see
+//! [`crate::export`]), answers `202 Accepted` with the operation and its
+//! `Location`, `/api/operations/:id`. The operation is a row in `operations`
+//! and the subject of a job of the same kind (see [`crate::job`]), which
+//! records how many items it has been through and what went wrong with
+//! single items it carried on without. Once the operation has succeeded its
+//! result, a JSON document or a zip archive in attachment storage, is read at
+//! `/api/operations/:id/result`. Operations are only seen in the scope of
+//! whoever started them.
+//!
+//! `POST /api/operations/:id/cancel` cancels a pending operation at once. A
+//! running one is asked to stop: its job checks its [`CancelToken`] between
+//! chunks of work, undoes what it cannot keep and marks the operation
+//! cancelled, reporting how many of the items it went through were rolled
+//! back.
+
+use std::{str::FromStr, sync::Arc};
+
+use axum::{
+    body::StreamBody,
+    extract::{Path, State},
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::mysql::MySqlPool;
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    auth::NoteScope,
+    error::AppError,
+    model::{text_enum, BinaryId, UserId},
+    state::DbPool,
+    AppState,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OperationStatus {
+    Pending,
+    Running,
+    Succeeded,
+    /// Its job ran out of attempts.
+    Failed,
+    Cancelled,
+}
+
+impl OperationStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OperationStatus::Pending => "pending",
+            OperationStatus::Running => "running",
+            OperationStatus::Succeeded => "succeeded",
+            OperationStatus::Failed => "failed",
+            OperationStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+impl FromStr for OperationStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(OperationStatus::Pending),
+            "running" => Ok(OperationStatus::Running),
+            "succeeded" => Ok(OperationStatus::Succeeded),
+            "failed" => Ok(OperationStatus::Failed),
+            "cancelled" => Ok(OperationStatus::Cancelled),
+            other => Err(format!("Unknown operation status '{}'", other)),
+        }
+    }
+}
+
+text_enum!(OperationStatus);
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct OperationModel {
+    pub id: BinaryId,
+    /// Also the kind of the job that works on it, such as `export`.
+    pub kind: String,
+    /// What it was started with, as JSON; `None` for kinds that need nothing.
+    #[serde(skip_serializing)]
+    pub params: Option<String>,
+    #[serde(skip_serializing)]
+    pub user_id: Option<UserId>,
+    pub status: OperationStatus,
+    /// `None` until the job has counted them.
+    pub total_items: Option<u32>,
+    pub completed_items: u32,
+    #[serde(skip_serializing)]
+    pub result_key: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub cancel_requested_at: Option<DateTime<Utc>>,
+    /// Of the completed items, those undone when the operation was cancelled.
+    pub rolled_back_items: Option<u32>,
+}
+
+impl OperationModel {
+    /// The share of the items done, from 0 to 100.
+    pub fn progress(&self) -> u32 {
+        match (self.status, self.total_items) {
+            (OperationStatus::Succeeded, _) => 100,
+            (_, Some(total)) if total > 0 => {
+                (u64::from(self.completed_items.min(total)) * 100 / u64::from(total)) as u32
+            }
+            _ => 0,
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("/api/operations/{}", self.id)
+    }
+
+    /// Whether the operation is still to finish, and so can be cancelled.
+    pub fn is_active(&self) -> bool {
+        matches!(
+            self.status,
+            OperationStatus::Pending | OperationStatus::Running
+        )
+    }
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct OperationError {
+    pub message: String,
+    pub created_at: DateTime<Utc>,
+}
+
+fn operation_not_found(id: impl std::fmt::Display) -> AppError {
+    AppError::NotFound(format!("Operation with ID: {} not found", id))
+}
+
+/// The operation as its status resource shows it, with links to itself and,
+/// once there is one, to its result.
+fn view(operation: &OperationModel, errors: &[OperationError]) -> Value {
+    let mut view = json!(operation);
+    view["progress"] = json!(operation.progress());
+    view["errors"] = json!(errors);
+    let mut links = json!({ "self": operation.url() });
+    if operation.status == OperationStatus::Succeeded {
+        links["result"] = json!(format!("{}/result", operation.url()));
+    }
+    if operation.is_active() && operation.cancel_requested_at.is_none() {
+        links["cancel"] = json!(format!("{}/cancel", operation.url()));
+    }
+    view["links"] = links;
+    view
+}
+
+/// Records a pending operation of `kind` for `owner`, started with `params`,
+/// and queues the job that works on it.
+pub async fn start(
+    data: &AppState,
+    kind: &str,
+    owner: Option<UserId>,
+    params: Option<Value>,
+) -> Result<OperationModel, AppError> {
+    let now = data.clock.now();
+    let operation = OperationModel {
+        id: BinaryId::from(data.ids.generate()),
+        kind: kind.to_string(),
+        params: params.map(|params| params.to_string()),
+        user_id: owner,
+        status: OperationStatus::Pending,
+        total_items: None,
+        completed_items: 0,
+        result_key: None,
+        created_at: now,
+        updated_at: now,
+        finished_at: None,
+        cancel_requested_at: None,
+        rolled_back_items: None,
+    };
+
+    let mut tx = data.db.begin().await?;
+    sqlx::query(
+        "INSERT INTO operations (id, kind, params, user_id, status, completed_items, created_at, updated_at) VALUES (?, ?, ?, ?, ?, 0, ?, ?)",
+    )
+    .bind(operation.id)
+    .bind(&operation.kind)
+    .bind(&operation.params)
+    .bind(operation.user_id)
+    .bind(operation.status)
+    .bind(now)
+    .bind(now)
+    .execute(&mut tx)
+    .await?;
+    data.jobs.enqueue(&mut tx, kind, operation.id, now).await?;
+    tx.commit().await?;
+    Ok(operation)
+}
+
+/// `202 Accepted` for the just started `operation`, pointing at its status.
+pub fn accepted(operation: &OperationModel) -> Response {
+    let location = HeaderValue::from_str(&operation.url()).expect("operation URLs are ASCII");
+    (
+        StatusCode::ACCEPTED,
+        [(header::LOCATION, location)],
+        Json(json!({
+            "status": "success",
+            "data": { "operation": view(operation, &[]) },
+        })),
+    )
+        .into_response()
+}
+
+pub async fn find(db: &MySqlPool, id: BinaryId) -> Result<Option<OperationModel>, sqlx::Error> {
+    sqlx::query_as::<_, OperationModel>("SELECT * FROM operations WHERE id = ?")
+        .bind(id)
+        .fetch_optional(db)
+        .await
+}
+
+/// Whether the job working on an operation has been asked to stop. Jobs
+/// check it between chunks of work, so they stop at the next one.
+pub struct CancelToken<'a> {
+    db: &'a MySqlPool,
+    id: BinaryId,
+}
+
+impl CancelToken<'_> {
+    pub async fn is_cancelled(&self) -> Result<bool, sqlx::Error> {
+        let requested: Option<Option<DateTime<Utc>>> =
+            sqlx::query_scalar("SELECT cancel_requested_at FROM operations WHERE id = ?")
+                .bind(self.id)
+                .fetch_optional(self.db)
+                .await?;
+        // A deleted operation is not worth finishing either.
+        Ok(requested.is_none_or(|requested| requested.is_some()))
+    }
+}
+
+/// Marks operation `id` running through `total` items from the start,
+/// dropping what an earlier attempt recorded. `None` when the operation is
+/// no longer pending or running, so there is nothing to do.
+pub async fn begin(
+    db: &MySqlPool,
+    id: BinaryId,
+    total: u32,
+    now: DateTime<Utc>,
+) -> Result<Option<CancelToken<'_>>, sqlx::Error> {
+    let started = sqlx::query(
+        "UPDATE operations SET status = ?, total_items = ?, completed_items = 0, updated_at = ? WHERE id = ? AND status IN ('pending', 'running')",
+    )
+    .bind(OperationStatus::Running)
+    .bind(total)
+    .bind(now)
+    .bind(id)
+    .execute(db)
+    .await?;
+    if started.rows_affected() == 0 {
+        return Ok(None);
+    }
+    sqlx::query("DELETE FROM operation_errors WHERE operation_id = ?")
+        .bind(id)
+        .execute(db)
+        .await?;
+    Ok(Some(CancelToken { db, id }))
+}
+
+/// Records that operation `id` has been through `completed` items.
+pub async fn advance(
+    db: &MySqlPool,
+    id: BinaryId,
+    completed: u32,
+    now: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE operations SET completed_items = ?, updated_at = ? WHERE id = ?")
+        .bind(completed)
+        .bind(now)
+        .bind(id)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Records what went wrong with items operation `id` carried on without.
+pub async fn record_error(
+    db: &MySqlPool,
+    id: BinaryId,
+    message: &str,
+    now: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO operation_errors (operation_id, message, created_at) VALUES (?, ?, ?)",
+    )
+    .bind(id)
+    .bind(message)
+    .bind(now)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Marks operation `id` succeeded with its result stored under `result_key`.
+pub async fn succeed(
+    db: &MySqlPool,
+    id: BinaryId,
+    result_key: &str,
+    now: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE operations SET status = ?, result_key = ?, updated_at = ?, finished_at = ? WHERE id = ?",
+    )
+    .bind(OperationStatus::Succeeded)
+    .bind(result_key)
+    .bind(now)
+    .bind(now)
+    .bind(id)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Marks operation `id` cancelled, for a job that stopped when asked to
+/// after undoing `rolled_back` of the items it had been through.
+pub async fn cancelled(
+    db: &MySqlPool,
+    id: BinaryId,
+    rolled_back: u32,
+    now: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE operations SET status = ?, rolled_back_items = ?, updated_at = ?, finished_at = ? WHERE id = ? AND status = 'running'",
+    )
+    .bind(OperationStatus::Cancelled)
+    .bind(rolled_back)
+    .bind(now)
+    .bind(now)
+    .bind(id)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Marks operation `id` failed, for a job that gave up on it.
+pub async fn fail(data: &AppState, id: BinaryId) {
+    let now = data.clock.now();
+    let result = sqlx::query(
+        "UPDATE operations SET status = ?, updated_at = ?, finished_at = ? WHERE id = ? AND status IN ('pending', 'running')",
+    )
+    .bind(OperationStatus::Failed)
+    .bind(now)
+    .bind(now)
+    .bind(id)
+    .execute(&data.db)
+    .await;
+    if let Err(err) = result {
+        tracing::error!(operation_id = %id, error = ?err, "Failed to mark operation failed");
+    }
+}
+
+/// Operation `id`, when `scope` may see it.
+async fn find_in_scope(
+    db: &MySqlPool,
+    scope: &NoteScope,
+    id: uuid::Uuid,
+) -> Result<OperationModel, AppError> {
+    find(db, BinaryId::from(id))
+        .await?
+        .filter(|operation| scope.permits(operation.user_id))
+        .ok_or_else(|| operation_not_found(id))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/operations/{id}",
+    tag = "operations",
+    params(("id" = Uuid, Path, description = "Operation ID")),
+    responses(
+        (status = 200, description = "The status, progress and errors of the operation, with a link to its result once it has succeeded"),
+        (status = 404, description = "Operation not found", body = ErrorResponse),
+    ),
+)]
+pub async fn get_operation_handler(
+    Path(id): Path<uuid::Uuid>,
+    scope: NoteScope,
+    State(db): State<DbPool>,
+) -> Result<impl IntoResponse, AppError> {
+    let operation = find_in_scope(&db, &scope, id).await?;
+    let errors = sqlx::query_as::<_, OperationError>(
+        "SELECT message, created_at FROM operation_errors WHERE operation_id = ? ORDER BY id",
+    )
+    .bind(operation.id)
+    .fetch_all(&*db)
+    .await?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "data": { "operation": view(&operation, &errors) },
+    })))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/operations/{id}/cancel",
+    tag = "operations",
+    params(("id" = Uuid, Path, description = "Operation ID")),
+    responses(
+        (status = 200, description = "The pending operation, now cancelled"),
+        (status = 202, description = "The running operation, whose job stops at its next chunk of work"),
+        (status = 404, description = "Operation not found", body = ErrorResponse),
+        (status = 409, description = "The operation has already finished", body = ErrorResponse),
+    ),
+)]
+pub async fn cancel_operation_handler(
+    Path(id): Path<uuid::Uuid>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    let operation = find_in_scope(&data.db, &scope, id).await?;
+    if !operation.is_active() {
+        return Err(AppError::Conflict(format!(
+            "Operation {} is {} and can no longer be cancelled",
+            id,
+            operation.status.as_str()
+        )));
+    }
+
+    let now = data.clock.now();
+    // Nothing has been done yet, so nothing needs undoing.
+    let cancelled = sqlx::query(
+        "UPDATE operations SET status = ?, cancel_requested_at = ?, rolled_back_items = 0, updated_at = ?, finished_at = ? WHERE id = ? AND status = 'pending'",
+    )
+    .bind(OperationStatus::Cancelled)
+    .bind(now)
+    .bind(now)
+    .bind(now)
+    .bind(operation.id)
+    .execute(&data.db)
+    .await?;
+    if cancelled.rows_affected() == 0 {
+        sqlx::query(
+            "UPDATE operations SET cancel_requested_at = COALESCE(cancel_requested_at, ?), updated_at = ? WHERE id = ? AND status = 'running'",
+        )
+        .bind(now)
+        .bind(now)
+        .bind(operation.id)
+        .execute(&data.db)
+        .await?;
+    }
+
+    let operation = find_in_scope(&data.db, &scope, id).await?;
+    let status = if operation.is_active() {
+        StatusCode::ACCEPTED
+    } else {
+        StatusCode::OK
+    };
+    Ok((
+        status,
+        Json(json!({
+            "status": "success",
+            "data": { "operation": view(&operation, &[]) },
+        })),
+    )
+        .into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/operations/{id}/result",
+    tag = "operations",
+    params(("id" = Uuid, Path, description = "Operation ID")),
+    responses(
+        (status = 200, description = "The result of the operation", content_type = ["application/json", "application/zip"]),
+        (status = 404, description = "Operation not found", body = ErrorResponse),
+        (status = 409, description = "The operation has not succeeded", body = ErrorResponse),
+    ),
+)]
+pub async fn operation_result_handler(
+    Path(id): Path<uuid::Uuid>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    let operation = find_in_scope(&data.db, &scope, id).await?;
+    let Some(key) = operation
+        .result_key
+        .as_deref()
+        .filter(|_| operation.status == OperationStatus::Succeeded)
+    else {
+        return Err(AppError::Conflict(format!(
+            "Operation {} is {}, not succeeded",
+            id,
+            operation.status.as_str()
+        )));
+    };
+
+    let reader = match data.attachments.open(key).await {
+        Ok(Some(reader)) => reader,
+        Ok(None) => {
+            tracing::warn!(operation_id = %id, "Operation result is missing from storage");
+            return Err(operation_not_found(id));
+        }
+        Err(err) => {
+            tracing::error!(operation_id = %id, error = ?err, "Failed to open operation result");
+            return Err(AppError::Response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"status": "error", "message": "Failed to read the result"})),
+            ));
+        }
+    };
+    let body = StreamBody::new(ReaderStream::new(reader));
+    match key.rsplit_once('/').map_or(key, |(_, name)| name) {
+        name if name.ends_with(".zip") => {
+            let disposition = HeaderValue::from_str(&format!("attachment; filename=\"{}\"", name))
+                .expect("result keys are ASCII without quotes");
+            Ok((
+                [
+                    (
+                        header::CONTENT_TYPE,
+                        HeaderValue::from_static("application/zip"),
+                    ),
+                    (header::CONTENT_DISPOSITION, disposition),
+                ],
+                body,
+            )
+                .into_response())
+        }
+        _ => Ok((
+            [(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            )],
+            body,
+        )
+            .into_response()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn operation(status: OperationStatus, total: Option<u32>, completed: u32) -> OperationModel {
+        let now = Utc::now();
+        OperationModel {
+            id: BinaryId::from(uuid::Uuid::new_v4()),
+            kind: "export".to_string(),
+            params: None,
+            user_id: None,
+            status,
+            total_items: total,
+            completed_items: completed,
+            result_key: None,
+            created_at: now,
+            updated_at: now,
+            finished_at: None,
+            cancel_requested_at: None,
+            rolled_back_items: None,
+        }
+    }
+
+    #[test]
+    fn progress_is_the_share_of_items_done() {
+        assert_eq!(operation(OperationStatus::Pending, None, 0).progress(), 0);
+        assert_eq!(
+            operation(OperationStatus::Running, Some(0), 0).progress(),
+            0
+        );
+        assert_eq!(
+            operation(OperationStatus::Running, Some(3), 1).progress(),
+            33
+        );
+        // Notes created while the job runs can push it past the count.
+        assert_eq!(
+            operation(OperationStatus::Running, Some(3), 4).progress(),
+            100
+        );
+        assert_eq!(
+            operation(OperationStatus::Succeeded, Some(0), 0).progress(),
+            100
+        );
+    }
+
+    #[test]
+    fn results_are_linked_once_there_is_one() {
+        let running = operation(OperationStatus::Running, Some(4), 2);
+        let view = view(&running, &[]);
+        assert_eq!(view["progress"], json!(50));
+        assert_eq!(view["links"]["self"], json!(running.url()));
+        assert!(view["links"].get("result").is_none());
+        assert!(view.get("result_key").is_none());
+
+        let done = operation(OperationStatus::Succeeded, Some(4), 4);
+        let view = super::view(&done, &[]);
+        assert_eq!(
+            view["links"]["result"],
+            json!(format!("{}/result", done.url()))
+        );
+    }
+
+    #[test]
+    fn only_active_operations_can_be_cancelled() {
+        let pending = operation(OperationStatus::Pending, None, 0);
+        assert_eq!(
+            view(&pending, &[])["links"]["cancel"],
+            json!(format!("{}/cancel", pending.url()))
+        );
+
+        let stopping = OperationModel {
+            cancel_requested_at: Some(Utc::now()),
+            ..operation(OperationStatus::Running, Some(4), 2)
+        };
+        assert!(stopping.is_active());
+        assert!(view(&stopping, &[])["links"].get("cancel").is_none());
+
+        let cancelled = OperationModel {
+            rolled_back_items: Some(2),
+            ..operation(OperationStatus::Cancelled, Some(4), 2)
+        };
+        assert!(!cancelled.is_active());
+        assert_eq!(view(&cancelled, &[])["rolled_back_items"], json!(2));
+        assert_eq!(
+            "cancelled".parse::<OperationStatus>(),
+            Ok(OperationStatus::Cancelled)
+        );
+    }
+}
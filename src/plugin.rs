@@ -0,0 +1,182 @@
+//! Optional subsystems (search backends, chat sinks, importers) packaged as
+//! [`Plugin`]s.
+//!
+//! A plugin contributes routes, schema migrations and background tasks, and
+//! is registered in `main.rs` (behind a cargo feature when it pulls in extra
+//! dependencies). Public routes are mounted next to the note API and admin
+//! routes behind `require_admin`; neither may reuse a core path, since axum
+//! panics on overlapping routes at startup.
+
+use std::{sync::Arc, time::Duration};
+
+use axum::{extract::State, response::IntoResponse, Json, Router};
+use serde_json::json;
+use sqlx::mysql::MySqlPool;
+
+use crate::{error::AppError, lock::DistributedLock, AppState};
+
+/// A schema change owned by a plugin, applied once per database.
+pub struct PluginMigration {
+    /// Unique within the plugin; applied in slice order.
+    pub version: &'static str,
+    pub sql: &'static str,
+}
+
+pub trait Plugin: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Routes mounted next to the note API.
+    fn routes(&self) -> Router<Arc<AppState>> {
+        Router::new()
+    }
+
+    /// Routes mounted behind admin authentication, conventionally under
+    /// `/api/admin/<plugin name>`.
+    fn admin_routes(&self) -> Router<Arc<AppState>> {
+        Router::new()
+    }
+
+    fn migrations(&self) -> &'static [PluginMigration] {
+        &[]
+    }
+
+    /// Spawns the plugin's background tasks once the state is built. Tasks
+    /// that must run on one instance only use `leader::spawn_singleton`.
+    fn start(&self, _state: Arc<AppState>) {}
+}
+
+#[derive(Default)]
+pub struct Plugins {
+    plugins: Vec<Arc<dyn Plugin>>,
+}
+
+/// Serializes plugin migrations across instances starting together.
+const MIGRATION_LOCK: &str = "plugin-migrations";
+const MIGRATION_LEASE: Duration = Duration::from_secs(300);
+
+impl Plugins {
+    pub fn register(&mut self, plugin: impl Plugin + 'static) {
+        tracing::info!(plugin = plugin.name(), "Registered plugin");
+        self.plugins.push(Arc::new(plugin));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.plugins.is_empty()
+    }
+
+    pub fn routes(&self) -> Router<Arc<AppState>> {
+        self.plugins.iter().fold(Router::new(), |router, plugin| {
+            router.merge(plugin.routes())
+        })
+    }
+
+    pub fn admin_routes(&self) -> Router<Arc<AppState>> {
+        self.plugins.iter().fold(Router::new(), |router, plugin| {
+            router.merge(plugin.admin_routes())
+        })
+    }
+
+    /// Applies every plugin migration not yet recorded in
+    /// `plugin_migrations`, holding [`MIGRATION_LOCK`] meanwhile.
+    pub async fn migrate(
+        &self,
+        db: &MySqlPool,
+        locks: &DistributedLock,
+    ) -> Result<(), sqlx::Error> {
+        if self
+            .plugins
+            .iter()
+            .all(|plugin| plugin.migrations().is_empty())
+        {
+            return Ok(());
+        }
+        while !locks.try_acquire(MIGRATION_LOCK, MIGRATION_LEASE).await? {
+            tracing::warn!("Waiting for another instance to migrate plugins");
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        let result = self.apply_migrations(db).await;
+        if let Err(err) = locks.release(MIGRATION_LOCK).await {
+            tracing::warn!(error = ?err, "Failed to release the plugin migration lock");
+        }
+        result
+    }
+
+    async fn apply_migrations(&self, db: &MySqlPool) -> Result<(), sqlx::Error> {
+        for plugin in &self.plugins {
+            for migration in plugin.migrations() {
+                let applied = sqlx::query_scalar::<_, i64>(
+                    "SELECT COUNT(*) FROM plugin_migrations WHERE plugin = ? AND version = ?",
+                )
+                .bind(plugin.name())
+                .bind(migration.version)
+                .fetch_one(db)
+                .await?;
+                if applied > 0 {
+                    continue;
+                }
+                // MySQL commits DDL implicitly, so the record is written after
+                // the change rather than in a transaction with it.
+                sqlx::query(migration.sql).execute(db).await?;
+                sqlx::query("INSERT INTO plugin_migrations (plugin, version) VALUES (?, ?)")
+                    .bind(plugin.name())
+                    .bind(migration.version)
+                    .execute(db)
+                    .await?;
+                tracing::info!(
+                    plugin = plugin.name(),
+                    version = migration.version,
+                    "Applied plugin migration"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub fn start(&self, state: &Arc<AppState>) {
+        for plugin in &self.plugins {
+            plugin.start(state.clone());
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/plugins",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Loaded plugins"),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn plugins_handler(
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let applied = sqlx::query_as::<_, (String, String)>(
+        "SELECT plugin, version FROM plugin_migrations ORDER BY applied_at, version",
+    )
+    .fetch_all(&data.db)
+    .await?;
+
+    let plugins = data
+        .plugins
+        .plugins
+        .iter()
+        .map(|plugin| {
+            let migrations = applied
+                .iter()
+                .filter(|(name, _)| name == plugin.name())
+                .map(|(_, version)| version)
+                .collect::<Vec<_>>();
+            json!({
+                "name": plugin.name(),
+                "migrations": migrations,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    Ok(Json(json!({
+        "status": "success",
+        "results": plugins.len(),
+        "plugins": plugins,
+    })))
+}
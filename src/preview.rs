@@ -0,0 +1,62 @@
+//! Plain-text previews of note content for list views.
+//!
+//! Previews are computed on write and stored next to the content, so list
+//! pages can skip the (possibly huge) content column entirely.
+
+use std::sync::OnceLock;
+
+use regex::Regex;
+
+/// Characters kept in a preview, not counting the trailing ellipsis.
+pub const PREVIEW_CHARS: usize = 200;
+
+fn markup() -> &'static [(Regex, &'static str)] {
+    static MARKUP: OnceLock<Vec<(Regex, &'static str)>> = OnceLock::new();
+    MARKUP.get_or_init(|| {
+        [
+            // Fenced code markers (the code itself is kept).
+            (r"(?m)^\s*(```|~~~).*$", ""),
+            // Images, then links: keep the text.
+            (r"!\[([^\]]*)\]\([^)]*\)", "$1"),
+            (r"\[([^\]]*)\]\([^)]*\)", "$1"),
+            // Headings, blockquotes, list markers and rules.
+            (r"(?m)^\s{0,3}(#{1,6}\s+|>\s?|[-*+]\s+|\d+[.)]\s+)", ""),
+            (r"(?m)^\s*([-*_]\s*){3,}$", ""),
+            // Emphasis, strikethrough and inline code.
+            (r"(\*{1,3}|_{1,3}|~~|`+)", ""),
+            // HTML tags.
+            (r"<[^>]+>", ""),
+        ]
+        .into_iter()
+        .map(|(pattern, replacement)| (Regex::new(pattern).unwrap(), replacement))
+        .collect()
+    })
+}
+
+/// The first [`PREVIEW_CHARS`] characters of `content` with Markdown syntax
+/// stripped and whitespace collapsed.
+pub fn preview(content: &str) -> String {
+    // Markup in a huge note's tail cannot affect its preview.
+    let mut text = content.chars().take(PREVIEW_CHARS * 10).collect::<String>();
+    for (pattern, replacement) in markup() {
+        text = pattern.replace_all(&text, *replacement).into_owned();
+    }
+    let mut preview = String::new();
+    let mut chars = 0;
+    for word in text.split_whitespace() {
+        let needed = word.chars().count() + usize::from(!preview.is_empty());
+        if chars + needed > PREVIEW_CHARS {
+            if preview.is_empty() {
+                preview.extend(word.chars().take(PREVIEW_CHARS));
+            }
+            preview.push('…');
+            return preview;
+        }
+        if !preview.is_empty() {
+            preview.push(' ');
+        }
+        preview.push_str(word);
+        chars += needed;
+    }
+    preview
+}
@@ -0,0 +1,257 @@
+//! Request rate limits, so scrapers cannot starve the database pool.
+//!
+//! Reads and writes are limited separately, each with its own number of
+//! requests per minute (`RATE_LIMIT_READS_PER_MINUTE`,
+//! `RATE_LIMIT_WRITES_PER_MINUTE`); either is off when unset. Requests made
+//! with a service-account key count against the account, all others against
+//! the client address. A client may burst up to a minute's worth of requests
+//! at once, then gets 429 with `Retry-After` until its allowance refills.
+//!
+//! Addresses in `RATE_LIMIT_EXEMPT_IPS` and the service accounts named in
+//! `RATE_LIMIT_EXEMPT_ACCOUNTS` are never limited, nor are the health checks.
+//!
+//! Writes made without a request of their own, such as splices sent over the
+//! collaborative WebSocket, are charged through the [`RateLimited`] the
+//! opening request carries.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    num::NonZeroU32,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::State,
+    http::{header, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::{json, Value};
+
+use crate::{
+    client_ip::{client_ip, IpRange},
+    model::BinaryId,
+    service_account::ServiceAccountPrincipal,
+    AppState,
+};
+
+/// Clients are forgotten once this many are tracked and they have their
+/// full allowance back, or failing that, the least recently seen of them.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+/// How many of [`MAX_TRACKED_CLIENTS`] are kept when some must be evicted.
+const EVICT_DOWN_TO: usize = MAX_TRACKED_CLIENTS * 9 / 10;
+
+#[derive(Debug, Clone, Default)]
+pub struct RateLimitOptions {
+    pub reads_per_minute: Option<NonZeroU32>,
+    pub writes_per_minute: Option<NonZeroU32>,
+    pub exempt_ips: Vec<IpRange>,
+    /// Service account names.
+    pub exempt_accounts: Vec<String>,
+}
+
+impl RateLimitOptions {
+    pub fn is_enabled(&self) -> bool {
+        self.reads_per_minute.is_some() || self.writes_per_minute.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Class {
+    Read,
+    Write,
+}
+
+impl Class {
+    fn of(method: &Method) -> Self {
+        if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) {
+            Class::Read
+        } else {
+            Class::Write
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum RateKey {
+    Ip(IpAddr),
+    Account(BinaryId),
+}
+
+/// The client a limited request counts against, left in its extensions for
+/// handlers that go on writing after it, such as WebSocket upgrades.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimited(RateKey);
+
+impl RateLimited {
+    /// Takes one write from the client's allowance, or says how long until
+    /// there is one to take.
+    pub fn acquire_write(&self, data: &AppState) -> Result<(), Duration> {
+        match &data.rate_limits {
+            Some(limiter) => limiter.acquire(self.0, Class::Write),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The body of a 429.
+pub fn slow_down() -> Value {
+    json!({
+        "status": "fail",
+        "message": "Too many requests, slow down",
+    })
+}
+
+/// A token bucket holding up to a minute's worth of requests.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+pub struct RateLimiter {
+    options: RateLimitOptions,
+    buckets: Mutex<HashMap<(RateKey, Class), Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(options: RateLimitOptions) -> Self {
+        Self {
+            options,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn per_minute(&self, class: Class) -> Option<NonZeroU32> {
+        match class {
+            Class::Read => self.options.reads_per_minute,
+            Class::Write => self.options.writes_per_minute,
+        }
+    }
+
+    /// Takes one request from the allowance of `key`, or says how long until
+    /// there is one to take, in whole seconds.
+    fn acquire(&self, key: RateKey, class: Class) -> Result<(), Duration> {
+        let Some(per_minute) = self.per_minute(class) else {
+            return Ok(());
+        };
+        let capacity = f64::from(per_minute.get());
+        let per_sec = capacity / 60.0;
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            buckets.retain(|(_, class), bucket| {
+                let capacity = self.per_minute(*class).map_or(0.0, |n| f64::from(n.get()));
+                let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+                bucket.tokens + elapsed * capacity / 60.0 < capacity
+            });
+        }
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            let mut seen: Vec<Instant> =
+                buckets.values().map(|bucket| bucket.refilled_at).collect();
+            let (_, cutoff, _) = seen.select_nth_unstable(buckets.len() - EVICT_DOWN_TO - 1);
+            let cutoff = *cutoff;
+            buckets.retain(|_, bucket| bucket.refilled_at > cutoff);
+        }
+        let bucket = buckets.entry((key, class)).or_insert(Bucket {
+            tokens: capacity,
+            refilled_at: now,
+        });
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_sec).min(capacity);
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs(
+                ((1.0 - bucket.tokens) / per_sec).ceil() as u64,
+            ))
+        }
+    }
+}
+
+/// Answers 429 to clients over their allowance. Runs after service-account
+/// authentication, to know which account a key belongs to.
+pub async fn limit_rate<B>(
+    State(data): State<Arc<AppState>>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(limiter) = &data.rate_limits else {
+        return next.run(req).await;
+    };
+    if matches!(req.uri().path(), "/healthz" | "/readyz" | "/api/health") {
+        return next.run(req).await;
+    }
+
+    let options = &limiter.options;
+    let key = match req.extensions().get::<ServiceAccountPrincipal>() {
+        Some(account) => (!options.exempt_accounts.contains(&account.name))
+            .then_some(RateKey::Account(account.id)),
+        None => client_ip(&req, data.settings.trust_forwarded_for)
+            .filter(|ip| !options.exempt_ips.iter().any(|range| range.contains(*ip)))
+            .map(RateKey::Ip),
+    };
+    let Some(key) = key else {
+        return next.run(req).await;
+    };
+
+    if let Err(wait) = limiter.acquire(key, Class::of(req.method())) {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, wait.as_secs().max(1).to_string())],
+            Json(slow_down()),
+        )
+            .into_response();
+    }
+    req.extensions_mut().insert(RateLimited(key));
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn limiter(per_minute: u32) -> RateLimiter {
+        RateLimiter::new(RateLimitOptions {
+            reads_per_minute: NonZeroU32::new(per_minute),
+            ..Default::default()
+        })
+    }
+
+    fn ip(n: u32) -> RateKey {
+        RateKey::Ip(IpAddr::V4(Ipv4Addr::from(n)))
+    }
+
+    #[test]
+    fn limits_each_client_to_its_allowance() {
+        let limiter = limiter(2);
+        assert!(limiter.acquire(ip(1), Class::Read).is_ok());
+        assert!(limiter.acquire(ip(1), Class::Read).is_ok());
+        let wait = limiter.acquire(ip(1), Class::Read).unwrap_err();
+        assert!(wait >= Duration::from_secs(29));
+        assert!(limiter.acquire(ip(2), Class::Read).is_ok());
+        // Writes are not limited.
+        assert!(limiter.acquire(ip(1), Class::Write).is_ok());
+    }
+
+    #[test]
+    fn tracks_at_most_max_clients_while_all_are_active() {
+        let limiter = limiter(1_000);
+        for n in 0..(MAX_TRACKED_CLIENTS as u32 * 2) {
+            limiter.acquire(ip(n), Class::Read).unwrap();
+        }
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(buckets.len() <= MAX_TRACKED_CLIENTS);
+        // The most recent client is kept.
+        assert!(buckets.contains_key(&(ip(MAX_TRACKED_CLIENTS as u32 * 2 - 1), Class::Read)));
+    }
+}
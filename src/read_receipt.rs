@@ -0,0 +1,227 @@
+//! Read receipts: who opened a note they do not own, and when.
+//!
+//! Each user's first and last read of a note, and how many times they read
+//! it, are kept in `note_reads`. Owners see them at
+//! `GET /api/notes/:id/reads`, to confirm an announcement was seen. A user's
+//! first read also publishes [`NoteEventKind::Read`], which reaches the
+//! event stream and note hooks. Owners reading their own notes leave no
+//! receipt, so today receipts come from reviewers opening the notes they
+//! were asked to review.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    auth::NoteScope,
+    error::AppError,
+    events::{NoteEvent, NoteEventKind},
+    model::{NoteId, NoteModel, UserId},
+    AppState,
+};
+
+/// Upserts the read; 1 row affected means it was the first.
+pub const RECORD_READ: &str = r#"INSERT INTO note_reads (note_id, user_id, first_read_at, last_read_at) VALUES (?, ?, ?, ?)
+ON DUPLICATE KEY UPDATE last_read_at = VALUES(last_read_at), read_count = read_count + 1"#;
+pub const SELECT_READS: &str = r#"SELECT note_reads.user_id, users.email, note_reads.first_read_at, note_reads.last_read_at, note_reads.read_count
+    FROM note_reads LEFT JOIN users ON users.id = note_reads.user_id
+    WHERE note_reads.note_id = ?
+    ORDER BY note_reads.first_read_at, note_reads.user_id"#;
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct ReadReceipt {
+    pub user_id: UserId,
+    /// `None` once the account is gone.
+    pub email: Option<String>,
+    pub first_read_at: DateTime<Utc>,
+    pub last_read_at: DateTime<Utc>,
+    pub read_count: u32,
+}
+
+/// Records that `reader` opened `note`, in the background so the read never
+/// waits on or fails because of it.
+pub fn record(data: &Arc<AppState>, note: &NoteModel, reader: UserId) {
+    if note.user_id == Some(reader) {
+        return;
+    }
+    let data = data.clone();
+    let note_id = note.id;
+    let owner = note.user_id;
+    // Stamped now, however long the task waits to run.
+    let now = data.clock.now();
+    tokio::spawn(async move {
+        match data.notes.record_read(note_id, reader, now).await {
+            Ok(true) => {
+                data.events
+                    .publish(NoteEventKind::Read, note_id, owner, now);
+            }
+            Ok(false) => {}
+            Err(err) => {
+                tracing::error!(%note_id, %reader, error = ?err, "Failed to record a note read")
+            }
+        }
+    });
+}
+
+/// Who has read the note, first reader first.
+#[utoipa::path(
+    get,
+    path = "/api/notes/{id}/reads",
+    tag = "notes",
+    params(("id" = Uuid, Path, description = "Note ID")),
+    responses(
+        (status = 200, description = "The read receipts of the note"),
+        (status = 404, description = "Note not found", body = ErrorResponse),
+    ),
+)]
+pub async fn note_reads_handler(
+    Path(id): Path<uuid::Uuid>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let note_id = NoteId::from(id);
+    scope.check(&data, note_id).await?;
+    if data.notes.find(note_id).await?.is_none() {
+        return Err(AppError::note_not_found(note_id));
+    }
+
+    let reads = data.notes.reads(note_id).await?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "results": reads.len(),
+        "reads": reads,
+    })))
+}
+
+/// Forgets the reads of deleted notes.
+pub fn spawn_note_cleanup(data: Arc<AppState>, mut events: broadcast::Receiver<NoteEvent>) {
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) if event.kind == NoteEventKind::Deleted => {
+                    let result = sqlx::query("DELETE FROM note_reads WHERE note_id = ?")
+                        .bind(event.note_id)
+                        .execute(&data.db)
+                        .await;
+                    if let Err(err) = result {
+                        tracing::error!(
+                            error = ?err,
+                            note_id = %event.note_id,
+                            "Failed to forget note reads",
+                        );
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!(skipped, "Read receipt cleanup skipped change events");
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use chrono::Duration;
+    use serde_json::Value;
+
+    use crate::{clock::Clock, handler::new_note, schema::CreateNoteSchema, testing::TestApp};
+
+    use super::*;
+
+    fn user() -> UserId {
+        UserId(uuid::Uuid::new_v4().into())
+    }
+
+    async fn announcement(app: &TestApp, owner: UserId) -> NoteModel {
+        let body = CreateNoteSchema {
+            title: "Office closed on Friday".to_string(),
+            content: "Enjoy the long weekend.".to_string(),
+            category: None,
+            published: None,
+            expires_at: None,
+        };
+        let note = new_note(
+            &app.state,
+            NoteId::from(uuid::Uuid::new_v4()),
+            Some(owner),
+            body,
+        )
+        .unwrap();
+        app.state.notes.insert(&note).await.unwrap();
+        note
+    }
+
+    /// Reads are recorded in the background; waits for `count` readers.
+    async fn reads(app: &TestApp, owner: UserId, note: &NoteModel, count: usize) -> Value {
+        let uri = format!("/api/notes/{}/reads", note.id);
+        let token = app.token(owner);
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+            if app.state.notes.reads(note.id).await.unwrap().len() >= count {
+                break;
+            }
+        }
+        let (status, _, body) = app.send(Method::GET, &uri, Some(&token), None).await;
+        assert_eq!(status, StatusCode::OK);
+        body
+    }
+
+    #[tokio::test]
+    async fn repeated_reads_are_counted_on_one_receipt() {
+        let app = TestApp::with_accounts();
+        let (owner, reader, other) = (user(), user(), user());
+        let note = announcement(&app, owner).await;
+        let mut events = app.state.events.subscribe();
+        let opened = app.clock.now();
+
+        record(&app.state, &note, reader);
+        reads(&app, owner, &note, 1).await;
+        app.clock.advance(Duration::hours(1));
+        record(&app.state, &note, reader);
+        app.clock.advance(Duration::hours(1));
+        record(&app.state, &note, other);
+        let body = reads(&app, owner, &note, 2).await;
+
+        assert_eq!(body["results"], 2);
+        let first = &body["reads"][0];
+        assert_eq!(first["user_id"], json!(reader));
+        assert_eq!(first["read_count"], 2);
+        assert_eq!(first["first_read_at"], json!(opened));
+        assert_eq!(first["last_read_at"], json!(opened + Duration::hours(1)));
+        assert_eq!(body["reads"][1]["user_id"], json!(other));
+        assert_eq!(body["reads"][1]["read_count"], 1);
+
+        // Only each reader's first read is announced.
+        let mut announced = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            assert_eq!(event.kind, NoteEventKind::Read);
+            announced.push(event.at);
+        }
+        assert_eq!(announced, [opened, opened + Duration::hours(2)]);
+    }
+
+    #[tokio::test]
+    async fn owners_leave_no_receipt() {
+        let app = TestApp::with_accounts();
+        let owner = user();
+        let note = announcement(&app, owner).await;
+        let mut events = app.state.events.subscribe();
+
+        record(&app.state, &note, owner);
+        let body = reads(&app, owner, &note, 1).await;
+        assert_eq!(body["results"], 0);
+        assert!(events.try_recv().is_err());
+    }
+}
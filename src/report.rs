@@ -0,0 +1,451 @@
+//! User reports of published notes.
+//!
+//! Anyone can flag a published note with `POST /api/notes/:id/report`.
+//! Reports start `open`; moderators resolve them as `reviewed` (no action
+//! needed) or `actioned` (the note was unpublished or removed), which settles
+//! every open report on the same note. Moderators are alerted once a note
+//! collects enough open reports.
+
+use std::{net::SocketAddr, str::FromStr, sync::Arc};
+
+use axum::{
+    extract::{ConnectInfo, Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Extension, Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    alerts::Alert,
+    auth::NoteScope,
+    client_ip,
+    error::AppError,
+    events::NoteEventKind,
+    model::{text_enum, NoteId, Transition},
+    repository::DELETE_NOTE,
+    service::NoteService,
+    service_account::ServiceAccountPrincipal,
+    state::DbPool,
+    AppState,
+};
+
+/// Open reports on one note that trigger a moderator alert.
+const ALERT_THRESHOLD: i64 = 3;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportReason {
+    Spam,
+    Abuse,
+    Illegal,
+    Other,
+}
+
+impl ReportReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ReportReason::Spam => "spam",
+            ReportReason::Abuse => "abuse",
+            ReportReason::Illegal => "illegal",
+            ReportReason::Other => "other",
+        }
+    }
+}
+
+impl FromStr for ReportReason {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "spam" => Ok(ReportReason::Spam),
+            "abuse" => Ok(ReportReason::Abuse),
+            "illegal" => Ok(ReportReason::Illegal),
+            "other" => Ok(ReportReason::Other),
+            other => Err(format!("Unknown report reason '{}'", other)),
+        }
+    }
+}
+
+text_enum!(ReportReason);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportState {
+    Open,
+    /// Looked at, with no action needed.
+    Reviewed,
+    /// The note was unpublished or removed.
+    Actioned,
+}
+
+impl ReportState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReportState::Open => "open",
+            ReportState::Reviewed => "reviewed",
+            ReportState::Actioned => "actioned",
+        }
+    }
+}
+
+impl FromStr for ReportState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(ReportState::Open),
+            "reviewed" => Ok(ReportState::Reviewed),
+            "actioned" => Ok(ReportState::Actioned),
+            other => Err(format!(
+                "Unknown report state '{}', expected open, reviewed or actioned",
+                other
+            )),
+        }
+    }
+}
+
+text_enum!(ReportState);
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateReportSchema {
+    pub reason: ReportReason,
+    pub details: Option<String>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct ReportModel {
+    pub id: u64,
+    pub note_id: NoteId,
+    pub reason: ReportReason,
+    pub details: Option<String>,
+    pub reporter: Option<String>,
+    pub state: ReportState,
+    pub resolution: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub resolved_at: Option<DateTime<Utc>>,
+}
+
+/// Who filed a report: the service account, or the client address.
+fn reporter(
+    data: &AppState,
+    headers: &HeaderMap,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    principal: Option<&ServiceAccountPrincipal>,
+) -> Option<String> {
+    if let Some(principal) = principal {
+        return Some(format!("service-account:{}", principal.name));
+    }
+    client_ip::resolve(
+        headers,
+        peer.map(|ConnectInfo(addr)| addr),
+        data.settings.trust_forwarded_for,
+    )
+    .map(|ip| format!("ip:{}", ip))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/notes/{id}/report",
+    tag = "notes",
+    params(("id" = Uuid, Path, description = "Note ID")),
+    request_body = CreateReportSchema,
+    responses(
+        (status = 201, description = "The report was filed"),
+        (status = 404, description = "Note not found", body = ErrorResponse),
+    ),
+)]
+pub async fn report_note_handler(
+    Path(id): Path<uuid::Uuid>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+    headers: HeaderMap,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    principal: Option<Extension<ServiceAccountPrincipal>>,
+    Json(body): Json<CreateReportSchema>,
+) -> Result<impl IntoResponse, AppError> {
+    let note_id = NoteId::from(id);
+    scope.check(&data, note_id).await?;
+    let published = data.notes.find(note_id).await?.map(|note| note.published);
+    if !published.unwrap_or_default().is_published() {
+        return Err(AppError::NotFound(format!(
+            "Note with ID: {} not found",
+            id
+        )));
+    }
+
+    let reporter = reporter(&data, &headers, peer, principal.as_deref());
+    let details = body
+        .details
+        .map(|details| details.trim().chars().take(2000).collect::<String>())
+        .filter(|details| !details.is_empty());
+
+    let result = sqlx::query(
+        r#"INSERT INTO note_reports (note_id, reason, details, reporter, created_at) VALUES (?, ?, ?, ?, ?)"#,
+    )
+    .bind(note_id)
+    .bind(body.reason.as_str())
+    .bind(&details)
+    .bind(&reporter)
+    .bind(data.clock.now())
+    .execute(&data.db)
+    .await?;
+
+    let open: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM note_reports WHERE note_id = ? AND state = 'open'",
+    )
+    .bind(note_id)
+    .fetch_one(&data.db)
+    .await?;
+    if open == ALERT_THRESHOLD {
+        data.alerts.send(Alert {
+            kind: "report",
+            message: format!("Note {} has {} open reports", note_id, open),
+            context: json!({
+                "note_id": note_id,
+                "open_reports": open,
+                "latest_reason": body.reason,
+            }),
+            at: data.clock.now(),
+        });
+    }
+
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({
+            "status": "success",
+            "data": json!({
+                "report_id": result.last_insert_id(),
+            })
+        })),
+    ))
+}
+
+#[derive(Debug, Deserialize, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ReportListOptions {
+    pub state: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/reports",
+    tag = "admin",
+    params(ReportListOptions),
+    responses(
+        (status = 200, description = "Reports in the given state"),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn list_reports_handler(
+    Query(opts): Query<ReportListOptions>,
+    State(db): State<DbPool>,
+) -> Result<impl IntoResponse, AppError> {
+    let state = opts
+        .state
+        .as_deref()
+        .map(str::parse::<ReportState>)
+        .transpose()
+        .map_err(AppError::Validation)?
+        .unwrap_or(ReportState::Open);
+    let reports = sqlx::query_as::<_, ReportModel>(
+        "SELECT * FROM note_reports WHERE state = ? ORDER BY created_at, id LIMIT 100",
+    )
+    .bind(state)
+    .fetch_all(&*db)
+    .await?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "results": reports.len(),
+        "reports": reports,
+    })))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportAction {
+    Unpublish,
+    Remove,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ResolveReportSchema {
+    /// Omit to mark the reports reviewed without touching the note.
+    pub action: Option<ReportAction>,
+    pub resolution: Option<String>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/admin/reports/{id}/resolve",
+    tag = "admin",
+    params(("id" = u64, Path, description = "Report ID")),
+    request_body = ResolveReportSchema,
+    responses(
+        (status = 200, description = "The reports were resolved"),
+        (status = 404, description = "Report not found", body = ErrorResponse),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn resolve_report_handler(
+    Path(id): Path<u64>,
+    State(data): State<Arc<AppState>>,
+    Json(body): Json<ResolveReportSchema>,
+) -> Result<impl IntoResponse, AppError> {
+    let report = sqlx::query_as::<_, ReportModel>(
+        "SELECT * FROM note_reports WHERE id = ? AND state = 'open'",
+    )
+    .bind(id)
+    .fetch_optional(&data.db)
+    .await?;
+    let Some(report) = report else {
+        return Err(AppError::NotFound(format!(
+            "Open report with ID: {} not found",
+            id
+        )));
+    };
+
+    if let Some(ReportAction::Unpublish) = body.action {
+        // The moderator acts on a note they may not own, and only published
+        // notes are unpublished.
+        NoteService::new(&data, &NoteScope(None))
+            .moderate(report.note_id, Transition::Unpublish)
+            .await?;
+    }
+
+    let owner = data.notes.find_owner(report.note_id).await?.flatten();
+    let now = data.clock.now();
+    let mut tx = data.db.begin().await?;
+    let (state, events) = match body.action {
+        None => (ReportState::Reviewed, vec![]),
+        Some(ReportAction::Unpublish) => (ReportState::Actioned, vec![]),
+        Some(ReportAction::Remove) => {
+            sqlx::query(DELETE_NOTE)
+                .bind(report.note_id)
+                .execute(&mut tx)
+                .await?;
+            (ReportState::Actioned, vec![NoteEventKind::Deleted])
+        }
+    };
+    let resolved = sqlx::query(
+        "UPDATE note_reports SET state = ?, resolution = ?, resolved_at = ? WHERE note_id = ? AND state = 'open'",
+    )
+    .bind(state)
+    .bind(&body.resolution)
+    .bind(now)
+    .bind(report.note_id)
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    for kind in events {
+        data.events.publish(kind, report.note_id, owner, now);
+    }
+    tracing::info!(
+        note_id = %report.note_id,
+        reports = resolved.rows_affected(),
+        state = state.as_str(),
+        "Reports resolved",
+    );
+
+    Ok(Json(json!({
+        "status": "success",
+        "data": json!({
+            "note_id": report.note_id,
+            "state": state,
+            "resolved_reports": resolved.rows_affected(),
+        })
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Method, Request},
+        routing::get,
+        Router,
+    };
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    use crate::{
+        model::{BinaryId, UserId},
+        testing::{unreachable_pool, TestApp},
+    };
+
+    use super::*;
+
+    async fn create(app: &TestApp, token: Option<&str>, body: Value) -> String {
+        let (status, _, body) = app
+            .send(Method::POST, "/api/notes", token, Some(body))
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        body["data"]["note"]["id"].as_str().unwrap().to_string()
+    }
+
+    async fn report(app: &TestApp, token: Option<&str>, id: &str) -> StatusCode {
+        let uri = format!("/api/notes/{}/report", id);
+        let body = json!({ "reason": "spam" });
+        app.send(Method::POST, &uri, token, Some(body)).await.0
+    }
+
+    #[tokio::test]
+    async fn only_published_notes_can_be_reported() {
+        let app = TestApp::new();
+        let draft = create(&app, None, json!({ "title": "Draft", "content": "Wip" })).await;
+        assert_eq!(report(&app, None, &draft).await, StatusCode::NOT_FOUND);
+        let missing = uuid::Uuid::new_v4().to_string();
+        assert_eq!(report(&app, None, &missing).await, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn other_users_notes_cannot_be_reported() {
+        let app = TestApp::with_accounts();
+        let alice = app.token(UserId(BinaryId(uuid::Uuid::new_v4())));
+        let bob = app.token(UserId(BinaryId(uuid::Uuid::new_v4())));
+        let body = json!({ "title": "Public", "content": "Hello", "published": true });
+        let id = create(&app, Some(&alice), body).await;
+        assert_eq!(report(&app, Some(&bob), &id).await, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn reports_name_the_account_or_the_address() {
+        let app = TestApp::new();
+        let peer = Some(ConnectInfo(SocketAddr::from(([192, 0, 2, 7], 4000))));
+        let account = ServiceAccountPrincipal {
+            id: BinaryId(uuid::Uuid::new_v4()),
+            name: "ci".to_string(),
+            scopes: vec![],
+        };
+        let headers = HeaderMap::new();
+        assert_eq!(
+            reporter(&app.state, &headers, peer, Some(&account)).as_deref(),
+            Some("service-account:ci")
+        );
+        assert_eq!(
+            reporter(&app.state, &headers, peer, None).as_deref(),
+            Some("ip:192.0.2.7")
+        );
+    }
+
+    #[tokio::test]
+    async fn listing_reports_needs_only_the_pool() {
+        let app = Router::new()
+            .route("/reports", get(list_reports_handler))
+            .with_state(DbPool(unreachable_pool()));
+        let call = |uri: &str| {
+            let req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            app.clone().oneshot(req)
+        };
+
+        let response = call("/reports?state=bogus").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = call("/reports").await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
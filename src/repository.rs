@@ -0,0 +1,1370 @@
+//! Data access for the note handlers.
+//!
+//! Handlers go through the [`NoteRepository`] in `AppState` instead of running
+//! SQL themselves: notes, who owns them, their content, the categories they
+//! are filed under and the relations `?expand=` embeds.
+//! [`MySqlNoteRepository`] is the real thing; [`InMemoryNoteRepository`] keeps
+//! notes in a map so handlers can be exercised without a database.
+
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+    ops::Range,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Timelike, Utc};
+use sqlx::{mysql::MySqlPool, Executor, FromRow, MySql, QueryBuilder, Row};
+
+use crate::{
+    attachment::AttachmentStorage,
+    category::SELECT_CATEGORY_BY_NAME,
+    clipper::NoteSource,
+    clock::Clock,
+    compression::{self, StoredContent},
+    conditional,
+    error::AppError,
+    loader::{in_list, TrendingRank},
+    model::{BinaryId, CategoryModel, NoteId, NoteModel, NoteStatus, TagModel, UserId},
+    offload,
+    preview::preview,
+    read_receipt::{ReadReceipt, RECORD_READ, SELECT_READS},
+    revision,
+    schema::ExpectedNoteFields,
+};
+
+// Expired notes are left out by comparing `expires_at` with a bound time, that
+// of the app's clock rather than `NOW()`, so that a frozen clock freezes
+// expiry too.
+pub const SELECT_NOTES_PAGE: &str =
+    "SELECT * FROM notes WHERE deleted_at IS NULL AND (expires_at IS NULL OR expires_at > ?) ORDER by id LIMIT ? OFFSET ?";
+/// Keyset page: seeks on the primary key instead of skipping rows.
+pub const SELECT_NOTES_AFTER: &str =
+    "SELECT * FROM notes WHERE deleted_at IS NULL AND (expires_at IS NULL OR expires_at > ?) AND id > ? ORDER BY id LIMIT ?";
+pub const SELECT_USER_NOTES_PAGE: &str =
+    "SELECT * FROM notes WHERE user_id = ? AND deleted_at IS NULL AND (expires_at IS NULL OR expires_at > ?) ORDER BY id LIMIT ? OFFSET ?";
+pub const SELECT_USER_NOTES_AFTER: &str =
+    "SELECT * FROM notes WHERE user_id = ? AND deleted_at IS NULL AND (expires_at IS NULL OR expires_at > ?) AND id > ? ORDER BY id LIMIT ?";
+/// Completed by [`MySqlNoteRepository::list_filtered`] with the conditions of
+/// the filter, owner, cursor and paging.
+pub const SELECT_FILTERED_NOTES: &str = "SELECT notes.* FROM notes";
+/// Completed by [`MySqlNoteRepository::count_filtered`] like
+/// [`SELECT_FILTERED_NOTES`].
+pub const COUNT_FILTERED_NOTES: &str = "SELECT COUNT(*) FROM notes";
+const JOIN_TAGS: &str =
+    " JOIN note_tags ON note_tags.note_id = notes.id JOIN tags ON tags.id = note_tags.tag_id";
+/// Notes compressed at rest keep only an excerpt in `content`; `preview` is
+/// indexed too, as it was all they kept before excerpts. Notes also match on the
+/// text read from their attachments, scoring by the best one.
+pub const SEARCH_NOTES: &str = r#"SELECT *, MATCH (title, content, preview) AGAINST (?) + COALESCE((SELECT MAX(MATCH (attachment_text) AGAINST (?)) FROM attachments WHERE attachments.note_id = notes.id), 0) AS score FROM notes WHERE (MATCH (title, content, preview) AGAINST (?) OR id IN (SELECT note_id FROM attachments WHERE MATCH (attachment_text) AGAINST (?))) AND deleted_at IS NULL AND (expires_at IS NULL OR expires_at > ?) AND (? IS NULL OR user_id = ?) ORDER BY score DESC, id LIMIT ? OFFSET ?"#;
+/// Trashed notes are left to [`crate::trash`], and expired notes to
+/// [`crate::expiry`].
+pub const SELECT_NOTE_BY_ID: &str = "SELECT * FROM notes WHERE id = ? AND deleted_at IS NULL AND (expires_at IS NULL OR expires_at > ?)";
+/// Content is bound as four columns through [`StoredContent::bind`].
+pub const INSERT_NOTE: &str = r#"INSERT INTO notes (id,title,content,content_encoding,content_zstd,preview,category,category_id,published,published_at,status,expires_at,created_at,updated_at,user_id) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"#;
+/// Bumps `version`, and only matches the note at the bound version unless
+/// that is `NULL`.
+/// Conditional on the expected version and, for compare-and-set, on each
+/// expected field; a compressed note matches its content on the blob, as
+/// encoding is deterministic.
+pub const UPDATE_NOTE: &str = r#"UPDATE notes SET title = ?, content = ?, content_encoding = ?, content_zstd = ?, content_key = NULL, preview = ?, category = ?, category_id = ?, published = ?, published_at = ?, status = ?, expires_at = ?, version = version + 1 WHERE id = ? AND deleted_at IS NULL AND (expires_at IS NULL OR expires_at > ?) AND (? IS NULL OR version = ?) AND (? IS NULL OR title = ?) AND (? IS NULL OR content_encoding = 'plain' AND content = ? OR content_zstd = ? OR content_key = ?) AND (? IS NULL OR category <=> ?) AND (? IS NULL OR published = ?)"#;
+pub const DELETE_NOTE: &str = r#"DELETE FROM notes WHERE id = ?"#;
+/// `updated_at` is left alone: moving a note in and out of the trash does not
+/// change it.
+pub const TRASH_NOTE: &str =
+    "UPDATE notes SET deleted_at = ?, updated_at = updated_at WHERE id = ? AND deleted_at IS NULL";
+pub const RESTORE_NOTE: &str = "UPDATE notes SET deleted_at = NULL, updated_at = updated_at \
+    WHERE id = ? AND deleted_at IS NOT NULL AND (expires_at IS NULL OR expires_at > ?)";
+pub const SELECT_NOTE_OWNER: &str = "SELECT user_id FROM notes WHERE id = ?";
+pub const SELECT_STORED_CONTENT: &str =
+    "SELECT content_encoding, OCTET_LENGTH(content), version FROM notes \
+    WHERE id = ? AND deleted_at IS NULL AND (expires_at IS NULL OR expires_at > ?)";
+pub const SELECT_CONTENT_ZSTD: &str = "SELECT content_zstd FROM notes WHERE id = ? AND version = ?";
+pub const SELECT_CONTENT_KEY: &str = "SELECT content_key FROM notes WHERE id = ? AND version = ?";
+/// SUBSTRING positions are 1-based. Finds nothing once the note has moved on
+/// from `version`.
+pub const SELECT_CONTENT_SLICE: &str =
+    "SELECT SUBSTRING(CAST(content AS BINARY), ?, ?) FROM notes \
+    WHERE id = ? AND version = ? AND deleted_at IS NULL AND (expires_at IS NULL OR expires_at > ?)";
+
+#[derive(Debug)]
+pub enum WriteError {
+    /// A note with the same id exists, possibly in the trash.
+    AlreadyExists,
+    /// Another live note of the same owner has the title.
+    DuplicateTitle,
+    Database(sqlx::Error),
+}
+
+pub(crate) fn is_duplicate_key(err: &sqlx::Error, key: &str) -> bool {
+    err.as_database_error()
+        .map(|e| e.message())
+        .is_some_and(|message| message.contains("Duplicate entry") && message.contains(key))
+}
+
+impl From<sqlx::Error> for WriteError {
+    fn from(err: sqlx::Error) -> Self {
+        if is_duplicate_key(&err, "PRIMARY") {
+            WriteError::AlreadyExists
+        } else if is_duplicate_key(&err, "title") {
+            WriteError::DuplicateTitle
+        } else {
+            WriteError::Database(err)
+        }
+    }
+}
+
+impl From<WriteError> for AppError {
+    fn from(err: WriteError) -> Self {
+        match err {
+            WriteError::AlreadyExists => {
+                AppError::Conflict("Note with that ID already exists".to_string())
+            }
+            WriteError::DuplicateTitle => {
+                AppError::Conflict("Note with that title already exists".to_string())
+            }
+            WriteError::Database(err) => err.into(),
+        }
+    }
+}
+
+/// What the notes of a list must match; fields left `None` match every note.
+#[derive(Debug, Default, Clone)]
+pub struct NoteFilter {
+    /// Name of a tag of the note.
+    pub tag: Option<String>,
+    /// Name of the note's category; empty for uncategorized notes.
+    pub category: Option<String>,
+    pub published: Option<bool>,
+    pub status: Option<NoteStatus>,
+    /// Created at or after.
+    pub created_after: Option<DateTime<Utc>>,
+    /// Created before.
+    pub created_before: Option<DateTime<Utc>>,
+}
+
+impl NoteFilter {
+    pub fn is_empty(&self) -> bool {
+        self.tag.is_none()
+            && self.category.is_none()
+            && self.published.is_none()
+            && self.status.is_none()
+            && self.created_after.is_none()
+            && self.created_before.is_none()
+    }
+
+    fn matches(&self, note: &NoteModel, tags: Option<&Vec<String>>) -> bool {
+        let tagged = |tag: &String| {
+            tags.is_some_and(|names| {
+                names
+                    .iter()
+                    .any(|name| name.eq_ignore_ascii_case(tag.trim()))
+            })
+        };
+        let created_at = note.created_at.unwrap_or_default();
+        self.tag.as_ref().is_none_or(tagged)
+            && self
+                .category
+                .as_ref()
+                .is_none_or(|category| note.category.eq_ignore_ascii_case(category.trim()))
+            && self
+                .published
+                .is_none_or(|published| (note.published.is_published()) == published)
+            && self.status.is_none_or(|status| note.status == status)
+            && self.created_after.is_none_or(|after| created_at >= after)
+            && self.created_before.is_none_or(|before| created_at < before)
+    }
+}
+
+/// The content of a note as stored, from one version of it.
+pub enum StoredNoteContent {
+    /// `size` bytes of text, read a slice at a time.
+    Plain {
+        size: u64,
+        slices: Box<dyn ContentSlices>,
+    },
+    /// Content compressed with zstd, whole, from the table or from storage
+    /// when it is offloaded.
+    Compressed(Vec<u8>),
+}
+
+/// Slices of one version of a note's content, however many are read.
+#[async_trait]
+pub trait ContentSlices: Send {
+    /// The bytes in `range`, which lies within the content.
+    async fn read(&mut self, range: Range<u64>) -> Result<Vec<u8>, sqlx::Error>;
+
+    /// Lets go of whatever the slices hold once every slice is read.
+    async fn finish(self: Box<Self>) -> Result<(), sqlx::Error>;
+}
+
+/// A column note lists can be ordered by.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SortField {
+    #[default]
+    Id,
+    CreatedAt,
+    UpdatedAt,
+    Title,
+}
+
+impl SortField {
+    pub const SUPPORTED: [&'static str; 3] = ["created_at", "updated_at", "title"];
+
+    fn column(self) -> &'static str {
+        match self {
+            SortField::Id => "notes.id",
+            SortField::CreatedAt => "notes.created_at",
+            SortField::UpdatedAt => "notes.updated_at",
+            SortField::Title => "notes.title",
+        }
+    }
+}
+
+/// The order of a note list. Notes that sort equal are ordered by id, in the
+/// same direction.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NoteSort {
+    pub field: SortField,
+    pub descending: bool,
+}
+
+impl NoteSort {
+    /// Parses the `sort` and `order` query parameters. Only the columns in
+    /// [`SortField::SUPPORTED`] are accepted, so neither ever reaches the SQL.
+    pub fn parse(sort: Option<&str>, order: Option<&str>) -> Result<Self, String> {
+        let field = match sort.map(str::trim).unwrap_or_default() {
+            "" => SortField::Id,
+            "created_at" => SortField::CreatedAt,
+            "updated_at" => SortField::UpdatedAt,
+            "title" => SortField::Title,
+            other => {
+                return Err(format!(
+                    "Unknown sort '{}'. Supported: {}",
+                    other,
+                    SortField::SUPPORTED.join(", ")
+                ))
+            }
+        };
+        let descending = match order.map(str::trim).unwrap_or_default() {
+            "" | "asc" => false,
+            "desc" => true,
+            other => return Err(format!("Unknown order '{}'. Supported: asc, desc", other)),
+        };
+        Ok(Self { field, descending })
+    }
+
+    /// By ascending id, the order cursors page in.
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn order_by(&self) -> String {
+        let direction = if self.descending { "DESC" } else { "ASC" };
+        match self.field {
+            SortField::Id => format!(" ORDER BY notes.id {}", direction),
+            field => format!(
+                " ORDER BY {} {}, notes.id {}",
+                field.column(),
+                direction,
+                direction
+            ),
+        }
+    }
+
+    /// The order [`Self::order_by`] gives, for notes kept in memory. Titles
+    /// compare case-insensitively and missing timestamps first, as in MySQL.
+    fn compare(&self, a: &NoteModel, b: &NoteModel) -> Ordering {
+        let ordering = match self.field {
+            SortField::Id => Ordering::Equal,
+            SortField::CreatedAt => a.created_at.cmp(&b.created_at),
+            SortField::UpdatedAt => a.updated_at.cmp(&b.updated_at),
+            SortField::Title => a.title.to_lowercase().cmp(&b.title.to_lowercase()),
+        }
+        .then(a.id.cmp(&b.id));
+        if self.descending {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    }
+}
+
+/// Notes as the handlers see them. Only live notes are returned; trashed ones
+/// are left to [`crate::trash`].
+#[async_trait]
+pub trait NoteRepository: Send + Sync {
+    /// Notes by id, skipping `offset`; only those of `owner` when set.
+    async fn list_page(
+        &self,
+        owner: Option<UserId>,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<NoteModel>, sqlx::Error>;
+
+    /// Notes by id after `after`; only those of `owner` when set.
+    async fn list_after(
+        &self,
+        owner: Option<UserId>,
+        after: NoteId,
+        limit: usize,
+    ) -> Result<Vec<NoteModel>, sqlx::Error>;
+
+    /// Notes matching `filter` in `sort` order, paged by cursor when `after`
+    /// is set and by offset otherwise. Cursors only page the default order.
+    async fn list_filtered(
+        &self,
+        filter: &NoteFilter,
+        sort: NoteSort,
+        owner: Option<UserId>,
+        after: Option<NoteId>,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<NoteModel>, sqlx::Error>;
+
+    /// How many notes [`Self::list_filtered`] pages through.
+    async fn count_filtered(
+        &self,
+        filter: &NoteFilter,
+        owner: Option<UserId>,
+    ) -> Result<u64, sqlx::Error>;
+
+    /// Notes matching `q` with their relevance, most relevant first.
+    async fn search(
+        &self,
+        q: &str,
+        owner: Option<UserId>,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<(NoteModel, f64)>, sqlx::Error>;
+
+    async fn find(&self, id: NoteId) -> Result<Option<NoteModel>, sqlx::Error>;
+
+    async fn insert(&self, note: &NoteModel) -> Result<(), WriteError>;
+
+    /// Inserts every note of `notes`, or none of them. The inner error is
+    /// that of the first note that could not be inserted, by its index.
+    async fn insert_many(
+        &self,
+        notes: &[NoteModel],
+    ) -> Result<Result<(), (usize, WriteError)>, sqlx::Error>;
+
+    /// Writes the title, content, category and published flag of `note` and
+    /// records them as a new revision. `false` when there is no such live
+    /// note, or it is no longer at `expected_version` or no longer holds the
+    /// `expected` fields.
+    async fn update(
+        &self,
+        note: &NoteModel,
+        expected_version: Option<u32>,
+        expected: Option<&ExpectedNoteFields>,
+    ) -> Result<bool, WriteError>;
+
+    /// Moves a live note to the trash. `false` when there is no such note.
+    async fn trash(&self, id: NoteId, at: DateTime<Utc>) -> Result<bool, sqlx::Error>;
+
+    /// Moves every note of `ids` to the trash, or none of them. The inner
+    /// error is the index of the first that is not a live note.
+    async fn trash_many(
+        &self,
+        ids: &[NoteId],
+        at: DateTime<Utc>,
+    ) -> Result<Result<(), usize>, sqlx::Error>;
+
+    /// Brings note `id` back from the trash. `None` when there is no such
+    /// note in the trash, or it has expired since it was trashed.
+    async fn restore(&self, id: NoteId) -> Result<Option<NoteModel>, WriteError>;
+
+    /// Settles the pending reviews of note `id`, which left review at `at`.
+    async fn cancel_reviews(&self, id: NoteId, at: DateTime<Utc>) -> Result<(), sqlx::Error>;
+
+    /// The owner of note `id`, live or trashed; `None` when there is no such
+    /// note.
+    async fn find_owner(&self, id: NoteId) -> Result<Option<Option<UserId>>, sqlx::Error>;
+
+    /// The content of note `id`, live and not expired, for reading ranges of
+    /// it.
+    async fn content(&self, id: NoteId) -> Result<Option<StoredNoteContent>, sqlx::Error>;
+
+    /// The category of `owner` named `name`, ignoring case.
+    async fn find_category_by_name(
+        &self,
+        owner: Option<UserId>,
+        name: &str,
+    ) -> Result<Option<CategoryModel>, sqlx::Error>;
+
+    /// Positions in the trending list of the notes of `ids` that have one.
+    async fn trending(&self, ids: &[NoteId]) -> Result<HashMap<NoteId, TrendingRank>, sqlx::Error>;
+
+    /// Where the notes of `ids` that were clipped were clipped from.
+    async fn sources(&self, ids: &[NoteId]) -> Result<HashMap<NoteId, NoteSource>, sqlx::Error>;
+
+    /// The tags of the notes of `ids` that have any, by name.
+    async fn tags(&self, ids: &[NoteId]) -> Result<HashMap<NoteId, Vec<TagModel>>, sqlx::Error>;
+
+    /// Counts a read of note `id` by `reader` at `at`; `true` when it is
+    /// their first.
+    async fn record_read(
+        &self,
+        id: NoteId,
+        reader: UserId,
+        at: DateTime<Utc>,
+    ) -> Result<bool, sqlx::Error>;
+
+    /// Who has read note `id`, first reader first.
+    async fn reads(&self, id: NoteId) -> Result<Vec<ReadReceipt>, sqlx::Error>;
+}
+
+pub(crate) async fn insert_note<'e, E>(db: E, note: &NoteModel) -> Result<(), sqlx::Error>
+where
+    E: Executor<'e, Database = MySql>,
+{
+    let query = sqlx::query(INSERT_NOTE).bind(note.id).bind(&note.title);
+    StoredContent::encode(&note.content)
+        .bind(query)
+        .bind(&note.category)
+        .bind(note.category_id)
+        .bind(note.published)
+        .bind(note.published_at)
+        .bind(note.status)
+        .bind(note.expires_at)
+        .bind(note.created_at)
+        .bind(note.updated_at)
+        .bind(note.user_id)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Joins and conditions selecting the notes of `owner` (all when `None`) live
+/// at `now` that match `filter`, after a `SELECT ... FROM notes`.
+fn push_filter(
+    query: &mut QueryBuilder<'_, MySql>,
+    filter: &NoteFilter,
+    owner: Option<UserId>,
+    now: DateTime<Utc>,
+) {
+    if filter.tag.is_some() {
+        query.push(JOIN_TAGS);
+    }
+    query
+        .push(
+            " WHERE notes.deleted_at IS NULL AND (notes.expires_at IS NULL OR notes.expires_at > ",
+        )
+        .push_bind(now)
+        .push(")");
+    if let Some(tag) = &filter.tag {
+        query
+            .push(" AND tags.name = ")
+            .push_bind(tag.trim().to_string());
+    }
+    if let Some(category) = &filter.category {
+        query
+            .push(" AND notes.category = ")
+            .push_bind(category.trim().to_string());
+    }
+    if let Some(published) = filter.published {
+        query.push(" AND notes.published = ").push_bind(published);
+    }
+    if let Some(status) = filter.status {
+        query.push(" AND notes.status = ").push_bind(status);
+    }
+    if let Some(created_after) = filter.created_after {
+        query
+            .push(" AND notes.created_at >= ")
+            .push_bind(created_after);
+    }
+    if let Some(created_before) = filter.created_before {
+        query
+            .push(" AND notes.created_at < ")
+            .push_bind(created_before);
+    }
+    if let Some(owner) = owner {
+        query.push(" AND notes.user_id = ").push_bind(owner);
+    }
+}
+
+pub struct MySqlNoteRepository {
+    db: MySqlPool,
+    clock: Arc<dyn Clock>,
+    /// Holds the content of notes offloaded from the table.
+    storage: Arc<dyn AttachmentStorage>,
+}
+
+/// Slices of one version, each read on whichever pool connection is free, so
+/// a slow download holds no connection between them. A slice read after the
+/// note has changed fails instead of mixing versions.
+struct VersionSlices {
+    db: MySqlPool,
+    id: NoteId,
+    version: u32,
+    /// When the content was opened, for the note to stay readable to the end.
+    opened_at: DateTime<Utc>,
+}
+
+#[async_trait]
+impl ContentSlices for VersionSlices {
+    async fn read(&mut self, range: Range<u64>) -> Result<Vec<u8>, sqlx::Error> {
+        sqlx::query_scalar(SELECT_CONTENT_SLICE)
+            .bind(range.start + 1)
+            .bind(range.end - range.start)
+            .bind(self.id)
+            .bind(self.version)
+            .bind(self.opened_at)
+            .fetch_one(&self.db)
+            .await
+    }
+
+    async fn finish(self: Box<Self>) -> Result<(), sqlx::Error> {
+        Ok(())
+    }
+}
+
+impl MySqlNoteRepository {
+    /// Notes expire by the time of `clock`, and offloaded content is read
+    /// back from `storage`.
+    pub fn new(db: MySqlPool, clock: Arc<dyn Clock>, storage: Arc<dyn AttachmentStorage>) -> Self {
+        Self { db, clock, storage }
+    }
+
+    async fn reassemble(&self, mut notes: Vec<NoteModel>) -> Result<Vec<NoteModel>, sqlx::Error> {
+        offload::reassemble(self.storage.as_ref(), &mut notes).await?;
+        Ok(notes)
+    }
+}
+
+#[async_trait]
+impl NoteRepository for MySqlNoteRepository {
+    async fn list_page(
+        &self,
+        owner: Option<UserId>,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<NoteModel>, sqlx::Error> {
+        let query = match owner {
+            Some(owner) => sqlx::query_as::<_, NoteModel>(SELECT_USER_NOTES_PAGE).bind(owner),
+            None => sqlx::query_as::<_, NoteModel>(SELECT_NOTES_PAGE),
+        };
+        let notes = query
+            .bind(self.clock.now())
+            .bind(limit as u64)
+            .bind(offset as u64)
+            .fetch_all(&self.db)
+            .await?;
+        self.reassemble(notes).await
+    }
+
+    async fn list_after(
+        &self,
+        owner: Option<UserId>,
+        after: NoteId,
+        limit: usize,
+    ) -> Result<Vec<NoteModel>, sqlx::Error> {
+        let query = match owner {
+            Some(owner) => sqlx::query_as::<_, NoteModel>(SELECT_USER_NOTES_AFTER).bind(owner),
+            None => sqlx::query_as::<_, NoteModel>(SELECT_NOTES_AFTER),
+        };
+        let notes = query
+            .bind(self.clock.now())
+            .bind(after)
+            .bind(limit as u64)
+            .fetch_all(&self.db)
+            .await?;
+        self.reassemble(notes).await
+    }
+
+    async fn list_filtered(
+        &self,
+        filter: &NoteFilter,
+        sort: NoteSort,
+        owner: Option<UserId>,
+        after: Option<NoteId>,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<NoteModel>, sqlx::Error> {
+        let mut query = QueryBuilder::<MySql>::new(SELECT_FILTERED_NOTES);
+        push_filter(&mut query, filter, owner, self.clock.now());
+        if let Some(after) = after {
+            query.push(" AND notes.id > ").push_bind(after);
+        }
+        query
+            .push(sort.order_by())
+            .push(" LIMIT ")
+            .push_bind(limit as u64);
+        if after.is_none() {
+            query.push(" OFFSET ").push_bind(offset as u64);
+        }
+        let notes = query
+            .build_query_as::<NoteModel>()
+            .fetch_all(&self.db)
+            .await?;
+        self.reassemble(notes).await
+    }
+
+    async fn count_filtered(
+        &self,
+        filter: &NoteFilter,
+        owner: Option<UserId>,
+    ) -> Result<u64, sqlx::Error> {
+        let mut query = QueryBuilder::<MySql>::new(COUNT_FILTERED_NOTES);
+        push_filter(&mut query, filter, owner, self.clock.now());
+        let count: i64 = query.build().fetch_one(&self.db).await?.try_get(0)?;
+        Ok(count as u64)
+    }
+
+    async fn search(
+        &self,
+        q: &str,
+        owner: Option<UserId>,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<(NoteModel, f64)>, sqlx::Error> {
+        let mut found = sqlx::query(SEARCH_NOTES)
+            .bind(q)
+            .bind(q)
+            .bind(q)
+            .bind(q)
+            .bind(self.clock.now())
+            .bind(owner)
+            .bind(owner)
+            .bind(limit as u64)
+            .bind(offset as u64)
+            .try_map(|row| Ok((NoteModel::from_row(&row)?, row.try_get::<f64, _>("score")?)))
+            .fetch_all(&self.db)
+            .await?;
+        for (note, _) in &mut found {
+            if let Some(key) = &note.content_key {
+                note.content = offload::read(self.storage.as_ref(), key).await?;
+            }
+        }
+        Ok(found)
+    }
+
+    async fn find(&self, id: NoteId) -> Result<Option<NoteModel>, sqlx::Error> {
+        let note = sqlx::query_as::<_, NoteModel>(SELECT_NOTE_BY_ID)
+            .bind(id)
+            .bind(self.clock.now())
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(self.reassemble(Vec::from_iter(note)).await?.pop())
+    }
+
+    async fn insert(&self, note: &NoteModel) -> Result<(), WriteError> {
+        Ok(insert_note(&self.db, note).await?)
+    }
+
+    async fn insert_many(
+        &self,
+        notes: &[NoteModel],
+    ) -> Result<Result<(), (usize, WriteError)>, sqlx::Error> {
+        let mut tx = self.db.begin().await?;
+        for (index, note) in notes.iter().enumerate() {
+            if let Err(err) = insert_note(&mut tx, note).await {
+                return Ok(Err((index, err.into())));
+            }
+        }
+        tx.commit().await?;
+        Ok(Ok(()))
+    }
+
+    async fn update(
+        &self,
+        note: &NoteModel,
+        expected_version: Option<u32>,
+        expected: Option<&ExpectedNoteFields>,
+    ) -> Result<bool, WriteError> {
+        let unchecked = ExpectedNoteFields::default();
+        let expected = expected.unwrap_or(&unchecked);
+        let expected_zstd = expected.content.as_deref().and_then(|content| {
+            StoredContent::encode(content)
+                .compressed()
+                .map(|(compressed, _)| compressed.to_vec())
+        });
+        let expected_key = expected
+            .content
+            .as_deref()
+            .map(|content| offload::content_key(note.id, content));
+        let mut tx = self.db.begin().await?;
+        revision::begin_edit(&mut tx, note.id).await?;
+        let query = sqlx::query(UPDATE_NOTE).bind(&note.title);
+        let result = StoredContent::encode(&note.content)
+            .bind(query)
+            .bind(&note.category)
+            .bind(note.category_id)
+            .bind(note.published)
+            .bind(note.published_at)
+            .bind(note.status)
+            .bind(note.expires_at)
+            .bind(note.id)
+            .bind(self.clock.now())
+            .bind(expected_version)
+            .bind(expected_version)
+            .bind(&expected.title)
+            .bind(&expected.title)
+            .bind(&expected.content)
+            .bind(&expected.content)
+            .bind(expected_zstd)
+            .bind(expected_key)
+            .bind(&expected.category)
+            .bind(&expected.category)
+            .bind(expected.published)
+            .bind(expected.published)
+            .execute(&mut tx)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        revision::record(&mut tx, note.id).await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn trash(&self, id: NoteId, at: DateTime<Utc>) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(TRASH_NOTE)
+            .bind(at)
+            .bind(id)
+            .execute(&self.db)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn trash_many(
+        &self,
+        ids: &[NoteId],
+        at: DateTime<Utc>,
+    ) -> Result<Result<(), usize>, sqlx::Error> {
+        let mut tx = self.db.begin().await?;
+        for (index, &id) in ids.iter().enumerate() {
+            let result = sqlx::query(TRASH_NOTE)
+                .bind(at)
+                .bind(id)
+                .execute(&mut tx)
+                .await?;
+            if result.rows_affected() == 0 {
+                return Ok(Err(index));
+            }
+        }
+        tx.commit().await?;
+        Ok(Ok(()))
+    }
+
+    async fn restore(&self, id: NoteId) -> Result<Option<NoteModel>, WriteError> {
+        let now = self.clock.now();
+        let mut tx = self.db.begin().await?;
+        let result = sqlx::query(RESTORE_NOTE)
+            .bind(id)
+            .bind(now)
+            .execute(&mut tx)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Ok(None);
+        }
+        let note = sqlx::query_as::<_, NoteModel>(SELECT_NOTE_BY_ID)
+            .bind(id)
+            .bind(now)
+            .fetch_one(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(self.reassemble(vec![note]).await?.pop())
+    }
+
+    async fn cancel_reviews(&self, id: NoteId, at: DateTime<Utc>) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE note_reviews SET state = 'cancelled', decided_at = ? WHERE note_id = ? AND state = 'pending'",
+        )
+        .bind(at)
+        .bind(id)
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    async fn find_owner(&self, id: NoteId) -> Result<Option<Option<UserId>>, sqlx::Error> {
+        sqlx::query_scalar(SELECT_NOTE_OWNER)
+            .bind(id)
+            .fetch_optional(&self.db)
+            .await
+    }
+
+    async fn content(&self, id: NoteId) -> Result<Option<StoredNoteContent>, sqlx::Error> {
+        let now = self.clock.now();
+        loop {
+            let stored: Option<(String, i64, u32)> = sqlx::query_as(SELECT_STORED_CONTENT)
+                .bind(id)
+                .bind(now)
+                .fetch_optional(&self.db)
+                .await?;
+            let Some((encoding, size, version)) = stored else {
+                return Ok(None);
+            };
+            if encoding != compression::ZSTD {
+                return Ok(Some(StoredNoteContent::Plain {
+                    size: size as u64,
+                    slices: Box::new(VersionSlices {
+                        db: self.db.clone(),
+                        id,
+                        version,
+                        opened_at: now,
+                    }),
+                }));
+            }
+            let compressed = if encoding == offload::EXTERNAL {
+                let key: Option<String> = sqlx::query_scalar(SELECT_CONTENT_KEY)
+                    .bind(id)
+                    .bind(version)
+                    .fetch_optional(&self.db)
+                    .await?;
+                match key {
+                    Some(key) => Some(
+                        offload::load(self.storage.as_ref(), &key)
+                            .await
+                            .map_err(|err| sqlx::Error::Decode(Box::new(err)))?,
+                    ),
+                    None => None,
+                }
+            } else {
+                sqlx::query_scalar(SELECT_CONTENT_ZSTD)
+                    .bind(id)
+                    .bind(version)
+                    .fetch_optional(&self.db)
+                    .await?
+            };
+            // Otherwise it changed in between; look again.
+            if let Some(compressed) = compressed {
+                return Ok(Some(StoredNoteContent::Compressed(compressed)));
+            }
+        }
+    }
+
+    async fn find_category_by_name(
+        &self,
+        owner: Option<UserId>,
+        name: &str,
+    ) -> Result<Option<CategoryModel>, sqlx::Error> {
+        sqlx::query_as::<_, CategoryModel>(SELECT_CATEGORY_BY_NAME)
+            .bind(owner)
+            .bind(name)
+            .fetch_optional(&self.db)
+            .await
+    }
+
+    async fn trending(&self, ids: &[NoteId]) -> Result<HashMap<NoteId, TrendingRank>, sqlx::Error> {
+        #[derive(sqlx::FromRow)]
+        struct Row {
+            note_id: NoteId,
+            #[sqlx(flatten)]
+            rank: TrendingRank,
+        }
+
+        let rows = in_list(
+            "SELECT note_id, position, view_count FROM note_trending WHERE note_id IN",
+            ids,
+        )
+        .build_query_as::<Row>()
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.note_id, row.rank))
+            .collect())
+    }
+
+    async fn sources(&self, ids: &[NoteId]) -> Result<HashMap<NoteId, NoteSource>, sqlx::Error> {
+        #[derive(sqlx::FromRow)]
+        struct Row {
+            note_id: NoteId,
+            #[sqlx(flatten)]
+            source: NoteSource,
+        }
+
+        let rows = in_list(
+            "SELECT note_id, url, site_name, author, excerpt, clipped_at FROM note_sources WHERE note_id IN",
+            ids,
+        )
+        .build_query_as::<Row>()
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.note_id, row.source))
+            .collect())
+    }
+
+    async fn tags(&self, ids: &[NoteId]) -> Result<HashMap<NoteId, Vec<TagModel>>, sqlx::Error> {
+        #[derive(sqlx::FromRow)]
+        struct Row {
+            note_id: NoteId,
+            #[sqlx(flatten)]
+            tag: TagModel,
+        }
+
+        let mut query = in_list(
+            "SELECT note_tags.note_id, tags.id, tags.user_id, tags.name, tags.created_at FROM note_tags JOIN tags ON tags.id = note_tags.tag_id WHERE note_tags.note_id IN",
+            ids,
+        );
+        query.push(" ORDER BY tags.name");
+        let rows = query.build_query_as::<Row>().fetch_all(&self.db).await?;
+
+        let mut tags: HashMap<NoteId, Vec<TagModel>> = HashMap::new();
+        for row in rows {
+            tags.entry(row.note_id).or_default().push(row.tag);
+        }
+        Ok(tags)
+    }
+
+    async fn record_read(
+        &self,
+        id: NoteId,
+        reader: UserId,
+        at: DateTime<Utc>,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(RECORD_READ)
+            .bind(id)
+            .bind(reader)
+            .bind(at)
+            .bind(at)
+            .execute(&self.db)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn reads(&self, id: NoteId) -> Result<Vec<ReadReceipt>, sqlx::Error> {
+        sqlx::query_as::<_, ReadReceipt>(SELECT_READS)
+            .bind(id)
+            .fetch_all(&self.db)
+            .await
+    }
+}
+
+/// Slices of content copied out of [`InMemoryNoteRepository`].
+struct BufferedSlices(Vec<u8>);
+
+#[async_trait]
+impl ContentSlices for BufferedSlices {
+    async fn read(&mut self, range: Range<u64>) -> Result<Vec<u8>, sqlx::Error> {
+        Ok(self.0[range.start as usize..range.end as usize].to_vec())
+    }
+
+    async fn finish(self: Box<Self>) -> Result<(), sqlx::Error> {
+        Ok(())
+    }
+}
+
+/// Notes in a map, for tests. Search scores a note by how many of the query
+/// words it contains, a rough stand-in for MySQL full-text search. Revisions,
+/// trending positions and clip sources are not kept, and readers have no
+/// email as there are no accounts.
+pub struct InMemoryNoteRepository {
+    clock: Arc<dyn Clock>,
+    notes: Mutex<BTreeMap<NoteId, NoteModel>>,
+    tags: Mutex<HashMap<NoteId, Vec<TagModel>>>,
+    categories: Mutex<Vec<CategoryModel>>,
+    reads: Mutex<HashMap<NoteId, Vec<ReadReceipt>>>,
+}
+
+impl InMemoryNoteRepository {
+    /// `clock` stamps `updated_at`, as `ON UPDATE CURRENT_TIMESTAMP` does.
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock,
+            notes: Mutex::new(BTreeMap::new()),
+            tags: Mutex::new(HashMap::new()),
+            categories: Mutex::new(Vec::new()),
+            reads: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Attaches tag `name` to note `id`, standing in for `note_tags`.
+    pub fn tag(&self, id: NoteId, name: &str) {
+        let user_id = self
+            .notes
+            .lock()
+            .unwrap()
+            .get(&id)
+            .and_then(|note| note.user_id);
+        let mut tags = self.tags.lock().unwrap();
+        let tags = tags.entry(id).or_default();
+        tags.push(TagModel {
+            id: BinaryId::from(uuid::Uuid::new_v4()),
+            user_id,
+            name: name.to_string(),
+            created_at: self.clock.now(),
+        });
+        tags.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+
+    /// Adds a category, standing in for `categories`.
+    pub fn add_category(&self, category: CategoryModel) {
+        self.categories.lock().unwrap().push(category);
+    }
+
+    /// The names of the tags of each note, for filtering.
+    fn tag_names(&self) -> HashMap<NoteId, Vec<String>> {
+        self.tags
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, tags)| (*id, tags.iter().map(|tag| tag.name.clone()).collect()))
+            .collect()
+    }
+
+    /// Live notes visible to `owner`, by id, for which `keep` holds.
+    fn live(&self, owner: Option<UserId>, keep: impl Fn(&NoteModel) -> bool) -> Vec<NoteModel> {
+        let now = self.clock.now();
+        self.notes
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|note| note.deleted_at.is_none() && !note.is_expired(now))
+            .filter(|note| owner.is_none() || note.user_id == owner)
+            .filter(|note| keep(note))
+            .cloned()
+            .collect()
+    }
+
+    fn insert_into(
+        notes: &mut BTreeMap<NoteId, NoteModel>,
+        note: &NoteModel,
+    ) -> Result<(), WriteError> {
+        if notes.contains_key(&note.id) {
+            return Err(WriteError::AlreadyExists);
+        }
+        if Self::title_taken(notes, note) {
+            return Err(WriteError::DuplicateTitle);
+        }
+        notes.insert(note.id, note.clone());
+        Ok(())
+    }
+
+    fn title_taken(notes: &BTreeMap<NoteId, NoteModel>, note: &NoteModel) -> bool {
+        notes.values().any(|other| {
+            other.id != note.id
+                && other.deleted_at.is_none()
+                && other.user_id == note.user_id
+                && other.title.eq_ignore_ascii_case(&note.title)
+        })
+    }
+}
+
+#[async_trait]
+impl NoteRepository for InMemoryNoteRepository {
+    async fn list_page(
+        &self,
+        owner: Option<UserId>,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<NoteModel>, sqlx::Error> {
+        Ok(self
+            .live(owner, |_| true)
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .collect())
+    }
+
+    async fn list_after(
+        &self,
+        owner: Option<UserId>,
+        after: NoteId,
+        limit: usize,
+    ) -> Result<Vec<NoteModel>, sqlx::Error> {
+        Ok(self
+            .live(owner, |note| note.id > after)
+            .into_iter()
+            .take(limit)
+            .collect())
+    }
+
+    async fn list_filtered(
+        &self,
+        filter: &NoteFilter,
+        sort: NoteSort,
+        owner: Option<UserId>,
+        after: Option<NoteId>,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<NoteModel>, sqlx::Error> {
+        let tags = self.tag_names();
+        let mut matching = self.live(owner, |note| {
+            after.is_none_or(|after| note.id > after) && filter.matches(note, tags.get(&note.id))
+        });
+        matching.sort_by(|a, b| sort.compare(a, b));
+
+        let skip = if after.is_some() { 0 } else { offset };
+        Ok(matching.into_iter().skip(skip).take(limit).collect())
+    }
+
+    async fn count_filtered(
+        &self,
+        filter: &NoteFilter,
+        owner: Option<UserId>,
+    ) -> Result<u64, sqlx::Error> {
+        let tags = self.tag_names();
+        let matching = self.live(owner, |note| filter.matches(note, tags.get(&note.id)));
+        Ok(matching.len() as u64)
+    }
+
+    async fn search(
+        &self,
+        q: &str,
+        owner: Option<UserId>,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<(NoteModel, f64)>, sqlx::Error> {
+        let words = q
+            .split_whitespace()
+            .map(str::to_lowercase)
+            .collect::<Vec<_>>();
+        let mut hits = self
+            .live(owner, |_| true)
+            .into_iter()
+            .filter_map(|note| {
+                let text =
+                    format!("{} {} {}", note.title, note.content, note.preview).to_lowercase();
+                let score = words.iter().filter(|word| text.contains(*word)).count();
+                (score > 0).then_some((note, score as f64))
+            })
+            .collect::<Vec<_>>();
+        // Stable, so equal scores stay ordered by id.
+        hits.sort_by(|a, b| b.1.total_cmp(&a.1));
+        Ok(hits.into_iter().skip(offset).take(limit).collect())
+    }
+
+    async fn find(&self, id: NoteId) -> Result<Option<NoteModel>, sqlx::Error> {
+        Ok(self
+            .notes
+            .lock()
+            .unwrap()
+            .get(&id)
+            .filter(|note| note.deleted_at.is_none() && !note.is_expired(self.clock.now()))
+            .cloned())
+    }
+
+    async fn insert(&self, note: &NoteModel) -> Result<(), WriteError> {
+        Self::insert_into(&mut self.notes.lock().unwrap(), note)
+    }
+
+    async fn insert_many(
+        &self,
+        notes: &[NoteModel],
+    ) -> Result<Result<(), (usize, WriteError)>, sqlx::Error> {
+        let mut stored = self.notes.lock().unwrap();
+        let mut inserted = stored.clone();
+        for (index, note) in notes.iter().enumerate() {
+            if let Err(err) = Self::insert_into(&mut inserted, note) {
+                return Ok(Err((index, err)));
+            }
+        }
+        *stored = inserted;
+        Ok(Ok(()))
+    }
+
+    async fn update(
+        &self,
+        note: &NoteModel,
+        expected_version: Option<u32>,
+        expected: Option<&ExpectedNoteFields>,
+    ) -> Result<bool, WriteError> {
+        let now = self.clock.now();
+        let mut notes = self.notes.lock().unwrap();
+        if Self::title_taken(&notes, note) {
+            return Err(WriteError::DuplicateTitle);
+        }
+        let Some(stored) = notes.get_mut(&note.id).filter(|n| {
+            n.deleted_at.is_none()
+                && !n.is_expired(now)
+                && conditional::mismatches(n, expected, expected_version).is_empty()
+        }) else {
+            return Ok(false);
+        };
+        stored.title = note.title.clone();
+        stored.content = note.content.clone();
+        stored.preview = preview(&note.content);
+        stored.category = note.category.clone();
+        stored.category_id = note.category_id;
+        stored.published = note.published;
+        stored.published_at = note.published_at;
+        stored.status = note.status;
+        stored.expires_at = note.expires_at;
+        stored.version += 1;
+        stored.updated_at = Some(now.with_nanosecond(0).unwrap());
+        Ok(true)
+    }
+
+    async fn trash(&self, id: NoteId, at: DateTime<Utc>) -> Result<bool, sqlx::Error> {
+        let mut notes = self.notes.lock().unwrap();
+        match notes.get_mut(&id).filter(|note| note.deleted_at.is_none()) {
+            Some(note) => {
+                note.deleted_at = Some(at);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn trash_many(
+        &self,
+        ids: &[NoteId],
+        at: DateTime<Utc>,
+    ) -> Result<Result<(), usize>, sqlx::Error> {
+        let mut notes = self.notes.lock().unwrap();
+        let live = |note: &NoteModel| note.deleted_at.is_none();
+        if let Some(index) = ids.iter().position(|id| !notes.get(id).is_some_and(live)) {
+            return Ok(Err(index));
+        }
+        for id in ids {
+            notes.get_mut(id).unwrap().deleted_at = Some(at);
+        }
+        Ok(Ok(()))
+    }
+
+    async fn restore(&self, id: NoteId) -> Result<Option<NoteModel>, WriteError> {
+        let mut notes = self.notes.lock().unwrap();
+        let Some(trashed) = notes
+            .get(&id)
+            .filter(|note| note.deleted_at.is_some() && !note.is_expired(self.clock.now()))
+        else {
+            return Ok(None);
+        };
+        if Self::title_taken(&notes, trashed) {
+            return Err(WriteError::DuplicateTitle);
+        }
+        let note = notes.get_mut(&id).unwrap();
+        note.deleted_at = None;
+        Ok(Some(note.clone()))
+    }
+
+    async fn cancel_reviews(&self, _id: NoteId, _at: DateTime<Utc>) -> Result<(), sqlx::Error> {
+        Ok(())
+    }
+
+    async fn find_owner(&self, id: NoteId) -> Result<Option<Option<UserId>>, sqlx::Error> {
+        Ok(self.notes.lock().unwrap().get(&id).map(|note| note.user_id))
+    }
+
+    async fn content(&self, id: NoteId) -> Result<Option<StoredNoteContent>, sqlx::Error> {
+        Ok(self.find(id).await?.map(|note| StoredNoteContent::Plain {
+            size: note.content.len() as u64,
+            slices: Box::new(BufferedSlices(note.content.into_bytes())),
+        }))
+    }
+
+    async fn find_category_by_name(
+        &self,
+        owner: Option<UserId>,
+        name: &str,
+    ) -> Result<Option<CategoryModel>, sqlx::Error> {
+        Ok(self
+            .categories
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|category| category.user_id == owner && category.name.eq_ignore_ascii_case(name))
+            .cloned())
+    }
+
+    async fn trending(
+        &self,
+        _ids: &[NoteId],
+    ) -> Result<HashMap<NoteId, TrendingRank>, sqlx::Error> {
+        Ok(HashMap::new())
+    }
+
+    async fn sources(&self, _ids: &[NoteId]) -> Result<HashMap<NoteId, NoteSource>, sqlx::Error> {
+        Ok(HashMap::new())
+    }
+
+    async fn tags(&self, ids: &[NoteId]) -> Result<HashMap<NoteId, Vec<TagModel>>, sqlx::Error> {
+        let tags = self.tags.lock().unwrap();
+        Ok(ids
+            .iter()
+            .filter_map(|id| tags.get(id).map(|tags| (*id, tags.clone())))
+            .collect())
+    }
+
+    async fn record_read(
+        &self,
+        id: NoteId,
+        reader: UserId,
+        at: DateTime<Utc>,
+    ) -> Result<bool, sqlx::Error> {
+        let mut reads = self.reads.lock().unwrap();
+        let reads = reads.entry(id).or_default();
+        match reads.iter_mut().find(|read| read.user_id == reader) {
+            Some(read) => {
+                read.last_read_at = at;
+                read.read_count += 1;
+                Ok(false)
+            }
+            None => {
+                reads.push(ReadReceipt {
+                    user_id: reader,
+                    email: None,
+                    first_read_at: at,
+                    last_read_at: at,
+                    read_count: 1,
+                });
+                Ok(true)
+            }
+        }
+    }
+
+    async fn reads(&self, id: NoteId) -> Result<Vec<ReadReceipt>, sqlx::Error> {
+        let mut reads = self
+            .reads
+            .lock()
+            .unwrap()
+            .get(&id)
+            .cloned()
+            .unwrap_or_default();
+        reads.sort_by_key(|read| (read.first_read_at, read.user_id));
+        Ok(reads)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sort_defaults_to_ascending_id() {
+        let sort = NoteSort::parse(None, None).unwrap();
+        assert!(sort.is_default());
+        assert_eq!(NoteSort::parse(Some(" "), Some("asc")).unwrap(), sort);
+        assert_eq!(sort.order_by(), " ORDER BY notes.id ASC");
+    }
+
+    #[test]
+    fn sort_parses_supported_fields_and_orders() {
+        let sort = NoteSort::parse(Some("title"), Some("desc")).unwrap();
+        assert_eq!(
+            sort,
+            NoteSort {
+                field: SortField::Title,
+                descending: true,
+            }
+        );
+        assert!(!sort.is_default());
+        assert_eq!(
+            NoteSort::parse(Some("created_at"), None).unwrap().field,
+            SortField::CreatedAt
+        );
+        assert_eq!(
+            NoteSort::parse(Some("updated_at"), None).unwrap().field,
+            SortField::UpdatedAt
+        );
+        // A descending id order is no longer the cursor order.
+        assert!(!NoteSort::parse(None, Some("desc")).unwrap().is_default());
+    }
+
+    #[test]
+    fn sort_rejects_what_could_reach_the_sql() {
+        let err = NoteSort::parse(Some("content; DROP TABLE notes"), None).unwrap_err();
+        assert!(err.starts_with("Unknown sort"), "{}", err);
+        assert!(NoteSort::parse(Some("TITLE"), None).is_err());
+        assert!(NoteSort::parse(None, Some("sideways")).is_err());
+    }
+}
@@ -0,0 +1,95 @@
+//! Request IDs and request tracing.
+//!
+//! Every request gets an `x-request-id`, kept from the request when the
+//! client or a proxy sent one and generated otherwise, and sent back on the
+//! response. Each request runs in a `request` span carrying the ID, so
+//! everything logged while serving it, sqlx queries included (shown with
+//! `RUST_LOG=sqlx=info`), can be traced back to it. Error bodies include the
+//! ID as `request_id` for users to quote in support tickets.
+
+use axum::{
+    body::{self, Body},
+    http::{header, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Router,
+};
+use serde_json::Value;
+use tower_http::{
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, RequestId, SetRequestIdLayer},
+    trace::{DefaultOnResponse, TraceLayer},
+};
+use tracing::Level;
+use tracing_subscriber::EnvFilter;
+
+/// What is logged unless `RUST_LOG` says otherwise.
+const DEFAULT_FILTER: &str = "info,sqlx=warn";
+
+/// Installs the global tracing subscriber, which also picks up `log` records
+/// such as sqlx's. Call once, at startup.
+pub fn init_tracing() {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER)),
+        )
+        .init();
+}
+
+/// Wraps `app` so that every request is assigned an ID and traced under it.
+pub fn layer(app: Router) -> Router {
+    app.layer(middleware::from_fn(include_in_errors))
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(|req: &Request<Body>| {
+                    let id = req
+                        .extensions()
+                        .get::<RequestId>()
+                        .and_then(|id| id.header_value().to_str().ok())
+                        .unwrap_or_default();
+                    tracing::info_span!(
+                        "request",
+                        request_id = %id,
+                        method = %req.method(),
+                        path = %req.uri().path(),
+                        user_id = tracing::field::Empty,
+                        service_account = tracing::field::Empty,
+                    )
+                })
+                .on_response(DefaultOnResponse::new().level(Level::INFO)),
+        )
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+}
+
+/// Adds `request_id` to JSON error bodies.
+async fn include_in_errors<B>(req: Request<B>, next: Next<B>) -> Response {
+    let id = req
+        .extensions()
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .map(str::to_string);
+    let response = next.run(req).await;
+
+    let failed = response.status().is_client_error() || response.status().is_server_error();
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|value| value == "application/json");
+    let Some(id) = id.filter(|_| failed && is_json) else {
+        return response;
+    };
+
+    let (mut parts, body) = response.into_parts();
+    let Ok(bytes) = hyper::body::to_bytes(body).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let body = match serde_json::from_slice::<Value>(&bytes) {
+        Ok(Value::Object(mut object)) => {
+            object.insert("request_id".to_string(), Value::String(id));
+            parts.headers.remove(header::CONTENT_LENGTH);
+            Value::Object(object).to_string().into()
+        }
+        _ => bytes,
+    };
+    Response::from_parts(parts, body::boxed(body::Full::from(body)))
+}
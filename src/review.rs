@@ -0,0 +1,568 @@
+//! Review requests, the approval step of the editorial workflow.
+//!
+//! The owner of a draft asks users to review it with
+//! `POST /api/notes/:id/request-review`, which puts the note in review.
+//! Reviewers find their requests at `GET /api/me/reviews`, read the note at
+//! `GET /api/reviews/:id` and approve or reject it there, with an optional
+//! comment. The first rejection sends the note back to draft; once every
+//! reviewer has approved, the note is published. The owner approving or
+//! rejecting the note directly cancels the reviews still pending.
+//!
+//! Reviewers are users, and reach the notes they were asked to review
+//! whoever owns them. Note hooks hear of every request and decision through
+//! [`crate::hooks::NoteHook::after_review`], to notify reviewers and owners.
+
+use std::{fmt, str::FromStr, sync::Arc};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{mysql::MySqlRow, FromRow, MySql, MySqlPool, QueryBuilder, Row};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    auth::NoteScope,
+    error::AppError,
+    handler::{filter_db_record, page_offset, MAX_PAGE_SIZE},
+    model::{text_enum, NoteId, NoteModel, NoteStatus, Transition, UserId},
+    read_receipt,
+    service::NoteService,
+    validation::FieldErrors,
+    AppState,
+};
+
+/// Reviewers one request can name.
+const MAX_REVIEWERS: usize = 10;
+const MAX_COMMENT_CHARS: usize = 2000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReviewState {
+    Pending,
+    Approved,
+    Rejected,
+    /// The note left review before this reviewer decided.
+    Cancelled,
+}
+
+impl ReviewState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReviewState::Pending => "pending",
+            ReviewState::Approved => "approved",
+            ReviewState::Rejected => "rejected",
+            ReviewState::Cancelled => "cancelled",
+        }
+    }
+}
+
+impl fmt::Display for ReviewState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ReviewState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(ReviewState::Pending),
+            "approved" => Ok(ReviewState::Approved),
+            "rejected" => Ok(ReviewState::Rejected),
+            "cancelled" => Ok(ReviewState::Cancelled),
+            other => Err(format!(
+                "Unknown review state '{}', expected pending, approved, rejected or cancelled",
+                other
+            )),
+        }
+    }
+}
+
+text_enum!(ReviewState);
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct ReviewModel {
+    pub id: u64,
+    pub note_id: NoteId,
+    pub reviewer_id: UserId,
+    /// `None` when requested without a user account.
+    pub requested_by: Option<UserId>,
+    pub state: ReviewState,
+    pub comment: Option<String>,
+    pub requested_at: DateTime<Utc>,
+    pub decided_at: Option<DateTime<Utc>>,
+}
+
+/// A review in a reviewer's queue, with what they need to pick it.
+#[derive(Debug, Serialize)]
+pub struct QueuedReview {
+    #[serde(flatten)]
+    pub review: ReviewModel,
+    pub note_title: String,
+    pub note_preview: String,
+}
+
+impl<'r> FromRow<'r, MySqlRow> for QueuedReview {
+    fn from_row(row: &'r MySqlRow) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            review: ReviewModel::from_row(row)?,
+            note_title: row.try_get("note_title")?,
+            note_preview: row
+                .try_get::<Option<String>, _>("note_preview")?
+                .unwrap_or_default(),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RequestReviewSchema {
+    /// Users to review the note; those already reviewing it are skipped.
+    pub reviewer_ids: Vec<uuid::Uuid>,
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct ReviewDecisionSchema {
+    pub comment: Option<String>,
+}
+
+/// `state` is `pending` (the default), `approved`, `rejected` or
+/// `cancelled`.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ReviewQueueOptions {
+    pub state: Option<String>,
+    pub page: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+/// The signed-in user, whom reviews are assigned to.
+fn reviewer(scope: &NoteScope) -> Result<UserId, AppError> {
+    scope.owner().ok_or_else(|| {
+        AppError::Response(
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "status": "fail",
+                "message": "Reviews are assigned to user accounts",
+            })),
+        )
+    })
+}
+
+fn review_not_found(id: u64) -> AppError {
+    AppError::NotFound(format!("Review with ID: {} not found", id))
+}
+
+async fn note_reviews(
+    db: &MySqlPool,
+    note_id: NoteId,
+    state: Option<ReviewState>,
+) -> Result<Vec<ReviewModel>, sqlx::Error> {
+    sqlx::query_as::<_, ReviewModel>(
+        "SELECT * FROM note_reviews WHERE note_id = ? AND (? IS NULL OR state = ?) ORDER BY id",
+    )
+    .bind(note_id)
+    .bind(state)
+    .bind(state)
+    .fetch_all(db)
+    .await
+}
+
+/// The distinct users of `ids`, all of which must exist and none of which may
+/// own `note`.
+async fn check_reviewers(
+    db: &MySqlPool,
+    note: &NoteModel,
+    ids: &[uuid::Uuid],
+) -> Result<Vec<UserId>, AppError> {
+    let invalid =
+        |message: String| AppError::InvalidFields(FieldErrors::single("reviewer_ids", message));
+    let mut reviewers = Vec::new();
+    for id in ids.iter().copied().map(UserId::from) {
+        if !reviewers.contains(&id) {
+            reviewers.push(id);
+        }
+    }
+    if reviewers.is_empty() {
+        return Err(invalid("must name at least one reviewer".to_string()));
+    }
+    if reviewers.len() > MAX_REVIEWERS {
+        return Err(invalid(format!(
+            "must name at most {} reviewers",
+            MAX_REVIEWERS
+        )));
+    }
+    if note.user_id.is_some_and(|owner| reviewers.contains(&owner)) {
+        return Err(invalid(
+            "must not include the owner of the note".to_string(),
+        ));
+    }
+
+    let mut query = QueryBuilder::<MySql>::new("SELECT id FROM users WHERE id IN (");
+    let mut separated = query.separated(", ");
+    for reviewer in &reviewers {
+        separated.push_bind(*reviewer);
+    }
+    separated.push_unseparated(")");
+    let existing: Vec<UserId> = query
+        .build_query_as::<(UserId,)>()
+        .fetch_all(db)
+        .await?
+        .into_iter()
+        .map(|(id,)| id)
+        .collect();
+    if let Some(missing) = reviewers.iter().find(|id| !existing.contains(id)) {
+        return Err(invalid(format!("no user with ID {}", missing)));
+    }
+    Ok(reviewers)
+}
+
+/// Asks users to review the note, putting a draft in review. A note already
+/// in review gains the reviewers it does not have yet.
+#[utoipa::path(
+    post,
+    path = "/api/notes/{id}/request-review",
+    tag = "reviews",
+    params(("id" = Uuid, Path, description = "Note ID")),
+    request_body = RequestReviewSchema,
+    responses(
+        (status = 200, description = "The note, with its pending reviews"),
+        (status = 404, description = "Note not found", body = ErrorResponse),
+        (status = 409, description = "The note is neither a draft nor in review", body = ErrorResponse),
+        (status = 422, description = "Invalid reviewers", body = ErrorResponse),
+    ),
+)]
+pub async fn request_review_handler(
+    Path(id): Path<uuid::Uuid>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+    Json(body): Json<RequestReviewSchema>,
+) -> Result<impl IntoResponse, AppError> {
+    let note_id = NoteId::from(id);
+    scope.check(&data, note_id).await?;
+    let Some(note) = data.notes.find(note_id).await? else {
+        return Err(AppError::note_not_found(note_id));
+    };
+    let reviewers = check_reviewers(&data.db, &note, &body.reviewer_ids).await?;
+
+    let note = if note.status == NoteStatus::InReview {
+        note
+    } else {
+        NoteService::new(&data, &scope)
+            .transition(note_id, Transition::Submit)
+            .await?
+    };
+
+    let pending = note_reviews(&data.db, note_id, Some(ReviewState::Pending)).await?;
+    let now = data.clock.now();
+    let mut requested = Vec::new();
+    for reviewer in reviewers {
+        if pending.iter().any(|review| review.reviewer_id == reviewer) {
+            continue;
+        }
+        let result = sqlx::query(
+            "INSERT INTO note_reviews (note_id, reviewer_id, requested_by, requested_at) VALUES (?, ?, ?, ?)",
+        )
+        .bind(note_id)
+        .bind(reviewer)
+        .bind(scope.owner())
+        .bind(now)
+        .execute(&data.db)
+        .await?;
+        requested.push(result.last_insert_id());
+    }
+
+    let reviews = note_reviews(&data.db, note_id, Some(ReviewState::Pending)).await?;
+    for review in reviews
+        .iter()
+        .filter(|review| requested.contains(&review.id))
+    {
+        data.hooks.after_review(review);
+    }
+
+    Ok(Json(json!({
+        "status": "success",
+        "data": {
+            "note": filter_db_record(&note),
+            "reviews": reviews,
+        },
+    })))
+}
+
+/// Every review the note has had, oldest first.
+#[utoipa::path(
+    get,
+    path = "/api/notes/{id}/reviews",
+    tag = "reviews",
+    params(("id" = Uuid, Path, description = "Note ID")),
+    responses(
+        (status = 200, description = "The reviews of the note"),
+        (status = 404, description = "Note not found", body = ErrorResponse),
+    ),
+)]
+pub async fn note_reviews_handler(
+    Path(id): Path<uuid::Uuid>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let note_id = NoteId::from(id);
+    scope.check(&data, note_id).await?;
+    if data.notes.find(note_id).await?.is_none() {
+        return Err(AppError::note_not_found(note_id));
+    }
+    let reviews = note_reviews(&data.db, note_id, None).await?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "results": reviews.len(),
+        "reviews": reviews,
+    })))
+}
+
+/// The reviews assigned to the signed-in user, oldest request first. Notes
+/// in the trash are left out.
+#[utoipa::path(
+    get,
+    path = "/api/me/reviews",
+    tag = "reviews",
+    params(ReviewQueueOptions),
+    responses(
+        (status = 200, description = "A page of reviews"),
+        (status = 400, description = "Unknown state", body = ErrorResponse),
+        (status = 403, description = "Not signed in as a user", body = ErrorResponse),
+    ),
+)]
+pub async fn my_reviews_handler(
+    Query(opts): Query<ReviewQueueOptions>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = reviewer(&scope)?;
+    let state = opts
+        .state
+        .as_deref()
+        .map(str::parse::<ReviewState>)
+        .transpose()
+        .map_err(AppError::Validation)?
+        .unwrap_or(ReviewState::Pending);
+    let limit = opts.limit.unwrap_or(10).min(MAX_PAGE_SIZE);
+    let offset = page_offset(opts.page, limit)?;
+
+    let reviews = sqlx::query_as::<_, QueuedReview>(
+        r#"SELECT note_reviews.*, notes.title AS note_title, notes.preview AS note_preview
+        FROM note_reviews JOIN notes ON notes.id = note_reviews.note_id
+        WHERE note_reviews.reviewer_id = ? AND note_reviews.state = ? AND notes.deleted_at IS NULL
+            AND (notes.expires_at IS NULL OR notes.expires_at > ?)
+        ORDER BY note_reviews.requested_at, note_reviews.id LIMIT ? OFFSET ?"#,
+    )
+    .bind(user)
+    .bind(state)
+    .bind(data.clock.now())
+    .bind(limit as u64)
+    .bind(offset as u64)
+    .fetch_all(&data.db)
+    .await?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "results": reviews.len(),
+        "reviews": reviews,
+    })))
+}
+
+/// Review `id` of `user`, with its note. Reviews of other users, and of notes
+/// in the trash, are not found.
+async fn assigned_review(
+    data: &AppState,
+    user: UserId,
+    id: u64,
+) -> Result<(ReviewModel, NoteModel), AppError> {
+    let review = sqlx::query_as::<_, ReviewModel>(
+        "SELECT * FROM note_reviews WHERE id = ? AND reviewer_id = ?",
+    )
+    .bind(id)
+    .bind(user)
+    .fetch_optional(&data.db)
+    .await?
+    .ok_or_else(|| review_not_found(id))?;
+    let note = data
+        .notes
+        .find(review.note_id)
+        .await?
+        .ok_or_else(|| review_not_found(id))?;
+    Ok((review, note))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/reviews/{id}",
+    tag = "reviews",
+    params(("id" = u64, Path, description = "Review ID")),
+    responses(
+        (status = 200, description = "The review, with the note to review"),
+        (status = 403, description = "Not signed in as a user", body = ErrorResponse),
+        (status = 404, description = "Review not found", body = ErrorResponse),
+    ),
+)]
+pub async fn get_review_handler(
+    Path(id): Path<u64>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = reviewer(&scope)?;
+    let (review, note) = assigned_review(&data, user, id).await?;
+    read_receipt::record(&data, &note, user);
+
+    Ok(Json(json!({
+        "status": "success",
+        "data": {
+            "review": review,
+            "note": filter_db_record(&note),
+        },
+    })))
+}
+
+/// Records the reviewer's decision, then moves the note on: back to draft on
+/// a rejection, published once no review is pending.
+async fn decide(
+    data: &AppState,
+    scope: &NoteScope,
+    id: u64,
+    state: ReviewState,
+    body: Option<Json<ReviewDecisionSchema>>,
+) -> Result<impl IntoResponse, AppError> {
+    let (review, _) = assigned_review(data, reviewer(scope)?, id).await?;
+    let already_decided = || {
+        AppError::Conflict(format!(
+            "Review with ID: {} is already {}",
+            id, review.state
+        ))
+    };
+    if review.state != ReviewState::Pending {
+        return Err(already_decided());
+    }
+    let comment = body
+        .and_then(|Json(body)| body.comment)
+        .map(|comment| {
+            comment
+                .trim()
+                .chars()
+                .take(MAX_COMMENT_CHARS)
+                .collect::<String>()
+        })
+        .filter(|comment| !comment.is_empty());
+
+    let decided = sqlx::query(
+        "UPDATE note_reviews SET state = ?, comment = ?, decided_at = ? WHERE id = ? AND state = 'pending'",
+    )
+    .bind(state)
+    .bind(&comment)
+    .bind(data.clock.now())
+    .bind(id)
+    .execute(&data.db)
+    .await?;
+    if decided.rows_affected() == 0 {
+        return Err(already_decided());
+    }
+
+    // The reviewer acts on a note they may not own.
+    let unscoped = NoteScope(None);
+    let service = NoteService::new(data, &unscoped);
+    let note = match state {
+        ReviewState::Rejected => Some(
+            service
+                .transition(review.note_id, Transition::Reject)
+                .await?,
+        ),
+        _ => {
+            let pending =
+                note_reviews(&data.db, review.note_id, Some(ReviewState::Pending)).await?;
+            if pending.is_empty() {
+                Some(
+                    service
+                        .transition(review.note_id, Transition::Approve)
+                        .await?,
+                )
+            } else {
+                None
+            }
+        }
+    };
+    let note = match note {
+        Some(note) => note,
+        None => data
+            .notes
+            .find(review.note_id)
+            .await?
+            .ok_or_else(|| review_not_found(id))?,
+    };
+
+    let review = sqlx::query_as::<_, ReviewModel>("SELECT * FROM note_reviews WHERE id = ?")
+        .bind(id)
+        .fetch_one(&data.db)
+        .await?;
+    data.hooks.after_review(&review);
+
+    Ok(Json(json!({
+        "status": "success",
+        "data": {
+            "review": review,
+            "note": filter_db_record(&note),
+        },
+    })))
+}
+
+/// Approves the note; it is published once every reviewer has. Publishing
+/// screens it, so moderation can keep it in review.
+#[utoipa::path(
+    post,
+    path = "/api/reviews/{id}/approve",
+    tag = "reviews",
+    params(("id" = u64, Path, description = "Review ID")),
+    request_body(content = Option<ReviewDecisionSchema>),
+    responses(
+        (status = 200, description = "The review, with the note as it now stands"),
+        (status = 403, description = "Not signed in as a user", body = ErrorResponse),
+        (status = 404, description = "Review not found", body = ErrorResponse),
+        (status = 409, description = "The review was already decided", body = ErrorResponse),
+        (status = 422, description = "Rejected by moderation", body = ErrorResponse),
+    ),
+)]
+pub async fn approve_review_handler(
+    Path(id): Path<u64>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+    body: Option<Json<ReviewDecisionSchema>>,
+) -> Result<impl IntoResponse, AppError> {
+    decide(&data, &scope, id, ReviewState::Approved, body).await
+}
+
+/// Rejects the note, sending it back to draft and cancelling the other
+/// pending reviews.
+#[utoipa::path(
+    post,
+    path = "/api/reviews/{id}/reject",
+    tag = "reviews",
+    params(("id" = u64, Path, description = "Review ID")),
+    request_body(content = Option<ReviewDecisionSchema>),
+    responses(
+        (status = 200, description = "The review, with the note as it now stands"),
+        (status = 403, description = "Not signed in as a user", body = ErrorResponse),
+        (status = 404, description = "Review not found", body = ErrorResponse),
+        (status = 409, description = "The review was already decided", body = ErrorResponse),
+    ),
+)]
+pub async fn reject_review_handler(
+    Path(id): Path<u64>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+    body: Option<Json<ReviewDecisionSchema>>,
+) -> Result<impl IntoResponse, AppError> {
+    decide(&data, &scope, id, ReviewState::Rejected, body).await
+}
@@ -0,0 +1,420 @@
+//! Revision history of notes.
+//!
+//! Every edit records the saved state of the note as its next revision, in
+//! the transaction of the edit itself. A note edited for the first time
+//! also gets its state before the edit recorded as revision 1, so notes
+//! written before revisions existed keep their original. Revisions are
+//! listed at `GET /api/notes/:id/revisions`, and
+//! `GET /api/notes/:id/revisions/:rev/diff` shows what a revision changed in
+//! the content. `POST /api/notes/:id/restore-to?at=` saves the note as it
+//! was at a point in time, which is itself recorded as a new revision.
+//! Revisions are deleted with the note when it is purged.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+use similar::TextDiff;
+use sqlx::{mysql::MySqlRow, FromRow, MySql, Row, Transaction};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    attachment::AttachmentStorage,
+    auth::NoteScope,
+    error::AppError,
+    events::{NoteEvent, NoteEventKind},
+    handler::filter_db_record,
+    model::{read_content, NoteId, Title, UserId},
+    offload,
+    schema::{RestoreToOptions, UpdateNoteSchema},
+    service::NoteService,
+    AppState,
+};
+
+/// Locks the note so concurrent edits record their revisions in order.
+const LOCK_NOTE: &str = "SELECT id FROM notes WHERE id = ? AND deleted_at IS NULL FOR UPDATE";
+const RECORD_ORIGINAL: &str = r#"INSERT INTO note_revisions (note_id, rev, title, content, content_encoding, content_zstd, content_key, category, published, created_at) SELECT id, 1, title, content, content_encoding, content_zstd, content_key, category, published, COALESCE(updated_at, created_at, CURRENT_TIMESTAMP) FROM notes WHERE id = ? AND NOT EXISTS (SELECT 1 FROM note_revisions WHERE note_id = ?)"#;
+const RECORD_REVISION: &str = r#"INSERT INTO note_revisions (note_id, rev, title, content, content_encoding, content_zstd, content_key, category, published, created_at) SELECT id, (SELECT COALESCE(MAX(rev), 0) + 1 FROM note_revisions WHERE note_id = ?), title, content, content_encoding, content_zstd, content_key, category, published, COALESCE(updated_at, CURRENT_TIMESTAMP) FROM notes WHERE id = ?"#;
+pub const SELECT_REVISIONS: &str = r#"SELECT rev, title, category, published, OCTET_LENGTH(content) + IFNULL(OCTET_LENGTH(content_zstd), 0) AS stored_bytes, created_at FROM note_revisions WHERE note_id = ? ORDER BY rev DESC"#;
+/// The revision current at a point in time.
+const SELECT_REVISION_AT: &str =
+    "SELECT * FROM note_revisions WHERE note_id = ? AND created_at <= ? ORDER BY rev DESC LIMIT 1";
+/// A revision and the one before it, newest first.
+const SELECT_REVISION_PAIR: &str =
+    "SELECT * FROM note_revisions WHERE note_id = ? AND rev IN (?, ?) ORDER BY rev DESC";
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct RevisionSummary {
+    pub rev: u32,
+    pub title: String,
+    pub category: Option<String>,
+    pub published: bool,
+    /// Size of the content as stored, compressed or not.
+    pub stored_bytes: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A revision with its content decompressed.
+pub struct NoteRevision {
+    pub rev: u32,
+    pub title: Title,
+    /// Only the excerpt until [`NoteRevision::reassemble`] when `content_key`
+    /// is set.
+    pub content: String,
+    pub content_key: Option<String>,
+    pub category: Option<String>,
+    pub published: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+impl<'r> FromRow<'r, MySqlRow> for NoteRevision {
+    fn from_row(row: &'r MySqlRow) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            rev: row.try_get("rev")?,
+            title: row.try_get("title")?,
+            content: read_content(row)?,
+            content_key: row.try_get("content_key")?,
+            category: row.try_get("category")?,
+            published: row.try_get("published")?,
+            created_at: row.try_get("created_at")?,
+        })
+    }
+}
+
+impl NoteRevision {
+    /// Reads the content back from storage when it was offloaded.
+    async fn reassemble(mut self, storage: &dyn AttachmentStorage) -> Result<Self, sqlx::Error> {
+        if let Some(key) = &self.content_key {
+            self.content = offload::read(storage, key).await?;
+        }
+        Ok(self)
+    }
+}
+
+/// Locks note `id` for the rest of `tx` and, when it has no revisions yet,
+/// records its current state as revision 1. Call before changing the note.
+pub async fn begin_edit(tx: &mut Transaction<'_, MySql>, id: NoteId) -> Result<(), sqlx::Error> {
+    sqlx::query(LOCK_NOTE)
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await?;
+    sqlx::query(RECORD_ORIGINAL)
+        .bind(id)
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    Ok(())
+}
+
+/// Records the state of note `id` as its next revision. Call after changing
+/// the note, in the same transaction as [`begin_edit`].
+pub async fn record(tx: &mut Transaction<'_, MySql>, id: NoteId) -> Result<(), sqlx::Error> {
+    sqlx::query(RECORD_REVISION)
+        .bind(id)
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    Ok(())
+}
+
+/// 404s unless note `id` is live and visible in `scope`.
+async fn check_note(data: &AppState, scope: &NoteScope, id: NoteId) -> Result<(), AppError> {
+    sqlx::query_scalar::<_, Option<UserId>>(
+        "SELECT user_id FROM notes WHERE id = ? AND deleted_at IS NULL AND (expires_at IS NULL OR expires_at > ?)",
+    )
+    .bind(id)
+    .bind(data.clock.now())
+    .fetch_optional(&data.db)
+    .await?
+    .filter(|owner| scope.permits(*owner))
+    .map(|_| ())
+    .ok_or_else(|| AppError::note_not_found(id))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/notes/{id}/revisions",
+    tag = "notes",
+    params(("id" = Uuid, Path, description = "Note ID")),
+    responses(
+        (status = 200, description = "Revisions of the note, newest first; empty until it is first edited"),
+        (status = 404, description = "Note not found", body = ErrorResponse),
+    ),
+)]
+pub async fn list_revisions_handler(
+    Path(id): Path<uuid::Uuid>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let id = NoteId::from(id);
+    check_note(&data, &scope, id).await?;
+
+    let revisions = sqlx::query_as::<_, RevisionSummary>(SELECT_REVISIONS)
+        .bind(id)
+        .fetch_all(&data.db)
+        .await?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "results": revisions.len(),
+        "revisions": revisions,
+    })))
+}
+
+/// The content changes a revision made, as a unified diff against the
+/// revision before it (against empty content for revision 1).
+#[utoipa::path(
+    get,
+    path = "/api/notes/{id}/revisions/{rev}/diff",
+    tag = "notes",
+    params(
+        ("id" = Uuid, Path, description = "Note ID"),
+        ("rev" = u32, Path, description = "Revision number"),
+    ),
+    responses(
+        (status = 200, description = "The diff of the revision"),
+        (status = 404, description = "Note or revision not found", body = ErrorResponse),
+    ),
+)]
+pub async fn revision_diff_handler(
+    Path((id, rev)): Path<(uuid::Uuid, u32)>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let id = NoteId::from(id);
+    check_note(&data, &scope, id).await?;
+
+    let mut pair = sqlx::query_as::<_, NoteRevision>(SELECT_REVISION_PAIR)
+        .bind(id)
+        .bind(rev)
+        .bind(rev.saturating_sub(1))
+        .fetch_all(&data.db)
+        .await?
+        .into_iter();
+    let Some(revision) = pair.next().filter(|revision| revision.rev == rev) else {
+        return Err(AppError::NotFound(format!(
+            "Revision {} of note with ID: {} not found",
+            rev, id
+        )));
+    };
+    let revision = revision.reassemble(data.attachments.as_ref()).await?;
+    let previous = match pair.next() {
+        Some(previous) => Some(previous.reassemble(data.attachments.as_ref()).await?),
+        None => None,
+    };
+    let diff = content_diff(previous.as_ref(), &revision);
+
+    Ok(Json(json!({
+        "status": "success",
+        "data": {
+            "rev": rev,
+            "previous_rev": previous.map(|previous| previous.rev),
+            "diff": diff,
+        },
+    })))
+}
+
+/// What `revision` changed in the content since `previous`, or since empty
+/// content for the first revision.
+fn content_diff(previous: Option<&NoteRevision>, revision: &NoteRevision) -> String {
+    let old_header = match previous {
+        Some(previous) => format!("rev {}", previous.rev),
+        None => "/dev/null".to_string(),
+    };
+    let old_content = previous.map_or("", |previous| previous.content.as_str());
+    TextDiff::from_lines(old_content, revision.content.as_str())
+        .unified_diff()
+        .header(&old_header, &format!("rev {}", revision.rev))
+        .to_string()
+}
+
+/// Saves the note as it was at `at`, from the latest revision recorded by
+/// then. Notes never edited have no revisions to restore from.
+#[utoipa::path(
+    post,
+    path = "/api/notes/{id}/restore-to",
+    tag = "notes",
+    params(
+        ("id" = Uuid, Path, description = "Note ID"),
+        RestoreToOptions,
+    ),
+    responses(
+        (status = 200, description = "The note as restored", body = NoteResponse),
+        (status = 404, description = "Note not found, or no revision at that time", body = ErrorResponse),
+        (status = 409, description = "Another note now has the restored title, or its status does not allow the restored visibility", body = ErrorResponse),
+        (status = 422, description = "Invalid fields, or rejected by moderation", body = ErrorResponse),
+    ),
+)]
+pub async fn restore_to_handler(
+    Path(id): Path<uuid::Uuid>,
+    Query(opts): Query<RestoreToOptions>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let id = NoteId::from(id);
+    check_note(&data, &scope, id).await?;
+
+    let revision = sqlx::query_as::<_, NoteRevision>(SELECT_REVISION_AT)
+        .bind(id)
+        .bind(opts.at)
+        .fetch_optional(&data.db)
+        .await?
+        .ok_or_else(|| {
+            AppError::NotFound(format!(
+                "Note with ID: {} has no revision at {}",
+                id,
+                opts.at.to_rfc3339()
+            ))
+        })?
+        .reassemble(data.attachments.as_ref())
+        .await?;
+    let body = UpdateNoteSchema {
+        title: Some(String::from(revision.title)),
+        content: Some(revision.content),
+        category: revision.category,
+        published: Some(revision.published),
+        expires_at: None,
+        expected: None,
+    };
+    let note = NoteService::new(&data, &scope).restore(id, body).await?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "data": {
+            "note": filter_db_record(&note),
+            "restored_rev": revision.rev,
+        },
+    })))
+}
+
+/// Deletes the revisions of purged notes.
+pub fn spawn_note_cleanup(data: Arc<AppState>, mut events: broadcast::Receiver<NoteEvent>) {
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) if event.kind == NoteEventKind::Deleted => {
+                    let result = sqlx::query("DELETE FROM note_revisions WHERE note_id = ?")
+                        .bind(event.note_id)
+                        .execute(&data.db)
+                        .await;
+                    if let Err(err) = result {
+                        tracing::error!(
+                            error = ?err,
+                            note_id = %event.note_id,
+                            "Failed to delete note revisions",
+                        );
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!(skipped, "Revision cleanup skipped change events");
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        clock::Clock,
+        model::{BinaryId, CategoryModel, CategoryName},
+        testing::TestApp,
+    };
+
+    use super::*;
+
+    fn revision(rev: u32, content: &str) -> NoteRevision {
+        NoteRevision {
+            rev,
+            title: Title::parse("Recipe").unwrap(),
+            content: content.to_string(),
+            content_key: None,
+            category: None,
+            published: false,
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn diffs_against_the_revision_before() {
+        let first = revision(1, "flour\nsugar\n");
+        let second = revision(2, "flour\nhoney\n");
+
+        let diff = content_diff(Some(&first), &second);
+        assert!(diff.starts_with("--- rev 1\n+++ rev 2\n"), "{}", diff);
+        assert!(diff.contains("\n-sugar\n+honey\n"), "{}", diff);
+
+        let diff = content_diff(None, &first);
+        assert!(diff.starts_with("--- /dev/null\n+++ rev 1\n"), "{}", diff);
+        assert!(diff.contains("\n+flour\n+sugar\n"), "{}", diff);
+    }
+
+    async fn create(app: &TestApp, body: serde_json::Value) -> NoteId {
+        let (status, body) = app.post("/api/notes", body).await;
+        assert_eq!(status, axum::http::StatusCode::OK, "{}", body);
+        body["data"]["note"]["id"]
+            .as_str()
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    fn fields(title: &str, content: &str, category: Option<&str>) -> UpdateNoteSchema {
+        UpdateNoteSchema {
+            title: Some(title.to_string()),
+            content: Some(content.to_string()),
+            category: category.map(str::to_string),
+            published: Some(false),
+            expires_at: None,
+            expected: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn restoring_saves_the_revision_as_a_new_version() {
+        let app = TestApp::new();
+        app.notes.add_category(CategoryModel {
+            id: BinaryId(uuid::Uuid::new_v4()),
+            user_id: None,
+            name: CategoryName::parse("Baking").unwrap(),
+            created_at: app.clock.now(),
+        });
+        let body = json!({ "title": "Recipe", "content": "flour", "category": "Baking" });
+        let id = create(&app, body).await;
+        let unscoped = NoteScope(None);
+        let service = NoteService::new(&app.state, &unscoped);
+        service
+            .restore(id, fields("Edited", "honey", None))
+            .await
+            .unwrap();
+
+        let note = service
+            .restore(id, fields("Recipe", "flour", Some("Baking")))
+            .await
+            .unwrap();
+        assert_eq!(String::from(note.title), "Recipe");
+        assert_eq!(note.version, 3);
+        assert_eq!(note.category, "Baking");
+        assert!(note.updated_at.is_some_and(|at| at <= app.clock.now()));
+    }
+
+    #[tokio::test]
+    async fn restoring_a_deleted_category_leaves_the_note_uncategorized() {
+        let app = TestApp::new();
+        let id = create(&app, json!({ "title": "Recipe", "content": "flour" })).await;
+        let unscoped = NoteScope(None);
+        let note = NoteService::new(&app.state, &unscoped)
+            .restore(id, fields("Recipe", "flour", Some("Gone")))
+            .await
+            .unwrap();
+        assert_eq!(note.category, "");
+    }
+}
@@ -1,27 +1,347 @@
 use std::sync::Arc;
 
 use axum::{
-    routing::{get},
-    Router,
+    extract::DefaultBodyLimit,
+    middleware,
+    routing::{delete, get, post},
+    Extension, Router,
 };
+use utoipa::OpenApi;
+use utoipa_swagger_ui::SwaggerUi;
 
 use crate::{
+    admin::require_admin,
+    advisor::index_advisor_handler,
+    anomaly::{anomalies_handler, lift_throttles_handler, throttle_anomalies},
+    archive::export_zip_handler,
+    attachment::{
+        download_attachment_handler, list_attachments_handler, upload_attachment_handler,
+    },
+    auth::{authenticate_user, login_handler, register_handler},
+    bulk::{bulk_create_handler, bulk_delete_handler},
+    canary::{
+        create_canary_handler, delete_canary_handler, detect_canary_access, list_canaries_handler,
+    },
+    category::{
+        category_notes_handler, create_category_handler, delete_category_handler,
+        get_category_handler, list_categories_handler, rename_category_handler,
+    },
+    chaos::{get_chaos_handler, inject_faults, update_chaos_handler},
+    clipper::clip_handler,
+    collab::note_socket_handler,
+    conditional::put_note_handler,
+    consent::{
+        accept_policy_handler, list_policies_handler, policy_handler, publish_policy_handler,
+        require_consent,
+    },
+    content::note_content_handler,
+    context::attach,
+    dead_letter::{
+        get_dead_letter_handler, list_dead_letters_handler, replay_dead_letter_handler,
+        replay_dead_letters_handler, replay_failed_job_handler,
+    },
+    events::{poll_changes_handler, stream_changes_handler},
+    export::{export_html_handler, export_notes_handler},
+    graphql::{self, graphql_handler, playground_handler},
     handler::{
-        create_note_handler, delete_note_handler, edit_note_handler, get_note_handler,
-        health_checker_handler, note_list_handler,
+        approve_note_handler, archive_note_handler, create_note_handler, delete_note_handler,
+        edit_note_handler, get_note_handler, health_checker_handler, note_list_handler,
+        publish_note_handler, readiness_handler, reject_note_handler, search_notes_handler,
+        submit_note_handler, unarchive_note_handler, unpublish_note_handler,
+    },
+    http_client::http_clients_handler,
+    leader::leader_handler,
+    link_check::broken_links_handler,
+    link_preview::note_links_handler,
+    lock::locks_handler,
+    moderation::{
+        create_rule_handler, delete_rule_handler, list_rules_handler, resolve_entry_handler,
+        review_queue_handler,
+    },
+    note_index::note_index_handler,
+    openapi::ApiDoc,
+    operation::{cancel_operation_handler, get_operation_handler, operation_result_handler},
+    plugin::plugins_handler,
+    rate_limit::limit_rate,
+    read_receipt::note_reads_handler,
+    report::{list_reports_handler, report_note_handler, resolve_report_handler},
+    review::{
+        approve_review_handler, get_review_handler, my_reviews_handler, note_reviews_handler,
+        reject_review_handler, request_review_handler,
+    },
+    revision::{list_revisions_handler, restore_to_handler, revision_diff_handler},
+    scripting::{
+        activate_script_handler, create_script_handler, deactivate_script_handler,
+        list_scripts_handler, script_versions_handler, test_script_handler,
+    },
+    service_account::{
+        authenticate_service_account, create_service_account_handler,
+        list_service_accounts_handler, require_note_scopes, revoke_service_account_handler,
+        rotate_service_account_key_handler,
     },
+    signing_key::{jwks_handler, rotate_signing_key_handler},
+    single_flight::single_flight_handler,
+    summary::{category_facets_handler, note_stats_handler, trending_notes_handler},
+    tag::{
+        create_tag_handler, delete_tag_handler, get_tag_handler, list_tags_handler,
+        note_tags_handler, rename_tag_handler, set_note_tags_handler, tag_cloud_handler,
+    },
+    telemetry::{metrics_handler, track_requests},
+    trash::{list_trash_handler, purge_note_handler, restore_note_handler},
     AppState,
 };
 
 pub fn create_router(app_state: Arc<AppState>) -> Router {
-    Router::new()
+    let public = Router::new()
+        .route("/healthz", get(health_checker_handler))
+        .route("/readyz", get(readiness_handler))
         .route("/api/health", get(health_checker_handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/api/auth/register", post(register_handler))
+        .route("/api/auth/login", post(login_handler))
+        .route("/.well-known/jwks.json", get(jwks_handler))
+        .route("/api/policy", get(policy_handler))
+        .route("/api/policy/accept", post(accept_policy_handler));
+
+    let mut api = Router::new()
+        .route("/api/clip", post(clip_handler))
         .route("/api/notes", get(note_list_handler).post(create_note_handler))
+        .route(
+            "/api/notes/bulk",
+            post(bulk_create_handler).delete(bulk_delete_handler),
+        )
+        .route("/api/notes/stats", get(note_stats_handler))
+        .route("/api/notes/trending", get(trending_notes_handler))
+        .route("/api/notes/facets", get(category_facets_handler))
+        .route("/api/notes/index", get(note_index_handler))
+        .route("/api/notes/search", get(search_notes_handler))
+        .route("/api/notes/changes/poll", get(poll_changes_handler))
+        .route("/api/notes/stream", get(stream_changes_handler))
+        .route("/api/notes/trash", get(list_trash_handler))
         .route(
             "/api/notes/:id",
             get(get_note_handler)
+                .put(put_note_handler)
                 .patch(edit_note_handler)
                 .delete(delete_note_handler),
         )
+        .route("/api/notes/:id/content", get(note_content_handler))
+        .route("/api/notes/:id/export.html", get(export_html_handler))
+        .route("/api/notes/:id/links", get(note_links_handler))
+        .route("/api/notes/:id/publish", post(publish_note_handler))
+        .route("/api/notes/:id/unpublish", post(unpublish_note_handler))
+        .route("/api/notes/:id/submit", post(submit_note_handler))
+        .route("/api/notes/:id/approve", post(approve_note_handler))
+        .route("/api/notes/:id/reject", post(reject_note_handler))
+        .route("/api/notes/:id/archive", post(archive_note_handler))
+        .route("/api/notes/:id/unarchive", post(unarchive_note_handler))
+        .route(
+            "/api/notes/:id/request-review",
+            post(request_review_handler),
+        )
+        .route("/api/notes/:id/reviews", get(note_reviews_handler))
+        .route("/api/notes/:id/reads", get(note_reads_handler))
+        .route(
+            "/api/notes/:id/attachments",
+            get(list_attachments_handler)
+                .post(upload_attachment_handler)
+                // Uploads are held to ATTACHMENT_MAX_BYTES by the handler.
+                .layer(DefaultBodyLimit::disable()),
+        )
+        .route(
+            "/api/notes/:id/attachments/:aid",
+            get(download_attachment_handler),
+        )
+        .route("/api/me/reviews", get(my_reviews_handler))
+        .route("/api/reviews/:id", get(get_review_handler))
+        .route("/api/reviews/:id/approve", post(approve_review_handler))
+        .route("/api/reviews/:id/reject", post(reject_review_handler))
+        .route("/api/notes/:id/report", post(report_note_handler))
+        .route("/api/notes/:id/restore", post(restore_note_handler))
+        .route("/api/notes/:id/restore-to", post(restore_to_handler))
+        .route("/api/notes/:id/revisions", get(list_revisions_handler))
+        .route(
+            "/api/notes/:id/revisions/:rev/diff",
+            get(revision_diff_handler),
+        )
+        .route("/api/notes/:id/purge", delete(purge_note_handler))
+        .route("/api/notes/:id/ws", get(note_socket_handler))
+        .route(
+            "/api/notes/:id/tags",
+            get(note_tags_handler).put(set_note_tags_handler),
+        )
+        .route("/api/tags", get(list_tags_handler).post(create_tag_handler))
+        .route("/api/tags/cloud", get(tag_cloud_handler))
+        .route(
+            "/api/tags/:id",
+            get(get_tag_handler)
+                .patch(rename_tag_handler)
+                .delete(delete_tag_handler),
+        )
+        .route(
+            "/api/categories",
+            get(list_categories_handler).post(create_category_handler),
+        )
+        .route(
+            "/api/categories/:id",
+            get(get_category_handler)
+                .patch(rename_category_handler)
+                .delete(delete_category_handler),
+        )
+        .route("/api/categories/:id/notes", get(category_notes_handler))
+        .route("/api/exports", post(export_notes_handler))
+        .route("/api/export/zip", post(export_zip_handler))
+        .route("/api/operations/:id", get(get_operation_handler))
+        .route("/api/operations/:id/cancel", post(cancel_operation_handler))
+        .route("/api/operations/:id/result", get(operation_result_handler))
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            detect_canary_access,
+        ));
+    if !app_state.plugins.is_empty() {
+        api = api.merge(app_state.plugins.routes());
+    }
+    // GraphQL, added below, checks service-account scopes per operation.
+    api = api.route_layer(middleware::from_fn(require_note_scopes));
+
+    let mut admin = Router::new()
+        .route("/api/admin/db/index-advisor", get(index_advisor_handler))
+        .route("/api/admin/anomalies", get(anomalies_handler))
+        .route(
+            "/api/admin/anomalies/throttles",
+            delete(lift_throttles_handler),
+        )
+        .route("/api/admin/http-clients", get(http_clients_handler))
+        .route("/api/admin/leader", get(leader_handler))
+        .route("/api/admin/links/broken", get(broken_links_handler))
+        .route("/api/admin/locks", get(locks_handler))
+        .route("/api/admin/jwt-keys/rotate", post(rotate_signing_key_handler))
+        .route("/api/admin/plugins", get(plugins_handler))
+        .route(
+            "/api/admin/policies",
+            get(list_policies_handler).post(publish_policy_handler),
+        )
+        .route("/api/admin/single-flight", get(single_flight_handler))
+        .route(
+            "/api/admin/canaries",
+            get(list_canaries_handler).post(create_canary_handler),
+        )
+        .route("/api/admin/canaries/:id", delete(delete_canary_handler))
+        .route("/api/admin/dead-letters", get(list_dead_letters_handler))
+        .route(
+            "/api/admin/dead-letters/replay",
+            post(replay_dead_letters_handler),
+        )
+        .route("/api/admin/dead-letters/:id", get(get_dead_letter_handler))
+        .route(
+            "/api/admin/dead-letters/:id/replay",
+            post(replay_dead_letter_handler),
+        )
+        .route(
+            "/api/admin/dead-letters/jobs/:id/replay",
+            post(replay_failed_job_handler),
+        )
+        .route(
+            "/api/admin/moderation/rules",
+            get(list_rules_handler).post(create_rule_handler),
+        )
+        .route(
+            "/api/admin/moderation/rules/:id",
+            delete(delete_rule_handler),
+        )
+        .route("/api/admin/moderation/queue", get(review_queue_handler))
+        .route("/api/admin/reports", get(list_reports_handler))
+        .route(
+            "/api/admin/reports/:id/resolve",
+            post(resolve_report_handler),
+        )
+        .route(
+            "/api/admin/moderation/queue/:id/resolve",
+            post(resolve_entry_handler),
+        )
+        .route(
+            "/api/admin/scripts",
+            get(list_scripts_handler).post(create_script_handler),
+        )
+        .route("/api/admin/scripts/test", post(test_script_handler))
+        .route(
+            "/api/admin/scripts/:name",
+            delete(deactivate_script_handler),
+        )
+        .route(
+            "/api/admin/scripts/:name/versions",
+            get(script_versions_handler),
+        )
+        .route(
+            "/api/admin/scripts/:name/activate",
+            post(activate_script_handler),
+        )
+        .route(
+            "/api/admin/service-accounts",
+            get(list_service_accounts_handler).post(create_service_account_handler),
+        )
+        .route(
+            "/api/admin/service-accounts/:id",
+            delete(revoke_service_account_handler),
+        )
+        .route(
+            "/api/admin/service-accounts/:id/rotate-key",
+            post(rotate_service_account_key_handler),
+        );
+
+    if app_state.chaos.is_some() {
+        api = api.layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            inject_faults,
+        ));
+        admin = admin.route(
+            "/api/admin/chaos",
+            get(get_chaos_handler).put(update_chaos_handler),
+        );
+    }
+
+    let graphql = post(graphql_handler).layer(Extension(graphql::schema()));
+    api = if app_state.settings.graphql_playground {
+        api.route("/api/graphql", graphql.get(playground_handler))
+    } else {
+        api.route("/api/graphql", graphql)
+    };
+
+    api = api.merge(SwaggerUi::new("/api/docs").url("/api/openapi.json", ApiDoc::openapi()));
+
+    if !app_state.plugins.is_empty() {
+        admin = admin.merge(app_state.plugins.admin_routes());
+    }
+
+    let admin = admin.route_layer(middleware::from_fn_with_state(
+        app_state.clone(),
+        require_admin,
+    ));
+
+    public
+        .merge(api)
+        .merge(admin)
+        .layer(middleware::from_fn_with_state(app_state.clone(), attach))
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            require_consent,
+        ))
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            authenticate_user,
+        ))
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            limit_rate,
+        ))
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            authenticate_service_account,
+        ))
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            throttle_anomalies,
+        ))
+        .layer(middleware::from_fn(track_requests))
         .with_state(app_state)
 }
@@ -1,30 +1,187 @@
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize};
+use utoipa::{IntoParams, ToSchema};
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct FilterOptions {
     pub page: Option<usize>,
+    /// At most 100.
     pub limit: Option<usize>,
+    /// `next_cursor` of the previous page; takes precedence over `page`.
+    pub after: Option<String>,
+    pub expand: Option<String>,
+    /// List pages return `preview` instead of `content` unless set.
+    pub include_content: Option<bool>,
+    /// Only notes carrying the tag with this name.
+    pub tag: Option<String>,
+    /// Only notes in the category with this name; empty for uncategorized
+    /// notes.
+    pub category: Option<String>,
+    pub published: Option<bool>,
+    /// `draft`, `in_review`, `published` or `archived`.
+    pub status: Option<String>,
+    /// Only notes created at or after this time.
+    pub created_after: Option<DateTime<Utc>>,
+    /// Only notes created before this time.
+    pub created_before: Option<DateTime<Utc>>,
+    /// `created_at`, `updated_at` or `title`; notes are ordered by id
+    /// otherwise. Sorted pages are fetched by `page`, not by cursor.
+    pub sort: Option<String>,
+    /// `asc` (the default) or `desc`.
+    pub order: Option<String>,
+}
+
+/// Restricts a tag cloud to notes created in `[from, to)` and in `category`.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq, Hash, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TagCloudOptions {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub category: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SearchOptions {
+    pub q: Option<String>,
+    pub page: Option<usize>,
+    /// At most 100.
+    pub limit: Option<usize>,
+}
+
+/// `status` filters by the last link check: `ok`, `redirected` or `broken`.
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TrashOptions {
+    pub page: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CategoryNotesOptions {
+    pub page: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct LinkFilterOptions {
+    pub status: Option<String>,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RestoreToOptions {
+    /// An RFC 3339 timestamp.
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExpandOptions {
+    pub expand: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
-pub struct ParamOptions {
-    pub id: String,
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PollOptions {
+    pub since: Option<u64>,
+    pub wait: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// `status` is `open` (the default), `replayed` or `all`.
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DeadLetterOptions {
+    pub status: Option<String>,
+    pub limit: Option<usize>,
+}
+
+/// Byte range of a note's raw content, for clients that cannot send `Range`.
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ContentRangeOptions {
+    pub offset: Option<u64>,
+    pub len: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 pub struct CreateNoteSchema {
     pub title: String,
     pub content: String,
+    /// The name of a category of the note's owner; see `/api/categories`.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub category: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub published: Option<bool>,
+    /// When the note self-destructs; must be in the future.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 pub struct UpdateNoteSchema {
+    pub title: Option<String>,
+    pub content: Option<String>,
+    pub category: Option<String>,
+    /// Still accepted, but `POST /api/notes/{id}/publish` and `/unpublish`
+    /// are the way to change it. Like them, only drafts can be published.
+    pub published: Option<bool>,
+    /// A new expiry in the future, or `null` for the note not to expire.
+    #[serde(
+        default,
+        deserialize_with = "nullable",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schema(value_type = Option<DateTime<Utc>>)]
+    pub expires_at: Option<Option<DateTime<Utc>>>,
+    /// Current values the update is conditional on (compare-and-set).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected: Option<ExpectedNoteFields>,
+}
+
+/// Tells an explicit `null` (`Some(None)`) from a missing field (`None`).
+fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, ToSchema)]
+pub struct ExpectedNoteFields {
     pub title: Option<String>,
     pub content: Option<String>,
     pub category: Option<String>,
     pub published: Option<bool>,
-}
\ No newline at end of file
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct TagSchema {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct CategorySchema {
+    pub name: String,
+}
+
+/// The complete set of tag names a note should carry; missing tags are
+/// created.
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct NoteTagsSchema {
+    pub tags: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct ReplaySchema {
+    /// Dead letters to send again.
+    #[serde(default)]
+    pub ids: Vec<u64>,
+    /// Failed jobs to queue again.
+    #[serde(default)]
+    pub job_ids: Vec<u64>,
+}
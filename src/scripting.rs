@@ -0,0 +1,740 @@
+//! Admin-managed Rhai scripts bound to note lifecycle hooks.
+//!
+//! A script sees the incoming change as a `note` map (`title`, `content`,
+//! `category`, `published`; on updates only the fields being changed, plus the
+//! note `id`) and may rewrite it in place or refuse the write with
+//! `reject("reason")`:
+//!
+//! ```text
+//! if note.content.contains("invoice") { note.category = "billing"; }
+//! if note.title.starts_with("TMP") { reject("temporary notes are not allowed"); }
+//! ```
+//!
+//! What a script leaves in `note` is validated like the request was, so a
+//! script that empties the title or outgrows the content limit fails the
+//! write with a 422.
+//!
+//! Scripts are sandboxed: no I/O, and operations, call depth and data sizes
+//! are capped. A script that errors or exceeds a limit is skipped (the write
+//! goes ahead) so a broken rule cannot block every write.
+//!
+//! Every upload under a name is a new version and becomes the active one;
+//! older versions can be re-activated to roll back.
+
+use std::{
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use rhai::{
+    module_resolvers::DummyModuleResolver, Dynamic, Engine, EvalAltResult, Map, Scope, AST,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::mysql::MySqlPool;
+use utoipa::ToSchema;
+
+use crate::{
+    error::AppError,
+    hooks::{HookRejection, NoteHook},
+    model::{text_enum, NoteId},
+    schema::{CreateNoteSchema, UpdateNoteSchema},
+    state::DbPool,
+    AppState,
+};
+
+const MAX_OPERATIONS: u64 = 50_000;
+const MAX_STRING_SIZE: usize = 4 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ScriptHook {
+    BeforeCreate,
+    BeforeUpdate,
+}
+
+impl ScriptHook {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScriptHook::BeforeCreate => "before_create",
+            ScriptHook::BeforeUpdate => "before_update",
+        }
+    }
+}
+
+impl FromStr for ScriptHook {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "before_create" => Ok(ScriptHook::BeforeCreate),
+            "before_update" => Ok(ScriptHook::BeforeUpdate),
+            other => Err(format!("Unknown script hook '{}'", other)),
+        }
+    }
+}
+
+text_enum!(ScriptHook);
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct HookScript {
+    pub id: u64,
+    pub name: String,
+    pub version: u32,
+    pub hook: ScriptHook,
+    pub source: String,
+    pub active: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+struct LoadedScript {
+    name: String,
+    version: u32,
+    hook: ScriptHook,
+    ast: AST,
+}
+
+/// How one script run ended.
+#[derive(Debug, Serialize)]
+#[serde(tag = "outcome", rename_all = "lowercase")]
+pub enum ScriptOutcome {
+    Accepted { note: Value },
+    Rejected { message: String },
+    Failed { message: String },
+}
+
+fn sandboxed_engine() -> Engine {
+    let mut engine = Engine::new();
+    engine
+        // The default resolver loads `import`ed modules from disk.
+        .set_module_resolver(DummyModuleResolver::new())
+        .set_max_operations(MAX_OPERATIONS)
+        .set_max_call_levels(16)
+        .set_max_expr_depths(64, 32)
+        .set_max_string_size(MAX_STRING_SIZE)
+        .set_max_array_size(10_000)
+        .set_max_map_size(1_000)
+        .on_print(|text| tracing::info!(target: "script", "{}", text))
+        .on_debug(|text, _, _| tracing::debug!(target: "script", "{}", text));
+    engine.register_fn("reject", |reason: &str| -> Result<(), Box<EvalAltResult>> {
+        Err(Box::new(EvalAltResult::ErrorRuntime(
+            reason.into(),
+            rhai::Position::NONE,
+        )))
+    });
+    engine
+}
+
+fn create_map(note: &CreateNoteSchema) -> Map {
+    let mut map = Map::new();
+    map.insert("title".into(), note.title.clone().into());
+    map.insert("content".into(), note.content.clone().into());
+    map.insert(
+        "category".into(),
+        note.category.clone().map_or(Dynamic::UNIT, Dynamic::from),
+    );
+    map.insert(
+        "published".into(),
+        note.published.map_or(Dynamic::UNIT, Dynamic::from),
+    );
+    map
+}
+
+fn update_map(changes: &UpdateNoteSchema) -> Map {
+    let mut map = Map::new();
+    if let Some(title) = &changes.title {
+        map.insert("title".into(), title.clone().into());
+    }
+    if let Some(content) = &changes.content {
+        map.insert("content".into(), content.clone().into());
+    }
+    if let Some(category) = &changes.category {
+        map.insert("category".into(), category.clone().into());
+    }
+    if let Some(published) = changes.published {
+        map.insert("published".into(), published.into());
+    }
+    map
+}
+
+fn string_field(map: &Map, field: &str) -> Result<Option<String>, String> {
+    match map.get(field) {
+        None => Ok(None),
+        Some(value) if value.is_unit() => Ok(None),
+        Some(value) => value
+            .clone()
+            .into_string()
+            .map(Some)
+            .map_err(|_| format!("note.{} must be a string", field)),
+    }
+}
+
+fn bool_field(map: &Map, field: &str) -> Result<Option<bool>, String> {
+    match map.get(field) {
+        None => Ok(None),
+        Some(value) if value.is_unit() => Ok(None),
+        Some(value) => value
+            .as_bool()
+            .map(Some)
+            .map_err(|_| format!("note.{} must be a boolean", field)),
+    }
+}
+
+pub struct ScriptHooks {
+    engine: Engine,
+    scripts: RwLock<Vec<Arc<LoadedScript>>>,
+}
+
+impl Default for ScriptHooks {
+    fn default() -> Self {
+        Self {
+            engine: sandboxed_engine(),
+            scripts: RwLock::new(Vec::new()),
+        }
+    }
+}
+
+impl ScriptHooks {
+    pub fn compile(&self, source: &str) -> Result<AST, String> {
+        self.engine
+            .compile(source)
+            .map_err(|err| format!("Invalid script: {}", err))
+    }
+
+    pub async fn reload(&self, db: &MySqlPool) -> Result<(), sqlx::Error> {
+        let scripts = sqlx::query_as::<_, HookScript>(
+            "SELECT * FROM hook_scripts WHERE active = 1 ORDER BY name",
+        )
+        .fetch_all(db)
+        .await?;
+        let loaded = scripts
+            .into_iter()
+            .filter_map(|script| {
+                let loaded = LoadedScript {
+                    hook: script.hook,
+                    ast: self.compile(&script.source).ok()?,
+                    name: script.name,
+                    version: script.version,
+                };
+                Some(Arc::new(loaded))
+            })
+            .collect();
+        *self.scripts.write().unwrap() = loaded;
+        Ok(())
+    }
+
+    fn scripts_for(&self, hook: ScriptHook) -> Vec<Arc<LoadedScript>> {
+        self.scripts
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|script| script.hook == hook)
+            .cloned()
+            .collect()
+    }
+
+    /// Runs `ast` against `note`, returning the possibly rewritten map.
+    pub fn run(&self, ast: &AST, note: Map, id: Option<NoteId>) -> (ScriptOutcome, Map) {
+        let mut scope = Scope::new();
+        scope.push("note", note.clone());
+        if let Some(id) = id {
+            scope.push_constant("id", id.to_string());
+        }
+        match self.engine.run_ast_with_scope(&mut scope, ast) {
+            Ok(()) => match scope.get_value::<Map>("note") {
+                Some(note) => {
+                    let value = serde_json::to_value(Dynamic::from_map(note.clone()))
+                        .unwrap_or(Value::Null);
+                    (ScriptOutcome::Accepted { note: value }, note)
+                }
+                None => (
+                    ScriptOutcome::Failed {
+                        message: "note must remain a map".to_string(),
+                    },
+                    note,
+                ),
+            },
+            Err(err) => match *err {
+                EvalAltResult::ErrorRuntime(reason, _) => (
+                    ScriptOutcome::Rejected {
+                        message: reason.to_string(),
+                    },
+                    note,
+                ),
+                err => (
+                    ScriptOutcome::Failed {
+                        message: err.to_string(),
+                    },
+                    note,
+                ),
+            },
+        }
+    }
+
+    /// Runs every active script for `hook` in name order, threading the map
+    /// through them.
+    fn run_all(
+        &self,
+        hook: ScriptHook,
+        mut note: Map,
+        id: Option<NoteId>,
+    ) -> Result<Map, HookRejection> {
+        for script in self.scripts_for(hook) {
+            let (outcome, rewritten) = self.run(&script.ast, note.clone(), id);
+            match outcome {
+                ScriptOutcome::Accepted { .. } => note = rewritten,
+                ScriptOutcome::Rejected { message } => {
+                    return Err(HookRejection::new(message));
+                }
+                ScriptOutcome::Failed { message } => {
+                    tracing::error!(
+                        script = %script.name,
+                        version = script.version,
+                        error = %message,
+                        "Script failed, skipping",
+                    )
+                }
+            }
+        }
+        Ok(note)
+    }
+}
+
+#[async_trait]
+impl NoteHook for ScriptHooks {
+    fn name(&self) -> &'static str {
+        "scripts"
+    }
+
+    async fn before_create(&self, note: &mut CreateNoteSchema) -> Result<(), HookRejection> {
+        let map = self.run_all(ScriptHook::BeforeCreate, create_map(note), None)?;
+        let title = string_field(&map, "title").map_err(HookRejection::new)?;
+        let content = string_field(&map, "content").map_err(HookRejection::new)?;
+        note.title = title.ok_or_else(|| HookRejection::new("note.title is required"))?;
+        note.content = content.ok_or_else(|| HookRejection::new("note.content is required"))?;
+        note.category = string_field(&map, "category").map_err(HookRejection::new)?;
+        note.published = bool_field(&map, "published").map_err(HookRejection::new)?;
+        Ok(())
+    }
+
+    async fn before_update(
+        &self,
+        id: NoteId,
+        changes: &mut UpdateNoteSchema,
+    ) -> Result<(), HookRejection> {
+        let map = self.run_all(ScriptHook::BeforeUpdate, update_map(changes), Some(id))?;
+        changes.title = string_field(&map, "title").map_err(HookRejection::new)?;
+        changes.content = string_field(&map, "content").map_err(HookRejection::new)?;
+        changes.category = string_field(&map, "category").map_err(HookRejection::new)?;
+        changes.published = bool_field(&map, "published").map_err(HookRejection::new)?;
+        Ok(())
+    }
+}
+
+pub fn spawn_reloader(db: MySqlPool, scripts: Arc<ScriptHooks>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(err) = scripts.reload(&db).await {
+                tracing::error!(error = ?err, "Failed to reload hook scripts");
+            }
+        }
+    });
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/scripts",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Active scripts"),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn list_scripts_handler(State(db): State<DbPool>) -> Result<impl IntoResponse, AppError> {
+    let scripts = sqlx::query_as::<_, HookScript>(
+        "SELECT * FROM hook_scripts WHERE active = 1 ORDER BY name",
+    )
+    .fetch_all(&*db)
+    .await?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "results": scripts.len(),
+        "scripts": scripts,
+    })))
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateScriptSchema {
+    pub name: String,
+    pub hook: ScriptHook,
+    pub source: String,
+}
+
+/// Uploads a new version of a script and makes it the active one.
+#[utoipa::path(
+    post,
+    path = "/api/admin/scripts",
+    tag = "admin",
+    request_body = CreateScriptSchema,
+    responses(
+        (status = 201, description = "The new script version"),
+        (status = 400, description = "The script does not compile", body = ErrorResponse),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn create_script_handler(
+    State(data): State<Arc<AppState>>,
+    Json(body): Json<CreateScriptSchema>,
+) -> Result<impl IntoResponse, AppError> {
+    let name = body.name.trim();
+    if name.is_empty() || name.len() > 100 {
+        return Err(AppError::Validation(
+            "Name must be between 1 and 100 characters".to_string(),
+        ));
+    }
+    data.scripts
+        .compile(&body.source)
+        .map_err(AppError::Validation)?;
+
+    let mut tx = data.db.begin().await?;
+    let latest: Option<u32> =
+        sqlx::query_scalar("SELECT MAX(version) FROM hook_scripts WHERE name = ? FOR UPDATE")
+            .bind(name)
+            .fetch_one(&mut tx)
+            .await?;
+    let version = latest.unwrap_or(0) + 1;
+    sqlx::query("UPDATE hook_scripts SET active = 0 WHERE name = ?")
+        .bind(name)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query(
+        "INSERT INTO hook_scripts (name, version, hook, source, active, created_at) VALUES (?, ?, ?, ?, 1, ?)",
+    )
+    .bind(name)
+    .bind(version)
+    .bind(body.hook.as_str())
+    .bind(&body.source)
+    .bind(data.clock.now())
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    data.scripts.reload(&data.db).await?;
+    tracing::info!(script = name, version, "Script activated");
+
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({
+            "status": "success",
+            "data": json!({ "name": name, "version": version })
+        })),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/scripts/{name}/versions",
+    tag = "admin",
+    params(("name" = String, Path, description = "Script name")),
+    responses(
+        (status = 200, description = "Every version of the script"),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn script_versions_handler(
+    Path(name): Path<String>,
+    State(db): State<DbPool>,
+) -> Result<impl IntoResponse, AppError> {
+    let versions = sqlx::query_as::<_, HookScript>(
+        "SELECT * FROM hook_scripts WHERE name = ? ORDER BY version DESC",
+    )
+    .bind(&name)
+    .fetch_all(&*db)
+    .await?;
+    if versions.is_empty() {
+        return Err(AppError::NotFound(format!("Script '{}' not found", name)));
+    }
+
+    Ok(Json(json!({
+        "status": "success",
+        "results": versions.len(),
+        "versions": versions,
+    })))
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ActivateScriptSchema {
+    pub version: u32,
+}
+
+/// Makes an existing version the active one, e.g. to roll back.
+#[utoipa::path(
+    post,
+    path = "/api/admin/scripts/{name}/activate",
+    tag = "admin",
+    params(("name" = String, Path, description = "Script name")),
+    request_body = ActivateScriptSchema,
+    responses(
+        (status = 200, description = "The activated version"),
+        (status = 404, description = "Version not found", body = ErrorResponse),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn activate_script_handler(
+    Path(name): Path<String>,
+    State(data): State<Arc<AppState>>,
+    Json(body): Json<ActivateScriptSchema>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut tx = data.db.begin().await?;
+    let exists: Option<u64> =
+        sqlx::query_scalar("SELECT id FROM hook_scripts WHERE name = ? AND version = ?")
+            .bind(&name)
+            .bind(body.version)
+            .fetch_optional(&mut tx)
+            .await?;
+    let Some(id) = exists else {
+        return Err(AppError::NotFound(format!(
+            "Script '{}' has no version {}",
+            name, body.version
+        )));
+    };
+    sqlx::query("UPDATE hook_scripts SET active = (id = ?) WHERE name = ?")
+        .bind(id)
+        .bind(&name)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+
+    data.scripts.reload(&data.db).await?;
+    tracing::info!(script = %name, version = body.version, "Script activated");
+
+    Ok(Json(json!({
+        "status": "success",
+        "data": json!({ "name": name, "version": body.version })
+    })))
+}
+
+/// Deactivates every version of a script; its history is kept.
+#[utoipa::path(
+    delete,
+    path = "/api/admin/scripts/{name}",
+    tag = "admin",
+    params(("name" = String, Path, description = "Script name")),
+    responses(
+        (status = 204, description = "The script was deactivated"),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn deactivate_script_handler(
+    Path(name): Path<String>,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let result = sqlx::query("UPDATE hook_scripts SET active = 0 WHERE name = ? AND active = 1")
+        .bind(&name)
+        .execute(&data.db)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound(format!("Script '{}' not found", name)));
+    }
+
+    data.scripts.reload(&data.db).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct TestScriptSchema {
+    pub hook: ScriptHook,
+    pub source: String,
+    /// Sample payload, shaped like a create or update request body.
+    #[schema(value_type = Object)]
+    pub note: Value,
+}
+
+/// Dry-runs a script against a sample payload without saving anything.
+#[utoipa::path(
+    post,
+    path = "/api/admin/scripts/test",
+    tag = "admin",
+    request_body = TestScriptSchema,
+    responses(
+        (status = 200, description = "What the script did with the sample note"),
+        (status = 400, description = "The script does not compile", body = ErrorResponse),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn test_script_handler(
+    State(data): State<Arc<AppState>>,
+    Json(body): Json<TestScriptSchema>,
+) -> Result<impl IntoResponse, AppError> {
+    let ast = data
+        .scripts
+        .compile(&body.source)
+        .map_err(AppError::Validation)?;
+    let note = match body.hook {
+        ScriptHook::BeforeCreate => {
+            serde_json::from_value::<CreateNoteSchema>(body.note).map(|note| create_map(&note))
+        }
+        ScriptHook::BeforeUpdate => serde_json::from_value::<UpdateNoteSchema>(body.note)
+            .map(|changes| update_map(&changes)),
+    }
+    .map_err(|err| AppError::Validation(format!("Invalid sample note: {}", err)))?;
+    let id = (body.hook == ScriptHook::BeforeUpdate).then(|| NoteId::from(uuid::Uuid::nil()));
+
+    let (outcome, _) = data.scripts.run(&ast, note, id);
+    Ok(Json(json!({
+        "status": "success",
+        "data": outcome,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{hooks::Hooks, testing::TestApp};
+
+    use super::*;
+
+    fn hooks(scripts: &[(&str, &str)]) -> ScriptHooks {
+        let hooks = ScriptHooks::default();
+        let loaded = scripts
+            .iter()
+            .map(|(name, source)| {
+                Arc::new(LoadedScript {
+                    name: name.to_string(),
+                    version: 1,
+                    hook: ScriptHook::BeforeCreate,
+                    ast: hooks.compile(source).unwrap(),
+                })
+            })
+            .collect();
+        *hooks.scripts.write().unwrap() = loaded;
+        hooks
+    }
+
+    fn note(title: &str, content: &str) -> CreateNoteSchema {
+        CreateNoteSchema {
+            title: title.to_string(),
+            content: content.to_string(),
+            category: None,
+            published: None,
+            expires_at: None,
+        }
+    }
+
+    fn outcome(source: &str) -> ScriptOutcome {
+        let hooks = ScriptHooks::default();
+        let ast = hooks.compile(source).unwrap();
+        hooks
+            .run(&ast, create_map(&note("Title", "Content")), None)
+            .0
+    }
+
+    #[tokio::test]
+    async fn scripts_rewrite_notes_in_name_order() {
+        let hooks = hooks(&[
+            (
+                "a",
+                r#"if note.content.contains("invoice") { note.category = "billing"; }"#,
+            ),
+            ("b", r#"note.title = note.category + ": " + note.title;"#),
+        ]);
+        let mut note = note("March", "Invoice 12, see invoice");
+        hooks.before_create(&mut note).await.unwrap();
+        assert_eq!(note.title, "billing: March");
+        assert_eq!(note.category.as_deref(), Some("billing"));
+    }
+
+    #[tokio::test]
+    async fn reject_refuses_the_write() {
+        let hooks = hooks(&[(
+            "tmp",
+            r#"if note.title.starts_with("TMP") { reject("temporary notes are not allowed"); }"#,
+        )]);
+        let err = hooks
+            .before_create(&mut note("TMP draft", "x"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.message, "temporary notes are not allowed");
+        assert!(hooks.before_create(&mut note("Kept", "x")).await.is_ok());
+    }
+
+    #[test]
+    fn runaway_scripts_are_stopped() {
+        for source in [
+            "loop {}",
+            "fn f(n) { f(n + 1) } f(0);",
+            r#"let s = "x"; loop { s += s; }"#,
+            "let a = []; loop { a.push(1); }",
+        ] {
+            assert!(
+                matches!(outcome(source), ScriptOutcome::Failed { .. }),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn scripts_have_no_io() {
+        let module = std::env::temp_dir().join(format!("{}.rhai", uuid::Uuid::new_v4()));
+        std::fs::write(&module, "export const SECRET = 42;").unwrap();
+        let source = format!(
+            "import {:?} as m; note.title = m::SECRET.to_string();",
+            module.with_extension("").display().to_string()
+        );
+        let result = outcome(&source);
+        let _ = std::fs::remove_file(&module);
+        assert!(
+            matches!(result, ScriptOutcome::Failed { .. }),
+            "{:?}",
+            result
+        );
+    }
+
+    #[tokio::test]
+    async fn failing_scripts_are_skipped() {
+        let hooks = hooks(&[("a", "loop {}"), ("b", r#"note.title = "Checked";"#)]);
+        let mut note = note("Title", "Content");
+        hooks.before_create(&mut note).await.unwrap();
+        assert_eq!(note.title, "Checked");
+    }
+
+    #[tokio::test]
+    async fn rewritten_fields_must_keep_their_types() {
+        let hooks = hooks(&[("a", "note.published = \"yes\";")]);
+        let err = hooks
+            .before_create(&mut note("Title", "Content"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.message, "note.published must be a boolean");
+    }
+
+    #[tokio::test]
+    async fn inflated_content_is_validated_again() {
+        let scripts = hooks(&[(
+            "pad",
+            r#"while note.content.len() <= 1048576 { note.content += note.content; }"#,
+        )]);
+        let mut registered = Hooks::default();
+        registered.register(scripts);
+        let app = TestApp::with_hooks(registered);
+
+        let (status, body) = app
+            .post("/api/notes", json!({ "title": "Padded", "content": "x" }))
+            .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{}", body);
+        assert!(body["errors"]["content"].is_string(), "{}", body);
+        assert_eq!(app.get("/api/notes").await.1["total"], json!(0));
+    }
+}
@@ -0,0 +1,395 @@
+//! Pluggable secret storage.
+//!
+//! Secrets are resolved through a [`SecretsProvider`] selected with
+//! `SECRETS_PROVIDER` (`env`, `vault` or `aws`) and cached by
+//! [`CachedSecrets`] for the lease the backend grants, or for
+//! `SECRETS_CACHE_TTL_SECS` when it grants none. Names may carry a `#key`
+//! suffix to pick one field out of a JSON secret, e.g. `db/notes#password`.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::http_client::{HttpClient, HttpError};
+
+#[derive(Debug, Clone)]
+pub struct Secret {
+    pub value: String,
+    /// How long the backend allows the value to be cached, if it says.
+    pub lease: Option<Duration>,
+}
+
+#[derive(Debug)]
+pub enum SecretsError {
+    NotFound(String),
+    Backend(String),
+}
+
+impl fmt::Display for SecretsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretsError::NotFound(name) => write!(f, "secret '{}' not found", name),
+            SecretsError::Backend(message) => write!(f, "secrets backend error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for SecretsError {}
+
+impl From<reqwest::Error> for SecretsError {
+    fn from(err: reqwest::Error) -> Self {
+        SecretsError::Backend(err.to_string())
+    }
+}
+
+impl From<HttpError> for SecretsError {
+    fn from(err: HttpError) -> Self {
+        SecretsError::Backend(err.to_string())
+    }
+}
+
+#[async_trait]
+pub trait SecretsProvider: Send + Sync {
+    async fn fetch(&self, name: &str) -> Result<Secret, SecretsError>;
+}
+
+/// Splits `path#key` into its parts; the key is optional.
+fn split_name(name: &str) -> (&str, Option<&str>) {
+    match name.split_once('#') {
+        Some((path, key)) => (path, Some(key)),
+        None => (name, None),
+    }
+}
+
+fn field(document: &Value, name: &str, key: &str) -> Result<String, SecretsError> {
+    match document.get(key) {
+        Some(Value::String(value)) => Ok(value.clone()),
+        Some(value) => Ok(value.to_string()),
+        None => Err(SecretsError::NotFound(name.to_string())),
+    }
+}
+
+/// Reads secrets from environment variables, mainly for local development.
+/// `db/notes#password` becomes `DB_NOTES_PASSWORD`.
+pub struct EnvSecrets;
+
+#[async_trait]
+impl SecretsProvider for EnvSecrets {
+    async fn fetch(&self, name: &str) -> Result<Secret, SecretsError> {
+        let var = name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>();
+        let value = std::env::var(&var).map_err(|_| SecretsError::NotFound(name.to_string()))?;
+        Ok(Secret { value, lease: None })
+    }
+}
+
+/// HashiCorp Vault KV version 2 engine, authenticated with a token.
+pub struct VaultSecrets {
+    http: Arc<HttpClient>,
+    addr: String,
+    token: String,
+    mount: String,
+}
+
+impl VaultSecrets {
+    pub fn new(http: Arc<HttpClient>, addr: String, token: String, mount: String) -> Self {
+        Self {
+            http,
+            addr: addr.trim_end_matches('/').to_string(),
+            token,
+            mount,
+        }
+    }
+}
+
+#[async_trait]
+impl SecretsProvider for VaultSecrets {
+    async fn fetch(&self, name: &str) -> Result<Secret, SecretsError> {
+        let (path, key) = split_name(name);
+        let request = self
+            .http
+            .get(format!("{}/v1/{}/data/{}", self.addr, self.mount, path))
+            .header("X-Vault-Token", &self.token);
+        let response = self.http.send("vault", request).await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(SecretsError::NotFound(name.to_string()));
+        }
+        if !response.status().is_success() {
+            return Err(SecretsError::Backend(format!(
+                "vault answered {} for '{}'",
+                response.status(),
+                path
+            )));
+        }
+
+        let body: Value = response.json().await?;
+        let lease = body["lease_duration"]
+            .as_u64()
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs);
+        let value = field(&body["data"]["data"], name, key.unwrap_or("value"))?;
+        Ok(Secret { value, lease })
+    }
+}
+
+/// AWS Secrets Manager, called directly with SigV4-signed requests.
+pub struct AwsSecretsManager {
+    http: Arc<HttpClient>,
+    region: String,
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+impl AwsSecretsManager {
+    pub fn new(
+        http: Arc<HttpClient>,
+        region: String,
+        access_key_id: String,
+        secret_access_key: String,
+        session_token: Option<String>,
+    ) -> Self {
+        Self {
+            http,
+            region,
+            access_key_id,
+            secret_access_key,
+            session_token,
+        }
+    }
+
+    fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+        mac.update(data.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    /// Signs a JSON 1.1 POST to the service root and returns the headers to
+    /// send with it.
+    fn signed_headers(&self, host: &str, target: &str, payload: &str) -> Vec<(String, String)> {
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+
+        let mut headers = vec![
+            (
+                "content-type".to_string(),
+                "application/x-amz-json-1.1".to_string(),
+            ),
+            ("host".to_string(), host.to_string()),
+            ("x-amz-date".to_string(), amz_date.clone()),
+            ("x-amz-target".to_string(), target.to_string()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token".to_string(), token.clone()));
+        }
+        headers.sort();
+
+        let canonical_headers = headers
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value))
+            .collect::<String>();
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_request = format!(
+            "POST\n/\n\n{}\n{}\n{}",
+            canonical_headers,
+            signed_headers,
+            hex::encode(Sha256::digest(payload.as_bytes()))
+        );
+
+        let scope = format!("{}/{}/secretsmanager/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let key = Self::hmac(format!("AWS4{}", self.secret_access_key).as_bytes(), &date);
+        let key = Self::hmac(&key, &self.region);
+        let key = Self::hmac(&key, "secretsmanager");
+        let key = Self::hmac(&key, "aws4_request");
+        let signature = hex::encode(Self::hmac(&key, &string_to_sign));
+
+        headers.retain(|(name, _)| name != "host");
+        headers.push((
+            "authorization".to_string(),
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.access_key_id, scope, signed_headers, signature
+            ),
+        ));
+        headers
+    }
+}
+
+#[async_trait]
+impl SecretsProvider for AwsSecretsManager {
+    async fn fetch(&self, name: &str) -> Result<Secret, SecretsError> {
+        let (secret_id, key) = split_name(name);
+        let host = format!("secretsmanager.{}.amazonaws.com", self.region);
+        let payload = json!({ "SecretId": secret_id }).to_string();
+
+        let mut request = self.http.post(format!("https://{}/", host));
+        for (name, value) in self.signed_headers(&host, "secretsmanager.GetSecretValue", &payload) {
+            request = request.header(name, value);
+        }
+        let response = self
+            .http
+            .send("aws-secrets-manager", request.body(payload))
+            .await?;
+        let status = response.status();
+        let body: Value = response.json().await?;
+        if !status.is_success() {
+            let kind = body["__type"].as_str().unwrap_or_default();
+            if kind.ends_with("ResourceNotFoundException") {
+                return Err(SecretsError::NotFound(name.to_string()));
+            }
+            return Err(SecretsError::Backend(format!(
+                "secrets manager answered {} for '{}': {}",
+                status, secret_id, kind
+            )));
+        }
+
+        let secret = body["SecretString"]
+            .as_str()
+            .ok_or_else(|| SecretsError::NotFound(name.to_string()))?;
+        let value = match key {
+            None => secret.to_string(),
+            Some(key) => {
+                let document: Value = serde_json::from_str(secret).map_err(|_| {
+                    SecretsError::Backend(format!("secret '{}' is not a JSON document", secret_id))
+                })?;
+                field(&document, name, key)?
+            }
+        };
+        // Secrets Manager has no leases; rotation is picked up on cache expiry.
+        Ok(Secret { value, lease: None })
+    }
+}
+
+struct CachedSecret {
+    value: String,
+    expires_at: Instant,
+}
+
+/// Caches secrets for their lease. When a refresh fails the last known value
+/// keeps being served, so a backend outage does not take the service down
+/// while a rotation is in progress.
+pub struct CachedSecrets {
+    provider: Arc<dyn SecretsProvider>,
+    default_ttl: Duration,
+    cache: Mutex<HashMap<String, CachedSecret>>,
+}
+
+impl CachedSecrets {
+    pub fn new(provider: Arc<dyn SecretsProvider>, default_ttl: Duration) -> Self {
+        Self {
+            provider,
+            default_ttl,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn get(&self, name: &str) -> Result<String, SecretsError> {
+        let stale = {
+            let cache = self.cache.lock().unwrap();
+            match cache.get(name) {
+                Some(cached) if cached.expires_at > Instant::now() => {
+                    return Ok(cached.value.clone())
+                }
+                Some(cached) => Some(cached.value.clone()),
+                None => None,
+            }
+        };
+
+        match self.provider.fetch(name).await {
+            Ok(secret) => {
+                if stale.as_ref().is_some_and(|value| *value != secret.value) {
+                    tracing::info!(secret = name, "Secret was rotated");
+                }
+                let ttl = secret.lease.unwrap_or(self.default_ttl);
+                self.cache.lock().unwrap().insert(
+                    name.to_string(),
+                    CachedSecret {
+                        value: secret.value.clone(),
+                        expires_at: Instant::now() + ttl,
+                    },
+                );
+                Ok(secret.value)
+            }
+            Err(err) => match stale {
+                Some(value) => {
+                    tracing::warn!(secret = name, error = %err, "Serving cached secret");
+                    Ok(value)
+                }
+                None => Err(err),
+            },
+        }
+    }
+
+    /// Drops a cached value, e.g. after the backend rejected it as rotated.
+    pub fn invalidate(&self, name: &str) {
+        self.cache.lock().unwrap().remove(name);
+    }
+}
+
+/// Builds the provider named by `SECRETS_PROVIDER` from its environment
+/// variables.
+pub fn from_env(http: Arc<HttpClient>) -> Result<CachedSecrets, String> {
+    let provider: Arc<dyn SecretsProvider> = match std::env::var("SECRETS_PROVIDER").as_deref() {
+        Err(_) | Ok("env") => Arc::new(EnvSecrets),
+        Ok("vault") => Arc::new(VaultSecrets::new(
+            http,
+            required("VAULT_ADDR")?,
+            required("VAULT_TOKEN")?,
+            std::env::var("VAULT_KV_MOUNT").unwrap_or_else(|_| "secret".to_string()),
+        )),
+        Ok("aws") => Arc::new(AwsSecretsManager::new(
+            http,
+            required("AWS_REGION")?,
+            required("AWS_ACCESS_KEY_ID")?,
+            required("AWS_SECRET_ACCESS_KEY")?,
+            std::env::var("AWS_SESSION_TOKEN").ok(),
+        )),
+        Ok(other) => {
+            return Err(format!(
+                "Unknown SECRETS_PROVIDER '{}', expected env, vault or aws",
+                other
+            ))
+        }
+    };
+
+    let default_ttl = std::env::var("SECRETS_CACHE_TTL_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::from_secs(300));
+
+    Ok(CachedSecrets::new(provider, default_ttl))
+}
+
+fn required(var: &str) -> Result<String, String> {
+    std::env::var(var).map_err(|_| format!("{} must be set", var))
+}
@@ -0,0 +1,518 @@
+//! The rules for changing notes, whichever API the change comes through.
+//!
+//! The REST handlers, the GraphQL mutations and the collaborative WebSocket
+//! create, edit, publish and trash notes through a [`NoteService`], and so
+//! do bulk creation and trashing, `PUT`, compare-and-set `PATCH` and
+//! restoring revisions. It checks the note is in
+//! scope, validates, runs hooks and moderation, files notes under their
+//! category, reports duplicate titles, only lets notes move between statuses
+//! by a [`Transition`] and publishes change events. What stays with the
+//! callers is their protocol: conditional headers, request parsing and the
+//! shape of responses.
+
+use std::collections::HashSet;
+
+use chrono::Timelike;
+
+use crate::{
+    auth::NoteScope,
+    category,
+    collab::Edited,
+    conditional::{self, version_mismatch},
+    error::AppError,
+    events::NoteEventKind,
+    expiry,
+    handler::{new_note, title_error},
+    model::{NoteId, NoteModel, NoteStatus, Title, Transition},
+    moderation::{self, Verdict},
+    repository::WriteError,
+    schema::{CreateNoteSchema, UpdateNoteSchema},
+    validation::{validate_new_note, validate_note_update},
+    AppState,
+};
+
+/// The notes of `scope`, as seen by one request.
+pub struct NoteService<'a> {
+    data: &'a AppState,
+    scope: &'a NoteScope,
+}
+
+impl<'a> NoteService<'a> {
+    pub fn new(data: &'a AppState, scope: &'a NoteScope) -> Self {
+        Self { data, scope }
+    }
+
+    /// Validates, screens and saves a new note.
+    pub async fn create(&self, body: CreateNoteSchema) -> Result<NoteModel, AppError> {
+        let id = NoteId::from(self.data.ids.generate());
+        self.create_at(id, body)
+            .await?
+            .ok_or_else(|| WriteError::AlreadyExists.into())
+    }
+
+    /// [`Self::create`] at a chosen `id`; `None` when a note, live or
+    /// trashed, already has it.
+    pub async fn create_at(
+        &self,
+        id: NoteId,
+        body: CreateNoteSchema,
+    ) -> Result<Option<NoteModel>, AppError> {
+        let (note, verdict) = self.prepare_create(id, body).await?;
+        match self.data.notes.insert(&note).await {
+            Ok(()) => {}
+            Err(WriteError::AlreadyExists) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        }
+        self.created(&note, &verdict).await?;
+        Ok(Some(note))
+    }
+
+    /// Creates a note for each of `bodies`, all or none. The inner error
+    /// holds why the items that failed did, by their index.
+    pub async fn create_many(
+        &self,
+        bodies: Vec<CreateNoteSchema>,
+    ) -> Result<Result<Vec<NoteModel>, Vec<(usize, AppError)>>, AppError> {
+        let mut prepared = Vec::with_capacity(bodies.len());
+        let mut failures = Vec::new();
+        for (index, body) in bodies.into_iter().enumerate() {
+            let id = NoteId::from(self.data.ids.generate());
+            match self.prepare_create(id, body).await {
+                Ok(note) => prepared.push(note),
+                Err(err) => failures.push((index, err)),
+            }
+        }
+        if !failures.is_empty() {
+            return Ok(Err(failures));
+        }
+
+        let (notes, verdicts): (Vec<_>, Vec<_>) = prepared.into_iter().unzip();
+        if let Err((index, err)) = self.data.notes.insert_many(&notes).await? {
+            return Ok(Err(vec![(index, err.into())]));
+        }
+        for (note, verdict) in notes.iter().zip(&verdicts) {
+            self.created(note, verdict).await?;
+        }
+        Ok(Ok(notes))
+    }
+
+    /// Validates, hooks and screens `body`, and files it under its category
+    /// as note `id`, ready to insert. What the hooks return is validated
+    /// again.
+    async fn prepare_create(
+        &self,
+        id: NoteId,
+        mut body: CreateNoteSchema,
+    ) -> Result<(NoteModel, Verdict), AppError> {
+        let data = self.data;
+        validate_new_note(&body, data.settings.max_content_bytes)?;
+        if !data.hooks.is_empty() {
+            data.hooks.before_create(&mut body).await?;
+            // Hooks may have rewritten any field.
+            validate_new_note(&body, data.settings.max_content_bytes)?;
+        }
+        let verdict = moderation::screen(data, Some(&body.title), Some(&body.content)).await?;
+        if verdict.unpublishes() {
+            body.published = Some(false);
+        }
+
+        let mut note = new_note(data, id, self.scope.owner(), body)?;
+        category::assign(data.notes.as_ref(), &mut note).await?;
+        Ok((note, verdict))
+    }
+
+    /// Announces `note`, just inserted, and queues it for review when
+    /// `verdict` asks.
+    async fn created(&self, note: &NoteModel, verdict: &Verdict) -> Result<(), AppError> {
+        let data = self.data;
+        data.cache.missing_notes.forget(note.id);
+        data.events.publish(
+            NoteEventKind::Created,
+            note.id,
+            note.user_id,
+            data.clock.now(),
+        );
+        moderation::enqueue(data, note.id, verdict).await?;
+        Ok(())
+    }
+
+    /// Checks that note `id` is in scope, then validates, hooks and screens
+    /// `body`, validating it again after the hooks. The verdict is for
+    /// [`Self::save_edit`] to act on.
+    pub async fn prepare_edit(
+        &self,
+        id: NoteId,
+        body: &mut UpdateNoteSchema,
+    ) -> Result<Verdict, AppError> {
+        let data = self.data;
+        self.scope.check(data, id).await?;
+        validate_note_update(body, data.settings.max_content_bytes)?;
+        if let Some(Some(expires_at)) = body.expires_at {
+            body.expires_at = Some(Some(expiry::check(expires_at, data.clock.now())?));
+        }
+        if !data.hooks.is_empty() {
+            data.hooks.before_update(id, body).await?;
+            validate_note_update(body, data.settings.max_content_bytes)?;
+        }
+        let verdict =
+            moderation::screen(data, body.title.as_deref(), body.content.as_deref()).await?;
+        if verdict.unpublishes() {
+            body.published = Some(false);
+        }
+        Ok(verdict)
+    }
+
+    /// Applies the fields set in `body` to note `id`, only if it is still at
+    /// `expected_version` and holds the fields `body` expects, when set, and
+    /// returns the saved note.
+    pub async fn save_edit(
+        &self,
+        id: NoteId,
+        body: UpdateNoteSchema,
+        expected_version: Option<u32>,
+        verdict: &Verdict,
+    ) -> Result<NoteModel, AppError> {
+        let data = self.data;
+        let Some(mut note) = data.notes.find(id).await? else {
+            return Err(AppError::note_not_found(id));
+        };
+
+        let was_published = note.published;
+        let published = body.published.unwrap_or(note.published.is_published());
+        if let Some(transition) = note.publishing(published) {
+            transition.apply(note.status).map_err(AppError::Conflict)?;
+        }
+        if let Some(title) = body.title {
+            note.title = Title::parse(title).map_err(title_error)?;
+        }
+        if let Some(content) = body.content {
+            note.content = content;
+            // Written back to the table; see `offload`.
+            note.content_key = None;
+        }
+        if let Some(category) = body.category {
+            note.category = category;
+            category::assign(data.notes.as_ref(), &mut note).await?;
+        }
+        if let Some(expires_at) = body.expires_at {
+            note.expires_at = expires_at;
+        }
+        note.set_published(published, data.clock.now().with_nanosecond(0).unwrap());
+
+        let expected = body.expected.as_ref();
+        if !data.notes.update(&note, expected_version, expected).await? {
+            // Changed by someone else since it was checked, or gone.
+            return match data.notes.find(id).await? {
+                Some(current) if expected.is_some() => Err(conditional::fields_mismatch(
+                    conditional::mismatches(&current, expected, expected_version),
+                )),
+                Some(current) if expected_version.is_some() => {
+                    Err(version_mismatch(current.version).into())
+                }
+                _ => Err(AppError::note_not_found(id)),
+            };
+        }
+
+        data.events
+            .publish(NoteEventKind::Updated, id, note.user_id, data.clock.now());
+        if let Some(kind) = NoteEventKind::visibility_change(was_published, note.published) {
+            data.events
+                .publish(kind, id, note.user_id, data.clock.now());
+        }
+        moderation::enqueue(data, id, verdict).await?;
+
+        data.notes
+            .find(id)
+            .await?
+            .ok_or_else(|| AppError::note_not_found(id))
+    }
+
+    /// [`Self::prepare_edit`] and [`Self::save_edit`] in one.
+    pub async fn edit(
+        &self,
+        id: NoteId,
+        mut body: UpdateNoteSchema,
+        expected_version: Option<u32>,
+    ) -> Result<NoteModel, AppError> {
+        let verdict = self.prepare_edit(id, &mut body).await?;
+        self.save_edit(id, body, expected_version, &verdict).await
+    }
+
+    /// Replaces every field of note `id` with those of `body`, as an edit
+    /// setting them all would: publishing or unpublishing it takes the
+    /// [`Transition`] that does, so notes in review or archived keep their
+    /// status, and a published note keeps its `published_at`.
+    pub async fn replace(&self, id: NoteId, body: CreateNoteSchema) -> Result<NoteModel, AppError> {
+        let mut body = UpdateNoteSchema {
+            title: Some(body.title),
+            content: Some(body.content),
+            category: body.category,
+            published: Some(body.published.unwrap_or(false)),
+            expires_at: Some(body.expires_at),
+            expected: None,
+        };
+        let verdict = self.prepare_edit(id, &mut body).await?;
+        // Without a category the note is uncategorized, which no edit can ask for.
+        body.category.get_or_insert_with(String::new);
+        self.save_edit(id, body, None, &verdict).await
+    }
+
+    /// Saves note `id` with the fields of one of its revisions, set in
+    /// `body`, as an edit setting them would. Unlike an edit, a category
+    /// deleted since, or none, leaves the note uncategorized.
+    pub async fn restore(
+        &self,
+        id: NoteId,
+        mut body: UpdateNoteSchema,
+    ) -> Result<NoteModel, AppError> {
+        let data = self.data;
+        body.category = body.category.filter(|name| !name.trim().is_empty());
+        let verdict = self.prepare_edit(id, &mut body).await?;
+        let owner = data
+            .notes
+            .find_owner(id)
+            .await?
+            .ok_or_else(|| AppError::note_not_found(id))?;
+        let name = body.category.take().unwrap_or_default();
+        let category = match category::resolve(data.notes.as_ref(), owner, &name).await {
+            Err(AppError::InvalidFields(_)) => None,
+            result => result?,
+        };
+        body.category = Some(
+            category
+                .map(|category| String::from(category.name))
+                .unwrap_or_default(),
+        );
+        self.save_edit(id, body, None, &verdict).await
+    }
+
+    /// Publishes or unpublishes note `id`, and tells its collaborative editors.
+    /// Publishing screens the note as it stands, so moderation can keep it
+    /// unpublished.
+    pub async fn publish(&self, id: NoteId, published: bool) -> Result<NoteModel, AppError> {
+        let data = self.data;
+        let mut body = UpdateNoteSchema {
+            title: None,
+            content: None,
+            category: None,
+            published: Some(published),
+            expires_at: None,
+            expected: None,
+        };
+        let mut verdict = self.prepare_edit(id, &mut body).await?;
+        if published {
+            let Some(note) = data.notes.find(id).await? else {
+                return Err(AppError::note_not_found(id));
+            };
+            verdict = moderation::screen(data, Some(&note.title), Some(&note.content)).await?;
+            if verdict.unpublishes() {
+                body.published = Some(false);
+            }
+        }
+        let note = self.save_edit(id, body, None, &verdict).await?;
+        data.collab.publish(id, Edited::PUBLISHED.patch(&note));
+        Ok(note)
+    }
+
+    /// Takes note `id` through `transition`, and tells its collaborative
+    /// editors when that publishes or unpublishes it. Transitions to
+    /// published screen the note as it stands; when moderation keeps it
+    /// unpublished, the note stays where it was.
+    pub async fn transition(
+        &self,
+        id: NoteId,
+        transition: Transition,
+    ) -> Result<NoteModel, AppError> {
+        let data = self.data;
+        self.scope.check(data, id).await?;
+        let Some(note) = data.notes.find(id).await? else {
+            return Err(AppError::note_not_found(id));
+        };
+        let status = transition.apply(note.status).map_err(AppError::Conflict)?;
+
+        let verdict = if status == NoteStatus::Published {
+            moderation::screen(data, Some(&note.title), Some(&note.content)).await?
+        } else {
+            Verdict::allow()
+        };
+        if verdict.unpublishes() {
+            moderation::enqueue(data, id, &verdict).await?;
+            return Ok(note);
+        }
+        self.save_status(note, status, &verdict).await
+    }
+
+    /// Takes note `id` through `transition` on a moderator's decision: unlike
+    /// [`Self::transition`], without screening it again, and `None` rather
+    /// than an error when the note is gone or its status does not allow the
+    /// transition.
+    pub async fn moderate(
+        &self,
+        id: NoteId,
+        transition: Transition,
+    ) -> Result<Option<NoteModel>, AppError> {
+        let Some(note) = self.data.notes.find(id).await? else {
+            return Ok(None);
+        };
+        let Ok(status) = transition.apply(note.status) else {
+            return Ok(None);
+        };
+        self.save_status(note, status, &Verdict::allow())
+            .await
+            .map(Some)
+    }
+
+    /// Saves `note` at `status`, as a new version and revision, and announces
+    /// the change.
+    async fn save_status(
+        &self,
+        mut note: NoteModel,
+        status: NoteStatus,
+        verdict: &Verdict,
+    ) -> Result<NoteModel, AppError> {
+        let data = self.data;
+        let id = note.id;
+        let was_published = note.published;
+        let left_review = note.status == NoteStatus::InReview && status != NoteStatus::InReview;
+        note.set_status(status, data.clock.now().with_nanosecond(0).unwrap());
+        if !data.notes.update(&note, None, None).await? {
+            return Err(AppError::note_not_found(id));
+        }
+        if left_review {
+            data.notes.cancel_reviews(id, data.clock.now()).await?;
+        }
+
+        data.events
+            .publish(NoteEventKind::Updated, id, note.user_id, data.clock.now());
+        moderation::enqueue(data, id, verdict).await?;
+        let note = data
+            .notes
+            .find(id)
+            .await?
+            .ok_or_else(|| AppError::note_not_found(id))?;
+        if let Some(kind) = NoteEventKind::visibility_change(was_published, note.published) {
+            data.events
+                .publish(kind, id, note.user_id, data.clock.now());
+            data.collab.publish(id, Edited::PUBLISHED.patch(&note));
+        }
+        Ok(note)
+    }
+
+    /// Moves note `id` to the trash.
+    pub async fn trash(&self, id: NoteId) -> Result<(), AppError> {
+        let data = self.data;
+        let owner = self.scope.owner_of(data, id).await?;
+        data.hooks.before_delete(id).await?;
+
+        let trashed = data.notes.trash(id, data.clock.now()).await?;
+        if !trashed {
+            return Err(AppError::note_not_found(id));
+        }
+
+        data.events
+            .publish(NoteEventKind::Trashed, id, owner, data.clock.now());
+        Ok(())
+    }
+
+    /// Moves every note of `ids` to the trash, all or none. The inner error
+    /// holds why the items that failed did, by their index.
+    pub async fn trash_many(
+        &self,
+        ids: &[NoteId],
+    ) -> Result<Result<(), Vec<(usize, AppError)>>, AppError> {
+        let data = self.data;
+        let mut seen = HashSet::new();
+        let mut failures = Vec::new();
+        let mut owners = Vec::with_capacity(ids.len());
+        for (index, &id) in ids.iter().enumerate() {
+            let checked = async {
+                if !seen.insert(id) {
+                    return Err(AppError::Validation(format!(
+                        "Note with ID: {} is listed more than once",
+                        id
+                    )));
+                }
+                let owner = self.scope.owner_of(data, id).await?;
+                data.hooks.before_delete(id).await?;
+                Ok(owner)
+            }
+            .await;
+            match checked {
+                Ok(owner) => owners.push(owner),
+                Err(err) => failures.push((index, err)),
+            }
+        }
+        if !failures.is_empty() {
+            return Ok(Err(failures));
+        }
+
+        let now = data.clock.now();
+        if let Err(index) = data.notes.trash_many(ids, now).await? {
+            return Ok(Err(vec![(index, AppError::note_not_found(ids[index]))]));
+        }
+        for (&id, owner) in ids.iter().zip(owners) {
+            data.events.publish(NoteEventKind::Trashed, id, owner, now);
+        }
+        Ok(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use crate::testing::TestApp;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn moderating_takes_only_transitions_the_status_allows() {
+        let app = TestApp::new();
+        let (_, body) = app
+            .post("/api/notes", json!({ "title": "Held", "content": "Back" }))
+            .await;
+        let id: NoteId = body["data"]["note"]["id"]
+            .as_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let unscoped = NoteScope(None);
+        let service = NoteService::new(&app.state, &unscoped);
+
+        let note = service.moderate(id, Transition::Publish).await.unwrap();
+        let note = note.expect("a draft can be published");
+        assert_eq!(note.status, NoteStatus::Published);
+        assert_eq!(note.version, 2);
+        assert!(service
+            .moderate(id, Transition::Publish)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn leaving_review_stays_on_the_repository() {
+        let app = TestApp::new();
+        let (_, body) = app
+            .post(
+                "/api/notes",
+                json!({ "title": "Submitted", "content": "For review" }),
+            )
+            .await;
+        let id = body["data"]["note"]["id"].as_str().unwrap();
+
+        let (status, body) = app
+            .post(&format!("/api/notes/{}/submit", id), json!({}))
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["data"]["note"]["status"], json!("in_review"));
+        // The test pool reaches no database, so this fails if cancelling the
+        // pending reviews bypasses the repository.
+        let (status, body) = app
+            .post(&format!("/api/notes/{}/reject", id), json!({}))
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["data"]["note"]["status"], json!("draft"));
+    }
+}
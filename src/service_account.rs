@@ -0,0 +1,669 @@
+//! Non-human service accounts for automation.
+//!
+//! Each account has its own API key (`sa_<prefix>_<secret>`, sent as
+//! `Authorization: Bearer ...`), a fixed set of scopes, an optional IP
+//! allowlist and an optional expiry. Only a SHA-256 hash of the key is
+//! stored; the key itself is returned once, on creation or rotation.
+//! Requests without a service-account key are handled as before.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::{header, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::mysql::MySqlPool;
+use utoipa::ToSchema;
+
+use crate::{
+    admin::constant_time_eq,
+    alerts::request_context,
+    anomaly::Signal,
+    client_ip::{client_ip, IpRange},
+    error::AppError,
+    lockout::{self, LockoutKey},
+    model::BinaryId,
+    state::DbPool,
+    AppState,
+};
+
+pub const SCOPE_NOTES_READ: &str = "notes:read";
+pub const SCOPE_NOTES_WRITE: &str = "notes:write";
+pub const SCOPE_ADMIN: &str = "admin";
+pub const SCOPES: &[&str] = &[SCOPE_NOTES_READ, SCOPE_NOTES_WRITE, SCOPE_ADMIN];
+
+const KEY_PREFIX: &str = "sa_";
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct ServiceAccountModel {
+    pub id: BinaryId,
+    pub name: String,
+    pub key_prefix: String,
+    pub key_hash: String,
+    pub scopes: String,
+    pub ip_allowlist: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl ServiceAccountModel {
+    pub fn scopes(&self) -> Vec<&str> {
+        self.scopes.split_whitespace().collect()
+    }
+
+    pub fn ip_allowlist(&self) -> Vec<IpRange> {
+        self.ip_allowlist
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|range| range.parse().ok())
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ServiceAccountResponse {
+    pub id: String,
+    pub name: String,
+    pub key_prefix: String,
+    pub scopes: Vec<String>,
+    pub ip_allowlist: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+fn filter_record(account: &ServiceAccountModel) -> ServiceAccountResponse {
+    ServiceAccountResponse {
+        id: account.id.to_string(),
+        name: account.name.clone(),
+        key_prefix: account.key_prefix.clone(),
+        scopes: account.scopes().into_iter().map(String::from).collect(),
+        ip_allowlist: account
+            .ip_allowlist()
+            .iter()
+            .map(IpRange::to_string)
+            .collect(),
+        expires_at: account.expires_at,
+        revoked_at: account.revoked_at,
+        last_used_at: account.last_used_at,
+        created_at: account.created_at,
+    }
+}
+
+/// The service account a request was authenticated as, available to
+/// handlers as a request extension.
+#[derive(Debug, Clone)]
+pub struct ServiceAccountPrincipal {
+    pub id: BinaryId,
+    pub name: String,
+    pub scopes: Vec<String>,
+}
+
+impl ServiceAccountPrincipal {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|granted| granted == scope)
+    }
+
+    /// A 403 unless the account was granted `scope`.
+    pub fn require(&self, scope: &str) -> Result<(), AppError> {
+        if self.has_scope(scope) {
+            Ok(())
+        } else {
+            Err(AppError::Forbidden(format!(
+                "Service account is missing the '{}' scope",
+                scope
+            )))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateServiceAccountSchema {
+    pub name: String,
+    pub scopes: Vec<String>,
+    #[serde(default)]
+    pub ip_allowlist: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// Generates a new key and returns `(key, prefix)`.
+fn generate_key() -> (String, String) {
+    let mut prefix = [0u8; 6];
+    let mut secret = [0u8; 24];
+    rand::thread_rng().fill_bytes(&mut prefix);
+    rand::thread_rng().fill_bytes(&mut secret);
+    let prefix = hex::encode(prefix);
+    let key = format!("{}{}_{}", KEY_PREFIX, prefix, hex::encode(secret));
+    (key, prefix)
+}
+
+/// Rejects a request whose key failed to authenticate.
+fn unauthorized<B>(
+    data: &AppState,
+    req: &Request<B>,
+    keys: &[LockoutKey],
+    message: &str,
+) -> Response {
+    data.anomalies.record(
+        Signal::AuthFailure,
+        request_context(req, data.settings.trust_forwarded_for),
+    );
+    lockout::record_failure(data, req, keys);
+    let error_response = json!({
+        "status": "fail",
+        "message": message,
+    });
+    (StatusCode::UNAUTHORIZED, Json(error_response)).into_response()
+}
+
+/// Why [`check_account`] turned a request away.
+#[derive(Debug, PartialEq)]
+enum Denied {
+    /// The key is not valid (any more); counts as a failed login.
+    Unauthorized(&'static str),
+    /// The key is valid but not for this request.
+    Forbidden(String),
+}
+
+/// Checks `key` against `account`, and the account's expiry and allowlist
+/// against the request. Scopes are checked by the routes.
+fn check_account<B>(
+    account: &ServiceAccountModel,
+    key: &str,
+    req: &Request<B>,
+    now: DateTime<Utc>,
+    trust_forwarded_for: bool,
+) -> Result<(), Denied> {
+    if !constant_time_eq(hash_key(key).as_bytes(), account.key_hash.as_bytes()) {
+        return Err(Denied::Unauthorized("Invalid service account key"));
+    }
+    if account.revoked_at.is_some() {
+        return Err(Denied::Unauthorized("Service account key has been revoked"));
+    }
+    if account.expires_at.is_some_and(|at| at <= now) {
+        return Err(Denied::Unauthorized("Service account key has expired"));
+    }
+
+    let allowlist = account.ip_allowlist();
+    if !allowlist.is_empty() {
+        let allowed = client_ip(req, trust_forwarded_for)
+            .is_some_and(|ip| allowlist.iter().any(|range| range.contains(ip)));
+        if !allowed {
+            tracing::warn!(
+                service_account = %account.name,
+                "Service account used from a disallowed address",
+            );
+            return Err(Denied::Forbidden(
+                "Request address is not allowed for this service account".into(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Holds service accounts to their scopes on the routes it is layered on:
+/// reads need `notes:read` and everything else `notes:write`. The REST note
+/// API and plugin routes are wrapped; admin routes need `admin` (see
+/// `admin::require_admin`) and GraphQL checks each operation by its type.
+pub async fn require_note_scopes<B>(req: Request<B>, next: Next<B>) -> Response {
+    if let Some(principal) = req.extensions().get::<ServiceAccountPrincipal>() {
+        let scope = if req.method() == Method::GET || req.method() == Method::HEAD {
+            SCOPE_NOTES_READ
+        } else {
+            SCOPE_NOTES_WRITE
+        };
+        if let Err(response) = principal.require(scope) {
+            return response.into_response();
+        }
+    }
+    next.run(req).await
+}
+
+/// Authenticates requests that carry a service-account key and enforces the
+/// account's allowlist and expiry. Which scope a request needs depends on
+/// the route, so that is left to the routes.
+pub async fn authenticate_service_account<B>(
+    State(data): State<Arc<AppState>>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(key) = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|token| token.starts_with(KEY_PREFIX))
+        .map(String::from)
+    else {
+        return next.run(req).await;
+    };
+
+    let prefix = key[KEY_PREFIX.len()..]
+        .split('_')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let keys = lockout::lockout_keys(&data, &req, Some(&format!("{}{}", KEY_PREFIX, prefix)));
+    if let Err(response) = lockout::admit(&data, &req, &keys).await {
+        return response;
+    }
+
+    let account = sqlx::query_as::<_, ServiceAccountModel>(
+        "SELECT * FROM service_accounts WHERE key_prefix = ?",
+    )
+    .bind(&prefix)
+    .fetch_optional(&data.db)
+    .await;
+    let account = match account {
+        Ok(Some(account)) => account,
+        Ok(None) => return unauthorized(&data, &req, &keys, "Invalid service account key"),
+        Err(err) => return AppError::from(err).into_response(),
+    };
+
+    let now = data.clock.now();
+    match check_account(&account, &key, &req, now, data.settings.trust_forwarded_for) {
+        Ok(()) => {}
+        Err(Denied::Unauthorized(message)) => return unauthorized(&data, &req, &keys, message),
+        Err(Denied::Forbidden(message)) => return AppError::Forbidden(message).into_response(),
+    }
+
+    // Coarse-grained so busy accounts do not write on every request.
+    let _ = sqlx::query(
+        "UPDATE service_accounts SET last_used_at = ? WHERE id = ? AND (last_used_at IS NULL OR last_used_at < ?)",
+    )
+    .bind(now)
+    .bind(account.id)
+    .bind(now - chrono::Duration::minutes(1))
+    .execute(&data.db)
+    .await;
+
+    data.logins.record_success(&keys);
+    req.extensions_mut().insert(ServiceAccountPrincipal {
+        id: account.id,
+        name: account.name.clone(),
+        scopes: account.scopes().into_iter().map(String::from).collect(),
+    });
+    next.run(req).await
+}
+
+fn not_found(id: uuid::Uuid) -> AppError {
+    AppError::NotFound(format!("Service account with ID: {} not found", id))
+}
+
+async fn fetch_account(db: &MySqlPool, id: BinaryId) -> Result<ServiceAccountModel, AppError> {
+    sqlx::query_as::<_, ServiceAccountModel>("SELECT * FROM service_accounts WHERE id = ?")
+        .bind(id)
+        .fetch_one(db)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => not_found(id.0),
+            e => e.into(),
+        })
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/service-accounts",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Service accounts"),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn list_service_accounts_handler(
+    State(db): State<DbPool>,
+) -> Result<impl IntoResponse, AppError> {
+    let accounts = sqlx::query_as::<_, ServiceAccountModel>(
+        "SELECT * FROM service_accounts ORDER BY created_at, name",
+    )
+    .fetch_all(&*db)
+    .await?;
+
+    let accounts = accounts.iter().map(filter_record).collect::<Vec<_>>();
+    Ok(Json(json!({
+        "status": "success",
+        "results": accounts.len(),
+        "service_accounts": accounts,
+    })))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/admin/service-accounts",
+    tag = "admin",
+    request_body = CreateServiceAccountSchema,
+    responses(
+        (status = 201, description = "The account and its key"),
+        (status = 400, description = "Invalid scopes or allowlist", body = ErrorResponse),
+        (status = 409, description = "An account with that name already exists", body = ErrorResponse),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn create_service_account_handler(
+    State(data): State<Arc<AppState>>,
+    Json(body): Json<CreateServiceAccountSchema>,
+) -> Result<impl IntoResponse, AppError> {
+    if body.name.trim().is_empty() {
+        return Err(AppError::Validation(
+            "Service account name must not be empty".into(),
+        ));
+    }
+    if body.scopes.is_empty() {
+        return Err(AppError::Validation(
+            "At least one scope is required".into(),
+        ));
+    }
+    if let Some(unknown) = body
+        .scopes
+        .iter()
+        .find(|scope| !SCOPES.contains(&scope.as_str()))
+    {
+        return Err(AppError::Validation(format!(
+            "Unknown scope '{}'. Supported: {}",
+            unknown,
+            SCOPES.join(", ")
+        )));
+    }
+    let allowlist = body
+        .ip_allowlist
+        .iter()
+        .map(|range| range.parse::<IpRange>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(AppError::Validation)?;
+
+    let id = BinaryId::from(data.ids.generate());
+    let (key, prefix) = generate_key();
+    let scopes = body.scopes.join(" ");
+    let allowlist = (!allowlist.is_empty()).then(|| {
+        allowlist
+            .iter()
+            .map(IpRange::to_string)
+            .collect::<Vec<_>>()
+            .join(",")
+    });
+
+    sqlx::query(
+        r#"INSERT INTO service_accounts (id, name, key_prefix, key_hash, scopes, ip_allowlist, expires_at, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?)"#,
+    )
+    .bind(id)
+    .bind(body.name.trim())
+    .bind(&prefix)
+    .bind(hash_key(&key))
+    .bind(&scopes)
+    .bind(&allowlist)
+    .bind(body.expires_at)
+    .bind(data.clock.now())
+    .execute(&data.db)
+    .await
+    .map_err(|err| {
+        if err.to_string().contains("Duplicate entry") {
+            AppError::Conflict("Service account with that name already exists".to_string())
+        } else {
+            err.into()
+        }
+    })?;
+
+    tracing::info!(
+        service_account = body.name.trim(),
+        "Service account created"
+    );
+    let account = fetch_account(&data.db, id).await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({
+            "status": "success",
+            "data": json!({
+                "service_account": filter_record(&account),
+                "key": key,
+            })
+        })),
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/admin/service-accounts/{id}/rotate-key",
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "Service account ID")),
+    responses(
+        (status = 200, description = "The new key"),
+        (status = 400, description = "The account has been revoked", body = ErrorResponse),
+        (status = 404, description = "Service account not found", body = ErrorResponse),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn rotate_service_account_key_handler(
+    Path(id): Path<uuid::Uuid>,
+    State(db): State<DbPool>,
+) -> Result<impl IntoResponse, AppError> {
+    let account = fetch_account(&db, BinaryId::from(id)).await?;
+    if account.revoked_at.is_some() {
+        return Err(AppError::Validation(format!(
+            "Service account with ID: {} has been revoked",
+            id
+        )));
+    }
+
+    let (key, prefix) = generate_key();
+    sqlx::query("UPDATE service_accounts SET key_prefix = ?, key_hash = ? WHERE id = ?")
+        .bind(&prefix)
+        .bind(hash_key(&key))
+        .bind(BinaryId::from(id))
+        .execute(&*db)
+        .await?;
+
+    let account = fetch_account(&db, BinaryId::from(id)).await?;
+    tracing::info!(service_account = %account.name, "Service account key rotated");
+    Ok(Json(json!({
+        "status": "success",
+        "data": json!({
+            "service_account": filter_record(&account),
+            "key": key,
+        })
+    })))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/admin/service-accounts/{id}",
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "Service account ID")),
+    responses(
+        (status = 204, description = "The account was revoked"),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn revoke_service_account_handler(
+    Path(id): Path<uuid::Uuid>,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let result = sqlx::query(
+        "UPDATE service_accounts SET revoked_at = COALESCE(revoked_at, ?) WHERE id = ?",
+    )
+    .bind(data.clock.now())
+    .bind(BinaryId::from(id))
+    .execute(&data.db)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(not_found(id));
+    }
+
+    tracing::info!(service_account_id = %id, "Service account revoked");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::extract::ConnectInfo;
+    use chrono::TimeZone;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 5, 3, 12, 0, 0).unwrap()
+    }
+
+    fn new_account(scopes: &str) -> (ServiceAccountModel, String) {
+        let (key, prefix) = generate_key();
+        let account = ServiceAccountModel {
+            id: BinaryId::from(uuid::Uuid::new_v4()),
+            name: "backup".to_string(),
+            key_prefix: prefix,
+            key_hash: hash_key(&key),
+            scopes: scopes.to_string(),
+            ip_allowlist: None,
+            expires_at: None,
+            revoked_at: None,
+            last_used_at: None,
+            created_at: now(),
+        };
+        (account, key)
+    }
+
+    fn request(method: Method, uri: &str, peer: &str) -> Request<()> {
+        let mut req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("x-forwarded-for", "10.1.2.3")
+            .body(())
+            .unwrap();
+        let peer: SocketAddr = peer.parse().unwrap();
+        req.extensions_mut().insert(ConnectInfo(peer));
+        req
+    }
+
+    fn get(uri: &str) -> Request<()> {
+        request(Method::GET, uri, "192.0.2.7:40000")
+    }
+
+    #[test]
+    fn keys_carry_their_prefix() {
+        let (key, prefix) = generate_key();
+        assert!(key.starts_with(&format!("{}{}_", KEY_PREFIX, prefix)));
+        assert_ne!(generate_key().0, key);
+    }
+
+    fn principal(scopes: &[&str]) -> ServiceAccountPrincipal {
+        ServiceAccountPrincipal {
+            id: BinaryId::from(uuid::Uuid::new_v4()),
+            name: "backup".to_string(),
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+        }
+    }
+
+    /// The status of `method /api/notes` for an account with `scopes`.
+    async fn note_route_status(scopes: &[&str], method: Method) -> StatusCode {
+        let ok = || async { StatusCode::OK };
+        let router: axum::Router = axum::Router::new()
+            .route("/api/notes", axum::routing::get(ok).post(ok).delete(ok))
+            .route_layer(axum::middleware::from_fn(require_note_scopes))
+            .layer(axum::Extension(principal(scopes)));
+        let req = Request::builder()
+            .method(method)
+            .uri("/api/notes")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        router.oneshot(req).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn note_reads_and_writes_need_their_scopes() {
+        let read = &[SCOPE_NOTES_READ];
+        assert_eq!(note_route_status(read, Method::GET).await, StatusCode::OK);
+        assert_eq!(note_route_status(read, Method::HEAD).await, StatusCode::OK);
+        for method in [Method::POST, Method::DELETE] {
+            assert_eq!(note_route_status(read, method).await, StatusCode::FORBIDDEN);
+        }
+
+        let write = &[SCOPE_NOTES_WRITE, SCOPE_ADMIN];
+        assert_eq!(note_route_status(write, Method::POST).await, StatusCode::OK);
+        // Writing does not imply reading, nor does admin.
+        assert_eq!(
+            note_route_status(write, Method::GET).await,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[test]
+    fn scopes_are_granted_one_by_one() {
+        let account = principal(&[SCOPE_NOTES_READ, SCOPE_NOTES_WRITE]);
+        assert!(account.require(SCOPE_NOTES_READ).is_ok());
+        let denied = account.require(SCOPE_ADMIN).unwrap_err();
+        assert!(matches!(denied, AppError::Forbidden(_)));
+    }
+
+    #[test]
+    fn wrong_revoked_and_expired_keys_are_refused() {
+        let (mut account, key) = new_account("notes:read");
+        let req = get("/api/notes");
+
+        let (other_key, _) = generate_key();
+        assert_eq!(
+            check_account(&account, &other_key, &req, now(), false),
+            Err(Denied::Unauthorized("Invalid service account key"))
+        );
+
+        account.expires_at = Some(now() + chrono::Duration::seconds(1));
+        assert_eq!(check_account(&account, &key, &req, now(), false), Ok(()));
+        account.expires_at = Some(now());
+        assert_eq!(
+            check_account(&account, &key, &req, now(), false),
+            Err(Denied::Unauthorized("Service account key has expired"))
+        );
+
+        account.expires_at = None;
+        account.revoked_at = Some(now());
+        assert_eq!(
+            check_account(&account, &key, &req, now(), false),
+            Err(Denied::Unauthorized("Service account key has been revoked"))
+        );
+    }
+
+    #[test]
+    fn allowlists_admit_only_their_addresses() {
+        let (mut account, key) = new_account("notes:read");
+        account.ip_allowlist = Some("192.0.2.0/28,2001:db8::1".to_string());
+        let check = |peer: &str, trust: bool| {
+            check_account(
+                &account,
+                &key,
+                &request(Method::GET, "/api/notes", peer),
+                now(),
+                trust,
+            )
+        };
+
+        assert_eq!(check("192.0.2.7:40000", false), Ok(()));
+        assert_eq!(check("[2001:db8::1]:40000", false), Ok(()));
+        assert!(matches!(
+            check("192.0.2.16:40000", false),
+            Err(Denied::Forbidden(_))
+        ));
+        // The forwarded address (10.1.2.3) only counts behind a trusted proxy.
+        assert!(matches!(
+            check("192.0.2.7:40000", true),
+            Err(Denied::Forbidden(_))
+        ));
+    }
+}
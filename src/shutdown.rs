@@ -0,0 +1,59 @@
+//! Graceful shutdown.
+//!
+//! On SIGTERM or Ctrl-C the server stops accepting connections and lets the
+//! requests in flight finish, for at most `SHUTDOWN_DRAIN_SECS`. Change
+//! streams and collaboration sockets never finish on their own, so they are
+//! cut off when that runs out. Buffered writes are then flushed and the
+//! database pool closed.
+
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+/// Kept below Kubernetes' default 30s termination grace period.
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// Resolves on the first SIGTERM or Ctrl-C.
+pub async fn signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for Ctrl-C");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+/// Cloneable handle on whether shutdown was requested.
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    /// Starts waiting for [`signal`] in the background.
+    pub fn listen() -> Self {
+        let (tx, rx) = watch::channel(false);
+        tokio::spawn(async move {
+            signal().await;
+            tracing::info!("Shutdown requested, draining requests in flight");
+            let _ = tx.send(true);
+        });
+        Self(rx)
+    }
+
+    /// Resolves once shutdown was requested.
+    pub async fn requested(mut self) {
+        let _ = self.0.wait_for(|requested| *requested).await;
+    }
+}
@@ -0,0 +1,497 @@
+//! Signing keys for user tokens, rotated without signing anyone out.
+//!
+//! User tokens are signed with Ed25519 (`EdDSA`) and name their key in the
+//! `kid` header. The keys live in `jwt_signing_keys` so that every instance
+//! signs and verifies with the same set; their private halves are sealed with
+//! a key derived from `JWT_SECRET`, so the table alone cannot forge tokens.
+//! `POST /api/admin/jwt-keys/rotate` retires the signing key for a fresh one.
+//! A retired key keeps verifying, and stays published at
+//! `/.well-known/jwks.json` for other services, until every token it may have
+//! signed has expired.
+//!
+//! HS256 tokens signed with `JWT_SECRET` itself, as issued before these keys,
+//! are only accepted if issued before the keys were deployed, and only for
+//! one token lifetime after that; see [`KeyRing::accepts_legacy`].
+
+use std::{
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use jsonwebtoken::{DecodingKey, EncodingKey};
+use rand::RngCore;
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey},
+    rand::SystemRandom,
+    signature::{Ed25519KeyPair, KeyPair},
+};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use sqlx::{mysql::MySqlPool, FromRow};
+
+use crate::{error::AppError, AppState};
+
+/// How long a retired key verifies beyond the token lifetime, covering
+/// tokens signed by instances that had not picked up the rotation yet.
+const RELOAD_GRACE: Duration = Duration::from_secs(5 * 60);
+/// Tokens naming an unknown key reload the keys at most this often.
+const MIN_RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+/// The migration that created `jwt_signing_keys`; when it ran is when
+/// tokens stopped being signed with `JWT_SECRET`.
+const SIGNING_KEYS_MIGRATION: i64 = 20230605;
+
+pub struct SigningKey {
+    pub kid: String,
+    pub(crate) encoding: EncodingKey,
+    pub(crate) decoding: DecodingKey,
+    public_key: Vec<u8>,
+    pub created_at: DateTime<Utc>,
+    pub retired_at: Option<DateTime<Utc>>,
+}
+
+impl SigningKey {
+    fn from_pkcs8(
+        kid: String,
+        pkcs8: &[u8],
+        created_at: DateTime<Utc>,
+        retired_at: Option<DateTime<Utc>>,
+    ) -> Option<Self> {
+        let pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(pkcs8).ok()?;
+        let public_key = pair.public_key().as_ref().to_vec();
+        Some(Self {
+            kid,
+            encoding: EncodingKey::from_ed_der(pkcs8),
+            decoding: DecodingKey::from_ed_der(&public_key),
+            public_key,
+            created_at,
+            retired_at,
+        })
+    }
+
+    /// A new key, and its private half as a PKCS#8 document.
+    pub(crate) fn generate(now: DateTime<Utc>) -> (Self, Vec<u8>) {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .expect("the system random source is available");
+        let mut kid = [0u8; 8];
+        rand::thread_rng().fill_bytes(&mut kid);
+        let key = Self::from_pkcs8(hex::encode(kid), pkcs8.as_ref(), now, None)
+            .expect("generated keys are valid");
+        (key, pkcs8.as_ref().to_vec())
+    }
+
+    /// The public key as a JSON Web Key.
+    pub fn jwk(&self) -> Value {
+        json!({
+            "kty": "OKP",
+            "crv": "Ed25519",
+            "alg": "EdDSA",
+            "use": "sig",
+            "kid": self.kid,
+            "x": URL_SAFE_NO_PAD.encode(&self.public_key),
+        })
+    }
+}
+
+/// Seals private keys at rest with a key derived from the JWT secret.
+struct Sealer(LessSafeKey);
+
+impl Sealer {
+    fn new(secret: &[u8]) -> Self {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any size");
+        mac.update(b"jwt-signing-keys");
+        let key = UnboundKey::new(&aead::CHACHA20_POLY1305, &mac.finalize().into_bytes())
+            .expect("SHA-256 output is a ChaCha20 key");
+        Self(LessSafeKey::new(key))
+    }
+
+    /// The nonce followed by the ciphertext, bound to `kid`.
+    fn seal(&self, kid: &str, plaintext: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let mut sealed = plaintext.to_vec();
+        self.0
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(kid.as_bytes()),
+                &mut sealed,
+            )
+            .expect("private keys fit a single message");
+        [nonce.as_slice(), &sealed].concat()
+    }
+
+    /// `None` when `sealed` was not sealed for `kid` with this secret.
+    fn open(&self, kid: &str, sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < aead::NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(aead::NONCE_LEN);
+        let mut buffer = ciphertext.to_vec();
+        let plaintext = self
+            .0
+            .open_in_place(
+                Nonce::try_assume_unique_for_key(nonce).ok()?,
+                Aad::from(kid.as_bytes()),
+                &mut buffer,
+            )
+            .ok()?;
+        Some(plaintext.to_vec())
+    }
+}
+
+#[derive(FromRow)]
+struct StoredKey {
+    kid: String,
+    private_key: Vec<u8>,
+    created_at: DateTime<Utc>,
+    retired_at: Option<DateTime<Utc>>,
+}
+
+/// The keys tokens are signed and verified with.
+pub struct KeyRing {
+    sealer: Sealer,
+    /// Oldest first; the newest key that is not retired signs.
+    keys: RwLock<Vec<Arc<SigningKey>>>,
+    /// How long a retired key keeps verifying.
+    retention: chrono::Duration,
+    last_reload: Mutex<Option<Instant>>,
+    /// When signing keys were deployed; `None` until read from the database.
+    legacy_cutoff: RwLock<Option<DateTime<Utc>>>,
+}
+
+impl KeyRing {
+    /// Starts with a key held in memory only, until [`reload`](Self::reload)
+    /// replaces it with the stored ones.
+    pub fn new(secret: &[u8], token_ttl: Duration) -> Self {
+        let (key, _) = SigningKey::generate(Utc::now());
+        Self {
+            sealer: Sealer::new(secret),
+            keys: RwLock::new(vec![Arc::new(key)]),
+            retention: chrono::Duration::from_std(token_ttl + RELOAD_GRACE)
+                .unwrap_or(chrono::Duration::MAX),
+            last_reload: Mutex::new(None),
+            legacy_cutoff: RwLock::new(None),
+        }
+    }
+
+    /// Whether an HS256 token issued at `iat` (in seconds) may still be
+    /// accepted: only if it predates the signing keys, and only while a
+    /// token issued just before them could still be valid. Once that has
+    /// passed for every deployment, the legacy path can be removed.
+    pub fn accepts_legacy(&self, iat: i64, now: DateTime<Utc>) -> bool {
+        match *self.legacy_cutoff.read().unwrap() {
+            Some(cutoff) => iat < cutoff.timestamp() && now < cutoff + self.retention,
+            None => false,
+        }
+    }
+
+    pub(crate) fn set_legacy_cutoff(&self, cutoff: DateTime<Utc>) {
+        *self.legacy_cutoff.write().unwrap() = Some(cutoff);
+    }
+
+    /// The key new tokens are signed with.
+    pub fn signing_key(&self) -> Arc<SigningKey> {
+        let keys = self.keys.read().unwrap();
+        keys.iter()
+            .rev()
+            .find(|key| key.retired_at.is_none())
+            .or_else(|| keys.last())
+            .cloned()
+            .expect("the key ring is never empty")
+    }
+
+    pub fn find(&self, kid: &str) -> Option<Arc<SigningKey>> {
+        let keys = self.keys.read().unwrap();
+        keys.iter().find(|key| key.kid == kid).cloned()
+    }
+
+    /// The keys tokens may still be verified with.
+    pub fn keys(&self) -> Vec<Arc<SigningKey>> {
+        self.keys.read().unwrap().clone()
+    }
+
+    /// Reads the stored keys, creating the first one if none is in use.
+    /// Keys sealed with another secret are skipped.
+    pub async fn reload(&self, db: &MySqlPool, now: DateTime<Utc>) -> Result<(), sqlx::Error> {
+        *self.last_reload.lock().unwrap() = Some(Instant::now());
+        if self.legacy_cutoff.read().unwrap().is_none() {
+            let deployed = sqlx::query_scalar::<_, DateTime<Utc>>(
+                "SELECT installed_on FROM _sqlx_migrations WHERE version = ?",
+            )
+            .bind(SIGNING_KEYS_MIGRATION)
+            .fetch_optional(db)
+            .await?;
+            if let Some(deployed) = deployed {
+                self.set_legacy_cutoff(deployed);
+            }
+        }
+        let stored = sqlx::query_as::<_, StoredKey>(
+            "SELECT kid, private_key, created_at, retired_at FROM jwt_signing_keys WHERE retired_at IS NULL OR retired_at > ? ORDER BY created_at, kid",
+        )
+        .bind(now - self.retention)
+        .fetch_all(db)
+        .await?;
+
+        let mut keys = Vec::with_capacity(stored.len() + 1);
+        for row in stored {
+            let key = self
+                .sealer
+                .open(&row.kid, &row.private_key)
+                .and_then(|pkcs8| {
+                    SigningKey::from_pkcs8(row.kid.clone(), &pkcs8, row.created_at, row.retired_at)
+                });
+            match key {
+                Some(key) => keys.push(Arc::new(key)),
+                None => {
+                    tracing::warn!(
+                        kid = %row.kid,
+                        "JWT signing key cannot be opened with this JWT_SECRET",
+                    )
+                }
+            }
+        }
+        if keys.iter().all(|key| key.retired_at.is_some()) {
+            let key = self.create(db, now).await?;
+            tracing::info!(kid = %key.kid, "Created JWT signing key");
+            keys.push(Arc::new(key));
+        }
+        *self.keys.write().unwrap() = keys;
+        Ok(())
+    }
+
+    /// [`reload`](Self::reload)s for a token naming a key this instance does
+    /// not know, which another instance may have just created.
+    pub async fn reload_for_unknown_key(&self, db: &MySqlPool, now: DateTime<Utc>) {
+        let recent = self
+            .last_reload
+            .lock()
+            .unwrap()
+            .is_some_and(|at| at.elapsed() < MIN_RELOAD_INTERVAL);
+        if recent {
+            return;
+        }
+        if let Err(err) = self.reload(db, now).await {
+            tracing::error!(error = ?err, "Failed to reload the JWT signing keys");
+        }
+    }
+
+    async fn create(&self, db: &MySqlPool, now: DateTime<Utc>) -> Result<SigningKey, sqlx::Error> {
+        let (key, pkcs8) = SigningKey::generate(now);
+        sqlx::query("INSERT INTO jwt_signing_keys (kid, private_key, created_at) VALUES (?, ?, ?)")
+            .bind(&key.kid)
+            .bind(self.sealer.seal(&key.kid, &pkcs8))
+            .bind(key.created_at)
+            .execute(db)
+            .await?;
+        Ok(key)
+    }
+
+    /// Retires the keys in use for a new one, and drops those no token can
+    /// name any more.
+    pub async fn rotate(
+        &self,
+        db: &MySqlPool,
+        now: DateTime<Utc>,
+    ) -> Result<Arc<SigningKey>, sqlx::Error> {
+        let (key, pkcs8) = SigningKey::generate(now);
+        let mut tx = db.begin().await?;
+        sqlx::query("UPDATE jwt_signing_keys SET retired_at = ? WHERE retired_at IS NULL")
+            .bind(now)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("INSERT INTO jwt_signing_keys (kid, private_key, created_at) VALUES (?, ?, ?)")
+            .bind(&key.kid)
+            .bind(self.sealer.seal(&key.kid, &pkcs8))
+            .bind(key.created_at)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("DELETE FROM jwt_signing_keys WHERE retired_at <= ?")
+            .bind(now - self.retention)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(self.install(key, now))
+    }
+
+    /// Makes `key` the signing key, retiring the others as of `now`.
+    pub(crate) fn install(&self, key: SigningKey, now: DateTime<Utc>) -> Arc<SigningKey> {
+        let key = Arc::new(key);
+        let mut keys = self.keys.write().unwrap();
+        let retention = self.retention;
+        *keys = keys
+            .iter()
+            .filter(|old| old.retired_at.is_none_or(|at| at > now - retention))
+            .map(|old| match old.retired_at {
+                Some(_) => old.clone(),
+                None => Arc::new(SigningKey {
+                    kid: old.kid.clone(),
+                    encoding: old.encoding.clone(),
+                    decoding: old.decoding.clone(),
+                    public_key: old.public_key.clone(),
+                    created_at: old.created_at,
+                    retired_at: Some(now),
+                }),
+            })
+            .chain([key.clone()])
+            .collect();
+        key
+    }
+}
+
+pub fn spawn_reloader(data: Arc<AppState>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let Some(auth) = data.auth.as_ref() else {
+                return;
+            };
+            if let Err(err) = auth.keys.reload(&data.db, data.clock.now()).await {
+                tracing::error!(error = ?err, "Failed to reload the JWT signing keys");
+            }
+        }
+    });
+}
+
+fn accounts_disabled() -> AppError {
+    AppError::Response(
+        StatusCode::NOT_FOUND,
+        Json(json!({
+            "status": "fail",
+            "message": "User accounts are disabled",
+        })),
+    )
+}
+
+/// The public keys user tokens are signed with, for other services to verify
+/// them. Verifiers should refetch when a token names a key not listed.
+#[utoipa::path(
+    get,
+    path = "/.well-known/jwks.json",
+    tag = "auth",
+    responses(
+        (status = 200, description = "The keys tokens may be signed with, as a JWK set"),
+        (status = 404, description = "User accounts are disabled", body = ErrorResponse),
+    ),
+    security(()),
+)]
+pub async fn jwks_handler(
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let auth = data.auth.as_ref().ok_or_else(accounts_disabled)?;
+    let keys = auth
+        .keys
+        .keys()
+        .iter()
+        .map(|key| key.jwk())
+        .collect::<Vec<_>>();
+    Ok((
+        [(header::CACHE_CONTROL, "public, max-age=300")],
+        Json(json!({ "keys": keys })),
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/admin/jwt-keys/rotate",
+    tag = "admin",
+    responses(
+        (status = 200, description = "The new signing key and the keys still verifying"),
+        (status = 404, description = "User accounts are disabled", body = ErrorResponse),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn rotate_signing_key_handler(
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let auth = data.auth.as_ref().ok_or_else(accounts_disabled)?;
+    let key = auth.keys.rotate(&data.db, data.clock.now()).await?;
+    tracing::info!(kid = %key.kid, "Rotated the JWT signing key");
+
+    let keys = auth
+        .keys
+        .keys()
+        .iter()
+        .map(|key| {
+            json!({
+                "kid": key.kid,
+                "created_at": key.created_at,
+                "retired_at": key.retired_at,
+            })
+        })
+        .collect::<Vec<_>>();
+    Ok(Json(json!({
+        "status": "success",
+        "data": {
+            "kid": key.kid,
+            "keys": keys,
+        }
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 5, 3, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn sealed_keys_open_only_with_their_secret_and_id() {
+        let sealer = Sealer::new(b"secret");
+        let sealed = sealer.seal("kid-1", b"private key");
+        assert_ne!(&sealed[aead::NONCE_LEN..], b"private key");
+        assert_eq!(
+            sealer.open("kid-1", &sealed).as_deref(),
+            Some(&b"private key"[..])
+        );
+        assert_eq!(sealer.open("kid-2", &sealed), None);
+        assert_eq!(Sealer::new(b"other secret").open("kid-1", &sealed), None);
+        assert_eq!(sealer.open("kid-1", &sealed[..4]), None);
+    }
+
+    #[test]
+    fn stored_keys_sign_like_the_generated_ones() {
+        let (key, pkcs8) = SigningKey::generate(now());
+        let restored = SigningKey::from_pkcs8(key.kid.clone(), &pkcs8, now(), None).unwrap();
+        assert_eq!(restored.jwk(), key.jwk());
+        assert_eq!(key.jwk()["kty"], "OKP");
+        assert_eq!(key.jwk()["x"].as_str().unwrap().len(), 43);
+    }
+
+    #[test]
+    fn rotation_retires_the_signing_key_until_its_tokens_expire() {
+        let ring = KeyRing::new(b"secret", Duration::from_secs(3600));
+        let first = ring.signing_key();
+
+        let (second, _) = SigningKey::generate(now());
+        let second = ring.install(second, now());
+        assert_eq!(ring.signing_key().kid, second.kid);
+        let retired = ring.find(&first.kid).unwrap();
+        assert_eq!(retired.retired_at, Some(now()));
+
+        // Still verifying an hour (plus the grace) later, gone after that.
+        let later = now() + chrono::Duration::hours(1);
+        let (third, _) = SigningKey::generate(later);
+        ring.install(third, later);
+        assert!(ring.find(&first.kid).is_some());
+        let much_later = later + chrono::Duration::hours(1);
+        let (fourth, _) = SigningKey::generate(much_later);
+        let fourth = ring.install(fourth, much_later);
+        assert!(ring.find(&first.kid).is_none());
+        assert!(ring.find(&second.kid).is_some());
+        assert_eq!(ring.keys().len(), 3);
+        assert_eq!(ring.signing_key().kid, fourth.kid);
+    }
+}
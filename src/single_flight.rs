@@ -0,0 +1,242 @@
+//! Request coalescing for hot reads.
+//!
+//! When many requests miss on the same note or list page at once (a public
+//! note going viral), only the first runs the query; the rest wait for it and
+//! share its result. Calls are forgotten as soon as they finish, so nothing is
+//! served that a request arriving after the query completed would not see.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use axum::{extract::State, response::IntoResponse, Json};
+use serde_json::{json, Value};
+use tokio::sync::OnceCell;
+
+use crate::{
+    model::{NoteId, NoteModel},
+    AppState,
+};
+
+pub struct SingleFlight<K, V> {
+    calls: Mutex<HashMap<K, Arc<OnceCell<V>>>>,
+    executed: AtomicU64,
+    coalesced: AtomicU64,
+}
+
+impl<K, V> Default for SingleFlight<K, V> {
+    fn default() -> Self {
+        Self {
+            calls: Mutex::new(HashMap::new()),
+            executed: AtomicU64::new(0),
+            coalesced: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> SingleFlight<K, V> {
+    /// Runs `load` unless a call for `key` is already in flight, in which case
+    /// its result is awaited instead. If the running call is cancelled, one of
+    /// the waiters runs its own `load` in its place.
+    pub async fn run<F, Fut>(&self, key: K, load: F) -> V
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V>,
+    {
+        let call = {
+            let mut calls = self.calls.lock().unwrap();
+            match calls.get(&key) {
+                Some(call) => {
+                    self.coalesced.fetch_add(1, Ordering::Relaxed);
+                    call.clone()
+                }
+                None => calls.entry(key.clone()).or_default().clone(),
+            }
+        };
+
+        let value = call
+            .get_or_init(|| {
+                self.executed.fetch_add(1, Ordering::Relaxed);
+                load()
+            })
+            .await
+            .clone();
+
+        let mut calls = self.calls.lock().unwrap();
+        if calls
+            .get(&key)
+            .is_some_and(|current| Arc::ptr_eq(current, &call))
+        {
+            calls.remove(&key);
+        }
+        value
+    }
+
+    pub fn snapshot(&self) -> Value {
+        json!({
+            "executed": self.executed.load(Ordering::Relaxed),
+            "coalesced": self.coalesced.load(Ordering::Relaxed),
+            "in_flight": self.calls.lock().unwrap().len(),
+        })
+    }
+}
+
+/// Errors are shared between coalesced requests, hence the `Arc`.
+pub type NoteLookup = Result<NoteModel, Arc<sqlx::Error>>;
+pub type PageLookup = Result<Vec<NoteModel>, Arc<sqlx::Error>>;
+
+#[derive(Default)]
+pub struct ReadCoalescing {
+    /// Single-note reads by id.
+    pub notes: SingleFlight<NoteId, NoteLookup>,
+    /// List pages by `(limit, offset)`.
+    pub pages: SingleFlight<(usize, usize), PageLookup>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/single-flight",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Request coalescing statistics"),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn single_flight_handler(State(data): State<Arc<AppState>>) -> impl IntoResponse {
+    Json(json!({
+        "status": "success",
+        "data": json!({
+            "notes": data.coalescing.notes.snapshot(),
+            "pages": data.coalescing.pages.snapshot(),
+        })
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use tokio::sync::Semaphore;
+
+    use super::*;
+
+    type Lookup = Result<u32, Arc<String>>;
+
+    /// Starts `callers` runs for one key whose load waits for `gate`, and
+    /// waits until all but the first are waiting on it.
+    async fn pile_up(
+        flight: &Arc<SingleFlight<&'static str, Lookup>>,
+        loads: &Arc<AtomicUsize>,
+        gate: &Arc<Semaphore>,
+        callers: usize,
+        result: Lookup,
+    ) -> Vec<tokio::task::JoinHandle<Lookup>> {
+        let handles = (0..callers)
+            .map(|_| {
+                let (flight, loads, gate) = (flight.clone(), loads.clone(), gate.clone());
+                let result = result.clone();
+                tokio::spawn(async move {
+                    flight
+                        .run("key", || async move {
+                            loads.fetch_add(1, Ordering::SeqCst);
+                            let _ = gate.acquire().await.unwrap();
+                            result
+                        })
+                        .await
+                })
+            })
+            .collect::<Vec<_>>();
+        while flight.snapshot()["coalesced"] != json!(callers - 1) {
+            tokio::task::yield_now().await;
+        }
+        handles
+    }
+
+    #[tokio::test]
+    async fn concurrent_callers_share_one_load() {
+        let flight = Arc::new(SingleFlight::default());
+        let loads = Arc::new(AtomicUsize::new(0));
+        let gate = Arc::new(Semaphore::new(0));
+
+        let handles = pile_up(&flight, &loads, &gate, 10, Ok(7)).await;
+        assert_eq!(flight.snapshot()["in_flight"], json!(1));
+        gate.add_permits(1);
+        for handle in handles {
+            assert_eq!(handle.await.unwrap(), Ok(7));
+        }
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        assert_eq!(flight.snapshot()["executed"], json!(1));
+
+        // Finished calls are forgotten: the next one loads again.
+        assert_eq!(flight.run("key", || async { Ok(8) }).await, Ok(8));
+        assert_eq!(flight.snapshot()["executed"], json!(2));
+        assert_eq!(flight.snapshot()["in_flight"], json!(0));
+    }
+
+    #[tokio::test]
+    async fn errors_reach_every_waiting_caller() {
+        let flight = Arc::new(SingleFlight::default());
+        let loads = Arc::new(AtomicUsize::new(0));
+        let gate = Arc::new(Semaphore::new(0));
+
+        let error = Arc::new("connection reset".to_string());
+        let handles = pile_up(&flight, &loads, &gate, 5, Err(error.clone())).await;
+        gate.add_permits(1);
+        for handle in handles {
+            let err = handle.await.unwrap().unwrap_err();
+            assert!(Arc::ptr_eq(&err, &error));
+        }
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+
+        // Nor are errors kept around.
+        assert_eq!(flight.run("key", || async { Ok(1) }).await, Ok(1));
+    }
+
+    #[tokio::test]
+    async fn keys_do_not_share_loads() {
+        let flight = SingleFlight::<u32, u32>::default();
+        let (a, b) = tokio::join!(
+            flight.run(1, || async { 10 }),
+            flight.run(2, || async { 20 }),
+        );
+        assert_eq!((a, b), (10, 20));
+        assert_eq!(flight.snapshot()["coalesced"], json!(0));
+    }
+
+    #[tokio::test]
+    async fn a_waiter_loads_in_place_of_a_cancelled_call() {
+        let flight = Arc::new(SingleFlight::<&'static str, u32>::default());
+        let gate = Arc::new(Semaphore::new(0));
+
+        let first = {
+            let (flight, gate) = (flight.clone(), gate.clone());
+            tokio::spawn(async move {
+                flight
+                    .run("key", || async move {
+                        let _ = gate.acquire().await.unwrap();
+                        1
+                    })
+                    .await
+            })
+        };
+        while flight.snapshot()["executed"] != json!(1) {
+            tokio::task::yield_now().await;
+        }
+        let second = {
+            let flight = flight.clone();
+            tokio::spawn(async move { flight.run("key", || async { 2 }).await })
+        };
+        while flight.snapshot()["coalesced"] != json!(1) {
+            tokio::task::yield_now().await;
+        }
+
+        first.abort();
+        assert_eq!(second.await.unwrap(), 2);
+    }
+}
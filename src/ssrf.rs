@@ -0,0 +1,394 @@
+//! SSRF protection for requests to URLs that users or admins supply.
+//!
+//! The shared HTTP client sends alert webhooks, link previews, link checks
+//! and web clips through an [`OutboundGuard`]. Host names are resolved by the
+//! guard, which only hands out addresses outside the loopback, private,
+//! link-local and other internal ranges, so a public name pointing into the
+//! network is refused at connect time, not on its spelling. URLs with IP
+//! addresses, which are never resolved, and every redirect are checked too.
+//!
+//! `OUTBOUND_ALLOW` lists networks that may be reached although they are
+//! internal, such as an in-house webhook receiver. `OUTBOUND_DENY` lists
+//! networks and domains that are never reached, allowed or not.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+
+use hyper::client::connect::dns::Name;
+use ipnet::IpNet;
+use reqwest::{
+    dns::{Addrs, Resolve, Resolving},
+    redirect::Policy,
+    Url,
+};
+
+/// Redirects followed before a request fails, as reqwest does by default.
+const MAX_REDIRECTS: usize = 10;
+
+#[derive(Debug, Default, Clone)]
+pub struct OutboundGuard {
+    /// Internal networks that may be reached anyway.
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+    /// Domains never reached, along with their subdomains.
+    pub deny_domains: Vec<String>,
+}
+
+/// Whether `ip` is in a range that is not on the public internet.
+fn is_internal(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // "This network", shared address space (carrier NAT),
+                // benchmarking and reserved.
+                || a == 0
+                || (a == 100 && b & 0xc0 == 64)
+                || (a == 198 && b & 0xfe == 18)
+                || a >= 240
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            // NAT64 addresses reach the IPv4 address in their last 32 bits.
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                let [.., high, low] = segments;
+                return is_internal(Ipv4Addr::from((high as u32) << 16 | low as u32).into());
+            }
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+                || segments[..2] == [0x2001, 0xdb8]
+        }
+    }
+}
+
+fn matches_domain(host: &str, domain: &str) -> bool {
+    host == domain
+        || host
+            .strip_suffix(domain)
+            .is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+impl OutboundGuard {
+    /// Parses `OUTBOUND_ALLOW` networks and `OUTBOUND_DENY` networks and
+    /// domains. A single address is a network of one.
+    pub fn parse(allow: &[String], deny: &[String]) -> Result<Self, String> {
+        let mut guard = Self::default();
+        for entry in allow {
+            guard.allow.push(
+                parse_network(entry)
+                    .ok_or_else(|| format!("'{}' is not an IP address or network", entry))?,
+            );
+        }
+        for entry in deny {
+            match parse_network(entry) {
+                Some(network) => guard.deny.push(network),
+                None => guard
+                    .deny_domains
+                    .push(entry.trim_start_matches('.').to_ascii_lowercase()),
+            }
+        }
+        Ok(guard)
+    }
+
+    pub fn check_ip(&self, ip: IpAddr) -> Result<(), String> {
+        let ip = ip.to_canonical();
+        if self.deny.iter().any(|network| network.contains(&ip)) {
+            return Err(format!("{} is denied", ip));
+        }
+        if is_internal(ip) && !self.allow.iter().any(|network| network.contains(&ip)) {
+            return Err(format!("{} is an internal address", ip));
+        }
+        Ok(())
+    }
+
+    pub fn check_domain(&self, host: &str) -> Result<(), String> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        if host == "localhost" || host.ends_with(".localhost") {
+            return Err(format!("{} is an internal address", host));
+        }
+        if self
+            .deny_domains
+            .iter()
+            .any(|domain| matches_domain(&host, domain))
+        {
+            return Err(format!("{} is denied", host));
+        }
+        Ok(())
+    }
+
+    /// Checks what can be checked of `url` before connecting: IP addresses
+    /// and denied domains. Addresses of host names are checked as they are
+    /// resolved.
+    pub fn check_url(&self, url: &Url) -> Result<(), String> {
+        let Some(host) = url.host_str() else {
+            return Err(format!("{} has no host", url));
+        };
+        match host
+            .trim_matches(|c| c == '[' || c == ']')
+            .parse::<IpAddr>()
+        {
+            Ok(ip) => self.check_ip(ip),
+            Err(_) => self.check_domain(host),
+        }
+    }
+}
+
+fn parse_network(entry: &str) -> Option<IpNet> {
+    entry
+        .parse::<IpNet>()
+        .ok()
+        .or_else(|| entry.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+/// Resolves host names to the addresses the guard permits.
+pub struct GuardedResolver(Arc<OutboundGuard>);
+
+impl GuardedResolver {
+    pub fn new(guard: Arc<OutboundGuard>) -> Self {
+        Self(guard)
+    }
+}
+
+impl Resolve for GuardedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let guard = self.0.clone();
+        Box::pin(async move {
+            let host = name.as_str();
+            guard.check_domain(host)?;
+            let addrs = tokio::net::lookup_host((host, 0))
+                .await?
+                .filter(|addr| guard.check_ip(addr.ip()).is_ok())
+                .collect::<Vec<SocketAddr>>();
+            if addrs.is_empty() {
+                return Err(format!("{} resolves to no permitted address", host).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Follows redirects only to URLs the guard lets through.
+pub fn redirect_policy(guard: Arc<OutboundGuard>) -> Policy {
+    Policy::custom(move |attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            return attempt.error("too many redirects");
+        }
+        match guard.check_url(attempt.url()) {
+            Ok(()) => attempt.follow(),
+            Err(err) => attempt.error(format!("redirect blocked: {}", err)),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use axum::{response::Redirect, routing::get, Router};
+
+    use super::*;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    fn guard(allow: &[&str], deny: &[&str]) -> OutboundGuard {
+        let strings = |entries: &[&str]| entries.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        OutboundGuard::parse(&strings(allow), &strings(deny)).unwrap()
+    }
+
+    #[test]
+    fn loopback_and_link_local_are_internal() {
+        for addr in [
+            "127.0.0.1",
+            "127.255.0.9",
+            "::1",
+            "169.254.169.254",
+            "fe80::1",
+            "0.0.0.0",
+            "::",
+        ] {
+            assert!(is_internal(ip(addr)), "{}", addr);
+        }
+        for addr in ["93.184.216.34", "8.8.8.8", "2606:4700::1111"] {
+            assert!(!is_internal(ip(addr)), "{}", addr);
+        }
+    }
+
+    #[test]
+    fn private_and_reserved_ranges_are_internal() {
+        for addr in [
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "198.18.0.1",
+            "240.0.0.1",
+            "255.255.255.255",
+            "224.0.0.1",
+            "fd00::1",
+            "2001:db8::1",
+        ] {
+            assert!(is_internal(ip(addr)), "{}", addr);
+        }
+    }
+
+    #[test]
+    fn ipv4_mapped_ipv6_is_checked_as_ipv4() {
+        assert!(is_internal(ip("::ffff:127.0.0.1")));
+        assert!(is_internal(ip("::ffff:169.254.169.254")));
+        assert!(is_internal(ip("::ffff:10.0.0.1")));
+        assert!(!is_internal(ip("::ffff:93.184.216.34")));
+        assert!(guard(&[], &[]).check_ip(ip("::ffff:127.0.0.1")).is_err());
+    }
+
+    #[test]
+    fn nat64_is_checked_as_the_embedded_ipv4() {
+        let nat64 = |v4: Ipv4Addr| {
+            let [a, b, c, d] = v4.octets();
+            IpAddr::V6(Ipv6Addr::new(
+                0x64,
+                0xff9b,
+                0,
+                0,
+                0,
+                0,
+                u16::from_be_bytes([a, b]),
+                u16::from_be_bytes([c, d]),
+            ))
+        };
+        assert!(is_internal(nat64(Ipv4Addr::LOCALHOST)));
+        assert!(is_internal(nat64(Ipv4Addr::new(169, 254, 169, 254))));
+        assert!(is_internal(nat64(Ipv4Addr::new(192, 168, 0, 1))));
+        assert!(!is_internal(nat64(Ipv4Addr::new(93, 184, 216, 34))));
+        assert!(is_internal(ip("64:ff9b::a00:1")));
+    }
+
+    #[test]
+    fn shared_address_space_is_internal() {
+        assert!(is_internal(ip("100.64.0.0")));
+        assert!(is_internal(ip("100.100.100.100")));
+        assert!(is_internal(ip("100.127.255.255")));
+        assert!(!is_internal(ip("100.63.255.255")));
+        assert!(!is_internal(ip("100.128.0.0")));
+    }
+
+    #[test]
+    fn allow_lets_internal_networks_through_but_deny_wins() {
+        let guard = guard(&["10.0.0.0/8", "127.0.0.1"], &["10.0.0.5", "8.8.8.0/24"]);
+        assert!(guard.check_ip(ip("10.1.2.3")).is_ok());
+        assert!(guard.check_ip(ip("127.0.0.1")).is_ok());
+        assert!(guard.check_ip(ip("127.0.0.2")).is_err());
+        assert_eq!(
+            guard.check_ip(ip("10.0.0.5")),
+            Err("10.0.0.5 is denied".to_string())
+        );
+        // Denied public networks too, and mapped addresses of them.
+        assert!(guard.check_ip(ip("8.8.8.8")).is_err());
+        assert!(guard.check_ip(ip("::ffff:10.0.0.5")).is_err());
+        assert!(guard.check_ip(ip("1.1.1.1")).is_ok());
+    }
+
+    #[test]
+    fn parse_rejects_malformed_allow_entries() {
+        assert!(OutboundGuard::parse(&["example.com".to_string()], &[]).is_err());
+    }
+
+    #[test]
+    fn denied_domains_cover_their_subdomains() {
+        let guard = guard(&[], &[".Example.com", "internal"]);
+        assert!(guard.check_domain("example.com").is_err());
+        assert!(guard.check_domain("EXAMPLE.com.").is_err());
+        assert!(guard.check_domain("api.example.com").is_err());
+        assert!(guard.check_domain("a.b.example.com").is_err());
+        assert!(guard.check_domain("notexample.com").is_ok());
+        assert!(guard.check_domain("example.com.evil.net").is_ok());
+        assert!(guard.check_domain("db.internal").is_err());
+        assert!(guard.check_domain("localhost").is_err());
+        assert!(guard.check_domain("app.localhost").is_err());
+    }
+
+    #[test]
+    fn check_url_checks_literal_addresses() {
+        let guard = guard(&[], &["example.com"]);
+        let check = |url: &str| guard.check_url(&Url::parse(url).unwrap());
+        assert!(check("http://127.0.0.1:8000/").is_err());
+        assert!(check("http://[::1]/").is_err());
+        assert!(check("http://[::ffff:169.254.169.254]/latest").is_err());
+        assert!(check("http://2130706433/").is_err());
+        assert!(check("https://www.example.com/").is_err());
+        assert!(check("https://93.184.216.34/").is_ok());
+        assert!(check("https://rust-lang.org/").is_ok());
+    }
+
+    /// Serves redirects on loopback, which the guards below allow.
+    async fn redirecting_server() -> SocketAddr {
+        let app = Router::new()
+            .route("/start", get(|| async { Redirect::temporary("/hop") }))
+            .route(
+                "/hop",
+                get(|| async { Redirect::temporary("http://169.254.169.254/latest/meta-data") }),
+            )
+            .route("/safe", get(|| async { Redirect::temporary("/done") }))
+            .route("/done", get(|| async { "done" }));
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    fn client(guard: OutboundGuard) -> reqwest::Client {
+        reqwest::Client::builder()
+            .redirect(redirect_policy(Arc::new(guard)))
+            .no_proxy()
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn redirect_policy_checks_every_hop() {
+        let addr = redirecting_server().await;
+        let client = client(guard(&["127.0.0.1"], &[]));
+
+        let done = client
+            .get(format!("http://{}/safe", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(done.text().await.unwrap(), "done");
+
+        // The first hop stays on the allowed host, the second does not.
+        let err = client
+            .get(format!("http://{}/start", addr))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(err.is_redirect(), "{:?}", err);
+        assert!(format!("{:?}", err)
+            .contains("redirect blocked: 169.254.169.254 is an internal address"));
+    }
+
+    #[tokio::test]
+    async fn redirect_policy_applies_deny_to_each_hop() {
+        let addr = redirecting_server().await;
+        // The first request is not the policy's to check, its redirect is.
+        let client = client(guard(&["127.0.0.0/8"], &["127.0.0.1"]));
+        let err = client
+            .get(format!("http://{}/safe", addr))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(err.is_redirect(), "{:?}", err);
+        assert!(format!("{:?}", err).contains("redirect blocked: 127.0.0.1 is denied"));
+    }
+}
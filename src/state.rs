@@ -0,0 +1,95 @@
+//! The services in [`AppState`] that handlers can extract on their own.
+//!
+//! A handler taking `State<DbPool>`, `State<EventBus>`, `State<Cache>` or
+//! `State<Settings>` instead of `State<Arc<AppState>>` declares what it
+//! uses. Axum derives each of them from the full state through `FromRef`,
+//! and a router whose state is just one of them serves the handler too,
+//! which is all a test of it has to build. Each is cheap to clone.
+
+use std::{ops::Deref, sync::Arc, time::Duration};
+
+use axum::extract::FromRef;
+use sqlx::mysql::MySqlPool;
+
+use crate::{
+    events::EventHub, negative_cache::NegativeCache, note_cache::NoteCache,
+    note_index::NoteIndexCache, tag::TagCloudCache, AppState,
+};
+
+#[derive(Clone)]
+pub struct DbPool(pub MySqlPool);
+
+impl Deref for DbPool {
+    type Target = MySqlPool;
+
+    fn deref(&self) -> &MySqlPool {
+        &self.0
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct EventBus(pub Arc<EventHub>);
+
+impl Deref for EventBus {
+    type Target = EventHub;
+
+    fn deref(&self) -> &EventHub {
+        &self.0
+    }
+}
+
+/// The caches in front of the notes table.
+#[derive(Clone)]
+pub struct Cache {
+    /// Redis cache of single notes, enabled by `REDIS_URL`.
+    pub notes: Option<NoteCache>,
+    /// Note ids recently looked up and not found.
+    pub missing_notes: Arc<NegativeCache>,
+    pub note_index: Arc<NoteIndexCache>,
+    pub tag_cloud: Arc<TagCloudCache>,
+}
+
+/// Configuration the handlers consult.
+#[derive(Clone)]
+pub struct Settings {
+    pub admin_token: Option<String>,
+    /// Whether `X-Forwarded-For` identifies the client (behind a trusted proxy).
+    pub trust_forwarded_for: bool,
+    /// Notes with more content than this are rejected.
+    pub max_content_bytes: usize,
+    /// Uploads bigger than this are rejected.
+    pub max_attachment_bytes: usize,
+    /// Attachments bigger than this are listed, not embedded, in HTML exports.
+    pub export_embed_max_bytes: usize,
+    /// Whether `GET /api/graphql` serves the GraphQL Playground.
+    pub graphql_playground: bool,
+    pub summary_refresh_interval: Duration,
+    /// Size limit of the connection pool.
+    pub db_max_connections: u32,
+    /// How long requests have to be answered; see [`crate::context::RequestContext`].
+    pub request_timeout: Duration,
+}
+
+impl FromRef<Arc<AppState>> for DbPool {
+    fn from_ref(data: &Arc<AppState>) -> Self {
+        Self(data.db.clone())
+    }
+}
+
+impl FromRef<Arc<AppState>> for EventBus {
+    fn from_ref(data: &Arc<AppState>) -> Self {
+        data.events.clone()
+    }
+}
+
+impl FromRef<Arc<AppState>> for Cache {
+    fn from_ref(data: &Arc<AppState>) -> Self {
+        data.cache.clone()
+    }
+}
+
+impl FromRef<Arc<AppState>> for Settings {
+    fn from_ref(data: &Arc<AppState>) -> Self {
+        data.settings.clone()
+    }
+}
@@ -0,0 +1,324 @@
+//! Materialized summaries for expensive, frequently-read aggregates.
+//!
+//! A background job recomputes the summary tables on an interval; the stats,
+//! trending and facet endpoints read those tables and report how old the data
+//! they return is. User accounts get the same aggregates over their own notes
+//! instead, which are few enough to compute on each request.
+
+use std::{sync::Arc, time::Duration};
+
+use axum::{extract::State, response::IntoResponse, Json};
+use chrono::{DateTime, Timelike, Utc};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::mysql::MySqlPool;
+
+use crate::{
+    auth::NoteScope,
+    clock::Clock,
+    error::AppError,
+    lock::DistributedLock,
+    model::{NoteId, UserId},
+    AppState,
+};
+
+/// Notes accessed within this window are candidates for the trending list.
+const TRENDING_WINDOW_DAYS: i64 = 7;
+const TRENDING_LIMIT: i64 = 20;
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct NoteStatsSummary {
+    pub total_notes: i64,
+    pub published_notes: i64,
+    pub total_views: i64,
+    pub refreshed_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct CategoryFacet {
+    pub category: String,
+    pub note_count: i64,
+    pub published_count: i64,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct TrendingNote {
+    pub position: i32,
+    pub note_id: NoteId,
+    pub title: String,
+    pub view_count: i64,
+}
+
+/// Lease held by the replica that refreshes the summary tables.
+const REFRESH_LOCK: &str = "summary-refresh";
+
+/// Refreshes the summaries on whichever replica holds the refresh lease. The
+/// lease outlives two intervals, so the holder keeps it by renewing each tick
+/// and another replica only takes over once the holder stops.
+pub fn spawn_refresher(
+    db: MySqlPool,
+    clock: Arc<dyn Clock>,
+    locks: Arc<DistributedLock>,
+    interval: Duration,
+) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match locks.try_acquire(REFRESH_LOCK, interval * 2).await {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(err) => {
+                    tracing::error!(error = ?err, "Failed to acquire summary refresh lock");
+                    continue;
+                }
+            }
+            if let Err(err) = refresh(&db, clock.now()).await {
+                tracing::error!(error = ?err, "Failed to refresh summary tables");
+            }
+        }
+    });
+}
+
+/// Recomputes every summary table in one transaction so readers never see a
+/// mix of old and new aggregates.
+pub async fn refresh(db: &MySqlPool, now: DateTime<Utc>) -> Result<(), sqlx::Error> {
+    let now = now.with_nanosecond(0).unwrap();
+    let mut tx = db.begin().await?;
+
+    sqlx::query(
+        r#"REPLACE INTO note_stats_summary (id, total_notes, published_notes, total_views, refreshed_at)
+        SELECT 1, COUNT(*), COALESCE(SUM(published), 0), COALESCE(SUM(view_count), 0), ?
+        FROM notes WHERE deleted_at IS NULL AND (expires_at IS NULL OR expires_at > ?)"#,
+    )
+    .bind(now)
+    .bind(now)
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query("DELETE FROM note_category_facets")
+        .execute(&mut tx)
+        .await?;
+    sqlx::query(
+        r#"INSERT INTO note_category_facets (category, note_count, published_count)
+        SELECT COALESCE(category, ''), COUNT(*), COALESCE(SUM(published), 0)
+        FROM notes WHERE deleted_at IS NULL AND (expires_at IS NULL OR expires_at > ?)
+        GROUP BY COALESCE(category, '')"#,
+    )
+    .bind(now)
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query("DELETE FROM note_trending")
+        .execute(&mut tx)
+        .await?;
+    sqlx::query(
+        r#"INSERT INTO note_trending (position, note_id, title, view_count)
+        SELECT ROW_NUMBER() OVER (ORDER BY view_count DESC, id), id, title, view_count
+        FROM notes WHERE last_accessed_at >= ? AND deleted_at IS NULL AND (expires_at IS NULL OR expires_at > ?)
+        ORDER BY view_count DESC, id LIMIT ?"#,
+    )
+    .bind(now - chrono::Duration::days(TRENDING_WINDOW_DAYS))
+    .bind(now)
+    .bind(TRENDING_LIMIT)
+    .execute(&mut tx)
+    .await?;
+
+    tx.commit().await
+}
+
+/// The notes of `owner` live at `now`, as the summary tables would count them.
+const OWNER_NOTES: &str =
+    "FROM notes WHERE user_id = ? AND deleted_at IS NULL AND (expires_at IS NULL OR expires_at > ?)";
+
+async fn owner_stats(
+    db: &MySqlPool,
+    owner: UserId,
+    now: DateTime<Utc>,
+) -> Result<NoteStatsSummary, sqlx::Error> {
+    let (total_notes, published_notes, total_views) = sqlx::query_as(&format!(
+        "SELECT COUNT(*), CAST(COALESCE(SUM(published), 0) AS SIGNED), \
+        CAST(COALESCE(SUM(view_count), 0) AS SIGNED) {}",
+        OWNER_NOTES
+    ))
+    .bind(owner)
+    .bind(now)
+    .fetch_one(db)
+    .await?;
+    Ok(NoteStatsSummary {
+        total_notes,
+        published_notes,
+        total_views,
+        refreshed_at: now,
+    })
+}
+
+async fn owner_facets(
+    db: &MySqlPool,
+    owner: UserId,
+    now: DateTime<Utc>,
+) -> Result<Vec<CategoryFacet>, sqlx::Error> {
+    sqlx::query_as(&format!(
+        "SELECT COALESCE(category, '') AS category, COUNT(*) AS note_count, \
+        CAST(COALESCE(SUM(published), 0) AS SIGNED) AS published_count {} \
+        GROUP BY COALESCE(category, '') ORDER BY note_count DESC, category",
+        OWNER_NOTES
+    ))
+    .bind(owner)
+    .bind(now)
+    .fetch_all(db)
+    .await
+}
+
+async fn owner_trending(
+    db: &MySqlPool,
+    owner: UserId,
+    now: DateTime<Utc>,
+) -> Result<Vec<TrendingNote>, sqlx::Error> {
+    let notes: Vec<(NoteId, String, i64)> = sqlx::query_as(&format!(
+        "SELECT id, title, CAST(view_count AS SIGNED) {} AND last_accessed_at >= ? \
+        ORDER BY view_count DESC, id LIMIT ?",
+        OWNER_NOTES
+    ))
+    .bind(owner)
+    .bind(now)
+    .bind(now - chrono::Duration::days(TRENDING_WINDOW_DAYS))
+    .bind(TRENDING_LIMIT)
+    .fetch_all(db)
+    .await?;
+    Ok(notes
+        .into_iter()
+        .zip(1..)
+        .map(|((note_id, title, view_count), position)| TrendingNote {
+            position,
+            note_id,
+            title,
+            view_count,
+        })
+        .collect())
+}
+
+/// Describes how fresh a summary is. Data older than two refresh intervals is
+/// flagged as stale, which usually means the refresher is failing.
+fn staleness(refreshed_at: Option<DateTime<Utc>>, data: &AppState) -> Value {
+    let interval = data.settings.summary_refresh_interval.as_secs() as i64;
+    let age = refreshed_at.map(|at| (data.clock.now() - at).num_seconds().max(0));
+
+    json!({
+        "refreshed_at": refreshed_at,
+        "age_seconds": age,
+        "refresh_interval_seconds": interval,
+        "stale": age.is_none_or(|age| age > interval * 2),
+    })
+}
+
+/// All summaries are refreshed together, so the stats row dates every table.
+async fn last_refreshed_at(db: &MySqlPool) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+    sqlx::query_scalar("SELECT refreshed_at FROM note_stats_summary WHERE id = 1")
+        .fetch_optional(db)
+        .await
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/notes/stats",
+    tag = "notes",
+    responses(
+        (status = 200, description = "Note statistics"),
+    ),
+)]
+pub async fn note_stats_handler(
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let stats = match scope.owner() {
+        Some(owner) => owner_stats(&data.db, owner, data.clock.now())
+            .await
+            .map(Some),
+        None => sqlx::query_as::<_, NoteStatsSummary>(
+            "SELECT total_notes, published_notes, total_views, refreshed_at FROM note_stats_summary WHERE id = 1",
+        )
+        .fetch_optional(&data.db)
+        .await,
+    }?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "summary": staleness(stats.as_ref().map(|stats| stats.refreshed_at), &data),
+        "data": json!({
+            "stats": stats,
+        })
+    })))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/notes/facets",
+    tag = "notes",
+    responses(
+        (status = 200, description = "Note counts per category"),
+    ),
+)]
+pub async fn category_facets_handler(
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let (facets, refreshed_at) = match scope.owner() {
+        Some(owner) => {
+            let now = data.clock.now();
+            let facets = owner_facets(&data.db, owner, now).await?;
+            (facets, Some(now))
+        }
+        None => {
+            let facets = sqlx::query_as::<_, CategoryFacet>(
+                "SELECT category, note_count, published_count FROM note_category_facets ORDER BY note_count DESC, category",
+            )
+            .fetch_all(&data.db)
+            .await?;
+            (facets, last_refreshed_at(&data.db).await?)
+        }
+    };
+
+    Ok(Json(json!({
+        "status": "success",
+        "summary": staleness(refreshed_at, &data),
+        "results": facets.len(),
+        "facets": facets,
+    })))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/notes/trending",
+    tag = "notes",
+    responses(
+        (status = 200, description = "The most viewed notes"),
+    ),
+)]
+pub async fn trending_notes_handler(
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let (trending, refreshed_at) = match scope.owner() {
+        Some(owner) => {
+            let now = data.clock.now();
+            let trending = owner_trending(&data.db, owner, now).await?;
+            (trending, Some(now))
+        }
+        None => {
+            let trending = sqlx::query_as::<_, TrendingNote>(
+                "SELECT position, note_id, title, view_count FROM note_trending ORDER BY position",
+            )
+            .fetch_all(&data.db)
+            .await?;
+            (trending, last_refreshed_at(&data.db).await?)
+        }
+    };
+
+    Ok(Json(json!({
+        "status": "success",
+        "summary": staleness(refreshed_at, &data),
+        "results": trending.len(),
+        "notes": trending,
+    })))
+}
@@ -0,0 +1,641 @@
+//! Tags, attached to notes through `note_tags`.
+//!
+//! Tags are managed at `/api/tags` and a note's set of tags is replaced with
+//! `PUT /api/notes/:id/tags`, creating tags that do not exist yet. Like note
+//! titles, tag names are unique per owner: users only see their own tags, and
+//! the tags of a note always belong to the note's owner.
+//!
+//! `GET /api/tags/cloud` weighs the most used tags for display as a cloud.
+//! Clouds are cached per owner and filter; tag changes on this instance drop
+//! them, note changes make them stale, and [`CLOUD_TTL`] bounds how long
+//! changes through other replicas go unnoticed.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde::Serialize;
+use serde_json::json;
+use sqlx::{mysql::MySqlPool, MySql, QueryBuilder};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    auth::NoteScope,
+    error::AppError,
+    events::{NoteEvent, NoteEventKind},
+    model::{BinaryId, NoteId, TagModel, UserId},
+    schema::{NoteTagsSchema, TagCloudOptions, TagSchema},
+    state::{Cache, DbPool},
+    AppState,
+};
+
+const MAX_NAME_CHARS: usize = 100;
+const MAX_TAGS_PER_NOTE: usize = 50;
+/// Clouds show at most this many of the most used tags.
+const MAX_CLOUD_TAGS: i64 = 200;
+pub const CLOUD_TTL: Duration = Duration::from_secs(60);
+/// Distinct filters cached before the cache starts over.
+const MAX_CACHED_CLOUDS: usize = 1000;
+
+pub const SELECT_TAG_BY_ID: &str = "SELECT id, user_id, name, created_at FROM tags WHERE id = ?";
+pub const SELECT_NOTE_TAGS: &str = r#"SELECT tags.id, tags.user_id, tags.name, tags.created_at FROM note_tags JOIN tags ON tags.id = note_tags.tag_id WHERE note_tags.note_id = ? ORDER BY tags.name"#;
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct TagSummary {
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub tag: TagModel,
+    pub note_count: i64,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct CloudTag {
+    pub id: BinaryId,
+    pub name: String,
+    pub note_count: i64,
+    /// `note_count` scaled onto 0..=1 between the least and most used tag.
+    #[sqlx(default)]
+    pub weight: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CloudKey {
+    owner: Option<UserId>,
+    options: TagCloudOptions,
+}
+
+struct CachedCloud {
+    seq: u64,
+    built_at: Instant,
+    tags: Vec<CloudTag>,
+}
+
+#[derive(Default)]
+pub struct TagCloudCache {
+    clouds: Mutex<HashMap<CloudKey, CachedCloud>>,
+}
+
+impl TagCloudCache {
+    fn get(&self, key: &CloudKey, seq: u64) -> Option<Vec<CloudTag>> {
+        self.clouds
+            .lock()
+            .unwrap()
+            .get(key)
+            .filter(|cached| cached.seq == seq && cached.built_at.elapsed() < CLOUD_TTL)
+            .map(|cached| cached.tags.clone())
+    }
+
+    fn insert(&self, key: CloudKey, seq: u64, tags: Vec<CloudTag>) {
+        let mut clouds = self.clouds.lock().unwrap();
+        if clouds.len() >= MAX_CACHED_CLOUDS {
+            clouds.clear();
+        }
+        clouds.insert(
+            key,
+            CachedCloud {
+                seq,
+                built_at: Instant::now(),
+                tags,
+            },
+        );
+    }
+
+    /// Tag writes do not show up as note events, so they drop every cloud.
+    fn invalidate(&self) {
+        self.clouds.lock().unwrap().clear();
+    }
+}
+
+/// Sets the weights of `tags`; when every count is the same, all weigh 1.
+fn weigh(tags: &mut [CloudTag]) {
+    let min = tags.iter().map(|tag| tag.note_count).min().unwrap_or(0);
+    let max = tags.iter().map(|tag| tag.note_count).max().unwrap_or(0);
+    for tag in tags {
+        tag.weight = if max == min {
+            1.0
+        } else {
+            (tag.note_count - min) as f64 / (max - min) as f64
+        };
+    }
+}
+
+fn tag_name(name: &str) -> Result<String, AppError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_CHARS {
+        return Err(AppError::Validation(format!(
+            "Tag names need 1 to {} characters",
+            MAX_NAME_CHARS
+        )));
+    }
+    Ok(name.to_string())
+}
+
+fn tag_not_found(id: impl std::fmt::Display) -> AppError {
+    AppError::NotFound(format!("Tag with ID: {} not found", id))
+}
+
+fn duplicate_name(err: sqlx::Error) -> AppError {
+    if err.to_string().contains("Duplicate entry") {
+        AppError::Conflict("Tag with that name already exists".to_string())
+    } else {
+        err.into()
+    }
+}
+
+async fn find_tag(db: &MySqlPool, scope: &NoteScope, id: BinaryId) -> Result<TagModel, AppError> {
+    sqlx::query_as::<_, TagModel>(SELECT_TAG_BY_ID)
+        .bind(id)
+        .fetch_optional(db)
+        .await?
+        .filter(|tag| scope.permits(tag.user_id))
+        .ok_or_else(|| tag_not_found(id))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/tags",
+    tag = "tags",
+    responses(
+        (status = 200, description = "Tags with their note counts", body = TagListResponse),
+    ),
+)]
+pub async fn list_tags_handler(
+    scope: NoteScope,
+    State(db): State<DbPool>,
+) -> Result<impl IntoResponse, AppError> {
+    let tags = sqlx::query_as::<_, TagSummary>(
+        r#"SELECT tags.id, tags.user_id, tags.name, tags.created_at, COUNT(note_tags.note_id) AS note_count
+        FROM tags LEFT JOIN note_tags ON note_tags.tag_id = tags.id
+        WHERE ? IS NULL OR tags.user_id = ?
+        GROUP BY tags.id ORDER BY tags.name"#,
+    )
+    .bind(scope.owner())
+    .bind(scope.owner())
+    .fetch_all(&*db)
+    .await?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "results": tags.len(),
+        "tags": tags,
+    })))
+}
+
+/// The most used tags in scope, ordered by name, with their note counts and
+/// weights.
+#[utoipa::path(
+    get,
+    path = "/api/tags/cloud",
+    tag = "tags",
+    params(TagCloudOptions),
+    responses(
+        (status = 200, description = "The most used tags with their note counts and weights"),
+        (status = 400, description = "`from` is not before `to`", body = ErrorResponse),
+    ),
+)]
+pub async fn tag_cloud_handler(
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+    Query(options): Query<TagCloudOptions>,
+) -> Result<impl IntoResponse, AppError> {
+    if let (Some(from), Some(to)) = (options.from, options.to) {
+        if from >= to {
+            return Err(AppError::Validation(
+                "`from` must be before `to`".to_string(),
+            ));
+        }
+    }
+
+    // Read before querying, so a change racing the query invalidates it.
+    let seq = data.events.last_seq();
+    let key = CloudKey {
+        owner: scope.owner(),
+        options,
+    };
+    let tags = match data.cache.tag_cloud.get(&key, seq) {
+        Some(tags) => tags,
+        None => {
+            let options = &key.options;
+            let mut tags = sqlx::query_as::<_, CloudTag>(
+                r#"SELECT tags.id, tags.name, COUNT(*) AS note_count
+                FROM note_tags JOIN tags ON tags.id = note_tags.tag_id JOIN notes ON notes.id = note_tags.note_id
+                WHERE notes.deleted_at IS NULL AND (notes.expires_at IS NULL OR notes.expires_at > ?)
+                AND (? IS NULL OR tags.user_id = ?)
+                AND (? IS NULL OR notes.created_at >= ?)
+                AND (? IS NULL OR notes.created_at < ?)
+                AND (? IS NULL OR notes.category = ?)
+                GROUP BY tags.id, tags.name ORDER BY note_count DESC, tags.name LIMIT ?"#,
+            )
+            .bind(data.clock.now())
+            .bind(key.owner)
+            .bind(key.owner)
+            .bind(options.from)
+            .bind(options.from)
+            .bind(options.to)
+            .bind(options.to)
+            .bind(&options.category)
+            .bind(&options.category)
+            .bind(MAX_CLOUD_TAGS)
+            .fetch_all(&data.db)
+            .await?;
+            weigh(&mut tags);
+            tags.sort_by(|a, b| a.name.cmp(&b.name));
+            data.cache.tag_cloud.insert(key, seq, tags.clone());
+            tags
+        }
+    };
+
+    Ok(Json(json!({
+        "status": "success",
+        "results": tags.len(),
+        "tags": tags,
+    })))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/tags",
+    tag = "tags",
+    request_body = TagSchema,
+    responses(
+        (status = 201, description = "The created tag", body = TagResponse),
+        (status = 400, description = "Invalid name", body = ErrorResponse),
+        (status = 409, description = "A tag with that name already exists", body = ErrorResponse),
+    ),
+)]
+pub async fn create_tag_handler(
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+    Json(body): Json<TagSchema>,
+) -> Result<impl IntoResponse, AppError> {
+    let tag = TagModel {
+        id: BinaryId::from(data.ids.generate()),
+        user_id: scope.owner(),
+        name: tag_name(&body.name)?,
+        created_at: data.clock.now(),
+    };
+    sqlx::query("INSERT INTO tags (id, user_id, name, created_at) VALUES (?, ?, ?, ?)")
+        .bind(tag.id)
+        .bind(tag.user_id)
+        .bind(&tag.name)
+        .bind(tag.created_at)
+        .execute(&data.db)
+        .await
+        .map_err(duplicate_name)?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({
+            "status": "success",
+            "data": json!({
+                "tag": tag
+            })
+        })),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/tags/{id}",
+    tag = "tags",
+    params(("id" = Uuid, Path, description = "Tag ID")),
+    responses(
+        (status = 200, description = "The tag", body = TagResponse),
+        (status = 404, description = "Tag not found", body = ErrorResponse),
+    ),
+)]
+pub async fn get_tag_handler(
+    Path(id): Path<uuid::Uuid>,
+    scope: NoteScope,
+    State(db): State<DbPool>,
+) -> Result<impl IntoResponse, AppError> {
+    let tag = find_tag(&db, &scope, BinaryId::from(id)).await?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "data": json!({
+            "tag": tag
+        })
+    })))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/tags/{id}",
+    tag = "tags",
+    params(("id" = Uuid, Path, description = "Tag ID")),
+    request_body = TagSchema,
+    responses(
+        (status = 200, description = "The renamed tag", body = TagResponse),
+        (status = 400, description = "Invalid name", body = ErrorResponse),
+        (status = 404, description = "Tag not found", body = ErrorResponse),
+        (status = 409, description = "A tag with that name already exists", body = ErrorResponse),
+    ),
+)]
+pub async fn rename_tag_handler(
+    Path(id): Path<uuid::Uuid>,
+    scope: NoteScope,
+    State(db): State<DbPool>,
+    State(cache): State<Cache>,
+    Json(body): Json<TagSchema>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut tag = find_tag(&db, &scope, BinaryId::from(id)).await?;
+    tag.name = tag_name(&body.name)?;
+    sqlx::query("UPDATE tags SET name = ? WHERE id = ?")
+        .bind(&tag.name)
+        .bind(tag.id)
+        .execute(&*db)
+        .await
+        .map_err(duplicate_name)?;
+    cache.tag_cloud.invalidate();
+
+    Ok(Json(json!({
+        "status": "success",
+        "data": json!({
+            "tag": tag
+        })
+    })))
+}
+
+/// Deletes the tag and detaches it from every note.
+#[utoipa::path(
+    delete,
+    path = "/api/tags/{id}",
+    tag = "tags",
+    params(("id" = Uuid, Path, description = "Tag ID")),
+    responses(
+        (status = 204, description = "The tag was deleted"),
+        (status = 404, description = "Tag not found", body = ErrorResponse),
+    ),
+)]
+pub async fn delete_tag_handler(
+    Path(id): Path<uuid::Uuid>,
+    scope: NoteScope,
+    State(db): State<DbPool>,
+    State(cache): State<Cache>,
+) -> Result<impl IntoResponse, AppError> {
+    let tag = find_tag(&db, &scope, BinaryId::from(id)).await?;
+
+    let mut tx = db.begin().await?;
+    sqlx::query("DELETE FROM note_tags WHERE tag_id = ?")
+        .bind(tag.id)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query("DELETE FROM tags WHERE id = ?")
+        .bind(tag.id)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+    cache.tag_cloud.invalidate();
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The owner of note `id`, or 404 when it does not exist in `scope`.
+async fn note_owner(
+    data: &AppState,
+    scope: &NoteScope,
+    id: NoteId,
+) -> Result<Option<UserId>, AppError> {
+    data.notes
+        .find(id)
+        .await?
+        .map(|note| note.user_id)
+        .filter(|owner| scope.permits(*owner))
+        .ok_or_else(|| AppError::note_not_found(id))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/notes/{id}/tags",
+    tag = "tags",
+    params(("id" = Uuid, Path, description = "Note ID")),
+    responses(
+        (status = 200, description = "The tags of the note", body = TagListResponse),
+        (status = 404, description = "Note not found", body = ErrorResponse),
+    ),
+)]
+pub async fn note_tags_handler(
+    Path(id): Path<uuid::Uuid>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let note_id = NoteId::from(id);
+    note_owner(&data, &scope, note_id).await?;
+    let tags = sqlx::query_as::<_, TagModel>(SELECT_NOTE_TAGS)
+        .bind(note_id)
+        .fetch_all(&data.db)
+        .await?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "results": tags.len(),
+        "tags": tags,
+    })))
+}
+
+/// Replaces the tags of a note with the named ones.
+#[utoipa::path(
+    put,
+    path = "/api/notes/{id}/tags",
+    tag = "tags",
+    params(("id" = Uuid, Path, description = "Note ID")),
+    request_body = NoteTagsSchema,
+    responses(
+        (status = 200, description = "The new tags of the note", body = TagListResponse),
+        (status = 400, description = "Invalid tag names", body = ErrorResponse),
+        (status = 404, description = "Note not found", body = ErrorResponse),
+    ),
+)]
+pub async fn set_note_tags_handler(
+    Path(id): Path<uuid::Uuid>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+    Json(body): Json<NoteTagsSchema>,
+) -> Result<impl IntoResponse, AppError> {
+    let note_id = NoteId::from(id);
+    let owner = note_owner(&data, &scope, note_id).await?;
+
+    // Names compare case-insensitively, as the column collation does.
+    let mut seen = HashSet::new();
+    let mut names = Vec::new();
+    for name in &body.tags {
+        let name = tag_name(name)?;
+        if seen.insert(name.to_lowercase()) {
+            names.push(name);
+        }
+    }
+    if names.len() > MAX_TAGS_PER_NOTE {
+        return Err(AppError::Validation(format!(
+            "Notes can carry at most {} tags",
+            MAX_TAGS_PER_NOTE
+        )));
+    }
+
+    let now = data.clock.now();
+    let mut tx = data.db.begin().await?;
+    for name in &names {
+        sqlx::query(
+            r#"INSERT INTO tags (id, user_id, name, created_at) VALUES (?, ?, ?, ?) ON DUPLICATE KEY UPDATE id = id"#,
+        )
+        .bind(BinaryId::from(data.ids.generate()))
+        .bind(owner)
+        .bind(name)
+        .bind(now)
+        .execute(&mut tx)
+        .await?;
+    }
+    let tags = if names.is_empty() {
+        Vec::new()
+    } else {
+        let mut query = QueryBuilder::<MySql>::new(
+            "SELECT id, user_id, name, created_at FROM tags WHERE user_id <=> ",
+        );
+        query.push_bind(owner).push(" AND name IN (");
+        let mut separated = query.separated(", ");
+        for name in &names {
+            separated.push_bind(name.clone());
+        }
+        query.push(") ORDER BY name");
+        query
+            .build_query_as::<TagModel>()
+            .fetch_all(&mut tx)
+            .await?
+    };
+
+    sqlx::query("DELETE FROM note_tags WHERE note_id = ?")
+        .bind(note_id)
+        .execute(&mut tx)
+        .await?;
+    for tag in &tags {
+        sqlx::query("INSERT INTO note_tags (note_id, tag_id) VALUES (?, ?)")
+            .bind(note_id)
+            .bind(tag.id)
+            .execute(&mut tx)
+            .await?;
+    }
+    tx.commit().await?;
+    data.cache.tag_cloud.invalidate();
+
+    Ok(Json(json!({
+        "status": "success",
+        "results": tags.len(),
+        "tags": tags,
+    })))
+}
+
+/// Detaches the tags of deleted notes.
+pub fn spawn_note_cleanup(data: Arc<AppState>, mut events: broadcast::Receiver<NoteEvent>) {
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) if event.kind == NoteEventKind::Deleted => {
+                    let result = sqlx::query("DELETE FROM note_tags WHERE note_id = ?")
+                        .bind(event.note_id)
+                        .execute(&data.db)
+                        .await;
+                    if let Err(err) = result {
+                        tracing::error!(
+                            error = ?err,
+                            note_id = %event.note_id,
+                            "Failed to detach note tags",
+                        );
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!(skipped, "Tag cleanup skipped change events");
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Method;
+    use chrono::Duration;
+
+    use crate::{clock::Clock, testing::TestApp};
+
+    use super::*;
+
+    fn cloud_tag(name: &str, note_count: i64) -> CloudTag {
+        CloudTag {
+            id: BinaryId::from(uuid::Uuid::new_v4()),
+            name: name.to_string(),
+            note_count,
+            weight: 0.0,
+        }
+    }
+
+    #[test]
+    fn weights_span_the_least_to_the_most_used_tag() {
+        let mut tags = vec![cloud_tag("a", 3), cloud_tag("b", 11), cloud_tag("c", 5)];
+        weigh(&mut tags);
+        let weights: Vec<f64> = tags.iter().map(|tag| tag.weight).collect();
+        assert_eq!(weights, vec![0.0, 1.0, 0.25]);
+
+        let mut tags = vec![cloud_tag("a", 2), cloud_tag("b", 2)];
+        weigh(&mut tags);
+        assert!(tags.iter().all(|tag| tag.weight == 1.0));
+    }
+
+    #[test]
+    fn clouds_are_cached_per_filter_until_notes_or_tags_change() {
+        let cache = TagCloudCache::default();
+        let key = CloudKey {
+            owner: None,
+            options: TagCloudOptions::default(),
+        };
+        let work = CloudKey {
+            owner: None,
+            options: TagCloudOptions {
+                category: Some("work".to_string()),
+                ..Default::default()
+            },
+        };
+        cache.insert(key.clone(), 7, vec![cloud_tag("a", 1)]);
+
+        assert_eq!(cache.get(&key, 7).map(|tags| tags.len()), Some(1));
+        assert!(cache.get(&work, 7).is_none());
+        assert!(cache.get(&key, 8).is_none());
+
+        cache.invalidate();
+        assert!(cache.get(&key, 7).is_none());
+    }
+
+    #[tokio::test]
+    async fn tags_of_expired_notes_are_gone() {
+        let app = TestApp::new();
+        let expires_at = app.clock.now() + Duration::hours(1);
+        let (status, body) = app
+            .post(
+                "/api/notes",
+                json!({ "title": "Brief", "content": "Soon gone", "expires_at": expires_at }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        let uri = format!(
+            "/api/notes/{}/tags",
+            body["data"]["note"]["id"].as_str().unwrap()
+        );
+
+        app.clock.advance(Duration::hours(2));
+        let (status, _) = app.get(&uri).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _, _) = app
+            .send(Method::PUT, &uri, None, Some(json!({ "tags": ["work"] })))
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
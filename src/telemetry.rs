@@ -0,0 +1,80 @@
+//! Prometheus metrics, scraped from `GET /metrics`.
+//!
+//! [`track_requests`] counts every request and times it, labelled by method,
+//! route pattern (`/api/notes/:id`, never the concrete path) and status.
+//! Connection pool gauges are sampled when the endpoint is scraped.
+
+use std::{sync::Arc, time::Instant};
+
+use axum::{
+    extract::{MatchedPath, State},
+    http::{header, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use metrics_exporter_prometheus::{BuildError, Matcher, PrometheusBuilder, PrometheusHandle};
+
+use crate::AppState;
+
+pub const REQUESTS_TOTAL: &str = "http_requests_total";
+pub const REQUEST_DURATION: &str = "http_request_duration_seconds";
+pub const POOL_CONNECTIONS: &str = "db_pool_connections";
+
+/// Latency buckets in seconds, from a cached read to a slow export.
+const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Installs the process-wide Prometheus recorder. Call once, at startup.
+pub fn install() -> Result<PrometheusHandle, BuildError> {
+    PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full(REQUEST_DURATION.to_string()),
+            DURATION_BUCKETS,
+        )?
+        .install_recorder()
+}
+
+/// Records the count and latency of each request.
+pub async fn track_requests<B>(req: Request<B>, next: Next<B>) -> Response {
+    // Unmatched paths share one label so scanners cannot blow up cardinality.
+    let path = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", |path| path.as_str())
+        .to_string();
+    let method = req.method().to_string();
+    let started = Instant::now();
+
+    let response = next.run(req).await;
+
+    let labels = [
+        ("method", method),
+        ("path", path),
+        ("status", response.status().as_u16().to_string()),
+    ];
+    metrics::increment_counter!(REQUESTS_TOTAL, &labels);
+    metrics::histogram!(REQUEST_DURATION, started.elapsed().as_secs_f64(), &labels);
+    response
+}
+
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "health",
+    responses(
+        (status = 200, description = "Metrics in the Prometheus text format", body = String, content_type = "text/plain"),
+    ),
+    security(()),
+)]
+pub async fn metrics_handler(State(data): State<Arc<AppState>>) -> impl IntoResponse {
+    let size = data.db.size() as f64;
+    let idle = data.db.num_idle() as f64;
+    metrics::gauge!(POOL_CONNECTIONS, idle, "state" => "idle");
+    metrics::gauge!(POOL_CONNECTIONS, size - idle, "state" => "active");
+
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        data.metrics.render(),
+    )
+}
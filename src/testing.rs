@@ -0,0 +1,308 @@
+//! The app over an [`InMemoryNoteRepository`], for handler tests.
+//!
+//! The MySQL pool is created lazily against an address nothing listens on,
+//! so handlers run without a database as long as they stay on the
+//! [`NoteRepository`](crate::repository::NoteRepository); a handler that
+//! queries the pool directly fails its request instead.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, Method, Request, StatusCode},
+    Router,
+};
+use chrono::{TimeZone, Utc};
+use metrics_exporter_prometheus::PrometheusBuilder;
+use serde_json::{json, Value};
+use sqlx::mysql::{MySqlPool, MySqlPoolOptions};
+use tower::ServiceExt;
+
+use crate::{
+    alerts::{Alert, AlertSink, Alerts, LogSink},
+    anomaly::{AnomalyDetector, AnomalyOptions},
+    attachment::{self, LocalDiskStorage},
+    auth::{JwtAuth, UserModel},
+    canary::Canaries,
+    chaos::Chaos,
+    clock::{Clock, FixedClock},
+    collab::CollabHub,
+    dead_letter::{DeadLetterStore, InMemoryDeadLetterStore},
+    export,
+    hooks::Hooks,
+    http_client::{HttpClient, HttpClientOptions},
+    id::SequentialIdGenerator,
+    job::Jobs,
+    leader::Leadership,
+    lock::DistributedLock,
+    lockout::{LockoutOptions, LoginGuard},
+    model::UserId,
+    moderation::Moderation,
+    negative_cache::NegativeCache,
+    note_cache::{self, InMemoryCacheStore, NoteCache, DEFAULT_TTL},
+    plugin::Plugins,
+    repository::InMemoryNoteRepository,
+    route::create_router,
+    scripting::ScriptHooks,
+    secrets::{CachedSecrets, EnvSecrets},
+    single_flight::ReadCoalescing,
+    state::{Cache, EventBus, Settings},
+    warmup::WarmupReport,
+    write_buffer::{WriteBuffer, WriteBufferOptions},
+    AppState,
+};
+
+const JWT_SECRET: &[u8] = b"test secret";
+
+/// Keeps every alert sent, as JSON.
+#[derive(Default)]
+pub struct RecordedAlerts(Mutex<Vec<Value>>);
+
+#[async_trait]
+impl AlertSink for RecordedAlerts {
+    fn name(&self) -> &'static str {
+        "recorded"
+    }
+
+    async fn deliver(&self, alert: &Alert) -> Result<(), String> {
+        self.redeliver(&json!(alert)).await
+    }
+
+    async fn redeliver(&self, payload: &Value) -> Result<(), String> {
+        self.0.lock().unwrap().push(payload.clone());
+        Ok(())
+    }
+}
+
+pub struct TestApp {
+    pub state: Arc<AppState>,
+    pub notes: Arc<InMemoryNoteRepository>,
+    pub clock: Arc<FixedClock>,
+    alerts: Arc<RecordedAlerts>,
+    router: Router,
+}
+
+/// What to build a [`TestApp`] with.
+#[derive(Default)]
+struct Options {
+    accounts: bool,
+    hooks: Hooks,
+    chaos: bool,
+    note_cache: bool,
+}
+
+/// A pool that never connects: queries through it fail fast with a
+/// database error.
+pub fn unreachable_pool() -> MySqlPool {
+    MySqlPoolOptions::new()
+        .acquire_timeout(Duration::from_millis(100))
+        .connect_lazy("mysql://test@127.0.0.1:1/test")
+        .unwrap()
+}
+
+impl TestApp {
+    /// Without user accounts, so every request sees every note.
+    pub fn new() -> Self {
+        Self::build(Options::default())
+    }
+
+    /// With user accounts, as when `JWT_SECRET` is set.
+    pub fn with_accounts() -> Self {
+        Self::build(Options {
+            accounts: true,
+            ..Options::default()
+        })
+    }
+
+    /// Without user accounts, running `hooks` around note writes.
+    pub fn with_hooks(hooks: Hooks) -> Self {
+        Self::build(Options {
+            hooks,
+            ..Options::default()
+        })
+    }
+
+    /// With the fault injection layer installed, as when
+    /// `CHAOS_ENABLED=true`; faults stay off until configured.
+    pub fn with_chaos() -> Self {
+        Self::build(Options {
+            chaos: true,
+            ..Options::default()
+        })
+    }
+
+    /// With a note cache in memory, as when `REDIS_URL` is set, evicting
+    /// notes as their change events come in.
+    pub fn with_note_cache() -> Self {
+        Self::build(Options {
+            note_cache: true,
+            ..Options::default()
+        })
+    }
+
+    fn build(options: Options) -> Self {
+        let Options {
+            accounts,
+            hooks,
+            chaos,
+            note_cache,
+        } = options;
+        let clock = Arc::new(FixedClock::new(
+            Utc.with_ymd_and_hms(2023, 5, 3, 12, 0, 0).unwrap(),
+        ));
+        let notes = Arc::new(InMemoryNoteRepository::new(clock.clone()));
+        let pool = unreachable_pool();
+        let http = Arc::new(HttpClient::new(HttpClientOptions::default()));
+        let recorded = Arc::new(RecordedAlerts::default());
+        let dead_letters: Arc<dyn DeadLetterStore> =
+            Arc::new(InMemoryDeadLetterStore::new(clock.clone()));
+        let alerts = Arc::new(
+            Alerts::new(vec![Arc::new(LogSink), recorded.clone()])
+                .with_dead_letters(dead_letters.clone()),
+        );
+        let locks = Arc::new(DistributedLock::new(pool.clone(), "test".to_string()));
+
+        let state = Arc::new(AppState {
+            db: pool.clone(),
+            notes: notes.clone(),
+            write_buffer: WriteBuffer::spawn(pool, WriteBufferOptions::default()),
+            warmup: WarmupReport::default(),
+            auth: accounts.then(|| JwtAuth::new(JWT_SECRET, Duration::from_secs(3600))),
+            chaos: chaos.then(Chaos::default),
+            clock: clock.clone(),
+            ids: Arc::new(SequentialIdGenerator::default()),
+            events: EventBus::default(),
+            collab: CollabHub::default(),
+            secrets: Arc::new(CachedSecrets::new(
+                Arc::new(EnvSecrets),
+                Duration::from_secs(60),
+            )),
+            http,
+            alerts: alerts.clone(),
+            dead_letters,
+            anomalies: Arc::new(AnomalyDetector::new(
+                AnomalyOptions::default(),
+                alerts,
+                clock.clone(),
+            )),
+            attachments: Arc::new(LocalDiskStorage::new(
+                std::env::temp_dir().join("rust-axum-mysql-test-attachments"),
+            )),
+            rate_limits: None,
+            logins: LoginGuard::new(LockoutOptions::default(), None),
+            canaries: Arc::new(Canaries::default()),
+            moderation: Arc::new(Moderation::new(None)),
+            leadership: Arc::new(Leadership::new(
+                locks.clone(),
+                clock.clone(),
+                Duration::from_secs(30),
+            )),
+            locks,
+            coalescing: ReadCoalescing::default(),
+            cache: Cache {
+                notes: note_cache.then(|| {
+                    NoteCache::with_store(Arc::new(InMemoryCacheStore::default()), DEFAULT_TTL)
+                }),
+                missing_notes: Arc::new(NegativeCache::new(Duration::from_secs(30), 1_000)),
+                note_index: Arc::default(),
+                tag_cloud: Arc::default(),
+            },
+            hooks,
+            scripts: Arc::new(ScriptHooks::default()),
+            plugins: Plugins::default(),
+            jobs: Jobs::default(),
+            metrics: PrometheusBuilder::new().build_recorder().handle(),
+            settings: Settings {
+                admin_token: None,
+                trust_forwarded_for: false,
+                max_content_bytes: 1024 * 1024,
+                max_attachment_bytes: attachment::DEFAULT_MAX_BYTES,
+                export_embed_max_bytes: export::DEFAULT_EMBED_MAX_BYTES,
+                graphql_playground: false,
+                summary_refresh_interval: Duration::from_secs(60),
+                db_max_connections: 1,
+                request_timeout: Duration::from_secs(10),
+            },
+        });
+
+        if let Some(cache) = &state.cache.notes {
+            note_cache::spawn_invalidator(cache.clone(), state.events.subscribe());
+        }
+
+        Self {
+            router: create_router(state.clone()),
+            state,
+            notes,
+            clock,
+            alerts: recorded,
+        }
+    }
+
+    /// The alerts sent so far. Alerts are delivered in the background, so
+    /// this lets those pending run first.
+    pub async fn alerts(&self) -> Vec<Value> {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        self.alerts.0.lock().unwrap().clone()
+    }
+
+    /// A token signing in as a new user. Issued now rather than at the
+    /// frozen time, as tokens expire by the system clock.
+    pub fn token(&self, user_id: UserId) -> String {
+        let auth = self.state.auth.as_ref().expect("accounts are enabled");
+        let user = UserModel {
+            id: user_id,
+            email: format!("{}@example.com", user_id),
+            password_hash: String::new(),
+            created_at: self.clock.now(),
+        };
+        auth.issue(&user, Utc::now())
+    }
+
+    /// Sends a request with an optional bearer `token` and JSON `body`, and
+    /// returns the status, headers and JSON body of the response (`Null`
+    /// when it is not JSON).
+    pub async fn send(
+        &self,
+        method: Method,
+        uri: &str,
+        token: Option<&str>,
+        body: Option<Value>,
+    ) -> (StatusCode, HeaderMap, Value) {
+        let mut req = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let req = match body {
+            Some(body) => req
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string())),
+            None => req.body(Body::empty()),
+        };
+        self.call(req.unwrap()).await
+    }
+
+    /// Sends `req` as it is.
+    pub async fn call(&self, req: Request<Body>) -> (StatusCode, HeaderMap, Value) {
+        let response = self.router.clone().oneshot(req).await.unwrap();
+        let (parts, body) = response.into_parts();
+        let bytes = hyper::body::to_bytes(body).await.unwrap();
+        let json = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+        (parts.status, parts.headers, json)
+    }
+
+    pub async fn get(&self, uri: &str) -> (StatusCode, Value) {
+        let (status, _, body) = self.send(Method::GET, uri, None, None).await;
+        (status, body)
+    }
+
+    pub async fn post(&self, uri: &str, body: Value) -> (StatusCode, Value) {
+        let (status, _, body) = self.send(Method::POST, uri, None, Some(body)).await;
+        (status, body)
+    }
+}
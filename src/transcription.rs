@@ -0,0 +1,207 @@
+//! Transcripts of voice notes.
+//!
+//! An audio attachment uploaded with `?voice_note=true` queues a `transcribe`
+//! job (see [`crate::job`]) when `TRANSCRIPTION_API_URL` is set. The job
+//! reads the recording back from attachment storage, runs it through the
+//! [`TranscriptionProvider`] and stores the text in the `transcript` column
+//! of the attachment, with its progress in `transcript_status`. The provider
+//! in use posts the recording to `TRANSCRIPTION_API_URL`; others implement
+//! the same trait.
+
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use axum::{
+    body::Bytes,
+    http::{header, HeaderValue},
+};
+use serde::Deserialize;
+
+use crate::{
+    attachment::AttachmentModel,
+    http_client::HttpClient,
+    job::{JobHandler, JobStatus},
+    model::BinaryId,
+    ocr::read_attachment,
+    AppState,
+};
+
+pub const JOB_KIND: &str = "transcribe";
+/// Longer transcripts are cut off, about three hours of speech.
+pub const MAX_TRANSCRIPT_CHARS: usize = 256 * 1024;
+const EXTERNAL_TIMEOUT: Duration = Duration::from_secs(4 * 60);
+
+/// Recording formats the usual speech-to-text services take.
+const AUDIO_TYPES: &[&str] = &[
+    "audio/mpeg",
+    "audio/mp4",
+    "audio/aac",
+    "audio/ogg",
+    "audio/opus",
+    "audio/webm",
+    "audio/wav",
+    "audio/x-wav",
+    "audio/flac",
+    "audio/x-m4a",
+];
+
+/// Turns speech into text.
+#[async_trait]
+pub trait TranscriptionProvider: Send + Sync {
+    async fn transcribe(&self, audio: Bytes, content_type: &str) -> Result<String, String>;
+}
+
+/// Whether attachments of `content_type` can be voice notes.
+pub fn is_audio(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    AUDIO_TYPES
+        .iter()
+        .any(|audio_type| essence.eq_ignore_ascii_case(audio_type))
+}
+
+/// A speech-to-text service that takes the recording as the request body,
+/// with its content type, and answers `{"text": ...}`.
+pub struct ExternalTranscription {
+    http: Arc<HttpClient>,
+    url: String,
+}
+
+impl ExternalTranscription {
+    pub fn new(http: Arc<HttpClient>, url: String) -> Self {
+        Self { http, url }
+    }
+}
+
+#[derive(Deserialize)]
+struct ExternalTranscriptionResponse {
+    text: String,
+}
+
+#[async_trait]
+impl TranscriptionProvider for ExternalTranscription {
+    async fn transcribe(&self, audio: Bytes, content_type: &str) -> Result<String, String> {
+        let content_type = HeaderValue::from_str(content_type)
+            .unwrap_or(HeaderValue::from_static("application/octet-stream"));
+        let request = self
+            .http
+            .post(&self.url)
+            .timeout(EXTERNAL_TIMEOUT)
+            .header(header::CONTENT_TYPE, content_type)
+            .body(audio);
+        let response = self
+            .http
+            .send("transcription", request)
+            .await
+            .map_err(|err| err.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("Transcription API answered {}", response.status()));
+        }
+        let response: ExternalTranscriptionResponse =
+            response.json().await.map_err(|err| err.to_string())?;
+        Ok(response.text)
+    }
+}
+
+/// Trimmed and cut to [`MAX_TRANSCRIPT_CHARS`] at a word; `None` when
+/// nothing was said.
+fn clean_transcript(text: &str) -> Option<String> {
+    let text = text.trim();
+    if text.is_empty() {
+        return None;
+    }
+    match text.char_indices().nth(MAX_TRANSCRIPT_CHARS) {
+        None => Some(text.to_string()),
+        Some((end, _)) => {
+            let cut = &text[..end];
+            let cut = cut.rfind(char::is_whitespace).map_or(cut, |at| &cut[..at]);
+            Some(cut.trim_end().to_string())
+        }
+    }
+}
+
+/// Runs the `transcribe` jobs queued for voice notes.
+pub struct TranscriptionJob {
+    provider: Arc<dyn TranscriptionProvider>,
+}
+
+impl TranscriptionJob {
+    pub fn new(provider: Arc<dyn TranscriptionProvider>) -> Self {
+        Self { provider }
+    }
+}
+
+#[async_trait]
+impl JobHandler for TranscriptionJob {
+    fn kind(&self) -> &'static str {
+        JOB_KIND
+    }
+
+    async fn run(&self, data: &AppState, subject: BinaryId) -> Result<(), String> {
+        let attachment =
+            sqlx::query_as::<_, AttachmentModel>("SELECT * FROM attachments WHERE id = ?")
+                .bind(subject)
+                .fetch_optional(&data.db)
+                .await
+                .map_err(|err| err.to_string())?;
+        // Gone with its note.
+        let Some(attachment) = attachment else {
+            return Ok(());
+        };
+        let audio = read_attachment(data, &attachment).await?;
+        let text = self
+            .provider
+            .transcribe(audio, &attachment.content_type)
+            .await?;
+        sqlx::query("UPDATE attachments SET transcript = ?, transcript_status = ? WHERE id = ?")
+            .bind(clean_transcript(&text))
+            .bind(JobStatus::Done)
+            .bind(subject)
+            .execute(&data.db)
+            .await
+            .map_err(|err| err.to_string())?;
+        Ok(())
+    }
+
+    async fn give_up(&self, data: &AppState, subject: BinaryId, _error: &str) {
+        let result = sqlx::query("UPDATE attachments SET transcript_status = ? WHERE id = ?")
+            .bind(JobStatus::Failed)
+            .bind(subject)
+            .execute(&data.db)
+            .await;
+        if let Err(err) = result {
+            tracing::error!(
+                attachment_id = %subject,
+                error = ?err,
+                "Failed to mark transcription failed",
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_recordings_are_voice_notes() {
+        assert!(is_audio("audio/mpeg"));
+        assert!(is_audio("Audio/Ogg; codecs=opus"));
+        assert!(!is_audio("audio/midi"));
+        assert!(!is_audio("video/mp4"));
+        assert!(!is_audio(""));
+    }
+
+    #[test]
+    fn transcripts_are_trimmed_and_capped_at_a_word() {
+        assert_eq!(
+            clean_transcript("  Buy milk.\nCall Sam.  "),
+            Some("Buy milk.\nCall Sam.".to_string())
+        );
+        assert_eq!(clean_transcript(" \n "), None);
+
+        let long = "word ".repeat(MAX_TRANSCRIPT_CHARS);
+        let cleaned = clean_transcript(&long).unwrap();
+        assert!(cleaned.chars().count() <= MAX_TRANSCRIPT_CHARS);
+        assert!(cleaned.ends_with("word"));
+    }
+}
@@ -0,0 +1,221 @@
+//! The trash: deleted notes, kept until they are restored or purged.
+//!
+//! `DELETE /api/notes/:id` only sets `deleted_at`, which hides the note from
+//! every other endpoint and frees its title. Trashed notes are listed at
+//! `GET /api/notes/trash`, brought back with `POST /api/notes/:id/restore`
+//! and deleted for good with `DELETE /api/notes/:id/purge`. Tags, sources and
+//! link previews are only cleaned up once a note is purged. Notes that expire
+//! while in the trash can no longer be restored.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde_json::json;
+
+use crate::{
+    auth::NoteScope,
+    error::AppError,
+    events::NoteEventKind,
+    handler::{filter_db_record, page_offset, MAX_PAGE_SIZE},
+    model::{NoteId, NoteModel},
+    offload,
+    repository::WriteError,
+    schema::TrashOptions,
+    AppState,
+};
+
+pub const SELECT_TRASHED_NOTES: &str = r#"SELECT * FROM notes WHERE deleted_at IS NOT NULL AND (? IS NULL OR user_id = ?) ORDER BY deleted_at DESC, id LIMIT ? OFFSET ?"#;
+pub const PURGE_NOTE: &str = "DELETE FROM notes WHERE id = ? AND deleted_at IS NOT NULL";
+
+fn not_in_trash(id: impl std::fmt::Display) -> AppError {
+    AppError::NotFound(format!("Note with ID: {} not found in the trash", id))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/notes/trash",
+    tag = "notes",
+    params(TrashOptions),
+    responses(
+        (status = 200, description = "Trashed notes, most recently deleted first", body = NoteListResponse),
+    ),
+)]
+pub async fn list_trash_handler(
+    opts: Option<Query<TrashOptions>>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let Query(opts) = opts.unwrap_or_default();
+    let limit = opts.limit.unwrap_or(10).min(MAX_PAGE_SIZE);
+    let offset = page_offset(opts.page, limit)?;
+
+    let mut notes = sqlx::query_as::<_, NoteModel>(SELECT_TRASHED_NOTES)
+        .bind(scope.owner())
+        .bind(scope.owner())
+        .bind(limit as u64)
+        .bind(offset as u64)
+        .fetch_all(&data.db)
+        .await?;
+    offload::reassemble(data.attachments.as_ref(), &mut notes).await?;
+
+    let note_responses = notes.iter().map(filter_db_record).collect::<Vec<_>>();
+
+    Ok(Json(json!({
+        "status": "success",
+        "results": note_responses.len(),
+        "notes": note_responses,
+    })))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/notes/{id}/restore",
+    tag = "notes",
+    params(("id" = Uuid, Path, description = "Note ID")),
+    responses(
+        (status = 200, description = "The restored note", body = NoteResponse),
+        (status = 404, description = "Note not found in the trash", body = ErrorResponse),
+        (status = 409, description = "Another note now has its title", body = ErrorResponse),
+    ),
+)]
+pub async fn restore_note_handler(
+    Path(id): Path<uuid::Uuid>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let id = NoteId::from(id);
+    scope.check(&data, id).await?;
+
+    let note = match data.notes.restore(id).await {
+        Ok(Some(note)) => note,
+        Ok(None) => return Err(not_in_trash(id)),
+        Err(WriteError::DuplicateTitle) => {
+            return Err(AppError::Conflict(
+                "Note with that title already exists, rename it before restoring".to_string(),
+            ))
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    data.cache.missing_notes.forget(id);
+    data.events
+        .publish(NoteEventKind::Restored, id, note.user_id, data.clock.now());
+
+    Ok(Json(json!({
+        "status": "success",
+        "data": json!({
+            "note": filter_db_record(&note)
+        })
+    })))
+}
+
+/// Only notes already in the trash can be purged.
+#[utoipa::path(
+    delete,
+    path = "/api/notes/{id}/purge",
+    tag = "notes",
+    params(("id" = Uuid, Path, description = "Note ID")),
+    responses(
+        (status = 204, description = "The note was deleted for good"),
+        (status = 404, description = "Note not found in the trash", body = ErrorResponse),
+    ),
+)]
+pub async fn purge_note_handler(
+    Path(id): Path<uuid::Uuid>,
+    scope: NoteScope,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let id = NoteId::from(id);
+    let owner = scope.owner_of(&data, id).await?;
+
+    let result = sqlx::query(PURGE_NOTE).bind(id).execute(&data.db).await?;
+    if result.rows_affected() == 0 {
+        return Err(not_in_trash(id));
+    }
+
+    data.events
+        .publish(NoteEventKind::Deleted, id, owner, data.clock.now());
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Method;
+    use chrono::Duration;
+    use serde_json::Value;
+
+    use crate::{clock::Clock, testing::TestApp};
+
+    use super::*;
+
+    async fn create(app: &TestApp, body: Value) -> String {
+        let (status, body) = app.post("/api/notes", body).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        body["data"]["note"]["id"].as_str().unwrap().to_string()
+    }
+
+    async fn trash(app: &TestApp, id: &str) {
+        let uri = format!("/api/notes/{}", id);
+        let (status, _, _) = app.send(Method::DELETE, &uri, None, None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn restores_trashed_notes() {
+        let app = TestApp::new();
+        let id = create(&app, json!({ "title": "Kept", "content": "Back again" })).await;
+        trash(&app, &id).await;
+
+        let (status, body) = app
+            .post(&format!("/api/notes/{}/restore", id), json!({}))
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["data"]["note"]["title"], json!("Kept"));
+        assert_eq!(
+            app.get(&format!("/api/notes/{}", id)).await.0,
+            StatusCode::OK
+        );
+
+        let (status, _) = app
+            .post(&format!("/api/notes/{}/restore", id), json!({}))
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn expired_notes_are_not_restored() {
+        let app = TestApp::new();
+        let expires_at = app.clock.now() + Duration::hours(1);
+        let id = create(
+            &app,
+            json!({ "title": "Brief", "content": "Soon gone", "expires_at": expires_at }),
+        )
+        .await;
+        trash(&app, &id).await;
+        app.clock.advance(Duration::hours(2));
+
+        let (status, _) = app
+            .post(&format!("/api/notes/{}/restore", id), json!({}))
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn restoring_a_taken_title_conflicts() {
+        let app = TestApp::new();
+        let id = create(&app, json!({ "title": "Taken", "content": "Old" })).await;
+        trash(&app, &id).await;
+        create(&app, json!({ "title": "Taken", "content": "New" })).await;
+
+        let (status, _) = app
+            .post(&format!("/api/notes/{}/restore", id), json!({}))
+            .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+    }
+}
@@ -0,0 +1,145 @@
+//! Field checks on note payloads, run before anything reaches the database.
+//!
+//! Every failing field is reported at once with a 422, instead of the first
+//! column the database refuses surfacing as a 500.
+
+use std::collections::BTreeMap;
+
+use crate::{
+    error::AppError,
+    model::{CategoryName, Title},
+    schema::{CreateNoteSchema, UpdateNoteSchema},
+};
+
+/// The length of the `title` column.
+pub const MAX_TITLE_CHARS: usize = 255;
+/// The length of the `category` column.
+pub const MAX_CATEGORY_CHARS: usize = 100;
+/// Default limit on content, in bytes; see `AppState::max_content_bytes`.
+pub const DEFAULT_MAX_CONTENT_BYTES: usize = 64 * 1024;
+/// What the MEDIUMTEXT `content` columns hold. Content is only compressed
+/// when that makes it smaller, so this bounds compressed content as well.
+pub const MAX_STORED_CONTENT_BYTES: usize = 16 * 1024 * 1024 - 1;
+
+/// Messages keyed by the field they are about.
+#[derive(Debug, Default)]
+pub struct FieldErrors(BTreeMap<&'static str, String>);
+
+impl FieldErrors {
+    /// A single problem, for fields checked against the database.
+    pub fn single(field: &'static str, message: String) -> Self {
+        Self(BTreeMap::from([(field, message)]))
+    }
+
+    fn check(&mut self, field: &'static str, problem: Option<String>) {
+        if let Some(message) = problem {
+            self.0.entry(field).or_insert(message);
+        }
+    }
+
+    pub fn into_map(self) -> BTreeMap<&'static str, String> {
+        self.0
+    }
+
+    fn into_result(self) -> Result<(), AppError> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(AppError::InvalidFields(self))
+        }
+    }
+}
+
+fn title_problem(title: &str) -> Option<String> {
+    Title::parse(title).err()
+}
+
+/// `max_bytes` is capped at what the column holds, so a limit set too high
+/// still answers 422 rather than failing the insert.
+fn content_problem(content: &str, max_bytes: usize) -> Option<String> {
+    let max_bytes = max_bytes.min(MAX_STORED_CONTENT_BYTES);
+    (content.len() > max_bytes).then(|| format!("must be at most {} bytes", max_bytes))
+}
+
+/// `category` may be left out, but not sent blank.
+fn category_problem(category: &str) -> Option<String> {
+    CategoryName::parse(category).err()
+}
+
+pub fn validate_new_note(
+    body: &CreateNoteSchema,
+    max_content_bytes: usize,
+) -> Result<(), AppError> {
+    let mut errors = FieldErrors::default();
+    errors.check("title", title_problem(&body.title));
+    errors.check("content", content_problem(&body.content, max_content_bytes));
+    errors.check(
+        "category",
+        body.category.as_deref().and_then(category_problem),
+    );
+    errors.into_result()
+}
+
+/// Only the fields present in the update are checked.
+pub fn validate_note_update(
+    body: &UpdateNoteSchema,
+    max_content_bytes: usize,
+) -> Result<(), AppError> {
+    let mut errors = FieldErrors::default();
+    errors.check("title", body.title.as_deref().and_then(title_problem));
+    errors.check(
+        "content",
+        body.content
+            .as_deref()
+            .and_then(|content| content_problem(content, max_content_bytes)),
+    );
+    errors.check(
+        "category",
+        body.category.as_deref().and_then(category_problem),
+    );
+    errors.into_result()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::StoredContent;
+
+    fn note(content: String) -> CreateNoteSchema {
+        serde_json::from_value(serde_json::json!({ "title": "Sized", "content": content }))
+            .unwrap()
+    }
+
+    /// Notes that do not compress are stored as they are, so the column has
+    /// to hold them.
+    #[test]
+    fn content_up_to_the_limit_is_accepted_and_fits_the_column() {
+        for len in [65535, 65536] {
+            let body = note("a".repeat(len));
+            assert!(validate_new_note(&body, DEFAULT_MAX_CONTENT_BYTES).is_ok());
+            let stored = match StoredContent::encode(&body.content) {
+                StoredContent::Plain(content) => content.len(),
+                StoredContent::Zstd {
+                    compressed,
+                    excerpt,
+                    ..
+                } => compressed.len().max(excerpt.len()),
+            };
+            assert!(stored <= MAX_STORED_CONTENT_BYTES);
+        }
+        let too_long = note("a".repeat(65537));
+        assert!(matches!(
+            validate_new_note(&too_long, DEFAULT_MAX_CONTENT_BYTES),
+            Err(AppError::InvalidFields(_))
+        ));
+    }
+
+    #[test]
+    fn the_limit_never_exceeds_the_column() {
+        let body = note("a".repeat(MAX_STORED_CONTENT_BYTES + 1));
+        assert!(matches!(
+            validate_new_note(&body, usize::MAX),
+            Err(AppError::InvalidFields(_))
+        ));
+    }
+}
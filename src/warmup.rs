@@ -0,0 +1,118 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use serde::Serialize;
+use sqlx::{
+    mysql::{MySqlPool, MySqlPoolOptions},
+    Executor,
+};
+
+use crate::repository::{INSERT_NOTE, SELECT_NOTES_PAGE, SELECT_NOTE_BY_ID, UPDATE_NOTE};
+
+/// Statements every connection prepares as soon as it is opened, so the first
+/// requests served by a fresh pool skip the COM_STMT_PREPARE round trip.
+const HOT_STATEMENTS: [&str; 4] = [
+    SELECT_NOTES_PAGE,
+    SELECT_NOTE_BY_ID,
+    INSERT_NOTE,
+    UPDATE_NOTE,
+];
+
+#[derive(Debug, Clone, Copy)]
+pub struct WarmupOptions {
+    pub enabled: bool,
+    pub min_connections: u32,
+}
+
+impl Default for WarmupOptions {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_connections: 2,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct WarmupStats {
+    prepared: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct WarmupReport {
+    pub enabled: bool,
+    pub connections: u32,
+    pub statements_prepared: usize,
+    pub statements_failed: usize,
+    pub duration_ms: u128,
+}
+
+pub fn configure(
+    pool_options: MySqlPoolOptions,
+    options: &WarmupOptions,
+    stats: Arc<WarmupStats>,
+) -> MySqlPoolOptions {
+    if !options.enabled {
+        return pool_options;
+    }
+
+    pool_options
+        .min_connections(options.min_connections)
+        .after_connect(move |conn, _meta| {
+            let stats = stats.clone();
+            Box::pin(async move {
+                for sql in HOT_STATEMENTS {
+                    match conn.prepare(sql).await {
+                        Ok(_) => stats.prepared.fetch_add(1, Ordering::Relaxed),
+                        Err(err) => {
+                            tracing::error!(error = ?err, "Failed to prepare warm-up statement");
+                            stats.failed.fetch_add(1, Ordering::Relaxed)
+                        }
+                    };
+                }
+                Ok(())
+            })
+        })
+}
+
+/// Opens `min_connections` connections up front and holds them until all are
+/// established, so none of them is lazily created by a live request.
+pub async fn run(pool: &MySqlPool, options: &WarmupOptions, stats: &WarmupStats) -> WarmupReport {
+    if !options.enabled {
+        return WarmupReport::default();
+    }
+
+    let started = Instant::now();
+    let mut connections = Vec::with_capacity(options.min_connections as usize);
+    for _ in 0..options.min_connections {
+        match pool.acquire().await {
+            Ok(conn) => connections.push(conn),
+            Err(err) => {
+                tracing::error!(error = ?err, "Failed to open warm-up connection");
+                break;
+            }
+        }
+    }
+
+    let report = WarmupReport {
+        enabled: true,
+        connections: connections.len() as u32,
+        statements_prepared: stats.prepared.load(Ordering::Relaxed),
+        statements_failed: stats.failed.load(Ordering::Relaxed),
+        duration_ms: started.elapsed().as_millis(),
+    };
+    tracing::info!(
+        connections = report.connections,
+        prepared = report.statements_prepared,
+        failed = report.statements_failed,
+        duration_ms = report.duration_ms,
+        "Warm-up finished"
+    );
+    report
+}
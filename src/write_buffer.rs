@@ -0,0 +1,289 @@
+use std::{collections::HashMap, time::Duration};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::{mysql::MySqlPool, MySql, QueryBuilder};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::model::NoteId;
+
+/// Upper bound on rows touched by a single flushed UPDATE statement.
+const MAX_ROWS_PER_STATEMENT: usize = 500;
+
+#[derive(Debug)]
+pub enum BufferedWrite {
+    NoteViewed {
+        id: NoteId,
+        at: DateTime<Utc>,
+    },
+    /// Flushes everything pending, then answers.
+    Flush(oneshot::Sender<()>),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WriteBufferOptions {
+    pub capacity: usize,
+    pub flush_interval: Duration,
+    /// Flushes early once this many notes have views pending.
+    pub max_pending: usize,
+}
+
+impl Default for WriteBufferOptions {
+    fn default() -> Self {
+        Self {
+            capacity: 10_000,
+            flush_interval: Duration::from_secs(5),
+            max_pending: MAX_ROWS_PER_STATEMENT,
+        }
+    }
+}
+
+/// Write-behind buffer for high-frequency, low-value writes.
+///
+/// Handlers push events into a bounded channel; a background task folds them
+/// per note and flushes them as a handful of multi-row UPDATEs, periodically
+/// and whenever enough notes are pending.
+/// Events are dropped when the channel is full: losing a view count is
+/// preferable to slowing down reads.
+#[derive(Clone)]
+pub struct WriteBuffer {
+    tx: mpsc::Sender<BufferedWrite>,
+}
+
+impl WriteBuffer {
+    pub fn spawn(db: MySqlPool, options: WriteBufferOptions) -> Self {
+        Self::spawn_with(db, options)
+    }
+
+    fn spawn_with(sink: impl ViewSink, options: WriteBufferOptions) -> Self {
+        let (tx, rx) = mpsc::channel(options.capacity);
+        tokio::spawn(run(sink, rx, options));
+        Self { tx }
+    }
+
+    pub fn record_view(&self, id: NoteId, at: DateTime<Utc>) {
+        let _ = self.tx.try_send(BufferedWrite::NoteViewed { id, at });
+    }
+
+    /// Writes out everything buffered so far, e.g. before the pool closes on
+    /// shutdown. Unlike recording, this waits for room in the channel.
+    pub async fn flush(&self) {
+        let (done, flushed) = oneshot::channel();
+        if self.tx.send(BufferedWrite::Flush(done)).await.is_ok() {
+            let _ = flushed.await;
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PendingView {
+    count: u64,
+    last_accessed_at: DateTime<Utc>,
+}
+
+/// Where flushed views are written.
+#[async_trait]
+trait ViewSink: Send + 'static {
+    async fn write(&mut self, views: &[(NoteId, PendingView)]);
+}
+
+#[async_trait]
+impl ViewSink for MySqlPool {
+    async fn write(&mut self, views: &[(NoteId, PendingView)]) {
+        for chunk in views.chunks(MAX_ROWS_PER_STATEMENT) {
+            if let Err(err) = build_view_update(chunk).build().execute(&*self).await {
+                tracing::error!(
+                    notes = chunk.len(),
+                    error = ?err,
+                    "Failed to flush buffered note views",
+                );
+            }
+        }
+    }
+}
+
+async fn run(
+    mut sink: impl ViewSink,
+    mut rx: mpsc::Receiver<BufferedWrite>,
+    options: WriteBufferOptions,
+) {
+    let mut ticker = tokio::time::interval(options.flush_interval);
+    let mut pending: HashMap<NoteId, PendingView> = HashMap::new();
+
+    loop {
+        tokio::select! {
+            write = rx.recv() => match write {
+                Some(BufferedWrite::NoteViewed { id, at }) => {
+                    let view = pending.entry(id).or_insert(PendingView {
+                        count: 0,
+                        last_accessed_at: at,
+                    });
+                    view.count += 1;
+                    view.last_accessed_at = view.last_accessed_at.max(at);
+                    if pending.len() >= options.max_pending {
+                        flush(&mut sink, &mut pending).await;
+                    }
+                }
+                Some(BufferedWrite::Flush(done)) => {
+                    flush(&mut sink, &mut pending).await;
+                    let _ = done.send(());
+                }
+                None => {
+                    flush(&mut sink, &mut pending).await;
+                    break;
+                }
+            },
+            _ = ticker.tick() => flush(&mut sink, &mut pending).await,
+        }
+    }
+}
+
+async fn flush(sink: &mut impl ViewSink, pending: &mut HashMap<NoteId, PendingView>) {
+    if pending.is_empty() {
+        return;
+    }
+
+    let views = pending.drain().collect::<Vec<_>>();
+    sink.write(&views).await;
+}
+
+fn build_view_update(views: &[(NoteId, PendingView)]) -> QueryBuilder<'_, MySql> {
+    let mut query = QueryBuilder::new("UPDATE notes SET view_count = view_count + CASE id");
+    for (id, view) in views {
+        query
+            .push(" WHEN ")
+            .push_bind(*id)
+            .push(" THEN ")
+            .push_bind(view.count);
+    }
+    query.push(" ELSE 0 END, last_accessed_at = CASE id");
+    for (id, view) in views {
+        query
+            .push(" WHEN ")
+            .push_bind(*id)
+            .push(" THEN ")
+            .push_bind(view.last_accessed_at);
+    }
+    // Assigning updated_at to itself stops MySQL's ON UPDATE CURRENT_TIMESTAMP
+    // from treating a view as an edit.
+    query.push(" ELSE last_accessed_at END, updated_at = updated_at WHERE id IN (");
+    let mut ids = query.separated(", ");
+    for (id, _) in views {
+        ids.push_bind(*id);
+    }
+    query.push(")");
+    query
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use tokio::time::timeout;
+
+    use super::*;
+
+    type Flushed = HashMap<NoteId, (u64, DateTime<Utc>)>;
+
+    /// Sends each flush down a channel instead of to the database.
+    struct Recorded(mpsc::UnboundedSender<Flushed>);
+
+    #[async_trait]
+    impl ViewSink for Recorded {
+        async fn write(&mut self, views: &[(NoteId, PendingView)]) {
+            let flushed = views
+                .iter()
+                .map(|(id, view)| (*id, (view.count, view.last_accessed_at)))
+                .collect();
+            let _ = self.0.send(flushed);
+        }
+    }
+
+    /// A buffer over a recording sink, past the tick its interval starts with.
+    async fn buffer(
+        options: WriteBufferOptions,
+    ) -> (WriteBuffer, mpsc::UnboundedReceiver<Flushed>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let buffer = WriteBuffer::spawn_with(Recorded(tx), options);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        (buffer, rx)
+    }
+
+    async fn nothing_flushed(rx: &mut mpsc::UnboundedReceiver<Flushed>) -> bool {
+        timeout(Duration::from_millis(50), rx.recv()).await.is_err()
+    }
+
+    async fn next_flush(rx: &mut mpsc::UnboundedReceiver<Flushed>) -> Flushed {
+        timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .expect("flushed in time")
+            .unwrap()
+    }
+
+    fn at(minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 5, 3, 12, minute, 0).unwrap()
+    }
+
+    fn note() -> NoteId {
+        NoteId::from(uuid::Uuid::new_v4())
+    }
+
+    #[tokio::test]
+    async fn views_are_folded_per_note_and_flushed_on_the_interval() {
+        let (buffer, mut rx) = buffer(WriteBufferOptions {
+            flush_interval: Duration::from_millis(300),
+            ..WriteBufferOptions::default()
+        })
+        .await;
+        let (read, skimmed) = (note(), note());
+        buffer.record_view(read, at(1));
+        buffer.record_view(read, at(3));
+        buffer.record_view(read, at(2));
+        buffer.record_view(skimmed, at(4));
+
+        assert!(nothing_flushed(&mut rx).await);
+        let flushed = next_flush(&mut rx).await;
+        assert_eq!(flushed.len(), 2);
+        assert_eq!(flushed[&read], (3, at(3)));
+        assert_eq!(flushed[&skimmed], (1, at(4)));
+
+        // Nothing pending, nothing to write.
+        assert!(timeout(Duration::from_millis(400), rx.recv())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn enough_pending_notes_flush_before_the_interval() {
+        let (buffer, mut rx) = buffer(WriteBufferOptions {
+            flush_interval: Duration::from_secs(3600),
+            max_pending: 3,
+            ..WriteBufferOptions::default()
+        })
+        .await;
+        let notes = [note(), note(), note()];
+        buffer.record_view(notes[0], at(1));
+        buffer.record_view(notes[1], at(1));
+        // More views of a pending note do not count towards the size.
+        buffer.record_view(notes[1], at(2));
+        assert!(nothing_flushed(&mut rx).await);
+
+        buffer.record_view(notes[2], at(3));
+        let flushed = next_flush(&mut rx).await;
+        assert_eq!(flushed.len(), 3);
+        assert_eq!(flushed[&notes[1]], (2, at(2)));
+        assert!(nothing_flushed(&mut rx).await);
+    }
+
+    #[tokio::test]
+    async fn flush_writes_out_what_is_pending() {
+        let (buffer, mut rx) = buffer(WriteBufferOptions {
+            flush_interval: Duration::from_secs(3600),
+            ..WriteBufferOptions::default()
+        })
+        .await;
+        let id = note();
+        buffer.record_view(id, at(1));
+        buffer.flush().await;
+        assert_eq!(rx.try_recv().unwrap()[&id], (1, at(1)));
+    }
+}